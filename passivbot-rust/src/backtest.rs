@@ -1,103 +1,149 @@
 use crate::closes::{
-    calc_closes_long, calc_closes_short, calc_next_close_long, calc_next_close_short,
+    calc_closes_long, calc_closes_short, calc_neutral_rebalance_close, calc_next_close_long,
+    calc_next_close_short, calc_stepped_trailing_stop_price_long,
+    calc_stepped_trailing_stop_price_short, calc_trailing_stop_price_long,
+    calc_trailing_stop_price_short,
 };
-use crate::constants::{CLOSE, HIGH, LONG, LOW, NO_POS, SHORT, VOLUME};
+use crate::constants::{CLOSE, FUNDING_INTERVAL, HIGH, LONG, LOW, SHORT, VOLUME};
 use crate::entries::{
-    calc_entries_long, calc_entries_short, calc_min_entry_qty, calc_next_entry_long,
-    calc_next_entry_short,
+    calc_entries_long, calc_entries_short, calc_min_entry_qty, calc_neutral_rebalance_entry,
+    calc_next_entry_long, calc_next_entry_short,
 };
+use crate::invariants::{check_ladder_invariants, WALLET_EXPOSURE_LEEWAY};
+use crate::lots::{CloseAttribution, LotMethod, LotTracker};
+use crate::observers::{BacktestObserver, CandleSnapshot};
+use crate::order_lifetimes::{OrderLifetimeStats, OrderLifetimeTracker};
+use crate::rng::Rng;
 use crate::types::{
-    Analysis, BacktestParams, Balance, BotParams, BotParamsPair, EMABands, Equities,
-    ExchangeParams, Fill, Order, OrderBook, OrderType, Position, Positions, StateParams,
-    TrailingPriceBundle,
+    Analysis, BacktestParams, Balance, BotParams, BotParamsPair, CashFlow, CloseTrailingAnchor,
+    EMABandsDetailed, Equities, EquityDownsample, Evaluation, ExchangeParams, Fill, ModeSwitch,
+    NextOrder, Order, OrderBook, OrderType, Position, Positions, PruneParams, ReduceOnlyPeriod,
+    StateParams, SymbolIdx, TradingMask, TradingMode, TrailingPriceBundle, WindDown,
 };
 use crate::utils::{
-    calc_auto_unstuck_allowance, calc_new_psize_pprice, calc_pnl_long, calc_pnl_short,
-    calc_pprice_diff_int, calc_wallet_exposure, cost_to_qty, hysteresis_rounding, qty_to_cost,
-    round_, round_dn, round_up,
+    calc_auto_unstuck_allowance, calc_correlation_scaled_wallet_exposure_limits,
+    calc_new_psize_pprice, calc_pnl_long, calc_pnl_short, calc_pprice_diff_int,
+    calc_unstuck_max_loss, calc_wallet_exposure, cost_to_qty, hysteresis_rounding, qty_to_cost,
+    rank_positions_for_unstucking, round_, round_dn, round_up,
+};
+use ndarray::{
+    s, Array1, Array2, Array3, Array4, ArrayView1, ArrayView2, ArrayView3, Axis, CowArray, Dim,
+    Ix1, Ix3, ViewRepr,
 };
-use ndarray::{s, Array1, Array2, Array3, Array4, ArrayView1, ArrayView3, Axis, Dim, ViewRepr};
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 
-#[derive(Clone, Default, Copy, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct EmaAlphas {
     pub long: Alphas,
     pub short: Alphas,
 }
 
-#[derive(Clone, Default, Copy, Debug)]
+impl EmaAlphas {
+    pub fn side(&self, pside: usize) -> &Alphas {
+        match pside {
+            LONG => &self.long,
+            SHORT => &self.short,
+            _ => panic!("Invalid pside"),
+        }
+    }
+}
+
+/// One side's EMA spans: the union of its entry and unstuck triplets, each span once, with
+/// where each triplet's spans sit in it.
+#[derive(Clone, Default, Debug)]
 pub struct Alphas {
-    pub alphas: [f64; 3],
-    pub alphas_inv: [f64; 3],
+    pub spans: Vec<f64>,
+    pub alphas: Vec<f64>,
+    pub alphas_inv: Vec<f64>,
+    pub entry: [usize; 3],   // ema_spans_sorted
+    pub unstuck: [usize; 3], // unstuck_ema_spans_sorted
+}
+
+impl Alphas {
+    pub fn new(entry_spans: [f64; 3], unstuck_spans: [f64; 3]) -> Self {
+        fn position(spans: &mut Vec<f64>, span: f64) -> usize {
+            spans.iter().position(|&s| s == span).unwrap_or_else(|| {
+                spans.push(span);
+                spans.len() - 1
+            })
+        }
+        let mut spans = Vec::with_capacity(6);
+        let entry = entry_spans.map(|span| position(&mut spans, span));
+        let unstuck = unstuck_spans.map(|span| position(&mut spans, span));
+        let alphas: Vec<f64> = spans.iter().map(|span| 2.0 / (span + 1.0)).collect();
+        let alphas_inv = alphas.iter().map(|alpha| 1.0 - alpha).collect();
+        Alphas {
+            spans,
+            alphas,
+            alphas_inv,
+            entry,
+            unstuck,
+        }
+    }
 }
 
+/// Per-span EMAs of a coin, in the order of its sides' Alphas.spans.
 #[derive(Debug)]
 pub struct EMAs {
-    pub long: [f64; 3],
-    pub short: [f64; 3],
+    pub long: Vec<f64>,
+    pub short: Vec<f64>,
 }
 impl EMAs {
-    pub fn compute_bands(&self, pside: usize) -> EMABands {
-        let (upper, lower) = match pside {
-            LONG => (
-                *self
-                    .long
-                    .iter()
-                    .max_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap_or(&f64::MIN),
-                *self
-                    .long
-                    .iter()
-                    .min_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap_or(&f64::MAX),
-            ),
-            SHORT => (
-                *self
-                    .short
-                    .iter()
-                    .max_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap_or(&f64::MIN),
-                *self
-                    .short
-                    .iter()
-                    .min_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap_or(&f64::MAX),
-            ),
+    fn side(&self, pside: usize) -> &[f64] {
+        match pside {
+            LONG => &self.long,
+            SHORT => &self.short,
             _ => panic!("Invalid pside"),
-        };
-        EMABands { upper, lower }
+        }
+    }
+
+    /// The three EMAs at triplet of alphas, e.g. Alphas.entry, with their spans; callers
+    /// after the band edges only take .bands().
+    pub fn compute_bands(
+        &self,
+        pside: usize,
+        alphas: &Alphas,
+        triplet: [usize; 3],
+    ) -> EMABandsDetailed {
+        EMABandsDetailed {
+            spans: triplet.map(|i| alphas.spans[i]),
+            emas: triplet.map(|i| self.side(pside)[i]),
+        }
     }
 }
 
 #[derive(Debug, Default)]
 pub struct OpenOrdersNew {
-    pub long: HashMap<usize, OpenOrderBundleNew>,
-    pub short: HashMap<usize, OpenOrderBundleNew>,
+    pub long: HashMap<SymbolIdx, OpenOrderBundleNew>,
+    pub short: HashMap<SymbolIdx, OpenOrderBundleNew>,
 }
 
 #[derive(Debug, Default)]
 pub struct OpenOrderBundleNew {
     pub entries: Vec<Order>,
     pub closes: Vec<Order>,
+    // trailing is in effect but not yet triggered; re-evaluate every candle
+    pub trailing_entry_pending: bool,
+    pub trailing_close_pending: bool,
 }
 
 #[derive(Default, Debug)]
 pub struct Actives {
-    long: HashSet<usize>,
-    short: HashSet<usize>,
+    long: HashSet<SymbolIdx>,
+    short: HashSet<SymbolIdx>,
 }
 
 #[derive(Default, Debug)]
 pub struct IsStuck {
-    long: HashSet<usize>,
-    short: HashSet<usize>,
+    long: HashSet<SymbolIdx>,
+    short: HashSet<SymbolIdx>,
 }
 
 #[derive(Default, Debug)]
 pub struct TrailingPrices {
-    pub long: HashMap<usize, TrailingPriceBundle>,
-    pub short: HashMap<usize, TrailingPriceBundle>,
+    pub long: HashMap<SymbolIdx, TrailingPriceBundle>,
+    pub short: HashMap<SymbolIdx, TrailingPriceBundle>,
 }
 
 pub struct TrailingEnabled {
@@ -110,6 +156,15 @@ pub struct TradingEnabled {
     short: bool,
 }
 
+// a side's wind-down in progress: its index in wind_downs, each coin's close at the switch,
+// and the qty closed since, valued at those marks and as filled
+struct ActiveWindDown {
+    i: usize,
+    marks: HashMap<SymbolIdx, f64>,
+    marked: f64,
+    filled: f64,
+}
+
 pub struct RollingVolumeSum {
     long: Vec<f64>,
     short: Vec<f64>,
@@ -118,11 +173,15 @@ pub struct RollingVolumeSum {
 }
 
 pub struct Backtest<'a> {
-    hlcvs: &'a ArrayView3<'a, f64>,
-    btc_usd_prices: &'a ArrayView1<'a, f64>, // Change to ArrayView1 (1D view)
+    // borrowed for run; owned, and grown one candle at a time, when stepped live
+    hlcvs: CowArray<'a, f64, Ix3>,
+    btc_usd_prices: CowArray<'a, f64, Ix1>,
     bot_params_pair: BotParamsPair,
+    entry_bot_params_list: Vec<BotParamsPair>, // per coin; max_position_cost overridden
+    close_bot_params_list: Vec<BotParamsPair>, // per coin; wallet_exposure_limit scaled by correlation
     exchange_params_list: Vec<ExchangeParams>,
     backtest_params: BacktestParams,
+    pub markup_floors: Vec<f64>, // per coin; see calc_markup_floors
     pub balance: Balance,
     n_coins: usize,
     ema_alphas: EmaAlphas,
@@ -137,15 +196,45 @@ pub struct Backtest<'a> {
     is_stuck: IsStuck,
     trading_enabled: TradingEnabled,
     trailing_enabled: TrailingEnabled,
+    neutral_mode: bool, // long and short kept at equal sizes per coin; see rebalance_neutral
     equities: Equities,
-    last_valid_timestamps: HashMap<usize, usize>,
-    first_valid_timestamps: HashMap<usize, usize>,
-    did_fill_long: HashSet<usize>,
-    did_fill_short: HashSet<usize>,
+    last_valid_timestamps: HashMap<SymbolIdx, usize>,
+    first_valid_timestamps: HashMap<SymbolIdx, usize>,
+    did_fill_long: HashSet<SymbolIdx>,
+    did_fill_short: HashSet<SymbolIdx>,
+    last_unstucked: Option<(SymbolIdx, usize)>, // (idx, pside) of the last unstuck close filled
+    // selection ranking of the last update_actives, used to settle contention for open slots
+    slot_ranking_long: Vec<SymbolIdx>,
+    slot_ranking_short: Vec<SymbolIdx>,
     n_eligible_long: usize,
     n_eligible_short: usize,
     rolling_volume_sum: RollingVolumeSum,
-    volume_indices_buffer: Option<Vec<(f64, usize)>>,
+    volume_indices_buffer: Option<Vec<(f64, SymbolIdx)>>,
+    // reused every candle to iterate per-symbol maps in sorted order without allocating
+    positions_idx_buffer: Vec<SymbolIdx>,
+    orders_idx_buffer: Vec<SymbolIdx>,
+    prune_params: PruneParams,
+    pub partial_fitnesses: Vec<f64>, // one per checkpoint reached
+    pub pruned: bool,
+    // (k, idx, pside) of entry fills while max_position_cost binds; see with_cost_capped_fills
+    pub cost_capped_fills: Vec<(usize, SymbolIdx, usize)>,
+    mode_schedule: Vec<ModeSwitch>, // descending k; switches are popped as they come due
+    trading_modes: [TradingMode; 2], // per pside
+    cash_flows: Vec<CashFlow>,      // descending k; flows are popped as they come due
+    pub wind_downs: Vec<WindDown>,
+    active_wind_downs: [Option<ActiveWindDown>; 2], // per pside
+    equity: f64,                                    // usd, as of the previous candle
+    peak_equity: f64,                               // usd
+    equity_drawdown_triggered: [bool; 2],           // per pside
+    pub reduce_only_periods: Vec<ReduceOnlyPeriod>, // the last is active while its end is None
+    slippage_rng: Rng,
+    impact_capacities: Vec<f64>,        // per coin; 0.0 == unlimited
+    impact_consumed: Vec<(usize, f64)>, // per coin (k, quote volume filled in candle k)
+    trading_masks: Vec<TradingMask>,    // per coin
+    observers: Vec<Box<dyn BacktestObserver + Send>>,
+    order_lifetimes: Option<OrderLifetimeTracker>, // see track_order_lifetimes
+    lots: Option<LotTracker>,                      // see track_lots
+    balance_curve: Option<&'a (dyn Fn(usize) -> f64 + Sync)>, // see set_balance_curve
 }
 
 impl<'a> Backtest<'a> {
@@ -155,6 +244,45 @@ impl<'a> Backtest<'a> {
         bot_params_pair: BotParamsPair,
         exchange_params_list: Vec<ExchangeParams>,
         backtest_params: &BacktestParams,
+    ) -> Self {
+        Backtest::from_data(
+            CowArray::from(hlcvs.view()),
+            CowArray::from(btc_usd_prices.view()),
+            bot_params_pair,
+            exchange_params_list,
+            backtest_params,
+        )
+    }
+
+    /// A backtest to be driven one candle at a time with push_candles and step, starting
+    /// from first_candles ([coin, HIGH..=VOLUME]). Collateral is USD.
+    pub fn new_stepwise(
+        first_candles: ArrayView2<f64>,
+        bot_params_pair: BotParamsPair,
+        exchange_params_list: Vec<ExchangeParams>,
+        backtest_params: &BacktestParams,
+    ) -> Backtest<'static> {
+        let hlcvs = first_candles
+            .to_owned()
+            .into_shape((1, first_candles.nrows(), first_candles.ncols()))
+            .expect("candles are contiguous");
+        let mut backtest = Backtest::from_data(
+            CowArray::from(hlcvs),
+            CowArray::from(Array1::ones(1)),
+            bot_params_pair,
+            exchange_params_list,
+            backtest_params,
+        );
+        backtest.init_trailing_prices();
+        backtest
+    }
+
+    fn from_data(
+        hlcvs: CowArray<'a, f64, Ix3>,
+        btc_usd_prices: CowArray<'a, f64, Ix1>,
+        bot_params_pair: BotParamsPair,
+        exchange_params_list: Vec<ExchangeParams>,
+        backtest_params: &BacktestParams,
     ) -> Self {
         // Determine if BTC collateral is used
         let mut balance = Balance::default();
@@ -176,12 +304,14 @@ impl<'a> Backtest<'a> {
 
         let n_timesteps = hlcvs.shape()[0];
         let n_coins = hlcvs.shape()[1];
+        let markup_floors = calc_markup_floors(&hlcvs.view(), backtest_params);
+        let ema_alphas = calc_ema_alphas(&bot_params_pair);
         let initial_emas = (0..n_coins)
             .map(|i| {
                 let close_price = hlcvs[[0, i, CLOSE]];
                 EMAs {
-                    long: [close_price; 3],
-                    short: [close_price; 3],
+                    long: vec![close_price; ema_alphas.long.spans.len()],
+                    short: vec![close_price; ema_alphas.short.spans.len()],
                 }
             })
             .collect();
@@ -198,41 +328,107 @@ impl<'a> Backtest<'a> {
             (n_coins as f64 * (1.0 - bot_params_pair.short.filter_volume_drop_pct)).round()
                 as usize,
         );
+        let entry_bot_params_list: Vec<BotParamsPair> = (0..n_coins)
+            .map(|i| {
+                let mut entry_bot_params = bot_params_pair_cloned.clone();
+                let max_position_cost = backtest_params
+                    .coins
+                    .get(i)
+                    .and_then(|coin| backtest_params.max_position_costs.get(coin));
+                if let Some(&max_position_cost) = max_position_cost {
+                    entry_bot_params.long.max_position_cost = max_position_cost;
+                    entry_bot_params.short.max_position_cost = max_position_cost;
+                }
+                entry_bot_params
+            })
+            .collect();
+        let close_bot_params_list = if backtest_params.correlation_matrix.len() == n_coins {
+            let scaled_wels_long = calc_correlation_scaled_wallet_exposure_limits(
+                bot_params_pair_cloned.long.wallet_exposure_limit,
+                &backtest_params.correlation_matrix,
+            );
+            let scaled_wels_short = calc_correlation_scaled_wallet_exposure_limits(
+                bot_params_pair_cloned.short.wallet_exposure_limit,
+                &backtest_params.correlation_matrix,
+            );
+            (0..n_coins)
+                .map(|i| {
+                    let mut close_bot_params = entry_bot_params_list[i].clone();
+                    close_bot_params.long.wallet_exposure_limit = scaled_wels_long[i];
+                    close_bot_params.short.wallet_exposure_limit = scaled_wels_short[i];
+                    close_bot_params
+                })
+                .collect()
+        } else {
+            entry_bot_params_list.clone()
+        };
+        // per-symbol containers are bounded by n_positions (open slots) or n_coins; size them up front
+        let n_long = bot_params_pair_cloned.long.n_positions;
+        let n_short = bot_params_pair_cloned.short.n_positions;
         Backtest {
             hlcvs,
             btc_usd_prices,
+            entry_bot_params_list,
+            close_bot_params_list,
             bot_params_pair: bot_params_pair_cloned,
             exchange_params_list,
             backtest_params: backtest_params.clone(),
+            markup_floors,
             balance,
             n_coins,
-            ema_alphas: calc_ema_alphas(&bot_params_pair),
+            ema_alphas,
             emas: initial_emas,
-            positions: Positions::default(),
-            open_orders: OpenOrdersNew::default(),
-            trailing_prices: TrailingPrices::default(),
-            actives: Actives::default(),
+            positions: Positions {
+                long: HashMap::with_capacity(n_long),
+                short: HashMap::with_capacity(n_short),
+            },
+            open_orders: OpenOrdersNew {
+                long: HashMap::with_capacity(n_long),
+                short: HashMap::with_capacity(n_short),
+            },
+            trailing_prices: TrailingPrices {
+                long: HashMap::with_capacity(n_coins),
+                short: HashMap::with_capacity(n_coins),
+            },
+            actives: Actives {
+                long: HashSet::with_capacity(n_long),
+                short: HashSet::with_capacity(n_short),
+            },
             pnl_cumsum_running: 0.0,
             pnl_cumsum_max: 0.0,
             fills: Vec::new(),
-            is_stuck: IsStuck::default(),
+            is_stuck: IsStuck {
+                long: HashSet::with_capacity(n_long),
+                short: HashSet::with_capacity(n_short),
+            },
             trading_enabled: TradingEnabled {
                 long: bot_params_pair.long.wallet_exposure_limit != 0.0
                     && bot_params_pair.long.n_positions > 0,
                 short: bot_params_pair.short.wallet_exposure_limit != 0.0
                     && bot_params_pair.short.n_positions > 0,
             },
+            neutral_mode: bot_params_pair.long.neutral_mode
+                && bot_params_pair.short.neutral_mode
+                && bot_params_pair.long.wallet_exposure_limit != 0.0
+                && bot_params_pair.long.n_positions > 0
+                && bot_params_pair.short.wallet_exposure_limit != 0.0
+                && bot_params_pair.short.n_positions > 0,
             trailing_enabled: TrailingEnabled {
                 long: bot_params_pair.long.close_trailing_grid_ratio != 0.0
-                    || bot_params_pair.long.entry_trailing_grid_ratio != 0.0,
+                    || bot_params_pair.long.entry_trailing_grid_ratio != 0.0
+                    || bot_params_pair.long.close_trailing_legs_enabled(),
                 short: bot_params_pair.short.close_trailing_grid_ratio != 0.0
-                    || bot_params_pair.short.entry_trailing_grid_ratio != 0.0,
+                    || bot_params_pair.short.entry_trailing_grid_ratio != 0.0
+                    || bot_params_pair.short.close_trailing_legs_enabled(),
             },
             equities: equities,
-            last_valid_timestamps: HashMap::new(),
-            first_valid_timestamps: HashMap::new(),
-            did_fill_long: HashSet::new(),
-            did_fill_short: HashSet::new(),
+            last_valid_timestamps: HashMap::with_capacity(n_coins),
+            first_valid_timestamps: HashMap::with_capacity(n_coins),
+            did_fill_long: HashSet::with_capacity(n_long),
+            did_fill_short: HashSet::with_capacity(n_short),
+            last_unstucked: None,
+            slot_ranking_long: Vec::with_capacity(n_coins),
+            slot_ranking_short: Vec::with_capacity(n_coins),
             n_eligible_long,
             n_eligible_short,
             rolling_volume_sum: RollingVolumeSum {
@@ -242,10 +438,127 @@ impl<'a> Backtest<'a> {
                 prev_k_short: 0,
             },
             volume_indices_buffer: Some(vec![(0.0, 0); n_coins]), // Initialize here
+            positions_idx_buffer: Vec::with_capacity(n_coins),
+            orders_idx_buffer: Vec::with_capacity(n_coins),
+            prune_params: PruneParams::default(),
+            partial_fitnesses: Vec::new(),
+            cost_capped_fills: Vec::new(),
+            mode_schedule: {
+                let mut mode_schedule = backtest_params.mode_schedule.clone();
+                mode_schedule.sort_by_key(|event| std::cmp::Reverse(event.k));
+                mode_schedule
+            },
+            trading_modes: [TradingMode::Normal; 2],
+            cash_flows: {
+                let mut cash_flows = backtest_params.cash_flows.clone();
+                cash_flows.sort_by_key(|flow| std::cmp::Reverse(flow.k));
+                cash_flows
+            },
+            wind_downs: Vec::new(),
+            active_wind_downs: [None, None],
+            equity: 0.0,
+            peak_equity: 0.0,
+            equity_drawdown_triggered: [false; 2],
+            reduce_only_periods: Vec::new(),
+            pruned: false,
+            slippage_rng: Rng::component(backtest_params.seed, "slippage"),
+            impact_capacities: backtest_params
+                .coins
+                .iter()
+                .map(|coin| {
+                    backtest_params
+                        .impact_capacities
+                        .get(coin)
+                        .copied()
+                        .unwrap_or(0.0)
+                })
+                .collect(),
+            impact_consumed: vec![(0, 0.0); n_coins],
+            trading_masks: backtest_params
+                .coins
+                .iter()
+                .map(|coin| {
+                    backtest_params
+                        .trading_masks
+                        .get(coin)
+                        .copied()
+                        .unwrap_or_default()
+                })
+                .collect(),
+            observers: Vec::new(),
+            order_lifetimes: None,
+            lots: None,
+            balance_curve: None,
+        }
+    }
+
+    /// Lets run stop early, setting pruned, once the partial fitness passes a threshold.
+    pub fn set_prune_params(&mut self, prune_params: PruneParams) {
+        self.prune_params = prune_params;
+    }
+
+    /// Has the close calculators read balance from balance_curve_fn at the current candle
+    /// instead of the running balance, e.g. a prescribed growth curve to test compounding
+    /// assumptions. Entries, unstucking and equity still follow the running balance.
+    pub fn set_balance_curve(&mut self, balance_curve_fn: &'a (dyn Fn(usize) -> f64 + Sync)) {
+        self.balance_curve = Some(balance_curve_fn);
+    }
+
+    /// Replaces coin idx's trading mask from the next order update on. Entries open on a
+    /// side it disables are cancelled; positions there keep their closes.
+    pub fn set_trading_mask(&mut self, idx: SymbolIdx, mask: TradingMask) {
+        self.trading_masks[idx as usize] = mask;
+        for (pside, open_orders) in [
+            (LONG, &mut self.open_orders.long),
+            (SHORT, &mut self.open_orders.short),
+        ] {
+            if let Some(orders) = open_orders.get_mut(&idx).filter(|_| !mask.enabled(pside)) {
+                orders.entries.clear();
+                orders.trailing_entry_pending = false;
+            }
         }
     }
 
-    pub fn calc_preferred_coins(&mut self, k: usize, pside: usize) -> Vec<usize> {
+    /// Registers observer to be called from every step after the built-in fill and equity
+    /// records.
+    pub fn add_observer(&mut self, observer: Box<dyn BacktestObserver + Send>) {
+        self.observers.push(observer);
+    }
+
+    /// Records from now on when each open order was laid out and when it filled or was
+    /// cancelled; see order_lifetime_stats.
+    pub fn track_order_lifetimes(&mut self) {
+        self.order_lifetimes = Some(OrderLifetimeTracker::default());
+    }
+
+    /// Per order type lifetimes so far; empty unless track_order_lifetimes was called.
+    pub fn order_lifetime_stats(&self) -> Vec<OrderLifetimeStats> {
+        self.order_lifetimes
+            .as_ref()
+            .map_or(Vec::new(), |tracker| tracker.stats())
+    }
+
+    /// Attributes from now on each close fill to the entry fills it offsets, per method; see
+    /// lot_attributions.
+    pub fn track_lots(&mut self, method: LotMethod) {
+        let c_mults = self
+            .backtest_params
+            .coins
+            .iter()
+            .cloned()
+            .zip(self.exchange_params_list.iter().map(|ep| ep.c_mult))
+            .collect();
+        self.lots = Some(LotTracker::new(method, c_mults, self.fills.len()));
+    }
+
+    /// One attribution per close fill so far; empty unless track_lots was called.
+    pub fn lot_attributions(&self) -> Vec<CloseAttribution> {
+        self.lots
+            .as_ref()
+            .map_or(Vec::new(), |tracker| tracker.attributions().to_vec())
+    }
+
+    pub fn calc_preferred_coins(&mut self, k: usize, pside: usize) -> Vec<SymbolIdx> {
         let (bot_params, n_positions) = match pside {
             LONG => (
                 &self.bot_params_pair.long,
@@ -258,14 +571,18 @@ impl<'a> Backtest<'a> {
             _ => panic!("Invalid pside"),
         };
 
-        if self.n_coins <= n_positions {
-            return (0..self.n_coins).collect();
-        }
-        let volume_filtered = self.filter_by_relative_volume(k, pside);
-        self.rank_by_noisiness(k, &volume_filtered, pside)
+        let mut preferred_coins = if self.n_coins <= n_positions {
+            (0..self.n_coins as SymbolIdx).collect()
+        } else {
+            let volume_filtered = self.filter_by_relative_volume(k, pside);
+            self.rank_by_noisiness(k, &volume_filtered, pside)
+        };
+        // masked coins are ranked like the rest but never take a slot
+        preferred_coins.retain(|&idx| self.trading_masks[idx as usize].enabled(pside));
+        preferred_coins
     }
 
-    fn filter_by_relative_volume(&mut self, k: usize, pside: usize) -> Vec<usize> {
+    fn filter_by_relative_volume(&mut self, k: usize, pside: usize) -> Vec<SymbolIdx> {
         let bot_params = match pside {
             LONG => &self.bot_params_pair.long,
             SHORT => &self.bot_params_pair.short,
@@ -294,12 +611,12 @@ impl<'a> Backtest<'a> {
                 rolling_volume_sum[idx] -=
                     self.hlcvs.slice(s![safe_start..start_k, idx, VOLUME]).sum();
                 rolling_volume_sum[idx] += self.hlcvs.slice(s![*prev_k..k, idx, VOLUME]).sum();
-                volume_indices[idx] = (rolling_volume_sum[idx], idx);
+                volume_indices[idx] = (rolling_volume_sum[idx], idx as SymbolIdx);
             }
         } else {
             for idx in 0..self.n_coins {
                 rolling_volume_sum[idx] = self.hlcvs.slice(s![start_k..k, idx, VOLUME]).sum();
-                volume_indices[idx] = (rolling_volume_sum[idx], idx);
+                volume_indices[idx] = (rolling_volume_sum[idx], idx as SymbolIdx);
             }
         }
         *prev_k = k;
@@ -318,7 +635,12 @@ impl<'a> Backtest<'a> {
             .collect()
     }
 
-    fn rank_by_noisiness(&self, k: usize, candidates: &[usize], pside: usize) -> Vec<usize> {
+    fn rank_by_noisiness(
+        &self,
+        k: usize,
+        candidates: &[SymbolIdx],
+        pside: usize,
+    ) -> Vec<SymbolIdx> {
         let bot_params = match pside {
             LONG => &self.bot_params_pair.long,
             SHORT => &self.bot_params_pair.short,
@@ -326,12 +648,12 @@ impl<'a> Backtest<'a> {
         };
         let start_k = k.saturating_sub(bot_params.filter_noisiness_rolling_window);
 
-        let mut noisinesses: Vec<(f64, usize)> = candidates
+        let mut noisinesses: Vec<(f64, SymbolIdx)> = candidates
             .iter()
             .map(|&idx| {
                 let noisiness: f64 = self
                     .hlcvs
-                    .slice(s![start_k..k, idx, ..])
+                    .slice(s![start_k..k, idx as usize, ..])
                     .axis_iter(Axis(0))
                     .map(|row| (row[HIGH] - row[LOW]) / row[CLOSE])
                     .sum();
@@ -345,7 +667,35 @@ impl<'a> Backtest<'a> {
 
     pub fn run(&mut self) -> (Vec<Fill>, Equities) {
         let n_timesteps = self.hlcvs.shape()[0];
-        for idx in 0..self.n_coins {
+        self.init_trailing_prices();
+
+        // --- find first & last valid candle for every coin (binary-search) ---
+        let (first_valid, last_valid) = find_valid_timestamp_bounds(&self.hlcvs.view());
+        for (idx, (&first, &last)) in first_valid.iter().zip(last_valid.iter()).enumerate() {
+            self.first_valid_timestamps.insert(idx as SymbolIdx, first);
+            if n_timesteps - last > 1400 {
+                // add only if delisted more than one day before last timestamp
+                self.last_valid_timestamps.insert(idx as SymbolIdx, last); // keep same name for callers
+            }
+        }
+
+        let checkpoint_ks: Vec<usize> = self
+            .prune_params
+            .checkpoints
+            .iter()
+            .map(|checkpoint| (checkpoint * n_timesteps as f64) as usize)
+            .collect();
+        for k in 1..(n_timesteps - 1) {
+            self.step(k);
+            if checkpoint_ks.get(self.partial_fitnesses.len()) == Some(&k) && self.prune() {
+                break;
+            }
+        }
+        (self.fills.clone(), self.equities.clone())
+    }
+
+    fn init_trailing_prices(&mut self) {
+        for idx in 0..self.n_coins as SymbolIdx {
             self.trailing_prices
                 .long
                 .insert(idx, TrailingPriceBundle::default());
@@ -353,60 +703,481 @@ impl<'a> Backtest<'a> {
                 .short
                 .insert(idx, TrailingPriceBundle::default());
         }
+    }
 
-        // --- find first & last valid candle for every coin (binary-search) ---
-        let (first_valid, last_valid) = find_valid_timestamp_bounds(&self.hlcvs);
-        for idx in 0..self.n_coins {
-            self.first_valid_timestamps.insert(idx, first_valid[idx]);
-            if n_timesteps - last_valid[idx] > 1400 {
-                // add only if delisted more than one day before last timestamp
-                self.last_valid_timestamps.insert(idx, last_valid[idx]); // keep same name for callers
+    /// Candle k: fills against the orders left by candle k - 1, then EMAs, balance, the
+    /// next orders and equity as of candle k's close. Peeks at candle k + 1, which must be
+    /// there, to decide whether to lay out full ladders.
+    pub fn step(&mut self, k: usize) {
+        let n_fills = self.fills.len();
+        let cash_flowed = self.apply_cash_flows(k);
+        self.switch_modes(k);
+        let reduce_only_changed = self.update_reduce_only(k);
+        self.check_for_fills(k);
+        if self.neutral_mode {
+            self.rebalance_neutral(k, n_fills);
+        }
+        if self.backtest_params.funding_rate != 0.0 && k.is_multiple_of(FUNDING_INTERVAL) {
+            self.settle_funding(k);
+        }
+        self.update_emas(k);
+        let mut balance_changed = cash_flowed;
+        if self.balance.use_btc_collateral {
+            self.balance.usd_total = (self.balance.btc * self.btc_usd_prices[k]) + self.balance.usd;
+            self.balance.btc_total = self.balance.usd_total / self.btc_usd_prices[k];
+            let new_usd_total_rounded = hysteresis_rounding(
+                self.balance.usd_total,
+                self.balance.usd_total_rounded,
+                0.02,
+                0.5,
+            );
+            if new_usd_total_rounded != self.balance.usd_total_rounded {
+                balance_changed = true;
             }
+            self.balance.usd_total_rounded = new_usd_total_rounded;
+        }
+        if self.update_equity_drawdown_triggered() {
+            // every close ladder switches between the normal and the drawdown grid
+            balance_changed = true;
+        }
+        if reduce_only_changed {
+            // entries come or go and every close ladder follows the markup scale
+            balance_changed = true;
+        }
+        if balance_changed || !self.did_fill_long.is_empty() || !self.did_fill_short.is_empty() {
+            self.update_open_orders_any_fill(k);
+        } else {
+            self.update_open_orders_no_fill(k);
+        }
+        if let Some(tracker) = self.order_lifetimes.as_mut() {
+            // coins dropped from the actives lose their orders without an update
+            let open_orders = &self.open_orders;
+            tracker.retain(k, |idx, pside| match pside {
+                LONG => open_orders.long.contains_key(&idx),
+                _ => open_orders.short.contains_key(&idx),
+            });
         }
+        self.end_wind_downs(k);
+        self.update_equities(k);
+    }
 
-        for k in 1..(n_timesteps - 1) {
-            self.check_for_fills(k);
-            self.update_emas(k);
-            let mut balance_changed = false;
+    /// Re-evaluates close_on_equity_drawdown_pct per pside; true if either side flipped.
+    fn update_equity_drawdown_triggered(&mut self) -> bool {
+        let state_params = StateParams {
+            equity: self.equity,
+            peak_equity: self.peak_equity,
+            ..Default::default()
+        };
+        let triggered = [
+            self.bot_params_pair
+                .long
+                .equity_drawdown_triggered(&state_params),
+            self.bot_params_pair
+                .short
+                .equity_drawdown_triggered(&state_params),
+        ];
+        let flipped = triggered != self.equity_drawdown_triggered;
+        self.equity_drawdown_triggered = triggered;
+        flipped
+    }
+
+    /// Applies the cash flows due by candle k to the usd balance; true if any was. Peak
+    /// equity moves with them, so withdrawals don't read as drawdowns.
+    fn apply_cash_flows(&mut self, k: usize) -> bool {
+        let mut applied = false;
+        while let Some(&flow) = self.cash_flows.last().filter(|flow| flow.k <= k) {
+            self.cash_flows.pop();
+            self.balance.usd += flow.amount;
             if self.balance.use_btc_collateral {
                 self.balance.usd_total =
                     (self.balance.btc * self.btc_usd_prices[k]) + self.balance.usd;
                 self.balance.btc_total = self.balance.usd_total / self.btc_usd_prices[k];
-                let new_usd_total_rounded = hysteresis_rounding(
-                    self.balance.usd_total,
-                    self.balance.usd_total_rounded,
-                    0.02,
-                    0.5,
-                );
-                if new_usd_total_rounded != self.balance.usd_total_rounded {
-                    balance_changed = true;
+            } else {
+                self.balance.usd_total = self.balance.usd;
+                self.balance.usd_total_rounded = self.balance.usd;
+            }
+            self.peak_equity = (self.peak_equity + flow.amount).max(0.0);
+            applied = true;
+        }
+        applied
+    }
+
+    /// Enters the reduce-only regime once equity's drawdown from peak reaches
+    /// reduce_only_drawdown_pct and leaves it once the drawdown is back below
+    /// reduce_only_recovery_pct; true on candles the regime is or was active, as its close
+    /// markups follow the drawdown.
+    fn update_reduce_only(&mut self, k: usize) -> bool {
+        let trip = self.backtest_params.reduce_only_drawdown_pct;
+        if trip <= 0.0 || self.peak_equity <= 0.0 {
+            return false;
+        }
+        let drawdown = 1.0 - self.equity / self.peak_equity;
+        let recovery = self.backtest_params.reduce_only_recovery_pct.min(trip);
+        match self.reduce_only_periods.last_mut() {
+            Some(period) if period.end.is_none() => {
+                if drawdown < recovery {
+                    period.end = Some(k);
+                } else {
+                    period.drawdown_max = period.drawdown_max.max(drawdown);
                 }
-                self.balance.usd_total_rounded = new_usd_total_rounded;
+                true
             }
-            if balance_changed || !self.did_fill_long.is_empty() || !self.did_fill_short.is_empty()
-            {
-                self.update_open_orders_any_fill(k);
-            } else {
-                self.update_open_orders_no_fill(k);
+            _ if drawdown >= trip => {
+                self.reduce_only_periods.push(ReduceOnlyPeriod {
+                    start: k,
+                    end: None,
+                    drawdown_max: drawdown,
+                });
+                true
             }
-            self.update_equities(k);
+            _ => false,
+        }
+    }
+
+    fn reduce_only_active(&self) -> bool {
+        self.reduce_only_periods
+            .last()
+            .is_some_and(|period| period.end.is_none())
+    }
+
+    /// Share of the grid close markups kept in the reduce-only regime: 0.0, i.e. breakeven,
+    /// at reduce_only_drawdown_pct and beyond, rising linearly to 1.0 at
+    /// reduce_only_recovery_pct.
+    fn reduce_only_markup_scale(&self) -> f64 {
+        let trip = self.backtest_params.reduce_only_drawdown_pct;
+        let recovery = self.backtest_params.reduce_only_recovery_pct.min(trip);
+        if trip <= recovery {
+            return 0.0;
+        }
+        let drawdown = 1.0 - self.equity / self.peak_equity;
+        ((trip - drawdown) / (trip - recovery)).clamp(0.0, 1.0)
+    }
+
+    /// Applies the mode switches due by candle k. A switch out of normal mode starts a
+    /// wind-down; into panic, it closes the side's positions at candle k's close.
+    fn switch_modes(&mut self, k: usize) {
+        while let Some(&switch) = self.mode_schedule.last().filter(|switch| switch.k <= k) {
+            self.mode_schedule.pop();
+            let psides = match switch.pside {
+                Some(pside) => vec![pside],
+                None => vec![LONG, SHORT],
+            };
+            for pside in psides {
+                self.trading_modes[pside] = switch.mode;
+                self.active_wind_downs[pside] = None;
+                if switch.mode == TradingMode::Normal {
+                    continue;
+                }
+                let marks: HashMap<SymbolIdx, f64> = match pside {
+                    LONG => &self.positions.long,
+                    _ => &self.positions.short,
+                }
+                .keys()
+                .map(|&idx| (idx, self.hlcvs[[k, idx as usize, CLOSE]]))
+                .collect();
+                self.wind_downs.push(WindDown {
+                    pside,
+                    mode: switch.mode,
+                    k,
+                    n_positions: marks.len(),
+                    duration: marks.is_empty().then_some(0),
+                    slippage_pct: 0.0,
+                });
+                if !marks.is_empty() {
+                    self.active_wind_downs[pside] = Some(ActiveWindDown {
+                        i: self.wind_downs.len() - 1,
+                        marks,
+                        marked: 0.0,
+                        filled: 0.0,
+                    });
+                }
+                if switch.mode == TradingMode::Panic {
+                    self.panic_close(k, pside);
+                }
+            }
+        }
+    }
+
+    fn panic_close(&mut self, k: usize, pside: usize) {
+        let mut indices = std::mem::take(&mut self.positions_idx_buffer);
+        match pside {
+            LONG => {
+                collect_sorted(&mut indices, self.positions.long.keys());
+                self.open_orders.long.clear();
+            }
+            _ => {
+                collect_sorted(&mut indices, self.positions.short.keys());
+                self.open_orders.short.clear();
+            }
+        }
+        for &idx in &indices {
+            let price = self.hlcvs[[k, idx as usize, CLOSE]];
+            let order = match pside {
+                LONG => Order::new(
+                    -self.positions.long[&idx].size,
+                    price,
+                    OrderType::ClosePanicLong,
+                ),
+                _ => Order::new(
+                    -self.positions.short[&idx].size,
+                    price,
+                    OrderType::ClosePanicShort,
+                ),
+            };
+            let order = self.slipped(order);
+            match pside {
+                LONG => {
+                    self.did_fill_long.insert(idx);
+                    self.process_close_fill_long(k, idx, &order);
+                }
+                _ => {
+                    self.did_fill_short.insert(idx);
+                    self.process_close_fill_short(k, idx, &order);
+                }
+            }
+        }
+        self.positions_idx_buffer = indices;
+    }
+
+    // values a close fill of a position held at its side's switch against the switch's mark
+    fn track_wind_down(&mut self, idx: SymbolIdx, pside: usize, qty: f64, price: f64) {
+        let Some(active) = self.active_wind_downs[pside].as_mut() else {
+            return;
+        };
+        let Some(&mark) = active.marks.get(&idx) else {
+            return;
+        };
+        let c_mult = self.exchange_params_list[idx as usize].c_mult;
+        active.marked += qty_to_cost(qty, mark, c_mult);
+        active.filled += qty_to_cost(qty, price, c_mult);
+        let worse = if pside == LONG {
+            active.marked - active.filled
+        } else {
+            active.filled - active.marked
+        };
+        self.wind_downs[active.i].slippage_pct = worse / active.marked;
+    }
+
+    // a wind-down ends once its side is flat
+    fn end_wind_downs(&mut self, k: usize) {
+        for pside in [LONG, SHORT] {
+            let flat = match pside {
+                LONG => self.positions.long.is_empty(),
+                _ => self.positions.short.is_empty(),
+            };
+            if flat {
+                if let Some(active) = self.active_wind_downs[pside].take() {
+                    let wind_down = &mut self.wind_downs[active.i];
+                    wind_down.duration = Some(k - wind_down.k);
+                }
+            }
+        }
+    }
+
+    /// Appends the next candle of a stepwise backtest ([coin, HIGH..=VOLUME]) and returns
+    /// its k, to be passed to step. Unlike run, which closes out the positions of coins whose
+    /// data ends over a day before the backtest's, a stepwise backtest cannot know a coin's
+    /// data has ended, so never treats it as delisted.
+    pub fn push_candles(&mut self, candles: ArrayView2<f64>) -> Result<usize, String> {
+        let mut hlcvs =
+            std::mem::replace(&mut self.hlcvs, CowArray::from(Array3::zeros((0, 0, 0))))
+                .into_owned();
+        let pushed = hlcvs.push(Axis(0), candles).map_err(|e| e.to_string());
+        self.hlcvs = CowArray::from(hlcvs);
+        pushed?;
+        let mut btc_usd_prices =
+            std::mem::replace(&mut self.btc_usd_prices, CowArray::from(Array1::zeros(0)))
+                .into_owned();
+        btc_usd_prices
+            .push(Axis(0), ndarray::aview0(&1.0))
+            .expect("1D push");
+        self.btc_usd_prices = CowArray::from(btc_usd_prices);
+        Ok(self.hlcvs.shape()[0] - 1)
+    }
+
+    pub fn fills(&self) -> &[Fill] {
+        &self.fills
+    }
+
+    /// Open entries then closes of the pside position on coin idx, as of the last step.
+    pub fn open_orders(&self, idx: SymbolIdx, pside: usize) -> Vec<Order> {
+        let open_orders = match pside {
+            LONG => &self.open_orders.long,
+            SHORT => &self.open_orders.short,
+            _ => panic!("Invalid pside"),
+        };
+        open_orders.get(&idx).map_or_else(Vec::new, |bundle| {
+            bundle
+                .entries
+                .iter()
+                .chain(&bundle.closes)
+                .copied()
+                .collect()
+        })
+    }
+
+    fn prune(&mut self) -> bool {
+        let partial_fitness =
+            calc_partial_fitness(&self.equities.usd, self.backtest_params.starting_balance);
+        let threshold = self
+            .prune_params
+            .thresholds
+            .get(self.partial_fitnesses.len())
+            .copied()
+            .unwrap_or(f64::NAN);
+        self.partial_fitnesses.push(partial_fitness);
+        // a NaN partial fitness cannot compete either
+        self.pruned =
+            !threshold.is_nan() && (partial_fitness.is_nan() || partial_fitness > threshold);
+        self.pruned
+    }
+
+    /// Middle of the three entry spans, i.e. sqrt(ema_span_0 * ema_span_1).
+    fn trailing_ma(&self, idx: SymbolIdx, pside: usize) -> f64 {
+        self.emas[idx as usize].side(pside)[self.ema_alphas.side(pside).entry[1]]
+    }
+
+    // the true balance still feeds unstuck allowances and equity
+    fn allocated_balance(&self, pside: usize) -> f64 {
+        self.bot_params_pair
+            .allocated_balance(self.balance.usd_total_rounded, pside)
+    }
+
+    /// wallet_exposure_limit of the coin's side, lowered to its max_position_cost where that
+    /// binds at the side's allocated balance.
+    fn capped_wallet_exposure_limit(&self, idx: SymbolIdx, pside: usize) -> f64 {
+        let bot_params_pair = &self.entry_bot_params_list[idx as usize];
+        let bot_params = if pside == LONG {
+            &bot_params_pair.long
+        } else {
+            &bot_params_pair.short
+        };
+        bot_params.capped_wallet_exposure_limit(self.allocated_balance(pside))
+    }
+
+    fn cost_cap_binds(&self, idx: SymbolIdx, pside: usize) -> bool {
+        let bot_params_pair = &self.entry_bot_params_list[idx as usize];
+        let wallet_exposure_limit = if pside == LONG {
+            bot_params_pair.long.wallet_exposure_limit
+        } else {
+            bot_params_pair.short.wallet_exposure_limit
+        };
+        self.capped_wallet_exposure_limit(idx, pside) < wallet_exposure_limit
+    }
+
+    /// With a per-side allocation or a max_position_cost, crops an entry fill so the position
+    /// stays within the capped wallet_exposure_limit of the side's allocated balance, which
+    /// may have shrunk since the order was placed. None if less than the minimum qty is left.
+    fn crop_entry_fill(&self, idx: SymbolIdx, pside: usize, order: Order) -> Option<Order> {
+        let bot_params_pair = &self.entry_bot_params_list[idx as usize];
+        let (allocation_pct, bot_params, position) = if pside == LONG {
+            (
+                bot_params_pair.long_allocation_pct,
+                &bot_params_pair.long,
+                self.positions.long.get(&idx),
+            )
+        } else {
+            (
+                bot_params_pair.short_allocation_pct,
+                &bot_params_pair.short,
+                self.positions.short.get(&idx),
+            )
+        };
+        if allocation_pct <= 0.0 && bot_params.max_position_cost <= 0.0 {
+            return Some(order);
+        }
+        let exchange_params = &self.exchange_params_list[idx as usize];
+        let max_cost = self.allocated_balance(pside)
+            * self.capped_wallet_exposure_limit(idx, pside)
+            * WALLET_EXPOSURE_LEEWAY;
+        let position_cost = position.map_or(0.0, |position| {
+            qty_to_cost(position.size, position.price, exchange_params.c_mult)
+        });
+        let max_qty = round_dn(
+            cost_to_qty(
+                (max_cost - position_cost).max(0.0),
+                order.price,
+                exchange_params.c_mult,
+            ),
+            exchange_params.qty_step,
+        );
+        if order.qty.abs() <= max_qty {
+            Some(order)
+        } else if max_qty >= calc_min_entry_qty(order.price, exchange_params) {
+            Some(Order {
+                qty: max_qty.copysign(order.qty),
+                ..order
+            })
+        } else {
+            None
         }
-        (self.fills.clone(), self.equities.clone())
     }
 
-    fn create_state_params(&self, k: usize, idx: usize, pside: usize) -> StateParams {
-        let close_price = self.hlcvs[[k, idx, CLOSE]];
+    fn create_state_params(&self, k: usize, idx: SymbolIdx, pside: usize) -> StateParams {
+        let close_price = self.hlcvs[[k, idx as usize, CLOSE]];
+        let alphas = self.ema_alphas.side(pside);
         StateParams {
-            balance: self.balance.usd_total_rounded,
-            order_book: OrderBook {
-                bid: close_price,
-                ask: close_price,
-            },
-            ema_bands: self.emas[idx].compute_bands(pside),
+            balance: self.allocated_balance(pside),
+            order_book: OrderBook::new(close_price, close_price),
+            ema_bands: self.emas[idx as usize]
+                .compute_bands(pside, alphas, alphas.entry)
+                .bands(),
+            trailing_ma: self.trailing_ma(idx, pside),
+            volume: self.hlcvs[[k, idx as usize, VOLUME]],
+            avg_volume: self.calc_avg_volume(k, idx, pside),
+            // no external target feed in backtests
+            timestamp: 0,
+            target_price: None,
+            hour: (self.backtest_params.start_hour + k / 60) % 24,
+            // realized pnl is already in balance
+            realized_pnl: 0.0,
+            equity: self.equity,
+            peak_equity: self.peak_equity,
+            min_markup_floor: self.markup_floors[idx as usize],
+            // candles carry no taker flow
+            order_flow_imbalance: None,
+            close_markup_scale: self
+                .reduce_only_active()
+                .then(|| self.reduce_only_markup_scale()),
+        }
+    }
+
+    /// state_params as the close calculators see them: with a balance curve, balance is the
+    /// curve's at candle k, allocated like the running balance.
+    fn close_state_params(
+        &self,
+        k: usize,
+        pside: usize,
+        state_params: &StateParams,
+    ) -> StateParams {
+        let mut close_state_params = state_params.clone();
+        if let Some(balance_curve) = self.balance_curve {
+            close_state_params.balance = self
+                .bot_params_pair
+                .allocated_balance(balance_curve(k), pside);
+        }
+        close_state_params
+    }
+
+    /// Mean candle volume over the side's filter_volume_rolling_window up to k; only closes
+    /// capped by volume use it, so it is 0.0 otherwise.
+    fn calc_avg_volume(&self, k: usize, idx: SymbolIdx, pside: usize) -> f64 {
+        let bot_params = match pside {
+            LONG => &self.bot_params_pair.long,
+            SHORT => &self.bot_params_pair.short,
+            _ => panic!("Invalid pside"),
+        };
+        if bot_params.close_max_qty_pct_of_volume <= 0.0 {
+            return 0.0;
         }
+        let start_k = (k + 1).saturating_sub(bot_params.filter_volume_rolling_window.max(1));
+        self.hlcvs
+            .slice(s![start_k..=k, idx as usize, VOLUME])
+            .mean()
+            .unwrap_or(0.0)
     }
 
-    fn get_position(&self, idx: usize, pside: usize) -> Position {
+    fn get_position(&self, idx: SymbolIdx, pside: usize) -> Position {
         match pside {
             LONG => self.positions.long.get(&idx).cloned().unwrap_or_default(),
             SHORT => self.positions.short.get(&idx).cloned().unwrap_or_default(),
@@ -456,42 +1227,108 @@ impl<'a> Backtest<'a> {
         let mut equity_btc = self.balance.btc_total;
 
         // Add the unrealized PNL of all positions
-        let mut long_keys: Vec<usize> = self.positions.long.keys().cloned().collect();
-        long_keys.sort();
-        for idx in long_keys {
+        let mut indices = std::mem::take(&mut self.positions_idx_buffer);
+        collect_sorted(&mut indices, self.positions.long.keys());
+        for &idx in &indices {
             let position = &self.positions.long[&idx];
-            let current_price = self.hlcvs[[k, idx, CLOSE]];
+            let current_price = self.hlcvs[[k, idx as usize, CLOSE]];
             let upnl = calc_pnl_long(
                 position.price,
                 current_price,
                 position.size,
-                self.exchange_params_list[idx].c_mult,
+                self.exchange_params_list[idx as usize].c_mult,
             );
             equity_usd += upnl;
             equity_btc += upnl / self.btc_usd_prices[k];
         }
 
-        let mut short_keys: Vec<usize> = self.positions.short.keys().cloned().collect();
-        short_keys.sort();
-        for idx in short_keys {
+        collect_sorted(&mut indices, self.positions.short.keys());
+        for &idx in &indices {
             let position = &self.positions.short[&idx];
-            let current_price = self.hlcvs[[k, idx, CLOSE]];
+            let current_price = self.hlcvs[[k, idx as usize, CLOSE]];
             let upnl = calc_pnl_short(
                 position.price,
                 current_price,
                 position.size,
-                self.exchange_params_list[idx].c_mult,
+                self.exchange_params_list[idx as usize].c_mult,
             );
             equity_usd += upnl;
             equity_btc += upnl / self.btc_usd_prices[k];
         }
+        self.positions_idx_buffer = indices;
+        self.equity = equity_usd;
+        self.peak_equity = self.peak_equity.max(equity_usd);
 
         // Finally push the results into the Equities struct
-        self.equities.usd.push(equity_usd);
-        self.equities.btc.push(equity_btc);
+        let candle = CandleSnapshot {
+            k,
+            equity_usd,
+            equity_btc,
+        };
+        self.equities.on_candle(&candle);
+        for observer in self.observers.iter_mut() {
+            observer.on_candle(&candle);
+        }
+    }
+
+    fn record_fill(&mut self, fill: Fill) {
+        self.fills.on_fill(&fill);
+        if let Some(tracker) = self.lots.as_mut() {
+            tracker.on_fill(&fill);
+        }
+        for observer in self.observers.iter_mut() {
+            observer.on_fill(&fill);
+        }
+    }
+
+    fn notify_order_update(&mut self, k: usize, idx: SymbolIdx, pside: usize) {
+        if self.observers.is_empty() && self.order_lifetimes.is_none() {
+            return;
+        }
+        let open_orders = match pside {
+            LONG => &self.open_orders.long,
+            SHORT => &self.open_orders.short,
+            _ => panic!("Invalid pside"),
+        };
+        let (entries, closes) = open_orders.get(&idx).map_or((&[][..], &[][..]), |bundle| {
+            (&bundle.entries[..], &bundle.closes[..])
+        });
+        if let Some(tracker) = self.order_lifetimes.as_mut() {
+            let exchange_params = &self.exchange_params_list[idx as usize];
+            tracker.update(k, idx, pside, entries.iter().chain(closes), exchange_params);
+        }
+        for observer in self.observers.iter_mut() {
+            observer.on_order_update(k, idx, pside, entries, closes);
+        }
     }
 
-    fn update_actives(&mut self, k: usize, pside: usize) -> Vec<usize> {
+    fn track_order_fill(&mut self, k: usize, idx: SymbolIdx, pside: usize, order: &Order) {
+        if let Some(tracker) = self.order_lifetimes.as_mut() {
+            tracker.fill(
+                k,
+                idx,
+                pside,
+                order,
+                &self.exchange_params_list[idx as usize],
+            );
+        }
+    }
+
+    fn update_actives(&mut self, k: usize, pside: usize) -> Vec<SymbolIdx> {
+        if pside == SHORT && self.neutral_mode {
+            // shorts pair up with the longs' coins
+            let mut actives_without_pos: Vec<SymbolIdx> = self
+                .actives
+                .long
+                .iter()
+                .filter(|idx| !self.positions.short.contains_key(idx))
+                .copied()
+                .collect();
+            actives_without_pos.sort();
+            self.actives.short = self.actives.long.clone();
+            self.actives.short.extend(self.positions.short.keys());
+            return actives_without_pos;
+        }
         // Calculate all the information we need before borrowing
         let (positions, n_positions) = match pside {
             LONG => (&self.positions.long, self.bot_params_pair.long.n_positions),
@@ -503,7 +1340,7 @@ impl<'a> Backtest<'a> {
         };
 
         // Sort positions to ensure stable iteration
-        let mut current_positions: Vec<usize> = positions.keys().cloned().collect();
+        let mut current_positions: Vec<SymbolIdx> = positions.keys().cloned().collect();
         current_positions.sort();
         let mut preferred_coins = Vec::new();
 
@@ -511,6 +1348,10 @@ impl<'a> Backtest<'a> {
         if current_positions.len() < n_positions {
             preferred_coins = self.calc_preferred_coins(k, pside);
         }
+        match pside {
+            LONG => self.slot_ranking_long.clone_from(&preferred_coins),
+            _ => self.slot_ranking_short.clone_from(&preferred_coins),
+        }
 
         // Now we can mutably borrow self.actives
         let actives = match pside {
@@ -545,11 +1386,11 @@ impl<'a> Backtest<'a> {
     fn check_for_fills(&mut self, k: usize) {
         self.did_fill_long.clear();
         self.did_fill_short.clear();
+        let mut indices = std::mem::take(&mut self.orders_idx_buffer);
         if self.trading_enabled.long {
-            let mut open_orders_keys_long: Vec<usize> =
-                self.open_orders.long.keys().cloned().collect();
-            open_orders_keys_long.sort();
-            for idx in open_orders_keys_long {
+            collect_sorted(&mut indices, self.open_orders.long.keys());
+            let refused = self.refused_initial_entries(k, LONG, &indices);
+            for &idx in &indices {
                 // Process close fills long
                 if !self.open_orders.long[&idx].closes.is_empty() {
                     let mut closes_to_process = Vec::new();
@@ -565,13 +1406,34 @@ impl<'a> Backtest<'a> {
                         //if order.qty != 0.0 && self.get_position
                         if self.positions.long.contains_key(&idx) {
                             self.did_fill_long.insert(idx);
-                            self.reset_trailing_prices(idx, LONG);
+                            if order.order_type == OrderType::CloseTrailingFibLong {
+                                // keep the peak; the next fib level retraces from it
+                                self.trailing_prices
+                                    .long
+                                    .entry(idx)
+                                    .or_default()
+                                    .fib_levels_closed += 1;
+                            } else if self.recenters_trailing(idx, LONG, &order) {
+                                *self.trailing_prices.long.entry(idx).or_default() =
+                                    TrailingPriceBundle::centered(order.price);
+                            } else {
+                                self.reset_trailing_prices(idx, LONG);
+                            }
+                            if order.order_type == OrderType::CloseUnstuckLong {
+                                self.last_unstucked = Some((idx, LONG));
+                            }
+                            self.track_order_fill(k, idx, LONG, &order);
+                            let order = self.slipped(order);
                             self.process_close_fill_long(k, idx, &order);
                         }
                     }
                 }
                 // Process entry fills long
-                if !self.open_orders.long[&idx].entries.is_empty() {
+                if self.trading_modes[LONG] == TradingMode::Normal
+                    && !self.reduce_only_active()
+                    && !refused.contains(&idx)
+                    && !self.open_orders.long[&idx].entries.is_empty()
+                {
                     let mut entries_to_process = Vec::new();
                     {
                         for entry_order in &self.open_orders.long[&idx].entries {
@@ -581,18 +1443,25 @@ impl<'a> Backtest<'a> {
                         }
                     }
                     for order in entries_to_process {
+                        let Some(order) = self.crop_entry_fill(idx, LONG, order) else {
+                            continue;
+                        };
+                        if self.cost_cap_binds(idx, LONG) {
+                            self.cost_capped_fills.push((k, idx, LONG));
+                        }
                         self.did_fill_long.insert(idx);
                         self.reset_trailing_prices(idx, LONG);
+                        self.track_order_fill(k, idx, LONG, &order);
+                        let order = self.slipped(order);
                         self.process_entry_fill_long(k, idx, &order);
                     }
                 }
             }
         }
         if self.trading_enabled.short {
-            let mut open_orders_keys_short: Vec<usize> =
-                self.open_orders.short.keys().cloned().collect();
-            open_orders_keys_short.sort();
-            for idx in open_orders_keys_short {
+            collect_sorted(&mut indices, self.open_orders.short.keys());
+            let refused = self.refused_initial_entries(k, SHORT, &indices);
+            for &idx in &indices {
                 // Process close fills short
                 if !self.open_orders.short[&idx].closes.is_empty() {
                     let mut closes_to_process = Vec::new();
@@ -606,13 +1475,34 @@ impl<'a> Backtest<'a> {
                     for order in closes_to_process {
                         if self.positions.short.contains_key(&idx) {
                             self.did_fill_short.insert(idx);
-                            self.reset_trailing_prices(idx, SHORT);
+                            if order.order_type == OrderType::CloseTrailingFibShort {
+                                // keep the peak; the next fib level retraces from it
+                                self.trailing_prices
+                                    .short
+                                    .entry(idx)
+                                    .or_default()
+                                    .fib_levels_closed += 1;
+                            } else if self.recenters_trailing(idx, SHORT, &order) {
+                                *self.trailing_prices.short.entry(idx).or_default() =
+                                    TrailingPriceBundle::centered(order.price);
+                            } else {
+                                self.reset_trailing_prices(idx, SHORT);
+                            }
+                            if order.order_type == OrderType::CloseUnstuckShort {
+                                self.last_unstucked = Some((idx, SHORT));
+                            }
+                            self.track_order_fill(k, idx, SHORT, &order);
+                            let order = self.slipped(order);
                             self.process_close_fill_short(k, idx, &order);
                         }
                     }
                 }
                 // Process entry fills short
-                if !self.open_orders.short[&idx].entries.is_empty() {
+                if self.trading_modes[SHORT] == TradingMode::Normal
+                    && !self.reduce_only_active()
+                    && !refused.contains(&idx)
+                    && !self.open_orders.short[&idx].entries.is_empty()
+                {
                     let mut entries_to_process = Vec::new();
                     {
                         for entry_order in &self.open_orders.short[&idx].entries {
@@ -622,26 +1512,130 @@ impl<'a> Backtest<'a> {
                         }
                     }
                     for order in entries_to_process {
+                        let Some(order) = self.crop_entry_fill(idx, SHORT, order) else {
+                            continue;
+                        };
+                        if self.cost_cap_binds(idx, SHORT) {
+                            self.cost_capped_fills.push((k, idx, SHORT));
+                        }
                         self.did_fill_short.insert(idx);
                         self.reset_trailing_prices(idx, SHORT);
+                        self.track_order_fill(k, idx, SHORT, &order);
+                        let order = self.slipped(order);
                         self.process_entry_fill_short(k, idx, &order);
                     }
                 }
             }
         }
+        self.orders_idx_buffer = indices;
+    }
+
+    /// Restores the balance of neutral pairs filled on candle k, fills from n_fills on, at
+    /// candle k's close: pairs left lopsided by entries enter on the lagging side, pairs
+    /// left lopsided by closes close on the leading side.
+    fn rebalance_neutral(&mut self, k: usize, n_fills: usize) {
+        let mut indices = std::mem::take(&mut self.orders_idx_buffer);
+        collect_sorted(&mut indices, self.did_fill_long.union(&self.did_fill_short));
+        for &idx in &indices {
+            let coin = &self.backtest_params.coins[idx as usize];
+            let (entered, closed) = self.fills[n_fills..]
+                .iter()
+                .filter(|fill| &fill.coin == coin)
+                .fold((0.0, 0.0), |(entered, closed), fill| {
+                    if fill.order_type.is_close() {
+                        (entered, closed + fill.fill_qty.abs())
+                    } else {
+                        (entered + fill.fill_qty.abs(), closed)
+                    }
+                });
+            let position_long = self.get_position(idx, LONG);
+            let position_short = self.get_position(idx, SHORT);
+            let exchange_params = &self.exchange_params_list[idx as usize];
+            let price = self.hlcvs[[k, idx as usize, CLOSE]];
+            let rebalance = if closed > entered {
+                calc_neutral_rebalance_close(
+                    exchange_params,
+                    &self.bot_params_pair,
+                    &position_long,
+                    &position_short,
+                    price,
+                )
+            } else {
+                calc_neutral_rebalance_entry(
+                    exchange_params,
+                    &self.bot_params_pair,
+                    &position_long,
+                    &position_short,
+                    price,
+                )
+            };
+            let Some(order) = rebalance else {
+                continue;
+            };
+            let pside = order.order_type.pside();
+            if !order.order_type.is_close()
+                && (self.trading_modes[pside] != TradingMode::Normal
+                    || self.reduce_only_active()
+                    || !self.trading_masks[idx as usize].enabled(pside))
+            {
+                continue;
+            }
+            let order = self.slipped(order);
+            match (order.order_type.pside(), order.order_type.is_close()) {
+                (LONG, true) => self.process_close_fill_long(k, idx, &order),
+                (LONG, false) => self.process_entry_fill_long(k, idx, &order),
+                (_, true) => self.process_close_fill_short(k, idx, &order),
+                (_, false) => self.process_entry_fill_short(k, idx, &order),
+            }
+            match order.order_type.pside() {
+                LONG => self.did_fill_long.insert(idx),
+                _ => self.did_fill_short.insert(idx),
+            };
+        }
+        self.orders_idx_buffer = indices;
     }
 
-    fn update_stuck_status(&mut self, idx: usize, pside: usize) {
+    /// Funding settlement at candle k's close prices: longs pay shorts funding_rate of
+    /// their notional, or receive it if negative. Payments accrue on the positions.
+    fn settle_funding(&mut self, k: usize) {
+        let mut indices = std::mem::take(&mut self.positions_idx_buffer);
+        let mut paid = 0.0;
+        for pside in [LONG, SHORT] {
+            let positions = match pside {
+                LONG => &mut self.positions.long,
+                _ => &mut self.positions.short,
+            };
+            collect_sorted(&mut indices, positions.keys());
+            for idx in &indices {
+                let position = positions.get_mut(idx).unwrap();
+                let notional = qty_to_cost(
+                    position.size,
+                    self.hlcvs[[k, *idx as usize, CLOSE]],
+                    self.exchange_params_list[*idx as usize].c_mult,
+                );
+                let payment = match pside {
+                    LONG => notional * self.backtest_params.funding_rate,
+                    _ => -notional * self.backtest_params.funding_rate,
+                };
+                position.accrued_funding += payment;
+                paid += payment;
+            }
+        }
+        self.positions_idx_buffer = indices;
+        self.update_balance(k, -paid, 0.0);
+    }
+
+    fn update_stuck_status(&mut self, idx: SymbolIdx, pside: usize) {
         match pside {
             LONG => {
                 if self.positions.long.contains_key(&idx) {
                     let wallet_exposure = calc_wallet_exposure(
-                        self.exchange_params_list[idx].c_mult,
-                        self.balance.usd_total_rounded,
+                        self.exchange_params_list[idx as usize].c_mult,
+                        self.allocated_balance(LONG),
                         self.positions.long[&idx].size,
                         self.positions.long[&idx].price,
                     );
-                    if wallet_exposure / self.bot_params_pair.long.wallet_exposure_limit
+                    if wallet_exposure / self.capped_wallet_exposure_limit(idx, LONG)
                         > self.bot_params_pair.long.unstuck_threshold
                     {
                         self.is_stuck.long.insert(idx);
@@ -655,12 +1649,12 @@ impl<'a> Backtest<'a> {
             SHORT => {
                 if self.positions.short.contains_key(&idx) {
                     let wallet_exposure = calc_wallet_exposure(
-                        self.exchange_params_list[idx].c_mult,
-                        self.balance.usd_total_rounded,
+                        self.exchange_params_list[idx as usize].c_mult,
+                        self.allocated_balance(SHORT),
                         self.positions.short[&idx].size.abs(),
                         self.positions.short[&idx].price,
                     );
-                    if wallet_exposure / self.bot_params_pair.short.wallet_exposure_limit
+                    if wallet_exposure / self.capped_wallet_exposure_limit(idx, SHORT)
                         > self.bot_params_pair.short.unstuck_threshold
                     {
                         self.is_stuck.short.insert(idx);
@@ -675,15 +1669,16 @@ impl<'a> Backtest<'a> {
         }
     }
 
-    fn process_close_fill_long(&mut self, k: usize, idx: usize, close_fill: &Order) {
+    fn process_close_fill_long(&mut self, k: usize, idx: SymbolIdx, close_fill: &Order) {
+        let (close_fill, impact_pct) = self.impacted(k, idx, close_fill);
         let mut new_psize = round_(
             self.positions.long[&idx].size + close_fill.qty,
-            self.exchange_params_list[idx].qty_step,
+            self.exchange_params_list[idx as usize].qty_step,
         );
         let mut adjusted_close_qty = close_fill.qty;
         if new_psize < 0.0 {
             println!("warning: close qty greater than psize long");
-            println!("coin: {}", self.backtest_params.coins[idx]);
+            println!("coin: {}", self.backtest_params.coins[idx as usize]);
             println!("new_psize: {}", new_psize);
             println!("close order: {:?}", close_fill);
             println!("bot config: {:?}", self.bot_params_pair.long);
@@ -693,50 +1688,56 @@ impl<'a> Backtest<'a> {
         let fee_paid = -qty_to_cost(
             adjusted_close_qty,
             close_fill.price,
-            self.exchange_params_list[idx].c_mult,
+            self.exchange_params_list[idx as usize].c_mult,
         ) * self.backtest_params.maker_fee;
         let pnl = calc_pnl_long(
             self.positions.long[&idx].price,
             close_fill.price,
             adjusted_close_qty,
-            self.exchange_params_list[idx].c_mult,
+            self.exchange_params_list[idx as usize].c_mult,
         );
         self.pnl_cumsum_running += pnl;
         self.pnl_cumsum_max = self.pnl_cumsum_max.max(self.pnl_cumsum_running);
         self.update_balance(k, pnl, fee_paid);
+        self.track_wind_down(idx, LONG, adjusted_close_qty, close_fill.price);
 
         let current_pprice = self.positions.long[&idx].price;
         if new_psize == 0.0 {
             self.positions.long.remove(&idx);
         } else {
-            self.positions.long.get_mut(&idx).unwrap().size = new_psize;
-        }
-        self.fills.push(Fill {
-            index: k,                                      // index minute
-            coin: self.backtest_params.coins[idx].clone(), // coin
-            pnl,                                           // realized pnl
-            fee_paid,                                      // fee paid
-            balance_usd_total: self.balance.usd_total,     // balance after fill
-            balance_btc: self.balance.btc,                 // Added
-            balance_usd: self.balance.usd,                 // Added
-            btc_price: self.btc_usd_prices[k],             // Added
-            fill_qty: adjusted_close_qty,                  // fill qty
-            fill_price: close_fill.price,                  // fill price
-            position_size: new_psize,                      // psize after fill
-            position_price: current_pprice,                // pprice after fill
-            order_type: close_fill.order_type.clone(),     // fill type
+            let position = self.positions.long.get_mut(&idx).unwrap();
+            // what is left carries its share of the funding paid
+            position.accrued_funding *= new_psize / position.size;
+            position.size = new_psize;
+        }
+        self.record_fill(Fill {
+            index: k,                                               // index minute
+            coin: self.backtest_params.coins[idx as usize].clone(), // coin
+            pnl,                                                    // realized pnl
+            fee_paid,                                               // fee paid
+            balance_usd_total: self.balance.usd_total,              // balance after fill
+            balance_btc: self.balance.btc,                          // Added
+            balance_usd: self.balance.usd,                          // Added
+            btc_price: self.btc_usd_prices[k],                      // Added
+            fill_qty: adjusted_close_qty,                           // fill qty
+            fill_price: close_fill.price,                           // fill price
+            position_size: new_psize,                               // psize after fill
+            position_price: current_pprice,                         // pprice after fill
+            order_type: close_fill.order_type.clone(),              // fill type
+            impact_pct,                                             // market impact
         });
     }
 
-    fn process_close_fill_short(&mut self, k: usize, idx: usize, order: &Order) {
+    fn process_close_fill_short(&mut self, k: usize, idx: SymbolIdx, order: &Order) {
+        let (order, impact_pct) = self.impacted(k, idx, order);
         let mut new_psize = round_(
             self.positions.short[&idx].size + order.qty,
-            self.exchange_params_list[idx].qty_step,
+            self.exchange_params_list[idx as usize].qty_step,
         );
         let mut adjusted_close_qty = order.qty;
         if new_psize > 0.0 {
             println!("warning: close qty greater than psize short");
-            println!("coin: {}", self.backtest_params.coins[idx]);
+            println!("coin: {}", self.backtest_params.coins[idx as usize]);
             println!("new_psize: {}", new_psize);
             println!("close order: {:?}", order);
             new_psize = 0.0;
@@ -745,47 +1746,53 @@ impl<'a> Backtest<'a> {
         let fee_paid = -qty_to_cost(
             adjusted_close_qty,
             order.price,
-            self.exchange_params_list[idx].c_mult,
+            self.exchange_params_list[idx as usize].c_mult,
         ) * self.backtest_params.maker_fee;
         let pnl = calc_pnl_short(
             self.positions.short[&idx].price,
             order.price,
             adjusted_close_qty,
-            self.exchange_params_list[idx].c_mult,
+            self.exchange_params_list[idx as usize].c_mult,
         );
         self.pnl_cumsum_running += pnl;
         self.pnl_cumsum_max = self.pnl_cumsum_max.max(self.pnl_cumsum_running);
         self.update_balance(k, pnl, fee_paid);
+        self.track_wind_down(idx, SHORT, adjusted_close_qty, order.price);
 
         let current_pprice = self.positions.short[&idx].price;
         if new_psize == 0.0 {
             self.positions.short.remove(&idx);
         } else {
-            self.positions.short.get_mut(&idx).unwrap().size = new_psize;
-        }
-        self.fills.push(Fill {
-            index: k,                                      // index minute
-            coin: self.backtest_params.coins[idx].clone(), // coin
-            pnl,                                           // realized pnl
-            fee_paid,                                      // fee paid
-            balance_usd_total: self.balance.usd_total,     // balance after fill
-            balance_btc: self.balance.btc,                 // Added
-            balance_usd: self.balance.usd,                 // Added
-            btc_price: self.btc_usd_prices[k],             // Added
-            fill_qty: adjusted_close_qty,                  // fill qty
-            fill_price: order.price,                       // fill price
-            position_size: new_psize,                      // psize after fill
-            position_price: current_pprice,                // pprice after fill
-            order_type: order.order_type.clone(),          // fill type
+            let position = self.positions.short.get_mut(&idx).unwrap();
+            // what is left carries its share of the funding paid
+            position.accrued_funding *= new_psize / position.size;
+            position.size = new_psize;
+        }
+        self.record_fill(Fill {
+            index: k,                                               // index minute
+            coin: self.backtest_params.coins[idx as usize].clone(), // coin
+            pnl,                                                    // realized pnl
+            fee_paid,                                               // fee paid
+            balance_usd_total: self.balance.usd_total,              // balance after fill
+            balance_btc: self.balance.btc,                          // Added
+            balance_usd: self.balance.usd,                          // Added
+            btc_price: self.btc_usd_prices[k],                      // Added
+            fill_qty: adjusted_close_qty,                           // fill qty
+            fill_price: order.price,                                // fill price
+            position_size: new_psize,                               // psize after fill
+            position_price: current_pprice,                         // pprice after fill
+            order_type: order.order_type.clone(),                   // fill type
+            impact_pct,                                             // market impact
         });
     }
 
-    fn process_entry_fill_long(&mut self, k: usize, idx: usize, order: &Order) {
+    fn process_entry_fill_long(&mut self, k: usize, idx: SymbolIdx, order: &Order) {
+        let (order, impact_pct) = self.impacted(k, idx, order);
         // long entry fill
         let fee_paid = -qty_to_cost(
             order.qty,
             order.price,
-            self.exchange_params_list[idx].c_mult,
+            self.exchange_params_list[idx as usize].c_mult,
         ) * self.backtest_params.maker_fee;
         self.update_balance(k, 0.0, fee_paid);
 
@@ -799,33 +1806,35 @@ impl<'a> Backtest<'a> {
             position_entry.price,
             order.qty,
             order.price,
-            self.exchange_params_list[idx].qty_step,
+            self.exchange_params_list[idx as usize].qty_step,
         );
         self.positions.long.get_mut(&idx).unwrap().size = new_psize;
         self.positions.long.get_mut(&idx).unwrap().price = new_pprice;
-        self.fills.push(Fill {
-            index: k,                                        // index minute
-            coin: self.backtest_params.coins[idx].clone(),   // coin
-            pnl: 0.0,                                        // realized pnl
-            fee_paid,                                        // fee paid
-            balance_usd_total: self.balance.usd_total,       // balance after fill
-            balance_btc: self.balance.btc,                   // Added
-            balance_usd: self.balance.usd,                   // Added
-            btc_price: self.btc_usd_prices[k],               // Added
-            fill_qty: order.qty,                             // fill qty
-            fill_price: order.price,                         // fill price
-            position_size: self.positions.long[&idx].size,   // psize after fill
-            position_price: self.positions.long[&idx].price, // pprice after fill
-            order_type: order.order_type.clone(),            // fill type
+        self.record_fill(Fill {
+            index: k,                                               // index minute
+            coin: self.backtest_params.coins[idx as usize].clone(), // coin
+            pnl: 0.0,                                               // realized pnl
+            fee_paid,                                               // fee paid
+            balance_usd_total: self.balance.usd_total,              // balance after fill
+            balance_btc: self.balance.btc,                          // Added
+            balance_usd: self.balance.usd,                          // Added
+            btc_price: self.btc_usd_prices[k],                      // Added
+            fill_qty: order.qty,                                    // fill qty
+            fill_price: order.price,                                // fill price
+            position_size: self.positions.long[&idx].size,          // psize after fill
+            position_price: self.positions.long[&idx].price,        // pprice after fill
+            order_type: order.order_type.clone(),                   // fill type
+            impact_pct,                                             // market impact
         });
     }
 
-    fn process_entry_fill_short(&mut self, k: usize, idx: usize, order: &Order) {
+    fn process_entry_fill_short(&mut self, k: usize, idx: SymbolIdx, order: &Order) {
+        let (order, impact_pct) = self.impacted(k, idx, order);
         // short entry fill
         let fee_paid = -qty_to_cost(
             order.qty,
             order.price,
-            self.exchange_params_list[idx].c_mult,
+            self.exchange_params_list[idx as usize].c_mult,
         ) * self.backtest_params.maker_fee;
         self.update_balance(k, 0.0, fee_paid);
         let position_entry = self
@@ -838,80 +1847,111 @@ impl<'a> Backtest<'a> {
             position_entry.price,
             order.qty,
             order.price,
-            self.exchange_params_list[idx].qty_step,
+            self.exchange_params_list[idx as usize].qty_step,
         );
         self.positions.short.get_mut(&idx).unwrap().size = new_psize;
         self.positions.short.get_mut(&idx).unwrap().price = new_pprice;
-        self.fills.push(Fill {
-            index: k,                                         // index minute
-            coin: self.backtest_params.coins[idx].clone(),    // coin
-            pnl: 0.0,                                         // realized pnl
-            fee_paid,                                         // fee paid
-            balance_usd_total: self.balance.usd_total,        // balance after fill
-            balance_btc: self.balance.btc,                    // Added
-            balance_usd: self.balance.usd,                    // Added
-            btc_price: self.btc_usd_prices[k],                // Added
-            fill_qty: order.qty,                              // fill qty
-            fill_price: order.price,                          // fill price
-            position_size: self.positions.short[&idx].size,   // psize after fill
-            position_price: self.positions.short[&idx].price, // pprice after fill
-            order_type: order.order_type.clone(),             // fill type
+        self.record_fill(Fill {
+            index: k,                                               // index minute
+            coin: self.backtest_params.coins[idx as usize].clone(), // coin
+            pnl: 0.0,                                               // realized pnl
+            fee_paid,                                               // fee paid
+            balance_usd_total: self.balance.usd_total,              // balance after fill
+            balance_btc: self.balance.btc,                          // Added
+            balance_usd: self.balance.usd,                          // Added
+            btc_price: self.btc_usd_prices[k],                      // Added
+            fill_qty: order.qty,                                    // fill qty
+            fill_price: order.price,                                // fill price
+            position_size: self.positions.short[&idx].size,         // psize after fill
+            position_price: self.positions.short[&idx].price,       // pprice after fill
+            order_type: order.order_type.clone(),                   // fill type
+            impact_pct,                                             // market impact
         });
     }
 
-    fn calc_next_grid_entry_long(&self, k: usize, idx: usize) -> Order {
+    fn calc_next_grid_entry_long(&self, k: usize, idx: SymbolIdx) -> NextOrder {
         let state_params = self.create_state_params(k, idx, LONG);
         let binding = Position::default();
         let position = self.positions.long.get(&idx).unwrap_or(&binding);
         calc_next_entry_long(
-            &self.exchange_params_list[idx],
+            &self.exchange_params_list[idx as usize],
             &state_params,
-            &self.bot_params_pair.long,
+            &self.entry_bot_params_list[idx as usize].long,
             position,
             &self.trailing_prices.long[&idx],
         )
     }
 
-    fn calc_next_grid_entry_short(&self, k: usize, idx: usize) -> Order {
+    fn calc_next_grid_entry_short(&self, k: usize, idx: SymbolIdx) -> NextOrder {
         let state_params = self.create_state_params(k, idx, SHORT);
         let binding = Position::default();
         let position = self.positions.short.get(&idx).unwrap_or(&binding);
         calc_next_entry_short(
-            &self.exchange_params_list[idx],
+            &self.exchange_params_list[idx as usize],
             &state_params,
-            &self.bot_params_pair.short,
+            &self.entry_bot_params_list[idx as usize].short,
             position,
             &self.trailing_prices.short[&idx],
         )
     }
 
-    fn calc_grid_close_long(&self, k: usize, idx: usize) -> Order {
-        let state_params = self.create_state_params(k, idx, LONG);
+    fn calc_grid_close_long(&self, k: usize, idx: SymbolIdx) -> NextOrder {
+        let state_params =
+            self.close_state_params(k, LONG, &self.create_state_params(k, idx, LONG));
         let binding = Position::default();
         let position = self.positions.long.get(&idx).unwrap_or(&binding);
         calc_next_close_long(
-            &self.exchange_params_list[idx],
+            &self.exchange_params_list[idx as usize],
             &state_params,
-            &self.bot_params_pair.long,
+            &self.close_bot_params_list[idx as usize].long,
             &position,
             &self.trailing_prices.long[&idx],
         )
     }
 
-    fn calc_grid_close_short(&self, k: usize, idx: usize) -> Order {
-        let state_params = self.create_state_params(k, idx, SHORT);
+    fn calc_grid_close_short(&self, k: usize, idx: SymbolIdx) -> NextOrder {
+        let state_params =
+            self.close_state_params(k, SHORT, &self.create_state_params(k, idx, SHORT));
         let binding = Position::default();
         let position = self.positions.short.get(&idx).unwrap_or(&binding);
         calc_next_close_short(
-            &self.exchange_params_list[idx],
+            &self.exchange_params_list[idx as usize],
             &state_params,
-            &self.bot_params_pair.short,
+            &self.close_bot_params_list[idx as usize].short,
             &position,
             &self.trailing_prices.short[&idx],
         )
     }
 
-    fn reset_trailing_prices(&mut self, idx: usize, pside: usize) {
+    /// Whether close, about to fill, is a grid close leaving part of idx's pside position open
+    /// under recenter_trailing_on_partial_close: the trailing bundle then restarts at the
+    /// fill price instead of empty, so the fill counts as the new peak (trough).
+    fn recenters_trailing(&self, idx: SymbolIdx, pside: usize, close: &Order) -> bool {
+        let (position, bot_params) = match pside {
+            LONG => (
+                self.positions.long.get(&idx),
+                &self.close_bot_params_list[idx as usize].long,
+            ),
+            _ => (
+                self.positions.short.get(&idx),
+                &self.close_bot_params_list[idx as usize].short,
+            ),
+        };
+        let qty_step = self.exchange_params_list[idx as usize].qty_step;
+        bot_params.recenter_trailing_on_partial_close
+            && matches!(
+                close.order_type,
+                OrderType::CloseGridLong
+                    | OrderType::CloseTakerLong
+                    | OrderType::CloseGridShort
+                    | OrderType::CloseTakerShort
+            )
+            && position.is_some_and(|position| {
+                round_(position.size.abs() - close.qty.abs(), qty_step) > 0.0
+            })
+    }
+
+    fn reset_trailing_prices(&mut self, idx: SymbolIdx, pside: usize) {
         let trailing_price_bundle = if pside == LONG {
             self.trailing_prices.long.entry(idx).or_default()
         } else {
@@ -920,31 +1960,30 @@ impl<'a> Backtest<'a> {
         *trailing_price_bundle = TrailingPriceBundle::default();
     }
 
-    fn update_trailing_prices(&mut self, k: usize, idx: usize, pside: usize) {
-        let trailing_price_bundle = if pside == LONG {
-            self.trailing_prices.long.entry(idx).or_default()
+    fn update_trailing_prices(&mut self, k: usize, idx: SymbolIdx, pside: usize) {
+        let trailing_ma = self.trailing_ma(idx, pside);
+        let (trailing_price_bundle, bot_params) = if pside == LONG {
+            (
+                self.trailing_prices.long.entry(idx).or_default(),
+                &self.close_bot_params_list[idx as usize].long,
+            )
         } else {
-            self.trailing_prices.short.entry(idx).or_default()
+            (
+                self.trailing_prices.short.entry(idx).or_default(),
+                &self.close_bot_params_list[idx as usize].short,
+            )
         };
-        if self.hlcvs[[k, idx, LOW]] < trailing_price_bundle.min_since_open {
-            trailing_price_bundle.min_since_open = self.hlcvs[[k, idx, LOW]];
-            trailing_price_bundle.max_since_min = self.hlcvs[[k, idx, CLOSE]];
-        } else {
-            trailing_price_bundle.max_since_min = trailing_price_bundle
-                .max_since_min
-                .max(self.hlcvs[[k, idx, HIGH]]);
-        }
-        if self.hlcvs[[k, idx, HIGH]] > trailing_price_bundle.max_since_open {
-            trailing_price_bundle.max_since_open = self.hlcvs[[k, idx, HIGH]];
-            trailing_price_bundle.min_since_max = self.hlcvs[[k, idx, CLOSE]];
-        } else {
-            trailing_price_bundle.min_since_max = trailing_price_bundle
-                .min_since_max
-                .min(self.hlcvs[[k, idx, LOW]]);
-        }
+        update_trailing_price_bundle(
+            trailing_price_bundle,
+            &self.exchange_params_list[idx as usize],
+            bot_params,
+            self.hlcvs.slice(s![k, idx as usize, ..]),
+            trailing_ma,
+            pside,
+        );
     }
 
-    fn has_next_grid_order(&mut self, order: &Order, pside: usize) -> bool {
+    fn has_next_grid_order(&self, order: &Order, pside: usize) -> bool {
         match pside {
             LONG => {
                 if order.qty == 0.0 {
@@ -955,6 +1994,7 @@ impl<'a> Backtest<'a> {
                         || order.order_type == OrderType::EntryInitialPartialLong
                 } else {
                     order.order_type == OrderType::CloseGridLong
+                        || order.order_type == OrderType::CloseTakerLong
                 }
             }
             SHORT => {
@@ -966,14 +2006,16 @@ impl<'a> Backtest<'a> {
                         || order.order_type == OrderType::EntryInitialPartialShort
                 } else {
                     order.order_type == OrderType::CloseGridShort
+                        || order.order_type == OrderType::CloseTakerShort
                 }
             }
             _ => panic!("Invalid pside"),
         }
     }
 
-    fn update_open_orders_long_single(&mut self, k: usize, idx: usize) {
+    fn update_open_orders_long_single(&mut self, k: usize, idx: SymbolIdx) {
         let state_params = self.create_state_params(k, idx, LONG);
+        let close_state_params = self.close_state_params(k, LONG, &state_params);
         let position = self
             .positions
             .long
@@ -988,68 +2030,106 @@ impl<'a> Backtest<'a> {
                     qty: -self.positions.long[&idx].size,
                     price: round_(
                         f64::min(
-                            self.hlcvs[[k, idx, HIGH]] - self.exchange_params_list[idx].price_step,
+                            self.hlcvs[[k, idx as usize, HIGH]]
+                                - self.exchange_params_list[idx as usize].price_step,
                             self.positions.long[&idx].price,
                         ),
-                        self.exchange_params_list[idx].price_step,
+                        self.exchange_params_list[idx as usize].price_step,
                     ),
                     order_type: OrderType::CloseUnstuckLong,
+                    iceberg_qty: None,
+                    min_fill_qty: -self.positions.long[&idx].size,
                 }];
-                self.open_orders
-                    .long
-                    .entry(idx)
-                    .or_default()
-                    .entries
-                    .clear();
+                let open_orders = self.open_orders.long.entry(idx).or_default();
+                open_orders.entries.clear();
+                open_orders.trailing_entry_pending = false;
+                open_orders.trailing_close_pending = false;
                 return;
             }
         }
         let next_entry_order = calc_next_entry_long(
-            &self.exchange_params_list[idx],
+            &self.exchange_params_list[idx as usize],
             &state_params,
-            &self.bot_params_pair.long,
+            &self.entry_bot_params_list[idx as usize].long,
             &position,
             &self.trailing_prices.long[&idx],
         );
         // if initial entry or grid, peek next candle to see if order will fill
-        if self.order_filled(k + 1, idx, &next_entry_order)
-            && self.has_next_grid_order(&next_entry_order, LONG)
-        {
+        if next_entry_order.order().is_some_and(|order| {
+            self.order_filled(k + 1, idx, &order) && self.has_next_grid_order(&order, LONG)
+        }) {
             self.open_orders.long.entry(idx).or_default().entries = calc_entries_long(
-                &self.exchange_params_list[idx],
+                &self.exchange_params_list[idx as usize],
                 &state_params,
-                &self.bot_params_pair.long,
+                &self.entry_bot_params_list[idx as usize].long,
                 &position,
                 &self.trailing_prices.long[&idx],
             );
         } else {
-            self.open_orders.long.entry(idx).or_default().entries = [next_entry_order].to_vec();
+            self.open_orders.long.entry(idx).or_default().entries =
+                next_entry_order.order().into_iter().collect();
+        }
+        self.open_orders
+            .long
+            .entry(idx)
+            .or_default()
+            .trailing_entry_pending = matches!(next_entry_order, NextOrder::TrailingPending);
+        if self.trading_modes[LONG] != TradingMode::Normal
+            || self.reduce_only_active()
+            || !self.trading_masks[idx as usize].enabled(LONG)
+        {
+            let open_orders = self.open_orders.long.entry(idx).or_default();
+            open_orders.entries.clear();
+            open_orders.trailing_entry_pending = false;
         }
         let next_close_order = calc_next_close_long(
-            &self.exchange_params_list[idx],
-            &state_params,
-            &self.bot_params_pair.long,
+            &self.exchange_params_list[idx as usize],
+            &close_state_params,
+            &self.close_bot_params_list[idx as usize].long,
             &position,
             &self.trailing_prices.long[&idx],
         );
+        let trailing_legs_enabled = self.close_bot_params_list[idx as usize]
+            .long
+            .close_trailing_legs_enabled();
         // if initial entry or grid, peek next candle to see if order will fill
-        if self.order_filled(k + 1, idx, &next_close_order)
-            && self.has_next_grid_order(&next_close_order, LONG)
+        // fast and slow trailing legs are only calculated alongside the full ladder
+        if trailing_legs_enabled
+            || next_close_order.order().is_some_and(|order| {
+                self.order_filled(k + 1, idx, &order) && self.has_next_grid_order(&order, LONG)
+            })
         {
             self.open_orders.long.entry(idx).or_default().closes = calc_closes_long(
-                &self.exchange_params_list[idx],
-                &state_params,
-                &self.bot_params_pair.long,
+                &self.exchange_params_list[idx as usize],
+                &close_state_params,
+                &self.close_bot_params_list[idx as usize].long,
                 &position,
                 &self.trailing_prices.long[&idx],
+                &[],
             );
         } else {
-            self.open_orders.long.entry(idx).or_default().closes = [next_close_order].to_vec();
+            self.open_orders.long.entry(idx).or_default().closes =
+                next_close_order.order().into_iter().collect();
+        }
+        self.open_orders
+            .long
+            .entry(idx)
+            .or_default()
+            .trailing_close_pending = matches!(next_close_order, NextOrder::TrailingPending)
+            || (trailing_legs_enabled && position.size != 0.0)
+            // closes deferred for want of volume are re-checked next candle
+            || (position.size != 0.0
+                && !self.close_bot_params_list[idx as usize]
+                    .long
+                    .close_volume_confirmed(state_params.volume));
+        if cfg!(debug_assertions) {
+            self.check_open_orders(idx, LONG, &state_params, &close_state_params, &position);
         }
     }
 
-    fn update_open_orders_short_single(&mut self, k: usize, idx: usize) {
+    fn update_open_orders_short_single(&mut self, k: usize, idx: SymbolIdx) {
         let state_params = self.create_state_params(k, idx, SHORT);
+        let close_state_params = self.close_state_params(k, SHORT, &state_params);
         let position = self
             .positions
             .short
@@ -1064,79 +2144,277 @@ impl<'a> Backtest<'a> {
                     qty: self.positions.short[&idx].size.abs(),
                     price: round_(
                         f64::max(
-                            self.hlcvs[[k, idx, LOW]] + self.exchange_params_list[idx].price_step,
+                            self.hlcvs[[k, idx as usize, LOW]]
+                                + self.exchange_params_list[idx as usize].price_step,
                             self.positions.short[&idx].price,
                         ),
-                        self.exchange_params_list[idx].price_step,
+                        self.exchange_params_list[idx as usize].price_step,
                     ),
                     order_type: OrderType::CloseUnstuckShort,
+                    iceberg_qty: None,
+                    min_fill_qty: self.positions.short[&idx].size.abs(),
                 }];
-                self.open_orders
-                    .short
-                    .entry(idx)
-                    .or_default()
-                    .entries
-                    .clear();
+                let open_orders = self.open_orders.short.entry(idx).or_default();
+                open_orders.entries.clear();
+                open_orders.trailing_entry_pending = false;
+                open_orders.trailing_close_pending = false;
                 return;
             }
         }
         let next_entry_order = calc_next_entry_short(
-            &self.exchange_params_list[idx],
+            &self.exchange_params_list[idx as usize],
             &state_params,
-            &self.bot_params_pair.short,
+            &self.entry_bot_params_list[idx as usize].short,
             &position,
             &self.trailing_prices.short[&idx],
         );
         // if initial entry or grid, peek next candle to see if order will fill
-        if self.order_filled(k + 1, idx, &next_entry_order)
-            && self.has_next_grid_order(&next_entry_order, SHORT)
-        {
+        if next_entry_order.order().is_some_and(|order| {
+            self.order_filled(k + 1, idx, &order) && self.has_next_grid_order(&order, SHORT)
+        }) {
             self.open_orders.short.entry(idx).or_default().entries = calc_entries_short(
-                &self.exchange_params_list[idx],
+                &self.exchange_params_list[idx as usize],
                 &state_params,
-                &self.bot_params_pair.short,
+                &self.entry_bot_params_list[idx as usize].short,
                 &position,
                 &self.trailing_prices.short[&idx],
             );
         } else {
-            self.open_orders.short.entry(idx).or_default().entries = [next_entry_order].to_vec();
+            self.open_orders.short.entry(idx).or_default().entries =
+                next_entry_order.order().into_iter().collect();
+        }
+        self.open_orders
+            .short
+            .entry(idx)
+            .or_default()
+            .trailing_entry_pending = matches!(next_entry_order, NextOrder::TrailingPending);
+        if self.trading_modes[SHORT] != TradingMode::Normal
+            || self.reduce_only_active()
+            || !self.trading_masks[idx as usize].enabled(SHORT)
+        {
+            let open_orders = self.open_orders.short.entry(idx).or_default();
+            open_orders.entries.clear();
+            open_orders.trailing_entry_pending = false;
         }
 
         let next_close_order = calc_next_close_short(
-            &self.exchange_params_list[idx],
-            &state_params,
-            &self.bot_params_pair.short,
+            &self.exchange_params_list[idx as usize],
+            &close_state_params,
+            &self.close_bot_params_list[idx as usize].short,
             &position,
             &self.trailing_prices.short[&idx],
         );
+        let trailing_legs_enabled = self.close_bot_params_list[idx as usize]
+            .short
+            .close_trailing_legs_enabled();
         // if initial entry or grid, peek next candle to see if order will fill
-        if self.order_filled(k + 1, idx, &next_close_order)
-            && self.has_next_grid_order(&next_close_order, SHORT)
+        // fast and slow trailing legs are only calculated alongside the full ladder
+        if trailing_legs_enabled
+            || next_close_order.order().is_some_and(|order| {
+                self.order_filled(k + 1, idx, &order) && self.has_next_grid_order(&order, SHORT)
+            })
         {
             self.open_orders.short.entry(idx).or_default().closes = calc_closes_short(
-                &self.exchange_params_list[idx],
-                &state_params,
-                &self.bot_params_pair.short,
+                &self.exchange_params_list[idx as usize],
+                &close_state_params,
+                &self.close_bot_params_list[idx as usize].short,
                 &position,
                 &self.trailing_prices.short[&idx],
+                &[],
             );
         } else {
-            self.open_orders.short.entry(idx).or_default().closes = [next_close_order].to_vec()
+            self.open_orders.short.entry(idx).or_default().closes =
+                next_close_order.order().into_iter().collect();
+        }
+        self.open_orders
+            .short
+            .entry(idx)
+            .or_default()
+            .trailing_close_pending = matches!(next_close_order, NextOrder::TrailingPending)
+            || (trailing_legs_enabled && position.size != 0.0)
+            // closes deferred for want of volume are re-checked next candle
+            || (position.size != 0.0
+                && !self.close_bot_params_list[idx as usize]
+                    .short
+                    .close_volume_confirmed(state_params.volume));
+        if cfg!(debug_assertions) {
+            self.check_open_orders(idx, SHORT, &state_params, &close_state_params, &position);
+        }
+    }
+
+    /// Panics if the open orders of idx on pside break check_ladder_invariants; entries and
+    /// closes are separate ladders, both laid out from position.
+    fn check_open_orders(
+        &self,
+        idx: SymbolIdx,
+        pside: usize,
+        state_params: &StateParams,
+        close_state_params: &StateParams,
+        position: &Position,
+    ) {
+        let (open_orders, bot_params, close_bot_params) = match pside {
+            LONG => (
+                &self.open_orders.long,
+                &self.entry_bot_params_list[idx as usize].long,
+                &self.close_bot_params_list[idx as usize].long,
+            ),
+            _ => (
+                &self.open_orders.short,
+                &self.entry_bot_params_list[idx as usize].short,
+                &self.close_bot_params_list[idx as usize].short,
+            ),
+        };
+        let Some(open_orders) = open_orders.get(&idx) else {
+            return;
+        };
+        let exchange_params = &self.exchange_params_list[idx as usize];
+        for (orders, state_params, bot_params) in [
+            (&open_orders.entries, state_params, bot_params),
+            (&open_orders.closes, close_state_params, close_bot_params),
+        ] {
+            if let Err(e) = check_ladder_invariants(
+                orders,
+                exchange_params,
+                state_params,
+                bot_params,
+                position,
+                pside,
+            ) {
+                panic!("{}: {}", self.backtest_params.coins[idx as usize], e);
+            }
+        }
+    }
+
+    /// With slippage_pct, a filled order's price moves against it by a random fraction of
+    /// up to slippage_pct: buys fill higher, sells lower.
+    fn slipped(&mut self, order: Order) -> Order {
+        if self.backtest_params.slippage_pct <= 0.0 {
+            return order;
+        }
+        let slippage = self
+            .slippage_rng
+            .uniform(0.0, self.backtest_params.slippage_pct);
+        Order {
+            price: order.price * (1.0 + slippage.copysign(order.qty)),
+            ..order
+        }
+    }
+
+    /// Price of a close taking the market at candle k's close: market_slippage_buffer_pct
+    /// past it, lower for longs and higher for shorts, as a market order fills worse than
+    /// the quote.
+    fn taker_close_price(&self, k: usize, idx: SymbolIdx, pside: usize) -> f64 {
+        let close = self.hlcvs[[k, idx as usize, CLOSE]];
+        let buffer = self.backtest_params.market_slippage_buffer_pct;
+        if buffer <= 0.0 {
+            return close;
         }
+        let price_step = self.exchange_params_list[idx as usize].price_step;
+        match pside {
+            LONG => round_dn(close * (1.0 - buffer), price_step),
+            _ => round_up(close * (1.0 + buffer), price_step),
+        }
+    }
+
+    /// With an impact capacity for the coin, fills in candle k beyond that much quote volume
+    /// move the price against the fill by market_impact_pct per capacity's worth of excess,
+    /// on top of any slippage; a close cropped to candle volume is only penalized for what
+    /// it fills. Returns the order at its impacted price and the penalty.
+    fn impacted(&mut self, k: usize, idx: SymbolIdx, order: &Order) -> (Order, f64) {
+        let capacity = self.impact_capacities[idx as usize];
+        if capacity <= 0.0 {
+            return (*order, 0.0);
+        }
+        let (candle, consumed) = &mut self.impact_consumed[idx as usize];
+        if *candle != k {
+            *candle = k;
+            *consumed = 0.0;
+        }
+        *consumed += qty_to_cost(
+            order.qty,
+            order.price,
+            self.exchange_params_list[idx as usize].c_mult,
+        );
+        let impact_pct =
+            self.backtest_params.market_impact_pct * (*consumed - capacity).max(0.0) / capacity;
+        let impacted = Order {
+            price: order.price * (1.0 + impact_pct.copysign(order.qty)),
+            ..*order
+        };
+        (impacted, impact_pct)
+    }
+
+    /// Coins among idxs whose initial entries fill on candle k but find no open slot. Slots are
+    /// those of n_positions not taken by a position at the candle's start, so a slot frees up
+    /// only once a position is fully closed. Contenders for the last slots are settled by
+    /// their selection ranking, then by index.
+    fn refused_initial_entries(
+        &self,
+        k: usize,
+        pside: usize,
+        idxs: &[SymbolIdx],
+    ) -> Vec<SymbolIdx> {
+        if pside == SHORT && self.neutral_mode {
+            // neutral shorts pair up with the longs, which already hold the slots
+            return Vec::new();
+        }
+        let (positions, open_orders, n_positions, ranking) = match pside {
+            LONG => (
+                &self.positions.long,
+                &self.open_orders.long,
+                self.bot_params_pair.long.n_positions,
+                &self.slot_ranking_long,
+            ),
+            _ => (
+                &self.positions.short,
+                &self.open_orders.short,
+                self.bot_params_pair.short.n_positions,
+                &self.slot_ranking_short,
+            ),
+        };
+        let mut contenders: Vec<SymbolIdx> = idxs
+            .iter()
+            .filter(|idx| !positions.contains_key(idx))
+            .filter(|idx| {
+                open_orders[idx]
+                    .entries
+                    .iter()
+                    .any(|order| self.order_filled(k, **idx, order))
+            })
+            .copied()
+            .collect();
+        let n_free = n_positions.saturating_sub(positions.len());
+        if contenders.len() <= n_free {
+            return Vec::new();
+        }
+        contenders.sort_by_key(|idx| {
+            (
+                ranking
+                    .iter()
+                    .position(|ranked| ranked == idx)
+                    .unwrap_or(usize::MAX),
+                *idx,
+            )
+        });
+        contenders.split_off(n_free)
     }
 
-    fn order_filled(&self, k: usize, idx: usize, order: &Order) -> bool {
+    fn order_filled(&self, k: usize, idx: SymbolIdx, order: &Order) -> bool {
         // check if will fill in next candle
         if order.qty > 0.0 {
-            self.hlcvs[[k, idx, LOW]] < order.price
+            self.hlcvs[[k, idx as usize, LOW]] < order.price
         } else if order.qty < 0.0 {
-            self.hlcvs[[k, idx, HIGH]] > order.price
+            self.hlcvs[[k, idx as usize, HIGH]] > order.price
         } else {
             false
         }
     }
 
-    fn calc_unstucking_close(&mut self, k: usize) -> (usize, usize, Order) {
+    fn calc_unstucking_close(&mut self, k: usize) -> Option<(SymbolIdx, usize, Order)> {
+        if self.neutral_mode {
+            // a neutral pair's loss on one side is the other's gain
+            return None;
+        }
         let mut stuck_positions = Vec::new();
         let mut unstuck_allowances = (0.0, 0.0);
 
@@ -1147,25 +2425,29 @@ impl<'a> Backtest<'a> {
                     * self.bot_params_pair.long.total_wallet_exposure_limit,
                 self.pnl_cumsum_max,
                 self.pnl_cumsum_running,
+                self.bot_params_pair.long.unstuck_require_profit_buffer_pct,
             );
             if unstuck_allowances.0 > 0.0 {
                 // Check long positions
                 // Sort the keys for long
-                let mut long_keys: Vec<usize> = self.positions.long.keys().cloned().collect();
+                let mut long_keys: Vec<SymbolIdx> = self.positions.long.keys().cloned().collect();
                 long_keys.sort();
                 for idx in long_keys {
                     let position = &self.positions.long[&idx];
                     let wallet_exposure = calc_wallet_exposure(
-                        self.exchange_params_list[idx].c_mult,
-                        self.balance.usd_total_rounded,
+                        self.exchange_params_list[idx as usize].c_mult,
+                        self.allocated_balance(LONG),
                         position.size,
                         position.price,
                     );
-                    if wallet_exposure / self.bot_params_pair.long.wallet_exposure_limit
+                    if wallet_exposure / self.capped_wallet_exposure_limit(idx, LONG)
                         > self.bot_params_pair.long.unstuck_threshold
                     {
-                        let pprice_diff =
-                            calc_pprice_diff_int(LONG, position.price, self.hlcvs[[k, idx, CLOSE]]);
+                        let pprice_diff = calc_pprice_diff_int(
+                            LONG,
+                            position.price,
+                            self.hlcvs[[k, idx as usize, CLOSE]],
+                        );
                         stuck_positions.push((idx, LONG, pprice_diff));
                     }
                 }
@@ -1179,28 +2461,29 @@ impl<'a> Backtest<'a> {
                     * self.bot_params_pair.short.total_wallet_exposure_limit,
                 self.pnl_cumsum_max,
                 self.pnl_cumsum_running,
+                self.bot_params_pair.short.unstuck_require_profit_buffer_pct,
             );
             if unstuck_allowances.1 > 0.0 {
                 // Check short positions
                 // Sort the keys for short
-                let mut short_keys: Vec<usize> = self.positions.short.keys().cloned().collect();
+                let mut short_keys: Vec<SymbolIdx> = self.positions.short.keys().cloned().collect();
                 short_keys.sort();
 
                 for idx in short_keys {
                     let position = &self.positions.short[&idx];
                     let wallet_exposure = calc_wallet_exposure(
-                        self.exchange_params_list[idx].c_mult,
-                        self.balance.usd_total_rounded,
+                        self.exchange_params_list[idx as usize].c_mult,
+                        self.allocated_balance(SHORT),
                         position.size,
                         position.price,
                     );
-                    if wallet_exposure / self.bot_params_pair.short.wallet_exposure_limit
+                    if wallet_exposure / self.capped_wallet_exposure_limit(idx, SHORT)
                         > self.bot_params_pair.short.unstuck_threshold
                     {
                         let pprice_diff = calc_pprice_diff_int(
                             SHORT,
                             position.price,
-                            self.hlcvs[[k, idx, CLOSE]],
+                            self.hlcvs[[k, idx as usize, CLOSE]],
                         );
                         stuck_positions.push((idx, SHORT, pprice_diff));
                     }
@@ -1208,45 +2491,66 @@ impl<'a> Backtest<'a> {
             }
         }
         if stuck_positions.is_empty() {
-            return (NO_POS, NO_POS, Order::default());
+            return None;
         }
-        // Sort with tie-breaker: first by diff, then by idx
-        stuck_positions.sort_by(|(i1, side1, d1), (i2, side2, d2)| {
-            match d1.partial_cmp(d2).unwrap_or(std::cmp::Ordering::Equal) {
-                std::cmp::Ordering::Equal => i1.cmp(i2),
-                other => other,
-            }
-        });
+        // what one close may lose; qtys are trimmed to it below
+        let unstuck_allowances = (
+            calc_unstuck_max_loss(
+                unstuck_allowances.0,
+                self.bot_params_pair.long.unstuck_max_allowance_fraction,
+            ),
+            calc_unstuck_max_loss(
+                unstuck_allowances.1,
+                self.bot_params_pair.short.unstuck_max_allowance_fraction,
+            ),
+        );
+        // both sides' stuck positions rotate together, within the wider tolerance
+        let rotation_tolerance = f64::max(
+            self.bot_params_pair.long.unstuck_rotation_tolerance,
+            self.bot_params_pair.short.unstuck_rotation_tolerance,
+        );
+        let stuck_positions =
+            rank_positions_for_unstucking(stuck_positions, self.last_unstucked, rotation_tolerance);
         for (idx, pside, _) in stuck_positions {
             match pside {
                 LONG => {
-                    let close_price = f64::max(
-                        self.hlcvs[[k, idx, CLOSE]],
-                        round_up(
-                            self.emas[idx].compute_bands(LONG).upper
-                                * (1.0 + self.bot_params_pair.long.unstuck_ema_dist),
-                            self.exchange_params_list[idx].price_step,
-                        ),
+                    let ema_price = round_up(
+                        self.emas[idx as usize]
+                            .compute_bands(
+                                LONG,
+                                &self.ema_alphas.long,
+                                self.ema_alphas.long.unstuck,
+                            )
+                            .upper()
+                            * (1.0 + self.bot_params_pair.long.unstuck_ema_dist),
+                        self.exchange_params_list[idx as usize].price_step,
                     );
+                    let close_price = if ema_price < self.hlcvs[[k, idx as usize, CLOSE]] {
+                        self.taker_close_price(k, idx, LONG)
+                    } else {
+                        ema_price
+                    };
                     if self.open_orders.long[&idx].closes.is_empty()
                         || self.open_orders.long[&idx].closes[0].qty == 0.0
                         || close_price < self.open_orders.long[&idx].closes[0].price
                     {
-                        let min_entry_qty =
-                            calc_min_entry_qty(close_price, &self.exchange_params_list[idx]);
+                        let min_entry_qty = calc_min_entry_qty(
+                            close_price,
+                            &self.exchange_params_list[idx as usize],
+                        );
                         let mut close_qty = -f64::min(
                             self.positions.long[&idx].size,
                             f64::max(
                                 min_entry_qty,
                                 round_dn(
                                     cost_to_qty(
-                                        self.balance.usd_total_rounded
-                                            * self.bot_params_pair.long.wallet_exposure_limit
+                                        self.allocated_balance(LONG)
+                                            * self.capped_wallet_exposure_limit(idx, LONG)
                                             * self.bot_params_pair.long.unstuck_close_pct,
                                         close_price,
-                                        self.exchange_params_list[idx].c_mult,
+                                        self.exchange_params_list[idx as usize].c_mult,
                                     ),
-                                    self.exchange_params_list[idx].qty_step,
+                                    self.exchange_params_list[idx as usize].qty_step,
                                 ),
                             ),
                         );
@@ -1255,7 +2559,7 @@ impl<'a> Backtest<'a> {
                                 self.positions.long[&idx].price,
                                 close_price,
                                 close_qty,
-                                self.exchange_params_list[idx].c_mult,
+                                self.exchange_params_list[idx as usize].c_mult,
                             );
                             let pnl_if_closed_abs = pnl_if_closed.abs();
                             if pnl_if_closed < 0.0 && pnl_if_closed_abs > unstuck_allowances.0 {
@@ -1268,51 +2572,64 @@ impl<'a> Backtest<'a> {
                                         round_dn(
                                             close_qty.abs()
                                                 * (unstuck_allowances.0 / pnl_if_closed_abs),
-                                            self.exchange_params_list[idx].qty_step,
+                                            self.exchange_params_list[idx as usize].qty_step,
                                         ),
                                     ),
                                 );
                             }
-                            return (
+                            return Some((
                                 idx,
                                 LONG,
                                 Order {
                                     qty: close_qty,
                                     price: close_price,
                                     order_type: OrderType::CloseUnstuckLong,
+                                    iceberg_qty: None,
+                                    // all or nothing; a partial fill could leave dust
+                                    min_fill_qty: close_qty,
                                 },
-                            );
+                            ));
                         }
                     }
                 }
                 SHORT => {
-                    let close_price = f64::min(
-                        self.hlcvs[[k, idx, CLOSE]],
-                        round_dn(
-                            self.emas[idx].compute_bands(SHORT).lower
-                                * (1.0 - self.bot_params_pair.short.unstuck_ema_dist),
-                            self.exchange_params_list[idx].price_step,
-                        ),
+                    let ema_price = round_dn(
+                        self.emas[idx as usize]
+                            .compute_bands(
+                                SHORT,
+                                &self.ema_alphas.short,
+                                self.ema_alphas.short.unstuck,
+                            )
+                            .lower()
+                            * (1.0 - self.bot_params_pair.short.unstuck_ema_dist),
+                        self.exchange_params_list[idx as usize].price_step,
                     );
+                    let close_price = if ema_price > self.hlcvs[[k, idx as usize, CLOSE]] {
+                        self.taker_close_price(k, idx, SHORT)
+                    } else {
+                        ema_price
+                    };
                     if self.open_orders.short[&idx].closes.is_empty()
                         || self.open_orders.short[&idx].closes[0].qty == 0.0
                         || close_price > self.open_orders.short[&idx].closes[0].price
                     {
-                        let min_entry_qty =
-                            calc_min_entry_qty(close_price, &self.exchange_params_list[idx]);
+                        let min_entry_qty = calc_min_entry_qty(
+                            close_price,
+                            &self.exchange_params_list[idx as usize],
+                        );
                         let mut close_qty = f64::min(
                             self.positions.short[&idx].size.abs(),
                             f64::max(
                                 min_entry_qty,
                                 round_dn(
                                     cost_to_qty(
-                                        self.balance.usd_total_rounded
-                                            * self.bot_params_pair.short.wallet_exposure_limit
+                                        self.allocated_balance(SHORT)
+                                            * self.capped_wallet_exposure_limit(idx, SHORT)
                                             * self.bot_params_pair.short.unstuck_close_pct,
                                         close_price,
-                                        self.exchange_params_list[idx].c_mult,
+                                        self.exchange_params_list[idx as usize].c_mult,
                                     ),
-                                    self.exchange_params_list[idx].qty_step,
+                                    self.exchange_params_list[idx as usize].qty_step,
                                 ),
                             ),
                         );
@@ -1321,7 +2638,7 @@ impl<'a> Backtest<'a> {
                                 self.positions.short[&idx].price,
                                 close_price,
                                 close_qty,
-                                self.exchange_params_list[idx].c_mult,
+                                self.exchange_params_list[idx as usize].c_mult,
                             );
                             let pnl_if_closed_abs = pnl_if_closed.abs();
                             if pnl_if_closed < 0.0 && pnl_if_closed_abs > unstuck_allowances.1 {
@@ -1333,20 +2650,22 @@ impl<'a> Backtest<'a> {
                                         min_entry_qty,
                                         round_dn(
                                             close_qty * (unstuck_allowances.1 / pnl_if_closed_abs),
-                                            self.exchange_params_list[idx].qty_step,
+                                            self.exchange_params_list[idx as usize].qty_step,
                                         ),
                                     ),
                                 );
                             }
-                            return (
+                            return Some((
                                 idx,
                                 SHORT,
                                 Order {
                                     qty: close_qty,
                                     price: close_price,
                                     order_type: OrderType::CloseUnstuckShort,
+                                    iceberg_qty: None,
+                                    min_fill_qty: close_qty,
                                 },
-                            );
+                            ));
                         }
                     }
                 }
@@ -1354,13 +2673,13 @@ impl<'a> Backtest<'a> {
             };
         }
 
-        (NO_POS, NO_POS, Order::default())
+        None
     }
 
     fn update_open_orders_any_fill(&mut self, k: usize) {
         if self.trading_enabled.long {
             if self.trailing_enabled.long {
-                let mut positions_long_indices: Vec<usize> =
+                let mut positions_long_indices: Vec<SymbolIdx> =
                     self.positions.long.keys().cloned().collect();
                 positions_long_indices.sort();
                 for idx in &positions_long_indices {
@@ -1373,16 +2692,18 @@ impl<'a> Backtest<'a> {
             self.open_orders
                 .long
                 .retain(|&idx, _| self.actives.long.contains(&idx));
-            let mut active_long_indices: Vec<usize> = self.actives.long.iter().cloned().collect();
+            let mut active_long_indices: Vec<SymbolIdx> =
+                self.actives.long.iter().cloned().collect();
             active_long_indices.sort(); // Ensure deterministic order
             for &idx in &active_long_indices {
                 self.update_stuck_status(idx, LONG);
                 self.update_open_orders_long_single(k, idx);
+                self.notify_order_update(k, idx, LONG);
             }
         }
         if self.trading_enabled.short {
             if self.trailing_enabled.short {
-                let mut positions_short_indices: Vec<usize> =
+                let mut positions_short_indices: Vec<SymbolIdx> =
                     self.positions.short.keys().cloned().collect();
                 positions_short_indices.sort();
                 for idx in &positions_short_indices {
@@ -1395,32 +2716,32 @@ impl<'a> Backtest<'a> {
             self.open_orders
                 .short
                 .retain(|&idx, _| self.actives.short.contains(&idx));
-            let mut active_short_indices: Vec<usize> = self.actives.short.iter().cloned().collect();
+            let mut active_short_indices: Vec<SymbolIdx> =
+                self.actives.short.iter().cloned().collect();
             active_short_indices.sort(); // Ensure deterministic order
             for &idx in &active_short_indices {
                 self.update_stuck_status(idx, SHORT);
                 self.update_open_orders_short_single(k, idx);
+                self.notify_order_update(k, idx, SHORT);
             }
         }
-        let (unstucking_idx, unstucking_pside, unstucking_close) = self.calc_unstucking_close(k);
-        if unstucking_pside != NO_POS {
+        if let Some((unstucking_idx, unstucking_pside, unstucking_close)) =
+            self.calc_unstucking_close(k)
+        {
             match unstucking_pside {
                 LONG => {
-                    self.open_orders
-                        .long
-                        .entry(unstucking_idx)
-                        .or_default()
-                        .closes = vec![unstucking_close];
+                    let open_orders = self.open_orders.long.entry(unstucking_idx).or_default();
+                    open_orders.closes = vec![unstucking_close];
+                    open_orders.trailing_close_pending = false;
                 }
                 SHORT => {
-                    self.open_orders
-                        .short
-                        .entry(unstucking_idx)
-                        .or_default()
-                        .closes = vec![unstucking_close];
+                    let open_orders = self.open_orders.short.entry(unstucking_idx).or_default();
+                    open_orders.closes = vec![unstucking_close];
+                    open_orders.trailing_close_pending = false;
                 }
                 _ => unreachable!(),
             }
+            self.notify_order_update(k, unstucking_idx, unstucking_pside);
         }
     }
 
@@ -1430,98 +2751,108 @@ impl<'a> Backtest<'a> {
         // - unstuck close if any stuck
         // - entries for coins with open trailing entries
         // - closes for coins with open trailing closes
+        let mut indices = std::mem::take(&mut self.positions_idx_buffer);
         if self.trading_enabled.long {
-            let mut positions_long_indices: Vec<usize> =
-                self.positions.long.keys().cloned().collect();
-            positions_long_indices.sort();
             if self.trailing_enabled.long {
-                for idx in &positions_long_indices {
-                    if !self.did_fill_long.contains(idx) {
-                        self.update_trailing_prices(k, *idx, LONG);
+                collect_sorted(&mut indices, self.positions.long.keys());
+                for &idx in &indices {
+                    if !self.did_fill_long.contains(&idx) {
+                        self.update_trailing_prices(k, idx, LONG);
                     }
                 }
             }
-            let mut actives_without_pos = Vec::<usize>::new();
-            if positions_long_indices.len() < self.bot_params_pair.long.n_positions {
+            let mut actives_without_pos = Vec::<SymbolIdx>::new();
+            if self.positions.long.len() < self.bot_params_pair.long.n_positions {
                 actives_without_pos = self.update_actives(k, LONG);
                 self.open_orders
                     .long
                     .retain(|&idx, _| self.actives.long.contains(&idx));
             }
-            let mut active_long_indices: Vec<usize> = self.actives.long.iter().cloned().collect();
-            active_long_indices.sort();
+            collect_sorted(&mut indices, self.actives.long.iter());
 
-            for idx in active_long_indices {
+            for &idx in &indices {
                 if actives_without_pos.contains(&idx)
                     || self.open_orders.long.get(&idx).map_or(false, |orders| {
-                        orders.closes.iter().any(|order| {
-                            order.order_type == OrderType::CloseUnstuckLong
-                                || order.order_type == OrderType::CloseTrailingLong
-                        }) || orders.entries.iter().any(|order| {
-                            order.order_type == OrderType::EntryTrailingNormalLong
-                                || order.order_type == OrderType::EntryTrailingCroppedLong
-                        })
+                        orders.trailing_entry_pending
+                            || orders.trailing_close_pending
+                            || orders.closes.iter().any(|order| {
+                                order.order_type == OrderType::CloseUnstuckLong
+                                    || order.order_type == OrderType::CloseTrailingLong
+                                    || order.order_type == OrderType::CloseDrawdownLong
+                            })
+                            || orders.entries.iter().any(|order| {
+                                order.order_type == OrderType::EntryTrailingNormalLong
+                                    || order.order_type == OrderType::EntryTrailingCroppedLong
+                            })
                     })
                 {
                     self.update_open_orders_long_single(k, idx);
+                    self.notify_order_update(k, idx, LONG);
                 }
             }
         }
 
         if self.trading_enabled.short {
-            let mut positions_short_indices: Vec<usize> =
-                self.positions.short.keys().cloned().collect();
-            positions_short_indices.sort();
             if self.trailing_enabled.short {
-                for idx in &positions_short_indices {
-                    if !self.did_fill_short.contains(idx) {
-                        self.update_trailing_prices(k, *idx, SHORT);
+                collect_sorted(&mut indices, self.positions.short.keys());
+                for &idx in &indices {
+                    if !self.did_fill_short.contains(&idx) {
+                        self.update_trailing_prices(k, idx, SHORT);
                     }
                 }
             }
-            let mut actives_without_pos = Vec::<usize>::new();
-            if positions_short_indices.len() < self.bot_params_pair.short.n_positions {
+            let mut actives_without_pos = Vec::<SymbolIdx>::new();
+            if self.positions.short.len() < self.bot_params_pair.short.n_positions {
                 actives_without_pos = self.update_actives(k, SHORT);
                 self.open_orders
                     .short
                     .retain(|&idx, _| self.actives.short.contains(&idx));
             }
-            let mut active_short_indices: Vec<usize> = self.actives.short.iter().cloned().collect();
-            active_short_indices.sort();
-            for idx in active_short_indices {
+            collect_sorted(&mut indices, self.actives.short.iter());
+            for &idx in &indices {
                 if actives_without_pos.contains(&idx)
                     || self.open_orders.short.get(&idx).map_or(false, |orders| {
-                        orders.closes.iter().any(|order| {
-                            order.order_type == OrderType::CloseUnstuckShort
-                                || order.order_type == OrderType::CloseTrailingShort
-                        }) || orders.entries.iter().any(|order| {
-                            order.order_type == OrderType::EntryTrailingNormalShort
-                                || order.order_type == OrderType::EntryTrailingCroppedShort
-                        })
+                        orders.trailing_entry_pending
+                            || orders.trailing_close_pending
+                            || orders.closes.iter().any(|order| {
+                                order.order_type == OrderType::CloseUnstuckShort
+                                    || order.order_type == OrderType::CloseTrailingShort
+                                    || order.order_type == OrderType::CloseDrawdownShort
+                            })
+                            || orders.entries.iter().any(|order| {
+                                order.order_type == OrderType::EntryTrailingNormalShort
+                                    || order.order_type == OrderType::EntryTrailingCroppedShort
+                            })
                     })
                 {
                     self.update_open_orders_short_single(k, idx);
+                    self.notify_order_update(k, idx, SHORT);
                 }
             }
         }
 
+        self.positions_idx_buffer = indices;
+
         if !self.is_stuck.long.is_empty() || !self.is_stuck.short.is_empty() {
-            let (unstucking_idx, unstucking_pside, unstucking_close) =
-                self.calc_unstucking_close(k);
-            if unstucking_pside != NO_POS {
+            if let Some((unstucking_idx, unstucking_pside, unstucking_close)) =
+                self.calc_unstucking_close(k)
+            {
                 match unstucking_pside {
                     LONG => {
                         if let Some(orders) = self.open_orders.long.get_mut(&unstucking_idx) {
                             orders.closes = vec![unstucking_close];
+                            orders.trailing_close_pending = false;
                         }
                     }
                     SHORT => {
                         if let Some(orders) = self.open_orders.short.get_mut(&unstucking_idx) {
                             orders.closes = vec![unstucking_close];
+                            orders.trailing_close_pending = false;
                         }
                     }
                     _ => panic!("Invalid unstucking_pside"),
                 }
+                self.notify_order_update(k, unstucking_idx, unstucking_pside);
             }
         }
     }
@@ -1530,22 +2861,160 @@ impl<'a> Backtest<'a> {
     fn update_emas(&mut self, k: usize) {
         for i in 0..self.n_coins {
             let close_price = self.hlcvs[[k, i, CLOSE]];
-
-            let long_alphas = &self.ema_alphas.long.alphas;
-            let long_alphas_inv = &self.ema_alphas.long.alphas_inv;
-            let short_alphas = &self.ema_alphas.short.alphas;
-            let short_alphas_inv = &self.ema_alphas.short.alphas_inv;
-
             let emas = &mut self.emas[i];
-
-            for z in 0..3 {
-                emas.long[z] = close_price * long_alphas[z] + emas.long[z] * long_alphas_inv[z];
-                emas.short[z] = close_price * short_alphas[z] + emas.short[z] * short_alphas_inv[z];
+            for (emas, alphas) in [
+                (&mut emas.long, &self.ema_alphas.long),
+                (&mut emas.short, &self.ema_alphas.short),
+            ] {
+                for ((ema, alpha), alpha_inv) in
+                    emas.iter_mut().zip(&alphas.alphas).zip(&alphas.alphas_inv)
+                {
+                    *ema = close_price * alpha + *ema * alpha_inv;
+                }
             }
         }
     }
 }
 
+/// Folds a candle without a fill into a position's trailing prices, then ratchets the stop
+/// resting with close_trailing_step_multiple; trailing_ma is the side's at that candle.
+pub fn update_trailing_price_bundle(
+    trailing_price_bundle: &mut TrailingPriceBundle,
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    candle: ArrayView1<f64>,
+    trailing_ma: f64,
+    pside: usize,
+) {
+    if candle[LOW] < trailing_price_bundle.min_since_open {
+        trailing_price_bundle.min_since_open = candle[LOW];
+        trailing_price_bundle.max_since_min = candle[CLOSE];
+        trailing_price_bundle.candles_since_min = 0;
+        if pside == SHORT {
+            trailing_price_bundle.fib_levels_closed = 0;
+        }
+    } else {
+        trailing_price_bundle.candles_since_min += 1;
+        trailing_price_bundle.max_since_min = trailing_price_bundle.max_since_min.max(candle[HIGH]);
+    }
+    if candle[HIGH] > trailing_price_bundle.max_since_open {
+        trailing_price_bundle.max_since_open = candle[HIGH];
+        trailing_price_bundle.min_since_max = candle[CLOSE];
+        trailing_price_bundle.candles_since_max = 0;
+        if pside == LONG {
+            trailing_price_bundle.fib_levels_closed = 0;
+        }
+    } else {
+        trailing_price_bundle.candles_since_max += 1;
+        trailing_price_bundle.min_since_max = trailing_price_bundle.min_since_max.min(candle[LOW]);
+    }
+    if bot_params.close_trailing_step_multiple <= 0.0 {
+        return;
+    }
+    let state_params = StateParams {
+        trailing_ma,
+        ..Default::default()
+    };
+    trailing_price_bundle.stepped_stop_price = if pside == LONG {
+        calc_stepped_trailing_stop_price_long(
+            exchange_params,
+            bot_params,
+            calc_trailing_stop_price_long(&state_params, bot_params, trailing_price_bundle),
+            trailing_price_bundle.stepped_stop_price,
+        )
+    } else {
+        calc_stepped_trailing_stop_price_short(
+            exchange_params,
+            bot_params,
+            calc_trailing_stop_price_short(&state_params, bot_params, trailing_price_bundle),
+            trailing_price_bundle.stepped_stop_price,
+        )
+    };
+}
+
+/// The TrailingPriceBundle a backtest would hold for a position in one coin's hlcvs after its
+/// last fill at candle last_fill_index: the fill resets it and every later candle updates it,
+/// so a restarted live bot can resume trailing where it left off. trailing_mas, the side's
+/// trailing_ma per candle, is only needed to step a moving-average anchored stop. Fib closes
+/// keep the bundle but leave no trace in candles, so fib_levels_closed restarts at 0.
+pub fn reconstruct_trailing_price_bundle(
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    hlcvs: &ArrayView2<f64>,
+    trailing_mas: &[f64],
+    last_fill_index: usize,
+    pside: usize,
+) -> Result<TrailingPriceBundle, String> {
+    let n_candles = hlcvs.shape()[0];
+    if hlcvs.shape()[1] <= CLOSE {
+        return Err(format!(
+            "expected high, low and close columns, got {}",
+            hlcvs.shape()[1]
+        ));
+    }
+    if last_fill_index >= n_candles {
+        return Err(format!(
+            "last_fill_index {} out of range for {} candles",
+            last_fill_index, n_candles
+        ));
+    }
+    let needs_trailing_mas = bot_params.close_trailing_step_multiple > 0.0
+        && bot_params.close_trailing_anchor == CloseTrailingAnchor::MovingAverage;
+    if needs_trailing_mas && trailing_mas.len() != n_candles {
+        return Err(format!(
+            "expected {} trailing_mas for a stepped moving-average stop, got {}",
+            n_candles,
+            trailing_mas.len()
+        ));
+    }
+    let mut trailing_price_bundle = TrailingPriceBundle::default();
+    for k in last_fill_index + 1..n_candles {
+        update_trailing_price_bundle(
+            &mut trailing_price_bundle,
+            exchange_params,
+            bot_params,
+            hlcvs.row(k),
+            trailing_mas.get(k).copied().unwrap_or(0.0),
+            pside,
+        );
+    }
+    Ok(trailing_price_bundle)
+}
+
+/// Refills `buffer` with `indices` in ascending order, keeping its allocation.
+fn collect_sorted<'a>(buffer: &mut Vec<SymbolIdx>, indices: impl Iterator<Item = &'a SymbolIdx>) {
+    buffer.clear();
+    buffer.extend(indices);
+    buffer.sort_unstable();
+}
+
+/// Per coin floor under close_grid_min_markup: twice markup_floor_taker_fee or, with
+/// markup_floor_median_range, the coin's median (high - low) / close if larger. As
+/// preprocessing, the median spans the whole dataset; both off gives 0.0.
+pub fn calc_markup_floors(hlcvs: &ArrayView3<f64>, backtest_params: &BacktestParams) -> Vec<f64> {
+    let fee_floor = backtest_params.markup_floor_taker_fee * 2.0;
+    (0..hlcvs.shape()[1])
+        .map(|idx| {
+            if !backtest_params.markup_floor_median_range {
+                return fee_floor;
+            }
+            let mut ranges: Vec<f64> = hlcvs
+                .slice(s![.., idx, ..])
+                .axis_iter(Axis(0))
+                // back/front-filled candles are flat and carry no volume
+                .filter(|row| row[CLOSE] > 0.0 && !(row[HIGH] == row[LOW] && row[VOLUME] <= 0.0))
+                .map(|row| (row[HIGH] - row[LOW]) / row[CLOSE])
+                .collect();
+            if ranges.is_empty() {
+                return fee_floor;
+            }
+            let mid = ranges.len() / 2;
+            let (_, median, _) = ranges.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+            median.max(fee_floor)
+        })
+        .collect()
+}
+
 /// Binary-search the **first** and **last** valid candle index for every coin.
 /// A candle is *invalid* when `high == low == close` **and** `volume <= 0.0`
 /// (volume is -1.0 in new data, 0.0 in older back/front-filled data).
@@ -1596,41 +3065,24 @@ fn find_valid_timestamp_bounds(hlcvs: &ArrayView3<f64>) -> (Vec<usize>, Vec<usiz
     (firsts, lasts)
 }
 
+/// Per side, the EMA spans behind the entry bands and the unstuck bands, each an ascending
+/// triplet of span_0, span_1 and their geometric mean.
 fn calc_ema_alphas(bot_params_pair: &BotParamsPair) -> EmaAlphas {
-    let mut ema_spans_long = [
-        bot_params_pair.long.ema_span_0,
-        bot_params_pair.long.ema_span_1,
-        (bot_params_pair.long.ema_span_0 * bot_params_pair.long.ema_span_1).sqrt(),
-    ];
-    ema_spans_long.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
-    let mut ema_spans_short = [
-        bot_params_pair.short.ema_span_0,
-        bot_params_pair.short.ema_span_1,
-        (bot_params_pair.short.ema_span_0 * bot_params_pair.short.ema_span_1).sqrt(),
-    ];
-    ema_spans_short.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
-    let ema_alphas_long = ema_spans_long.map(|x| 2.0 / (x + 1.0));
-    let ema_alphas_long_inv = ema_alphas_long.map(|x| 1.0 - x);
-
-    let ema_alphas_short = ema_spans_short.map(|x| 2.0 / (x + 1.0));
-    let ema_alphas_short_inv = ema_alphas_short.map(|x| 1.0 - x);
-
+    let side = |bot_params: &BotParams| {
+        Alphas::new(
+            bot_params.ema_spans_sorted(),
+            bot_params.unstuck_ema_spans_sorted(),
+        )
+    };
     EmaAlphas {
-        long: Alphas {
-            alphas: ema_alphas_long,
-            alphas_inv: ema_alphas_long_inv,
-        },
-        short: Alphas {
-            alphas: ema_alphas_short,
-            alphas_inv: ema_alphas_short_inv,
-        },
+        long: side(&bot_params_pair.long),
+        short: side(&bot_params_pair.short),
     }
 }
 
 fn analyze_backtest_basic(fills: &[Fill], equities: &Vec<f64>) -> Analysis {
-    if fills.len() <= 1 {
+    // daily metrics need at least two days
+    if fills.len() <= 1 || equities.len() <= 1440 {
         return Analysis::default();
     }
     // Calculate daily equities
@@ -1983,6 +3435,20 @@ fn analyze_backtest_basic(fills: &[Fill], equities: &Vec<f64>) -> Analysis {
 
 pub fn analyze_backtest(fills: &[Fill], equities: &Vec<f64>) -> Analysis {
     let mut analysis = analyze_backtest_basic(fills, equities);
+    // over the whole run only: fills of a tail miss the positions opened before it
+    let [long, short] = calc_exposure_distributions(fills, equities.len());
+    analysis.exposure_mean_long = long.mean;
+    analysis.exposure_p50_long = long.p50;
+    analysis.exposure_p90_long = long.p90;
+    analysis.exposure_max_long = long.max;
+    analysis.exposure_mean_short = short.mean;
+    analysis.exposure_p50_short = short.p50;
+    analysis.exposure_p90_short = short.p90;
+    analysis.exposure_max_short = short.max;
+    let exposure_mean = long.mean + short.mean;
+    if exposure_mean > 0.0 {
+        analysis.return_on_deployed_margin = analysis.adg / exposure_mean;
+    }
 
     if fills.len() <= 1 {
         return analysis;
@@ -2078,11 +3544,57 @@ pub fn analyze_backtest_pair(
     for fill in btc_fills.iter_mut() {
         fill.balance_usd_total /= fill.btc_price; // Use actual BTC balance if available
         fill.pnl = fill.pnl / fill.btc_price; // Convert PNL to BTC
+        fill.position_price /= fill.btc_price; // keeps exposure, cost over balance, unitless
     }
     let analysis_btc = analyze_backtest(&btc_fills, &equities.btc);
     (analysis_usd, analysis_btc)
 }
 
+/// Backtests a candidate config for the optimizers, aborting it at prune_params' checkpoints
+/// if it falls behind.
+pub fn evaluate_backtest<'a>(
+    hlcvs: &'a ArrayView3<'a, f64>,
+    btc_usd_prices: &'a ArrayView1<'a, f64>,
+    bot_params_pair: BotParamsPair,
+    exchange_params_list: Vec<ExchangeParams>,
+    backtest_params: &BacktestParams,
+    prune_params: &PruneParams,
+) -> (Evaluation, Equities) {
+    let mut backtest = Backtest::new(
+        hlcvs,
+        btc_usd_prices,
+        bot_params_pair,
+        exchange_params_list,
+        backtest_params,
+    );
+    backtest.set_prune_params(prune_params.clone());
+    let (fills, equities) = backtest.run();
+    let evaluation = Evaluation {
+        analyses: analyze_backtest_pair(&fills, &equities, backtest.balance.use_btc_collateral),
+        partial_fitnesses: backtest.partial_fitnesses,
+        pruned: backtest.pruned,
+    };
+    (evaluation, equities)
+}
+
+/// Cheap stand-in for fitness while a backtest runs, lower being better: the daily log gain so
+/// far over the worst drawdown so far, floored at 1%, negated.
+pub fn calc_partial_fitness(equities: &[f64], starting_balance: f64) -> f64 {
+    let n_days = equities.len() as f64 / 1440.0;
+    let equity = match equities.last() {
+        Some(&equity) if n_days > 0.0 => equity,
+        _ => return f64::NAN,
+    };
+    let mut peak = starting_balance;
+    let mut drawdown_worst = 0.0f64;
+    for &equity in equities {
+        peak = peak.max(equity);
+        drawdown_worst = drawdown_worst.max(1.0 - equity / peak);
+    }
+    let daily_log_gain = (equity / starting_balance).ln() / n_days;
+    -daily_log_gain / drawdown_worst.max(0.01)
+}
+
 fn calc_drawdowns(equity_series: &[f64]) -> Vec<f64> {
     let mut cumulative_returns = vec![1.0];
     let mut cumulative_max = vec![1.0];
@@ -2101,6 +3613,62 @@ fn calc_drawdowns(equity_series: &[f64]) -> Vec<f64> {
         .collect()
 }
 
+/// (peak index, trough index, drawdown) of the worst peak-to-trough fall, drawdown as a
+/// share of the peak; (0, 0, 0.0) if equity never falls.
+pub fn calc_max_drawdown_span(equities: &[f64]) -> (usize, usize, f64) {
+    let mut worst = (0, 0, 0.0);
+    let mut peak_idx = 0;
+    for (i, &equity) in equities.iter().enumerate() {
+        if equity > equities[peak_idx] {
+            peak_idx = i;
+        }
+        let drawdown = 1.0 - equity / equities[peak_idx];
+        if drawdown > worst.2 {
+            worst = (peak_idx, i, drawdown);
+        }
+    }
+    worst
+}
+
+/// Thins equities to the first and last sample plus each of n_buckets equal buckets' min
+/// and max, in order, so crashes within a bucket stay visible. The worst drawdown's peak
+/// and trough are always kept: the kept samples being a subsequence, their max drawdown
+/// equals the full curve's.
+pub fn downsample_equities(equities: &[f64], n_buckets: usize) -> Result<EquityDownsample, String> {
+    if n_buckets == 0 {
+        return Err("n_buckets must be at least 1".to_string());
+    }
+    let n = equities.len();
+    if n == 0 {
+        return Ok(EquityDownsample::default());
+    }
+    let n_buckets = n_buckets.min(n);
+    let bucket_bounds: Vec<usize> = (0..=n_buckets).map(|i| i * n / n_buckets).collect();
+    let (peak_idx, trough_idx, _) = calc_max_drawdown_span(equities);
+    let mut indices = vec![0, n - 1, peak_idx, trough_idx];
+    for bounds in bucket_bounds.windows(2) {
+        let (mut min_idx, mut max_idx) = (bounds[0], bounds[0]);
+        for i in bounds[0]..bounds[1] {
+            if equities[i] < equities[min_idx] {
+                min_idx = i;
+            }
+            if equities[i] > equities[max_idx] {
+                max_idx = i;
+            }
+        }
+        indices.push(min_idx);
+        indices.push(max_idx);
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    let values = indices.iter().map(|&i| equities[i]).collect();
+    Ok(EquityDownsample {
+        indices,
+        values,
+        bucket_bounds,
+    })
+}
+
 /// Calculates the normalized total variation (sum of absolute first differences divided by net equity gain)
 pub fn calc_equity_choppiness(equity: &[f64]) -> f64 {
     if equity.len() < 2 {
@@ -2200,6 +3768,79 @@ pub fn smoothed_terminal_geometric_gain_and_adg(daily_eqs: &[f64]) -> (f64, f64)
 
 /// Calculates average volume per day as a percentage of balance.
 /// For each fill: abs(qty) * price / balance_at_fill
+struct ExposureDistribution {
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    max: f64,
+}
+
+/// Per pside distribution over candles 0..n_candles of wallet exposure, the summed cost of
+/// the side's positions over the balance. Rebuilt from the fills: between fills every
+/// position's size and price and the balance hold, so exposure only changes at fills.
+fn calc_exposure_distributions(fills: &[Fill], n_candles: usize) -> [ExposureDistribution; 2] {
+    let mut costs: HashMap<(&str, usize), f64> = HashMap::new();
+    let mut exposures = [0.0; 2];
+    // (exposure, candles held) per pside
+    let mut spans: [Vec<(f64, usize)>; 2] = [Vec::new(), Vec::new()];
+    let mut k_prev = 0;
+    for fill in fills {
+        let k = fill.index.min(n_candles);
+        if k > k_prev {
+            for pside in [LONG, SHORT] {
+                spans[pside].push((exposures[pside], k - k_prev));
+            }
+            k_prev = k;
+        }
+        let pside = fill.order_type.pside();
+        costs.insert(
+            (fill.coin.as_str(), pside),
+            fill.position_size.abs() * fill.position_price,
+        );
+        for pside in [LONG, SHORT] {
+            let cost: f64 = costs
+                .iter()
+                .filter(|((_, cost_pside), _)| *cost_pside == pside)
+                .map(|(_, cost)| cost)
+                .sum();
+            exposures[pside] = if fill.balance_usd_total > 0.0 {
+                cost / fill.balance_usd_total
+            } else {
+                0.0
+            };
+        }
+    }
+    for pside in [LONG, SHORT] {
+        spans[pside].push((exposures[pside], n_candles.saturating_sub(k_prev)));
+    }
+    spans.map(|mut spans| {
+        spans.retain(|&(_, n)| n > 0);
+        spans.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let total = spans.iter().map(|&(_, n)| n).sum::<usize>() as f64;
+        // smallest exposure held at or below for q of the candles
+        let quantile = |q: f64| {
+            let mut held = 0.0;
+            for &(exposure, n) in &spans {
+                held += n as f64;
+                if held >= q * total {
+                    return exposure;
+                }
+            }
+            0.0
+        };
+        ExposureDistribution {
+            mean: if total > 0.0 {
+                spans.iter().map(|&(e, n)| e * n as f64).sum::<f64>() / total
+            } else {
+                0.0
+            },
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            max: spans.last().map_or(0.0, |&(exposure, _)| exposure),
+        }
+    })
+}
+
 pub fn calc_avg_volume_pct_per_day(fills: &[Fill]) -> f64 {
     if fills.is_empty() {
         return 0.0;
@@ -2222,3 +3863,1499 @@ pub fn calc_avg_volume_pct_per_day(fills: &[Fill]) -> f64 {
         daily_totals.values().sum::<f64>() / total_days
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results::BacktestResult;
+    use crate::synthetic::{generate_synthetic_hlcvs, SyntheticMarketSpec, SyntheticRegime};
+    use crate::types::CashFlow;
+    use crate::utils::calc_ema_spans;
+
+    fn test_backtest_params(n_coins: usize) -> BacktestParams {
+        serde_json::from_value(serde_json::json!({
+            "starting_balance": 1000.0,
+            "maker_fee": 0.0002,
+            "coins": (0..n_coins).map(|idx| format!("COIN{}", idx)).collect::<Vec<_>>(),
+            "correlation_matrix": [],
+        }))
+        .unwrap()
+    }
+
+    fn test_bot_params() -> BotParams {
+        BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.003,
+            close_grid_qty_pct: 0.2,
+            close_trailing_qty_pct: 0.5,
+            close_trailing_retracement_pct: 0.005,
+            close_trailing_threshold_pct: 0.01,
+            enforce_exposure_limit: true,
+            ema_span_0: 200.0,
+            ema_span_1: 800.0,
+            entry_grid_double_down_factor: 1.0,
+            entry_grid_spacing_pct: 0.02,
+            entry_grid_spacing_weight: 0.5,
+            entry_initial_ema_dist: 0.002,
+            entry_initial_qty_pct: 0.1,
+            entry_trailing_double_down_factor: 1.0,
+            entry_trailing_retracement_pct: 0.005,
+            entry_trailing_threshold_pct: 0.01,
+            filter_noisiness_rolling_window: 60,
+            filter_volume_rolling_window: 60,
+            n_positions: 1,
+            total_wallet_exposure_limit: 0.75,
+            unstuck_close_pct: 0.05,
+            unstuck_ema_dist: 0.001,
+            unstuck_ema_span_0: 200.0,
+            unstuck_ema_span_1: 800.0,
+            unstuck_loss_allowance_pct: 0.02,
+            unstuck_threshold: 0.6,
+            wallet_exposure_limit: 0.75,
+            ..Default::default()
+        }
+    }
+
+    fn long_only(bot_params: BotParams) -> BotParamsPair {
+        BotParamsPair {
+            short: BotParams {
+                wallet_exposure_limit: 0.0,
+                total_wallet_exposure_limit: 0.0,
+                ..bot_params.clone()
+            },
+            long: bot_params,
+            ..Default::default()
+        }
+    }
+
+    fn test_exchange_params(n_coins: usize) -> Vec<ExchangeParams> {
+        vec![
+            ExchangeParams {
+                qty_step: 0.001,
+                price_step: 0.001,
+                min_qty: 0.001,
+                min_cost: 1.0,
+                c_mult: 1.0,
+                ..Default::default()
+            };
+            n_coins
+        ]
+    }
+
+    fn sideways_hlcvs(n_coins: usize, n_candles: usize, seed: u64) -> Array3<f64> {
+        let mut hlcvs = generate_synthetic_hlcvs(&SyntheticMarketSpec {
+            seed,
+            n_coins,
+            regimes: vec![SyntheticRegime {
+                n_candles,
+                volatility: 0.003,
+                mean_reversion: 0.01,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        // exchange candles are on price_step; orders priced off them must be too
+        let price_step = test_exchange_params(1)[0].price_step;
+        hlcvs
+            .slice_mut(s![.., .., HIGH..VOLUME])
+            .mapv_inplace(|price| round_(price, price_step));
+        hlcvs
+    }
+
+    fn long_exposure(backtest: &Backtest, idx: SymbolIdx) -> f64 {
+        backtest.positions.long.get(&idx).map_or(0.0, |position| {
+            calc_wallet_exposure(1.0, backtest.balance.usd, position.size, position.price)
+        })
+    }
+
+    #[test]
+    fn ema_bands_span_the_per_span_emas() {
+        let hlcvs = sideways_hlcvs(1, 500, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params = test_bot_params();
+        let bot_params_pair = BotParamsPair {
+            long: bot_params.clone(),
+            short: BotParams {
+                ema_span_0: 30.0,
+                ema_span_1: 90.0,
+                ..bot_params
+            },
+            ..Default::default()
+        };
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            bot_params_pair.clone(),
+            test_exchange_params(1),
+            &test_backtest_params(1),
+        );
+        for k in 0..hlcvs.dim().0 {
+            backtest.update_emas(k);
+        }
+        // seeded with the first close, as in the backtest
+        let ema = |span: f64| {
+            let alpha = 2.0 / (span + 1.0);
+            hlcvs
+                .slice(s![.., 0, CLOSE])
+                .fold(hlcvs[[0, 0, CLOSE]], |ema, &close| {
+                    close * alpha + ema * (1.0 - alpha)
+                })
+        };
+        let assert_close = |a: f64, b: f64| assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+        for (pside, bot_params) in [
+            (LONG, &bot_params_pair.long),
+            (SHORT, &bot_params_pair.short),
+        ] {
+            let alphas = backtest.ema_alphas.side(pside);
+            for (triplet, spans) in [
+                (
+                    alphas.entry,
+                    calc_ema_spans(bot_params.ema_span_0, bot_params.ema_span_1),
+                ),
+                (
+                    alphas.unstuck,
+                    calc_ema_spans(bot_params.unstuck_ema_span_0, bot_params.unstuck_ema_span_1),
+                ),
+            ] {
+                let bands = backtest.emas[0].compute_bands(pside, alphas, triplet);
+                assert_eq!(bands.spans, spans);
+                let per_span = spans.map(ema);
+                for (ema, expected) in bands.emas.iter().zip(per_span) {
+                    assert_close(*ema, expected);
+                }
+                assert_close(bands.upper(), per_span.into_iter().fold(f64::MIN, f64::max));
+                assert_close(bands.lower(), per_span.into_iter().fold(f64::MAX, f64::min));
+            }
+            let ema_bands = backtest.create_state_params(0, 0, pside).ema_bands;
+            let entry = backtest.emas[0].compute_bands(pside, alphas, alphas.entry);
+            assert_eq!(
+                (ema_bands.upper, ema_bands.lower),
+                (entry.upper(), entry.lower())
+            );
+        }
+    }
+
+    #[test]
+    fn auto_reduce_brings_exposure_back_under_limit_after_withdrawal() {
+        let hlcvs = sideways_hlcvs(1, 3000, 7);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params = BotParams {
+            auto_reduce_enabled: true,
+            auto_reduce_tolerance_pct: 0.01,
+            ..test_bot_params()
+        };
+        let limit = bot_params.wallet_exposure_limit * 1.01;
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            long_only(bot_params.clone()),
+            test_exchange_params(1),
+            &test_backtest_params(1),
+        );
+        backtest.init_trailing_prices();
+        // the first candle the position holds a fifth of its limit
+        let k_withdrawal = (1..2000)
+            .find(|&k| {
+                backtest.step(k);
+                long_exposure(&backtest, 0) > bot_params.wallet_exposure_limit / 5.0
+            })
+            .unwrap()
+            + 1;
+
+        // withdrawing 90% of the balance takes exposure to twice the limit
+        let mut backtest_params = test_backtest_params(1);
+        backtest_params.cash_flows = vec![CashFlow {
+            k: k_withdrawal,
+            amount: -backtest.balance.usd * 0.9,
+        }];
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            long_only(bot_params),
+            test_exchange_params(1),
+            &backtest_params,
+        );
+        backtest.init_trailing_prices();
+        for k in 1..=k_withdrawal {
+            backtest.step(k);
+        }
+        let exposures: Vec<f64> = (k_withdrawal + 1..k_withdrawal + 60)
+            .map(|k| {
+                backtest.step(k);
+                long_exposure(&backtest, 0)
+            })
+            .collect();
+        assert!(exposures[0] > limit);
+        let k_converged = exposures
+            .iter()
+            .position(|&exposure| exposure <= limit)
+            .expect("exposure converges");
+        assert!(k_converged < 10, "{:?}", exposures);
+        assert!(exposures[k_converged..]
+            .iter()
+            .all(|&exposure| exposure <= limit));
+    }
+
+    #[test]
+    fn position_cost_plateaus_at_cap_as_balance_grows() {
+        let n_candles = 10000;
+        let hlcvs = sideways_hlcvs(2, n_candles, 1);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(n_candles);
+        let btc_usd_prices = btc_usd_prices.view();
+        let mut backtest_params = test_backtest_params(2);
+        // deposits grow the balance tenfold
+        backtest_params.cash_flows = (1..10)
+            .map(|i| CashFlow {
+                k: i * 1000,
+                amount: 1000.0,
+            })
+            .collect();
+        let run = |max_position_cost: f64| {
+            let bot_params_pair = long_only(BotParams {
+                max_position_cost,
+                n_positions: 2,
+                total_wallet_exposure_limit: 1.5,
+                ..test_bot_params()
+            });
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                bot_params_pair.clone(),
+                test_exchange_params(2),
+                &backtest_params,
+            );
+            let (fills, equities) = backtest.run();
+            let position_costs: Vec<(usize, f64)> = fills
+                .iter()
+                .map(|fill| (fill.index, fill.position_size.abs() * fill.position_price))
+                .collect();
+            let result = BacktestResult::new(
+                fills,
+                equities,
+                false,
+                bot_params_pair,
+                test_exchange_params(2),
+                backtest_params.clone(),
+                0,
+            )
+            .with_cost_capped_fills(&backtest.cost_capped_fills);
+            (position_costs, result)
+        };
+        let max_cost = |position_costs: &[(usize, f64)], k_from: usize| {
+            position_costs
+                .iter()
+                .filter(|&&(k, _)| k >= k_from)
+                .map(|&(_, cost)| cost)
+                .fold(0.0, f64::max)
+        };
+
+        let (uncapped_costs, uncapped) = run(0.0);
+        assert!(max_cost(&uncapped_costs, 5000) > 300.0 * WALLET_EXPOSURE_LEEWAY);
+        assert_eq!(uncapped.analysis_usd.n_cost_capped_fills, 0.0);
+
+        let (capped_costs, capped) = run(300.0);
+        assert!(max_cost(&capped_costs, 0) <= 300.0 * WALLET_EXPOSURE_LEEWAY);
+        assert!(max_cost(&capped_costs, 5000) > 250.0);
+        let n_cost_capped_fills: usize = capped
+            .coin_stats
+            .iter()
+            .map(|stats| stats.n_cost_capped_fills)
+            .sum();
+        assert!(n_cost_capped_fills > 0);
+        assert_eq!(
+            capped.analysis_usd.n_cost_capped_fills,
+            n_cost_capped_fills as f64
+        );
+    }
+
+    fn flat_hlcvs(n_coins: usize, n_candles: usize) -> Array3<f64> {
+        let mut hlcvs = Array3::from_elem((n_candles, n_coins, 4), 100.0);
+        hlcvs.slice_mut(s![.., .., VOLUME]).fill(1000.0);
+        hlcvs
+    }
+
+    #[test]
+    fn order_lifetimes_time_to_fill_on_scripted_path() {
+        let run = |hlcvs: &Array3<f64>, track_order_lifetimes: bool| {
+            let hlcvs = hlcvs.view();
+            let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+            let btc_usd_prices = btc_usd_prices.view();
+            let bot_params = BotParams {
+                n_positions: 2,
+                total_wallet_exposure_limit: 1.5,
+                ..test_bot_params()
+            };
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(bot_params),
+                test_exchange_params(2),
+                &test_backtest_params(2),
+            );
+            if track_order_lifetimes {
+                backtest.track_order_lifetimes();
+            }
+            let (fills, _) = backtest.run();
+            let stats = backtest.order_lifetime_stats();
+            (fills, stats, backtest.open_orders(0, LONG))
+        };
+        let initial_stats = |stats: &[OrderLifetimeStats]| {
+            stats
+                .iter()
+                .find(|stats| stats.order_type == OrderType::EntryInitialNormalLong)
+                .cloned()
+                .unwrap()
+        };
+
+        // flat at 100: one initial entry per coin, laid out on candle 1 and never replaced
+        let (fills, stats, open_orders) = run(&flat_hlcvs(2, 300), true);
+        assert!(fills.is_empty());
+        let initial = initial_stats(&stats);
+        assert_eq!(
+            (
+                initial.n_created,
+                initial.n_cancelled,
+                initial.n_open_at_end
+            ),
+            (2, 0, 2)
+        );
+        let entry_price = open_orders
+            .iter()
+            .find(|order| order.order_type == OrderType::EntryInitialNormalLong)
+            .unwrap()
+            .price;
+
+        // each coin dips through its initial entry once, at a known candle
+        let mut hlcvs = flat_hlcvs(2, 300);
+        for (idx, k_touch) in [(0, 50), (1, 120)] {
+            hlcvs[[k_touch, idx, LOW]] = entry_price - 0.002;
+        }
+        let (fills, stats, _) = run(&hlcvs, true);
+        let entry_fills: Vec<(usize, &str)> = fills
+            .iter()
+            .filter(|fill| fill.order_type == OrderType::EntryInitialNormalLong)
+            .map(|fill| (fill.index, fill.coin.as_str()))
+            .collect();
+        assert_eq!(entry_fills, vec![(50, "COIN0"), (120, "COIN1")]);
+        let initial = initial_stats(&stats);
+        // COIN0's fill moves the balance, so COIN1's entry is relaid with a new qty at 50
+        assert_eq!(
+            (initial.n_created, initial.n_filled, initial.n_cancelled),
+            (3, 2, 1)
+        );
+        assert_eq!(initial.cancelled_lifetime_mean, 49.0);
+        assert_eq!(initial.time_to_fill_max, 70);
+        assert_eq!(initial.time_to_fill_mean, (49.0 + 70.0) / 2.0);
+        for stats in &stats {
+            assert_eq!(
+                stats.n_created,
+                stats.n_filled + stats.n_cancelled + stats.n_open_at_end,
+                "{:?}",
+                stats
+            );
+        }
+
+        // opt-in only
+        let (_, stats, _) = run(&hlcvs, false);
+        assert!(stats.is_empty());
+    }
+
+    #[test]
+    fn average_cost_lots_add_up_to_close_pnl() {
+        let hlcvs = sideways_hlcvs(2, 20000, 4);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params = BotParams {
+            n_positions: 2,
+            total_wallet_exposure_limit: 1.5,
+            ..test_bot_params()
+        };
+        let exchange_params: Vec<ExchangeParams> = test_exchange_params(2)
+            .into_iter()
+            .map(|exchange_params| ExchangeParams {
+                c_mult: 0.1,
+                ..exchange_params
+            })
+            .collect();
+        let run = |lot_method: Option<LotMethod>| {
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                BotParamsPair {
+                    long: bot_params.clone(),
+                    short: bot_params.clone(),
+                    ..Default::default()
+                },
+                exchange_params.clone(),
+                &test_backtest_params(2),
+            );
+            if let Some(lot_method) = lot_method {
+                backtest.track_lots(lot_method);
+            }
+            let (fills, _) = backtest.run();
+            (fills, backtest.lot_attributions())
+        };
+
+        let (fills, attributions) = run(Some(LotMethod::AverageCost));
+        let n_closes = fills
+            .iter()
+            .filter(|fill| fill.order_type.is_close())
+            .count();
+        assert!(n_closes > 10);
+        assert_eq!(attributions.len(), n_closes);
+        for attribution in &attributions {
+            let close = &fills[attribution.fill_id];
+            assert!(close.order_type.is_close());
+            assert!(attribution.lots.iter().all(|lot| {
+                let entry = &fills[lot.entry_fill_id];
+                !entry.order_type.is_close()
+                    && entry.coin == close.coin
+                    && lot.entry_fill_id < attribution.fill_id
+            }));
+            assert_eq!(attribution.unattributed_qty, 0.0);
+            let qty: f64 = attribution.lots.iter().map(|lot| lot.qty).sum();
+            assert!((qty - close.fill_qty.abs()).abs() < 1e-9);
+            let pnl: f64 = attribution.lots.iter().map(|lot| lot.pnl).sum();
+            assert!((pnl - close.pnl).abs() < 1e-9 * close.pnl.abs().max(1.0));
+        }
+
+        // off unless asked for
+        assert!(run(None).1.is_empty());
+    }
+
+    #[test]
+    fn slippage_follows_the_seed() {
+        let hlcvs = sideways_hlcvs(1, 3000, 4);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let fill_prices = |seed: u64, slippage_pct: f64| {
+            let mut backtest_params = test_backtest_params(1);
+            backtest_params.seed = seed;
+            backtest_params.slippage_pct = slippage_pct;
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(test_bot_params()),
+                test_exchange_params(1),
+                &backtest_params,
+            );
+            let (fills, _) = backtest.run();
+            fills
+                .iter()
+                .map(|fill| fill.fill_price)
+                .collect::<Vec<f64>>()
+        };
+        let prices = fill_prices(1, 0.001);
+        assert!(prices.len() > 2);
+        assert_eq!(fill_prices(1, 0.001), prices);
+        assert_ne!(fill_prices(2, 0.001), prices);
+        // without slippage no draws are made, so the seed changes nothing
+        assert_eq!(fill_prices(1, 0.0), fill_prices(2, 0.0));
+        assert_ne!(fill_prices(1, 0.0), prices);
+    }
+
+    #[test]
+    fn stepwise_replay_matches_run() {
+        let n_candles = 3000;
+        let hlcvs = sideways_hlcvs(2, n_candles, 6);
+        let btc_usd_prices = Array1::ones(n_candles);
+        let bot_params_pair = BotParamsPair {
+            long: BotParams {
+                n_positions: 2,
+                ..test_bot_params()
+            },
+            short: BotParams {
+                n_positions: 2,
+                wallet_exposure_limit: 0.5,
+                total_wallet_exposure_limit: 0.5,
+                ..test_bot_params()
+            },
+            ..Default::default()
+        };
+        let (hlcvs_view, btc_usd_prices_view) = (hlcvs.view(), btc_usd_prices.view());
+        let mut backtest = Backtest::new(
+            &hlcvs_view,
+            &btc_usd_prices_view,
+            bot_params_pair.clone(),
+            test_exchange_params(2),
+            &test_backtest_params(2),
+        );
+        let (fills, equities) = backtest.run();
+        assert!(fills.len() > 2);
+
+        let mut stepwise = Backtest::new_stepwise(
+            hlcvs.slice(s![0, .., ..]),
+            bot_params_pair,
+            test_exchange_params(2),
+            &test_backtest_params(2),
+        );
+        // candle k is stepped once candle k + 1 is in, as run peeks at it
+        for k in 1..n_candles {
+            assert_eq!(stepwise.push_candles(hlcvs.slice(s![k, .., ..])), Ok(k));
+            if k >= 2 {
+                stepwise.step(k - 1);
+            }
+        }
+        assert_eq!(format!("{:?}", stepwise.fills()), format!("{:?}", fills));
+        assert_eq!(stepwise.equities.usd, equities.usd);
+    }
+
+    #[test]
+    fn stepwise_backtests_never_delist() {
+        // coin 0's data ends at candle 2800, with a position open, and stays flat for over a day;
+        // coin 1's fills keep every position's orders recomputed
+        let (n_listed, n_candles) = (2800, 4300);
+        let mut hlcvs = sideways_hlcvs(2, n_candles, 7);
+        let last_close = hlcvs[[n_listed - 1, 0, CLOSE]];
+        for k in n_listed..n_candles {
+            hlcvs
+                .slice_mut(s![k, 0, ..])
+                .assign(&ndarray::arr1(&[last_close, last_close, last_close, 0.0]));
+        }
+        let bot_params = BotParams {
+            n_positions: 2,
+            ..test_bot_params()
+        };
+        let btc_usd_prices = Array1::ones(n_candles);
+        let (hlcvs_view, btc_usd_prices_view) = (hlcvs.view(), btc_usd_prices.view());
+        let mut backtest = Backtest::new(
+            &hlcvs_view,
+            &btc_usd_prices_view,
+            long_only(bot_params.clone()),
+            test_exchange_params(2),
+            &test_backtest_params(2),
+        );
+        let (fills, _) = backtest.run();
+        let is_delisting_close = |fill: &Fill| fill.coin == "COIN0" && fill.index >= n_listed;
+        let delisting_closes: Vec<&Fill> = fills.iter().filter(|f| is_delisting_close(f)).collect();
+        assert_eq!(delisting_closes.len(), 1);
+        assert_eq!(delisting_closes[0].order_type, OrderType::CloseUnstuckLong);
+        assert_eq!(delisting_closes[0].position_size, 0.0);
+
+        let mut stepwise = Backtest::new_stepwise(
+            hlcvs.slice(s![0, .., ..]),
+            long_only(bot_params),
+            test_exchange_params(2),
+            &test_backtest_params(2),
+        );
+        for k in 1..n_candles {
+            stepwise.push_candles(hlcvs.slice(s![k, .., ..])).unwrap();
+            if k >= 2 {
+                stepwise.step(k - 1);
+            }
+        }
+        // the same fills up to the delisting, after which coin 0's position is kept
+        let delisted_at = fills.iter().position(is_delisting_close).unwrap();
+        assert_eq!(
+            format!("{:?}", &stepwise.fills()[..delisted_at]),
+            format!("{:?}", &fills[..delisted_at])
+        );
+        assert!(!stepwise.fills().iter().any(is_delisting_close));
+        assert!(stepwise.positions.long.contains_key(&0));
+    }
+
+    #[derive(Default)]
+    struct CallCounts {
+        fills: usize,
+        order_updates: usize,
+        candles: Vec<usize>, // k of each on_candle
+    }
+
+    struct CountingObserver(std::sync::Arc<std::sync::Mutex<CallCounts>>);
+
+    impl BacktestObserver for CountingObserver {
+        fn on_fill(&mut self, _fill: &Fill) {
+            self.0.lock().unwrap().fills += 1;
+        }
+
+        fn on_order_update(
+            &mut self,
+            _k: usize,
+            _idx: SymbolIdx,
+            _pside: usize,
+            _entries: &[Order],
+            _closes: &[Order],
+        ) {
+            self.0.lock().unwrap().order_updates += 1;
+        }
+
+        fn on_candle(&mut self, candle: &CandleSnapshot) {
+            self.0.lock().unwrap().candles.push(candle.k);
+        }
+    }
+
+    #[test]
+    fn observers_see_every_fill_and_candle() {
+        let n_candles = 3000;
+        let hlcvs = sideways_hlcvs(2, n_candles, 5);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(n_candles);
+        let btc_usd_prices = btc_usd_prices.view();
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            long_only(test_bot_params()),
+            test_exchange_params(2),
+            &test_backtest_params(2),
+        );
+        let counts = std::sync::Arc::new(std::sync::Mutex::new(CallCounts::default()));
+        backtest.add_observer(Box::new(CountingObserver(counts.clone())));
+        let (fills, equities) = backtest.run();
+        let counts = counts.lock().unwrap();
+        assert!(fills.len() > 2);
+        assert_eq!(counts.fills, fills.len());
+        assert!(counts.order_updates > 0);
+        // the last candle is only peeked at
+        assert_eq!(counts.candles, (1..n_candles - 1).collect::<Vec<_>>());
+        // the built-in equity curve starts from the starting balance
+        assert_eq!(equities.usd.len(), counts.candles.len() + 1);
+    }
+
+    // Throughput of the hot loop over many symbols, for comparing builds:
+    #[test]
+    fn downsampling_keeps_the_worst_drawdown() {
+        // the worst fall is 100 -> 60; its peak is neither an end nor the bucket's max
+        let equities = [90.0, 100.0, 60.0, 200.0, 140.0, 150.0];
+        assert_eq!(calc_max_drawdown_span(&equities), (1, 2, 0.4));
+        let downsample = downsample_equities(&equities, 1).unwrap();
+        assert_eq!(downsample.indices, [0, 1, 2, 3, 5]);
+        assert_eq!(downsample.values, [90.0, 100.0, 60.0, 200.0, 150.0]);
+        assert_eq!(downsample.bucket_bounds, [0, 6]);
+        assert_eq!(calc_max_drawdown_span(&downsample.values).2, 0.4);
+
+        // more buckets than samples keeps everything
+        let downsample = downsample_equities(&equities, 10).unwrap();
+        assert_eq!(downsample.indices, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(downsample.bucket_bounds, [0, 1, 2, 3, 4, 5, 6]);
+
+        let equities: Vec<f64> = (0..10_000)
+            .map(|i| 1000.0 + i as f64 * 0.01 + 50.0 * (i as f64 * 0.013).sin())
+            .collect();
+        let downsample = downsample_equities(&equities, 100).unwrap();
+        assert!(downsample.indices.len() <= 2 * 100 + 4);
+        assert!(downsample.indices.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(
+            calc_max_drawdown_span(&downsample.values).2,
+            calc_max_drawdown_span(&equities).2
+        );
+
+        assert!(downsample_equities(&equities, 0).is_err());
+        assert!(downsample_equities(&[], 10).unwrap().indices.is_empty());
+        assert_eq!(calc_max_drawdown_span(&[1.0, 2.0, 3.0]), (0, 0, 0.0));
+    }
+
+    #[test]
+    fn funding_settles_from_longs_to_shorts() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let mut backtest_params = test_backtest_params(1);
+        backtest_params.funding_rate = 0.001;
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            BotParamsPair::default(),
+            test_exchange_params(1),
+            &backtest_params,
+        );
+        backtest.positions.long.insert(
+            0,
+            Position {
+                size: 2.0,
+                price: 100.0,
+                ..Default::default()
+            },
+        );
+        backtest.positions.short.insert(
+            0,
+            Position {
+                size: -1.0,
+                price: 100.0,
+                ..Default::default()
+            },
+        );
+        let balance = backtest.balance.usd;
+        backtest.settle_funding(5);
+        let notional = hlcvs[[5, 0, CLOSE]] * 0.001;
+        let long_paid = backtest.positions.long[&0].accrued_funding;
+        let short_paid = backtest.positions.short[&0].accrued_funding;
+        assert!((long_paid - 2.0 * notional).abs() < 1e-12);
+        assert!((short_paid + notional).abs() < 1e-12);
+        // the wallet pays the net of both sides
+        assert!((balance - backtest.balance.usd - notional).abs() < 1e-9);
+    }
+
+    #[test]
+    fn entry_fills_are_cropped_to_the_sides_allocation() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params_pair = BotParamsPair {
+            long_allocation_pct: 0.5,
+            ..long_only(test_bot_params())
+        };
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            bot_params_pair,
+            test_exchange_params(1),
+            &test_backtest_params(1),
+        );
+        backtest.positions.long.insert(
+            0,
+            Position {
+                size: 3.0,
+                price: 100.0,
+                ..Default::default()
+            },
+        );
+        let entry = |qty: f64| Order {
+            qty,
+            price: 100.0,
+            order_type: OrderType::EntryGridNormalLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        };
+        // 0.75 of half the 1000.0 balance, with leeway, leaves 78.75 above the 300.0 held
+        assert_eq!(
+            backtest.crop_entry_fill(0, LONG, entry(0.5)).unwrap().qty,
+            0.5
+        );
+        assert_eq!(
+            backtest.crop_entry_fill(0, LONG, entry(2.0)).unwrap().qty,
+            0.787
+        );
+        backtest.positions.long.get_mut(&0).unwrap().size = 3.787;
+        assert!(backtest.crop_entry_fill(0, LONG, entry(2.0)).is_none());
+    }
+
+    #[test]
+    fn initial_entries_past_the_free_slots_are_refused() {
+        let n_coins = 4;
+        let hlcvs = sideways_hlcvs(n_coins, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params = BotParams {
+            n_positions: 2,
+            ..test_bot_params()
+        };
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            long_only(bot_params),
+            test_exchange_params(n_coins),
+            &test_backtest_params(n_coins),
+        );
+        let k = 5;
+        backtest.positions.long.insert(
+            0,
+            Position {
+                size: 1.0,
+                price: 100.0,
+                ..Default::default()
+            },
+        );
+        // coins 1 and 2 would fill on candle k, coin 3's entry rests far below
+        for (idx, price) in [(1, 2.0), (2, 2.0), (3, 0.001)] {
+            backtest.open_orders.long.entry(idx).or_default().entries = vec![Order {
+                qty: 1.0,
+                price: hlcvs[[k, idx as usize, HIGH]] * price,
+                order_type: OrderType::EntryInitialNormalLong,
+                iceberg_qty: None,
+                min_fill_qty: 0.0,
+            }];
+        }
+        // one slot is free; the selection ranking settles it, then the coin index
+        let idxs = [1, 2, 3];
+        backtest.slot_ranking_long = vec![2, 1];
+        assert_eq!(backtest.refused_initial_entries(k, LONG, &idxs), [1]);
+        backtest.slot_ranking_long.clear();
+        assert_eq!(backtest.refused_initial_entries(k, LONG, &idxs), [2]);
+        // with a second slot free, or nothing filling, no entry is refused
+        backtest.positions.long.clear();
+        assert!(backtest.refused_initial_entries(k, LONG, &idxs).is_empty());
+        backtest.positions.long.insert(0, Position::default());
+        assert!(backtest.refused_initial_entries(k, LONG, &[3]).is_empty());
+    }
+
+    #[test]
+    fn scheduled_modes_wind_the_side_down() {
+        let hlcvs = sideways_hlcvs(1, 3000, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let run = |mode_schedule: Vec<ModeSwitch>| {
+            let mut backtest_params = test_backtest_params(1);
+            backtest_params.mode_schedule = mode_schedule;
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(test_bot_params()),
+                test_exchange_params(1),
+                &backtest_params,
+            );
+            let (fills, _) = backtest.run();
+            (fills, backtest.wind_downs)
+        };
+        let (fills, wind_downs) = run(Vec::new());
+        assert!(wind_downs.is_empty());
+        // a 6.268 long is open from candle 2057 until the grid closes it at 2134
+        let k = 2060;
+        let open = fills.iter().rfind(|fill| fill.index < k).unwrap();
+        assert_eq!(open.position_size, 6.268);
+
+        // panic closes it at the switch's close and places nothing afterwards
+        let (fills, wind_downs) = run(vec![ModeSwitch {
+            k,
+            pside: Some(LONG),
+            mode: TradingMode::Panic,
+        }]);
+        let panic = fills.last().unwrap();
+        assert_eq!(
+            (
+                panic.index,
+                panic.order_type,
+                panic.fill_qty,
+                panic.fill_price
+            ),
+            (k, OrderType::ClosePanicLong, -6.268, hlcvs[[k, 0, CLOSE]])
+        );
+        assert_eq!(panic.position_size, 0.0);
+        assert_eq!(wind_downs.len(), 1);
+        let wind_down = &wind_downs[0];
+        assert_eq!(
+            (
+                wind_down.pside,
+                wind_down.mode,
+                wind_down.k,
+                wind_down.n_positions,
+                wind_down.duration,
+                wind_down.slippage_pct,
+            ),
+            (LONG, TradingMode::Panic, k, 1, Some(0), 0.0)
+        );
+
+        // graceful_stop closes out without another entry; the flat short side is done at once
+        let (fills, wind_downs) = run(vec![ModeSwitch {
+            k,
+            pside: None,
+            mode: TradingMode::GracefulStop,
+        }]);
+        let after: Vec<&Fill> = fills.iter().filter(|fill| fill.index >= k).collect();
+        assert!(!after.is_empty());
+        assert!(after.iter().all(|fill| fill.order_type.is_close()));
+        let flat_at = after.last().unwrap();
+        assert_eq!(flat_at.position_size, 0.0);
+        assert_eq!(wind_downs.len(), 2);
+        assert_eq!(
+            (wind_downs[0].n_positions, wind_downs[0].duration),
+            (1, Some(flat_at.index - k))
+        );
+        assert_eq!((wind_downs[1].pside, wind_downs[1].n_positions), (SHORT, 0));
+        assert_eq!(wind_downs[1].duration, Some(0));
+    }
+
+    #[test]
+    fn unstuck_closes_are_fill_or_kill() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            long_only(test_bot_params()),
+            test_exchange_params(1),
+            &test_backtest_params(1),
+        );
+        backtest.positions.long.insert(
+            0,
+            Position {
+                size: 7.0,
+                price: 110.0,
+                ..Default::default()
+            },
+        );
+        backtest.open_orders.long.entry(0).or_default();
+        // the stuck long's unstucking close takes all of its qty or nothing
+        let (_, _, unstuck) = backtest.calc_unstucking_close(5).unwrap();
+        assert_eq!(unstuck.order_type, OrderType::CloseUnstuckLong);
+        assert!(unstuck.qty < 0.0 && unstuck.qty > -7.0);
+        assert_eq!(unstuck.min_fill_qty, unstuck.qty);
+        // as does the close of a delisted coin, of the whole position
+        backtest.last_valid_timestamps.insert(0, 5);
+        backtest.update_open_orders_long_single(5, 0);
+        let closes = &backtest.open_orders.long[&0].closes;
+        assert_eq!(closes.len(), 1);
+        assert_eq!(
+            (closes[0].order_type, closes[0].qty, closes[0].min_fill_qty),
+            (OrderType::CloseUnstuckLong, -7.0, -7.0)
+        );
+    }
+
+    #[test]
+    fn markup_floors_take_the_median_candle_range() {
+        let mut hlcvs = flat_hlcvs(2, 5);
+        for (k, range) in [(0, 1.0), (1, 3.0), (2, 2.0)] {
+            hlcvs[[k, 0, HIGH]] = 100.0 + range / 2.0;
+            hlcvs[[k, 0, LOW]] = 100.0 - range / 2.0;
+        }
+        // filled candles are flat without volume and left out
+        hlcvs.slice_mut(s![3.., 0, VOLUME]).fill(0.0);
+        let floors = |markup_floor_median_range: bool, markup_floor_taker_fee: f64| {
+            let mut backtest_params = test_backtest_params(2);
+            backtest_params.markup_floor_median_range = markup_floor_median_range;
+            backtest_params.markup_floor_taker_fee = markup_floor_taker_fee;
+            calc_markup_floors(&hlcvs.view(), &backtest_params)
+        };
+        assert_eq!(floors(false, 0.0), [0.0, 0.0]);
+        assert_eq!(floors(false, 0.0005), [0.001, 0.001]);
+        // coin 1 never moves, so its floor stays at the fees
+        assert_eq!(floors(true, 0.0005), [0.02, 0.001]);
+        assert_eq!(floors(true, 0.02), [0.04, 0.04]);
+    }
+
+    #[test]
+    fn trailing_prices_are_reconstructed_from_the_candles() {
+        let hlcvs = sideways_hlcvs(1, 3000, 3);
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let bot_params = BotParams {
+            close_trailing_grid_ratio: 0.5,
+            close_trailing_step_multiple: 1.0,
+            ..test_bot_params()
+        };
+        let run = |n_candles: usize| {
+            let hlcvs = hlcvs.slice(s![..n_candles, .., ..]);
+            let btc_usd_prices = btc_usd_prices.slice(s![..n_candles]);
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(bot_params.clone()),
+                test_exchange_params(1),
+                &test_backtest_params(1),
+            );
+            let (fills, _) = backtest.run();
+            (fills, format!("{:?}", backtest.trailing_prices.long[&0]))
+        };
+        // cut the run off while a position is held, a while after its last fill
+        let (fills, _) = run(hlcvs.dim().0);
+        let (last_fill, next_fill) = fills
+            .windows(2)
+            .find(|w| w[0].position_size > 0.0 && w[1].index > w[0].index + 20)
+            .map(|w| (w[0].index, w[1].index))
+            .unwrap();
+        // a run doesn't step its last candle, so this one ends just before the next fill
+        let (fills, held) = run(next_fill + 1);
+        assert_eq!(fills.last().unwrap().index, last_fill);
+        let reconstructed = reconstruct_trailing_price_bundle(
+            &test_exchange_params(1)[0],
+            &bot_params,
+            &hlcvs.slice(s![..next_fill, 0, ..]),
+            &[],
+            last_fill,
+            LONG,
+        )
+        .unwrap();
+        assert!(reconstructed.stepped_stop_price > 0.0);
+        assert_eq!(format!("{:?}", reconstructed), held);
+
+        // a stop stepped off the moving average can't be replayed without it
+        let bot_params = BotParams {
+            close_trailing_anchor: CloseTrailingAnchor::MovingAverage,
+            ..bot_params
+        };
+        assert!(reconstruct_trailing_price_bundle(
+            &test_exchange_params(1)[0],
+            &bot_params,
+            &hlcvs.slice(s![..next_fill, 0, ..]),
+            &[],
+            last_fill,
+            LONG,
+        )
+        .unwrap_err()
+        .contains("trailing_mas"));
+    }
+
+    #[test]
+    fn closes_read_their_balance_off_the_curve() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params_pair = BotParamsPair {
+            long_allocation_pct: 0.5,
+            ..long_only(test_bot_params())
+        };
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            bot_params_pair,
+            test_exchange_params(1),
+            &test_backtest_params(1),
+        );
+        let state_params = backtest.create_state_params(5, 0, LONG);
+        assert_eq!(state_params.balance, 500.0);
+        // without a curve closes see the running balance
+        assert_eq!(
+            backtest.close_state_params(5, LONG, &state_params).balance,
+            500.0
+        );
+        let balance_curve = |k: usize| 1000.0 + 100.0 * k as f64;
+        backtest.set_balance_curve(&balance_curve);
+        // allocated like the running balance
+        assert_eq!(
+            backtest.close_state_params(5, LONG, &state_params).balance,
+            750.0
+        );
+        assert_eq!(
+            backtest.close_state_params(8, LONG, &state_params).balance,
+            900.0
+        );
+        assert_eq!(backtest.create_state_params(5, 0, LONG).balance, 500.0);
+    }
+
+    #[test]
+    fn fills_past_the_impact_capacity_move_the_price() {
+        let hlcvs = sideways_hlcvs(2, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let mut backtest_params = test_backtest_params(2);
+        backtest_params
+            .impact_capacities
+            .insert("COIN0".to_string(), 100.0);
+        backtest_params.market_impact_pct = 0.01;
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            BotParamsPair::default(),
+            test_exchange_params(2),
+            &backtest_params,
+        );
+        let mut impacted = |k: usize, idx: SymbolIdx, qty: f64| {
+            let (order, impact_pct) = backtest.impacted(
+                k,
+                idx,
+                &Order::new(qty, 100.0, OrderType::EntryGridNormalLong),
+            );
+            (order.price, impact_pct)
+        };
+        // the first 100.0 of quote volume in a candle goes unpenalized
+        assert_eq!(impacted(5, 0, 0.5), (100.0, 0.0));
+        let (price, impact_pct) = impacted(5, 0, 1.0);
+        assert!((impact_pct - 0.005).abs() < 1e-12);
+        assert!((price - 100.5).abs() < 1e-9);
+        // sells count alike and are pushed down
+        let (price, impact_pct) = impacted(5, 0, -1.0);
+        assert!((impact_pct - 0.015).abs() < 1e-12);
+        assert!((price - 98.5).abs() < 1e-9);
+        // each candle starts afresh, and coins without a capacity are never penalized
+        assert_eq!(impacted(6, 0, 0.5), (100.0, 0.0));
+        assert_eq!(impacted(6, 1, 100.0), (100.0, 0.0));
+    }
+
+    #[test]
+    fn masked_coins_get_no_entries_on_the_side() {
+        let hlcvs = sideways_hlcvs(2, 2000, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params = BotParams {
+            n_positions: 2,
+            total_wallet_exposure_limit: 1.5,
+            ..test_bot_params()
+        };
+        let new_backtest = |backtest_params: &BacktestParams| {
+            Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(bot_params.clone()),
+                test_exchange_params(2),
+                backtest_params,
+            )
+        };
+        let coins_filled = |backtest_params: &BacktestParams| {
+            let (fills, _) = new_backtest(backtest_params).run();
+            fills
+                .into_iter()
+                .map(|fill| fill.coin)
+                .collect::<HashSet<_>>()
+        };
+        let mut backtest_params = test_backtest_params(2);
+        assert_eq!(coins_filled(&backtest_params).len(), 2);
+        let long_disabled = TradingMask {
+            long_enabled: false,
+            short_enabled: true,
+        };
+        backtest_params
+            .trading_masks
+            .insert("COIN1".to_string(), long_disabled);
+        assert_eq!(
+            coins_filled(&backtest_params),
+            HashSet::from(["COIN0".to_string()])
+        );
+
+        // masking mid-run cancels the side's open entries but keeps its closes
+        let mut backtest = new_backtest(&test_backtest_params(2));
+        let open_orders = backtest.open_orders.long.entry(1).or_default();
+        open_orders.entries = vec![Order::new(1.0, 99.0, OrderType::EntryGridNormalLong)];
+        open_orders.closes = vec![Order::new(-1.0, 101.0, OrderType::CloseGridLong)];
+        backtest.set_trading_mask(1, long_disabled);
+        assert!(backtest.open_orders.long[&1].entries.is_empty());
+        assert_eq!(backtest.open_orders.long[&1].closes.len(), 1);
+    }
+
+    #[test]
+    fn partial_grid_closes_recenter_trailing() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let new_backtest = |recenter_trailing_on_partial_close: bool| {
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(BotParams {
+                    recenter_trailing_on_partial_close,
+                    ..test_bot_params()
+                }),
+                test_exchange_params(1),
+                &test_backtest_params(1),
+            );
+            backtest.positions.long.insert(
+                0,
+                Position {
+                    size: 4.0,
+                    price: 100.0,
+                    ..Default::default()
+                },
+            );
+            backtest
+        };
+        let close = |qty: f64, order_type: OrderType| Order::new(qty, 101.0, order_type);
+        let backtest = new_backtest(true);
+        assert!(backtest.recenters_trailing(0, LONG, &close(-1.0, OrderType::CloseGridLong)));
+        assert!(backtest.recenters_trailing(0, LONG, &close(-1.0, OrderType::CloseTakerLong)));
+        // a close of the whole position, or of another kind, clears the bundle as before
+        assert!(!backtest.recenters_trailing(0, LONG, &close(-4.0, OrderType::CloseGridLong)));
+        assert!(!backtest.recenters_trailing(0, LONG, &close(-1.0, OrderType::CloseTrailingLong)));
+        assert!(!backtest.recenters_trailing(0, SHORT, &close(1.0, OrderType::CloseGridShort)));
+        assert!(!new_backtest(false).recenters_trailing(
+            0,
+            LONG,
+            &close(-1.0, OrderType::CloseGridLong)
+        ));
+
+        let centered = TrailingPriceBundle::centered(101.0);
+        assert_eq!(
+            (
+                centered.min_since_open,
+                centered.max_since_min,
+                centered.max_since_open,
+                centered.min_since_max,
+            ),
+            (101.0, 101.0, 101.0, 101.0)
+        );
+    }
+
+    #[test]
+    fn exposure_is_weighted_by_the_candles_held() {
+        let fill = |index: usize, position_size: f64, order_type: OrderType| Fill {
+            index,
+            coin: "COIN0".to_string(),
+            pnl: 0.0,
+            fee_paid: 0.0,
+            balance_usd_total: 1000.0,
+            balance_btc: 0.0,
+            balance_usd: 1000.0,
+            btc_price: 1.0,
+            fill_qty: 0.0,
+            fill_price: 100.0,
+            position_size,
+            position_price: 100.0,
+            order_type,
+            impact_pct: 0.0,
+        };
+        // long 0.1 over candles 2..6, 0.3 over 6..8; short 0.2 from candle 4 to the end
+        let fills = [
+            fill(2, 1.0, OrderType::EntryInitialNormalLong),
+            fill(4, -2.0, OrderType::EntryInitialNormalShort),
+            fill(6, 3.0, OrderType::EntryGridNormalLong),
+            fill(8, 0.0, OrderType::CloseGridLong),
+        ];
+        let [long, short] = calc_exposure_distributions(&fills, 10);
+        assert!((long.mean - 0.1).abs() < 1e-12);
+        assert_eq!((long.p50, long.p90, long.max), (0.1, 0.3, 0.3));
+        assert!((short.mean - 0.12).abs() < 1e-12);
+        assert_eq!((short.p50, short.p90, short.max), (0.2, 0.2, 0.2));
+
+        let [long, _] = calc_exposure_distributions(&[], 10);
+        assert_eq!((long.mean, long.p50, long.max), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn reduce_only_trips_on_drawdown_and_recovers() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let mut backtest_params = test_backtest_params(1);
+        backtest_params.reduce_only_drawdown_pct = 0.2;
+        backtest_params.reduce_only_recovery_pct = 0.1;
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            long_only(test_bot_params()),
+            test_exchange_params(1),
+            &backtest_params,
+        );
+        backtest.peak_equity = 1000.0;
+        let mut at_equity = |k: usize, equity: f64| {
+            backtest.equity = equity;
+            let changed = backtest.update_reduce_only(k);
+            let scale = backtest.create_state_params(k, 0, LONG).close_markup_scale;
+            (changed, backtest.reduce_only_active(), scale)
+        };
+        assert_eq!(at_equity(1, 900.0), (false, false, None));
+        // tripped at a 21% drawdown: closes at breakeven
+        assert_eq!(at_equity(2, 790.0), (true, true, Some(0.0)));
+        // halfway back to the recovery threshold, markups are half
+        let (_, active, scale) = at_equity(3, 850.0);
+        assert!(active && (scale.unwrap() - 0.5).abs() < 1e-9);
+        at_equity(4, 700.0);
+        assert_eq!(at_equity(5, 950.0), (true, false, None));
+        assert_eq!(at_equity(6, 950.0), (false, false, None));
+        assert_eq!(backtest.reduce_only_periods.len(), 1);
+        let period = &backtest.reduce_only_periods[0];
+        assert_eq!((period.start, period.end), (2, Some(5)));
+        assert!((period.drawdown_max - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn taker_unstuck_closes_pay_the_slippage_buffer() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let unstuck_close = |market_slippage_buffer_pct: f64| {
+            let mut backtest_params = test_backtest_params(1);
+            backtest_params.market_slippage_buffer_pct = market_slippage_buffer_pct;
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(test_bot_params()),
+                test_exchange_params(1),
+                &backtest_params,
+            );
+            backtest.positions.long.insert(
+                0,
+                Position {
+                    size: 7.0,
+                    price: 110.0,
+                    ..Default::default()
+                },
+            );
+            backtest.open_orders.long.entry(0).or_default();
+            let (_, _, close) = backtest.calc_unstucking_close(5).unwrap();
+            (close, backtest.taker_close_price(5, 0, SHORT))
+        };
+        // the EMA price is under the candle's close, so the close takes the market
+        let close = hlcvs[[5, 0, CLOSE]];
+        let (unbuffered, short_price) = unstuck_close(0.0);
+        assert_eq!((unbuffered.price, short_price), (close, close));
+        let (buffered, short_price) = unstuck_close(0.01);
+        assert_eq!(buffered.price, round_dn(close * 0.99, 0.001));
+        assert_eq!(short_price, round_up(close * 1.01, 0.001));
+    }
+
+    #[test]
+    fn unstuck_closes_lose_at_most_their_share_of_the_allowance() {
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let backtest_params = test_backtest_params(1);
+        let unstuck_close = |unstuck_max_allowance_fraction: f64| {
+            let mut bot_params = test_bot_params();
+            bot_params.unstuck_max_allowance_fraction = unstuck_max_allowance_fraction;
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(bot_params),
+                test_exchange_params(1),
+                &backtest_params,
+            );
+            backtest.positions.long.insert(
+                0,
+                Position {
+                    size: 7.0,
+                    price: 110.0,
+                    ..Default::default()
+                },
+            );
+            backtest.open_orders.long.entry(0).or_default();
+            let allowance = calc_auto_unstuck_allowance(
+                backtest.balance.usd_total_rounded,
+                0.02 * 0.75,
+                backtest.pnl_cumsum_max,
+                backtest.pnl_cumsum_running,
+                0.0,
+            );
+            let (_, _, close) = backtest.calc_unstucking_close(5).unwrap();
+            let loss = -calc_pnl_long(110.0, close.price, close.qty, 1.0);
+            (close.qty, loss, allowance)
+        };
+        // the whole allowance covers the close as sized by unstuck_close_pct
+        let (whole_qty, whole_loss, allowance) = unstuck_close(0.0);
+        assert!(whole_loss > 0.0 && whole_loss < allowance);
+        // a tenth of it does not, so the close is trimmed to lose at most that
+        let (capped_qty, capped_loss, _) = unstuck_close(0.1);
+        assert!(capped_qty.abs() < whole_qty.abs());
+        assert!(capped_loss > 0.0 && capped_loss <= allowance * 0.1);
+    }
+
+    #[test]
+    fn unstuck_closes_price_off_the_unstuck_bands() {
+        // spans both triplets share are tracked once
+        let alphas = Alphas::new([10.0, 20.0, 40.0], [20.0, 40.0, 80.0]);
+        assert_eq!(alphas.spans, vec![10.0, 20.0, 40.0, 80.0]);
+        assert_eq!((alphas.entry, alphas.unstuck), ([0, 1, 2], [1, 2, 3]));
+        assert_eq!(alphas.alphas[3], 2.0 / 81.0);
+
+        let hlcvs = sideways_hlcvs(1, 10, 3);
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params = BotParams {
+            unstuck_ema_span_0: 800.0,
+            unstuck_ema_span_1: 3200.0,
+            ..test_bot_params()
+        };
+        let mut backtest = Backtest::new(
+            &hlcvs,
+            &btc_usd_prices,
+            long_only(bot_params),
+            test_exchange_params(1),
+            &test_backtest_params(1),
+        );
+        let alphas = backtest.ema_alphas.long.clone();
+        assert_eq!(alphas.spans, vec![200.0, 400.0, 800.0, 1600.0, 3200.0]);
+        assert_eq!(alphas.unstuck, [2, 3, 4]);
+        // the unstuck-only EMAs sit above the candle, the entry ones on it
+        let close = hlcvs[[5, 0, CLOSE]];
+        backtest.emas[0].long = (0..alphas.spans.len())
+            .map(|i| {
+                if alphas.entry.contains(&i) {
+                    close
+                } else {
+                    close * 1.05
+                }
+            })
+            .collect();
+        backtest.positions.long.insert(
+            0,
+            Position {
+                size: 7.0,
+                price: 110.0,
+                ..Default::default()
+            },
+        );
+        backtest.open_orders.long.entry(0).or_default();
+        let (_, _, unstuck_close) = backtest.calc_unstucking_close(5).unwrap();
+        assert_eq!(unstuck_close.price, round_up(close * 1.05 * 1.001, 0.001));
+        // entries still go by the entry bands
+        let ema_bands = backtest.create_state_params(5, 0, LONG).ema_bands;
+        assert_eq!((ema_bands.upper, ema_bands.lower), (close, close));
+    }
+
+    // cargo test --release --lib backtest_throughput -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn backtest_throughput() {
+        let (n_coins, n_candles) = (500, 20000);
+        let mut rng = Rng::new(1);
+        let mut hlcvs = Array3::zeros((n_candles, n_coins, 4));
+        let to_cents = |price: f64| (price * 100.0).round() / 100.0;
+        for idx in 0..n_coins {
+            let mut close = 100.0;
+            for k in 0..n_candles {
+                close *= 1.0 + (rng.next_f64() - 0.5) * 0.006;
+                let high = to_cents(close * (1.0 + rng.next_f64() * 0.002));
+                let low = to_cents(close * (1.0 - rng.next_f64() * 0.002));
+                hlcvs[[k, idx, HIGH]] = high;
+                hlcvs[[k, idx, LOW]] = low;
+                hlcvs[[k, idx, CLOSE]] = to_cents(close).clamp(low, high);
+                hlcvs[[k, idx, VOLUME]] = 1000.0;
+            }
+        }
+        let hlcvs = hlcvs.view();
+        let btc_usd_prices = Array1::ones(n_candles);
+        let btc_usd_prices = btc_usd_prices.view();
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.005,
+            close_grid_qty_pct: 0.2,
+            close_trailing_grid_ratio: 0.5,
+            close_trailing_qty_pct: 0.3,
+            close_trailing_retracement_pct: 0.01,
+            close_trailing_threshold_pct: 0.01,
+            enforce_exposure_limit: true,
+            ema_span_0: 100.0,
+            ema_span_1: 200.0,
+            entry_grid_double_down_factor: 1.0,
+            entry_grid_spacing_pct: 0.03,
+            entry_grid_spacing_weight: 0.5,
+            entry_initial_ema_dist: 0.002,
+            entry_initial_qty_pct: 0.02,
+            entry_trailing_double_down_factor: 1.0,
+            entry_trailing_retracement_pct: 0.01,
+            entry_trailing_threshold_pct: 0.02,
+            filter_noisiness_rolling_window: 10,
+            filter_volume_drop_pct: 0.5,
+            filter_volume_rolling_window: 10,
+            n_positions: 100,
+            total_wallet_exposure_limit: 2.0,
+            unstuck_ema_span_0: 100.0,
+            unstuck_ema_span_1: 200.0,
+            unstuck_threshold: 0.9,
+            wallet_exposure_limit: 0.02,
+            ..Default::default()
+        };
+        let exchange_params = ExchangeParams {
+            price_step: 0.01,
+            ..test_exchange_params(1)[0].clone()
+        };
+        let mut backtest_params = test_backtest_params(n_coins);
+        backtest_params.starting_balance = 10000.0;
+        let mut seconds = Vec::new();
+        let mut n_fills = 0;
+        for _ in 0..5 {
+            let mut backtest = Backtest::new(
+                &hlcvs,
+                &btc_usd_prices,
+                long_only(bot_params.clone()),
+                vec![exchange_params.clone(); n_coins],
+                &backtest_params,
+            );
+            let start = std::time::Instant::now();
+            n_fills = backtest.run().0.len();
+            seconds.push(start.elapsed().as_secs_f64());
+        }
+        seconds.sort_by(f64::total_cmp);
+        eprintln!(
+            "{} coins x {} candles, {} fills: median {:.3}s, min {:.3}s",
+            n_coins, n_candles, n_fills, seconds[2], seconds[0]
+        );
+    }
+}
@@ -1,14 +1,17 @@
-use crate::entries::calc_min_entry_qty;
+use crate::constants::{LONG, SHORT};
+use crate::entries::{calc_entries_long, calc_entries_short, calc_min_entry_qty};
 use crate::types::{
-    BotParams, BotParamsPair, EMABands, ExchangeParams, Order, OrderType, Position, Positions,
-    StateParams, TrailingPriceBundle,
+    BotParams, BotParamsPair, CloseTrailingAnchor, CloseWithFallback, ExchangeParams, NextOrder,
+    Order, OrderKey, OrderType, Position, Positions, Price, Qty, StaggeredClose, StateParams,
+    SymbolIdx, TrailingGridSplit, TrailingPriceBundle,
 };
 use crate::utils::{
-    calc_pprice_diff_int, calc_wallet_exposure, cost_to_qty, interpolate, round_, round_dn,
-    round_up,
+    calc_neutral_imbalance, calc_pnl_long, calc_pnl_short, calc_pprice_diff_int,
+    calc_wallet_exposure, cost_to_qty, interpolate, qty_to_cost, round_, round_dn,
+    round_price_for_side, round_qty, round_up,
 };
-use ndarray::{Array1, Array2};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 
 pub fn calc_close_qty(
     exchange_params: &ExchangeParams,
@@ -46,734 +49,5776 @@ pub fn calc_close_qty(
     }
 }
 
+/// Largest close qty at price which close_max_qty_pct_of_volume of the average candle's quote
+/// volume allows, never below the min qty; None when uncapped.
+pub fn calc_close_qty_cap(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    price: f64,
+) -> Option<f64> {
+    if bot_params.close_max_qty_pct_of_volume <= 0.0 || state_params.avg_volume <= 0.0 {
+        return None;
+    }
+    let cap = round_dn(
+        cost_to_qty(
+            state_params.avg_volume * bot_params.close_max_qty_pct_of_volume,
+            price,
+            exchange_params.c_mult,
+        ),
+        exchange_params.qty_step,
+    );
+    Some(f64::max(cap, calc_min_entry_qty(price, exchange_params)))
+}
+
+/// close_qty, positive, capped per calc_close_qty_cap; what the close leaves is kept at or
+/// above the min qty, or closed along with it.
+fn cap_close_qty_to_volume(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position_size_abs: f64,
+    close_qty: f64,
+    close_price: f64,
+) -> f64 {
+    let cap = match calc_close_qty_cap(exchange_params, state_params, bot_params, close_price) {
+        Some(cap) if cap < close_qty => cap,
+        _ => return close_qty,
+    };
+    let min_entry_qty = calc_min_entry_qty(close_price, exchange_params);
+    let cap = f64::min(
+        cap,
+        round_dn(position_size_abs - min_entry_qty, exchange_params.qty_step),
+    );
+    if cap < min_entry_qty {
+        close_qty
+    } else {
+        cap
+    }
+}
+
+/// Qty pct of the close grid level reached once closed_share of the full position is closed.
+/// Levels number round(1 / close_grid_qty_pct), each close_grid_qty_ratio times the previous,
+/// and sum to the full position; a ratio <= 0.0 or of 1.0 keeps the grid flat.
+pub fn calc_geometric_close_qty_pct(
+    close_grid_qty_pct: f64,
+    close_grid_qty_ratio: f64,
+    closed_share: f64,
+) -> f64 {
+    let ratio = close_grid_qty_ratio;
+    if ratio <= 0.0 || ratio == 1.0 || close_grid_qty_pct >= 1.0 {
+        return close_grid_qty_pct;
+    }
+    let n_levels = (1.0 / close_grid_qty_pct).round().max(1.0);
+    let closed_share = closed_share.clamp(0.0, 1.0);
+    // level k closes share(k) = ratio^k * (ratio - 1) / (ratio^n - 1), written to not overflow
+    let (level, share_of_first) = if ratio > 1.0 {
+        let tail = ratio.powf(-n_levels);
+        (
+            n_levels + (tail + closed_share * (1.0 - tail)).ln() / ratio.ln(),
+            (ratio - 1.0) * tail / (1.0 - tail),
+        )
+    } else {
+        let tail = ratio.powf(n_levels);
+        (
+            (1.0 - closed_share * (1.0 - tail)).ln() / ratio.ln(),
+            (1.0 - ratio) / (1.0 - tail),
+        )
+    };
+    let level = level.round().clamp(0.0, n_levels - 1.0);
+    share_of_first * ratio.powf(level)
+}
+
+/// Markup recovering the position's accrued funding when closed, as a share of its cost, so
+/// the net exit still makes the intended markup; 0.0 unless close_recover_funding is set,
+/// and never negative: funding received does not narrow closes.
+pub fn calc_funding_markup(
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    position: &Position,
+) -> f64 {
+    let cost = qty_to_cost(position.size, position.price, exchange_params.c_mult);
+    if !bot_params.close_recover_funding || position.accrued_funding <= 0.0 || cost <= 0.0 {
+        return 0.0;
+    }
+    position.accrued_funding / cost
+}
+
+/// state_params.target_price if it is valid and at most target_max_staleness_ms old at
+/// state_params.timestamp; None while target_max_staleness_ms is 0.
+pub fn fresh_target_price(state_params: &StateParams, bot_params: &BotParams) -> Option<f64> {
+    let (price, timestamp) = state_params.target_price?;
+    if bot_params.target_max_staleness_ms == 0 || !(price.is_finite() && price > 0.0) {
+        return None;
+    }
+    // a target stamped after now is clock skew, not staleness
+    if state_params.timestamp.saturating_sub(timestamp) > bot_params.target_max_staleness_ms {
+        return None;
+    }
+    Some(price)
+}
+
 pub fn calc_grid_close_long(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-) -> Order {
+) -> Option<Order> {
+    let bot_params: &BotParams =
+        &bot_params.with_position_cost_cap(bot_params.close_balance(state_params));
+    calc_grid_close_long_unchecked(exchange_params, state_params, bot_params, position)
+        .filter(|close| !post_only_rejected(bot_params, state_params, close))
+}
+
+fn calc_grid_close_long_unchecked(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+) -> Option<Order> {
+    let balance = bot_params.close_balance(state_params);
+    // widened to recover funding paid while the position was held, tightened under
+    // order flow against the position or by the caller's close_markup_scale
+    let markup_scale = bot_params.flow_imbalance_markup_scale(state_params, LONG)
+        * state_params
+            .close_markup_scale
+            .map_or(1.0, |scale| scale.max(0.0));
+    let close_grid_min_markup = (bot_params
+        .effective_close_grid_min_markup(state_params.min_markup_floor)
+        + calc_funding_markup(exchange_params, bot_params, position))
+        * markup_scale;
+    let close_grid_markup_range = bot_params.close_grid_markup_range * markup_scale;
+    // a negative size is a short, or corrupt state; closing it as a long would grow it
     if position.size <= 0.0 {
-        return Order::default();
+        return None;
     }
-    if bot_params.close_grid_markup_range <= 0.0
-        || bot_params.close_grid_qty_pct < 0.0
-        || bot_params.close_grid_qty_pct >= 1.0
-    {
-        return Order {
-            qty: -round_(position.size, exchange_params.qty_step),
-            price: f64::max(
-                state_params.order_book.ask,
-                round_up(
-                    position.price * (1.0 + bot_params.close_grid_min_markup),
-                    exchange_params.price_step,
-                ),
+    let close_grid_qty_pct = bot_params.close_grid_qty_pct_clamped();
+    let cap_qty = |close_qty: f64, close_price: f64| {
+        cap_close_qty_to_volume(
+            exchange_params,
+            state_params,
+            bot_params,
+            position.size,
+            close_qty,
+            close_price,
+        )
+    };
+    // a fresh external target, e.g. a TA signal from the Python side, replaces the grid
+    if let Some(target_price) = fresh_target_price(state_params, bot_params) {
+        let close_price = f64::max(
+            state_params.order_book.ask,
+            round_price_for_side(target_price, false, exchange_params),
+        );
+        return Some(Order {
+            qty: -cap_qty(round_(position.size, exchange_params.qty_step), close_price),
+            price: close_price,
+            order_type: OrderType::CloseGridLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
+    }
+    if close_grid_markup_range <= 0.0 || close_grid_qty_pct >= 1.0 {
+        let close_price = f64::max(
+            state_params.order_book.ask,
+            round_price_for_side(
+                position.price * (1.0 + close_grid_min_markup),
+                false,
+                exchange_params,
             ),
+        );
+        return Some(Order {
+            qty: -cap_qty(round_(position.size, exchange_params.qty_step), close_price),
+            price: close_price,
             order_type: OrderType::CloseGridLong,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
-    let close_prices_start = round_up(
-        position.price * (1.0 + bot_params.close_grid_min_markup),
-        exchange_params.price_step,
+    let close_prices_start = round_price_for_side(
+        position.price * (1.0 + close_grid_min_markup),
+        false,
+        exchange_params,
     );
-    let close_prices_end = round_up(
-        position.price
-            * (1.0 + bot_params.close_grid_min_markup + bot_params.close_grid_markup_range),
-        exchange_params.price_step,
+    let close_prices_end = round_price_for_side(
+        position.price * (1.0 + close_grid_min_markup + close_grid_markup_range),
+        false,
+        exchange_params,
     );
     if close_prices_start == close_prices_end {
-        return Order {
-            qty: -round_(position.size, exchange_params.qty_step),
-            price: f64::max(state_params.order_book.ask, close_prices_start),
+        let close_price = f64::max(state_params.order_book.ask, close_prices_start);
+        return Some(Order {
+            qty: -cap_qty(round_(position.size, exchange_params.qty_step), close_price),
+            price: close_price,
             order_type: OrderType::CloseGridLong,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
     let n_steps = ((close_prices_end - close_prices_start) / exchange_params.price_step).ceil();
-    let close_grid_qty_pct_modified = f64::max(bot_params.close_grid_qty_pct, 1.0 / n_steps);
     let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
-        state_params.balance,
+        balance,
         position.size,
         position.price,
     );
     let wallet_exposure_ratio = f64::min(1.0, wallet_exposure / bot_params.wallet_exposure_limit);
+    if wallet_exposure_ratio < 0.5 && !bot_params.close_volume_confirmed(state_params.volume) {
+        // don't take the upper half of the range on a low-conviction move
+        return None;
+    }
+    // larger chunks in liquid hours, smaller in thin ones
+    let close_grid_qty_pct_modified = calc_geometric_close_qty_pct(
+        f64::max(close_grid_qty_pct, 1.0 / n_steps),
+        bot_params.close_grid_qty_ratio,
+        1.0 - wallet_exposure_ratio,
+    ) * bot_params.liquidity_multiplier(state_params.hour);
     let close_price = f64::max(
-        round_up(
+        round_price_for_side(
             position.price
                 * (1.0
-                    + bot_params.close_grid_min_markup
-                    + bot_params.close_grid_markup_range * (1.0 - wallet_exposure_ratio)),
-            exchange_params.price_step,
+                    + close_grid_min_markup
+                    + close_grid_markup_range * (1.0 - wallet_exposure_ratio)),
+            false,
+            exchange_params,
         ),
         state_params.order_book.ask,
     );
-    let close_qty = -calc_close_qty(
-        &exchange_params,
-        &bot_params,
-        &position,
-        close_grid_qty_pct_modified,
-        state_params.balance,
+    let close_qty = -cap_qty(
+        calc_close_qty(
+            &exchange_params,
+            &bot_params,
+            &position,
+            close_grid_qty_pct_modified,
+            balance,
+            close_price,
+        ),
         close_price,
     );
-    Order {
+    Some(Order {
         qty: close_qty,
         price: close_price,
         order_type: OrderType::CloseGridLong,
-    }
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
 }
 
-pub fn calc_trailing_close_long(
+pub fn calc_close_with_fallback_long(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-    trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
-    if position.size == 0.0 {
-        return Order::default();
-    }
-    if bot_params.close_trailing_threshold_pct <= 0.0 {
-        // means trailing close immediately from pos open
-        if bot_params.close_trailing_retracement_pct > 0.0
-            && trailing_price_bundle.min_since_max
-                < trailing_price_bundle.max_since_open
-                    * (1.0 - bot_params.close_trailing_retracement_pct)
-        {
-            Order {
-                qty: -calc_close_qty(
-                    &exchange_params,
-                    &bot_params,
-                    &position,
-                    bot_params.close_trailing_qty_pct,
-                    state_params.balance,
-                    state_params.order_book.ask,
-                ),
-                price: state_params.order_book.ask,
-                order_type: OrderType::CloseTrailingLong,
-            }
-        } else {
-            Order {
-                qty: 0.0,
-                price: 0.0,
-                order_type: OrderType::CloseTrailingLong,
-            }
-        }
-    } else {
-        // means trailing close will activate only after a threshold
-        if bot_params.close_trailing_retracement_pct <= 0.0 {
-            // close at threshold
-            let close_price = f64::max(
-                state_params.order_book.ask,
-                round_up(
-                    position.price * (1.0 + bot_params.close_trailing_threshold_pct),
-                    exchange_params.price_step,
-                ),
-            );
-            Order {
-                qty: -calc_close_qty(
-                    &exchange_params,
-                    &bot_params,
-                    &position,
-                    bot_params.close_trailing_qty_pct,
-                    state_params.balance,
-                    close_price,
-                ),
-                price: close_price,
-                order_type: OrderType::CloseTrailingLong,
-            }
-        } else {
-            // close if both conditions are met
-            if trailing_price_bundle.max_since_open
-                > position.price * (1.0 + bot_params.close_trailing_threshold_pct)
-                && trailing_price_bundle.min_since_max
-                    < trailing_price_bundle.max_since_open
-                        * (1.0 - bot_params.close_trailing_retracement_pct)
-            {
-                let close_price = f64::max(
-                    state_params.order_book.ask,
-                    round_up(
-                        position.price
-                            * (1.0 + bot_params.close_trailing_threshold_pct
-                                - bot_params.close_trailing_retracement_pct),
-                        exchange_params.price_step,
-                    ),
-                );
-                Order {
-                    qty: -calc_close_qty(
-                        &exchange_params,
-                        &bot_params,
-                        &position,
-                        bot_params.close_trailing_qty_pct,
-                        state_params.balance,
-                        close_price,
-                    ),
-                    price: close_price,
-                    order_type: OrderType::CloseTrailingLong,
-                }
-            } else {
-                Order {
-                    qty: 0.0,
-                    price: 0.0,
-                    order_type: OrderType::CloseTrailingLong,
-                }
-            }
-        }
+    fallback_candles: usize,
+) -> Option<CloseWithFallback> {
+    let limit = calc_grid_close_long(exchange_params, state_params, bot_params, position)?;
+    if limit.qty == 0.0 {
+        return None;
     }
+    Some(CloseWithFallback {
+        limit,
+        fallback: Order {
+            qty: limit.qty,
+            price: state_params.order_book.bid,
+            order_type: OrderType::CloseFallbackMarketLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        },
+        fallback_candles,
+    })
 }
 
-pub fn calc_next_close_long(
+/// Grid close sized toward realizing daily_pnl_target quote profit today. Until the target
+/// is met the grid close qty is capped at what would realize the remainder at the close
+/// price; once met, closes slow to the minimum qty. daily_pnl_target <= 0 disables.
+pub fn calc_daily_pnl_target_close_long(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-    trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
-    if position.size == 0.0 {
-        // no position
-        return Order::default();
-    }
-    let wallet_exposure = calc_wallet_exposure(
-        exchange_params.c_mult,
-        state_params.balance,
+    pnl_realized_today: f64,
+    daily_pnl_target: f64,
+) -> Option<Order> {
+    let close = calc_grid_close_long(exchange_params, state_params, bot_params, position)?;
+    let pnl_per_qty = calc_pnl_long(position.price, close.price, 1.0, exchange_params.c_mult);
+    let close_qty = calc_daily_pnl_target_qty(
+        exchange_params,
         position.size,
-        position.price,
+        &close,
+        pnl_per_qty,
+        pnl_realized_today,
+        daily_pnl_target,
     );
-    let wallet_exposure_ratio = if bot_params.wallet_exposure_limit <= 0.0 {
-        10.0
+    Some(Order {
+        qty: -close_qty,
+        ..close
+    })
+}
+
+/// calc_daily_pnl_target_close_long for shorts.
+pub fn calc_daily_pnl_target_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    pnl_realized_today: f64,
+    daily_pnl_target: f64,
+) -> Option<Order> {
+    let close = calc_grid_close_short(exchange_params, state_params, bot_params, position)?;
+    let pnl_per_qty = calc_pnl_short(position.price, close.price, 1.0, exchange_params.c_mult);
+    let close_qty = calc_daily_pnl_target_qty(
+        exchange_params,
+        -position.size,
+        &close,
+        pnl_per_qty,
+        pnl_realized_today,
+        daily_pnl_target,
+    );
+    Some(Order {
+        qty: close_qty,
+        ..close
+    })
+}
+
+// unsigned qty of the grid close capped toward the target; position_size is positive for a
+// position of the closing side
+fn calc_daily_pnl_target_qty(
+    exchange_params: &ExchangeParams,
+    position_size: f64,
+    close: &Order,
+    pnl_per_qty: f64,
+    pnl_realized_today: f64,
+    daily_pnl_target: f64,
+) -> f64 {
+    if daily_pnl_target <= 0.0 || close.qty == 0.0 || pnl_per_qty <= 0.0 {
+        return close.qty.abs();
+    }
+    let min_qty = calc_min_entry_qty(close.price, exchange_params);
+    let pnl_remaining = daily_pnl_target - pnl_realized_today;
+    let target_qty = if pnl_remaining > 0.0 {
+        round_up(pnl_remaining / pnl_per_qty, exchange_params.qty_step)
     } else {
-        wallet_exposure / bot_params.wallet_exposure_limit
+        min_qty
     };
-    if bot_params.enforce_exposure_limit && wallet_exposure_ratio > 1.01 {
-        let position_size_lowered = position.size * 0.9;
-        let wallet_exposure_lowered = calc_wallet_exposure(
-            exchange_params.c_mult,
-            state_params.balance,
-            position_size_lowered,
-            position.price,
-        );
-        let ideal_psize = interpolate(
-            bot_params.wallet_exposure_limit * 1.01,
-            &[wallet_exposure, wallet_exposure_lowered],
-            &[position.size, position_size_lowered],
-        );
-        let auto_reduce_qty = position.size - ideal_psize;
-        if auto_reduce_qty > 0.0 {
-            let close_qty = f64::min(
-                round_(position.size, exchange_params.qty_step),
-                f64::max(
-                    calc_min_entry_qty(state_params.order_book.ask, &exchange_params),
-                    round_(auto_reduce_qty, exchange_params.qty_step),
-                ),
-            );
-            return Order {
-                price: state_params.order_book.ask,
-                qty: -close_qty,
-                order_type: OrderType::CloseAutoReduceLong,
-            };
-        }
-    }
-    if bot_params.close_trailing_grid_ratio >= 1.0 || bot_params.close_trailing_grid_ratio <= -1.0 {
-        // return trailing only
-        return calc_trailing_close_long(
-            &exchange_params,
-            &state_params,
-            &bot_params,
-            &position,
-            &trailing_price_bundle,
-        );
-    }
-    if bot_params.close_trailing_grid_ratio == 0.0 {
-        // return grid only
-        return calc_grid_close_long(&exchange_params, &state_params, &bot_params, &position);
+    let position_size = round_(position_size, exchange_params.qty_step);
+    let close_qty = f64::min(close.qty.abs(), f64::max(min_qty, target_qty));
+    if position_size - close_qty < min_qty {
+        // don't leave a remainder too small to close
+        return position_size;
     }
-    if bot_params.close_trailing_grid_ratio > 0.0 {
-        // trailing first
-        if wallet_exposure_ratio < bot_params.close_trailing_grid_ratio {
-            // return trailing order
-            calc_trailing_close_long(
-                &exchange_params,
-                &state_params,
-                &bot_params,
-                &position,
-                &trailing_price_bundle,
-            )
-        } else {
-            // return grid order, but leave full_psize * close_trailing_grid_ratio for trailing close
-            let mut trailing_allocation = cost_to_qty(
-                state_params.balance
-                    * bot_params.wallet_exposure_limit
-                    * bot_params.close_trailing_grid_ratio,
-                position.price,
-                exchange_params.c_mult,
-            );
-            let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
-            if trailing_allocation < min_entry_qty {
-                trailing_allocation = 0.0;
-            }
-            let grid_allocation = round_(
-                position.size - trailing_allocation,
-                exchange_params.qty_step,
-            );
-            let position_mod = Position {
-                size: f64::min(position.size, f64::max(grid_allocation, min_entry_qty)),
-                price: position.price,
-            };
-            calc_grid_close_long(&exchange_params, &state_params, &bot_params, &position_mod)
-        }
-    } else {
-        // grid first
-        if wallet_exposure_ratio < 1.0 + bot_params.close_trailing_grid_ratio {
-            // return grid order, closing whole position
-            calc_grid_close_long(&exchange_params, &state_params, &bot_params, &position)
-        } else {
-            // return trailing order, but leave full_psize * (1.0 + close_trailing_grid_ratio) for grid close
-            let mut grid_allocation = cost_to_qty(
-                state_params.balance
-                    * bot_params.wallet_exposure_limit
-                    * (1.0 + bot_params.close_trailing_grid_ratio),
-                position.price,
-                exchange_params.c_mult,
-            );
-            let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
-            if grid_allocation < min_entry_qty {
-                grid_allocation = 0.0;
-            }
-            let trailing_allocation =
-                round_(position.size - grid_allocation, exchange_params.qty_step);
-            let position_mod = Position {
-                size: f64::min(position.size, f64::max(trailing_allocation, min_entry_qty)),
-                price: position.price,
-            };
-            calc_trailing_close_long(
-                &exchange_params,
-                &state_params,
-                &bot_params,
-                &position_mod,
-                &trailing_price_bundle,
-            )
-        }
+    close_qty
+}
+
+/// Kelly-optimal share of capital to stake on a bet won with win_prob and paying
+/// win_loss_ratio times what a loss costs: win_prob - (1 - win_prob) / win_loss_ratio,
+/// within [0, 1]; 0.0 without an edge.
+pub fn calc_kelly_fraction(win_prob: f64, win_loss_ratio: f64) -> f64 {
+    if win_loss_ratio <= 0.0 || !win_prob.is_finite() {
+        return 0.0;
     }
+    let win_prob = win_prob.clamp(0.0, 1.0);
+    (win_prob - (1.0 - win_prob) / win_loss_ratio).clamp(0.0, 1.0)
 }
 
-pub fn calc_grid_close_short(
+/// Grid close sized by edge: each level closes the Kelly fraction of the full position in
+/// place of close_grid_qty_pct. win_prob and win_loss_ratio come from the strategy layer;
+/// without an edge levels close the least the grid allows, with certainty the whole position.
+pub fn calc_kelly_close_long(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-) -> Order {
-    let position_size_abs = position.size.abs();
-    if position_size_abs == 0.0 {
-        return Order::default();
-    }
-    if bot_params.close_grid_markup_range <= 0.0
-        || bot_params.close_grid_qty_pct < 0.0
-        || bot_params.close_grid_qty_pct >= 1.0
-    {
-        return Order {
-            qty: round_(position_size_abs, exchange_params.qty_step),
-            price: f64::min(
-                state_params.order_book.bid,
-                round_dn(
-                    position.price * (1.0 - bot_params.close_grid_min_markup),
-                    exchange_params.price_step,
-                ),
-            ),
-            order_type: OrderType::CloseGridShort,
-        };
-    }
-    let close_prices_start = round_dn(
-        position.price * (1.0 - bot_params.close_grid_min_markup),
-        exchange_params.price_step,
-    );
-    let close_prices_end = round_dn(
-        position.price
-            * (1.0 - bot_params.close_grid_min_markup - bot_params.close_grid_markup_range),
-        exchange_params.price_step,
-    );
-    if close_prices_start == close_prices_end {
-        return Order {
-            qty: round_(position_size_abs, exchange_params.qty_step),
-            price: f64::min(state_params.order_book.bid, close_prices_start),
-            order_type: OrderType::CloseGridShort,
-        };
-    }
-    let n_steps = ((close_prices_start - close_prices_end) / exchange_params.price_step).ceil();
-    let close_grid_qty_pct_modified = f64::max(bot_params.close_grid_qty_pct, 1.0 / n_steps);
-    let wallet_exposure = calc_wallet_exposure(
-        exchange_params.c_mult,
-        state_params.balance,
-        position_size_abs,
-        position.price,
-    );
-    let wallet_exposure_ratio = f64::min(1.0, wallet_exposure / bot_params.wallet_exposure_limit);
-    let close_price = f64::min(
-        round_dn(
-            position.price
-                * (1.0
-                    - bot_params.close_grid_min_markup
-                    - bot_params.close_grid_markup_range * (1.0 - wallet_exposure_ratio)),
-            exchange_params.price_step,
-        ),
-        state_params.order_book.bid,
-    );
-    let close_qty = calc_close_qty(
-        &exchange_params,
-        &bot_params,
-        &position,
-        close_grid_qty_pct_modified,
-        state_params.balance,
-        close_price,
-    );
-    Order {
-        qty: close_qty,
-        price: close_price,
-        order_type: OrderType::CloseGridShort,
-    }
+    win_prob: f64,
+    win_loss_ratio: f64,
+) -> Option<Order> {
+    let kelly_params = BotParams {
+        close_grid_qty_pct: calc_kelly_fraction(win_prob, win_loss_ratio),
+        ..bot_params.clone()
+    };
+    calc_grid_close_long(exchange_params, state_params, &kelly_params, position)
 }
 
-pub fn calc_trailing_close_short(
+/// calc_kelly_close_long for shorts.
+pub fn calc_kelly_close_short(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-    trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
-    let position_size_abs = position.size.abs();
-    if position_size_abs == 0.0 {
-        return Order::default();
-    }
-    if bot_params.close_trailing_threshold_pct <= 0.0 {
-        // means trailing stop immediately from pos open
-        if bot_params.close_trailing_retracement_pct > 0.0
-            && trailing_price_bundle.max_since_min
-                > trailing_price_bundle.min_since_open
-                    * (1.0 + bot_params.close_trailing_retracement_pct)
-        {
-            Order {
-                qty: calc_close_qty(
-                    &exchange_params,
-                    &bot_params,
+    win_prob: f64,
+    win_loss_ratio: f64,
+) -> Option<Order> {
+    let kelly_params = BotParams {
+        close_grid_qty_pct: calc_kelly_fraction(win_prob, win_loss_ratio),
+        ..bot_params.clone()
+    };
+    calc_grid_close_short(exchange_params, state_params, &kelly_params, position)
+}
+
+/// Close reducing the position just enough to bring the margin ratio, maintenance_margin
+/// over used_margin (the margin balance backing the position), to target_margin_ratio or
+/// below, priced at the ask like auto-reduce closes. Maintenance margin is taken to shrink
+/// in proportion to the position; closing doesn't change used_margin. None if the ratio is
+/// already within target, or target_margin_ratio <= 0.0; with no used_margin left the
+/// whole position is closed.
+pub fn calc_margin_target_close_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    position: &Position,
+    used_margin: f64,
+    maintenance_margin: f64,
+    target_margin_ratio: f64,
+) -> Option<Order> {
+    let ask = state_params.order_book.ask;
+    let qty = calc_margin_target_qty(
+        exchange_params,
+        position.size,
+        ask,
+        used_margin,
+        maintenance_margin,
+        target_margin_ratio,
+    )?;
+    Some(Order {
+        qty: -qty,
+        price: ask,
+        order_type: OrderType::CloseMarginTargetLong,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+/// calc_margin_target_close_long for shorts, at the bid.
+pub fn calc_margin_target_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    position: &Position,
+    used_margin: f64,
+    maintenance_margin: f64,
+    target_margin_ratio: f64,
+) -> Option<Order> {
+    let bid = state_params.order_book.bid;
+    let qty = calc_margin_target_qty(
+        exchange_params,
+        -position.size,
+        bid,
+        used_margin,
+        maintenance_margin,
+        target_margin_ratio,
+    )?;
+    Some(Order {
+        qty,
+        price: bid,
+        order_type: OrderType::CloseMarginTargetShort,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+// unsigned qty restoring the margin ratio, at least min qty at price; position_size is
+// positive for a position of the closing side
+fn calc_margin_target_qty(
+    exchange_params: &ExchangeParams,
+    position_size: f64,
+    price: f64,
+    used_margin: f64,
+    maintenance_margin: f64,
+    target_margin_ratio: f64,
+) -> Option<f64> {
+    let position_size = round_(position_size, exchange_params.qty_step);
+    if position_size <= 0.0 || maintenance_margin <= 0.0 || target_margin_ratio <= 0.0 {
+        return None;
+    }
+    let close_share = if used_margin > 0.0 {
+        1.0 - target_margin_ratio * used_margin / maintenance_margin
+    } else {
+        1.0
+    };
+    if close_share <= 0.0 {
+        return None;
+    }
+    let min_qty = calc_min_entry_qty(price, exchange_params);
+    let close_qty = f64::max(
+        min_qty,
+        round_up(position_size * close_share, exchange_params.qty_step),
+    );
+    if position_size - close_qty < min_qty {
+        // don't leave a remainder too small to close
+        return Some(position_size);
+    }
+    Some(close_qty)
+}
+
+/// Trims close_before_funding_pct of a long position at the ask within
+/// close_before_funding_minutes of a funding settlement longs pay (funding_rate > 0.0),
+/// sparing that share the charge. None outside the window, so entries may rebuild the
+/// position once funding has settled, and unless close_before_funding is set.
+pub fn calc_funding_window_close_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    minutes_to_funding: f64,
+    funding_rate: f64,
+) -> Option<Order> {
+    let ask = state_params.order_book.ask;
+    let qty = calc_funding_window_qty(
+        exchange_params,
+        bot_params,
+        position.size,
+        ask,
+        minutes_to_funding,
+        funding_rate,
+    )?;
+    Some(Order {
+        qty: -qty,
+        price: ask,
+        order_type: OrderType::CloseFundingWindowLong,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+/// calc_funding_window_close_long for shorts, which pay funding when funding_rate < 0.0; at
+/// the bid.
+pub fn calc_funding_window_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    minutes_to_funding: f64,
+    funding_rate: f64,
+) -> Option<Order> {
+    let bid = state_params.order_book.bid;
+    let qty = calc_funding_window_qty(
+        exchange_params,
+        bot_params,
+        -position.size,
+        bid,
+        minutes_to_funding,
+        -funding_rate,
+    )?;
+    Some(Order {
+        qty,
+        price: bid,
+        order_type: OrderType::CloseFundingWindowShort,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+// unsigned qty to trim ahead of settlement, at least min qty at price; position_size and
+// funding_paid are positive for a position of the closing side which pays funding
+fn calc_funding_window_qty(
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    position_size: f64,
+    price: f64,
+    minutes_to_funding: f64,
+    funding_paid: f64,
+) -> Option<f64> {
+    let position_size = round_(position_size, exchange_params.qty_step);
+    if !bot_params.close_before_funding
+        || bot_params.close_before_funding_pct <= 0.0
+        || position_size <= 0.0
+        || funding_paid <= 0.0
+        || funding_paid.is_nan()
+        || !(0.0..=bot_params.close_before_funding_minutes).contains(&minutes_to_funding)
+    {
+        return None;
+    }
+    let min_qty = calc_min_entry_qty(price, exchange_params);
+    let close_qty = f64::max(
+        min_qty,
+        round_up(
+            position_size * bot_params.close_before_funding_pct.min(1.0),
+            exchange_params.qty_step,
+        ),
+    );
+    if position_size - close_qty < min_qty {
+        // don't leave a remainder too small to close
+        return Some(position_size);
+    }
+    Some(close_qty)
+}
+
+/// In neutral mode, close at price trimming the leading side of a coin's pair down to the
+/// lagging side once they drift apart by more than the leading side's
+/// rebalance_threshold_pct; the counterpart of calc_neutral_rebalance_entry after closes.
+/// None within tolerance or if the gap is below min_qty.
+pub fn calc_neutral_rebalance_close(
+    exchange_params: &ExchangeParams,
+    bot_params_pair: &BotParamsPair,
+    position_long: &Position,
+    position_short: &Position,
+    price: f64,
+) -> Option<Order> {
+    let imbalance = calc_neutral_imbalance(position_long.size, position_short.size);
+    let (leading_params, order_type, sign) = if imbalance > 0.0 {
+        (&bot_params_pair.long, OrderType::CloseRebalanceLong, -1.0)
+    } else {
+        (&bot_params_pair.short, OrderType::CloseRebalanceShort, 1.0)
+    };
+    if imbalance.abs() <= leading_params.rebalance_threshold_pct {
+        return None;
+    }
+    let gap = round_(
+        (position_long.size.abs() - position_short.size.abs()).abs(),
+        exchange_params.qty_step,
+    );
+    // closes are reduce-only, exempt from min_cost
+    if gap < exchange_params.min_qty.max(exchange_params.qty_step) {
+        return None;
+    }
+    Some(Order {
+        qty: sign * gap,
+        price,
+        order_type,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+/// Closes the whole position in levels clustered around target_price instead of along the
+/// markup range: one level per close_grid_qty_pct of the position, evenly spaced within
+/// bracket_pct of target_price and never below the ask. Levels share the qty equally, the
+/// last taking the remainder; levels at one price merge.
+pub fn calc_bracket_close_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    target_price: f64,
+    bracket_pct: f64,
+) -> Vec<Order> {
+    calc_bracket_closes(
+        exchange_params,
+        bot_params,
+        position.size,
+        state_params.order_book.ask,
+        target_price,
+        bracket_pct,
+        LONG,
+    )
+}
+
+/// calc_bracket_close_long for shorts: levels run down from the top of the bracket and
+/// never above the bid.
+pub fn calc_bracket_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    target_price: f64,
+    bracket_pct: f64,
+) -> Vec<Order> {
+    calc_bracket_closes(
+        exchange_params,
+        bot_params,
+        -position.size,
+        state_params.order_book.bid,
+        target_price,
+        bracket_pct,
+        SHORT,
+    )
+}
+
+// position_size is positive for a position of pside; levels go nearest the book first
+fn calc_bracket_closes(
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    position_size: f64,
+    book_price: f64,
+    target_price: f64,
+    bracket_pct: f64,
+    pside: usize,
+) -> Vec<Order> {
+    let position_size = round_(position_size, exchange_params.qty_step);
+    if position_size <= 0.0 || target_price <= 0.0 {
+        return Vec::new();
+    }
+    let n_levels = if bot_params.close_grid_qty_pct > 0.0 {
+        (1.0 / bot_params.close_grid_qty_pct).round().max(1.0) as usize
+    } else {
+        1
+    };
+    let bracket_pct = bracket_pct.max(0.0);
+    let (low, high) = (
+        target_price * (1.0 - bracket_pct),
+        target_price * (1.0 + bracket_pct),
+    );
+    let level_qty = round_dn(position_size / n_levels as f64, exchange_params.qty_step);
+    let mut closes: Vec<Order> = Vec::with_capacity(n_levels);
+    let mut remaining = position_size;
+    for i in 0..n_levels {
+        if remaining <= 0.0 {
+            break;
+        }
+        let share = if n_levels == 1 {
+            0.5
+        } else {
+            i as f64 / (n_levels - 1) as f64
+        };
+        let price = if pside == LONG {
+            f64::max(
+                book_price,
+                round_(low + (high - low) * share, exchange_params.price_step),
+            )
+        } else {
+            f64::min(
+                book_price,
+                round_(high - (high - low) * share, exchange_params.price_step),
+            )
+            .max(exchange_params.price_step)
+        };
+        let min_qty = calc_min_entry_qty(price, exchange_params);
+        let mut qty = f64::min(remaining, f64::max(min_qty, level_qty));
+        if i == n_levels - 1 || remaining - qty < min_qty {
+            // don't leave a remainder too small to close
+            qty = remaining;
+        }
+        remaining = round_(remaining - qty, exchange_params.qty_step);
+        let sign = if pside == LONG { -1.0 } else { 1.0 };
+        match closes.last_mut() {
+            Some(last) if last.price == price => {
+                last.qty = sign * round_(last.qty.abs() + qty, exchange_params.qty_step)
+            }
+            _ => closes.push(Order {
+                qty: sign * qty,
+                price,
+                order_type: if pside == LONG {
+                    OrderType::CloseGridLong
+                } else {
+                    OrderType::CloseGridShort
+                },
+                iceberg_qty: None,
+                min_fill_qty: 0.0,
+            }),
+        }
+    }
+    closes
+}
+
+/// Whether price has stalled past the peak (trough for shorts) for longer than
+/// close_trailing_max_candles_since_peak, in which case the trailing close fires without
+/// waiting for the retracement.
+fn stagnated_since_peak(bot_params: &BotParams, candles_since_peak: usize) -> bool {
+    bot_params.close_trailing_max_candles_since_peak > 0
+        && candles_since_peak >= bot_params.close_trailing_max_candles_since_peak
+}
+
+/// Trailing close trigger price for longs: the anchor (peak since open, or trailing_ma)
+/// less close_trailing_retracement_pct.
+pub fn calc_trailing_stop_price_long(
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> f64 {
+    let anchor = match bot_params.close_trailing_anchor {
+        CloseTrailingAnchor::Peak => trailing_price_bundle.max_since_open,
+        CloseTrailingAnchor::MovingAverage => state_params.trailing_ma,
+    };
+    anchor * (1.0 - bot_params.close_trailing_retracement_pct)
+}
+
+/// Trailing close trigger price for shorts: the anchor (trough since open, or trailing_ma)
+/// plus close_trailing_retracement_pct.
+pub fn calc_trailing_stop_price_short(
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> f64 {
+    let anchor = match bot_params.close_trailing_anchor {
+        CloseTrailingAnchor::Peak => trailing_price_bundle.min_since_open,
+        CloseTrailingAnchor::MovingAverage => state_params.trailing_ma,
+    };
+    anchor * (1.0 + bot_params.close_trailing_retracement_pct)
+}
+
+/// With close_trailing_step_multiple, stop_price rounded down to a multiple of
+/// price_step * close_trailing_step_multiple and never below stepped_stop_price, the last
+/// stepped stop: the stop only moves up in whole increments, so a resting stop order is
+/// amended once per increment instead of every tick.
+pub fn calc_stepped_trailing_stop_price_long(
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    stop_price: f64,
+    stepped_stop_price: f64,
+) -> f64 {
+    if bot_params.close_trailing_step_multiple <= 0.0 {
+        return stop_price;
+    }
+    let increment = exchange_params.price_step * bot_params.close_trailing_step_multiple;
+    round_dn(stop_price, increment).max(stepped_stop_price)
+}
+
+/// As calc_stepped_trailing_stop_price_long, rounding up and only moving down.
+pub fn calc_stepped_trailing_stop_price_short(
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    stop_price: f64,
+    stepped_stop_price: f64,
+) -> f64 {
+    if bot_params.close_trailing_step_multiple <= 0.0 {
+        return stop_price;
+    }
+    let increment = exchange_params.price_step * bot_params.close_trailing_step_multiple;
+    let stop_price = round_up(stop_price, increment);
+    if stepped_stop_price > 0.0 {
+        stop_price.min(stepped_stop_price)
+    } else {
+        stop_price
+    }
+}
+
+// peak-anchored stops compare against the extreme since the peak, MA-anchored ones against
+// the current price; a missing trailing_ma never triggers
+fn retraced_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> bool {
+    let stop_price = calc_stepped_trailing_stop_price_long(
+        exchange_params,
+        bot_params,
+        calc_trailing_stop_price_long(state_params, bot_params, trailing_price_bundle),
+        trailing_price_bundle.stepped_stop_price,
+    );
+    match bot_params.close_trailing_anchor {
+        CloseTrailingAnchor::Peak => trailing_price_bundle.min_since_max < stop_price,
+        CloseTrailingAnchor::MovingAverage => {
+            state_params.trailing_ma > 0.0 && state_params.order_book.ask < stop_price
+        }
+    }
+}
+
+fn retraced_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> bool {
+    let stop_price = calc_stepped_trailing_stop_price_short(
+        exchange_params,
+        bot_params,
+        calc_trailing_stop_price_short(state_params, bot_params, trailing_price_bundle),
+        trailing_price_bundle.stepped_stop_price,
+    );
+    match bot_params.close_trailing_anchor {
+        CloseTrailingAnchor::Peak => trailing_price_bundle.max_since_min > stop_price,
+        CloseTrailingAnchor::MovingAverage => {
+            state_params.trailing_ma > 0.0 && state_params.order_book.bid > stop_price
+        }
+    }
+}
+
+pub fn calc_trailing_close_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> NextOrder {
+    let balance = bot_params.close_balance(state_params);
+    if position.size <= 0.0 {
+        return NextOrder::NoOrder;
+    }
+    if bot_params.close_trailing_threshold_pct <= 0.0 {
+        // means trailing close immediately from pos open
+        if (bot_params.close_trailing_retracement_pct > 0.0
+            && retraced_long(
+                exchange_params,
+                state_params,
+                bot_params,
+                trailing_price_bundle,
+            ))
+            || stagnated_since_peak(bot_params, trailing_price_bundle.candles_since_max)
+        {
+            NextOrder::Order(Order {
+                qty: -calc_close_qty(
+                    &exchange_params,
+                    &bot_params,
                     &position,
                     bot_params.close_trailing_qty_pct,
-                    state_params.balance,
-                    state_params.order_book.bid,
+                    balance,
+                    state_params.order_book.ask,
                 ),
-                price: state_params.order_book.bid,
-                order_type: OrderType::CloseTrailingShort,
-            }
+                price: state_params.order_book.ask,
+                order_type: OrderType::CloseTrailingLong,
+                iceberg_qty: None,
+                min_fill_qty: 0.0,
+            })
         } else {
-            Order {
-                qty: 0.0,
-                price: 0.0,
-                order_type: OrderType::CloseTrailingShort,
-            }
+            NextOrder::TrailingPending
         }
     } else {
-        // means trailing stop will activate only after a threshold
+        // means trailing close will activate only after a threshold
         if bot_params.close_trailing_retracement_pct <= 0.0 {
             // close at threshold
-            let close_price = f64::min(
-                state_params.order_book.bid,
-                round_dn(
-                    position.price * (1.0 - bot_params.close_trailing_threshold_pct),
-                    exchange_params.price_step,
+            let close_price = f64::max(
+                state_params.order_book.ask,
+                round_price_for_side(
+                    position.price * (1.0 + bot_params.close_trailing_threshold_pct),
+                    false,
+                    exchange_params,
                 ),
             );
-            Order {
-                qty: calc_close_qty(
+            NextOrder::Order(Order {
+                qty: -calc_close_qty(
                     &exchange_params,
                     &bot_params,
                     &position,
                     bot_params.close_trailing_qty_pct,
-                    state_params.balance,
+                    balance,
                     close_price,
                 ),
                 price: close_price,
-                order_type: OrderType::CloseTrailingShort,
-            }
+                order_type: OrderType::CloseTrailingLong,
+                iceberg_qty: None,
+                min_fill_qty: 0.0,
+            })
         } else {
-            if trailing_price_bundle.min_since_open
-                < position.price * (1.0 - bot_params.close_trailing_threshold_pct)
-                && trailing_price_bundle.max_since_min
-                    > trailing_price_bundle.min_since_open
-                        * (1.0 + bot_params.close_trailing_retracement_pct)
+            // close if both conditions are met
+            if trailing_price_bundle.max_since_open
+                > position.price * (1.0 + bot_params.close_trailing_threshold_pct)
+                && (retraced_long(
+                    exchange_params,
+                    state_params,
+                    bot_params,
+                    trailing_price_bundle,
+                ) || stagnated_since_peak(bot_params, trailing_price_bundle.candles_since_max))
             {
-                let close_price = f64::min(
-                    state_params.order_book.bid,
-                    round_dn(
-                        position.price
-                            * (1.0 - bot_params.close_trailing_threshold_pct
-                                + bot_params.close_trailing_retracement_pct),
-                        exchange_params.price_step,
+                let close_price = match bot_params.close_trailing_anchor {
+                    CloseTrailingAnchor::Peak => f64::max(
+                        state_params.order_book.ask,
+                        round_price_for_side(
+                            position.price
+                                * (1.0 + bot_params.close_trailing_threshold_pct
+                                    - bot_params.close_trailing_retracement_pct),
+                            false,
+                            exchange_params,
+                        ),
                     ),
-                );
-                Order {
-                    qty: calc_close_qty(
+                    // the moving average moves with price; close at market once crossed
+                    CloseTrailingAnchor::MovingAverage => state_params.order_book.ask,
+                };
+                NextOrder::Order(Order {
+                    qty: -calc_close_qty(
                         &exchange_params,
                         &bot_params,
                         &position,
                         bot_params.close_trailing_qty_pct,
-                        state_params.balance,
+                        balance,
                         close_price,
                     ),
                     price: close_price,
-                    order_type: OrderType::CloseTrailingShort,
-                }
+                    order_type: OrderType::CloseTrailingLong,
+                    iceberg_qty: None,
+                    min_fill_qty: 0.0,
+                })
             } else {
-                Order {
-                    qty: 0.0,
-                    price: 0.0,
-                    order_type: OrderType::CloseTrailingShort,
-                }
+                NextOrder::TrailingPending
             }
         }
     }
 }
 
-pub fn calc_next_close_short(
+/// The close_trailing_fib_levels close, armed once price clears close_trailing_threshold_pct.
+/// Level i fires when price retraces through fraction close_trailing_fib_levels[i] of the
+/// move from position price to peak, closing close_trailing_fib_qty_pct; levels fire in
+/// order, trailing_price_bundle.fib_levels_closed counting those already filled.
+pub fn calc_trailing_fib_close_long(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
-    let position_size_abs = position.size.abs();
-    if position_size_abs == 0.0 {
-        // no position
-        return Order::default();
+) -> Option<Order> {
+    if position.size <= 0.0 || !bot_params.close_trailing_fib_enabled() {
+        return None;
     }
-    let wallet_exposure = calc_wallet_exposure(
-        exchange_params.c_mult,
-        state_params.balance,
-        position_size_abs,
-        position.price,
+    let level = *bot_params
+        .close_trailing_fib_levels
+        .get(trailing_price_bundle.fib_levels_closed)?;
+    let peak = trailing_price_bundle.max_since_open;
+    if peak <= position.price * (1.0 + bot_params.close_trailing_threshold_pct) {
+        return None;
+    }
+    let level_price = peak - level * (peak - position.price);
+    if trailing_price_bundle.min_since_max >= level_price {
+        return None;
+    }
+    let close_price = f64::max(
+        state_params.order_book.ask,
+        round_price_for_side(level_price, false, exchange_params),
     );
-    let wallet_exposure_ratio = if bot_params.wallet_exposure_limit <= 0.0 {
-        10.0
-    } else {
-        wallet_exposure / bot_params.wallet_exposure_limit
-    };
-    if bot_params.enforce_exposure_limit && wallet_exposure_ratio > 1.01 {
-        let position_size_lowered = position_size_abs * 0.9;
-        let wallet_exposure_lowered = calc_wallet_exposure(
-            exchange_params.c_mult,
-            state_params.balance,
-            position_size_lowered,
-            position.price,
+    Some(Order {
+        qty: -calc_close_qty(
+            exchange_params,
+            bot_params,
+            position,
+            bot_params.close_trailing_fib_qty_pct,
+            bot_params.close_balance(state_params),
+            close_price,
+        ),
+        price: close_price,
+        order_type: OrderType::CloseTrailingFibLong,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+/// The fast and slow trailing closes, each armed by its own close_trailing_{fast,slow}_*
+/// threshold and retracement, then the next Fibonacci level close. The fast leg is sized on
+/// the whole position, each later leg on what the legs before it leave.
+pub fn calc_trailing_legs_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> Vec<Order> {
+    let legs = [
+        (
+            OrderType::CloseTrailingFastLong,
+            bot_params.close_trailing_fast_threshold_pct,
+            bot_params.close_trailing_fast_retracement_pct,
+            bot_params.close_trailing_fast_qty_pct,
+        ),
+        (
+            OrderType::CloseTrailingSlowLong,
+            bot_params.close_trailing_slow_threshold_pct,
+            bot_params.close_trailing_slow_retracement_pct,
+            bot_params.close_trailing_slow_qty_pct,
+        ),
+    ];
+    let mut position_left = *position;
+    let mut orders = Vec::new();
+    for (order_type, threshold_pct, retracement_pct, qty_pct) in legs {
+        if qty_pct <= 0.0 {
+            continue;
+        }
+        let leg_params = BotParams {
+            close_trailing_threshold_pct: threshold_pct,
+            close_trailing_retracement_pct: retracement_pct,
+            close_trailing_qty_pct: qty_pct,
+            ..bot_params.clone()
+        };
+        if let Some(close) = calc_trailing_close_long(
+            exchange_params,
+            state_params,
+            &leg_params,
+            &position_left,
+            trailing_price_bundle,
+        )
+        .order()
+        {
+            if close.qty == 0.0 {
+                continue;
+            }
+            position_left.size = round_(position_left.size + close.qty, exchange_params.qty_step);
+            orders.push(Order {
+                order_type,
+                ..close
+            });
+        }
+    }
+    if let Some(close) = calc_trailing_fib_close_long(
+        exchange_params,
+        state_params,
+        bot_params,
+        &position_left,
+        trailing_price_bundle,
+    ) {
+        if close.qty != 0.0 {
+            orders.push(close);
+        }
+    }
+    orders
+}
+
+/// The protective exit once equity_drawdown_triggered: the whole position closes on a grid
+/// anchored at the ask instead of at pprice, in close_grid_qty_pct chunks from the ask up to
+/// close_grid_min_markup above it, ignoring the markup range and trailing.
+pub fn calc_drawdown_closes_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+) -> Vec<Order> {
+    let ask = state_params.order_book.ask;
+    let close_grid_qty_pct = bot_params.close_grid_qty_pct_clamped();
+    let n_levels = (1.0 / close_grid_qty_pct).ceil().max(1.0);
+    let mut closes = Vec::<Order>::new();
+    let mut psize = round_(position.size, exchange_params.qty_step);
+    while psize > 0.0 {
+        let markup = bot_params.close_grid_min_markup * closes.len() as f64 / n_levels;
+        let price = f64::max(
+            ask,
+            round_price_for_side(ask * (1.0 + markup), false, exchange_params),
         );
-        let ideal_psize = interpolate(
-            bot_params.wallet_exposure_limit * 1.01,
-            &[wallet_exposure, wallet_exposure_lowered],
-            &[position_size_abs, position_size_lowered],
+        let qty = f64::min(
+            psize,
+            // at least one qty_step, or a zero close_grid_qty_pct never gets through psize
+            calc_min_entry_qty(price, exchange_params)
+                .max(round_up(
+                    position.size * close_grid_qty_pct,
+                    exchange_params.qty_step,
+                ))
+                .max(exchange_params.qty_step),
         );
-        let auto_reduce_qty = position_size_abs - ideal_psize;
-        if auto_reduce_qty > 0.0 {
-            let close_qty = f64::min(
-                round_(position_size_abs, exchange_params.qty_step),
-                f64::max(
-                    calc_min_entry_qty(state_params.order_book.bid, &exchange_params),
-                    round_(auto_reduce_qty, exchange_params.qty_step),
-                ),
-            );
-            return Order {
-                price: state_params.order_book.bid,
-                qty: close_qty,
-                order_type: OrderType::CloseAutoReduceShort,
-            };
+        psize = round_(psize - qty, exchange_params.qty_step);
+        match closes.last_mut() {
+            Some(previous) if previous.price == price => {
+                previous.qty = round_(previous.qty - qty, exchange_params.qty_step)
+            }
+            _ => closes.push(Order::new(-qty, price, OrderType::CloseDrawdownLong)),
         }
     }
-    if bot_params.close_trailing_grid_ratio >= 1.0 || bot_params.close_trailing_grid_ratio <= -1.0 {
-        // return trailing only
-        return calc_trailing_close_short(
-            &exchange_params,
-            &state_params,
-            &bot_params,
-            &position,
-            &trailing_price_bundle,
+    closes
+}
+
+/// calc_drawdown_closes_long for shorts, from the bid down.
+pub fn calc_drawdown_closes_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+) -> Vec<Order> {
+    let bid = state_params.order_book.bid;
+    let close_grid_qty_pct = bot_params.close_grid_qty_pct_clamped();
+    let n_levels = (1.0 / close_grid_qty_pct).ceil().max(1.0);
+    let mut closes = Vec::<Order>::new();
+    let mut psize = round_(position.size.abs(), exchange_params.qty_step);
+    while psize > 0.0 {
+        let markup = bot_params.close_grid_min_markup * closes.len() as f64 / n_levels;
+        let price = f64::min(
+            bid,
+            round_price_for_side(bid * (1.0 - markup), true, exchange_params),
+        );
+        let qty = f64::min(
+            psize,
+            calc_min_entry_qty(price, exchange_params)
+                .max(round_up(
+                    position.size.abs() * close_grid_qty_pct,
+                    exchange_params.qty_step,
+                ))
+                .max(exchange_params.qty_step),
         );
+        psize = round_(psize - qty, exchange_params.qty_step);
+        match closes.last_mut() {
+            Some(previous) if previous.price == price => {
+                previous.qty = round_(previous.qty + qty, exchange_params.qty_step)
+            }
+            _ => closes.push(Order::new(qty, price, OrderType::CloseDrawdownShort)),
+        }
     }
-    if bot_params.close_trailing_grid_ratio == 0.0 {
-        // return grid only
-        return calc_grid_close_short(&exchange_params, &state_params, &bot_params, &position);
+    closes
+}
+
+/// Close at the ask bringing a long position's wallet exposure back down to
+/// wallet_exposure_limit, once it exceeds the limit by more than auto_reduce_tolerance_pct,
+/// e.g. after a withdrawal or a lowered limit. Without auto_reduce_enabled, the legacy
+/// reduction applies instead: past 1% over the limit, back down to 1% over it. Takes
+/// precedence over every other close but the drawdown exit; None unless
+/// enforce_exposure_limit is set.
+pub fn calc_auto_reduce_close_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+) -> Option<Order> {
+    let ask = state_params.order_book.ask;
+    let qty = calc_auto_reduce_qty(exchange_params, state_params, bot_params, position, ask)?;
+    Some(Order {
+        qty: -qty,
+        price: ask,
+        order_type: OrderType::CloseAutoReduceLong,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+/// calc_auto_reduce_close_long for shorts, at the bid.
+pub fn calc_auto_reduce_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+) -> Option<Order> {
+    let bid = state_params.order_book.bid;
+    let qty = calc_auto_reduce_qty(exchange_params, state_params, bot_params, position, bid)?;
+    Some(Order {
+        qty,
+        price: bid,
+        order_type: OrderType::CloseAutoReduceShort,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+// unsigned qty taking position back under wallet_exposure_limit, at least min qty at price
+fn calc_auto_reduce_qty(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    price: f64,
+) -> Option<f64> {
+    let balance = bot_params.close_balance(state_params);
+    let bot_params = bot_params.with_position_cost_cap(balance);
+    if !bot_params.enforce_exposure_limit {
+        return None;
     }
-    let wallet_exposure_ratio = calc_wallet_exposure(
+    let position_size_abs = position.size.abs();
+    let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
-        state_params.balance,
+        balance,
         position_size_abs,
         position.price,
-    ) / bot_params.wallet_exposure_limit;
-    if bot_params.close_trailing_grid_ratio > 0.0 {
-        // trailing first
-        if wallet_exposure_ratio < bot_params.close_trailing_grid_ratio {
-            // return trailing order, closing whole pos
-            calc_trailing_close_short(
-                &exchange_params,
-                &state_params,
-                &bot_params,
-                &position,
-                &trailing_price_bundle,
-            )
-        } else {
-            // return grid order, but leave full_psize * close_trailing_grid_ratio for trailing close
-            let mut trailing_allocation = cost_to_qty(
-                state_params.balance
-                    * bot_params.wallet_exposure_limit
-                    * bot_params.close_trailing_grid_ratio,
-                position.price,
-                exchange_params.c_mult,
-            );
-            let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
-            if trailing_allocation < min_entry_qty {
-                trailing_allocation = 0.0;
-            }
-            let grid_allocation = round_(
-                position_size_abs - trailing_allocation,
-                exchange_params.qty_step,
-            );
-            let position_mod = Position {
-                size: -f64::min(position_size_abs, f64::max(grid_allocation, min_entry_qty)),
-                price: position.price,
-            };
-            calc_grid_close_short(&exchange_params, &state_params, &bot_params, &position_mod)
-        }
+    );
+    let wallet_exposure_ratio = if bot_params.wallet_exposure_limit <= 0.0 {
+        10.0
     } else {
-        if wallet_exposure_ratio < 1.0 + bot_params.close_trailing_grid_ratio {
-            // return grid order, closing whole position
-            return calc_grid_close_short(&exchange_params, &state_params, &bot_params, &position);
-        } else {
-            // return trailing order, but leave full_psize * (1.0 + close_trailing_grid_ratio) for grid close
-            let mut grid_allocation = cost_to_qty(
-                state_params.balance
-                    * bot_params.wallet_exposure_limit
-                    * (1.0 + bot_params.close_trailing_grid_ratio),
-                position.price,
-                exchange_params.c_mult,
-            );
-            let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
-            if grid_allocation < min_entry_qty {
-                grid_allocation = 0.0;
-            }
-            let trailing_allocation = round_(
-                position_size_abs - grid_allocation,
-                exchange_params.qty_step,
-            );
-            let position_mod = Position {
-                size: -f64::min(
-                    position_size_abs,
-                    f64::max(trailing_allocation, min_entry_qty),
-                ),
-                price: position.price,
-            };
-            calc_trailing_close_short(
-                &exchange_params,
-                &state_params,
-                &bot_params,
-                &position_mod,
-                &trailing_price_bundle,
-            )
+        wallet_exposure / bot_params.wallet_exposure_limit
+    };
+    let ideal_psize = if bot_params.auto_reduce_enabled {
+        let wallet_exposure_limit = bot_params.wallet_exposure_limit.max(0.0);
+        let tolerance = bot_params.auto_reduce_tolerance_pct.max(0.0);
+        if wallet_exposure <= wallet_exposure_limit * (1.0 + tolerance) {
+            return None;
         }
+        // exposure is linear in size
+        position_size_abs * wallet_exposure_limit / wallet_exposure
+    } else if wallet_exposure_ratio > 1.01 {
+        let position_size_lowered = position_size_abs * 0.9;
+        let wallet_exposure_lowered = calc_wallet_exposure(
+            exchange_params.c_mult,
+            balance,
+            position_size_lowered,
+            position.price,
+        );
+        interpolate(
+            bot_params.wallet_exposure_limit * 1.01,
+            &[wallet_exposure, wallet_exposure_lowered],
+            &[position_size_abs, position_size_lowered],
+        )
+    } else {
+        return None;
+    };
+    let auto_reduce_qty = position_size_abs - ideal_psize;
+    if auto_reduce_qty <= 0.0 {
+        return None;
     }
+    Some(f64::min(
+        round_(position_size_abs, exchange_params.qty_step),
+        f64::max(
+            calc_min_entry_qty(price, exchange_params),
+            round_qty(auto_reduce_qty, exchange_params),
+        ),
+    ))
 }
 
-pub fn calc_closes_long(
+pub fn calc_next_close_long(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
-) -> Vec<Order> {
-    let mut closes = Vec::<Order>::new();
-    let mut psize = position.size;
-    let mut ask = state_params.order_book.ask;
-    for _ in 0..500 {
-        let position_mod = Position {
-            size: psize,
-            price: position.price,
-        };
-        let mut state_params_mod = state_params.clone();
-        state_params_mod.order_book.ask = ask;
-        let close = calc_next_close_long(
-            exchange_params,
-            &state_params_mod,
-            bot_params,
-            &position_mod,
+) -> NextOrder {
+    let balance = bot_params.close_balance(state_params);
+    let bot_params: &BotParams = &bot_params.with_position_cost_cap(balance);
+    if position.size <= 0.0 {
+        // no position, or a wrong-signed one from corrupt state
+        return NextOrder::NoOrder;
+    }
+    if bot_params.equity_drawdown_triggered(state_params) {
+        return calc_drawdown_closes_long(exchange_params, state_params, bot_params, position)
+            .first()
+            .copied()
+            .into();
+    }
+    let wallet_exposure = calc_wallet_exposure(
+        exchange_params.c_mult,
+        balance,
+        position.size,
+        position.price,
+    );
+    let wallet_exposure_ratio = if bot_params.wallet_exposure_limit <= 0.0 {
+        10.0
+    } else {
+        wallet_exposure / bot_params.wallet_exposure_limit
+    };
+    if let Some(close) =
+        calc_auto_reduce_close_long(exchange_params, state_params, bot_params, position)
+    {
+        return NextOrder::Order(close);
+    }
+    match bot_params.close_trailing_grid_split() {
+        TrailingGridSplit::TrailingOnly => calc_trailing_close_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &position,
             &trailing_price_bundle,
-        );
-        if close.qty == 0.0 {
-            break;
+        ),
+        TrailingGridSplit::GridOnly => {
+            calc_grid_close_long(&exchange_params, &state_params, &bot_params, &position).into()
         }
-        psize = round_(psize + close.qty, exchange_params.qty_step);
-        ask = ask.max(close.price);
-        if !closes.is_empty() {
-            if close.order_type == OrderType::CloseTrailingLong {
-                break;
+        TrailingGridSplit::TrailingFirst(trailing_share) => {
+            if wallet_exposure_ratio < trailing_share {
+                // return trailing order
+                calc_trailing_close_long(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    &position,
+                    &trailing_price_bundle,
+                )
+            } else {
+                // return grid order, but leave full_psize * close_trailing_grid_ratio for trailing close
+                let mut trailing_allocation = cost_to_qty(
+                    balance * bot_params.wallet_exposure_limit * trailing_share,
+                    position.price,
+                    exchange_params.c_mult,
+                );
+                let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
+                if trailing_allocation < min_entry_qty {
+                    trailing_allocation = 0.0;
+                }
+                let grid_allocation = round_(
+                    position.size - trailing_allocation,
+                    exchange_params.qty_step,
+                );
+                let position_mod = position.resized(f64::min(
+                    position.size,
+                    f64::max(grid_allocation, min_entry_qty),
+                ));
+                calc_grid_close_long(&exchange_params, &state_params, &bot_params, &position_mod)
+                    .into()
             }
-            if closes[closes.len() - 1].price == close.price {
-                let previous_close = closes.pop();
-                let merged_close = Order {
-                    qty: round_(
-                        previous_close.unwrap().qty + close.qty,
-                        exchange_params.qty_step,
-                    ),
-                    price: close.price,
-                    order_type: close.order_type,
-                };
-                closes.push(merged_close);
-                continue;
+        }
+        TrailingGridSplit::GridFirst(grid_share) => {
+            if wallet_exposure_ratio < grid_share {
+                // return grid order, closing whole position
+                calc_grid_close_long(&exchange_params, &state_params, &bot_params, &position).into()
+            } else {
+                // return trailing order, but leave full_psize * (1.0 + close_trailing_grid_ratio) for grid close
+                let mut grid_allocation = cost_to_qty(
+                    balance * bot_params.wallet_exposure_limit * grid_share,
+                    position.price,
+                    exchange_params.c_mult,
+                );
+                let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
+                if grid_allocation < min_entry_qty {
+                    grid_allocation = 0.0;
+                }
+                let trailing_allocation =
+                    round_(position.size - grid_allocation, exchange_params.qty_step);
+                let position_mod = position.resized(f64::min(
+                    position.size,
+                    f64::max(trailing_allocation, min_entry_qty),
+                ));
+                calc_trailing_close_long(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    &position_mod,
+                    &trailing_price_bundle,
+                )
             }
         }
-        closes.push(close);
     }
-    closes
 }
 
-pub fn calc_closes_short(
+pub fn calc_grid_close_short(
     exchange_params: &ExchangeParams,
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-    trailing_price_bundle: &TrailingPriceBundle,
-) -> Vec<Order> {
-    let mut closes = Vec::<Order>::new();
-    let mut psize = position.size;
-    let mut bid = state_params.order_book.bid;
-    for _ in 0..500 {
-        let position_mod = Position {
-            size: psize,
-            price: position.price,
-        };
-        let mut state_params_mod = state_params.clone();
-        state_params_mod.order_book.bid = bid;
-        let close = calc_next_close_short(
+) -> Option<Order> {
+    let bot_params: &BotParams =
+        &bot_params.with_position_cost_cap(bot_params.close_balance(state_params));
+    calc_grid_close_short_unchecked(exchange_params, state_params, bot_params, position)
+        .filter(|close| !post_only_rejected(bot_params, state_params, close))
+}
+
+fn calc_grid_close_short_unchecked(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+) -> Option<Order> {
+    let balance = bot_params.close_balance(state_params);
+    // widened to recover funding paid while the position was held, tightened under
+    // order flow against the position or by the caller's close_markup_scale
+    let markup_scale = bot_params.flow_imbalance_markup_scale(state_params, SHORT)
+        * state_params
+            .close_markup_scale
+            .map_or(1.0, |scale| scale.max(0.0));
+    let close_grid_min_markup = (bot_params
+        .effective_close_grid_min_markup(state_params.min_markup_floor)
+        + calc_funding_markup(exchange_params, bot_params, position))
+        * markup_scale;
+    let close_grid_markup_range = bot_params.close_grid_markup_range * markup_scale;
+    // a positive size is a long, or corrupt state; closing it as a short would grow it
+    if position.size >= 0.0 {
+        return None;
+    }
+    let position_size_abs = position.size.abs();
+    let close_grid_qty_pct = bot_params.close_grid_qty_pct_clamped();
+    // a markup of 100% or more, e.g. from recovering heavy funding on a small position,
+    // would price the close at or below zero; the lowest valid price is one step
+    let markup_price = |markup: f64| {
+        f64::max(
+            round_price_for_side(position.price * (1.0 - markup), true, exchange_params),
+            exchange_params.price_step,
+        )
+    };
+    let cap_qty = |close_qty: f64, close_price: f64| {
+        cap_close_qty_to_volume(
             exchange_params,
-            &state_params_mod,
+            state_params,
             bot_params,
-            &position_mod,
-            &trailing_price_bundle,
+            position_size_abs,
+            close_qty,
+            close_price,
+        )
+    };
+    if close_grid_markup_range <= 0.0 || close_grid_qty_pct >= 1.0 {
+        let close_price = f64::min(
+            state_params.order_book.bid,
+            markup_price(close_grid_min_markup),
         );
-        if close.qty == 0.0 {
-            break;
-        }
-        psize = round_(psize + close.qty, exchange_params.qty_step);
-        bid = bid.min(close.price);
-        if !closes.is_empty() {
-            if close.order_type == OrderType::CloseTrailingShort {
-                break;
-            }
-            if closes[closes.len() - 1].price == close.price {
-                let previous_close = closes.pop();
-                let merged_close = Order {
-                    qty: round_(
-                        previous_close.unwrap().qty + close.qty,
-                        exchange_params.qty_step,
-                    ),
-                    price: close.price,
-                    order_type: close.order_type,
-                };
-                closes.push(merged_close);
-                continue;
-            }
-        }
-        closes.push(close);
+        return Some(Order {
+            qty: cap_qty(
+                round_(position_size_abs, exchange_params.qty_step),
+                close_price,
+            ),
+            price: close_price,
+            order_type: OrderType::CloseGridShort,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
+    }
+    let close_prices_start = markup_price(close_grid_min_markup);
+    let close_prices_end = markup_price(close_grid_min_markup + close_grid_markup_range);
+    if close_prices_start == close_prices_end {
+        let close_price = f64::min(state_params.order_book.bid, close_prices_start);
+        return Some(Order {
+            qty: cap_qty(
+                round_(position_size_abs, exchange_params.qty_step),
+                close_price,
+            ),
+            price: close_price,
+            order_type: OrderType::CloseGridShort,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
+    }
+    let n_steps = ((close_prices_start - close_prices_end) / exchange_params.price_step).ceil();
+    let wallet_exposure = calc_wallet_exposure(
+        exchange_params.c_mult,
+        balance,
+        position_size_abs,
+        position.price,
+    );
+    let wallet_exposure_ratio = f64::min(1.0, wallet_exposure / bot_params.wallet_exposure_limit);
+    if wallet_exposure_ratio < 0.5 && !bot_params.close_volume_confirmed(state_params.volume) {
+        // don't take the upper half of the range on a low-conviction move
+        return None;
+    }
+    // larger chunks in liquid hours, smaller in thin ones
+    let close_grid_qty_pct_modified = calc_geometric_close_qty_pct(
+        f64::max(close_grid_qty_pct, 1.0 / n_steps),
+        bot_params.close_grid_qty_ratio,
+        1.0 - wallet_exposure_ratio,
+    ) * bot_params.liquidity_multiplier(state_params.hour);
+    let close_price = f64::min(
+        markup_price(
+            close_grid_min_markup + close_grid_markup_range * (1.0 - wallet_exposure_ratio),
+        ),
+        state_params.order_book.bid,
+    );
+    let close_qty = cap_qty(
+        calc_close_qty(
+            &exchange_params,
+            &bot_params,
+            &position,
+            close_grid_qty_pct_modified,
+            balance,
+            close_price,
+        ),
+        close_price,
+    );
+    Some(Order {
+        qty: close_qty,
+        price: close_price,
+        order_type: OrderType::CloseGridShort,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+pub fn calc_close_with_fallback_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    fallback_candles: usize,
+) -> Option<CloseWithFallback> {
+    let limit = calc_grid_close_short(exchange_params, state_params, bot_params, position)?;
+    if limit.qty == 0.0 {
+        return None;
+    }
+    Some(CloseWithFallback {
+        limit,
+        fallback: Order {
+            qty: limit.qty,
+            price: state_params.order_book.ask,
+            order_type: OrderType::CloseFallbackMarketShort,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        },
+        fallback_candles,
+    })
+}
+
+pub fn calc_trailing_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> NextOrder {
+    let balance = bot_params.close_balance(state_params);
+    if position.size >= 0.0 {
+        return NextOrder::NoOrder;
+    }
+    if bot_params.close_trailing_threshold_pct <= 0.0 {
+        // means trailing stop immediately from pos open
+        if (bot_params.close_trailing_retracement_pct > 0.0
+            && retraced_short(
+                exchange_params,
+                state_params,
+                bot_params,
+                trailing_price_bundle,
+            ))
+            || stagnated_since_peak(bot_params, trailing_price_bundle.candles_since_min)
+        {
+            NextOrder::Order(Order {
+                qty: calc_close_qty(
+                    &exchange_params,
+                    &bot_params,
+                    &position,
+                    bot_params.close_trailing_qty_pct,
+                    balance,
+                    state_params.order_book.bid,
+                ),
+                price: state_params.order_book.bid,
+                order_type: OrderType::CloseTrailingShort,
+                iceberg_qty: None,
+                min_fill_qty: 0.0,
+            })
+        } else {
+            NextOrder::TrailingPending
+        }
+    } else {
+        // means trailing stop will activate only after a threshold
+        if bot_params.close_trailing_retracement_pct <= 0.0 {
+            // close at threshold
+            let close_price = f64::min(
+                state_params.order_book.bid,
+                round_price_for_side(
+                    position.price * (1.0 - bot_params.close_trailing_threshold_pct),
+                    true,
+                    exchange_params,
+                ),
+            );
+            NextOrder::Order(Order {
+                qty: calc_close_qty(
+                    &exchange_params,
+                    &bot_params,
+                    &position,
+                    bot_params.close_trailing_qty_pct,
+                    balance,
+                    close_price,
+                ),
+                price: close_price,
+                order_type: OrderType::CloseTrailingShort,
+                iceberg_qty: None,
+                min_fill_qty: 0.0,
+            })
+        } else {
+            if trailing_price_bundle.min_since_open
+                < position.price * (1.0 - bot_params.close_trailing_threshold_pct)
+                && (retraced_short(
+                    exchange_params,
+                    state_params,
+                    bot_params,
+                    trailing_price_bundle,
+                ) || stagnated_since_peak(bot_params, trailing_price_bundle.candles_since_min))
+            {
+                let close_price = match bot_params.close_trailing_anchor {
+                    CloseTrailingAnchor::Peak => f64::min(
+                        state_params.order_book.bid,
+                        round_price_for_side(
+                            position.price
+                                * (1.0 - bot_params.close_trailing_threshold_pct
+                                    + bot_params.close_trailing_retracement_pct),
+                            true,
+                            exchange_params,
+                        ),
+                    ),
+                    // the moving average moves with price; close at market once crossed
+                    CloseTrailingAnchor::MovingAverage => state_params.order_book.bid,
+                };
+                NextOrder::Order(Order {
+                    qty: calc_close_qty(
+                        &exchange_params,
+                        &bot_params,
+                        &position,
+                        bot_params.close_trailing_qty_pct,
+                        balance,
+                        close_price,
+                    ),
+                    price: close_price,
+                    order_type: OrderType::CloseTrailingShort,
+                    iceberg_qty: None,
+                    min_fill_qty: 0.0,
+                })
+            } else {
+                NextOrder::TrailingPending
+            }
+        }
+    }
+}
+
+/// The close_trailing_fib_levels close, armed once price clears close_trailing_threshold_pct.
+/// Level i fires when price retraces through fraction close_trailing_fib_levels[i] of the
+/// move from position price to trough, closing close_trailing_fib_qty_pct; levels fire in
+/// order, trailing_price_bundle.fib_levels_closed counting those already filled.
+pub fn calc_trailing_fib_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> Option<Order> {
+    if position.size >= 0.0 || !bot_params.close_trailing_fib_enabled() {
+        return None;
+    }
+    let level = *bot_params
+        .close_trailing_fib_levels
+        .get(trailing_price_bundle.fib_levels_closed)?;
+    let trough = trailing_price_bundle.min_since_open;
+    if trough >= position.price * (1.0 - bot_params.close_trailing_threshold_pct) {
+        return None;
+    }
+    let level_price = trough + level * (position.price - trough);
+    if trailing_price_bundle.max_since_min <= level_price {
+        return None;
+    }
+    let close_price = f64::min(
+        state_params.order_book.bid,
+        round_price_for_side(level_price, true, exchange_params),
+    );
+    Some(Order {
+        qty: calc_close_qty(
+            exchange_params,
+            bot_params,
+            position,
+            bot_params.close_trailing_fib_qty_pct,
+            bot_params.close_balance(state_params),
+            close_price,
+        ),
+        price: close_price,
+        order_type: OrderType::CloseTrailingFibShort,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+/// The fast and slow trailing closes, each armed by its own close_trailing_{fast,slow}_*
+/// threshold and retracement, then the next Fibonacci level close. The fast leg is sized on
+/// the whole position, each later leg on what the legs before it leave.
+pub fn calc_trailing_legs_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> Vec<Order> {
+    let legs = [
+        (
+            OrderType::CloseTrailingFastShort,
+            bot_params.close_trailing_fast_threshold_pct,
+            bot_params.close_trailing_fast_retracement_pct,
+            bot_params.close_trailing_fast_qty_pct,
+        ),
+        (
+            OrderType::CloseTrailingSlowShort,
+            bot_params.close_trailing_slow_threshold_pct,
+            bot_params.close_trailing_slow_retracement_pct,
+            bot_params.close_trailing_slow_qty_pct,
+        ),
+    ];
+    let mut position_left = *position;
+    let mut orders = Vec::new();
+    for (order_type, threshold_pct, retracement_pct, qty_pct) in legs {
+        if qty_pct <= 0.0 {
+            continue;
+        }
+        let leg_params = BotParams {
+            close_trailing_threshold_pct: threshold_pct,
+            close_trailing_retracement_pct: retracement_pct,
+            close_trailing_qty_pct: qty_pct,
+            ..bot_params.clone()
+        };
+        if let Some(close) = calc_trailing_close_short(
+            exchange_params,
+            state_params,
+            &leg_params,
+            &position_left,
+            trailing_price_bundle,
+        )
+        .order()
+        {
+            if close.qty == 0.0 {
+                continue;
+            }
+            position_left.size = round_(position_left.size + close.qty, exchange_params.qty_step);
+            orders.push(Order {
+                order_type,
+                ..close
+            });
+        }
+    }
+    if let Some(close) = calc_trailing_fib_close_short(
+        exchange_params,
+        state_params,
+        bot_params,
+        &position_left,
+        trailing_price_bundle,
+    ) {
+        if close.qty != 0.0 {
+            orders.push(close);
+        }
+    }
+    orders
+}
+
+pub fn calc_next_close_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+) -> NextOrder {
+    let balance = bot_params.close_balance(state_params);
+    let bot_params: &BotParams = &bot_params.with_position_cost_cap(balance);
+    if position.size >= 0.0 {
+        // no position, or a wrong-signed one from corrupt state
+        return NextOrder::NoOrder;
+    }
+    if bot_params.equity_drawdown_triggered(state_params) {
+        return calc_drawdown_closes_short(exchange_params, state_params, bot_params, position)
+            .first()
+            .copied()
+            .into();
+    }
+    let position_size_abs = position.size.abs();
+    let wallet_exposure = calc_wallet_exposure(
+        exchange_params.c_mult,
+        balance,
+        position_size_abs,
+        position.price,
+    );
+    let wallet_exposure_ratio = if bot_params.wallet_exposure_limit <= 0.0 {
+        10.0
+    } else {
+        wallet_exposure / bot_params.wallet_exposure_limit
+    };
+    if let Some(close) =
+        calc_auto_reduce_close_short(exchange_params, state_params, bot_params, position)
+    {
+        return NextOrder::Order(close);
+    }
+    match bot_params.close_trailing_grid_split() {
+        TrailingGridSplit::TrailingOnly => calc_trailing_close_short(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &position,
+            &trailing_price_bundle,
+        ),
+        TrailingGridSplit::GridOnly => {
+            calc_grid_close_short(&exchange_params, &state_params, &bot_params, &position).into()
+        }
+        TrailingGridSplit::TrailingFirst(trailing_share) => {
+            if wallet_exposure_ratio < trailing_share {
+                // return trailing order, closing whole pos
+                calc_trailing_close_short(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    &position,
+                    &trailing_price_bundle,
+                )
+            } else {
+                // return grid order, but leave full_psize * close_trailing_grid_ratio for trailing close
+                let mut trailing_allocation = cost_to_qty(
+                    balance * bot_params.wallet_exposure_limit * trailing_share,
+                    position.price,
+                    exchange_params.c_mult,
+                );
+                let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
+                if trailing_allocation < min_entry_qty {
+                    trailing_allocation = 0.0;
+                }
+                let grid_allocation = round_(
+                    position_size_abs - trailing_allocation,
+                    exchange_params.qty_step,
+                );
+                let position_mod = position.resized(-f64::min(
+                    position_size_abs,
+                    f64::max(grid_allocation, min_entry_qty),
+                ));
+                calc_grid_close_short(&exchange_params, &state_params, &bot_params, &position_mod)
+                    .into()
+            }
+        }
+        TrailingGridSplit::GridFirst(grid_share) => {
+            if wallet_exposure_ratio < grid_share {
+                // return grid order, closing whole position
+                return calc_grid_close_short(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    &position,
+                )
+                .into();
+            } else {
+                // return trailing order, but leave full_psize * (1.0 + close_trailing_grid_ratio) for grid close
+                let mut grid_allocation = cost_to_qty(
+                    balance * bot_params.wallet_exposure_limit * grid_share,
+                    position.price,
+                    exchange_params.c_mult,
+                );
+                let min_entry_qty = calc_min_entry_qty(position.price, &exchange_params);
+                if grid_allocation < min_entry_qty {
+                    grid_allocation = 0.0;
+                }
+                let trailing_allocation = round_(
+                    position_size_abs - grid_allocation,
+                    exchange_params.qty_step,
+                );
+                let position_mod = position.resized(-f64::min(
+                    position_size_abs,
+                    f64::max(trailing_allocation, min_entry_qty),
+                ));
+                calc_trailing_close_short(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    &position_mod,
+                    &trailing_price_bundle,
+                )
+            }
+        }
+    }
+}
+
+/// Clamps close prices into mark_price * (1 ± price_band_pct), outside of which exchanges
+/// reject limit orders. Closes clamped onto a price already taken by a nearer close are
+/// merged into it, so the ladder still closes the whole position; all closes are dropped
+/// only if the band is narrower than one price step.
+pub fn apply_price_band(
+    closes: Vec<Order>,
+    exchange_params: &ExchangeParams,
+    mark_price: f64,
+) -> Vec<Order> {
+    if exchange_params.price_band_pct <= 0.0 || mark_price <= 0.0 {
+        return closes;
+    }
+    let lower = round_up(
+        mark_price * (1.0 - exchange_params.price_band_pct),
+        exchange_params.price_step,
+    );
+    let upper = round_dn(
+        mark_price * (1.0 + exchange_params.price_band_pct),
+        exchange_params.price_step,
+    );
+    if lower > upper {
+        return Vec::new();
+    }
+    let mut banded = Vec::<Order>::with_capacity(closes.len());
+    for close in closes {
+        let clamped = Order {
+            price: close.price.max(lower).min(upper),
+            ..close
+        };
+        if let Some(previous) = banded.last_mut() {
+            if OrderKey::new(previous, exchange_params).price_ticks
+                == OrderKey::new(&clamped, exchange_params).price_ticks
+            {
+                previous.qty = round_(previous.qty + clamped.qty, exchange_params.qty_step);
+                continue;
+            }
+        }
+        banded.push(clamped);
+    }
+    banded
+}
+
+/// Total quote notional of a close ladder, sum of |qty| * price * c_mult.
+/// Qty signs differ between long and short closes, so both sides may be summed together.
+pub fn calc_ladder_notional(orders: &[Order], c_mult: f64) -> f64 {
+    orders
+        .iter()
+        .map(|order| qty_to_cost(order.qty, order.price, c_mult))
+        .sum()
+}
+
+/// Moves a close price off blocked prices one tick at a time, away from the market: up for
+/// long closes (tick_direction 1), down for short closes (tick_direction -1). None if no tick
+/// at or above one price_step is left, as when blocked prices reach down to a short's floor.
+fn nudge_off_blocked_prices(
+    price: Price,
+    blocked_prices: &[f64],
+    tick_direction: i64,
+) -> Option<Price> {
+    let is_blocked = |price: Price| {
+        blocked_prices
+            .iter()
+            .any(|&blocked| Price::from_f64(blocked, price.step()) == Some(price))
+    };
+    let mut price = price;
+    while price.ticks() >= 1 && is_blocked(price) {
+        price = price.offset(tick_direction);
+    }
+    (price.ticks() >= 1).then_some(price)
+}
+
+/// nudge_off_blocked_prices for a ladder priced outward from the touch. A close nudged onto
+/// the next one's price is merged into it; one left without a price is dropped.
+fn nudge_closes_off_blocked_prices(
+    closes: Vec<Order>,
+    exchange_params: &ExchangeParams,
+    blocked_prices: &[f64],
+    tick_direction: i64,
+) -> Vec<Order> {
+    let mut nudged = Vec::<Order>::with_capacity(closes.len());
+    for close in closes {
+        let price = match Price::from_f64(close.price, exchange_params.price_step) {
+            Some(price) => match nudge_off_blocked_prices(price, blocked_prices, tick_direction) {
+                Some(price) => price.to_f64(),
+                None => continue,
+            },
+            None => close.price,
+        };
+        match nudged.last_mut() {
+            Some(previous) if previous.price == price => {
+                previous.qty = round_(previous.qty + close.qty, exchange_params.qty_step)
+            }
+            _ => nudged.push(Order { price, ..close }),
+        }
+    }
+    nudged
+}
+
+/// With simulate_post_only_reject, whether the exchange would reject a grid close as a post-only
+/// order crossing the book: a long close priced at or below the ask, a short close at or above
+/// the bid.
+fn post_only_rejected(bot_params: &BotParams, state_params: &StateParams, close: &Order) -> bool {
+    bot_params.simulate_post_only_reject
+        && match close.order_type {
+            OrderType::CloseGridLong => close.price <= state_params.order_book.ask,
+            OrderType::CloseGridShort => close.price >= state_params.order_book.bid,
+            _ => false,
+        }
+}
+
+// rungs are built unchecked, so the ones past a rejected rung still size down from it
+fn ladder_bot_params(bot_params: &BotParams) -> Cow<'_, BotParams> {
+    if bot_params.simulate_post_only_reject {
+        Cow::Owned(BotParams {
+            simulate_post_only_reject: false,
+            ..bot_params.clone()
+        })
+    } else {
+        Cow::Borrowed(bot_params)
+    }
+}
+
+/// With close_nearest_taker, retypes the grid close nearest the market as taker so the most
+/// urgent level is sure to fill while farther levels rest as maker. The level keeps its price;
+/// it is only retyped if within close_taker_threshold_pct of market_price (0.0 == any distance).
+fn flag_nearest_close_taker(
+    closes: &mut [Order],
+    bot_params: &BotParams,
+    market_price: f64,
+    grid_type: OrderType,
+    taker_type: OrderType,
+) {
+    if !bot_params.close_nearest_taker || market_price <= 0.0 {
+        return;
+    }
+    let distance = |close: &Order| (close.price - market_price).abs() / market_price;
+    let nearest = closes
+        .iter_mut()
+        .filter(|close| close.order_type == grid_type && close.qty != 0.0)
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)));
+    if let Some(close) = nearest {
+        if bot_params.close_taker_threshold_pct <= 0.0
+            || distance(close) <= bot_params.close_taker_threshold_pct
+        {
+            close.order_type = taker_type;
+        }
+    }
+}
+
+/// With close_touch_qty_pct, takes that share of each grid close at the touch, as one taker
+/// order priced at touch_price ahead of the grid, so slow assets get a partial fill; the rest
+/// of each level rests at its grid price. Levels whose rest would fall below the exchange
+/// minimum are left whole, as is the ladder if the touch order would.
+fn split_closes_at_touch(
+    closes: Vec<Order>,
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    touch_price: f64,
+    grid_type: OrderType,
+    taker_type: OrderType,
+) -> Vec<Order> {
+    if bot_params.close_touch_qty_pct <= 0.0 || touch_price <= 0.0 {
+        return closes;
+    }
+    let touch_qtys: Vec<f64> = closes
+        .iter()
+        .map(|close| {
+            if close.order_type != grid_type {
+                return 0.0;
+            }
+            let touch_qty = round_dn(
+                close.qty.abs() * bot_params.close_touch_qty_pct.min(1.0),
+                exchange_params.qty_step,
+            );
+            let rest = round_(close.qty.abs() - touch_qty, exchange_params.qty_step);
+            if rest >= calc_min_entry_qty(close.price, exchange_params) {
+                touch_qty
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let touch_qty = round_(touch_qtys.iter().sum(), exchange_params.qty_step);
+    if touch_qty <= 0.0 || touch_qty < calc_min_entry_qty(touch_price, exchange_params) {
+        return closes;
+    }
+    let first_split = touch_qtys.iter().position(|&qty| qty > 0.0).unwrap();
+    let mut split: Vec<Order> = closes
+        .into_iter()
+        .zip(touch_qtys)
+        .map(|(close, touch_qty)| Order {
+            qty: round_(close.qty.abs() - touch_qty, exchange_params.qty_step).copysign(close.qty),
+            ..close
+        })
+        .collect();
+    split.insert(
+        first_split,
+        Order {
+            qty: if grid_type.pside() == LONG {
+                -touch_qty
+            } else {
+                touch_qty
+            },
+            price: touch_price,
+            order_type: taker_type,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        },
+    );
+    split
+}
+
+/// With close_iceberg_visible_qty, shows each grid close larger than it as an iceberg: only
+/// that qty, rounded down to qty_step but no less than the exchange minimum, is visible.
+fn set_close_icebergs(
+    closes: &mut [Order],
+    exchange_params: &ExchangeParams,
+    bot_params: &BotParams,
+    grid_type: OrderType,
+) {
+    if bot_params.close_iceberg_visible_qty <= 0.0 {
+        return;
+    }
+    for close in closes
+        .iter_mut()
+        .filter(|close| close.order_type == grid_type)
+    {
+        let visible_qty = f64::max(
+            round_dn(
+                bot_params.close_iceberg_visible_qty,
+                exchange_params.qty_step,
+            ),
+            calc_min_entry_qty(close.price, exchange_params),
+        );
+        if visible_qty < close.qty.abs() {
+            close.iceberg_qty = Some(visible_qty.copysign(close.qty));
+        }
+    }
+}
+
+/// Merges each close priced past the one before it the wrong way, below it for longs and above
+/// it for shorts, into that one, which keeps its price: levels rounded in different directions
+/// can cross, and the ladder must stay monotonic. Prices are compared in ticks.
+pub fn merge_crossed_closes(
+    closes: Vec<Order>,
+    exchange_params: &ExchangeParams,
+    pside: usize,
+) -> Vec<Order> {
+    let ticks = |close: &Order| Price::from_f64(close.price, exchange_params.price_step);
+    let mut merged: Vec<Order> = Vec::with_capacity(closes.len());
+    for close in closes {
+        if let Some(previous) = merged.last_mut() {
+            let crossed = match (ticks(previous), ticks(&close)) {
+                (Some(previous_price), Some(price)) if pside == LONG => price < previous_price,
+                (Some(previous_price), Some(price)) => price > previous_price,
+                _ => false,
+            };
+            if crossed {
+                previous.qty = round_(previous.qty + close.qty, exchange_params.qty_step);
+                continue;
+            }
+        }
+        merged.push(close);
+    }
+    merged
+}
+
+pub fn calc_closes_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+    blocked_prices: &[f64],
+) -> Vec<Order> {
+    let bot_params: &BotParams =
+        &bot_params.with_position_cost_cap(bot_params.close_balance(state_params));
+    if bot_params.equity_drawdown_triggered(state_params) {
+        return calc_drawdown_closes_long(exchange_params, state_params, bot_params, position);
+    }
+    if bot_params.mirror_entries
+        && calc_auto_reduce_close_long(exchange_params, state_params, bot_params, position)
+            .is_none()
+    {
+        let entries = calc_entries_long(
+            exchange_params,
+            state_params,
+            bot_params,
+            position,
+            trailing_price_bundle,
+        );
+        let closes = calc_mirrored_closes_long(exchange_params, state_params, position, &entries);
+        if !closes.is_empty() {
+            let closes =
+                nudge_closes_off_blocked_prices(closes, exchange_params, blocked_prices, 1);
+            return finish_closes(closes, exchange_params, state_params, bot_params, LONG);
+        }
+    }
+    let legs: Vec<Order> = calc_trailing_legs_long(
+        exchange_params,
+        state_params,
+        bot_params,
+        position,
+        trailing_price_bundle,
+    )
+    .into_iter()
+    .filter_map(
+        |leg| match Price::from_f64(leg.price, exchange_params.price_step) {
+            Some(price) => nudge_off_blocked_prices(price, blocked_prices, 1).map(|price| Order {
+                price: price.to_f64(),
+                ..leg
+            }),
+            None => Some(leg),
+        },
+    )
+    .collect();
+    // rungs are matched in ticks and merged in steps so float drift cannot split or dust them
+    let mut closes = Vec::<(Price, Qty, Order)>::new();
+    // the ladder closes what the trailing legs leave
+    let mut psize = legs.iter().fold(position.size, |psize, leg| {
+        round_(psize + leg.qty, exchange_params.qty_step)
+    });
+    let ladder_bot_params = ladder_bot_params(bot_params);
+    let mut ask = state_params.order_book.ask;
+    // as if the levels before each had filled, crediting their pnl
+    let mut balance = state_params.balance;
+    let level_pnl = |close: &Order| {
+        if bot_params.close_credit_ladder_pnl {
+            calc_pnl_long(
+                position.price,
+                close.price,
+                close.qty,
+                exchange_params.c_mult,
+            )
+        } else {
+            0.0
+        }
+    };
+    for _ in 0..500 {
+        let position_mod = position.resized(psize);
+        let mut state_params_mod = state_params.clone();
+        state_params_mod.order_book.ask = ask;
+        state_params_mod.balance = balance;
+        let close = match calc_next_close_long(
+            exchange_params,
+            &state_params_mod,
+            &ladder_bot_params,
+            &position_mod,
+            &trailing_price_bundle,
+        )
+        .order()
+        {
+            Some(close) => close,
+            None => break,
+        };
+        let (price, qty) = match (
+            Price::from_f64(close.price, exchange_params.price_step),
+            Qty::from_f64(close.qty, exchange_params.qty_step),
+        ) {
+            (Some(price), Some(qty)) if !qty.is_zero() => (price, qty),
+            _ => break,
+        };
+        let nudged_price = match nudge_off_blocked_prices(price, blocked_prices, 1) {
+            Some(nudged_price) => nudged_price,
+            None => break,
+        };
+        // calculators may price at the raw order book; only nudged prices are re-derived
+        let mut close = if nudged_price == price {
+            close
+        } else {
+            Order {
+                price: nudged_price.to_f64(),
+                ..close
+            }
+        };
+        let mut price = nudged_price;
+        psize = round_(psize + close.qty, exchange_params.qty_step);
+        ask = ask.max(close.price);
+        if let Some(previous_close) = closes.last_mut() {
+            if close.order_type == OrderType::CloseTrailingLong {
+                break;
+            }
+            if previous_close.0 == price {
+                let merged_qty = previous_close.1 + qty;
+                if calc_close_qty_cap(exchange_params, state_params, bot_params, close.price)
+                    .is_none_or(|cap| merged_qty.to_f64().abs() <= cap)
+                {
+                    balance += level_pnl(&close);
+                    let merged_close = Order {
+                        qty: merged_qty.to_f64(),
+                        ..close
+                    };
+                    *previous_close = (price, merged_qty, merged_close);
+                    continue;
+                }
+                // merged, the rung would exceed the volume cap; move a tick further out
+                price = match nudge_off_blocked_prices(price.offset(1), blocked_prices, 1) {
+                    Some(price) => price,
+                    None => break,
+                };
+                close.price = price.to_f64();
+                ask = ask.max(close.price);
+            }
+        }
+        balance += level_pnl(&close);
+        closes.push((price, qty, close));
+    }
+    let closes = legs
+        .into_iter()
+        .chain(merge_crossed_closes(
+            closes.into_iter().map(|(_, _, close)| close).collect(),
+            exchange_params,
+            LONG,
+        ))
+        .collect();
+    finish_closes(closes, exchange_params, state_params, bot_params, LONG)
+}
+
+/// What every close ladder goes through once priced: clamping into the price band, the taker
+/// decision at the touch, post-only rejects and icebergs.
+fn finish_closes(
+    closes: Vec<Order>,
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    pside: usize,
+) -> Vec<Order> {
+    let (touch, grid_type, taker_type) = if pside == LONG {
+        (
+            state_params.order_book.ask,
+            OrderType::CloseGridLong,
+            OrderType::CloseTakerLong,
+        )
+    } else {
+        (
+            state_params.order_book.bid,
+            OrderType::CloseGridShort,
+            OrderType::CloseTakerShort,
+        )
+    };
+    // order book stands in for mark price
+    let mut closes = apply_price_band(closes, exchange_params, touch);
+    flag_nearest_close_taker(&mut closes, bot_params, touch, grid_type, taker_type);
+    let mut closes = split_closes_at_touch(
+        closes,
+        exchange_params,
+        bot_params,
+        touch,
+        grid_type,
+        taker_type,
+    );
+    // taker closes don't rest on the book, so only the maker rungs left can be rejected
+    closes.retain(|close| !post_only_rejected(bot_params, state_params, close));
+    set_close_icebergs(&mut closes, exchange_params, bot_params, grid_type);
+    closes
+}
+
+pub fn calc_closes_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+    blocked_prices: &[f64],
+) -> Vec<Order> {
+    let bot_params: &BotParams =
+        &bot_params.with_position_cost_cap(bot_params.close_balance(state_params));
+    if bot_params.equity_drawdown_triggered(state_params) {
+        return calc_drawdown_closes_short(exchange_params, state_params, bot_params, position);
+    }
+    if bot_params.mirror_entries
+        && calc_auto_reduce_close_short(exchange_params, state_params, bot_params, position)
+            .is_none()
+    {
+        let entries = calc_entries_short(
+            exchange_params,
+            state_params,
+            bot_params,
+            position,
+            trailing_price_bundle,
+        );
+        let closes = calc_mirrored_closes_short(exchange_params, state_params, position, &entries);
+        if !closes.is_empty() {
+            let closes =
+                nudge_closes_off_blocked_prices(closes, exchange_params, blocked_prices, -1);
+            return finish_closes(closes, exchange_params, state_params, bot_params, SHORT);
+        }
+    }
+    let legs: Vec<Order> = calc_trailing_legs_short(
+        exchange_params,
+        state_params,
+        bot_params,
+        position,
+        trailing_price_bundle,
+    )
+    .into_iter()
+    .filter_map(
+        |leg| match Price::from_f64(leg.price, exchange_params.price_step) {
+            Some(price) => nudge_off_blocked_prices(price, blocked_prices, -1).map(|price| Order {
+                price: price.to_f64(),
+                ..leg
+            }),
+            None => Some(leg),
+        },
+    )
+    .collect();
+    // rungs are matched in ticks and merged in steps so float drift cannot split or dust them
+    let mut closes = Vec::<(Price, Qty, Order)>::new();
+    // the ladder closes what the trailing legs leave
+    let mut psize = legs.iter().fold(position.size, |psize, leg| {
+        round_(psize + leg.qty, exchange_params.qty_step)
+    });
+    let ladder_bot_params = ladder_bot_params(bot_params);
+    let mut bid = state_params.order_book.bid;
+    // as if the levels before each had filled, crediting their pnl
+    let mut balance = state_params.balance;
+    let level_pnl = |close: &Order| {
+        if bot_params.close_credit_ladder_pnl {
+            calc_pnl_short(
+                position.price,
+                close.price,
+                close.qty,
+                exchange_params.c_mult,
+            )
+        } else {
+            0.0
+        }
+    };
+    for _ in 0..500 {
+        let position_mod = position.resized(psize);
+        let mut state_params_mod = state_params.clone();
+        state_params_mod.order_book.bid = bid;
+        state_params_mod.balance = balance;
+        let close = match calc_next_close_short(
+            exchange_params,
+            &state_params_mod,
+            &ladder_bot_params,
+            &position_mod,
+            &trailing_price_bundle,
+        )
+        .order()
+        {
+            Some(close) => close,
+            None => break,
+        };
+        let (price, qty) = match (
+            Price::from_f64(close.price, exchange_params.price_step),
+            Qty::from_f64(close.qty, exchange_params.qty_step),
+        ) {
+            (Some(price), Some(qty)) if !qty.is_zero() => (price, qty),
+            _ => break,
+        };
+        let nudged_price = match nudge_off_blocked_prices(price, blocked_prices, -1) {
+            Some(nudged_price) => nudged_price,
+            None => break,
+        };
+        // calculators may price at the raw order book; only nudged prices are re-derived
+        let mut close = if nudged_price == price {
+            close
+        } else {
+            Order {
+                price: nudged_price.to_f64(),
+                ..close
+            }
+        };
+        let mut price = nudged_price;
+        psize = round_(psize + close.qty, exchange_params.qty_step);
+        bid = bid.min(close.price);
+        if let Some(previous_close) = closes.last_mut() {
+            if close.order_type == OrderType::CloseTrailingShort {
+                break;
+            }
+            if previous_close.0 == price {
+                let merged_qty = previous_close.1 + qty;
+                if calc_close_qty_cap(exchange_params, state_params, bot_params, close.price)
+                    .is_none_or(|cap| merged_qty.to_f64().abs() <= cap)
+                {
+                    balance += level_pnl(&close);
+                    let merged_close = Order {
+                        qty: merged_qty.to_f64(),
+                        ..close
+                    };
+                    *previous_close = (price, merged_qty, merged_close);
+                    continue;
+                }
+                // merged, the rung would exceed the volume cap; move a tick further out
+                price = match nudge_off_blocked_prices(price.offset(-1), blocked_prices, -1) {
+                    Some(price) => price,
+                    None => break,
+                };
+                close.price = price.to_f64();
+                bid = bid.min(close.price);
+            }
+        }
+        balance += level_pnl(&close);
+        closes.push((price, qty, close));
+    }
+    let closes = legs
+        .into_iter()
+        .chain(merge_crossed_closes(
+            closes.into_iter().map(|(_, _, close)| close).collect(),
+            exchange_params,
+            SHORT,
+        ))
+        .collect();
+    finish_closes(closes, exchange_params, state_params, bot_params, SHORT)
+}
+
+/// calc_closes_long with an expiry per level growing with its distance above the ask, from
+/// min_expiry_candles for the nearest level to max_expiry_candles for the farthest, so near
+/// levels refresh often while far ones, unlikely to fill soon, rest without cancel/replace.
+pub fn calc_staggered_closes_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+    min_expiry_candles: usize,
+    max_expiry_candles: usize,
+) -> Vec<StaggeredClose> {
+    let closes = calc_closes_long(
+        exchange_params,
+        state_params,
+        bot_params,
+        position,
+        trailing_price_bundle,
+        &[],
+    );
+    let ask = state_params.order_book.ask;
+    stagger_expiries(closes, min_expiry_candles, max_expiry_candles, |price| {
+        price / ask - 1.0
+    })
+}
+
+/// calc_staggered_closes_long for shorts, by distance below the bid.
+pub fn calc_staggered_closes_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+    min_expiry_candles: usize,
+    max_expiry_candles: usize,
+) -> Vec<StaggeredClose> {
+    let closes = calc_closes_short(
+        exchange_params,
+        state_params,
+        bot_params,
+        position,
+        trailing_price_bundle,
+        &[],
+    );
+    let bid = state_params.order_book.bid;
+    stagger_expiries(closes, min_expiry_candles, max_expiry_candles, |price| {
+        1.0 - price / bid
+    })
+}
+
+/// Grid close ladder with risk-uniform levels: each level closes enough that the remaining
+/// position's value at risk, size times price times volatility, drops by
+/// close_level_var_target, in place of close_grid_qty_pct's uniform qty, so levels shrink in
+/// qty as they rise in price. Levels are priced as calc_grid_close_long prices them;
+/// volatility is the caller's, e.g. a daily return stdev. Empty unless volatility and
+/// close_level_var_target are positive.
+pub fn calc_var_target_closes_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    volatility: f64,
+    close_level_var_target: f64,
+) -> Vec<Order> {
+    calc_var_target_closes(
+        position,
+        volatility,
+        close_level_var_target,
+        |position, ask| {
+            let mut state_params = state_params.clone();
+            state_params.order_book.ask = ask;
+            calc_grid_close_long(exchange_params, &state_params, bot_params, position)
+        },
+        exchange_params,
+        state_params.order_book.ask,
+        LONG,
+    )
+}
+
+/// calc_var_target_closes_long for shorts, levels descending from the bid.
+pub fn calc_var_target_closes_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    volatility: f64,
+    close_level_var_target: f64,
+) -> Vec<Order> {
+    calc_var_target_closes(
+        position,
+        volatility,
+        close_level_var_target,
+        |position, bid| {
+            let mut state_params = state_params.clone();
+            state_params.order_book.bid = bid;
+            calc_grid_close_short(exchange_params, &state_params, bot_params, position)
+        },
+        exchange_params,
+        state_params.order_book.bid,
+        SHORT,
+    )
+}
+
+// walks the grid as closes fill, touch moving with the last level so none prices through it
+fn calc_var_target_closes(
+    position: &Position,
+    volatility: f64,
+    close_level_var_target: f64,
+    calc_grid_close: impl Fn(&Position, f64) -> Option<Order>,
+    exchange_params: &ExchangeParams,
+    mut touch: f64,
+    pside: usize,
+) -> Vec<Order> {
+    let mut closes = Vec::new();
+    let valid = |value: f64| value.is_finite() && value > 0.0;
+    if !valid(volatility) || !valid(close_level_var_target) {
+        return closes;
+    }
+    let sign = if pside == LONG { 1.0 } else { -1.0 };
+    let mut psize_abs = round_(position.size * sign, exchange_params.qty_step);
+    for _ in 0..500 {
+        if psize_abs <= 0.0 {
+            break;
+        }
+        let Some(close) = calc_grid_close(&position.resized(psize_abs * sign), touch) else {
+            break;
+        };
+        let min_qty = calc_min_entry_qty(close.price, exchange_params);
+        let mut close_qty = f64::max(
+            min_qty,
+            round_qty(
+                close_level_var_target / (volatility * close.price),
+                exchange_params,
+            ),
+        );
+        if psize_abs - close_qty < min_qty {
+            // don't leave a remainder too small to close
+            close_qty = psize_abs;
+        }
+        psize_abs = round_(psize_abs - close_qty, exchange_params.qty_step);
+        touch = close.price;
+        closes.push(Order {
+            qty: -close_qty * sign,
+            ..close
+        });
+    }
+    closes
+}
+
+/// Close ladder mirroring the entry grid: each entry level below pprice gets a close as far
+/// above it, for the entry's qty, so a symmetric grid exits on the spacing it entered on.
+/// Levels are clamped to the ask and the farthest takes whatever of the position the entries
+/// don't cover. Empty if there are no entries to mirror.
+pub fn calc_mirrored_closes_long(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    position: &Position,
+    entries: &[Order],
+) -> Vec<Order> {
+    calc_mirrored_closes(
+        exchange_params,
+        position,
+        entries,
+        state_params.order_book.ask,
+        LONG,
+    )
+}
+
+/// calc_mirrored_closes_long for shorts, entries above pprice mirrored below it.
+pub fn calc_mirrored_closes_short(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    position: &Position,
+    entries: &[Order],
+) -> Vec<Order> {
+    calc_mirrored_closes(
+        exchange_params,
+        position,
+        entries,
+        state_params.order_book.bid,
+        SHORT,
+    )
+}
+
+fn calc_mirrored_closes(
+    exchange_params: &ExchangeParams,
+    position: &Position,
+    entries: &[Order],
+    touch: f64,
+    pside: usize,
+) -> Vec<Order> {
+    let (sign, order_type) = if pside == LONG {
+        (1.0, OrderType::CloseGridLong)
+    } else {
+        (-1.0, OrderType::CloseGridShort)
+    };
+    let mut closes = Vec::<Order>::new();
+    let mut psize_abs = round_(position.size * sign, exchange_params.qty_step);
+    if psize_abs <= 0.0 || position.price <= 0.0 {
+        return closes;
+    }
+    // nearest entry first, so closes run outward from pprice
+    let mut entries: Vec<&Order> = entries.iter().filter(|e| e.qty * sign > 0.0).collect();
+    entries.sort_by(|a, b| (b.price * sign).total_cmp(&(a.price * sign)));
+    for entry in entries {
+        if psize_abs <= 0.0 {
+            break;
+        }
+        let mirrored = 2.0 * position.price - entry.price;
+        let price = if pside == LONG {
+            round_price_for_side(mirrored, false, exchange_params).max(touch)
+        } else {
+            round_price_for_side(mirrored, true, exchange_params).min(touch)
+        };
+        if price <= 0.0 {
+            break;
+        }
+        let min_qty = calc_min_entry_qty(price, exchange_params);
+        let mut qty = f64::max(min_qty, round_(entry.qty.abs(), exchange_params.qty_step));
+        if psize_abs - qty < min_qty {
+            // don't leave a remainder too small to close
+            qty = psize_abs;
+        }
+        psize_abs = round_(psize_abs - qty, exchange_params.qty_step);
+        match closes.last_mut() {
+            Some(previous) if previous.price == price => {
+                previous.qty = round_(previous.qty - qty * sign, exchange_params.qty_step);
+            }
+            _ => closes.push(Order {
+                price,
+                qty: -qty * sign,
+                order_type,
+                iceberg_qty: None,
+                min_fill_qty: 0.0,
+            }),
+        }
+    }
+    if let Some(last) = closes.last_mut() {
+        last.qty = round_(last.qty - psize_abs * sign, exchange_params.qty_step);
+    }
+    closes
+}
+
+/// Expiries interpolated linearly in distance between the nearest and farthest close; a
+/// max_expiry_candles below min_expiry_candles is raised to it.
+fn stagger_expiries(
+    closes: Vec<Order>,
+    min_expiry_candles: usize,
+    max_expiry_candles: usize,
+    distance: impl Fn(f64) -> f64,
+) -> Vec<StaggeredClose> {
+    let distances: Vec<f64> = closes.iter().map(|close| distance(close.price)).collect();
+    let nearest = distances.iter().copied().fold(f64::INFINITY, f64::min);
+    let farthest = distances.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let (min_expiry, max_expiry) = (
+        min_expiry_candles as f64,
+        max_expiry_candles.max(min_expiry_candles) as f64,
+    );
+    closes
+        .into_iter()
+        .zip(distances)
+        .map(|(order, distance)| StaggeredClose {
+            order,
+            expiry_candles: if farthest > nearest {
+                interpolate(distance, &[nearest, farthest], &[min_expiry, max_expiry]).round()
+                    as usize
+            } else {
+                min_expiry_candles
+            },
+        })
+        .collect()
+}
+
+/// calc_closes_long keyed by price, for lookups and range queries such as diffing against
+/// open orders. Closes on the same tick, e.g. a trailing leg and a grid rung, merge into one
+/// entry keeping the first close's order type.
+pub fn calc_closes_long_by_price(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+    blocked_prices: &[f64],
+) -> BTreeMap<Price, Order> {
+    closes_by_price(
+        calc_closes_long(
+            exchange_params,
+            state_params,
+            bot_params,
+            position,
+            trailing_price_bundle,
+            blocked_prices,
+        ),
+        exchange_params,
+    )
+}
+
+/// See calc_closes_long_by_price.
+pub fn calc_closes_short_by_price(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+    blocked_prices: &[f64],
+) -> BTreeMap<Price, Order> {
+    closes_by_price(
+        calc_closes_short(
+            exchange_params,
+            state_params,
+            bot_params,
+            position,
+            trailing_price_bundle,
+            blocked_prices,
+        ),
+        exchange_params,
+    )
+}
+
+/// Closes for every open pside position, as (symbol index, closes) sorted by symbol index,
+/// so the result doesn't depend on the positions map's iteration order. Lists are indexed
+/// by symbol index; symbols without a trailing bundle use the default one. A pside other
+/// than LONG or SHORT, or a position whose index is past the lists, is an error.
+pub fn calc_all_closes_ordered(
+    exchange_params_list: &[ExchangeParams],
+    state_params_list: &[StateParams],
+    bot_params_pair: &BotParamsPair,
+    positions: &Positions,
+    trailing_price_bundles: &HashMap<SymbolIdx, TrailingPriceBundle>,
+    pside: usize,
+) -> Result<Vec<(usize, Vec<Order>)>, String> {
+    let (positions, bot_params, calc_closes): (_, _, fn(_, _, _, _, _, _) -> Vec<Order>) =
+        match pside {
+            LONG => (&positions.long, &bot_params_pair.long, calc_closes_long),
+            SHORT => (&positions.short, &bot_params_pair.short, calc_closes_short),
+            _ => return Err(format!("unknown pside {}", pside)),
+        };
+    let mut idxs: Vec<SymbolIdx> = positions
+        .iter()
+        .filter(|(_, position)| position.size != 0.0)
+        .map(|(&idx, _)| idx)
+        .collect();
+    idxs.sort_unstable();
+    let default_bundle = TrailingPriceBundle::default();
+    idxs.into_iter()
+        .map(|idx| {
+            let i = idx as usize;
+            let (exchange_params, state_params) =
+                match (exchange_params_list.get(i), state_params_list.get(i)) {
+                    (Some(exchange_params), Some(state_params)) => (exchange_params, state_params),
+                    _ => {
+                        return Err(format!(
+                            "idx {} out of range for {} symbols",
+                            idx,
+                            exchange_params_list.len().min(state_params_list.len())
+                        ))
+                    }
+                };
+            let closes = calc_closes(
+                exchange_params,
+                state_params,
+                bot_params,
+                &positions[&idx],
+                trailing_price_bundles.get(&idx).unwrap_or(&default_bundle),
+                &[],
+            );
+            Ok((i, closes))
+        })
+        .collect()
+}
+
+fn closes_by_price(closes: Vec<Order>, exchange_params: &ExchangeParams) -> BTreeMap<Price, Order> {
+    let mut by_price = BTreeMap::<Price, Order>::new();
+    for close in closes {
+        let (price, qty) = match (
+            Price::from_f64(close.price, exchange_params.price_step),
+            Qty::from_f64(close.qty, exchange_params.qty_step),
+        ) {
+            (Some(price), Some(qty)) => (price, qty),
+            _ => continue,
+        };
+        by_price
+            .entry(price)
+            .and_modify(|merged| {
+                // merged in steps so float drift cannot dust the entry
+                let merged_qty = Qty::from_f64(merged.qty, exchange_params.qty_step)
+                    .map_or(qty, |merged_qty| merged_qty + qty);
+                merged.qty = merged_qty.to_f64();
+            })
+            .or_insert(close);
+    }
+    by_price
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OrderBook;
+
+    fn test_exchange_params() -> ExchangeParams {
+        ExchangeParams {
+            qty_step: 0.001,
+            price_step: 0.01,
+            min_qty: 0.001,
+            min_cost: 1.0,
+            c_mult: 1.0,
+            ..Default::default()
+        }
+    }
+
+    fn test_state_params(bid: f64, ask: f64) -> StateParams {
+        StateParams {
+            balance: 1000.0,
+            order_book: OrderBook::new(bid, ask),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn auto_reduce_sizes_back_to_limit() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(99.0, 101.0);
+        let bot_params = BotParams {
+            wallet_exposure_limit: 0.5,
+            enforce_exposure_limit: true,
+            auto_reduce_enabled: true,
+            auto_reduce_tolerance_pct: 0.05,
+            ..Default::default()
+        };
+        // exposure 0.8 against a 0.5 limit
+        let long = Position {
+            size: 8.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close =
+            calc_auto_reduce_close_long(&exchange_params, &state_params, &bot_params, &long)
+                .unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (-3.0, 101.0, OrderType::CloseAutoReduceLong)
+        );
+        let short = Position {
+            size: -8.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close =
+            calc_auto_reduce_close_short(&exchange_params, &state_params, &bot_params, &short)
+                .unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (3.0, 99.0, OrderType::CloseAutoReduceShort)
+        );
+
+        // 0.52 is within 5% of the limit
+        let within = Position {
+            size: 5.2,
+            price: 100.0,
+            ..Default::default()
+        };
+        assert!(
+            calc_auto_reduce_close_long(&exchange_params, &state_params, &bot_params, &within)
+                .is_none()
+        );
+        let strict = BotParams {
+            auto_reduce_tolerance_pct: 0.0,
+            ..bot_params.clone()
+        };
+        let close =
+            calc_auto_reduce_close_long(&exchange_params, &state_params, &strict, &within).unwrap();
+        assert_eq!(close.qty, -0.2);
+
+        let not_enforced = BotParams {
+            enforce_exposure_limit: false,
+            ..bot_params.clone()
+        };
+        assert!(
+            calc_auto_reduce_close_long(&exchange_params, &state_params, &not_enforced, &long)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn auto_reduce_legacy_path_without_flag() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(99.0, 101.0);
+        let bot_params = BotParams {
+            wallet_exposure_limit: 0.5,
+            enforce_exposure_limit: true,
+            auto_reduce_tolerance_pct: 0.05,
+            ..Default::default()
+        };
+        // back down to 1% over the limit: 0.505 of 1000 at 100
+        let long = Position {
+            size: 8.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close =
+            calc_auto_reduce_close_long(&exchange_params, &state_params, &bot_params, &long)
+                .unwrap();
+        assert!((close.qty + 2.95).abs() < 1e-9, "{:?}", close);
+
+        // tolerance is ignored; only past 1% over the limit reduces
+        let within = Position {
+            size: 5.06,
+            price: 100.0,
+            ..Default::default()
+        };
+        assert!(
+            calc_auto_reduce_close_long(&exchange_params, &state_params, &bot_params, &within)
+                .is_some()
+        );
+        let at_limit = Position {
+            size: 5.05,
+            price: 100.0,
+            ..Default::default()
+        };
+        assert!(calc_auto_reduce_close_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &at_limit
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn auto_reduce_leads_the_close_ladder() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(99.0, 101.0);
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.02,
+            close_grid_qty_pct: 0.25,
+            wallet_exposure_limit: 0.5,
+            enforce_exposure_limit: true,
+            auto_reduce_enabled: true,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 8.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = calc_closes_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &long,
+            &TrailingPriceBundle::default(),
+            &[],
+        );
+        assert_eq!(closes[0].order_type, OrderType::CloseAutoReduceLong);
+        assert_eq!(closes[0].qty, -3.0);
+        assert!(closes[1..]
+            .iter()
+            .all(|close| close.order_type == OrderType::CloseGridLong));
+    }
+
+    #[test]
+    fn blocked_prices_nudge_closes_off_natural_levels() {
+        let exchange_params = test_exchange_params();
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.01,
+            close_grid_qty_pct: 0.25,
+            wallet_exposure_limit: 1.0,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 9.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let state_params = test_state_params(99.0, 99.0);
+        let closes = |blocked_prices: &[f64]| {
+            calc_closes_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                &TrailingPriceBundle::default(),
+                blocked_prices,
+            )
+        };
+        let natural = closes(&[])[0].price;
+        let blocked = closes(&[natural, natural + 0.01]);
+        assert!(blocked
+            .iter()
+            .all(|close| (close.price - natural).abs() > 1e-9
+                && (close.price - natural - 0.01).abs() > 1e-9));
+        assert!((blocked[0].price - (natural + 0.02)).abs() < 1e-9);
+        assert!((blocked.iter().map(|close| close.qty).sum::<f64>() + 9.0).abs() < 1e-9);
+
+        let short = Position {
+            size: -9.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let state_params = test_state_params(101.0, 101.0);
+        let closes = |blocked_prices: &[f64]| {
+            calc_closes_short(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &short,
+                &TrailingPriceBundle::default(),
+                blocked_prices,
+            )
+        };
+        let natural = closes(&[])[0].price;
+        let blocked = closes(&[natural]);
+        assert!((blocked[0].price - (natural - 0.01)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn blocked_prices_down_to_the_floor_drop_the_level() {
+        let price = |price: f64| Price::from_f64(price, 0.01).unwrap();
+        let blocked_prices = [0.03, 0.02, 0.01];
+        assert_eq!(
+            nudge_off_blocked_prices(price(0.05), &blocked_prices, -1),
+            Some(price(0.05))
+        );
+        assert_eq!(
+            nudge_off_blocked_prices(price(0.03), &blocked_prices, -1),
+            None
+        );
+        assert_eq!(
+            nudge_off_blocked_prices(price(0.03), &blocked_prices, 1),
+            Some(price(0.04))
+        );
+    }
+
+    #[test]
+    fn price_band_clamps_far_closes_and_keeps_their_qty() {
+        let exchange_params = ExchangeParams {
+            price_band_pct: 0.05,
+            ..test_exchange_params()
+        };
+        let close = |qty: f64, price: f64| Order {
+            qty,
+            price,
+            order_type: OrderType::CloseGridLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        };
+        let closes = vec![
+            close(-1.0, 101.0),
+            close(-1.0, 103.0),
+            close(-1.0, 106.0),
+            close(-1.5, 110.0),
+        ];
+        let banded = apply_price_band(closes, &exchange_params, 100.0);
+        let prices_qtys: Vec<(f64, f64)> = banded.iter().map(|c| (c.price, c.qty)).collect();
+        assert_eq!(prices_qtys, [(101.0, -1.0), (103.0, -1.0), (105.0, -2.5)]);
+
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.3,
+            close_grid_min_markup: 0.01,
+            close_grid_qty_pct: 0.2,
+            wallet_exposure_limit: 1.0,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 9.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let unbanded = calc_closes_long(
+            &test_exchange_params(),
+            &test_state_params(99.0, 99.0),
+            &bot_params,
+            &long,
+            &TrailingPriceBundle::default(),
+            &[],
+        );
+        assert!(unbanded.iter().any(|close| close.price > 105.0));
+        let closes = calc_closes_long(
+            &exchange_params,
+            &test_state_params(100.0, 100.0),
+            &bot_params,
+            &long,
+            &TrailingPriceBundle::default(),
+            &[],
+        );
+        assert!(closes.iter().all(|close| close.price <= 105.0));
+        assert!((closes.iter().map(|close| close.qty).sum::<f64>() + 9.0).abs() < 1e-9);
+
+        let short = Position {
+            size: -9.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = calc_closes_short(
+            &exchange_params,
+            &test_state_params(100.0, 100.0),
+            &bot_params,
+            &short,
+            &TrailingPriceBundle::default(),
+            &[],
+        );
+        assert!(closes.iter().all(|close| close.price >= 95.0));
+        assert!((closes.iter().map(|close| close.qty).sum::<f64>() - 9.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn price_band_narrower_than_a_tick_drops_every_close() {
+        let exchange_params = ExchangeParams {
+            price_band_pct: 0.00001,
+            ..test_exchange_params()
+        };
+        let closes = vec![Order {
+            qty: -1.0,
+            price: 100.01,
+            order_type: OrderType::CloseGridLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        }];
+        // the band around 100.005 holds no whole tick
+        assert!(apply_price_band(closes.clone(), &exchange_params, 100.005).is_empty());
+        assert_eq!(apply_price_band(closes, &exchange_params, 100.0).len(), 1);
+    }
+
+    #[test]
+    fn margin_target_closes_part_of_the_position() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(99.0, 101.0);
+        let long = Position {
+            size: 10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        // ratio 80 / 100 against a 0.5 target: 1 - 0.5 * 100 / 80 of the position goes
+        let close =
+            calc_margin_target_close_long(&exchange_params, &state_params, &long, 100.0, 80.0, 0.5)
+                .unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (-3.75, 101.0, OrderType::CloseMarginTargetLong)
+        );
+        let maintenance_after = 80.0 * (long.size + close.qty) / long.size;
+        assert!(maintenance_after / 100.0 <= 0.5 + 1e-12);
+
+        let short = Position {
+            size: -10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close = calc_margin_target_close_short(
+            &exchange_params,
+            &state_params,
+            &short,
+            100.0,
+            80.0,
+            0.5,
+        )
+        .unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (3.75, 99.0, OrderType::CloseMarginTargetShort)
+        );
+
+        // already within target, or the other side's position
+        for (used_margin, maintenance_margin, position) in
+            [(100.0, 40.0, &long), (100.0, 80.0, &short)]
+        {
+            assert!(calc_margin_target_close_long(
+                &exchange_params,
+                &state_params,
+                position,
+                used_margin,
+                maintenance_margin,
+                0.5
+            )
+            .is_none());
+        }
+        // a remainder under min qty is closed along with the rest
+        let close = calc_margin_target_close_long(
+            &exchange_params,
+            &state_params,
+            &long,
+            100.0,
+            80.0,
+            0.0001,
+        )
+        .unwrap();
+        assert_eq!(close.qty, -10.0);
+    }
+
+    #[test]
+    fn funding_window_trims_the_paying_side_near_settlement() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(99.0, 101.0);
+        let bot_params = BotParams {
+            close_before_funding: true,
+            close_before_funding_minutes: 10.0,
+            close_before_funding_pct: 0.5,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position {
+            size: -10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close_long = |minutes_to_funding: f64, funding_rate: f64| {
+            calc_funding_window_close_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                minutes_to_funding,
+                funding_rate,
+            )
+        };
+        let close_short = |minutes_to_funding: f64, funding_rate: f64| {
+            calc_funding_window_close_short(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &short,
+                minutes_to_funding,
+                funding_rate,
+            )
+        };
+
+        // near the window, the side paying funding trims
+        let close = close_long(5.0, 0.0001).unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (-5.0, 101.0, OrderType::CloseFundingWindowLong)
+        );
+        let close = close_short(5.0, -0.0001).unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (5.0, 99.0, OrderType::CloseFundingWindowShort)
+        );
+        assert!(close_long(0.0, 0.0001).is_some() && close_long(10.0, 0.0001).is_some());
+
+        // the side receiving funding, or no rate, keeps its position
+        assert!(close_long(5.0, -0.0001).is_none());
+        assert!(close_short(5.0, 0.0001).is_none());
+        assert!(close_long(5.0, 0.0).is_none() && close_long(5.0, f64::NAN).is_none());
+        assert!(close_short(5.0, f64::NAN).is_none());
+
+        // far from the window, or once funding has settled
+        for minutes_to_funding in [10.5, 240.0, -1.0] {
+            assert!(close_long(minutes_to_funding, 0.0001).is_none());
+            assert!(close_short(minutes_to_funding, -0.0001).is_none());
+        }
+
+        let disabled = BotParams {
+            close_before_funding: false,
+            ..bot_params.clone()
+        };
+        assert!(calc_funding_window_close_long(
+            &exchange_params,
+            &state_params,
+            &disabled,
+            &long,
+            5.0,
+            0.0001
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn daily_pnl_target_slows_closes_once_met() {
+        let exchange_params = test_exchange_params();
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.0,
+            close_grid_min_markup: 0.01,
+            close_grid_qty_pct: 0.5,
+            wallet_exposure_limit: 1.0,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let state_params = test_state_params(99.0, 99.0);
+        let grid =
+            calc_grid_close_long(&exchange_params, &state_params, &bot_params, &long).unwrap();
+        // 1.0 quote pnl per qty closed at 101
+        assert_eq!(grid.price, 101.0);
+        let close_qty = |pnl_realized_today: f64, daily_pnl_target: f64| {
+            calc_daily_pnl_target_close_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                pnl_realized_today,
+                daily_pnl_target,
+            )
+            .unwrap()
+            .qty
+        };
+        // partially met: 3.0 of a 10.0 target is left
+        assert_eq!(close_qty(7.0, 10.0), -3.0);
+        // barely started: the grid qty caps the close
+        assert_eq!(close_qty(0.0, 1000.0), grid.qty);
+        // fully met, or overshot: down to the min qty
+        assert_eq!(close_qty(10.0, 10.0), -0.01);
+        assert_eq!(close_qty(12.0, 10.0), -0.01);
+        // disabled
+        assert_eq!(close_qty(12.0, 0.0), grid.qty);
+
+        let short = Position {
+            size: -10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let state_params = test_state_params(101.0, 101.0);
+        let close_short = |pnl_realized_today: f64| {
+            calc_daily_pnl_target_close_short(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &short,
+                pnl_realized_today,
+                10.0,
+            )
+            .unwrap()
+        };
+        let close = close_short(7.0);
+        assert_eq!((close.qty, close.price), (3.0, 99.0));
+        assert_eq!(close.order_type, OrderType::CloseGridShort);
+        assert_eq!(close_short(10.0).qty, 0.011);
+    }
+
+    #[test]
+    fn kelly_fraction_sizes_grid_closes() {
+        // (win_prob, win_loss_ratio, fraction)
+        let table = [
+            (0.6, 1.0, 0.2),
+            (0.5, 2.0, 0.25),
+            (0.7, 3.0, 0.6),
+            (0.55, 0.5, 0.0), // negative edge clamps to 0
+            (0.4, 1.0, 0.0),
+            (0.5, 1.0, 0.0), // no edge
+            (1.0, 1.0, 1.0),
+            (1.5, 2.0, 1.0), // win_prob clamps to 1
+            (0.9, 0.0, 0.0), // no payoff
+            (f64::NAN, 2.0, 0.0),
+        ];
+        for (win_prob, win_loss_ratio, fraction) in table {
+            let kelly = calc_kelly_fraction(win_prob, win_loss_ratio);
+            assert!(
+                (kelly - fraction).abs() < 1e-12,
+                "{} {} -> {}",
+                win_prob,
+                win_loss_ratio,
+                kelly
+            );
+        }
+
+        let exchange_params = test_exchange_params();
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.01,
+            close_grid_qty_pct: 0.5,
+            wallet_exposure_limit: 1.0,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position {
+            size: -10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close_qtys = |win_prob: f64, win_loss_ratio: f64| {
+            let long_close = calc_kelly_close_long(
+                &exchange_params,
+                &test_state_params(99.0, 99.0),
+                &bot_params,
+                &long,
+                win_prob,
+                win_loss_ratio,
+            )
+            .unwrap();
+            let short_close = calc_kelly_close_short(
+                &exchange_params,
+                &test_state_params(101.0, 101.0),
+                &bot_params,
+                &short,
+                win_prob,
+                win_loss_ratio,
+            )
+            .unwrap();
+            (long_close.qty, short_close.qty)
+        };
+        // each level closes the fraction of the full position, not close_grid_qty_pct
+        assert_eq!(close_qtys(0.5, 2.0), (-2.5, 2.5));
+        assert_eq!(close_qtys(0.7, 3.0), (-6.0, 6.0));
+        assert_eq!(close_qtys(1.0, 1.0), (-10.0, 10.0));
+        // without an edge, the least the grid closes
+        let no_edge = BotParams {
+            close_grid_qty_pct: 0.0,
+            ..bot_params.clone()
+        };
+        let least = calc_grid_close_long(
+            &exchange_params,
+            &test_state_params(99.0, 99.0),
+            &no_edge,
+            &long,
+        )
+        .unwrap();
+        assert!(least.qty > -1.0);
+        assert_eq!(close_qtys(0.4, 1.0), (least.qty, -least.qty));
+    }
+
+    #[test]
+    fn bracket_closes_cluster_around_the_target() {
+        let exchange_params = test_exchange_params();
+        let bot_params = BotParams {
+            close_grid_qty_pct: 0.25,
+            ..Default::default()
+        };
+        let (target_price, bracket_pct) = (100.0, 0.03);
+        let within_bracket = |closes: &[Order]| {
+            closes.iter().all(|close| {
+                (target_price * (1.0 - bracket_pct)..=target_price * (1.0 + bracket_pct))
+                    .contains(&close.price)
+            })
+        };
+        let total_qty = |closes: &[Order]| closes.iter().map(|close| close.qty).sum::<f64>();
+
+        let long = Position {
+            size: 10.001,
+            price: 90.0,
+            ..Default::default()
+        };
+        let closes = calc_bracket_close_long(
+            &exchange_params,
+            &test_state_params(95.0, 95.0),
+            &bot_params,
+            &long,
+            target_price,
+            bracket_pct,
+        );
+        let levels: Vec<(f64, f64)> = closes.iter().map(|c| (c.price, c.qty)).collect();
+        assert_eq!(
+            levels,
+            [(97.0, -2.5), (99.0, -2.5), (101.0, -2.5), (103.0, -2.501)]
+        );
+        assert!(within_bracket(&closes));
+        assert!((total_qty(&closes) + 10.001).abs() < 1e-9);
+
+        let short = Position {
+            size: -10.0,
+            price: 110.0,
+            ..Default::default()
+        };
+        let closes = calc_bracket_close_short(
+            &exchange_params,
+            &test_state_params(105.0, 105.0),
+            &bot_params,
+            &short,
+            target_price,
+            bracket_pct,
+        );
+        let levels: Vec<(f64, f64)> = closes.iter().map(|c| (c.price, c.qty)).collect();
+        assert_eq!(
+            levels,
+            [(103.0, 2.5), (101.0, 2.5), (99.0, 2.5), (97.0, 2.5)]
+        );
+        assert!(closes
+            .iter()
+            .all(|close| close.order_type == OrderType::CloseGridShort));
+        assert!(within_bracket(&closes));
+        assert!((total_qty(&closes) - 10.0).abs() < 1e-9);
+
+        // levels the book has passed merge at the book, still within the bracket
+        let closes = calc_bracket_close_long(
+            &exchange_params,
+            &test_state_params(100.0, 100.0),
+            &bot_params,
+            &long,
+            target_price,
+            bracket_pct,
+        );
+        let levels: Vec<(f64, f64)> = closes.iter().map(|c| (c.price, c.qty)).collect();
+        assert_eq!(levels, [(100.0, -5.0), (101.0, -2.5), (103.0, -2.501)]);
+        assert!(within_bracket(&closes));
+        assert!((total_qty(&closes) + 10.001).abs() < 1e-9);
+    }
+
+    #[test]
+    fn trailing_legs_fire_on_their_own_thresholds() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(103.99, 104.0);
+        let bot_params = BotParams {
+            close_trailing_fast_threshold_pct: 0.01,
+            close_trailing_fast_retracement_pct: 0.005,
+            close_trailing_fast_qty_pct: 0.25,
+            close_trailing_slow_threshold_pct: 0.05,
+            close_trailing_slow_retracement_pct: 0.015,
+            close_trailing_slow_qty_pct: 1.0,
+            wallet_exposure_limit: 1.0,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 10.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let legs = |max_since_open: f64, min_since_max: f64| {
+            calc_trailing_legs_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                &TrailingPriceBundle {
+                    max_since_open,
+                    min_since_max,
+                    ..Default::default()
+                },
+            )
+            .iter()
+            .map(|close| (close.qty, close.order_type))
+            .collect::<Vec<_>>()
+        };
+        // 104.5 clears the fast threshold only, and 103.9 retraces it
+        assert_eq!(
+            legs(104.5, 103.9),
+            [(-2.5, OrderType::CloseTrailingFastLong)]
+        );
+        // 106.0 clears both; 104.5 retraces the fast leg but not the slow one
+        assert_eq!(
+            legs(106.0, 104.5),
+            [(-2.5, OrderType::CloseTrailingFastLong)]
+        );
+        // the slow leg closes no more than the fast leg leaves
+        assert_eq!(
+            legs(106.0, 104.0),
+            [
+                (-2.5, OrderType::CloseTrailingFastLong),
+                (-7.5, OrderType::CloseTrailingSlowLong)
+            ]
+        );
+        let fast_only = BotParams {
+            close_trailing_slow_qty_pct: 0.0,
+            ..bot_params.clone()
+        };
+        assert_eq!(
+            calc_trailing_legs_long(
+                &exchange_params,
+                &state_params,
+                &fast_only,
+                &long,
+                &TrailingPriceBundle {
+                    max_since_open: 106.0,
+                    min_since_max: 104.0,
+                    ..Default::default()
+                },
+            )
+            .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn trailing_close_fires_once_stagnant_past_the_peak() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(100.99, 101.0);
+        let bot_params = BotParams {
+            close_trailing_threshold_pct: 0.01,
+            close_trailing_retracement_pct: 0.02,
+            close_trailing_qty_pct: 0.5,
+            close_trailing_max_candles_since_peak: 10,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 5.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position {
+            size: -5.0,
+            price: 102.0,
+            ..Default::default()
+        };
+        // past the threshold, but not retraced by close_trailing_retracement_pct
+        let long_bundle = |candles_since_max: usize| TrailingPriceBundle {
+            max_since_open: 102.0,
+            min_since_max: 101.5,
+            candles_since_max,
+            ..Default::default()
+        };
+        let short_bundle = |candles_since_min: usize| TrailingPriceBundle {
+            min_since_open: 100.0,
+            max_since_min: 100.5,
+            candles_since_min,
+            ..Default::default()
+        };
+        let close_long = |bot_params: &BotParams, candles: usize| {
+            calc_trailing_close_long(
+                &exchange_params,
+                &state_params,
+                bot_params,
+                &long,
+                &long_bundle(candles),
+            )
+        };
+        let close_short = |bot_params: &BotParams, candles: usize| {
+            calc_trailing_close_short(
+                &exchange_params,
+                &state_params,
+                bot_params,
+                &short,
+                &short_bundle(candles),
+            )
+        };
+
+        assert!(matches!(
+            close_long(&bot_params, 9),
+            NextOrder::TrailingPending
+        ));
+        assert!(matches!(
+            close_short(&bot_params, 9),
+            NextOrder::TrailingPending
+        ));
+        let close = close_long(&bot_params, 10).order().unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (-2.5, 101.0, OrderType::CloseTrailingLong)
+        );
+        let close = close_short(&bot_params, 10).order().unwrap();
+        assert_eq!(
+            (close.qty, close.price, close.order_type),
+            (2.55, 100.99, OrderType::CloseTrailingShort)
+        );
+
+        // 0 disables it, however long price stalls
+        let disabled = BotParams {
+            close_trailing_max_candles_since_peak: 0,
+            ..bot_params.clone()
+        };
+        assert!(matches!(
+            close_long(&disabled, 10_000),
+            NextOrder::TrailingPending
+        ));
+        assert!(matches!(
+            close_short(&disabled, 10_000),
+            NextOrder::TrailingPending
+        ));
+    }
+
+    #[test]
+    fn ladder_notional_sums_both_sides() {
+        let close = |qty: f64, price: f64, order_type: OrderType| Order {
+            qty,
+            price,
+            order_type,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        };
+        let ladder = [
+            close(-1.0, 101.0, OrderType::CloseGridLong),
+            close(-0.5, 102.0, OrderType::CloseGridLong),
+            close(0.0, 103.0, OrderType::CloseGridLong),
+            close(2.0, 99.0, OrderType::CloseGridShort),
+            close(0.25, 98.0, OrderType::CloseTrailingShort),
+        ];
+        // 101 + 51 + 0 + 198 + 24.5
+        assert_eq!(calc_ladder_notional(&ladder, 1.0), 374.5);
+        assert_eq!(calc_ladder_notional(&ladder, 0.1), 37.45);
+        assert_eq!(calc_ladder_notional(&[], 1.0), 0.0);
+    }
+
+    #[test]
+    fn closes_by_price_have_one_entry_per_price() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(100.0, 100.01);
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.005,
+            close_grid_qty_pct: 0.2,
+            close_trailing_retracement_pct: 0.01,
+            close_trailing_grid_ratio: 0.5,
+            close_trailing_qty_pct: 0.3,
+            close_trailing_threshold_pct: 0.01,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 4.0,
+            price: 98.0,
+            ..Default::default()
+        };
+        let retraced = TrailingPriceBundle {
+            max_since_open: 102.0,
+            min_since_max: 100.5,
+            ..Default::default()
+        };
+        let closes = calc_closes_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &long,
+            &retraced,
+            &[],
+        );
+        let by_price = calc_closes_long_by_price(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &long,
+            &retraced,
+            &[],
+        );
+        let prices: Vec<f64> = by_price.values().map(|close| close.price).collect();
+        assert_eq!(prices, [100.01, 100.29]);
+        assert_eq!(
+            by_price.values().map(|close| close.qty).sum::<f64>(),
+            closes.iter().map(|close| close.qty).sum::<f64>()
+        );
+
+        // a trailing leg and a grid rung on one tick, float noise apart, merge into one
+        // entry under the first's order type
+        let close = |qty: f64, price: f64, order_type: OrderType| Order {
+            qty,
+            price,
+            order_type,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        };
+        let by_price = closes_by_price(
+            vec![
+                close(-0.1, 100.01, OrderType::CloseTrailingLong),
+                close(-0.2, 100.01 + 1e-12, OrderType::CloseGridLong),
+                close(-0.3, 100.29, OrderType::CloseGridLong),
+            ],
+            &exchange_params,
+        );
+        let entries: Vec<_> = by_price
+            .iter()
+            .map(|(price, close)| (price.ticks(), close.qty, close.order_type))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (10001, -0.3, OrderType::CloseTrailingLong),
+                (10029, -0.3, OrderType::CloseGridLong),
+            ]
+        );
+    }
+
+    #[test]
+    fn all_closes_come_sorted_by_symbol() {
+        let n_symbols = 16;
+        let exchange_params_list = vec![test_exchange_params(); n_symbols];
+        let state_params_list = vec![test_state_params(100.0, 100.01); n_symbols];
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.005,
+            close_grid_qty_pct: 0.2,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        };
+        let bot_params_pair = BotParamsPair {
+            long: bot_params.clone(),
+            short: bot_params,
+            ..Default::default()
+        };
+        // symbol 5 is flat, so has no entry
+        let position = |idx: SymbolIdx| Position {
+            size: if idx == 5 { 0.0 } else { 1.0 + idx as f64 },
+            price: 100.0,
+            ..Default::default()
+        };
+        let calc = |idxs: &[SymbolIdx]| {
+            let mut positions = Positions::default();
+            for &idx in idxs {
+                positions.long.insert(idx, position(idx));
+            }
+            calc_all_closes_ordered(
+                &exchange_params_list,
+                &state_params_list,
+                &bot_params_pair,
+                &positions,
+                &HashMap::new(),
+                LONG,
+            )
+            .unwrap()
+        };
+        let ascending: Vec<SymbolIdx> = (0..n_symbols as SymbolIdx).collect();
+        let descending: Vec<SymbolIdx> = ascending.iter().rev().copied().collect();
+        let closes = calc(&ascending);
+        let idxs: Vec<usize> = closes.iter().map(|(idx, _)| *idx).collect();
+        let expected_idxs: Vec<usize> = (0..n_symbols).filter(|&idx| idx != 5).collect();
+        assert_eq!(idxs, expected_idxs);
+        for (idx, symbol_closes) in &closes {
+            let qty: f64 = symbol_closes.iter().map(|close| -close.qty).sum();
+            assert!((qty - position(*idx as SymbolIdx).size).abs() < 1e-9);
+        }
+        // each map hashes with its own seed, so iterates in its own order
+        for _ in 0..8 {
+            let rerun = calc(&descending);
+            assert_eq!(rerun.len(), closes.len());
+            for ((idx, symbol_closes), (rerun_idx, rerun_closes)) in closes.iter().zip(&rerun) {
+                assert_eq!(idx, rerun_idx);
+                let key = |close: &Order| OrderKey::new(close, &exchange_params_list[*idx]);
+                assert!(symbol_closes
+                    .iter()
+                    .map(key)
+                    .eq(rerun_closes.iter().map(key)));
+            }
+        }
+    }
+
+    /// Closes with no feature added since the baseline enabled, whose ladders
+    /// golden_closes_* pin to the baseline's output for the same inputs.
+    #[test]
+    fn closes_are_sized_against_the_allocated_balance() {
+        let exchange_params = test_exchange_params();
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let retraced = TrailingPriceBundle {
+            max_since_open: 102.0,
+            min_since_max: 100.5,
+            ..Default::default()
+        };
+        let closes = |balance: f64, balance_allocation_pct: f64| {
+            calc_closes_long(
+                &exchange_params,
+                &StateParams {
+                    balance,
+                    ..test_state_params(100.0, 100.01)
+                },
+                &BotParams {
+                    balance_allocation_pct,
+                    ..golden_bot_params(0.5)
+                },
+                &position,
+                &retraced,
+                &[],
+            )
+            .iter()
+            .map(|close| (close.qty, close.price, close.order_type))
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(closes(2000.0, 0.5), closes(1000.0, 0.0));
+        assert_ne!(closes(2000.0, 0.5), closes(2000.0, 0.0));
+        // outside (0, 1) is the whole balance
+        assert_eq!(closes(2000.0, 1.5), closes(2000.0, 0.0));
+        assert_eq!(closes(2000.0, -0.5), closes(2000.0, 0.0));
+    }
+
+    #[test]
+    fn geometric_close_grids_back_load_the_levels() {
+        let share = |ratio: f64, closed_share: f64| {
+            calc_geometric_close_qty_pct(0.25, ratio, closed_share) * 15.0
+        };
+        // four levels, each twice the last: 1, 2, 4 and 8 fifteenths, or halving from 8
+        for (closed_share, expected) in [(0.0, 1.0), (1.0 / 15.0, 2.0), (0.2, 4.0), (0.5, 8.0)] {
+            assert!((share(2.0, closed_share) - expected).abs() < 1e-9);
+        }
+        for (closed_share, expected) in [(0.0, 8.0), (0.6, 4.0), (0.8, 2.0), (0.95, 1.0)] {
+            assert!((share(0.5, closed_share) - expected).abs() < 1e-9);
+        }
+        assert_eq!(calc_geometric_close_qty_pct(0.25, 1.0, 0.5), 0.25);
+        assert_eq!(calc_geometric_close_qty_pct(0.25, 0.0, 0.5), 0.25);
+
+        let closes = calc_closes_long(
+            &test_exchange_params(),
+            &test_state_params(100.0, 100.01),
+            &BotParams {
+                close_grid_qty_ratio: 2.0,
+                close_grid_qty_pct: 0.25,
+                ..golden_bot_params(0.0)
+            },
+            &Position {
+                size: 5.0,
+                price: 100.0,
+                ..Default::default()
+            },
+            &TrailingPriceBundle::default(),
+            &[],
+        );
+        let qtys: Vec<f64> = closes.iter().map(|close| -close.qty).collect();
+        assert!(qtys.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", qtys);
+        assert!(qtys[0] < qtys[qtys.len() - 1]);
+        assert!((qtys.iter().sum::<f64>() - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn upper_grid_closes_wait_for_volume() {
+        let exchange_params = test_exchange_params();
+        let bot_params = BotParams {
+            close_require_volume: true,
+            min_close_volume: 500.0,
+            ..golden_bot_params(0.0)
+        };
+        let grid_close = |size: f64, volume: f64| {
+            calc_grid_close_long(
+                &exchange_params,
+                &StateParams {
+                    volume,
+                    ..test_state_params(100.0, 100.01)
+                },
+                &bot_params,
+                &Position {
+                    size,
+                    price: 100.0,
+                    ..Default::default()
+                },
+            )
+        };
+        // 0.2 of the exposure limit closes in the upper half of the range
+        assert!(grid_close(1.0, 500.0).is_none());
+        assert!(grid_close(1.0, 500.1).is_some());
+        // from half the limit on, closes go out whatever the volume
+        assert!(grid_close(2.5, 0.0).is_some());
+        let unconfirmed = BotParams {
+            close_require_volume: false,
+            ..bot_params.clone()
+        };
+        assert!(calc_grid_close_long(
+            &exchange_params,
+            &test_state_params(100.0, 100.01),
+            &unconfirmed,
+            &Position {
+                size: 1.0,
+                price: 100.0,
+                ..Default::default()
+            },
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn close_levels_are_capped_by_average_volume() {
+        let exchange_params = test_exchange_params();
+        let state_params = StateParams {
+            avg_volume: 10000.0,
+            ..test_state_params(100.0, 100.01)
+        };
+        let bot_params = BotParams {
+            close_max_qty_pct_of_volume: 0.01,
+            ..golden_bot_params(0.0)
+        };
+        // 1% of 10000.0 quote is 1.0 at 100.0, 0.99 at 101.0
+        let cap =
+            |price: f64| calc_close_qty_cap(&exchange_params, &state_params, &bot_params, price);
+        assert_eq!(cap(100.0), Some(1.0));
+        assert_eq!(cap(101.0), Some(0.99));
+        assert_eq!(
+            calc_close_qty_cap(
+                &exchange_params,
+                &test_state_params(100.0, 100.01),
+                &bot_params,
+                100.0
+            ),
+            None
+        );
+
+        let closes = calc_closes_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &Position {
+                size: 4.0,
+                price: 100.0,
+                ..Default::default()
+            },
+            &TrailingPriceBundle::default(),
+            &[],
+        );
+        // uncapped, the levels would close 1.0 each; the cut spills over to a fifth level
+        assert_ladder(
+            closes,
+            &[
+                (-0.991, 100.9, OrderType::CloseGridLong),
+                (-0.987, 101.3, OrderType::CloseGridLong),
+                (-0.983, 101.7, OrderType::CloseGridLong),
+                (-0.979, 102.09, OrderType::CloseGridLong),
+                (-0.06, 102.48, OrderType::CloseGridLong),
+            ],
+        );
+    }
+
+    #[test]
+    fn fallback_pairs_the_grid_close_with_a_market_order() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(100.0, 100.01);
+        let bot_params = golden_bot_params(0.0);
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close =
+            calc_close_with_fallback_long(&exchange_params, &state_params, &bot_params, &long, 30)
+                .unwrap();
+        let grid_close =
+            calc_grid_close_long(&exchange_params, &state_params, &bot_params, &long).unwrap();
+        assert_eq!(
+            (close.limit.qty, close.limit.price, close.limit.order_type),
+            (grid_close.qty, grid_close.price, grid_close.order_type)
+        );
+        // the same qty at the bid, for closing the rest of the way at market
+        assert_eq!(
+            (
+                close.fallback.qty,
+                close.fallback.price,
+                close.fallback.order_type
+            ),
+            (grid_close.qty, 100.0, OrderType::CloseFallbackMarketLong)
+        );
+        assert_eq!(close.fallback_candles, 30);
+
+        let short = Position {
+            size: -4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let close = calc_close_with_fallback_short(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &short,
+            30,
+        )
+        .unwrap();
+        assert!(close.limit.qty > 0.0);
+        assert_eq!(
+            (
+                close.fallback.qty,
+                close.fallback.price,
+                close.fallback.order_type
+            ),
+            (close.limit.qty, 100.01, OrderType::CloseFallbackMarketShort)
+        );
+
+        let flat = Position::default();
+        assert!(calc_close_with_fallback_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &flat,
+            30
+        )
+        .is_none());
+    }
+
+    fn golden_bot_params(close_trailing_grid_ratio: f64) -> BotParams {
+        BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.005,
+            close_grid_qty_pct: 0.2,
+            close_trailing_retracement_pct: 0.01,
+            close_trailing_grid_ratio,
+            close_trailing_qty_pct: 0.3,
+            close_trailing_threshold_pct: 0.01,
+            enforce_exposure_limit: true,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        }
+    }
+
+    fn assert_ladder(orders: Vec<Order>, expected: &[(f64, f64, OrderType)]) {
+        let orders: Vec<_> = orders
+            .iter()
+            .map(|order| (order.qty, order.price, order.order_type))
+            .collect();
+        assert_eq!(orders, expected);
+    }
+
+    #[test]
+    fn golden_closes_long() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        // peaked at 102 and retraced past close_trailing_retracement_pct
+        let retraced = TrailingPriceBundle {
+            max_since_open: 102.0,
+            min_since_max: 100.5,
+            ..Default::default()
+        };
+        let closes = |ratio: f64, trailing_price_bundle: &TrailingPriceBundle| {
+            calc_closes_long(
+                &exchange_params,
+                &state_params,
+                &golden_bot_params(ratio),
+                &position,
+                trailing_price_bundle,
+                &[],
+            )
+        };
+        assert_ladder(
+            closes(0.0, &TrailingPriceBundle::default()),
+            &[
+                (-1.0, 100.9, OrderType::CloseGridLong),
+                (-1.0, 101.3, OrderType::CloseGridLong),
+                (-1.0, 101.7, OrderType::CloseGridLong),
+                (-1.0, 102.1, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(
+            closes(0.5, &retraced),
+            &[
+                (-1.0, 101.9, OrderType::CloseGridLong),
+                (-0.5, 102.3, OrderType::CloseGridLong),
+                (-0.05, 102.48, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(
+            closes(-0.5, &retraced),
+            &[(-1.5, 100.01, OrderType::CloseTrailingLong)],
+        );
+    }
+
+    #[test]
+    fn golden_closes_short() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let position = Position {
+            size: -4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        // bottomed at 98 and retraced past close_trailing_retracement_pct
+        let retraced = TrailingPriceBundle {
+            min_since_open: 98.0,
+            max_since_min: 99.0,
+            ..Default::default()
+        };
+        let closes = |ratio: f64, trailing_price_bundle: &TrailingPriceBundle| {
+            calc_closes_short(
+                &exchange_params,
+                &state_params,
+                &golden_bot_params(ratio),
+                &position,
+                trailing_price_bundle,
+                &[],
+            )
+        };
+        assert_ladder(
+            closes(0.0, &TrailingPriceBundle::default()),
+            &[
+                (1.0, 99.1, OrderType::CloseGridShort),
+                (1.0, 98.7, OrderType::CloseGridShort),
+                (1.0, 98.3, OrderType::CloseGridShort),
+                (1.0, 97.89, OrderType::CloseGridShort),
+            ],
+        );
+        assert_ladder(
+            closes(0.5, &retraced),
+            &[
+                (1.0, 98.1, OrderType::CloseGridShort),
+                (0.5, 97.7, OrderType::CloseGridShort),
+                (0.05, 97.52, OrderType::CloseGridShort),
+            ],
+        );
+        assert_ladder(
+            closes(-0.5, &retraced),
+            &[(1.5, 100.0, OrderType::CloseTrailingShort)],
+        );
+    }
+
+    #[test]
+    fn next_closes_split_exposure_alike_on_both_sides() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let long_bundle = TrailingPriceBundle {
+            max_since_open: 102.0,
+            min_since_max: 100.5,
+            ..Default::default()
+        };
+        let short_bundle = TrailingPriceBundle {
+            min_since_open: 98.0,
+            max_since_min: 99.0,
+            ..Default::default()
+        };
+        let next = |order: NextOrder| match order {
+            NextOrder::Order(order) => (
+                order.qty.abs(),
+                matches!(
+                    order.order_type,
+                    OrderType::CloseTrailingLong | OrderType::CloseTrailingShort
+                ),
+            ),
+            _ => panic!("expected a close"),
+        };
+        // 1.0 and 4.0 sit either side of half the 0.5 exposure limit on a 1000 balance
+        for ratio in [0.0, 0.5, -0.5, 1.0] {
+            for size in [1.0, 4.0] {
+                let bot_params = golden_bot_params(ratio);
+                let long = Position {
+                    size,
+                    price: 100.0,
+                    ..Default::default()
+                };
+                let short = Position {
+                    size: -size,
+                    ..long
+                };
+                assert_eq!(
+                    next(calc_next_close_long(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params,
+                        &long,
+                        &long_bundle,
+                    )),
+                    next(calc_next_close_short(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params,
+                        &short,
+                        &short_bundle,
+                    )),
+                    "ratio {} size {}",
+                    ratio,
+                    size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_close_is_flagged_taker_within_the_threshold() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let bot_params = |close_taker_threshold_pct: f64| BotParams {
+            close_nearest_taker: true,
+            close_taker_threshold_pct,
+            ..golden_bot_params(0.0)
+        };
+        // the nearest levels, 100.9 and 99.1, are 0.9% off the touch
+        for (threshold, long_type, short_type) in [
+            (0.0, OrderType::CloseTakerLong, OrderType::CloseTakerShort),
+            (0.01, OrderType::CloseTakerLong, OrderType::CloseTakerShort),
+            (0.005, OrderType::CloseGridLong, OrderType::CloseGridShort),
+        ] {
+            let closes = calc_closes_long(
+                &exchange_params,
+                &state_params,
+                &bot_params(threshold),
+                &long,
+                &TrailingPriceBundle::default(),
+                &[],
+            );
+            assert_ladder(
+                closes,
+                &[
+                    (-1.0, 100.9, long_type),
+                    (-1.0, 101.3, OrderType::CloseGridLong),
+                    (-1.0, 101.7, OrderType::CloseGridLong),
+                    (-1.0, 102.1, OrderType::CloseGridLong),
+                ],
+            );
+            let closes = calc_closes_short(
+                &exchange_params,
+                &state_params,
+                &bot_params(threshold),
+                &short,
+                &TrailingPriceBundle::default(),
+                &[],
+            );
+            assert_ladder(
+                closes,
+                &[
+                    (1.0, 99.1, short_type),
+                    (1.0, 98.7, OrderType::CloseGridShort),
+                    (1.0, 98.3, OrderType::CloseGridShort),
+                    (1.0, 97.89, OrderType::CloseGridShort),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn closes_widen_to_recover_funding_paid() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let bot_params = BotParams {
+            close_recover_funding: true,
+            ..golden_bot_params(0.0)
+        };
+        // 2.0 paid on a 400.0 position is half a percent on top of close_grid_min_markup
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            accrued_funding: 2.0,
+        };
+        let short = Position { size: -4.0, ..long };
+        assert_eq!(
+            calc_funding_markup(&exchange_params, &bot_params, &long),
+            0.005
+        );
+        assert_eq!(
+            calc_funding_markup(&exchange_params, &bot_params, &short),
+            0.005
+        );
+        // every level carries the same share
+        assert_eq!(long.resized(1.0).accrued_funding, 0.5);
+        assert_eq!(
+            calc_funding_markup(&exchange_params, &bot_params, &short.resized(-1.0)),
+            0.005
+        );
+        let closes = |bot_params: &BotParams, position: &Position| {
+            if position.size > 0.0 {
+                calc_closes_long(
+                    &exchange_params,
+                    &state_params,
+                    bot_params,
+                    position,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                )
+            } else {
+                calc_closes_short(
+                    &exchange_params,
+                    &state_params,
+                    bot_params,
+                    position,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                )
+            }
+        };
+        assert_ladder(
+            closes(&bot_params, &long),
+            &[
+                (-1.0, 101.4, OrderType::CloseGridLong),
+                (-1.0, 101.8, OrderType::CloseGridLong),
+                (-1.0, 102.2, OrderType::CloseGridLong),
+                (-1.0, 102.6, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(
+            closes(&bot_params, &short),
+            &[
+                (1.0, 98.6, OrderType::CloseGridShort),
+                (1.0, 98.2, OrderType::CloseGridShort),
+                (1.0, 97.8, OrderType::CloseGridShort),
+                (1.0, 97.39, OrderType::CloseGridShort),
+            ],
+        );
+
+        // funding received, or the option off, leaves the golden ladder
+        let received = Position {
+            accrued_funding: -2.0,
+            ..long
+        };
+        assert_eq!(
+            calc_funding_markup(&exchange_params, &bot_params, &received),
+            0.0
+        );
+        assert_ladder(
+            closes(&bot_params, &received),
+            &[
+                (-1.0, 100.9, OrderType::CloseGridLong),
+                (-1.0, 101.3, OrderType::CloseGridLong),
+                (-1.0, 101.7, OrderType::CloseGridLong),
+                (-1.0, 102.1, OrderType::CloseGridLong),
+            ],
+        );
+        assert_eq!(
+            calc_funding_markup(&exchange_params, &golden_bot_params(0.0), &long),
+            0.0
+        );
+    }
+
+    #[test]
+    fn large_grid_closes_show_an_iceberg_slice() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let icebergs = |close_iceberg_visible_qty: f64, position: &Position| {
+            let bot_params = BotParams {
+                close_iceberg_visible_qty,
+                ..golden_bot_params(0.0)
+            };
+            let closes = if position.size > 0.0 {
+                calc_closes_long(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    position,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                )
+            } else {
+                calc_closes_short(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    position,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                )
+            };
+            // the golden ladder of four 1.0 levels, whatever is visible
+            assert_eq!(closes.len(), 4);
+            assert!(closes.iter().all(|close| close.qty.abs() == 1.0));
+            closes
+                .iter()
+                .map(|close| close.iceberg_qty)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(icebergs(0.0, &long), [None; 4]);
+        // rounded down to qty_step, with the sign of the order
+        assert_eq!(icebergs(0.2555, &long), [Some(-0.255); 4]);
+        assert_eq!(icebergs(0.2555, &short), [Some(0.255); 4]);
+        // no smaller than the 5.0 min_cost at each level's price
+        assert_eq!(
+            icebergs(0.01, &long),
+            [Some(-0.05), Some(-0.05), Some(-0.05), Some(-0.049)]
+        );
+        assert_eq!(
+            icebergs(0.01, &short),
+            [Some(0.051), Some(0.051), Some(0.051), Some(0.052)]
+        );
+        // levels no larger than the slice stay fully visible
+        assert_eq!(icebergs(1.0, &long), [None; 4]);
+    }
+
+    #[test]
+    fn fresh_targets_replace_the_grid_close() {
+        let exchange_params = test_exchange_params();
+        let bot_params = BotParams {
+            target_max_staleness_ms: 1000,
+            ..golden_bot_params(0.0)
+        };
+        let state_params = |target_price: Option<(f64, u64)>| StateParams {
+            timestamp: 10_000,
+            target_price,
+            ..test_state_params(100.0, 100.01)
+        };
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        for (target_price, fresh) in [
+            (None, None),
+            (Some((103.0, 9_000)), Some(103.0)),
+            (Some((103.0, 8_999)), None),
+            // stamped after now is clock skew
+            (Some((103.0, 12_000)), Some(103.0)),
+            (Some((f64::NAN, 10_000)), None),
+            (Some((0.0, 10_000)), None),
+        ] {
+            assert_eq!(
+                fresh_target_price(&state_params(target_price), &bot_params),
+                fresh,
+                "{:?}",
+                target_price
+            );
+        }
+        let off = BotParams {
+            target_max_staleness_ms: 0,
+            ..bot_params.clone()
+        };
+        assert_eq!(
+            fresh_target_price(&state_params(Some((103.0, 10_000))), &off),
+            None
+        );
+
+        let close = |target_price: Option<(f64, u64)>| {
+            let close = calc_grid_close_long(
+                &exchange_params,
+                &state_params(target_price),
+                &bot_params,
+                &position,
+            )
+            .unwrap();
+            (close.qty, close.price, close.order_type)
+        };
+        // the whole position at the target, rounded up to price_step and no lower than the ask
+        assert_eq!(
+            close(Some((103.004, 9_500))),
+            (-4.0, 103.01, OrderType::CloseGridLong)
+        );
+        assert_eq!(
+            close(Some((99.0, 9_500))),
+            (-4.0, 100.01, OrderType::CloseGridLong)
+        );
+        // a stale target leaves the grid
+        assert_eq!(
+            close(Some((103.0, 5_000))),
+            (-1.0, 100.9, OrderType::CloseGridLong)
+        );
+    }
+
+    #[test]
+    fn neutral_rebalance_closes_trim_the_leading_side_down() {
+        let exchange_params = test_exchange_params();
+        let bot_params_pair = BotParamsPair {
+            long: BotParams {
+                rebalance_threshold_pct: 0.1,
+                ..Default::default()
+            },
+            short: BotParams {
+                rebalance_threshold_pct: 0.2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let close = |long_size: f64, short_size: f64| {
+            calc_neutral_rebalance_close(
+                &exchange_params,
+                &bot_params_pair,
+                &Position {
+                    size: long_size,
+                    price: 100.0,
+                    ..Default::default()
+                },
+                &Position {
+                    size: short_size,
+                    price: 100.0,
+                    ..Default::default()
+                },
+                100.0,
+            )
+            .map(|close| (close.qty, close.price, close.order_type))
+        };
+        // the leading side's tolerance applies
+        assert_eq!(
+            close(1.0, -0.85),
+            Some((-0.15, 100.0, OrderType::CloseRebalanceLong))
+        );
+        assert_eq!(close(0.85, -1.0), None);
+        assert_eq!(
+            close(0.75, -1.0),
+            Some((0.25, 100.0, OrderType::CloseRebalanceShort))
+        );
+        // reduce-only closes are exempt from min_cost
+        assert_eq!(
+            close(0.003, -0.0),
+            Some((-0.003, 100.0, OrderType::CloseRebalanceLong))
+        );
+        assert_eq!(close(0.0, -0.0), None);
+    }
+
+    #[test]
+    fn fib_closes_fire_level_by_level() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(100.0, 100.01);
+        let bot_params = BotParams {
+            close_trailing_fib_levels: vec![0.236, 0.382, 0.618],
+            close_trailing_fib_qty_pct: 0.25,
+            ..golden_bot_params(1.0)
+        };
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let long_close = |max_since_open: f64, min_since_max: f64, fib_levels_closed: usize| {
+            calc_trailing_fib_close_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                &TrailingPriceBundle {
+                    max_since_open,
+                    min_since_max,
+                    fib_levels_closed,
+                    ..Default::default()
+                },
+            )
+            .map(|close| (close.qty, close.price, close.order_type))
+        };
+        let short_close = |min_since_open: f64, max_since_min: f64, fib_levels_closed: usize| {
+            calc_trailing_fib_close_short(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &short,
+                &TrailingPriceBundle {
+                    min_since_open,
+                    max_since_min,
+                    fib_levels_closed,
+                    ..Default::default()
+                },
+            )
+            .map(|close| (close.qty, close.price, close.order_type))
+        };
+        // a quarter of the 5.0 full position at the exposure limit per level; peaked at 110,
+        // the levels are 107.64, 106.18 and 103.82
+        assert_eq!(
+            long_close(110.0, 107.5, 0),
+            Some((-1.25, 107.64, OrderType::CloseTrailingFibLong))
+        );
+        assert_eq!(long_close(110.0, 107.7, 0), None);
+        // a filled level moves on to the next, from the same peak
+        assert_eq!(long_close(110.0, 107.5, 1), None);
+        assert_eq!(
+            long_close(110.0, 106.0, 1),
+            Some((-1.25, 106.18, OrderType::CloseTrailingFibLong))
+        );
+        assert_eq!(long_close(110.0, 100.0, 3), None);
+        // not armed below close_trailing_threshold_pct
+        assert_eq!(long_close(100.9, 100.0, 0), None);
+
+        assert_eq!(
+            short_close(90.0, 92.5, 0),
+            Some((1.25, 92.36, OrderType::CloseTrailingFibShort))
+        );
+        assert_eq!(short_close(90.0, 92.3, 0), None);
+        assert_eq!(
+            short_close(90.0, 96.2, 2),
+            Some((1.25, 96.18, OrderType::CloseTrailingFibShort))
+        );
+
+        let disabled = BotParams {
+            close_trailing_fib_qty_pct: 0.0,
+            ..bot_params.clone()
+        };
+        assert!(calc_trailing_fib_close_long(
+            &exchange_params,
+            &state_params,
+            &disabled,
+            &long,
+            &TrailingPriceBundle {
+                max_since_open: 110.0,
+                min_since_max: 100.0,
+                ..Default::default()
+            },
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn wrong_signed_positions_get_no_closes() {
+        let exchange_params = test_exchange_params();
+        let state_params = test_state_params(100.0, 100.01);
+        let bot_params = BotParams {
+            close_trailing_fib_levels: vec![0.5],
+            close_trailing_fib_qty_pct: 0.25,
+            ..golden_bot_params(0.5)
+        };
+        // a deep retracement from far either side would trigger every trailing close
+        let bundle = TrailingPriceBundle {
+            max_since_open: 120.0,
+            min_since_max: 90.0,
+            min_since_open: 80.0,
+            max_since_min: 110.0,
+            ..Default::default()
+        };
+        let short_as_long = Position {
+            size: -4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let long_as_short = Position {
+            size: 4.0,
+            ..short_as_long
+        };
+        // rightly signed, the same state closes
+        assert!(!calc_closes_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &long_as_short,
+            &bundle,
+            &[]
+        )
+        .is_empty());
+        let (long, short) = (&short_as_long, &long_as_short);
+        assert!(calc_grid_close_long(&exchange_params, &state_params, &bot_params, long).is_none());
+        assert!(
+            calc_grid_close_short(&exchange_params, &state_params, &bot_params, short).is_none()
+        );
+        assert!(matches!(
+            calc_trailing_close_long(&exchange_params, &state_params, &bot_params, long, &bundle),
+            NextOrder::NoOrder
+        ));
+        assert!(matches!(
+            calc_trailing_close_short(&exchange_params, &state_params, &bot_params, short, &bundle),
+            NextOrder::NoOrder
+        ));
+        assert!(calc_trailing_fib_close_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            long,
+            &bundle
+        )
+        .is_none());
+        assert!(calc_trailing_fib_close_short(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            short,
+            &bundle
+        )
+        .is_none());
+        assert!(matches!(
+            calc_next_close_long(&exchange_params, &state_params, &bot_params, long, &bundle),
+            NextOrder::NoOrder
+        ));
+        assert!(matches!(
+            calc_next_close_short(&exchange_params, &state_params, &bot_params, short, &bundle),
+            NextOrder::NoOrder
+        ));
+        assert!(calc_closes_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            long,
+            &bundle,
+            &[]
+        )
+        .is_empty());
+        assert!(calc_closes_short(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            short,
+            &bundle,
+            &[]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn grid_closes_scale_with_the_hours_liquidity() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let bot_params = BotParams {
+            liquidity_profile: vec![1.0, 2.0, 0.5],
+            ..golden_bot_params(0.0)
+        };
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = |hour: usize| {
+            calc_closes_long(
+                &exchange_params,
+                &StateParams {
+                    hour,
+                    ..test_state_params(100.0, 100.01)
+                },
+                &bot_params,
+                &position,
+                &TrailingPriceBundle::default(),
+                &[],
+            )
+            .iter()
+            .map(|close| close.qty)
+            .collect::<Vec<_>>()
+        };
+        // hours past the profile keep the golden ladder
+        assert_eq!(closes(0), [-1.0; 4]);
+        assert_eq!(closes(5), [-1.0; 4]);
+        assert_eq!(closes(1), [-2.0, -2.0]);
+        assert_eq!(closes(2), [-0.5; 8]);
+        assert_eq!(bot_params.liquidity_multiplier(2), 0.5);
+        assert_eq!(
+            BotParams {
+                liquidity_profile: vec![-1.0],
+                ..Default::default()
+            }
+            .liquidity_multiplier(0),
+            0.0
+        );
+    }
+
+    #[test]
+    fn stepped_trailing_stops_ratchet_in_whole_increments() {
+        let exchange_params = test_exchange_params();
+        let stepped = BotParams {
+            close_trailing_step_multiple: 10.0,
+            ..golden_bot_params(1.0)
+        };
+        let continuous = golden_bot_params(1.0);
+        let long = |bot_params: &BotParams, stop_price: f64, stepped_stop_price: f64| {
+            calc_stepped_trailing_stop_price_long(
+                &exchange_params,
+                bot_params,
+                stop_price,
+                stepped_stop_price,
+            )
+        };
+        let short = |bot_params: &BotParams, stop_price: f64, stepped_stop_price: f64| {
+            calc_stepped_trailing_stop_price_short(
+                &exchange_params,
+                bot_params,
+                stop_price,
+                stepped_stop_price,
+            )
+        };
+        // increments of 0.1, rounded away from the market and never moved back
+        assert!((long(&stepped, 102.97, 0.0) - 102.9).abs() < 1e-9);
+        assert_eq!(long(&stepped, 102.97, 103.0), 103.0);
+        assert!((short(&stepped, 97.03, 0.0) - 97.1).abs() < 1e-9);
+        assert_eq!(short(&stepped, 97.03, 97.05), 97.05);
+        assert_eq!(long(&continuous, 102.97, 103.0), 102.97);
+        assert_eq!(short(&continuous, 97.03, 97.0), 97.03);
+
+        // peaked at 104: the continuous stop at 102.96 fires on 102.93, the stepped one at
+        // 102.9 waits
+        let state_params = test_state_params(100.0, 100.01);
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let fires = |bot_params: &BotParams, min_since_max: f64| {
+            matches!(
+                calc_trailing_close_long(
+                    &exchange_params,
+                    &state_params,
+                    bot_params,
+                    &position,
+                    &TrailingPriceBundle {
+                        max_since_open: 104.0,
+                        min_since_max,
+                        ..Default::default()
+                    },
+                ),
+                NextOrder::Order(_)
+            )
+        };
+        assert!(fires(&continuous, 102.93));
+        assert!(!fires(&stepped, 102.93));
+        assert!(fires(&stepped, 102.89));
+    }
+
+    #[test]
+    fn compounded_closes_size_against_realized_pnl_too() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let compounding = BotParams {
+            compound_realized_into_balance: true,
+            ..golden_bot_params(0.0)
+        };
+        let state_params = |balance: f64, realized_pnl: f64| StateParams {
+            balance,
+            realized_pnl,
+            ..test_state_params(100.0, 100.01)
+        };
+        assert_eq!(
+            compounding.close_balance(&state_params(1000.0, 500.0)),
+            1500.0
+        );
+        assert_eq!(
+            golden_bot_params(0.0).close_balance(&state_params(1000.0, 500.0)),
+            1000.0
+        );
+        // reinvested before the allocation is taken
+        let allocated = BotParams {
+            balance_allocation_pct: 0.5,
+            ..compounding.clone()
+        };
+        assert_eq!(allocated.close_balance(&state_params(1000.0, 500.0)), 750.0);
+
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = |bot_params: &BotParams, state_params: &StateParams| {
+            calc_closes_long(
+                &exchange_params,
+                state_params,
+                bot_params,
+                &position,
+                &TrailingPriceBundle::default(),
+                &[],
+            )
+            .iter()
+            .map(|close| (close.qty, close.price))
+            .collect::<Vec<_>>()
+        };
+        let compounded = closes(&compounding, &state_params(1000.0, 1000.0));
+        assert_eq!(
+            compounded,
+            closes(&golden_bot_params(0.0), &state_params(2000.0, 0.0))
+        );
+        assert_ne!(
+            compounded,
+            closes(&golden_bot_params(0.0), &state_params(1000.0, 1000.0))
+        );
+    }
+
+    #[test]
+    fn post_only_rejects_drop_closes_that_would_cross() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = |simulate_post_only_reject: bool, close_nearest_taker: bool| {
+            let bot_params = BotParams {
+                simulate_post_only_reject,
+                close_nearest_taker,
+                ..golden_bot_params(0.0)
+            };
+            calc_closes_long(
+                &exchange_params,
+                &test_state_params(101.49, 101.5),
+                &bot_params,
+                &position,
+                &TrailingPriceBundle::default(),
+                &[],
+            )
+        };
+        // the ask sits past the first two levels, which merge into one at the ask
+        assert_ladder(
+            closes(false, false),
+            &[
+                (-2.0, 101.5, OrderType::CloseGridLong),
+                (-1.0, 101.7, OrderType::CloseGridLong),
+                (-1.0, 102.1, OrderType::CloseGridLong),
+            ],
+        );
+        // rejected as post-only, leaving the rest of the ladder as it was
+        assert_ladder(
+            closes(true, false),
+            &[
+                (-1.0, 101.7, OrderType::CloseGridLong),
+                (-1.0, 102.1, OrderType::CloseGridLong),
+            ],
+        );
+        // a taker close is not post-only
+        assert_ladder(
+            closes(true, true),
+            &[
+                (-2.0, 101.5, OrderType::CloseTakerLong),
+                (-1.0, 101.7, OrderType::CloseGridLong),
+                (-1.0, 102.1, OrderType::CloseGridLong),
+            ],
+        );
+        let bot_params = BotParams {
+            simulate_post_only_reject: true,
+            ..golden_bot_params(0.0)
+        };
+        assert!(calc_grid_close_long(
+            &exchange_params,
+            &test_state_params(101.49, 101.5),
+            &bot_params,
+            &position
+        )
+        .is_none());
+        assert!(calc_grid_close_short(
+            &exchange_params,
+            &test_state_params(98.5, 98.51),
+            &bot_params,
+            &Position {
+                size: -4.0,
+                ..position
+            }
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn touch_share_of_grid_closes_is_taken_at_the_touch() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let bot_params = |close_touch_qty_pct: f64| BotParams {
+            close_touch_qty_pct,
+            ..golden_bot_params(0.0)
+        };
+        let closes = |close_touch_qty_pct: f64| {
+            (
+                calc_closes_long(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params(close_touch_qty_pct),
+                    &long,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                ),
+                calc_closes_short(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params(close_touch_qty_pct),
+                    &short,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                ),
+            )
+        };
+        // a quarter of each level goes to one taker order at the touch
+        let (long_closes, short_closes) = closes(0.25);
+        assert_ladder(
+            long_closes,
+            &[
+                (-1.0, 100.01, OrderType::CloseTakerLong),
+                (-0.75, 100.9, OrderType::CloseGridLong),
+                (-0.75, 101.3, OrderType::CloseGridLong),
+                (-0.75, 101.7, OrderType::CloseGridLong),
+                (-0.75, 102.1, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(
+            short_closes,
+            &[
+                (1.0, 100.0, OrderType::CloseTakerShort),
+                (0.75, 99.1, OrderType::CloseGridShort),
+                (0.75, 98.7, OrderType::CloseGridShort),
+                (0.75, 98.3, OrderType::CloseGridShort),
+                (0.75, 97.89, OrderType::CloseGridShort),
+            ],
+        );
+        // rests under the exchange minimum keep the levels whole, as does a touch order under it
+        for close_touch_qty_pct in [0.99, 0.01] {
+            let (long_closes, short_closes) = closes(close_touch_qty_pct);
+            assert_ladder(
+                long_closes,
+                &[
+                    (-1.0, 100.9, OrderType::CloseGridLong),
+                    (-1.0, 101.3, OrderType::CloseGridLong),
+                    (-1.0, 101.7, OrderType::CloseGridLong),
+                    (-1.0, 102.1, OrderType::CloseGridLong),
+                ],
+            );
+            assert_ladder(
+                short_closes,
+                &[
+                    (1.0, 99.1, OrderType::CloseGridShort),
+                    (1.0, 98.7, OrderType::CloseGridShort),
+                    (1.0, 98.3, OrderType::CloseGridShort),
+                    (1.0, 97.89, OrderType::CloseGridShort),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn markup_floors_raise_the_min_markup() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = |min_markup_floor: f64| {
+            let state_params = StateParams {
+                min_markup_floor,
+                ..test_state_params(100.0, 100.01)
+            };
+            calc_closes_long(
+                &exchange_params,
+                &state_params,
+                &golden_bot_params(0.0),
+                &long,
+                &TrailingPriceBundle::default(),
+                &[],
+            )
+        };
+        // a floor under close_grid_min_markup leaves the ladder be
+        assert_ladder(
+            closes(0.004),
+            &[
+                (-1.0, 100.9, OrderType::CloseGridLong),
+                (-1.0, 101.3, OrderType::CloseGridLong),
+                (-1.0, 101.7, OrderType::CloseGridLong),
+                (-1.0, 102.1, OrderType::CloseGridLong),
+            ],
+        );
+        // one above it shifts the ladder up by the difference
+        assert_ladder(
+            closes(0.01),
+            &[
+                (-1.0, 101.4, OrderType::CloseGridLong),
+                (-1.0, 101.8, OrderType::CloseGridLong),
+                (-1.0, 102.2, OrderType::CloseGridLong),
+                (-1.0, 102.6, OrderType::CloseGridLong),
+            ],
+        );
+    }
+
+    #[test]
+    fn staggered_closes_expire_later_the_farther_they_rest() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let bot_params = golden_bot_params(0.0);
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let expiries = |min_expiry_candles: usize, max_expiry_candles: usize| {
+            let long_closes = calc_staggered_closes_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                &TrailingPriceBundle::default(),
+                min_expiry_candles,
+                max_expiry_candles,
+            );
+            let short_closes = calc_staggered_closes_short(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &short,
+                &TrailingPriceBundle::default(),
+                min_expiry_candles,
+                max_expiry_candles,
+            );
+            [long_closes, short_closes].map(|closes| {
+                closes
+                    .iter()
+                    .map(|close| (close.order.price, close.expiry_candles))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let [long_expiries, short_expiries] = expiries(10, 40);
+        assert_eq!(
+            long_expiries,
+            [(100.9, 10), (101.3, 20), (101.7, 30), (102.1, 40)]
+        );
+        assert_eq!(
+            short_expiries,
+            [(99.1, 10), (98.7, 20), (98.3, 30), (97.89, 40)]
+        );
+        // a max below the min is raised to it
+        let [long_expiries, _] = expiries(10, 5);
+        assert!(long_expiries.iter().all(|&(_, expiry)| expiry == 10));
+    }
+
+    #[test]
+    fn crossed_close_levels_are_merged() {
+        let exchange_params = test_exchange_params();
+        let close = |qty: f64, price: f64| Order::new(qty, price, OrderType::CloseGridLong);
+        let qtys_and_prices = |closes: Vec<Order>| {
+            closes
+                .iter()
+                .map(|close| (close.qty, close.price))
+                .collect::<Vec<_>>()
+        };
+        // 100.99 rounded below the 101.0 level before it; it joins that level
+        let merged = merge_crossed_closes(
+            vec![
+                close(-0.1, 101.0),
+                close(-0.2, 100.99),
+                close(-0.3, 101.5),
+                close(-0.4, 101.5),
+            ],
+            &exchange_params,
+            LONG,
+        );
+        assert_eq!(
+            qtys_and_prices(merged),
+            [(-0.3, 101.0), (-0.3, 101.5), (-0.4, 101.5)]
+        );
+        let merged = merge_crossed_closes(
+            vec![close(0.1, 99.0), close(0.2, 99.01), close(0.3, 98.5)],
+            &exchange_params,
+            SHORT,
+        );
+        assert_eq!(qtys_and_prices(merged), [(0.3, 99.0), (0.3, 98.5)]);
+    }
+
+    #[test]
+    fn var_target_closes_hold_the_risk_per_level() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let bot_params = golden_bot_params(0.0);
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let long_closes = |volatility: f64| {
+            calc_var_target_closes_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                volatility,
+                1.5,
+            )
+        };
+        // 1.5 / (0.01 * 100.9) == 1.487; levels shrink as they rise and the last takes the rest
+        assert_ladder(
+            long_closes(0.01),
+            &[
+                (-1.487, 100.9, OrderType::CloseGridLong),
+                (-1.478, 101.5, OrderType::CloseGridLong),
+                (-1.035, 102.09, OrderType::CloseGridLong),
+            ],
+        );
+        let short_closes = calc_var_target_closes_short(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &short,
+            0.01,
+            1.5,
+        );
+        assert_ladder(
+            short_closes,
+            &[
+                (1.514, 99.1, OrderType::CloseGridShort),
+                (1.523, 98.49, OrderType::CloseGridShort),
+                (0.963, 97.88, OrderType::CloseGridShort),
+            ],
+        );
+        assert!(long_closes(0.0).is_empty());
+        assert!(long_closes(f64::NAN).is_empty());
+    }
+
+    #[test]
+    fn flow_against_the_position_tightens_the_closes() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let bot_params = BotParams {
+            close_on_flow_imbalance: 0.5,
+            ..golden_bot_params(0.0)
+        };
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let closes = |order_flow_imbalance: Option<f64>| {
+            let state_params = StateParams {
+                order_flow_imbalance,
+                ..test_state_params(100.0, 100.01)
+            };
+            (
+                calc_closes_long(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    &long,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                ),
+                calc_closes_short(
+                    &exchange_params,
+                    &state_params,
+                    &bot_params,
+                    &short,
+                    &TrailingPriceBundle::default(),
+                    &[],
+                ),
+            )
+        };
+        let golden_long = [
+            (-1.0, 100.9, OrderType::CloseGridLong),
+            (-1.0, 101.3, OrderType::CloseGridLong),
+            (-1.0, 101.7, OrderType::CloseGridLong),
+            (-1.0, 102.1, OrderType::CloseGridLong),
+        ];
+        let golden_short = [
+            (1.0, 99.1, OrderType::CloseGridShort),
+            (1.0, 98.7, OrderType::CloseGridShort),
+            (1.0, 98.3, OrderType::CloseGridShort),
+            (1.0, 97.89, OrderType::CloseGridShort),
+        ];
+        let (long_closes, short_closes) = closes(None);
+        assert_ladder(long_closes, &golden_long);
+        assert_ladder(short_closes, &golden_short);
+        // buying 0.25 past the threshold halves the short's markups; the long keeps its own
+        let (long_closes, short_closes) = closes(Some(0.75));
+        assert_ladder(long_closes, &golden_long);
+        assert_ladder(
+            short_closes,
+            &[
+                (1.0, 99.55, OrderType::CloseGridShort),
+                (1.0, 99.35, OrderType::CloseGridShort),
+                (1.0, 99.15, OrderType::CloseGridShort),
+                (1.0, 98.95, OrderType::CloseGridShort),
+            ],
+        );
+        let (long_closes, short_closes) = closes(Some(-0.75));
+        assert_ladder(
+            long_closes,
+            &[
+                (-1.0, 100.45, OrderType::CloseGridLong),
+                (-1.0, 100.65, OrderType::CloseGridLong),
+                (-1.0, 100.85, OrderType::CloseGridLong),
+                (-1.0, 101.05, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(short_closes, &golden_short);
+        // one-sided selling closes the long whole at the touch
+        let (long_closes, _) = closes(Some(-1.0));
+        assert_ladder(long_closes, &[(-4.0, 100.01, OrderType::CloseGridLong)]);
+    }
+
+    #[test]
+    fn close_markup_scale_pulls_the_ladder_toward_breakeven() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = |close_markup_scale: Option<f64>| {
+            let state_params = StateParams {
+                close_markup_scale,
+                ..test_state_params(100.0, 100.01)
+            };
+            calc_closes_long(
+                &exchange_params,
+                &state_params,
+                &golden_bot_params(0.0),
+                &long,
+                &TrailingPriceBundle::default(),
+                &[],
+            )
+        };
+        assert_ladder(
+            closes(Some(0.5)),
+            &[
+                (-1.0, 100.45, OrderType::CloseGridLong),
+                (-1.0, 100.65, OrderType::CloseGridLong),
+                (-1.0, 100.85, OrderType::CloseGridLong),
+                (-1.0, 101.05, OrderType::CloseGridLong),
+            ],
+        );
+        // 0.0, or below, closes the whole position at the touch
+        for scale in [0.0, -1.0] {
+            assert_ladder(
+                closes(Some(scale)),
+                &[(-4.0, 100.01, OrderType::CloseGridLong)],
+            );
+        }
+    }
+
+    #[test]
+    fn credited_ladder_pnl_sizes_the_later_levels() {
+        let exchange_params = ExchangeParams {
+            min_cost: 5.0,
+            ..test_exchange_params()
+        };
+        let state_params = test_state_params(100.0, 100.01);
+        let long = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -4.0, ..long };
+        let bot_params = BotParams {
+            close_credit_ladder_pnl: true,
+            ..golden_bot_params(0.0)
+        };
+        let trailing_price_bundle = TrailingPriceBundle::default();
+        // against the golden 1.0 per level at 100.9 up to 102.1: each level's profit lowers
+        // the exposure the next is priced and sized at, the first and the total unchanged
+        assert_ladder(
+            calc_closes_long(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &long,
+                &trailing_price_bundle,
+                &[],
+            ),
+            &[
+                (-1.0, 100.9, OrderType::CloseGridLong),
+                (-1.001, 101.31, OrderType::CloseGridLong),
+                (-1.003, 101.71, OrderType::CloseGridLong),
+                (-0.996, 102.11, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(
+            calc_closes_short(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &short,
+                &trailing_price_bundle,
+                &[],
+            ),
+            &[
+                (1.0, 99.1, OrderType::CloseGridShort),
+                (1.001, 98.69, OrderType::CloseGridShort),
+                (1.003, 98.29, OrderType::CloseGridShort),
+                (0.996, 97.89, OrderType::CloseGridShort),
+            ],
+        );
+    }
+
+    #[test]
+    fn drawdown_closes_ladder_from_the_touch() {
+        let exchange_params = test_exchange_params();
+        let bot_params = BotParams {
+            close_grid_qty_pct: 0.25,
+            close_grid_min_markup: 0.01,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 1.0,
+            price: 120.0,
+            ..Default::default()
+        };
+        assert_ladder(
+            calc_drawdown_closes_long(
+                &exchange_params,
+                &test_state_params(99.99, 100.0),
+                &bot_params,
+                &long,
+            ),
+            &[
+                (-0.25, 100.0, OrderType::CloseDrawdownLong),
+                (-0.25, 100.25, OrderType::CloseDrawdownLong),
+                (-0.25, 100.5, OrderType::CloseDrawdownLong),
+                (-0.25, 100.75, OrderType::CloseDrawdownLong),
+            ],
+        );
+        let short = Position {
+            size: -1.0,
+            price: 80.0,
+            ..Default::default()
+        };
+        assert_ladder(
+            calc_drawdown_closes_short(
+                &exchange_params,
+                &test_state_params(100.0, 100.01),
+                &bot_params,
+                &short,
+            ),
+            &[
+                (0.25, 100.0, OrderType::CloseDrawdownShort),
+                (0.25, 99.75, OrderType::CloseDrawdownShort),
+                (0.25, 99.5, OrderType::CloseDrawdownShort),
+                (0.25, 99.25, OrderType::CloseDrawdownShort),
+            ],
+        );
+    }
+
+    #[test]
+    fn drawdown_closes_end_with_a_zero_qty_pct() {
+        // nothing but qty_step bounds a close from below
+        let exchange_params = ExchangeParams {
+            min_qty: 0.0,
+            min_cost: 0.0,
+            ..test_exchange_params()
+        };
+        let bot_params = BotParams {
+            close_grid_qty_pct: 0.0,
+            close_grid_min_markup: 0.01,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 0.01,
+            price: 120.0,
+            ..Default::default()
+        };
+        assert_ladder(
+            calc_drawdown_closes_long(
+                &exchange_params,
+                &test_state_params(99.99, 100.0),
+                &bot_params,
+                &long,
+            ),
+            &[(-0.01, 100.0, OrderType::CloseDrawdownLong)],
+        );
+        let short = Position {
+            size: -0.01,
+            price: 80.0,
+            ..Default::default()
+        };
+        assert_ladder(
+            calc_drawdown_closes_short(
+                &exchange_params,
+                &test_state_params(100.0, 100.01),
+                &bot_params,
+                &short,
+            ),
+            &[(0.01, 100.0, OrderType::CloseDrawdownShort)],
+        );
+    }
+
+    fn mirror_bot_params() -> BotParams {
+        BotParams {
+            mirror_entries: true,
+            entry_grid_spacing_pct: 0.02,
+            entry_grid_double_down_factor: 0.5,
+            entry_initial_qty_pct: 0.05,
+            entry_initial_ema_dist: 0.0,
+            wallet_exposure_limit: 2.0,
+            ..golden_bot_params(0.0)
+        }
+    }
+
+    #[test]
+    fn mirrored_closes_keep_to_the_band_and_off_blocked_prices() {
+        let state_params = StateParams {
+            ema_bands: crate::types::EMABands {
+                upper: 100.0,
+                lower: 100.0,
+            },
+            ..test_state_params(100.0, 100.01)
+        };
+        let long = Position {
+            size: 3.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position {
+            size: -3.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let closes = |exchange_params: &ExchangeParams, position: &Position, blocked: &[f64]| {
+            let trailing_price_bundle = TrailingPriceBundle::default();
+            if position.size > 0.0 {
+                calc_closes_long(
+                    exchange_params,
+                    &state_params,
+                    &mirror_bot_params(),
+                    position,
+                    &trailing_price_bundle,
+                    blocked,
+                )
+            } else {
+                calc_closes_short(
+                    exchange_params,
+                    &state_params,
+                    &mirror_bot_params(),
+                    position,
+                    &trailing_price_bundle,
+                    blocked,
+                )
+            }
+        };
+        // long entries at 98.0 and 97.34 mirror above pprice, short ones at 102.0 and 102.68 below
+        let exchange_params = test_exchange_params();
+        assert_ladder(
+            closes(&exchange_params, &long, &[]),
+            &[
+                (-1.5, 102.0, OrderType::CloseGridLong),
+                (-1.5, 102.66, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(
+            closes(&exchange_params, &short, &[]),
+            &[
+                (1.5, 98.0, OrderType::CloseGridShort),
+                (1.5, 97.32, OrderType::CloseGridShort),
+            ],
+        );
+
+        // neither level fits a 1.5% band; both are clamped onto its edge
+        let banded = ExchangeParams {
+            price_band_pct: 0.015,
+            ..exchange_params.clone()
+        };
+        assert_ladder(
+            closes(&banded, &long, &[]),
+            &[(-3.0, 101.51, OrderType::CloseGridLong)],
+        );
+        assert_ladder(
+            closes(&banded, &short, &[]),
+            &[(3.0, 98.5, OrderType::CloseGridShort)],
+        );
+
+        // blocked levels move a tick away from the market
+        assert_ladder(
+            closes(&exchange_params, &long, &[102.0]),
+            &[
+                (-1.5, 102.01, OrderType::CloseGridLong),
+                (-1.5, 102.66, OrderType::CloseGridLong),
+            ],
+        );
+        assert_ladder(
+            closes(&exchange_params, &short, &[98.0, 97.99]),
+            &[
+                (1.5, 97.98, OrderType::CloseGridShort),
+                (1.5, 97.32, OrderType::CloseGridShort),
+            ],
+        );
+    }
+
+    #[test]
+    fn all_closes_reject_bad_input() {
+        let exchange_params_list = vec![test_exchange_params(); 2];
+        let state_params_list = vec![test_state_params(100.0, 100.01); 2];
+        let bot_params_pair = BotParamsPair {
+            long: golden_bot_params(0.0),
+            short: golden_bot_params(0.0),
+            ..Default::default()
+        };
+        let mut positions = Positions::default();
+        let position = |size: f64| Position {
+            size,
+            price: 100.0,
+            ..Default::default()
+        };
+        positions.long.insert(1, position(1.0));
+        positions.short.insert(2, position(-1.0));
+        let calc = |pside: usize| {
+            calc_all_closes_ordered(
+                &exchange_params_list,
+                &state_params_list,
+                &bot_params_pair,
+                &positions,
+                &HashMap::new(),
+                pside,
+            )
+        };
+        assert_eq!(calc(2).unwrap_err(), "unknown pside 2");
+        assert_eq!(calc(SHORT).unwrap_err(), "idx 2 out of range for 2 symbols");
+        let closes = calc(LONG).unwrap();
+        assert_eq!(closes.len(), 1);
+        assert_eq!(closes[0].0, 1);
     }
-    closes
 }
@@ -0,0 +1,406 @@
+use crate::types::{BotParams, BotParamsPair};
+use crate::utils::set_json_path;
+use serde_json::{json, Map, Value};
+
+/// A per-side field's (legacy name, current name, value transform).
+type Rename = (&'static str, &'static str, fn(f64) -> f64);
+
+/// Per-side fields renamed since v6 configs.
+const RENAMED_FIELDS: &[Rename] = &[
+    ("ddown_factor", "entry_grid_double_down_factor", unchanged),
+    (
+        "initial_eprice_ema_dist",
+        "entry_initial_ema_dist",
+        unchanged,
+    ),
+    ("initial_qty_pct", "entry_initial_qty_pct", unchanged),
+    ("markup_range", "close_grid_markup_range", unchanged),
+    ("min_markup", "close_grid_min_markup", unchanged),
+    (
+        "n_close_orders",
+        "close_grid_qty_pct",
+        n_close_orders_to_qty_pct,
+    ),
+    ("rentry_pprice_dist", "entry_grid_spacing_pct", unchanged),
+    (
+        "rentry_pprice_dist_wallet_exposure_weighting",
+        "entry_grid_spacing_weight",
+        unchanged,
+    ),
+];
+
+/// Per-side fields older configs predate which start out as a copy of another, as (added
+/// field, source field): the unstuck bands shared the entry bands' spans.
+const COPIED_FIELDS: &[(&str, &str)] = &[
+    ("unstuck_ema_span_0", "ema_span_0"),
+    ("unstuck_ema_span_1", "ema_span_1"),
+];
+
+/// Pair-level fields, kept beside "long" and "short".
+const PAIR_FIELDS: &[&str] = &["long_allocation_pct", "short_allocation_pct"];
+
+/// v6 multi configs keep these at top level, shared by both sides.
+const GLOBAL_FIELDS: &[(&str, &str)] = &[
+    ("loss_allowance_pct", "unstuck_loss_allowance_pct"),
+    ("stuck_threshold", "unstuck_threshold"),
+    ("unstuck_close_pct", "unstuck_close_pct"),
+];
+
+fn unchanged(value: f64) -> f64 {
+    value
+}
+
+fn n_close_orders_to_qty_pct(n_close_orders: f64) -> f64 {
+    1.0 / n_close_orders.round().max(1.0)
+}
+
+/// Defaults for fields which older configs predate; trailing is disabled.
+fn added_field_default(field: &str) -> Option<Value> {
+    Some(match field {
+        "close_trailing_anchor" => json!("peak"),
+        "close_trailing_fib_levels" | "liquidity_profile" => json!([]),
+        "close_trailing_max_candles_since_peak" | "target_max_staleness_ms" => json!(0),
+        "balance_allocation_pct"
+        | "close_before_funding_minutes"
+        | "close_before_funding_pct"
+        | "close_grid_qty_ratio"
+        | "close_iceberg_visible_qty"
+        | "close_max_qty_pct_of_volume"
+        | "close_on_equity_drawdown_pct"
+        | "close_on_flow_imbalance"
+        | "close_taker_threshold_pct"
+        | "close_touch_qty_pct"
+        | "close_trailing_fast_qty_pct"
+        | "close_trailing_fast_retracement_pct"
+        | "close_trailing_fast_threshold_pct"
+        | "close_trailing_fib_qty_pct"
+        | "close_trailing_grid_ratio"
+        | "close_trailing_qty_pct"
+        | "close_trailing_slow_qty_pct"
+        | "close_trailing_slow_retracement_pct"
+        | "close_trailing_slow_threshold_pct"
+        | "close_trailing_step_multiple"
+        | "close_trailing_retracement_pct"
+        | "close_trailing_threshold_pct"
+        | "entry_trailing_double_down_factor"
+        | "entry_trailing_grid_ratio"
+        | "entry_trailing_retracement_pct"
+        | "entry_trailing_threshold_pct"
+        | "max_position_cost"
+        | "min_close_volume"
+        | "rebalance_threshold_pct"
+        | "unstuck_ema_dist"
+        | "unstuck_max_allowance_fraction"
+        | "unstuck_require_profit_buffer_pct"
+        | "unstuck_rotation_tolerance" => json!(0.0),
+        "auto_reduce_enabled"
+        | "close_before_funding"
+        | "close_credit_ladder_pnl"
+        | "close_nearest_taker"
+        | "close_recover_funding"
+        | "close_require_volume"
+        | "compound_realized_into_balance"
+        | "mirror_entries"
+        | "neutral_mode"
+        | "recenter_trailing_on_partial_close"
+        | "simulate_post_only_reject" => json!(false),
+        "enforce_exposure_limit" => json!(true),
+        // the tolerance auto-reduce had before it was configurable
+        "auto_reduce_tolerance_pct" => json!(0.01),
+        "filter_noisiness_rolling_window" | "filter_volume_rolling_window" => json!(60),
+        "filter_volume_drop_pct" => json!(0.95),
+        _ => return None,
+    })
+}
+
+/// Rewrites a v7 config ({"bot": {"long", "short"}}), a bare {"long", "short"} pair or a v6
+/// multi config ({"universal_live_config", ..}) into a current {"long", "short"} pair.
+/// Returns the pair with one report line per migration applied; unknown fields are kept
+/// for `bot_params_pair_from_config` to reject.
+pub fn migrate_bot_params_pair(config: &Value) -> Result<(Value, Vec<String>), String> {
+    let mut report = Vec::new();
+    let (sides, v6_globals) = if let Some(universal) = config.get("universal_live_config") {
+        report.push("universal_live_config -> bot".to_string());
+        (universal, Some(config))
+    } else if let Some(bot) = config.get("bot") {
+        (bot, None)
+    } else {
+        (config, None)
+    };
+    let mut pair = Map::new();
+    for pside in ["long", "short"] {
+        let mut params = sides
+            .get(pside)
+            .and_then(Value::as_object)
+            .cloned()
+            .ok_or_else(|| format!("missing '{}' parameters", pside))?;
+        if let Some(globals) = v6_globals {
+            migrate_v6_globals(globals, pside, &mut params, &mut report);
+        }
+        for &(legacy, current, transform) in RENAMED_FIELDS {
+            if let Some(value) = params.remove(legacy) {
+                let value = value
+                    .as_f64()
+                    .ok_or_else(|| format!("'{}.{}' is not a number", pside, legacy))?;
+                params.insert(current.to_string(), json!(transform(value)));
+                report.push(format!("{}.{} -> {}.{}", pside, legacy, pside, current));
+            }
+        }
+        for &(added, source) in COPIED_FIELDS {
+            if params.contains_key(added) {
+                continue;
+            }
+            if let Some(value) = params.get(source).cloned() {
+                report.push(format!("{}.{} added as {}.{}", pside, added, pside, source));
+                params.insert(added.to_string(), value);
+            }
+        }
+        for field in bot_params_fields() {
+            if params.contains_key(&field) {
+                continue;
+            }
+            if let Some(default) = added_field_default(&field) {
+                report.push(format!("{}.{} added as {}", pside, field, default));
+                params.insert(field, default);
+            }
+        }
+        if !params.contains_key("wallet_exposure_limit") {
+            let total_wallet_exposure_limit = params
+                .get("total_wallet_exposure_limit")
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0);
+            let n_positions = params
+                .get("n_positions")
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0)
+                .round();
+            let wallet_exposure_limit = if n_positions > 0.0 {
+                total_wallet_exposure_limit / n_positions
+            } else {
+                0.0
+            };
+            params.insert(
+                "wallet_exposure_limit".to_string(),
+                json!(wallet_exposure_limit),
+            );
+            report.push(format!(
+                "{}.wallet_exposure_limit derived as total_wallet_exposure_limit / n_positions",
+                pside
+            ));
+        }
+        pair.insert(pside.to_string(), Value::Object(params));
+    }
+    for &field in PAIR_FIELDS {
+        if let Some(value) = sides.get(field) {
+            pair.insert(field.to_string(), value.clone());
+        }
+    }
+    Ok((Value::Object(pair), report))
+}
+
+// TWE_long, long_enabled and n_longs map to total_wallet_exposure_limit and n_positions
+fn migrate_v6_globals(
+    globals: &Value,
+    pside: &str,
+    params: &mut Map<String, Value>,
+    report: &mut Vec<String>,
+) {
+    for &(legacy, current) in GLOBAL_FIELDS {
+        if let Some(value) = globals.get(legacy) {
+            params.insert(current.to_string(), value.clone());
+            report.push(format!("{} -> {}.{}", legacy, pside, current));
+        }
+    }
+    let twe_key = format!("TWE_{}", pside);
+    if let Some(twe) = globals.get(&twe_key).and_then(Value::as_f64) {
+        let enabled_key = format!("{}_enabled", pside);
+        let enabled = globals
+            .get(&enabled_key)
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+        params.insert(
+            "total_wallet_exposure_limit".to_string(),
+            json!(if enabled { twe } else { 0.0 }),
+        );
+        report.push(format!(
+            "{}, {} -> {}.total_wallet_exposure_limit",
+            twe_key, enabled_key, pside
+        ));
+    }
+    let n_key = format!("n_{}s", pside);
+    if let Some(n_positions) = globals.get(&n_key).and_then(Value::as_f64) {
+        if n_positions > 0.0 {
+            params.insert("n_positions".to_string(), json!(n_positions));
+            report.push(format!("{} -> {}.n_positions", n_key, pside));
+        } else {
+            // 0 meant no forager mode: one position per approved symbol
+            let n_approved = match globals.get("approved_symbols") {
+                Some(Value::Object(symbols)) => symbols.len(),
+                Some(Value::Array(symbols)) => symbols.len(),
+                _ => 0,
+            };
+            params.insert("n_positions".to_string(), json!(n_approved));
+            report.push(format!(
+                "{} == 0, approved_symbols -> {}.n_positions",
+                n_key, pside
+            ));
+        }
+    }
+}
+
+fn bot_params_fields() -> Vec<String> {
+    match serde_json::to_value(BotParams::default()) {
+        Ok(Value::Object(map)) => map.keys().cloned().collect(),
+        _ => unreachable!("BotParams serializes to an object"),
+    }
+}
+
+/// Migrates `config` and parses it strictly: every BotParams field must be present after
+/// migration and unknown fields are rejected. Integer fields accept whole or rounded floats.
+pub fn bot_params_pair_from_config(config: &Value) -> Result<(BotParamsPair, Vec<String>), String> {
+    let (migrated, report) = migrate_bot_params_pair(config)?;
+    let mut pair =
+        serde_json::to_value(BotParamsPair::default()).expect("BotParamsPair serializes");
+    for pside in ["long", "short"] {
+        let params = migrated[pside]
+            .as_object()
+            .expect("migrate_bot_params_pair returns both sides");
+        for field in bot_params_fields() {
+            if !params.contains_key(&field) {
+                return Err(format!("missing parameter '{}.{}'", pside, field));
+            }
+        }
+        for (field, value) in params {
+            let path = format!("{}.{}", pside, field);
+            // counts like n_positions are often stored as floats
+            let value = match (&pair[pside][field], value.as_f64()) {
+                (Value::Number(old), Some(new)) if old.is_u64() && new >= 0.0 => {
+                    json!(new.round() as u64)
+                }
+                _ => value.clone(),
+            };
+            set_json_path(&mut pair, &path, value)?;
+        }
+    }
+    for &field in PAIR_FIELDS {
+        if let Some(value) = migrated.get(field) {
+            set_json_path(&mut pair, field, value.clone())?;
+        }
+    }
+    let pair: BotParamsPair = serde_json::from_value(pair).map_err(|e| e.to_string())?;
+    pair.validate_allocations()?;
+    pair.validate_ema_spans()?;
+    Ok((pair, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current_pair() -> Value {
+        let mut pair = serde_json::to_value(BotParamsPair::default()).unwrap();
+        for pside in ["long", "short"] {
+            pair[pside]["ema_span_0"] = json!(200.0);
+            pair[pside]["ema_span_1"] = json!(1000.0);
+            pair[pside]["unstuck_ema_span_0"] = json!(200.0);
+            pair[pside]["unstuck_ema_span_1"] = json!(1000.0);
+        }
+        pair
+    }
+
+    #[test]
+    fn current_configs_pass_through_unchanged() {
+        let pair = current_pair();
+        let (migrated, report) = migrate_bot_params_pair(&json!({ "bot": pair })).unwrap();
+        assert_eq!(migrated, pair);
+        assert!(report.is_empty(), "{:?}", report);
+        let (parsed, _) = bot_params_pair_from_config(&pair).unwrap();
+        assert_eq!(serde_json::to_value(parsed).unwrap(), pair);
+    }
+
+    #[test]
+    fn v6_configs_migrate_to_the_current_fields() {
+        let mut sides = current_pair();
+        for pside in ["long", "short"] {
+            let params = sides[pside].as_object_mut().unwrap();
+            for field in [
+                "entry_grid_double_down_factor",
+                "close_grid_min_markup",
+                "close_grid_qty_pct",
+                "unstuck_ema_span_0",
+                "unstuck_ema_span_1",
+                "close_trailing_anchor",
+                "wallet_exposure_limit",
+            ] {
+                params.remove(field);
+            }
+            params.insert("ddown_factor".to_string(), json!(0.8));
+            params.insert("min_markup".to_string(), json!(0.004));
+            params.insert("n_close_orders".to_string(), json!(3.6));
+        }
+        let config = json!({
+            "universal_live_config": sides,
+            "loss_allowance_pct": 0.02,
+            "TWE_long": 1.5,
+            "long_enabled": true,
+            "TWE_short": 1.0,
+            "short_enabled": false,
+            "n_longs": 3,
+            "n_shorts": 0,
+            "approved_symbols": ["BTCUSDT", "ETHUSDT"],
+        });
+        let (pair, report) = bot_params_pair_from_config(&config).unwrap();
+
+        assert_eq!(pair.long.entry_grid_double_down_factor, 0.8);
+        assert_eq!(pair.long.close_grid_min_markup, 0.004);
+        // n_close_orders rounds to 4 closes of a quarter each
+        assert_eq!(pair.long.close_grid_qty_pct, 0.25);
+        assert_eq!(pair.long.unstuck_ema_span_0, 200.0);
+        assert_eq!(pair.long.unstuck_ema_span_1, 1000.0);
+        assert_eq!(pair.long.unstuck_loss_allowance_pct, 0.02);
+        assert_eq!(pair.short.unstuck_loss_allowance_pct, 0.02);
+        assert_eq!(pair.long.total_wallet_exposure_limit, 1.5);
+        assert_eq!(pair.long.n_positions, 3);
+        assert_eq!(pair.long.wallet_exposure_limit, 0.5);
+        // a disabled side keeps no exposure; n_shorts == 0 meant every approved symbol
+        assert_eq!(pair.short.total_wallet_exposure_limit, 0.0);
+        assert_eq!(pair.short.n_positions, 2);
+        assert_eq!(pair.short.wallet_exposure_limit, 0.0);
+
+        for line in [
+            "universal_live_config -> bot",
+            "long.ddown_factor -> long.entry_grid_double_down_factor",
+            "long.n_close_orders -> long.close_grid_qty_pct",
+            "short.unstuck_ema_span_0 added as short.ema_span_0",
+            "long.close_trailing_anchor added as \"peak\"",
+            "loss_allowance_pct -> short.unstuck_loss_allowance_pct",
+            "TWE_short, short_enabled -> short.total_wallet_exposure_limit",
+            "n_shorts == 0, approved_symbols -> short.n_positions",
+        ] {
+            assert!(
+                report.iter().any(|l| l == line),
+                "{} not in {:?}",
+                line,
+                report
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_and_malformed_fields_are_rejected() {
+        let mut pair = current_pair();
+        pair["long"]["not_a_param"] = json!(1.0);
+        assert!(bot_params_pair_from_config(&pair).is_err());
+
+        let mut pair = current_pair();
+        pair["short"]["min_markup"] = json!("0.004");
+        assert_eq!(
+            migrate_bot_params_pair(&pair).unwrap_err(),
+            "'short.min_markup' is not a number"
+        );
+        assert_eq!(
+            migrate_bot_params_pair(&json!({ "long": {} })).unwrap_err(),
+            "missing 'short' parameters"
+        );
+    }
+}
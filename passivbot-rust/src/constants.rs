@@ -5,4 +5,5 @@ pub const VOLUME: usize = 3;
 
 pub const LONG: usize = 0;
 pub const SHORT: usize = 1;
-pub const NO_POS: usize = 2;
+
+pub const FUNDING_INTERVAL: usize = 480; // candles between funding settlements in backtests
@@ -1,9 +1,11 @@
 use crate::types::{
-    BotParams, ExchangeParams, Order, OrderType, Position, StateParams, TrailingPriceBundle,
+    BotParams, BotParamsPair, ExchangeParams, NextOrder, Order, OrderType, Position, Price,
+    StateParams, TrailingGridSplit, TrailingPriceBundle,
 };
 use crate::utils::{
-    calc_ema_price_ask, calc_ema_price_bid, calc_new_psize_pprice, calc_wallet_exposure,
-    calc_wallet_exposure_if_filled, cost_to_qty, interpolate, round_, round_dn, round_up,
+    calc_ema_price_ask, calc_ema_price_bid, calc_neutral_imbalance, calc_new_psize_pprice,
+    calc_wallet_exposure, calc_wallet_exposure_if_filled, cost_to_qty, interpolate, round_,
+    round_dn, round_price_for_side, round_qty, round_up,
 };
 
 pub fn calc_initial_entry_qty(
@@ -14,13 +16,13 @@ pub fn calc_initial_entry_qty(
 ) -> f64 {
     f64::max(
         calc_min_entry_qty(entry_price, &exchange_params),
-        round_(
+        round_qty(
             cost_to_qty(
                 balance * bot_params.wallet_exposure_limit * bot_params.entry_initial_qty_pct,
                 entry_price,
                 exchange_params.c_mult,
             ),
-            exchange_params.qty_step,
+            exchange_params,
         ),
     )
 }
@@ -68,10 +70,7 @@ pub fn calc_cropped_reentry_qty(
         ) - position_size_abs;
         (
             wallet_exposure_if_filled,
-            f64::max(
-                round_(entry_qty_abs, exchange_params.qty_step),
-                min_entry_qty,
-            ),
+            f64::max(round_qty(entry_qty_abs, exchange_params), min_entry_qty),
         )
     } else {
         (
@@ -91,14 +90,14 @@ pub fn calc_reentry_qty(
 ) -> f64 {
     f64::max(
         calc_min_entry_qty(entry_price, &exchange_params),
-        round_(
+        round_qty(
             f64::max(
                 position_size.abs() * double_down_factor,
                 cost_to_qty(balance, entry_price, exchange_params.c_mult)
                     * bot_params.wallet_exposure_limit
                     * bot_params.entry_initial_qty_pct,
             ),
-            exchange_params.qty_step,
+            exchange_params,
         ),
     )
 }
@@ -113,9 +112,10 @@ fn calc_reentry_price_bid(
     let multiplier =
         (wallet_exposure / bot_params.wallet_exposure_limit) * bot_params.entry_grid_spacing_weight;
     let reentry_price = f64::min(
-        round_dn(
+        round_price_for_side(
             position_price * (1.0 - bot_params.entry_grid_spacing_pct * (1.0 + multiplier)),
-            exchange_params.price_step,
+            true,
+            exchange_params,
         ),
         order_book_bid,
     );
@@ -136,9 +136,10 @@ fn calc_reentry_price_ask(
     let multiplier =
         (wallet_exposure / bot_params.wallet_exposure_limit) * bot_params.entry_grid_spacing_weight;
     let reentry_price = f64::max(
-        round_up(
+        round_price_for_side(
             position_price * (1.0 + bot_params.entry_grid_spacing_pct * (1.0 + multiplier)),
-            exchange_params.price_step,
+            false,
+            exchange_params,
         ),
         order_book_ask,
     );
@@ -154,18 +155,18 @@ pub fn calc_grid_entry_long(
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-) -> Order {
+) -> Option<Order> {
     if bot_params.wallet_exposure_limit == 0.0 || state_params.balance <= 0.0 {
-        return Order::default();
+        return None;
     }
     let initial_entry_price = calc_ema_price_bid(
-        exchange_params.price_step,
+        exchange_params,
         state_params.order_book.bid,
         state_params.ema_bands.lower,
         bot_params.entry_initial_ema_dist,
     );
     if initial_entry_price <= exchange_params.price_step {
-        return Order::default();
+        return None;
     }
     let initial_entry_qty = calc_initial_entry_qty(
         exchange_params,
@@ -174,20 +175,24 @@ pub fn calc_grid_entry_long(
         initial_entry_price,
     );
     if position.size == 0.0 {
-        return Order {
+        return Some(Order {
             qty: initial_entry_qty,
             price: initial_entry_price,
             order_type: OrderType::EntryInitialNormalLong,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     } else if position.size < initial_entry_qty * 0.8 {
-        return Order {
+        return Some(Order {
             qty: f64::max(
                 calc_min_entry_qty(initial_entry_price, &exchange_params),
                 round_dn(initial_entry_qty - position.size, exchange_params.qty_step),
             ),
             price: initial_entry_price,
             order_type: OrderType::EntryInitialPartialLong,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
     let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
@@ -196,7 +201,7 @@ pub fn calc_grid_entry_long(
         position.price,
     );
     if wallet_exposure >= bot_params.wallet_exposure_limit * 0.999 {
-        return Order::default();
+        return None;
     }
 
     // normal re-entry
@@ -208,7 +213,7 @@ pub fn calc_grid_entry_long(
         bot_params,
     );
     if reentry_price <= 0.0 {
-        return Order::default();
+        return None;
     }
     let reentry_qty = f64::max(
         calc_reentry_qty(
@@ -231,11 +236,13 @@ pub fn calc_grid_entry_long(
         reentry_price,
     );
     if reentry_qty_cropped < reentry_qty {
-        return Order {
+        return Some(Order {
             qty: reentry_qty_cropped,
             price: reentry_price,
             order_type: OrderType::EntryGridCroppedLong,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
     // preview next order to check if reentry qty is to be inflated
     let (psize_if_filled, pprice_if_filled) = calc_new_psize_pprice(
@@ -269,6 +276,7 @@ pub fn calc_grid_entry_long(
         &Position {
             size: psize_if_filled,
             price: pprice_if_filled,
+            ..Default::default()
         },
         wallet_exposure_if_filled,
         state_params.balance,
@@ -283,17 +291,21 @@ pub fn calc_grid_entry_long(
             &[wallet_exposure, wallet_exposure_if_filled],
             &[position.size, position.size + reentry_qty],
         ) - position.size;
-        Order {
-            qty: round_(new_entry_qty, exchange_params.qty_step),
+        Some(Order {
+            qty: round_qty(new_entry_qty, exchange_params),
             price: reentry_price,
             order_type: OrderType::EntryGridInflatedLong,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     } else {
-        Order {
+        Some(Order {
             qty: reentry_qty,
             price: reentry_price,
             order_type: OrderType::EntryGridNormalLong,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     }
 }
 
@@ -303,24 +315,15 @@ pub fn calc_next_entry_long(
     bot_params: &BotParams,
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
+) -> NextOrder {
+    let bot_params: &BotParams = &bot_params.with_position_cost_cap(state_params.balance);
     // determines whether trailing or grid order, returns Order
-    if bot_params.wallet_exposure_limit == 0.0 || state_params.balance <= 0.0 {
-        // no orders
-        return Order::default();
-    }
-    if bot_params.entry_trailing_grid_ratio >= 1.0 || bot_params.entry_trailing_grid_ratio <= -1.0 {
-        // return trailing only
-        return calc_trailing_entry_long(
-            &exchange_params,
-            &state_params,
-            &bot_params,
-            &position,
-            &trailing_price_bundle,
-        );
-    } else if bot_params.entry_trailing_grid_ratio == 0.0 {
-        // return grid only
-        return calc_grid_entry_long(&exchange_params, &state_params, &bot_params, &position);
+    if bot_params.wallet_exposure_limit == 0.0
+        || state_params.balance <= 0.0
+        || bot_params.equity_drawdown_triggered(state_params)
+    {
+        // no orders; a drawdown exit only closes
+        return NextOrder::NoOrder;
     }
     let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
@@ -329,62 +332,74 @@ pub fn calc_next_entry_long(
         position.price,
     );
     let wallet_exposure_ratio = wallet_exposure / bot_params.wallet_exposure_limit;
-    if bot_params.entry_trailing_grid_ratio > 0.0 {
-        // trailing first
-        if wallet_exposure_ratio < bot_params.entry_trailing_grid_ratio {
-            // return trailing order, but crop to max bot_params.wallet_exposure_limit * bot_params.entry_trailing_grid_ratio + 1%
-            if wallet_exposure == 0.0 {
-                calc_trailing_entry_long(
-                    &exchange_params,
-                    &state_params,
-                    &bot_params,
-                    &position,
-                    &trailing_price_bundle,
-                )
+    match bot_params.entry_trailing_grid_split() {
+        TrailingGridSplit::TrailingOnly => calc_trailing_entry_long(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &position,
+            &trailing_price_bundle,
+        ),
+        TrailingGridSplit::GridOnly => {
+            calc_grid_entry_long(&exchange_params, &state_params, &bot_params, &position).into()
+        }
+        TrailingGridSplit::TrailingFirst(trailing_share) => {
+            if wallet_exposure_ratio < trailing_share {
+                // return trailing order, but crop to max bot_params.wallet_exposure_limit * trailing_share + 1%
+                if wallet_exposure == 0.0 {
+                    calc_trailing_entry_long(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params,
+                        &position,
+                        &trailing_price_bundle,
+                    )
+                } else {
+                    let mut bot_params_modified = bot_params.clone();
+                    bot_params_modified.wallet_exposure_limit =
+                        bot_params.wallet_exposure_limit * trailing_share * 1.01;
+                    calc_trailing_entry_long(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params_modified,
+                        &position,
+                        &trailing_price_bundle,
+                    )
+                }
             } else {
-                let mut bot_params_modified = bot_params.clone();
-                bot_params_modified.wallet_exposure_limit =
-                    bot_params.wallet_exposure_limit * bot_params.entry_trailing_grid_ratio * 1.01;
-                calc_trailing_entry_long(
-                    &exchange_params,
-                    &state_params,
-                    &bot_params_modified,
-                    &position,
-                    &trailing_price_bundle,
-                )
+                // return grid order
+                calc_grid_entry_long(&exchange_params, &state_params, &bot_params, &position).into()
             }
-        } else {
-            // return grid order
-            calc_grid_entry_long(&exchange_params, &state_params, &bot_params, &position)
         }
-    } else {
-        // grid first
-        if wallet_exposure_ratio < 1.0 + bot_params.entry_trailing_grid_ratio {
-            // return grid order, but crop to max bot_params.wallet_exposure_limit * (1.0 + bot_params.entry_trailing_grid_ratio) + 1%
-            if wallet_exposure == 0.0 {
-                calc_grid_entry_long(&exchange_params, &state_params, &bot_params, &position)
-            } else {
-                let mut bot_params_modified = bot_params.clone();
-                if wallet_exposure != 0.0 {
-                    bot_params_modified.wallet_exposure_limit = bot_params.wallet_exposure_limit
-                        * (1.0 + bot_params.entry_trailing_grid_ratio)
-                        * 1.01;
+        TrailingGridSplit::GridFirst(grid_share) => {
+            if wallet_exposure_ratio < grid_share {
+                // return grid order, but crop to max bot_params.wallet_exposure_limit * grid_share + 1%
+                if wallet_exposure == 0.0 {
+                    calc_grid_entry_long(&exchange_params, &state_params, &bot_params, &position)
+                        .into()
+                } else {
+                    let mut bot_params_modified = bot_params.clone();
+                    if wallet_exposure != 0.0 {
+                        bot_params_modified.wallet_exposure_limit =
+                            bot_params.wallet_exposure_limit * grid_share * 1.01;
+                    }
+                    calc_grid_entry_long(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params_modified,
+                        &position,
+                    )
+                    .into()
                 }
-                calc_grid_entry_long(
+            } else {
+                calc_trailing_entry_long(
                     &exchange_params,
                     &state_params,
-                    &bot_params_modified,
+                    &bot_params,
                     &position,
+                    &trailing_price_bundle,
                 )
             }
-        } else {
-            calc_trailing_entry_long(
-                &exchange_params,
-                &state_params,
-                &bot_params,
-                &position,
-                &trailing_price_bundle,
-            )
         }
     }
 }
@@ -395,15 +410,15 @@ pub fn calc_trailing_entry_long(
     bot_params: &BotParams,
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
+) -> NextOrder {
     let initial_entry_price = calc_ema_price_bid(
-        exchange_params.price_step,
+        exchange_params,
         state_params.order_book.bid,
         state_params.ema_bands.lower,
         bot_params.entry_initial_ema_dist,
     );
     if initial_entry_price <= exchange_params.price_step {
-        return Order::default();
+        return NextOrder::NoOrder;
     }
     let initial_entry_qty = calc_initial_entry_qty(
         exchange_params,
@@ -413,20 +428,24 @@ pub fn calc_trailing_entry_long(
     );
     if position.size == 0.0 {
         // normal initial entry
-        return Order {
+        return NextOrder::Order(Order {
             qty: initial_entry_qty,
             price: initial_entry_price,
             order_type: OrderType::EntryInitialNormalLong,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     } else if position.size < initial_entry_qty * 0.8 {
-        return Order {
+        return NextOrder::Order(Order {
             qty: f64::max(
                 calc_min_entry_qty(initial_entry_price, &exchange_params),
                 round_dn(initial_entry_qty - position.size, exchange_params.qty_step),
             ),
             price: initial_entry_price,
             order_type: OrderType::EntryInitialPartialLong,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
     let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
@@ -435,7 +454,7 @@ pub fn calc_trailing_entry_long(
         position.price,
     );
     if wallet_exposure > bot_params.wallet_exposure_limit * 0.999 {
-        return Order::default();
+        return NextOrder::NoOrder;
     }
     let mut entry_triggered = false;
     let mut reentry_price = 0.0;
@@ -456,9 +475,10 @@ pub fn calc_trailing_entry_long(
             entry_triggered = true;
             reentry_price = f64::min(
                 state_params.order_book.bid,
-                round_dn(
+                round_price_for_side(
                     position.price * (1.0 - bot_params.entry_trailing_threshold_pct),
-                    exchange_params.price_step,
+                    true,
+                    exchange_params,
                 ),
             );
         } else {
@@ -472,22 +492,19 @@ pub fn calc_trailing_entry_long(
                 entry_triggered = true;
                 reentry_price = f64::min(
                     state_params.order_book.bid,
-                    round_dn(
+                    round_price_for_side(
                         position.price
                             * (1.0 - bot_params.entry_trailing_threshold_pct
                                 + bot_params.entry_trailing_retracement_pct),
-                        exchange_params.price_step,
+                        true,
+                        exchange_params,
                     ),
                 );
             }
         }
     }
     if !entry_triggered {
-        return Order {
-            qty: 0.0,
-            price: 0.0,
-            order_type: OrderType::EntryTrailingNormalLong,
-        };
+        return NextOrder::TrailingPending;
     }
     let reentry_qty = f64::max(
         calc_reentry_qty(
@@ -510,17 +527,21 @@ pub fn calc_trailing_entry_long(
         reentry_price,
     );
     if reentry_qty_cropped < reentry_qty {
-        Order {
+        NextOrder::Order(Order {
             qty: reentry_qty_cropped,
             price: reentry_price,
             order_type: OrderType::EntryTrailingCroppedLong,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     } else {
-        Order {
+        NextOrder::Order(Order {
             qty: reentry_qty,
             price: reentry_price,
             order_type: OrderType::EntryTrailingNormalLong,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     }
 }
 
@@ -529,18 +550,18 @@ pub fn calc_grid_entry_short(
     state_params: &StateParams,
     bot_params: &BotParams,
     position: &Position,
-) -> Order {
+) -> Option<Order> {
     if bot_params.wallet_exposure_limit == 0.0 || state_params.balance <= 0.0 {
-        return Order::default();
+        return None;
     }
     let initial_entry_price = calc_ema_price_ask(
-        exchange_params.price_step,
+        exchange_params,
         state_params.order_book.ask,
         state_params.ema_bands.upper,
         bot_params.entry_initial_ema_dist,
     );
     if initial_entry_price <= exchange_params.price_step {
-        return Order::default();
+        return None;
     }
     let initial_entry_qty = calc_initial_entry_qty(
         exchange_params,
@@ -550,13 +571,15 @@ pub fn calc_grid_entry_short(
     );
     let position_size_abs = position.size.abs();
     if position_size_abs == 0.0 {
-        return Order {
+        return Some(Order {
             qty: -initial_entry_qty,
             price: initial_entry_price,
             order_type: OrderType::EntryInitialNormalShort,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     } else if position_size_abs < initial_entry_qty * 0.8 {
-        return Order {
+        return Some(Order {
             qty: -f64::max(
                 calc_min_entry_qty(initial_entry_price, &exchange_params),
                 round_dn(
@@ -566,7 +589,9 @@ pub fn calc_grid_entry_short(
             ),
             price: initial_entry_price,
             order_type: OrderType::EntryInitialPartialShort,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
     let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
@@ -575,7 +600,7 @@ pub fn calc_grid_entry_short(
         position.price,
     );
     if wallet_exposure >= bot_params.wallet_exposure_limit * 0.999 {
-        return Order::default();
+        return None;
     }
 
     // normal re-entry
@@ -587,7 +612,7 @@ pub fn calc_grid_entry_short(
         bot_params,
     );
     if reentry_price <= 0.0 {
-        return Order::default();
+        return None;
     }
     let reentry_qty = f64::max(
         calc_reentry_qty(
@@ -610,11 +635,13 @@ pub fn calc_grid_entry_short(
         reentry_price,
     );
     if reentry_qty_cropped < reentry_qty {
-        return Order {
+        return Some(Order {
             qty: -reentry_qty_cropped,
             price: reentry_price,
             order_type: OrderType::EntryGridCroppedShort,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
     // preview next order to check if reentry qty is to be inflated
     let (psize_if_filled, pprice_if_filled) = calc_new_psize_pprice(
@@ -648,6 +675,7 @@ pub fn calc_grid_entry_short(
         &Position {
             size: psize_if_filled,
             price: pprice_if_filled,
+            ..Default::default()
         },
         wallet_exposure_if_filled,
         state_params.balance,
@@ -662,17 +690,21 @@ pub fn calc_grid_entry_short(
             &[wallet_exposure, wallet_exposure_if_filled],
             &[position_size_abs, position_size_abs + reentry_qty],
         ) - position_size_abs;
-        Order {
-            qty: -round_(new_entry_qty, exchange_params.qty_step),
+        Some(Order {
+            qty: -round_qty(new_entry_qty, exchange_params),
             price: reentry_price,
             order_type: OrderType::EntryGridInflatedShort,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     } else {
-        Order {
+        Some(Order {
             qty: -reentry_qty,
             price: reentry_price,
             order_type: OrderType::EntryGridNormalShort,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     }
 }
 
@@ -682,15 +714,15 @@ pub fn calc_trailing_entry_short(
     bot_params: &BotParams,
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
+) -> NextOrder {
     let initial_entry_price = calc_ema_price_ask(
-        exchange_params.price_step,
+        exchange_params,
         state_params.order_book.ask,
         state_params.ema_bands.upper,
         bot_params.entry_initial_ema_dist,
     );
     if initial_entry_price <= exchange_params.price_step {
-        return Order::default();
+        return NextOrder::NoOrder;
     }
     let initial_entry_qty = calc_initial_entry_qty(
         exchange_params,
@@ -701,13 +733,15 @@ pub fn calc_trailing_entry_short(
     let position_size_abs = position.size.abs();
     if position_size_abs == 0.0 {
         // normal initial entry
-        return Order {
+        return NextOrder::Order(Order {
             qty: -initial_entry_qty,
             price: initial_entry_price,
             order_type: OrderType::EntryInitialNormalShort,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     } else if position_size_abs < initial_entry_qty * 0.8 {
-        return Order {
+        return NextOrder::Order(Order {
             qty: -f64::max(
                 calc_min_entry_qty(initial_entry_price, &exchange_params),
                 round_dn(
@@ -717,7 +751,9 @@ pub fn calc_trailing_entry_short(
             ),
             price: initial_entry_price,
             order_type: OrderType::EntryInitialPartialShort,
-        };
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        });
     }
     let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
@@ -726,7 +762,7 @@ pub fn calc_trailing_entry_short(
         position.price,
     );
     if wallet_exposure > bot_params.wallet_exposure_limit * 0.999 {
-        return Order::default();
+        return NextOrder::NoOrder;
     }
     let mut entry_triggered = false;
     let mut reentry_price = 0.0;
@@ -747,9 +783,10 @@ pub fn calc_trailing_entry_short(
             entry_triggered = true;
             reentry_price = f64::max(
                 state_params.order_book.ask,
-                round_up(
+                round_price_for_side(
                     position.price * (1.0 + bot_params.entry_trailing_threshold_pct),
-                    exchange_params.price_step,
+                    false,
+                    exchange_params,
                 ),
             );
         } else {
@@ -763,22 +800,19 @@ pub fn calc_trailing_entry_short(
                 entry_triggered = true;
                 reentry_price = f64::max(
                     state_params.order_book.ask,
-                    round_up(
+                    round_price_for_side(
                         position.price
                             * (1.0 + bot_params.entry_trailing_threshold_pct
                                 - bot_params.entry_trailing_retracement_pct),
-                        exchange_params.price_step,
+                        false,
+                        exchange_params,
                     ),
                 );
             }
         }
     }
     if !entry_triggered {
-        return Order {
-            qty: 0.0,
-            price: 0.0,
-            order_type: OrderType::EntryTrailingNormalShort,
-        };
+        return NextOrder::TrailingPending;
     }
     let reentry_qty = f64::max(
         calc_reentry_qty(
@@ -801,17 +835,21 @@ pub fn calc_trailing_entry_short(
         reentry_price,
     );
     if reentry_qty_cropped < reentry_qty {
-        Order {
+        NextOrder::Order(Order {
             qty: -reentry_qty_cropped,
             price: reentry_price,
             order_type: OrderType::EntryTrailingCroppedShort,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     } else {
-        Order {
+        NextOrder::Order(Order {
             qty: -reentry_qty,
             price: reentry_price,
             order_type: OrderType::EntryTrailingNormalShort,
-        }
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
     }
 }
 
@@ -821,24 +859,15 @@ pub fn calc_next_entry_short(
     bot_params: &BotParams,
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
-) -> Order {
+) -> NextOrder {
+    let bot_params: &BotParams = &bot_params.with_position_cost_cap(state_params.balance);
     // determines whether trailing or grid order, returns Order
-    if bot_params.wallet_exposure_limit == 0.0 || state_params.balance <= 0.0 {
-        // no orders
-        return Order::default();
-    }
-    if bot_params.entry_trailing_grid_ratio >= 1.0 || bot_params.entry_trailing_grid_ratio <= -1.0 {
-        // return trailing only
-        return calc_trailing_entry_short(
-            &exchange_params,
-            &state_params,
-            &bot_params,
-            &position,
-            &trailing_price_bundle,
-        );
-    } else if bot_params.entry_trailing_grid_ratio == 0.0 {
-        // return grid only
-        return calc_grid_entry_short(&exchange_params, &state_params, &bot_params, &position);
+    if bot_params.wallet_exposure_limit == 0.0
+        || state_params.balance <= 0.0
+        || bot_params.equity_drawdown_triggered(state_params)
+    {
+        // no orders; a drawdown exit only closes
+        return NextOrder::NoOrder;
     }
     let wallet_exposure = calc_wallet_exposure(
         exchange_params.c_mult,
@@ -847,62 +876,75 @@ pub fn calc_next_entry_short(
         position.price,
     );
     let wallet_exposure_ratio = wallet_exposure / bot_params.wallet_exposure_limit;
-    if bot_params.entry_trailing_grid_ratio > 0.0 {
-        // trailing first
-        if wallet_exposure_ratio < bot_params.entry_trailing_grid_ratio {
-            if wallet_exposure == 0.0 {
-                calc_trailing_entry_short(
-                    &exchange_params,
-                    &state_params,
-                    &bot_params,
-                    &position,
-                    &trailing_price_bundle,
-                )
+    match bot_params.entry_trailing_grid_split() {
+        TrailingGridSplit::TrailingOnly => calc_trailing_entry_short(
+            &exchange_params,
+            &state_params,
+            &bot_params,
+            &position,
+            &trailing_price_bundle,
+        ),
+        TrailingGridSplit::GridOnly => {
+            calc_grid_entry_short(&exchange_params, &state_params, &bot_params, &position).into()
+        }
+        TrailingGridSplit::TrailingFirst(trailing_share) => {
+            if wallet_exposure_ratio < trailing_share {
+                if wallet_exposure == 0.0 {
+                    calc_trailing_entry_short(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params,
+                        &position,
+                        &trailing_price_bundle,
+                    )
+                } else {
+                    // return trailing order, but crop to max bot_params.wallet_exposure_limit * trailing_share + 1%
+                    let mut bot_params_modified = bot_params.clone();
+                    bot_params_modified.wallet_exposure_limit =
+                        bot_params.wallet_exposure_limit * trailing_share * 1.01;
+                    calc_trailing_entry_short(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params_modified,
+                        &position,
+                        &trailing_price_bundle,
+                    )
+                }
             } else {
-                // return trailing order, but crop to max bot_params.wallet_exposure_limit * bot_params.entry_trailing_grid_ratio + 1%
-                let mut bot_params_modified = bot_params.clone();
-                bot_params_modified.wallet_exposure_limit =
-                    bot_params.wallet_exposure_limit * bot_params.entry_trailing_grid_ratio * 1.01;
-                calc_trailing_entry_short(
-                    &exchange_params,
-                    &state_params,
-                    &bot_params_modified,
-                    &position,
-                    &trailing_price_bundle,
-                )
+                // return grid order
+                calc_grid_entry_short(&exchange_params, &state_params, &bot_params, &position)
+                    .into()
             }
-        } else {
-            // return grid order
-            calc_grid_entry_short(&exchange_params, &state_params, &bot_params, &position)
         }
-    } else {
-        // grid first
-        if wallet_exposure_ratio < 1.0 + bot_params.entry_trailing_grid_ratio {
-            // return grid order, but crop to max bot_params.wallet_exposure_limit * (1.0 + bot_params.entry_trailing_grid_ratio) + 1%
-            if wallet_exposure == 0.0 {
-                calc_grid_entry_short(&exchange_params, &state_params, &bot_params, &position)
-            } else {
-                let mut bot_params_modified = bot_params.clone();
-                if wallet_exposure != 0.0 {
-                    bot_params_modified.wallet_exposure_limit = bot_params.wallet_exposure_limit
-                        * (1.0 + bot_params.entry_trailing_grid_ratio)
-                        * 1.01;
+        TrailingGridSplit::GridFirst(grid_share) => {
+            if wallet_exposure_ratio < grid_share {
+                // return grid order, but crop to max bot_params.wallet_exposure_limit * grid_share + 1%
+                if wallet_exposure == 0.0 {
+                    calc_grid_entry_short(&exchange_params, &state_params, &bot_params, &position)
+                        .into()
+                } else {
+                    let mut bot_params_modified = bot_params.clone();
+                    if wallet_exposure != 0.0 {
+                        bot_params_modified.wallet_exposure_limit =
+                            bot_params.wallet_exposure_limit * grid_share * 1.01;
+                    }
+                    calc_grid_entry_short(
+                        &exchange_params,
+                        &state_params,
+                        &bot_params_modified,
+                        &position,
+                    )
+                    .into()
                 }
-                calc_grid_entry_short(
+            } else {
+                calc_trailing_entry_short(
                     &exchange_params,
                     &state_params,
-                    &bot_params_modified,
+                    &bot_params,
                     &position,
+                    &trailing_price_bundle,
                 )
             }
-        } else {
-            calc_trailing_entry_short(
-                &exchange_params,
-                &state_params,
-                &bot_params,
-                &position,
-                &trailing_price_bundle,
-            )
         }
     }
 }
@@ -914,7 +956,9 @@ pub fn calc_entries_long(
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
 ) -> Vec<Order> {
+    let bot_params: &BotParams = &bot_params.with_position_cost_cap(state_params.balance);
     let mut entries = Vec::<Order>::new();
+    let mut previous_price = None::<Price>;
     let mut psize = position.size;
     let mut pprice = position.price;
     let mut bid = state_params.order_book.bid;
@@ -922,26 +966,34 @@ pub fn calc_entries_long(
         let position_mod = Position {
             size: psize,
             price: pprice,
+            ..Default::default()
         };
         let mut state_params_mod = state_params.clone();
         state_params_mod.order_book.bid = bid;
-        let entry = calc_next_entry_long(
+        let entry = match calc_next_entry_long(
             exchange_params,
             &state_params_mod,
             bot_params,
             &position_mod,
             &trailing_price_bundle,
-        );
-        if entry.qty == 0.0 {
-            break;
-        }
-        if !entries.is_empty() {
+        )
+        .order()
+        {
+            Some(entry) if entry.qty != 0.0 => entry,
+            _ => break,
+        };
+        let price = match Price::from_f64(entry.price, exchange_params.price_step) {
+            Some(price) => price,
+            None => break,
+        };
+        if let Some(previous_price) = previous_price {
             if entry.order_type == OrderType::EntryTrailingNormalLong
                 || entry.order_type == OrderType::EntryTrailingCroppedLong
             {
                 break;
             }
-            if entries[entries.len() - 1].price == entry.price {
+            // compared in ticks; float equality let rungs one ulp apart through
+            if previous_price == price {
                 break;
             }
         }
@@ -953,6 +1005,7 @@ pub fn calc_entries_long(
             exchange_params.qty_step,
         );
         bid = bid.min(entry.price);
+        previous_price = Some(price);
         entries.push(entry);
     }
     entries
@@ -965,7 +1018,9 @@ pub fn calc_entries_short(
     position: &Position,
     trailing_price_bundle: &TrailingPriceBundle,
 ) -> Vec<Order> {
+    let bot_params: &BotParams = &bot_params.with_position_cost_cap(state_params.balance);
     let mut entries = Vec::<Order>::new();
+    let mut previous_price = None::<Price>;
     let mut psize = position.size;
     let mut pprice = position.price;
     let mut ask = state_params.order_book.ask;
@@ -973,26 +1028,34 @@ pub fn calc_entries_short(
         let position_mod = Position {
             size: psize,
             price: pprice,
+            ..Default::default()
         };
         let mut state_params_mod = state_params.clone();
         state_params_mod.order_book.ask = ask;
-        let entry = calc_next_entry_short(
+        let entry = match calc_next_entry_short(
             exchange_params,
             &state_params_mod,
             bot_params,
             &position_mod,
             &trailing_price_bundle,
-        );
-        if entry.qty == 0.0 {
-            break;
-        }
-        if !entries.is_empty() {
+        )
+        .order()
+        {
+            Some(entry) if entry.qty != 0.0 => entry,
+            _ => break,
+        };
+        let price = match Price::from_f64(entry.price, exchange_params.price_step) {
+            Some(price) => price,
+            None => break,
+        };
+        if let Some(previous_price) = previous_price {
             if entry.order_type == OrderType::EntryTrailingNormalShort
                 || entry.order_type == OrderType::EntryTrailingCroppedShort
             {
                 break;
             }
-            if entries[entries.len() - 1].price == entry.price {
+            // compared in ticks; float equality let rungs one ulp apart through
+            if previous_price == price {
                 break;
             }
         }
@@ -1004,7 +1067,243 @@ pub fn calc_entries_short(
             exchange_params.qty_step,
         );
         ask = ask.max(entry.price);
+        previous_price = Some(price);
         entries.push(entry);
     }
     entries
 }
+
+/// In neutral mode, entry at price sizing the lagging side of a coin's pair up to the
+/// leading side once they drift apart by more than the lagging side's
+/// rebalance_threshold_pct. None within tolerance or if the gap is below the minimum qty.
+pub fn calc_neutral_rebalance_entry(
+    exchange_params: &ExchangeParams,
+    bot_params_pair: &BotParamsPair,
+    position_long: &Position,
+    position_short: &Position,
+    price: f64,
+) -> Option<Order> {
+    let imbalance = calc_neutral_imbalance(position_long.size, position_short.size);
+    let (lagging_params, order_type, sign) = if imbalance < 0.0 {
+        (&bot_params_pair.long, OrderType::EntryRebalanceLong, 1.0)
+    } else {
+        (&bot_params_pair.short, OrderType::EntryRebalanceShort, -1.0)
+    };
+    if imbalance.abs() <= lagging_params.rebalance_threshold_pct {
+        return None;
+    }
+    let gap = round_(
+        (position_long.size.abs() - position_short.size.abs()).abs(),
+        exchange_params.qty_step,
+    );
+    if gap < calc_min_entry_qty(price, exchange_params) {
+        return None;
+    }
+    Some(Order {
+        qty: sign * gap,
+        price,
+        order_type,
+        iceberg_qty: None,
+        min_fill_qty: 0.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{EMABands, OrderBook};
+
+    fn golden_exchange_params() -> ExchangeParams {
+        ExchangeParams {
+            qty_step: 0.001,
+            price_step: 0.01,
+            min_qty: 0.001,
+            min_cost: 5.0,
+            c_mult: 1.0,
+            ..Default::default()
+        }
+    }
+
+    fn golden_state_params() -> StateParams {
+        StateParams {
+            balance: 1000.0,
+            order_book: OrderBook::new(100.0, 100.01),
+            ema_bands: EMABands {
+                upper: 101.0,
+                lower: 99.0,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Entries with no feature added since the baseline enabled, whose ladders
+    /// golden_entries_* pin to the baseline's output for the same inputs.
+    fn golden_bot_params(entry_trailing_grid_ratio: f64) -> BotParams {
+        BotParams {
+            enforce_exposure_limit: true,
+            entry_grid_double_down_factor: 1.0,
+            entry_grid_spacing_weight: 0.5,
+            entry_grid_spacing_pct: 0.03,
+            entry_initial_ema_dist: 0.002,
+            entry_initial_qty_pct: 0.02,
+            entry_trailing_double_down_factor: 1.0,
+            entry_trailing_retracement_pct: 0.01,
+            entry_trailing_grid_ratio,
+            entry_trailing_threshold_pct: 0.02,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        }
+    }
+
+    fn assert_ladder(orders: Vec<Order>, expected: &[(f64, f64, OrderType)]) {
+        let orders: Vec<_> = orders
+            .iter()
+            .map(|order| (order.qty, order.price, order.order_type))
+            .collect();
+        assert_eq!(orders, expected);
+    }
+
+    #[test]
+    fn golden_entries_long() {
+        let entries = |ratio: f64, size: f64, trailing_price_bundle: &TrailingPriceBundle| {
+            calc_entries_long(
+                &golden_exchange_params(),
+                &golden_state_params(),
+                &golden_bot_params(ratio),
+                &Position {
+                    size,
+                    price: 101.0,
+                    ..Default::default()
+                },
+                trailing_price_bundle,
+            )
+        };
+        let no_trailing = TrailingPriceBundle::default();
+        assert_ladder(
+            entries(0.0, 0.0, &no_trailing),
+            &[
+                (0.101, 98.8, OrderType::EntryInitialNormalLong),
+                (0.104, 95.8, OrderType::EntryGridNormalLong),
+                (0.205, 94.3, OrderType::EntryGridNormalLong),
+                (0.41, 92.8, OrderType::EntryGridNormalLong),
+                (0.82, 91.24, OrderType::EntryGridNormalLong),
+                (1.64, 89.56, OrderType::EntryGridNormalLong),
+                (2.294, 87.61, OrderType::EntryGridCroppedLong),
+            ],
+        );
+        assert_ladder(
+            entries(0.0, 0.5, &no_trailing),
+            &[
+                (0.5, 97.81, OrderType::EntryGridNormalLong),
+                (1.0, 96.12, OrderType::EntryGridNormalLong),
+                (2.0, 94.25, OrderType::EntryGridNormalLong),
+                (1.26, 92.01, OrderType::EntryGridCroppedLong),
+            ],
+        );
+        // bottomed at 97 and bounced past entry_trailing_retracement_pct
+        let bounced = TrailingPriceBundle {
+            min_since_open: 97.0,
+            max_since_min: 98.5,
+            ..Default::default()
+        };
+        assert_ladder(
+            entries(0.5, 0.5, &bounced),
+            &[(0.5, 99.99, OrderType::EntryTrailingNormalLong)],
+        );
+    }
+
+    #[test]
+    fn golden_entries_short() {
+        let entries = |ratio: f64, size: f64, trailing_price_bundle: &TrailingPriceBundle| {
+            calc_entries_short(
+                &golden_exchange_params(),
+                &golden_state_params(),
+                &golden_bot_params(ratio),
+                &Position {
+                    size,
+                    price: 99.0,
+                    ..Default::default()
+                },
+                trailing_price_bundle,
+            )
+        };
+        let no_trailing = TrailingPriceBundle::default();
+        assert_ladder(
+            entries(0.0, 0.0, &no_trailing),
+            &[
+                (-0.099, 101.21, OrderType::EntryInitialNormalShort),
+                (-0.099, 104.28, OrderType::EntryGridNormalShort),
+                (-0.198, 105.9, OrderType::EntryGridNormalShort),
+                (-0.396, 107.59, OrderType::EntryGridNormalShort),
+                (-0.792, 109.41, OrderType::EntryGridNormalShort),
+                (-1.584, 111.47, OrderType::EntryGridNormalShort),
+                (-1.341, 114.01, OrderType::EntryGridCroppedShort),
+            ],
+        );
+        assert_ladder(
+            entries(0.0, -0.5, &no_trailing),
+            &[
+                (-0.5, 102.12, OrderType::EntryGridNormalShort),
+                (-1.0, 103.89, OrderType::EntryGridNormalShort),
+                (-2.79, 105.92, OrderType::EntryGridInflatedShort),
+            ],
+        );
+        // peaked at 103 and retraced past entry_trailing_retracement_pct
+        let retraced = TrailingPriceBundle {
+            max_since_open: 103.0,
+            min_since_max: 101.5,
+            ..Default::default()
+        };
+        assert_ladder(
+            entries(0.5, -0.5, &retraced),
+            &[(-0.5, 100.01, OrderType::EntryTrailingNormalShort)],
+        );
+    }
+
+    #[test]
+    fn neutral_rebalance_entries_size_the_lagging_side_up() {
+        let exchange_params = golden_exchange_params();
+        let bot_params_pair = BotParamsPair {
+            long: BotParams {
+                rebalance_threshold_pct: 0.1,
+                ..Default::default()
+            },
+            short: BotParams {
+                rebalance_threshold_pct: 0.2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let entry = |long_size: f64, short_size: f64| {
+            calc_neutral_rebalance_entry(
+                &exchange_params,
+                &bot_params_pair,
+                &Position {
+                    size: long_size,
+                    price: 100.0,
+                    ..Default::default()
+                },
+                &Position {
+                    size: short_size,
+                    price: 100.0,
+                    ..Default::default()
+                },
+                100.0,
+            )
+            .map(|entry| (entry.qty, entry.price, entry.order_type))
+        };
+        // the lagging side's tolerance applies: 15% is past the long's 10%, within the short's 20%
+        assert_eq!(
+            entry(0.85, -1.0),
+            Some((0.15, 100.0, OrderType::EntryRebalanceLong))
+        );
+        assert_eq!(entry(1.0, -0.85), None);
+        assert_eq!(
+            entry(1.0, -0.75),
+            Some((-0.25, 100.0, OrderType::EntryRebalanceShort))
+        );
+        assert_eq!(entry(1.0, -1.0), None);
+        // a gap worth less than min_cost is left alone
+        assert_eq!(entry(0.03, -0.0), None);
+    }
+}
@@ -0,0 +1,491 @@
+use crate::constants::{LONG, SHORT};
+use crate::entries::calc_min_entry_qty;
+use crate::types::{BotParams, ExchangeParams, Order, Position, StateParams};
+use crate::utils::{calc_new_psize_pprice, calc_wallet_exposure, is_on_step, round_};
+
+// entries may be cropped to 1% over wallet_exposure_limit, see calc_cropped_reentry_qty
+pub const WALLET_EXPOSURE_LEEWAY: f64 = 1.01;
+
+/// Rules every order emitted for pside must satisfy before it is submitted: price and qty
+/// finite, price positive and qty nonzero, both on their exchange steps, and an order type
+/// of pside. Closes must reduce the position and be no larger than it. Entries must keep
+/// wallet exposure within wallet_exposure_limit once filled, with the calculators' 1% crop
+/// leeway and up to one qty_step of rounding; an entry at the exchange minimum qty is
+/// exempt, as it cannot be smaller.
+/// position is the one the order applies to; Err names the first rule broken.
+pub fn check_order_invariants(
+    order: &Order,
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    pside: usize,
+) -> Result<(), String> {
+    let fail = |rule: &str| Err(format!("{} {:?}: {}", pside_name(pside), order, rule));
+    if !(order.price.is_finite() && order.price > 0.0) {
+        return fail("price must be positive and finite");
+    }
+    if !order.qty.is_finite() || order.qty == 0.0 {
+        return fail("qty must be nonzero and finite");
+    }
+    if !is_on_step(order.qty, exchange_params.qty_step) {
+        return fail("qty is not a multiple of qty_step");
+    }
+    if !is_on_step(order.price, exchange_params.price_step) {
+        return fail("price is not a multiple of price_step");
+    }
+    if order.order_type.pside() != pside {
+        return fail("order type is of the other side");
+    }
+    // long entries and short closes buy
+    let buys = (pside == LONG) != order.order_type.is_close();
+    if buys != (order.qty > 0.0) {
+        return fail("qty has the wrong sign for its order type");
+    }
+    let tolerance = exchange_params.qty_step * 0.5;
+    if order.order_type.is_close() {
+        let reducible = match pside {
+            LONG => position.size.max(0.0),
+            _ => (-position.size).max(0.0),
+        };
+        if order.qty.abs() > reducible + tolerance {
+            return fail("close exceeds position size");
+        }
+        return Ok(());
+    }
+    let exceeds_limit = |qty: f64| {
+        let (size_if_filled, price_if_filled) = calc_new_psize_pprice(
+            position.size,
+            position.price,
+            qty,
+            order.price,
+            exchange_params.qty_step,
+        );
+        calc_wallet_exposure(
+            exchange_params.c_mult,
+            state_params.balance,
+            size_if_filled.abs(),
+            price_if_filled,
+        ) > bot_params.wallet_exposure_limit * WALLET_EXPOSURE_LEEWAY + f64::EPSILON
+    };
+    // entry qtys are rounded to the nearest step; an overshoot one step less would undo is
+    // rounding, not sizing
+    let step_less = order.qty - exchange_params.qty_step.copysign(order.qty);
+    if exceeds_limit(order.qty)
+        && exceeds_limit(step_less)
+        && order.qty.abs() > calc_min_entry_qty(order.price, exchange_params) + tolerance
+    {
+        return fail("entry exceeds wallet_exposure_limit once filled");
+    }
+    Ok(())
+}
+
+/// check_order_invariants over a ladder, each order checked against the position left by
+/// filling all before it, as calc_entries_* and calc_closes_* lay their orders out.
+pub fn check_ladder_invariants(
+    orders: &[Order],
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    pside: usize,
+) -> Result<(), String> {
+    let mut position = *position;
+    for (i, order) in orders.iter().enumerate() {
+        check_order_invariants(
+            order,
+            exchange_params,
+            state_params,
+            bot_params,
+            &position,
+            pside,
+        )
+        .map_err(|e| format!("order {}: {}", i, e))?;
+        position = if order.order_type.is_close() {
+            position.resized(round_(position.size + order.qty, exchange_params.qty_step))
+        } else {
+            let (size, price) = calc_new_psize_pprice(
+                position.size,
+                position.price,
+                order.qty,
+                order.price,
+                exchange_params.qty_step,
+            );
+            Position {
+                size,
+                price,
+                ..position
+            }
+        };
+    }
+    Ok(())
+}
+
+fn pside_name(pside: usize) -> &'static str {
+    match pside {
+        SHORT => "short",
+        _ => "long",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::closes::{calc_closes_long, calc_closes_short};
+    use crate::entries::{calc_entries_long, calc_entries_short};
+    use crate::types::{EMABands, OrderBook, OrderType, TrailingPriceBundle};
+    use proptest::prelude::*;
+
+    struct Case {
+        exchange_params: ExchangeParams,
+        state_params: StateParams,
+        bot_params: BotParams,
+        position: Position,
+        trailing_price_bundle: TrailingPriceBundle,
+    }
+
+    impl std::fmt::Debug for Case {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+                self.exchange_params,
+                self.state_params,
+                self.bot_params,
+                self.position,
+                self.trailing_price_bundle
+            )
+        }
+    }
+
+    prop_compose! {
+        fn exchange_params()(
+            qty_step in prop::sample::select(vec![0.001, 0.01, 1.0]),
+            price_step in prop::sample::select(vec![0.0001, 0.01, 0.1]),
+            min_cost in 0.0..10.0,
+        ) -> ExchangeParams {
+            ExchangeParams {
+                qty_step,
+                price_step,
+                min_qty: qty_step,
+                min_cost,
+                c_mult: 1.0,
+                ..Default::default()
+            }
+        }
+    }
+
+    prop_compose! {
+        fn close_params()(
+            close_grid_markup_range in 0.0..0.05,
+            close_grid_min_markup in 0.001..0.02,
+            close_grid_qty_pct in 0.05..1.0,
+            close_recover_funding in any::<bool>(),
+            close_trailing_grid_ratio in -1.0..1.0,
+            close_trailing_qty_pct in 0.05..1.0,
+            close_trailing_retracement_pct in 0.001..0.02,
+            close_trailing_threshold_pct in -0.01..0.05,
+            enforce_exposure_limit in any::<bool>(),
+            wallet_exposure_limit in 0.1..2.0,
+        ) -> BotParams {
+            BotParams {
+                close_grid_markup_range,
+                close_grid_min_markup,
+                close_grid_qty_pct,
+                close_recover_funding,
+                close_trailing_grid_ratio,
+                close_trailing_qty_pct,
+                close_trailing_retracement_pct,
+                close_trailing_threshold_pct,
+                enforce_exposure_limit,
+                n_positions: 1,
+                total_wallet_exposure_limit: 1.0,
+                wallet_exposure_limit,
+                ..Default::default()
+            }
+        }
+    }
+
+    // over the ranges configs use in practice
+    prop_compose! {
+        fn bot_params()(
+            close_params in close_params(),
+            entry_grid_double_down_factor in 0.1..3.0,
+            entry_grid_spacing_pct in 0.005..0.1,
+            entry_grid_spacing_weight in 0.0..2.0,
+            entry_initial_ema_dist in -0.01..0.01,
+            entry_initial_qty_pct in 0.005..0.1,
+            entry_trailing_double_down_factor in 0.1..3.0,
+            entry_trailing_grid_ratio in -1.0..1.0,
+            entry_trailing_retracement_pct in 0.001..0.02,
+            entry_trailing_threshold_pct in -0.01..0.05,
+        ) -> BotParams {
+            BotParams {
+                entry_grid_double_down_factor,
+                entry_grid_spacing_pct,
+                entry_grid_spacing_weight,
+                entry_initial_ema_dist,
+                entry_initial_qty_pct,
+                entry_trailing_double_down_factor,
+                entry_trailing_grid_ratio,
+                entry_trailing_retracement_pct,
+                entry_trailing_threshold_pct,
+                ..close_params
+            }
+        }
+    }
+
+    // a third of the positions are flat; the others are up to 20% over wallet_exposure_limit
+    // and have paid up to five times their cost in funding, as on a small position
+    prop_compose! {
+        fn case(pside: usize)(
+            exchange_params in exchange_params(),
+            price_in_steps in 10000.0..200000.0,
+            balance in 1000.0..100000.0,
+            ema_bands in (1.0..1.05, 0.95..1.0),
+            bot_params in bot_params(),
+            position in prop::option::weighted(2.0 / 3.0, (0.9..1.1, 0.01..1.2, -0.05..5.0)),
+            trailing in (0.9..1.0, 0.95..1.05, 1.0..1.1, 0.95..1.05),
+        ) -> Case {
+            let ExchangeParams { qty_step, price_step, .. } = exchange_params;
+            let price = round_(price_in_steps * price_step, price_step);
+            let state_params = StateParams {
+                balance,
+                order_book: OrderBook::new(price, price + price_step),
+                ema_bands: EMABands {
+                    upper: round_(price * ema_bands.0, price_step),
+                    lower: round_(price * ema_bands.1, price_step),
+                },
+                ..Default::default()
+            };
+            let position = match position {
+                None => Position::default(),
+                Some((price_ratio, exposure_ratio, funding_ratio)) => {
+                    let position_price = round_(price * price_ratio, price_step);
+                    let exposure = bot_params.wallet_exposure_limit * exposure_ratio;
+                    let size = round_(exposure * balance / position_price, qty_step).max(qty_step);
+                    Position {
+                        size: if pside == LONG { size } else { -size },
+                        price: position_price,
+                        accrued_funding: size * position_price * funding_ratio,
+                    }
+                }
+            };
+            let trailing_price_bundle = TrailingPriceBundle {
+                min_since_open: price * trailing.0,
+                max_since_min: price * trailing.1,
+                max_since_open: price * trailing.2,
+                min_since_max: price * trailing.3,
+                ..Default::default()
+            };
+            Case {
+                exchange_params,
+                state_params,
+                bot_params,
+                position,
+                trailing_price_bundle,
+            }
+        }
+    }
+
+    fn ladders(case: &Case, pside: usize) -> (Vec<Order>, Vec<Order>) {
+        let Case {
+            exchange_params,
+            state_params,
+            bot_params,
+            position,
+            trailing_price_bundle,
+        } = case;
+        match pside {
+            LONG => (
+                calc_entries_long(
+                    exchange_params,
+                    state_params,
+                    bot_params,
+                    position,
+                    trailing_price_bundle,
+                ),
+                calc_closes_long(
+                    exchange_params,
+                    state_params,
+                    bot_params,
+                    position,
+                    trailing_price_bundle,
+                    &[],
+                ),
+            ),
+            _ => (
+                calc_entries_short(
+                    exchange_params,
+                    state_params,
+                    bot_params,
+                    position,
+                    trailing_price_bundle,
+                ),
+                calc_closes_short(
+                    exchange_params,
+                    state_params,
+                    bot_params,
+                    position,
+                    trailing_price_bundle,
+                    &[],
+                ),
+            ),
+        }
+    }
+
+    fn check_ladders(case: &Case, pside: usize) -> Result<(), TestCaseError> {
+        let (entries, closes) = ladders(case, pside);
+        let ExchangeParams {
+            qty_step,
+            price_step,
+            ..
+        } = case.exchange_params;
+        for (ladder, buys) in [(&entries, pside == LONG), (&closes, pside == SHORT)] {
+            for order in ladder.iter() {
+                prop_assert_eq!(order.qty > 0.0, buys, "qty sign of {:?}", order);
+                prop_assert!(is_on_step(order.qty, qty_step), "qty step of {:?}", order);
+                prop_assert!(
+                    is_on_step(order.price, price_step),
+                    "price step of {:?}",
+                    order
+                );
+            }
+            // each order is further from the market than the one before: buys lower, sells
+            // higher
+            for pair in ladder.windows(2) {
+                prop_assert!(
+                    if buys {
+                        pair[1].price <= pair[0].price
+                    } else {
+                        pair[1].price >= pair[0].price
+                    },
+                    "prices out of order: {:?}",
+                    pair
+                );
+            }
+            let checked = check_ladder_invariants(
+                ladder,
+                &case.exchange_params,
+                &case.state_params,
+                &case.bot_params,
+                &case.position,
+                pside,
+            );
+            prop_assert!(checked.is_ok(), "{}", checked.unwrap_err());
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1000))]
+
+        #[test]
+        fn long_ladders_keep_the_invariants(case in case(LONG)) {
+            check_ladders(&case, LONG)?;
+        }
+
+        #[test]
+        fn short_ladders_keep_the_invariants(case in case(SHORT)) {
+            check_ladders(&case, SHORT)?;
+        }
+    }
+
+    #[test]
+    fn short_grid_closes_stay_above_zero_under_heavy_funding() {
+        let exchange_params = ExchangeParams {
+            qty_step: 0.001,
+            price_step: 0.01,
+            min_qty: 0.001,
+            min_cost: 1.0,
+            c_mult: 1.0,
+            ..Default::default()
+        };
+        let state_params = StateParams {
+            balance: 1000.0,
+            order_book: OrderBook::new(100.0, 100.01),
+            ..Default::default()
+        };
+        // funding paid is twice the position's cost, a markup of 200% to recover it
+        let position = Position {
+            size: -0.5,
+            price: 100.0,
+            accrued_funding: 100.0,
+        };
+        for close_grid_markup_range in [0.0, 0.02] {
+            let bot_params = BotParams {
+                close_grid_markup_range,
+                close_grid_min_markup: 0.005,
+                close_grid_qty_pct: 0.25,
+                close_recover_funding: true,
+                wallet_exposure_limit: 0.5,
+                ..Default::default()
+            };
+            let closes = calc_closes_short(
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &position,
+                &TrailingPriceBundle::default(),
+                &[],
+            );
+            assert!(!closes.is_empty());
+            assert!(closes.iter().all(|close| close.price >= 0.01));
+            check_ladder_invariants(
+                &closes,
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &position,
+                SHORT,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn broken_orders_are_caught() {
+        let exchange_params = ExchangeParams {
+            qty_step: 0.01,
+            price_step: 0.1,
+            min_qty: 0.01,
+            min_cost: 1.0,
+            c_mult: 1.0,
+            ..Default::default()
+        };
+        let state_params = StateParams {
+            balance: 1000.0,
+            ..Default::default()
+        };
+        let bot_params = BotParams {
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        };
+        let position = Position {
+            size: 2.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let check = |qty: f64, price: f64, order_type: OrderType| {
+            check_order_invariants(
+                &Order::new(qty, price, order_type),
+                &exchange_params,
+                &state_params,
+                &bot_params,
+                &position,
+                LONG,
+            )
+        };
+        check(1.0, 100.0, OrderType::EntryGridNormalLong).unwrap();
+        check(-1.0, 101.0, OrderType::CloseGridLong).unwrap();
+        assert!(check(1.0, 0.0, OrderType::EntryGridNormalLong).is_err());
+        assert!(check(0.0, 100.0, OrderType::EntryGridNormalLong).is_err());
+        assert!(check(1.005, 100.0, OrderType::EntryGridNormalLong).is_err());
+        assert!(check(1.0, 100.05, OrderType::EntryGridNormalLong).is_err());
+        assert!(check(1.0, 100.0, OrderType::EntryGridNormalShort).is_err());
+        assert!(check(-1.0, 100.0, OrderType::EntryGridNormalLong).is_err());
+        assert!(check(-2.5, 101.0, OrderType::CloseGridLong).is_err());
+        // exposure 0.2 to 0.6 once filled, over the 0.5 limit
+        assert!(check(4.0, 100.0, OrderType::EntryGridNormalLong).is_err());
+    }
+}
@@ -1,20 +1,62 @@
+// without the python bindings only the ladder calculators are exported
+#![cfg_attr(not(feature = "python"), allow(dead_code))]
+
+#[cfg(feature = "backtest")]
 mod backtest;
 mod closes;
+mod config;
 mod constants;
 mod entries;
+mod invariants;
+#[cfg(feature = "backtest")]
+mod lots;
+#[cfg(feature = "backtest")]
+mod observers;
+#[cfg(feature = "backtest")]
+mod operators;
+#[cfg(feature = "backtest")]
+mod optimizer;
+#[cfg(feature = "backtest")]
+mod order_lifetimes;
+mod orders;
+#[cfg(feature = "backtest")]
+mod paper;
+#[cfg(feature = "backtest")]
+mod pareto;
+#[cfg(feature = "python")]
 mod python;
+#[cfg(feature = "backtest")]
+mod results;
+mod rng;
+mod scoring;
+#[cfg(feature = "backtest")]
+mod stress;
+#[cfg(feature = "backtest")]
+mod synthetic;
 mod types;
 mod utils;
+#[cfg(feature = "backtest")]
+mod walk_forward;
+#[cfg(feature = "wasm")]
+mod wasm;
 
+#[cfg(feature = "python")]
 use backtest::*;
+#[cfg(feature = "python")]
 use closes::*;
+#[cfg(feature = "python")]
 use entries::*;
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
+#[cfg(feature = "python")]
 use pyo3::wrap_pyfunction;
+#[cfg(feature = "python")]
 use python::*;
+#[cfg(feature = "python")]
 use utils::*;
 
 /// A Python module implemented in Rust.
+#[cfg(feature = "python")]
 #[pymodule]
 fn passivbot_rust(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(round_, m)?)?;
@@ -38,8 +80,74 @@ fn passivbot_rust(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(calc_entries_short_py, m)?)?;
     m.add_function(wrap_pyfunction!(calc_closes_long_py, m)?)?;
     m.add_function(wrap_pyfunction!(calc_closes_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_stepped_trailing_stop_price_py, m)?)?;
+    m.add_function(wrap_pyfunction!(reconstruct_trailing_price_bundle_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_close_with_fallback_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_close_with_fallback_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_staggered_closes_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_staggered_closes_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_var_target_closes_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_var_target_closes_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_mirrored_closes_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_mirrored_closes_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_daily_pnl_target_close_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_daily_pnl_target_close_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_kelly_close_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_kelly_close_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_margin_target_close_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_margin_target_close_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_funding_window_close_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_funding_window_close_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_neutral_rebalance_entry_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_neutral_rebalance_close_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_bracket_close_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_bracket_close_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_closes_by_price_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_all_closes_ordered_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_ladder_notional_py, m)?)?;
+    m.add_function(wrap_pyfunction!(check_ladder_invariants_py, m)?)?;
+    m.add_function(wrap_pyfunction!(validate_orders_py, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_orders_py, m)?)?;
     m.add_function(wrap_pyfunction!(run_backtest, m)?)?;
+    m.add_function(wrap_pyfunction!(load_backtest_result, m)?)?;
+    m.add_function(wrap_pyfunction!(load_slot_utilization, m)?)?;
+    m.add_function(wrap_pyfunction!(load_wind_downs, m)?)?;
+    m.add_function(wrap_pyfunction!(load_order_lifetimes, m)?)?;
+    m.add_function(wrap_pyfunction!(load_lot_attributions, m)?)?;
+    m.add_function(wrap_pyfunction!(load_reduce_only_periods, m)?)?;
+    m.add_function(wrap_pyfunction!(compare_backtest_results, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_fitness_py, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_bot_params_py, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_bot_params_py, m)?)?;
+    m.add_function(wrap_pyfunction!(migrate_config, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_ema_bands_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_order_book_stats_py, m)?)?;
     m.add_function(wrap_pyfunction!(calc_auto_unstuck_allowance, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_unstuck_max_loss, m)?)?;
+    m.add_function(wrap_pyfunction!(rank_positions_for_unstucking, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_stuck_severity_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_min_balance_to_avoid_unstuck_py, m)?)?;
+    m.add_function(wrap_pyfunction!(positions_from_exchange_snapshot_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_immediate_full_close_pnl_long_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_immediate_full_close_pnl_short_py, m)?)?;
+    m.add_function(wrap_pyfunction!(calc_dataset_fingerprint_py, m)?)?;
+    m.add_function(wrap_pyfunction!(downsample_equities_py, m)?)?;
+    m.add_function(wrap_pyfunction!(pareto_front_py, m)?)?;
+    m.add_function(wrap_pyfunction!(hypervolume_py, m)?)?;
+    m.add_function(wrap_pyfunction!(knee_points_py, m)?)?;
+    m.add_function(wrap_pyfunction!(run_walk_forward_py, m)?)?;
+    m.add_function(wrap_pyfunction!(run_stress_test_py, m)?)?;
     m.add_function(wrap_pyfunction!(hysteresis_rounding, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_synthetic_hlcvs_py, m)?)?;
+    m.add_class::<ParamBoundsPy>()?;
+    m.add_class::<GeneticOperatorsPy>()?;
+    m.add_class::<EvaluationCachePy>()?;
+    m.add_class::<ParticleSwarmOptimizer>()?;
+    m.add_class::<Nsga2Optimizer>()?;
+    m.add_class::<GridSearchOptimizer>()?;
+    m.add_class::<PaperTraderPy>()?;
+    m.add_class::<IdealOrdersCachePy>()?;
+    m.add_class::<CloseSchedulerPy>()?;
+    m.add_class::<CloseAmendThrottlePy>()?;
     Ok(())
 }
@@ -0,0 +1,286 @@
+use crate::constants::LONG;
+use crate::observers::BacktestObserver;
+use crate::types::Fill;
+use crate::utils::{calc_pnl_long, calc_pnl_short};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// How a close fill is matched against the entry fills it offsets.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LotMethod {
+    /// oldest entry fills first, each at its own price
+    #[default]
+    Fifo,
+    /// every open entry fill pro rata, at the position price
+    AverageCost,
+}
+
+impl std::str::FromStr for LotMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(LotMethod::Fifo),
+            "average_cost" => Ok(LotMethod::AverageCost),
+            _ => Err(format!("unknown lot_method '{}'", s)),
+        }
+    }
+}
+
+/// The part of one entry fill a close fill closed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LotClose {
+    pub entry_fill_id: usize, // index into the fills
+    pub qty: f64,             // unsigned
+    pub pnl: f64,
+}
+
+/// The entry fills a close fill offset, in the order they were closed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CloseAttribution {
+    pub fill_id: usize, // index into the fills
+    pub lots: Vec<LotClose>,
+    pub unattributed_qty: f64, // closed beyond the open lots, e.g. of a position opened untracked
+}
+
+#[derive(Debug)]
+struct Lot {
+    fill_id: usize,
+    qty: f64, // unsigned, still open
+    price: f64,
+}
+
+/// Open entry fills per (coin, pside), fed by Backtest with every fill. Entries, cropped or
+/// not, open a lot of the qty they filled; closes, partial or full, consume lots per
+/// LotMethod. Lots left when a position goes flat are float dust and are dropped, so the
+/// next position starts from its own entries.
+#[derive(Debug, Default)]
+pub struct LotTracker {
+    method: LotMethod,
+    c_mults: HashMap<String, f64>,                 // per coin
+    open: HashMap<(String, usize), VecDeque<Lot>>, // oldest first
+    attributions: Vec<CloseAttribution>,
+    next_fill_id: usize,
+}
+
+impl LotTracker {
+    /// next_fill_id is the id of the first fill observed, the number of fills before it.
+    pub fn new(method: LotMethod, c_mults: HashMap<String, f64>, next_fill_id: usize) -> Self {
+        LotTracker {
+            method,
+            c_mults,
+            next_fill_id,
+            ..Default::default()
+        }
+    }
+
+    /// fill is the fill_id-th fill of the backtest.
+    pub fn fill(&mut self, fill_id: usize, fill: &Fill) {
+        self.next_fill_id = fill_id + 1;
+        let c_mult = self.c_mults.get(&fill.coin).copied().unwrap_or(1.0);
+        let pside = fill.order_type.pside();
+        let lots = self.open.entry((fill.coin.clone(), pside)).or_default();
+        if !fill.order_type.is_close() {
+            lots.push_back(Lot {
+                fill_id,
+                qty: fill.fill_qty.abs(),
+                price: fill.fill_price,
+            });
+            return;
+        }
+        let calc_pnl = if pside == LONG {
+            calc_pnl_long
+        } else {
+            calc_pnl_short
+        };
+        let mut remaining = fill.fill_qty.abs();
+        // qtys are on qty_step; what is left of an exact match is float noise
+        let tolerance = remaining * 1e-9;
+        let mut closed = Vec::new();
+        match self.method {
+            LotMethod::Fifo => {
+                while let Some(lot) = lots.front_mut().filter(|_| remaining > tolerance) {
+                    let qty = lot.qty.min(remaining);
+                    closed.push(LotClose {
+                        entry_fill_id: lot.fill_id,
+                        qty,
+                        pnl: calc_pnl(lot.price, fill.fill_price, qty, c_mult),
+                    });
+                    lot.qty -= qty;
+                    remaining -= qty;
+                    if lot.qty <= tolerance {
+                        lots.pop_front();
+                    }
+                }
+            }
+            LotMethod::AverageCost => {
+                let open_qty: f64 = lots.iter().map(|lot| lot.qty).sum();
+                if open_qty > 0.0 {
+                    let share = (remaining / open_qty).min(1.0);
+                    for lot in lots.iter_mut() {
+                        let qty = lot.qty * share;
+                        closed.push(LotClose {
+                            entry_fill_id: lot.fill_id,
+                            qty,
+                            pnl: calc_pnl(fill.position_price, fill.fill_price, qty, c_mult),
+                        });
+                        lot.qty -= qty;
+                    }
+                    remaining -= open_qty * share;
+                    lots.retain(|lot| lot.qty > tolerance);
+                }
+            }
+        }
+        if fill.position_size == 0.0 {
+            lots.clear();
+        }
+        self.attributions.push(CloseAttribution {
+            fill_id,
+            lots: closed,
+            unattributed_qty: if remaining > tolerance {
+                remaining
+            } else {
+                0.0
+            },
+        });
+    }
+
+    /// One attribution per close fill so far, in fill order.
+    pub fn attributions(&self) -> &[CloseAttribution] {
+        &self.attributions
+    }
+}
+
+/// Fills are numbered in the order observed, from next_fill_id on.
+impl BacktestObserver for LotTracker {
+    fn on_fill(&mut self, fill: &Fill) {
+        self.fill(self.next_fill_id, fill);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OrderType;
+
+    fn fill(
+        fill_qty: f64,
+        fill_price: f64,
+        position_size: f64,
+        position_price: f64,
+        order_type: OrderType,
+    ) -> Fill {
+        Fill {
+            index: 0,
+            coin: "A".to_string(),
+            pnl: 0.0,
+            fee_paid: 0.0,
+            balance_usd_total: 0.0,
+            balance_btc: 0.0,
+            balance_usd: 0.0,
+            btc_price: 1.0,
+            fill_qty,
+            fill_price,
+            position_size,
+            position_price,
+            order_type,
+            impact_pct: 0.0,
+        }
+    }
+
+    fn lot(entry_fill_id: usize, qty: f64, pnl: f64) -> LotClose {
+        LotClose {
+            entry_fill_id,
+            qty,
+            pnl,
+        }
+    }
+
+    fn dca_then_scale_out(method: LotMethod, c_mult: f64) -> Vec<CloseAttribution> {
+        // buys 1@100, 1@90 and 2@80 for an average of 87.5, then sells 1.5@95, 1.5@100, 1@105
+        let fills = [
+            fill(1.0, 100.0, 1.0, 100.0, OrderType::EntryInitialNormalLong),
+            fill(1.0, 90.0, 2.0, 95.0, OrderType::EntryGridCroppedLong),
+            fill(2.0, 80.0, 4.0, 87.5, OrderType::EntryGridNormalLong),
+            fill(-1.5, 95.0, 2.5, 87.5, OrderType::CloseGridLong),
+            fill(-1.5, 100.0, 1.0, 87.5, OrderType::CloseGridLong),
+            fill(-1.0, 105.0, 0.0, 87.5, OrderType::CloseGridLong),
+        ];
+        let mut tracker = LotTracker::new(method, HashMap::from([("A".to_string(), c_mult)]), 0);
+        for (fill_id, fill) in fills.iter().enumerate() {
+            tracker.fill(fill_id, fill);
+        }
+        tracker.attributions().to_vec()
+    }
+
+    fn total_pnl(attributions: &[CloseAttribution]) -> f64 {
+        attributions
+            .iter()
+            .flat_map(|attribution| &attribution.lots)
+            .map(|lot| lot.pnl)
+            .sum()
+    }
+
+    #[test]
+    fn fifo_lots_after_dca_then_scale_out() {
+        let fifo = dca_then_scale_out(LotMethod::Fifo, 1.0);
+        let fill_ids: Vec<usize> = fifo.iter().map(|attribution| attribution.fill_id).collect();
+        assert_eq!(fill_ids, [3, 4, 5]);
+        assert_eq!(fifo[0].lots, [lot(0, 1.0, -5.0), lot(1, 0.5, 2.5)]);
+        assert_eq!(fifo[1].lots, [lot(1, 0.5, 5.0), lot(2, 1.0, 20.0)]);
+        assert_eq!(fifo[2].lots, [lot(2, 1.0, 25.0)]);
+        assert!(fifo
+            .iter()
+            .all(|attribution| attribution.unattributed_qty == 0.0));
+    }
+
+    #[test]
+    fn average_cost_lots_after_dca_then_scale_out() {
+        let average_cost = dca_then_scale_out(LotMethod::AverageCost, 2.0);
+        assert_eq!(
+            average_cost[0].lots,
+            [
+                lot(0, 0.375, 5.625),
+                lot(1, 0.375, 5.625),
+                lot(2, 0.75, 11.25)
+            ]
+        );
+        // 0.625, 0.625 and 1.25 remain; closing 1.5 of 2.5 takes 60% of each
+        for (lot, qty) in average_cost[1].lots.iter().zip([0.375, 0.375, 0.75]) {
+            assert!((lot.qty - qty).abs() < 1e-12);
+            assert!((lot.pnl - qty * 2.0 * 12.5).abs() < 1e-9);
+        }
+        let qty: f64 = average_cost[2].lots.iter().map(|lot| lot.qty).sum();
+        assert!((qty - 1.0).abs() < 1e-12);
+
+        // once flat, both methods have realized the same pnl
+        let fifo = dca_then_scale_out(LotMethod::Fifo, 1.0);
+        assert!((total_pnl(&fifo) * 2.0 - total_pnl(&average_cost)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn short_lots_and_closes_beyond_them() {
+        let mut tracker = LotTracker::new(LotMethod::Fifo, HashMap::new(), 0);
+        tracker.fill(
+            0,
+            &fill(-2.0, 50.0, -2.0, 50.0, OrderType::EntryInitialNormalShort),
+        );
+        tracker.fill(1, &fill(1.0, 40.0, -1.0, 50.0, OrderType::CloseGridShort));
+        // closes 0.5 more than the lots hold
+        tracker.fill(2, &fill(1.5, 45.0, 0.0, 50.0, OrderType::CloseUnstuckShort));
+        tracker.fill(
+            3,
+            &fill(-1.0, 60.0, -1.0, 60.0, OrderType::EntryInitialNormalShort),
+        );
+        tracker.fill(4, &fill(1.0, 55.0, 0.0, 60.0, OrderType::CloseGridShort));
+        let attributions = tracker.attributions();
+        assert_eq!(attributions[0].lots, [lot(0, 1.0, 10.0)]);
+        assert_eq!(attributions[1].lots, [lot(0, 1.0, 5.0)]);
+        assert_eq!(attributions[1].unattributed_qty, 0.5);
+        assert_eq!(attributions[2].lots, [lot(3, 1.0, 5.0)]);
+
+        assert!("average_cost".parse::<LotMethod>().is_ok());
+        assert!("lifo".parse::<LotMethod>().is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use crate::types::{Equities, Fill, Order, SymbolIdx};
+
+/// The backtest's state once candle k is done: fills processed, orders updated for the
+/// next candle and equity marked at candle k's close.
+pub struct CandleSnapshot {
+    pub k: usize,
+    pub equity_usd: f64,
+    pub equity_btc: f64,
+}
+
+/// Instrumentation hooks into Backtest::step, registered with Backtest::add_observer. All
+/// methods default to no-ops. Per candle the order is: on_fill for each fill against the
+/// orders left by the previous candle, on_order_update for each coin and pside whose
+/// orders were recomputed, then on_candle.
+pub trait BacktestObserver {
+    fn on_fill(&mut self, _fill: &Fill) {}
+
+    /// entries and closes now open on coin idx's pside position.
+    fn on_order_update(
+        &mut self,
+        _k: usize,
+        _idx: SymbolIdx,
+        _pside: usize,
+        _entries: &[Order],
+        _closes: &[Order],
+    ) {
+    }
+
+    fn on_candle(&mut self, _candle: &CandleSnapshot) {}
+}
+
+/// The equity curve every backtest records.
+impl BacktestObserver for Equities {
+    fn on_candle(&mut self, candle: &CandleSnapshot) {
+        self.usd.push(candle.equity_usd);
+        self.btc.push(candle.equity_btc);
+    }
+}
+
+/// The fills every backtest records.
+impl BacktestObserver for Vec<Fill> {
+    fn on_fill(&mut self, fill: &Fill) {
+        self.push(fill.clone());
+    }
+}
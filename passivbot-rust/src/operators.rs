@@ -0,0 +1,413 @@
+use crate::optimizer::{ParamBound, ParamBounds};
+use crate::rng::Rng;
+use crate::types::BotParamsPair;
+
+/// Keeps the parameter at `greater` no lower than the one at `lesser`, e.g.
+/// "long.close_trailing_threshold_pct" over "long.close_trailing_retracement_pct".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParamConstraint {
+    pub greater: String,
+    pub lesser: String,
+}
+
+/// Recombination and mutation of positions over ParamBounds, for genetic optimizers and
+/// evolutionary loops scripted from Python. Operators work on positions scaled to [0, 1],
+/// in log space for log scale bounds, and every child is repaired: clipped into its bounds,
+/// discrete parameters rounded, and constraints restored. Results depend only on the rng.
+#[derive(Debug, Clone)]
+pub struct GeneticOperators {
+    bounds: ParamBounds,
+    constraints: Vec<(usize, usize)>, // (greater, lesser) position indices
+}
+
+impl GeneticOperators {
+    /// Both paths of each constraint must be searched bounds, and the greater's bound must
+    /// reach the lesser's, so that repair can always satisfy it.
+    pub fn new(bounds: ParamBounds, constraints: Vec<ParamConstraint>) -> Result<Self, String> {
+        let index = |path: &str| {
+            bounds
+                .searched()
+                .iter()
+                .position(|bound| bound.path == path)
+                .ok_or_else(|| format!("constraint path '{}' is not a searched bound", path))
+        };
+        let mut indices = Vec::with_capacity(constraints.len());
+        for constraint in &constraints {
+            let (greater, lesser) = (index(&constraint.greater)?, index(&constraint.lesser)?);
+            let searched = bounds.searched();
+            if greater == lesser || highest(&searched[greater]) < lowest(&searched[lesser]) {
+                return Err(format!(
+                    "'{}' can never be at least '{}' within bounds",
+                    constraint.greater, constraint.lesser
+                ));
+            }
+            indices.push((greater, lesser));
+        }
+        Ok(GeneticOperators {
+            bounds,
+            constraints: indices,
+        })
+    }
+
+    pub fn bounds(&self) -> &ParamBounds {
+        &self.bounds
+    }
+
+    /// Each parameter swapped between the children with probability 0.5.
+    pub fn uniform_crossover(
+        &self,
+        rng: &mut Rng,
+        a: &[f64],
+        b: &[f64],
+    ) -> Result<(Vec<f64>, Vec<f64>), String> {
+        let (mut child_a, mut child_b) = (self.to_units(a)?, self.to_units(b)?);
+        for i in 0..child_a.len() {
+            if rng.next_f64() < 0.5 {
+                std::mem::swap(&mut child_a[i], &mut child_b[i]);
+            }
+        }
+        Ok((
+            self.repaired_from_units(&child_a),
+            self.repaired_from_units(&child_b),
+        ))
+    }
+
+    /// BLX-alpha: each child's parameter is drawn uniformly from the parents' range extended
+    /// by alpha times its width on both sides.
+    pub fn blend_crossover(
+        &self,
+        rng: &mut Rng,
+        a: &[f64],
+        b: &[f64],
+        alpha: f64,
+    ) -> Result<(Vec<f64>, Vec<f64>), String> {
+        let (mut child_a, mut child_b) = (self.to_units(a)?, self.to_units(b)?);
+        for (x, y) in child_a.iter_mut().zip(child_b.iter_mut()) {
+            let extension = alpha * (*x - *y).abs();
+            let (low, high) = (x.min(*y) - extension, x.max(*y) + extension);
+            *x = rng.uniform(low, high).clamp(0.0, 1.0);
+            *y = rng.uniform(low, high).clamp(0.0, 1.0);
+        }
+        Ok((
+            self.repaired_from_units(&child_a),
+            self.repaired_from_units(&child_b),
+        ))
+    }
+
+    /// Each parameter, with probability mutation_prob (0.0 == 1 / n parameters), moved by a
+    /// normal step of sigma bound widths.
+    pub fn gaussian_mutation(
+        &self,
+        rng: &mut Rng,
+        position: &[f64],
+        sigma: f64,
+        mutation_prob: f64,
+    ) -> Result<Vec<f64>, String> {
+        let mutation_prob = self.mutation_prob(mutation_prob);
+        let mut units = self.to_units(position)?;
+        for unit in units.iter_mut() {
+            if rng.next_f64() < mutation_prob {
+                *unit = (*unit + sigma * rng.standard_normal()).clamp(0.0, 1.0);
+            }
+        }
+        Ok(self.repaired_from_units(&units))
+    }
+
+    /// Each parameter, with probability mutation_prob (0.0 == 1 / n parameters), perturbed
+    /// by polynomial mutation with distribution index eta.
+    pub fn polynomial_mutation(
+        &self,
+        rng: &mut Rng,
+        position: &[f64],
+        eta: f64,
+        mutation_prob: f64,
+    ) -> Result<Vec<f64>, String> {
+        let mutation_prob = self.mutation_prob(mutation_prob);
+        let mut units = self.to_units(position)?;
+        for unit in units.iter_mut() {
+            if rng.next_f64() < mutation_prob {
+                *unit = polynomial_mutation(rng, *unit, eta);
+            }
+        }
+        Ok(self.repaired_from_units(&units))
+    }
+
+    /// position clipped into bounds, discrete parameters rounded, then each violated
+    /// constraint restored by raising its greater parameter, or, where the greater's bound
+    /// stops short, lowering its lesser one. Constraints sharing a parameter are repaired
+    /// in turn, up to one pass per constraint.
+    pub fn repair(&self, position: &[f64]) -> Result<Vec<f64>, String> {
+        self.check_len(position)?;
+        let searched = self.bounds.searched();
+        let mut position: Vec<f64> = searched
+            .iter()
+            .zip(position)
+            .map(|(bound, &value)| bound.clip(value))
+            .collect();
+        for _ in 0..self.constraints.len() {
+            if self.violated_constraint(&position).is_none() {
+                break;
+            }
+            for &(greater, lesser) in &self.constraints {
+                if position[greater] >= position[lesser] {
+                    continue;
+                }
+                position[greater] =
+                    searched[greater].clip(raised(&searched[greater], position[lesser]));
+                if position[greater] < position[lesser] {
+                    position[lesser] =
+                        searched[lesser].clip(lowered(&searched[lesser], position[greater]));
+                }
+            }
+        }
+        Ok(position)
+    }
+
+    /// Errs if bot_params_pair is outside bounds, off a frozen value, or breaks a constraint.
+    pub fn validate(&self, bot_params_pair: &BotParamsPair) -> Result<(), String> {
+        self.bounds.validate(bot_params_pair)?;
+        match self.violated_constraint(&self.bounds.values(bot_params_pair)) {
+            Some((greater, lesser)) => Err(format!(
+                "'{}' is below '{}'",
+                self.bounds.searched()[greater].path,
+                self.bounds.searched()[lesser].path
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn violated_constraint(&self, position: &[f64]) -> Option<(usize, usize)> {
+        self.constraints
+            .iter()
+            .copied()
+            .find(|&(greater, lesser)| position[greater] < position[lesser])
+    }
+
+    fn mutation_prob(&self, mutation_prob: f64) -> f64 {
+        if mutation_prob > 0.0 {
+            mutation_prob
+        } else {
+            1.0 / self.bounds.len().max(1) as f64
+        }
+    }
+
+    fn check_len(&self, position: &[f64]) -> Result<(), String> {
+        if position.len() != self.bounds.len() {
+            return Err(format!(
+                "expected {} parameter values, got {}",
+                self.bounds.len(),
+                position.len()
+            ));
+        }
+        Ok(())
+    }
+
+    fn to_units(&self, position: &[f64]) -> Result<Vec<f64>, String> {
+        self.check_len(position)?;
+        Ok(self
+            .bounds
+            .searched()
+            .iter()
+            .zip(position)
+            .map(|(bound, &value)| bound.to_unit(value))
+            .collect())
+    }
+
+    fn repaired_from_units(&self, units: &[f64]) -> Vec<f64> {
+        let position: Vec<f64> = self
+            .bounds
+            .searched()
+            .iter()
+            .zip(units)
+            .map(|(bound, &unit)| bound.value_at_unit(unit))
+            .collect();
+        self.repair(&position)
+            .expect("positions have one value per bound")
+    }
+}
+
+// the extremes a parameter can take once clipped
+fn highest(bound: &ParamBound) -> f64 {
+    bound.clip(bound.high)
+}
+
+fn lowest(bound: &ParamBound) -> f64 {
+    bound.clip(bound.low)
+}
+
+// the nearest value of bound's kind at or above value; discrete parameters are integers
+fn raised(bound: &ParamBound, value: f64) -> f64 {
+    if bound.discrete {
+        value.ceil()
+    } else {
+        value
+    }
+}
+
+fn lowered(bound: &ParamBound, value: f64) -> f64 {
+    if bound.discrete {
+        value.floor()
+    } else {
+        value
+    }
+}
+
+/// Simulated binary crossover of two values in [0, 1].
+pub fn sbx(rng: &mut Rng, a: f64, b: f64, eta: f64) -> (f64, f64) {
+    let u = rng.next_f64();
+    let beta = if u <= 0.5 {
+        (2.0 * u).powf(1.0 / (eta + 1.0))
+    } else {
+        (1.0 / (2.0 * (1.0 - u))).powf(1.0 / (eta + 1.0))
+    };
+    (
+        (0.5 * ((1.0 + beta) * a + (1.0 - beta) * b)).clamp(0.0, 1.0),
+        (0.5 * ((1.0 - beta) * a + (1.0 + beta) * b)).clamp(0.0, 1.0),
+    )
+}
+
+/// Polynomial mutation of a value in [0, 1].
+pub fn polynomial_mutation(rng: &mut Rng, x: f64, eta: f64) -> f64 {
+    let u = rng.next_f64();
+    let delta = if u < 0.5 {
+        (2.0 * u).powf(1.0 / (eta + 1.0)) - 1.0
+    } else {
+        1.0 - (2.0 * (1.0 - u)).powf(1.0 / (eta + 1.0))
+    };
+    (x + delta).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn operators() -> GeneticOperators {
+        let bounds = ParamBounds::from_config(
+            &json!({
+                "long_close_grid_min_markup": [0.001, 0.02],
+                "long_close_trailing_retracement_pct": [0.002, 0.05],
+                "long_close_trailing_threshold_pct": {"low": 0.001, "high": 0.1, "scale": "log"},
+                "long_n_positions": [1.0, 5.0],
+                "short_n_positions": [2.0, 8.0],
+            }),
+            None,
+        )
+        .unwrap();
+        let constraint = |greater: &str, lesser: &str| ParamConstraint {
+            greater: greater.to_string(),
+            lesser: lesser.to_string(),
+        };
+        GeneticOperators::new(
+            bounds,
+            vec![
+                constraint(
+                    "long.close_trailing_threshold_pct",
+                    "long.close_trailing_retracement_pct",
+                ),
+                constraint(
+                    "long.close_trailing_retracement_pct",
+                    "long.close_grid_min_markup",
+                ),
+                constraint("long.n_positions", "short.n_positions"),
+            ],
+        )
+        .unwrap()
+    }
+
+    // random positions, a third of them with values outside the bounds
+    fn random_position(operators: &GeneticOperators, rng: &mut Rng) -> Vec<f64> {
+        let wild = rng.next_f64() < 1.0 / 3.0;
+        operators
+            .bounds()
+            .searched()
+            .iter()
+            .map(|bound| {
+                let value = bound.value_at_unit(rng.next_f64());
+                if wild {
+                    value + rng.uniform(-2.0, 2.0) * (bound.high - bound.low)
+                } else {
+                    value
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn children_always_validate() {
+        let operators = operators();
+        let base = BotParamsPair::default();
+        let mut rng = Rng::new(5);
+        let check = |position: &[f64]| {
+            let bot_params_pair = operators.bounds().apply(&base, position).unwrap();
+            operators
+                .validate(&bot_params_pair)
+                .unwrap_or_else(|e| panic!("{:?}: {}", position, e));
+        };
+        for _ in 0..500 {
+            let a = random_position(&operators, &mut rng);
+            let b = random_position(&operators, &mut rng);
+            check(&operators.repair(&a).unwrap());
+            let (c, d) = operators.uniform_crossover(&mut rng, &a, &b).unwrap();
+            check(&c);
+            check(&d);
+            let alpha = rng.uniform(0.0, 1.0);
+            let (c, d) = operators.blend_crossover(&mut rng, &a, &b, alpha).unwrap();
+            check(&c);
+            check(&d);
+            let sigma = rng.uniform(0.0, 1.0);
+            let prob = rng.next_f64();
+            check(
+                &operators
+                    .gaussian_mutation(&mut rng, &a, sigma, prob)
+                    .unwrap(),
+            );
+            let eta = rng.uniform(1.0, 50.0);
+            check(
+                &operators
+                    .polynomial_mutation(&mut rng, &a, eta, prob)
+                    .unwrap(),
+            );
+        }
+        assert!(operators.repair(&[0.01]).is_err());
+        assert!(operators
+            .uniform_crossover(&mut rng, &[0.01], &[0.01])
+            .is_err());
+    }
+
+    #[test]
+    fn repair_keeps_valid_positions() {
+        let operators = operators();
+        let mut rng = Rng::new(6);
+        for _ in 0..200 {
+            let repaired = operators
+                .repair(&random_position(&operators, &mut rng))
+                .unwrap();
+            assert_eq!(operators.repair(&repaired).unwrap(), repaired);
+        }
+    }
+
+    #[test]
+    fn unit_operators_stay_in_the_unit_interval() {
+        let mut rng = Rng::new(7);
+        for _ in 0..1000 {
+            let (a, b) = (rng.next_f64(), rng.next_f64());
+            let eta = rng.uniform(0.5, 50.0);
+            let (c, d) = sbx(&mut rng, a, b, eta);
+            assert!((0.0..=1.0).contains(&c) && (0.0..=1.0).contains(&d));
+            // unclamped, the children are spread symmetrically about the parents' mean
+            if c > 0.0 && c < 1.0 && d > 0.0 && d < 1.0 {
+                assert!(((c + d) - (a + b)).abs() < 1e-12);
+            }
+            assert!((0.0..=1.0).contains(&polynomial_mutation(&mut rng, a, eta)));
+        }
+        assert!(GeneticOperators::new(
+            operators().bounds().clone(),
+            vec![ParamConstraint {
+                greater: "long.close_grid_min_markup".to_string(),
+                lesser: "long.not_searched".to_string(),
+            }],
+        )
+        .is_err());
+    }
+}
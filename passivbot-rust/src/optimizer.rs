@@ -0,0 +1,1555 @@
+use crate::operators::{polynomial_mutation, sbx};
+use crate::rng::{fnv1a, Rng, FNV_OFFSET};
+use crate::scoring::{check_metric, metric_value, Score, ScoringConfig};
+use crate::types::{
+    Analysis, BacktestParams, BotParamsPair, Evaluation, ExchangeParams, PruneParams,
+};
+use crate::utils::set_json_path;
+use ndarray::{ArrayView1, ArrayView3};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParamScale {
+    #[default]
+    Linear,
+    Log, // searched uniformly in log space; requires low > 0
+}
+
+/// Search range of one bot parameter, addressed by dotted path, e.g. "long.ema_span_0".
+#[derive(Debug, Clone)]
+pub struct ParamBound {
+    pub path: String,
+    pub low: f64,
+    pub high: f64,
+    pub discrete: bool, // integer and bool parameters are rounded when applied
+    pub scale: ParamScale,
+}
+
+impl ParamBound {
+    /// Position of value within the bound, 0.0 at low and 1.0 at high.
+    pub fn to_unit(&self, value: f64) -> f64 {
+        // clamped first, as values outside a log bound may have no log
+        let value = value.clamp(self.low, self.high);
+        let unit = if self.high <= self.low {
+            0.0
+        } else if self.scale == ParamScale::Log {
+            (value.ln() - self.low.ln()) / (self.high.ln() - self.low.ln())
+        } else {
+            (value - self.low) / (self.high - self.low)
+        };
+        unit.clamp(0.0, 1.0)
+    }
+
+    pub fn value_at_unit(&self, unit: f64) -> f64 {
+        let unit = unit.clamp(0.0, 1.0);
+        let value = if self.scale == ParamScale::Log {
+            (self.low.ln() + unit * (self.high.ln() - self.low.ln())).exp()
+        } else {
+            self.low + unit * (self.high - self.low)
+        };
+        value.clamp(self.low, self.high)
+    }
+
+    /// value clamped into the bound; discrete parameters are rounded to an integer within it.
+    pub fn clip(&self, value: f64) -> f64 {
+        if self.discrete {
+            value.round().clamp(self.low.ceil(), self.high.floor())
+        } else {
+            value.clamp(self.low, self.high)
+        }
+    }
+}
+
+/// The bot parameters an optimization searches, and those it holds fixed. Positions handed
+/// to the optimizers have one element per searched bound, in path order so dimensions do not
+/// depend on config key order.
+#[derive(Debug, Clone, Default)]
+pub struct ParamBounds {
+    bounds: Vec<ParamBound>,
+    frozen: Vec<(String, Value)>,
+}
+
+impl ParamBounds {
+    pub fn new(mut bounds: Vec<ParamBound>, mut frozen: Vec<(String, Value)>) -> Self {
+        bounds.sort_by(|a, b| a.path.cmp(&b.path));
+        frozen.sort_by(|a, b| a.0.cmp(&b.0));
+        ParamBounds { bounds, frozen }
+    }
+
+    /// Reads config["optimize"]["bounds"]-style bounds, {"long_ema_span_0": [200, 1440], ..}.
+    /// As in optimize.py the first and last elements of each bound are (low, high); a bound
+    /// may instead be {"low": 200, "high": 1440, "scale": "log", "frozen": false}. A frozen
+    /// bound keeps the base config's value, and a bound given as one number, or with low ==
+    /// high, is frozen at that value. `frozen` fixes any further fields by dotted path,
+    /// {"long.close_trailing_anchor": "peak", ..}, overriding bounds on the same field.
+    pub fn from_config(bounds: &Value, frozen: Option<&Value>) -> Result<Self, String> {
+        let template =
+            serde_json::to_value(BotParamsPair::default()).expect("BotParamsPair serializes");
+        let bounds = bounds
+            .as_object()
+            .ok_or_else(|| "bounds must be an object".to_string())?;
+        let mut param_bounds = ParamBounds::default();
+        for (key, bound) in bounds {
+            let path = match key.split_once('_') {
+                Some((pside @ ("long" | "short"), field)) => format!("{}.{}", pside, field),
+                _ => return Err(format!("bound '{}' has no long_ or short_ prefix", key)),
+            };
+            let template_value = template
+                .pointer(&format!("/{}", path.replace('.', "/")))
+                .ok_or_else(|| format!("unknown parameter '{}'", key))?;
+            if !(template_value.is_number() || template_value.is_boolean()) {
+                return Err(format!("'{}' is not a numeric parameter", key));
+            }
+            let (low, high, scale, is_frozen) = match bound {
+                Value::Number(value) => (value.as_f64(), value.as_f64(), None, false),
+                Value::Array(b) => (
+                    b.first().and_then(Value::as_f64),
+                    b.last().and_then(Value::as_f64),
+                    None,
+                    false,
+                ),
+                Value::Object(b) => (
+                    b.get("low").and_then(Value::as_f64),
+                    b.get("high").and_then(Value::as_f64),
+                    b.get("scale"),
+                    b.get("frozen").and_then(Value::as_bool).unwrap_or(false),
+                ),
+                _ => (None, None, None, false),
+            };
+            let (low, high) = match (low, high) {
+                (Some(low), Some(high)) if low <= high => (low, high),
+                _ => {
+                    return Err(format!(
+                        "bound '{}' must be [low, .., high] with low <= high",
+                        key
+                    ))
+                }
+            };
+            let scale = match scale.map(|scale| scale.as_str()) {
+                None | Some(Some("linear")) => ParamScale::Linear,
+                Some(Some("log")) => ParamScale::Log,
+                _ => return Err(format!("scale of '{}' must be \"linear\" or \"log\"", key)),
+            };
+            if scale == ParamScale::Log && low <= 0.0 {
+                return Err(format!("log scale bound '{}' must have low > 0", key));
+            }
+            let bound = ParamBound {
+                path,
+                low,
+                high,
+                discrete: template_value.is_u64() || template_value.is_boolean(),
+                scale,
+            };
+            if bound.discrete && bound.low.ceil() > bound.high.floor() {
+                return Err(format!("bound '{}' contains no integer", key));
+            }
+            if is_frozen {
+                continue;
+            }
+            if low == high {
+                param_bounds
+                    .frozen
+                    .push((bound.path.clone(), json!(bound.clip(low))));
+                continue;
+            }
+            param_bounds.bounds.push(bound);
+        }
+        if let Some(frozen) = frozen {
+            let frozen = frozen
+                .as_object()
+                .ok_or_else(|| "frozen must be an object of path: value".to_string())?;
+            for (path, value) in frozen {
+                // rejects unknown paths and values of the wrong type
+                set_json_path(&mut template.clone(), path, value.clone())?;
+                param_bounds
+                    .frozen
+                    .retain(|(frozen_path, _)| frozen_path != path);
+                param_bounds.frozen.push((path.clone(), value.clone()));
+            }
+            param_bounds
+                .bounds
+                .retain(|bound| !frozen.contains_key(&bound.path));
+        }
+        Ok(ParamBounds::new(param_bounds.bounds, param_bounds.frozen))
+    }
+
+    /// The searched bounds, one per position element.
+    pub fn searched(&self) -> &[ParamBound] {
+        &self.bounds
+    }
+
+    pub fn len(&self) -> usize {
+        self.bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_empty()
+    }
+
+    /// A random position, uniform within linear bounds and log-uniform within log bounds.
+    pub fn sample_values(&self, rng: &mut Rng) -> Vec<f64> {
+        self.bounds
+            .iter()
+            .map(|bound| bound.value_at_unit(rng.next_f64()))
+            .collect()
+    }
+
+    /// `base` with frozen fields set and searched fields sampled as by sample_values.
+    pub fn sample(&self, base: &BotParamsPair, rng: &mut Rng) -> BotParamsPair {
+        self.apply(base, &self.sample_values(rng))
+            .expect("bounds and frozen values were checked against BotParamsPair")
+    }
+
+    /// `base` with frozen fields set and each searched field set to the matching element of
+    /// `values`, clipped into its bound.
+    pub fn apply(&self, base: &BotParamsPair, values: &[f64]) -> Result<BotParamsPair, String> {
+        if values.len() != self.bounds.len() {
+            return Err(format!(
+                "expected {} parameter values, got {}",
+                self.bounds.len(),
+                values.len()
+            ));
+        }
+        let updates: Vec<(String, Value)> = self
+            .frozen
+            .iter()
+            .cloned()
+            .chain(
+                self.bounds
+                    .iter()
+                    .zip(values)
+                    .map(|(bound, &value)| (bound.path.clone(), json!(bound.clip(value)))),
+            )
+            .collect();
+        let mut bot_params_pair = base.clone();
+        bot_params_pair.merge_partial(&updates)?;
+        Ok(bot_params_pair)
+    }
+
+    /// The searched fields of `bot_params_pair`, in position order.
+    pub fn values(&self, bot_params_pair: &BotParamsPair) -> Vec<f64> {
+        let json = serde_json::to_value(bot_params_pair).expect("BotParamsPair serializes");
+        self.bounds
+            .iter()
+            .map(|bound| json_path_f64(&json, &bound.path))
+            .collect()
+    }
+
+    /// `bot_params_pair` with searched fields clipped into their bounds and frozen fields set.
+    pub fn clip(&self, bot_params_pair: &BotParamsPair) -> BotParamsPair {
+        self.apply(bot_params_pair, &self.values(bot_params_pair))
+            .expect("bounds and frozen values were checked against BotParamsPair")
+    }
+
+    /// Errs on the first searched field outside its bound or frozen field off its value.
+    pub fn validate(&self, bot_params_pair: &BotParamsPair) -> Result<(), String> {
+        let json = serde_json::to_value(bot_params_pair).expect("BotParamsPair serializes");
+        for (bound, value) in self.bounds.iter().zip(self.values(bot_params_pair)) {
+            if !(bound.low..=bound.high).contains(&value)
+                || (bound.discrete && value.fract() != 0.0)
+            {
+                return Err(format!(
+                    "'{}' is {}, outside [{}, {}]",
+                    bound.path, value, bound.low, bound.high
+                ));
+            }
+        }
+        for (path, frozen_value) in &self.frozen {
+            let mut expected = json.clone();
+            set_json_path(&mut expected, path, frozen_value.clone())?;
+            if expected != json {
+                return Err(format!("'{}' is not frozen at {}", path, frozen_value));
+            }
+        }
+        Ok(())
+    }
+}
+
+// numbers as f64 and bools as 0.0 / 1.0; paths were checked against BotParamsPair
+fn json_path_f64(json: &Value, path: &str) -> f64 {
+    match json.pointer(&format!("/{}", path.replace('.', "/"))) {
+        Some(Value::Bool(value)) => f64::from(u8::from(*value)),
+        Some(value) => value.as_f64().unwrap_or(f64::NAN),
+        None => f64::NAN,
+    }
+}
+
+/// Reads {"adg": "maximize", "drawdown_worst": "minimize", ..} into (metric, sign) pairs;
+/// objective values are metric * sign, so every objective is minimized. btc_ metrics are
+/// read from the BTC-denominated analysis.
+pub fn objectives_from_config(objectives: &Value) -> Result<Vec<(String, f64)>, String> {
+    let objectives = objectives
+        .as_object()
+        .ok_or_else(|| "objectives must be an object of metric: direction".to_string())?;
+    if objectives.is_empty() {
+        return Err("objectives is empty".to_string());
+    }
+    objectives
+        .iter()
+        .map(|(metric, direction)| {
+            check_metric(metric)?;
+            let sign = match direction.as_str() {
+                Some("minimize") => 1.0,
+                Some("maximize") => -1.0,
+                _ => {
+                    return Err(format!(
+                        "direction of '{}' must be \"minimize\" or \"maximize\"",
+                        metric
+                    ))
+                }
+            };
+            Ok((metric.clone(), sign))
+        })
+        .collect()
+}
+
+/// Each objective metric times its sign, in the order given.
+pub fn calc_objective_values(
+    objectives: &[(String, f64)],
+    analysis_usd: &Analysis,
+    analysis_btc: &Analysis,
+) -> Vec<f64> {
+    let analysis_usd = serde_json::to_value(analysis_usd).expect("Analysis serializes");
+    let analysis_btc = serde_json::to_value(analysis_btc).expect("Analysis serializes");
+    objectives
+        .iter()
+        .map(|(metric, sign)| sign * metric_value(metric, &analysis_usd, &analysis_btc))
+        .collect()
+}
+
+/// Fewer partial fitnesses than this at a checkpoint leave it without a threshold.
+const MIN_PRUNE_SAMPLES: usize = 4;
+
+/// Sets backtest pruning thresholds from the partial fitnesses of the latest batch of
+/// evaluations: at each checkpoint, a backtest worse than that quantile of the batch is pruned.
+/// Until a batch has been recorded nothing is pruned.
+#[derive(Debug, Clone)]
+pub struct Pruner {
+    checkpoints: Vec<f64>,
+    quantile: f64,
+    thresholds: Vec<f64>,
+}
+
+impl Pruner {
+    pub fn new(checkpoints: Vec<f64>, quantile: f64) -> Result<Self, String> {
+        if checkpoints.is_empty() {
+            return Err("prune checkpoints is empty".to_string());
+        }
+        if checkpoints.iter().any(|&c| !(c > 0.0 && c < 1.0))
+            || checkpoints.windows(2).any(|w| w[0] >= w[1])
+        {
+            return Err("prune checkpoints must ascend within (0, 1)".to_string());
+        }
+        if !(quantile > 0.0 && quantile <= 1.0) {
+            return Err("prune quantile must be within (0, 1]".to_string());
+        }
+        Ok(Pruner {
+            thresholds: vec![f64::NAN; checkpoints.len()],
+            checkpoints,
+            quantile,
+        })
+    }
+
+    /// Reads {"checkpoints": [0.1, 0.25], "quantile": 0.5}; quantile defaults to the median.
+    pub fn from_config(prune: &Value) -> Result<Self, String> {
+        let checkpoints = prune
+            .get("checkpoints")
+            .and_then(Value::as_array)
+            .ok_or_else(|| "prune checkpoints must be a list of fractions".to_string())?
+            .iter()
+            .map(|checkpoint| {
+                checkpoint
+                    .as_f64()
+                    .ok_or_else(|| "prune checkpoints must be a list of fractions".to_string())
+            })
+            .collect::<Result<_, _>>()?;
+        let quantile = match prune.get("quantile") {
+            None => 0.5,
+            Some(quantile) => quantile
+                .as_f64()
+                .ok_or_else(|| "prune quantile is not a number".to_string())?,
+        };
+        Pruner::new(checkpoints, quantile)
+    }
+
+    pub fn prune_params(&self) -> PruneParams {
+        PruneParams {
+            checkpoints: self.checkpoints.clone(),
+            thresholds: self.thresholds.clone(),
+        }
+    }
+
+    /// Replaces the thresholds with the batch's; backtests pruned early only count at the
+    /// checkpoints they reached.
+    pub fn update(&mut self, partial_fitnesses: &[Vec<f64>]) {
+        for (i, threshold) in self.thresholds.iter_mut().enumerate() {
+            let mut values: Vec<f64> = partial_fitnesses
+                .iter()
+                .filter_map(|partials| partials.get(i).copied())
+                .filter(|value| !value.is_nan())
+                .collect();
+            *threshold = if values.len() < MIN_PRUNE_SAMPLES {
+                f64::NAN
+            } else {
+                values.sort_by(f64::total_cmp);
+                values[((values.len() - 1) as f64 * self.quantile).round() as usize]
+            };
+        }
+    }
+}
+
+/// Identifies what a backtest ran on: the candles (see calc_candles_fingerprint) and the
+/// exchange and backtest params.
+pub fn calc_dataset_fingerprint(
+    hlcvs: &ArrayView3<f64>,
+    btc_usd_prices: &ArrayView1<f64>,
+    exchange_params_list: &[ExchangeParams],
+    backtest_params: &BacktestParams,
+) -> u64 {
+    let params =
+        serde_json::to_string(&(exchange_params_list, backtest_params)).expect("params serialize");
+    fnv1a(
+        calc_candles_fingerprint(hlcvs, btc_usd_prices),
+        params.as_bytes(),
+    )
+}
+
+/// Identifies market data alone: its shape and up to 4096 evenly spaced samples of it, the
+/// BTC/USD prices likewise.
+pub fn calc_candles_fingerprint(hlcvs: &ArrayView3<f64>, btc_usd_prices: &ArrayView1<f64>) -> u64 {
+    let sample = |hash: u64, len: usize, value: &dyn Fn(usize) -> f64| {
+        let stride = (len / 4096).max(1);
+        (0..len)
+            .step_by(stride)
+            .fold(fnv1a(hash, &len.to_le_bytes()), |hash, i| {
+                fnv1a(hash, &value(i).to_bits().to_le_bytes())
+            })
+    };
+    let (n_timesteps, n_coins, n_fields) = hlcvs.dim();
+    let mut hash = FNV_OFFSET;
+    for dim in [n_timesteps, n_coins, n_fields] {
+        hash = fnv1a(hash, &dim.to_le_bytes());
+    }
+    hash = sample(hash, hlcvs.len(), &|i| {
+        hlcvs[[
+            i / (n_coins * n_fields),
+            i / n_fields % n_coins,
+            i % n_fields,
+        ]]
+    });
+    sample(hash, btc_usd_prices.len(), &|i| btc_usd_prices[i])
+}
+
+#[derive(Debug)]
+struct CacheEntries<S> {
+    stats: HashMap<u64, S>,
+    order: VecDeque<u64>, // insertion order, oldest first
+}
+
+/// Thread-safe stats of evaluated configs, so configs repeated across iterations, or made
+/// equal by clipping and rounding to bounds, are not backtested again. Keyed by
+/// calc_key; when full, the oldest entry is evicted. max_entries 0 disables caching.
+#[derive(Debug)]
+pub struct EvaluationCache<S> {
+    max_entries: usize,
+    entries: Mutex<CacheEntries<S>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<S: Clone> EvaluationCache<S> {
+    pub fn new(max_entries: usize) -> Self {
+        EvaluationCache {
+            max_entries,
+            entries: Mutex::new(CacheEntries {
+                stats: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Hash of the config's canonical JSON and the dataset fingerprint.
+    pub fn calc_key(bot_params_pair: &BotParamsPair, dataset_fingerprint: u64) -> u64 {
+        let canonical = serde_json::to_string(bot_params_pair).expect("BotParamsPair serializes");
+        fnv1a(
+            fnv1a(FNV_OFFSET, &dataset_fingerprint.to_le_bytes()),
+            canonical.as_bytes(),
+        )
+    }
+
+    /// Counts a hit or a miss.
+    pub fn get(&self, key: u64) -> Option<S> {
+        let stats = self.entries.lock().unwrap().stats.get(&key).cloned();
+        match stats {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        stats
+    }
+
+    pub fn insert(&self, key: u64, stats: S) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.stats.insert(key, stats).is_none() {
+            entries.order.push_back(key);
+        }
+        while entries.order.len() > self.max_entries {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.stats.remove(&oldest);
+            }
+        }
+    }
+
+    /// Cached stats for key, else evaluate's, cached if keep says so.
+    pub fn get_or_insert_with<F, K>(&self, key: u64, evaluate: F, keep: K) -> S
+    where
+        F: FnOnce() -> S,
+        K: FnOnce(&S) -> bool,
+    {
+        if let Some(stats) = self.get(key) {
+            return stats;
+        }
+        let stats = evaluate();
+        if keep(&stats) {
+            self.insert(key, stats.clone());
+        }
+        stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().stats.len()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Share of lookups which hit; 0.0 before any lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let (hits, misses) = (self.hits(), self.misses());
+        if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PsoParams {
+    pub swarm_size: usize,
+    pub n_iterations: usize,
+    pub inertia: f64,
+    pub cognitive: f64, // pull toward the particle's own best
+    pub social: f64,    // pull toward the swarm's best
+    pub seed: u64,
+    pub n_threads: usize, // particles evaluated concurrently; 0 == available parallelism
+}
+
+impl Default for PsoParams {
+    fn default() -> Self {
+        PsoParams {
+            swarm_size: 32,
+            n_iterations: 100,
+            inertia: 0.7,
+            cognitive: 1.5,
+            social: 1.5,
+            seed: 0,
+            n_threads: 0,
+        }
+    }
+}
+
+/// An evaluated position. Lower fitness is better; non-finite fitness ranks last.
+#[derive(Debug, Clone)]
+pub struct Candidate<S> {
+    pub position: Vec<f64>,
+    pub fitness: f64,
+    pub stats: S,
+}
+
+#[derive(Debug, Clone)]
+struct Particle<S> {
+    position: Vec<f64>,
+    velocity: Vec<f64>,
+    best: Option<Candidate<S>>,
+}
+
+/// Particle swarm minimizing a fitness function over a box given by ParamBounds. Each
+/// `step` moves every particle and evaluates the swarm once (the first step only evaluates
+/// the random initial positions). Results depend only on the seed, not on n_threads.
+pub struct ParticleSwarm<S> {
+    bounds: ParamBounds,
+    params: PsoParams,
+    particles: Vec<Particle<S>>,
+    best: Option<Candidate<S>>,
+    iteration: usize,
+    rng: Rng,
+}
+
+impl<S: Clone + Send + Sync> ParticleSwarm<S> {
+    pub fn new(bounds: ParamBounds, params: PsoParams) -> Result<Self, String> {
+        if bounds.is_empty() {
+            return Err("no parameter bounds to optimize".to_string());
+        }
+        if params.swarm_size == 0 {
+            return Err("swarm_size must be positive".to_string());
+        }
+        let mut rng = Rng::component(params.seed, "pso");
+        let particles = (0..params.swarm_size)
+            .map(|_| Particle {
+                position: bounds.sample_values(&mut rng),
+                velocity: bounds
+                    .searched()
+                    .iter()
+                    .map(|b| rng.uniform(b.low - b.high, b.high - b.low))
+                    .collect(),
+                best: None,
+            })
+            .collect();
+        Ok(ParticleSwarm {
+            bounds,
+            params,
+            particles,
+            best: None,
+            iteration: 0,
+            rng,
+        })
+    }
+
+    pub fn bounds(&self) -> &ParamBounds {
+        &self.bounds
+    }
+
+    pub fn iteration(&self) -> usize {
+        self.iteration
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.iteration >= self.params.n_iterations
+    }
+
+    pub fn best(&self) -> Option<&Candidate<S>> {
+        self.best.as_ref()
+    }
+
+    /// The particles' personal bests, best first, at most n.
+    pub fn best_n(&self, n: usize) -> Vec<&Candidate<S>> {
+        let mut bests: Vec<&Candidate<S>> = self
+            .particles
+            .iter()
+            .filter_map(|p| p.best.as_ref())
+            .collect();
+        bests.sort_by(|a, b| rank(a.fitness).total_cmp(&rank(b.fitness)));
+        bests.truncate(n);
+        bests
+    }
+
+    /// Advances one iteration; returns false without evaluating once n_iterations is reached.
+    pub fn step<F>(&mut self, fitness: &F) -> bool
+    where
+        F: Fn(&[f64]) -> (f64, S) + Sync,
+    {
+        if self.is_done() {
+            return false;
+        }
+        if self.iteration > 0 {
+            self.move_particles();
+        }
+        let evaluations = self.evaluate(fitness);
+        for (particle, (value, stats)) in self.particles.iter_mut().zip(evaluations) {
+            let candidate = Candidate {
+                position: particle.position.clone(),
+                fitness: value,
+                stats,
+            };
+            if improves(&candidate, &self.best) {
+                self.best = Some(candidate.clone());
+            }
+            if improves(&candidate, &particle.best) {
+                particle.best = Some(candidate);
+            }
+        }
+        self.iteration += 1;
+        true
+    }
+
+    fn move_particles(&mut self) {
+        let global_best = match &self.best {
+            Some(best) => best.position.clone(),
+            None => return,
+        };
+        for particle in self.particles.iter_mut() {
+            let personal_best = match &particle.best {
+                Some(best) => best.position.clone(),
+                None => particle.position.clone(),
+            };
+            for (i, bound) in self.bounds.searched().iter().enumerate() {
+                let range = bound.high - bound.low;
+                let r_cognitive = self.rng.next_f64();
+                let r_social = self.rng.next_f64();
+                let x = particle.position[i];
+                let velocity = self.params.inertia * particle.velocity[i]
+                    + self.params.cognitive * r_cognitive * (personal_best[i] - x)
+                    + self.params.social * r_social * (global_best[i] - x);
+                let velocity = velocity.clamp(-range, range);
+                let moved = x + velocity;
+                // particles stop at the walls instead of bouncing out of bounds
+                if moved < bound.low || moved > bound.high {
+                    particle.position[i] = moved.clamp(bound.low, bound.high);
+                    particle.velocity[i] = 0.0;
+                } else {
+                    particle.position[i] = moved;
+                    particle.velocity[i] = velocity;
+                }
+            }
+        }
+    }
+
+    fn evaluate<F>(&self, fitness: &F) -> Vec<(f64, S)>
+    where
+        F: Fn(&[f64]) -> (f64, S) + Sync,
+    {
+        evaluate_parallel(
+            &self.particles,
+            self.params.n_threads,
+            &|particle: &Particle<S>| fitness(&particle.position),
+        )
+    }
+}
+
+/// Advances swarm one iteration, scoring the evaluation of each position (pruned runs rank
+/// last, positions which cannot be evaluated too) and updating pruner's thresholds with the
+/// partial fitnesses; false once the swarm is done.
+pub fn step_scored_swarm<E>(
+    swarm: &mut ParticleSwarm<(Analysis, Analysis)>,
+    scoring: &ScoringConfig,
+    pruner: &mut Option<Pruner>,
+    evaluate: &E,
+) -> bool
+where
+    E: Fn(&[f64], &PruneParams) -> Option<Evaluation> + Sync,
+{
+    let prune_params = pruner
+        .as_ref()
+        .map(Pruner::prune_params)
+        .unwrap_or_default();
+    let partial_fitnesses = Mutex::new(Vec::new());
+    let fitness = |values: &[f64]| match evaluate(values, &prune_params) {
+        Some(evaluation) => {
+            let (analysis_usd, analysis_btc) = &evaluation.analyses;
+            let score = if evaluation.pruned {
+                Score::pruned()
+            } else {
+                scoring.score(analysis_usd, analysis_btc)
+            };
+            partial_fitnesses
+                .lock()
+                .unwrap()
+                .push(evaluation.partial_fitnesses);
+            (score.fitness, evaluation.analyses)
+        }
+        None => (f64::INFINITY, Default::default()),
+    };
+    let stepped = swarm.step(&fitness);
+    if let (true, Some(pruner)) = (stepped, pruner.as_mut()) {
+        pruner.update(&partial_fitnesses.into_inner().unwrap());
+    }
+    stepped
+}
+
+/// Evaluates items on up to n_threads scoped threads (0 == available parallelism), returning
+/// results in input order.
+fn evaluate_parallel<T, R, F>(items: &[T], n_threads: usize, evaluate: &F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if items.is_empty() {
+        return Vec::new();
+    }
+    let chunk_size = items.len().div_ceil(resolve_n_threads(n_threads));
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(evaluate).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("fitness evaluation panicked"))
+            .collect()
+    })
+}
+
+// 0 == available parallelism
+fn resolve_n_threads(n_threads: usize) -> usize {
+    match n_threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+// NaN and inf rank after every finite fitness
+fn rank(fitness: f64) -> f64 {
+    if fitness.is_finite() {
+        fitness
+    } else {
+        f64::INFINITY
+    }
+}
+
+fn improves<S>(candidate: &Candidate<S>, best: &Option<Candidate<S>>) -> bool {
+    match best {
+        Some(best) => rank(candidate.fitness) < rank(best.fitness),
+        None => true,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Nsga2Params {
+    pub population_size: usize,
+    pub n_generations: usize,
+    pub crossover_prob: f64,
+    pub crossover_eta: f64, // SBX distribution index; larger keeps children near parents
+    pub mutation_prob: f64, // per parameter; 0.0 == 1 / n parameters
+    pub mutation_eta: f64,  // polynomial mutation distribution index
+    pub seed: u64,
+    pub n_threads: usize, // individuals evaluated concurrently; 0 == available parallelism
+}
+
+impl Default for Nsga2Params {
+    fn default() -> Self {
+        Nsga2Params {
+            population_size: 64,
+            n_generations: 100,
+            crossover_prob: 0.9,
+            crossover_eta: 20.0,
+            mutation_prob: 0.0,
+            mutation_eta: 20.0,
+            seed: 0,
+            n_threads: 0,
+        }
+    }
+}
+
+/// An evaluated position with its objective values, all minimized. rank 0 is the
+/// non-dominated front; larger crowding distance means a less crowded neighbourhood.
+#[derive(Debug, Clone)]
+pub struct Individual<S> {
+    pub position: Vec<f64>,
+    pub objectives: Vec<f64>,
+    pub rank: usize,
+    pub crowding_distance: f64,
+    pub stats: S,
+}
+
+/// Whether a is no worse than b in every objective and better in at least one.
+pub fn dominates(a: &[f64], b: &[f64]) -> bool {
+    let mut better = false;
+    for (&a, &b) in a.iter().zip(b) {
+        let (a, b) = (rank(a), rank(b));
+        if a > b {
+            return false;
+        }
+        better |= a < b;
+    }
+    better
+}
+
+/// Fast non-dominated sort; returns indices into objectives grouped by front, front 0 first.
+pub fn non_dominated_sort(objectives: &[Vec<f64>]) -> Vec<Vec<usize>> {
+    let n = objectives.len();
+    let mut dominated_by = vec![Vec::new(); n]; // dominated_by[i]: solutions i dominates
+    let mut n_dominating = vec![0usize; n];
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if dominates(&objectives[i], &objectives[j]) {
+                dominated_by[i].push(j);
+                n_dominating[j] += 1;
+            } else if dominates(&objectives[j], &objectives[i]) {
+                dominated_by[j].push(i);
+                n_dominating[i] += 1;
+            }
+        }
+    }
+    let mut fronts = Vec::new();
+    let mut front: Vec<usize> = (0..n).filter(|&i| n_dominating[i] == 0).collect();
+    while !front.is_empty() {
+        let mut next = Vec::new();
+        for &i in &front {
+            for &j in &dominated_by[i] {
+                n_dominating[j] -= 1;
+                if n_dominating[j] == 0 {
+                    next.push(j);
+                }
+            }
+        }
+        next.sort_unstable();
+        fronts.push(front);
+        front = next;
+    }
+    fronts
+}
+
+/// Crowding distance of each member of front, in front order. Boundary solutions of each
+/// objective get infinity; objectives with no spread within the front add nothing.
+pub fn crowding_distances(objectives: &[Vec<f64>], front: &[usize]) -> Vec<f64> {
+    let mut distances = vec![0.0; front.len()];
+    let n_objectives = front.first().map_or(0, |&i| objectives[i].len());
+    let mut order: Vec<usize> = (0..front.len()).collect();
+    for m in 0..n_objectives {
+        let value = |k: usize| rank(objectives[front[k]][m]);
+        order.sort_by(|&a, &b| value(a).total_cmp(&value(b)));
+        let (first, last) = (order[0], order[order.len() - 1]);
+        distances[first] = f64::INFINITY;
+        distances[last] = f64::INFINITY;
+        let spread = value(last) - value(first);
+        if !(spread.is_finite() && spread > 0.0) {
+            continue;
+        }
+        for w in order.windows(3) {
+            distances[w[1]] += (value(w[2]) - value(w[0])) / spread;
+        }
+    }
+    distances
+}
+
+/// NSGA-II minimizing several objectives over a box given by ParamBounds. Crossover (SBX)
+/// and polynomial mutation work on positions scaled to [0, 1], in log space for log scale
+/// bounds. The first `step` evaluates the random initial population; each later step breeds
+/// and evaluates population_size children and keeps the best population_size of parents and
+/// children by rank, then crowding distance. Results depend only on the seed.
+pub struct Nsga2<S> {
+    bounds: ParamBounds,
+    params: Nsga2Params,
+    population: Vec<Individual<S>>,
+    generation: usize,
+    rng: Rng,
+}
+
+impl<S: Clone + Send + Sync> Nsga2<S> {
+    pub fn new(bounds: ParamBounds, params: Nsga2Params) -> Result<Self, String> {
+        if bounds.is_empty() {
+            return Err("no parameter bounds to optimize".to_string());
+        }
+        if params.population_size < 2 {
+            return Err("population_size must be at least 2".to_string());
+        }
+        let rng = Rng::component(params.seed, "nsga2");
+        Ok(Nsga2 {
+            bounds,
+            params,
+            population: Vec::new(),
+            generation: 0,
+            rng,
+        })
+    }
+
+    pub fn bounds(&self) -> &ParamBounds {
+        &self.bounds
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.generation >= self.params.n_generations
+    }
+
+    /// The current population ordered by rank, then by crowding distance, descending.
+    pub fn population(&self) -> &[Individual<S>] {
+        &self.population
+    }
+
+    pub fn pareto_front(&self) -> Vec<&Individual<S>> {
+        self.population
+            .iter()
+            .take_while(|individual| individual.rank == 0)
+            .collect()
+    }
+
+    /// Advances one generation; returns false without evaluating once n_generations is
+    /// reached.
+    pub fn step<F>(&mut self, fitness: &F) -> bool
+    where
+        F: Fn(&[f64]) -> (Vec<f64>, S) + Sync,
+    {
+        if self.is_done() {
+            return false;
+        }
+        let positions = if self.generation == 0 {
+            (0..self.params.population_size)
+                .map(|_| self.bounds.sample_values(&mut self.rng))
+                .collect()
+        } else {
+            self.breed()
+        };
+        let evaluations =
+            evaluate_parallel(&positions, self.params.n_threads, &|position: &Vec<f64>| {
+                fitness(position)
+            });
+        let mut pool = std::mem::take(&mut self.population);
+        pool.extend(positions.into_iter().zip(evaluations).map(
+            |(position, (objectives, stats))| Individual {
+                position,
+                objectives,
+                rank: 0,
+                crowding_distance: 0.0,
+                stats,
+            },
+        ));
+        self.population = select_survivors(pool, self.params.population_size);
+        self.generation += 1;
+        true
+    }
+
+    fn breed(&mut self) -> Vec<Vec<f64>> {
+        let n_params = self.bounds.len();
+        let mutation_prob = if self.params.mutation_prob > 0.0 {
+            self.params.mutation_prob
+        } else {
+            1.0 / n_params as f64
+        };
+        let mut children = Vec::with_capacity(self.params.population_size + 1);
+        while children.len() < self.params.population_size {
+            let parent_a = self.tournament();
+            let parent_b = self.tournament();
+            let mut child_a: Vec<f64> = self.unit_position(parent_a);
+            let mut child_b: Vec<f64> = self.unit_position(parent_b);
+            if self.rng.next_f64() < self.params.crossover_prob {
+                for i in 0..n_params {
+                    if self.rng.next_f64() < 0.5 {
+                        let (a, b) = sbx(
+                            &mut self.rng,
+                            child_a[i],
+                            child_b[i],
+                            self.params.crossover_eta,
+                        );
+                        child_a[i] = a;
+                        child_b[i] = b;
+                    }
+                }
+            }
+            for child in [&mut child_a, &mut child_b] {
+                for unit in child.iter_mut() {
+                    if self.rng.next_f64() < mutation_prob {
+                        *unit = polynomial_mutation(&mut self.rng, *unit, self.params.mutation_eta);
+                    }
+                }
+            }
+            for child in [child_a, child_b] {
+                let position = self
+                    .bounds
+                    .searched()
+                    .iter()
+                    .zip(child)
+                    .map(|(bound, unit)| bound.value_at_unit(unit))
+                    .collect();
+                children.push(position);
+            }
+        }
+        children.truncate(self.params.population_size);
+        children
+    }
+
+    fn unit_position(&self, index: usize) -> Vec<f64> {
+        self.bounds
+            .searched()
+            .iter()
+            .zip(&self.population[index].position)
+            .map(|(bound, &value)| bound.to_unit(value))
+            .collect()
+    }
+
+    // binary tournament on (rank, -crowding distance)
+    fn tournament(&mut self) -> usize {
+        let n = self.population.len();
+        let a = (self.rng.next_u64() % n as u64) as usize;
+        let b = (self.rng.next_u64() % n as u64) as usize;
+        let (ia, ib) = (&self.population[a], &self.population[b]);
+        if (ia.rank, -ia.crowding_distance) <= (ib.rank, -ib.crowding_distance) {
+            a
+        } else {
+            b
+        }
+    }
+}
+
+// keeps whole fronts while they fit, then the least crowded of the front that does not
+fn select_survivors<S>(pool: Vec<Individual<S>>, n: usize) -> Vec<Individual<S>> {
+    let objectives: Vec<Vec<f64>> = pool.iter().map(|i| i.objectives.clone()).collect();
+    let mut pool: Vec<Option<Individual<S>>> = pool.into_iter().map(Some).collect();
+    let mut survivors = Vec::with_capacity(n);
+    for (front_rank, front) in non_dominated_sort(&objectives).into_iter().enumerate() {
+        if survivors.len() >= n {
+            break;
+        }
+        let distances = crowding_distances(&objectives, &front);
+        let mut members: Vec<(usize, f64)> = front.into_iter().zip(distances).collect();
+        members.sort_by(|a, b| b.1.total_cmp(&a.1));
+        members.truncate(n - survivors.len());
+        for (index, crowding_distance) in members {
+            let mut individual = pool[index].take().expect("each index is in one front");
+            individual.rank = front_rank;
+            individual.crowding_distance = crowding_distance;
+            survivors.push(individual);
+        }
+    }
+    survivors
+}
+
+/// Exhaustive search space: a list of values per dotted parameter path, {"long.ema_span_0":
+/// [200, 400], ..}. Combinations are numbered in a fixed order, paths sorted and the last
+/// path varying fastest, so runs over the same grid evaluate them in the same order.
+#[derive(Debug, Clone, Default)]
+pub struct ParamGrid {
+    axes: Vec<(String, Vec<Value>)>,
+}
+
+impl ParamGrid {
+    pub fn from_config(grid: &Value) -> Result<Self, String> {
+        let template =
+            serde_json::to_value(BotParamsPair::default()).expect("BotParamsPair serializes");
+        let grid = grid
+            .as_object()
+            .ok_or_else(|| "grid must be an object of path: [values]".to_string())?;
+        if grid.is_empty() {
+            return Err("grid is empty".to_string());
+        }
+        let mut axes = Vec::with_capacity(grid.len());
+        let mut n_combinations = 1usize;
+        for (path, values) in grid {
+            let values = match values.as_array() {
+                Some(values) if !values.is_empty() => values.clone(),
+                _ => {
+                    return Err(format!(
+                        "grid values of '{}' must be a non-empty list",
+                        path
+                    ))
+                }
+            };
+            for value in &values {
+                // rejects unknown paths and values of the wrong type
+                set_json_path(&mut template.clone(), path, value.clone())?;
+            }
+            n_combinations = n_combinations
+                .checked_mul(values.len())
+                .ok_or_else(|| "grid has too many combinations".to_string())?;
+            axes.push((path.clone(), values));
+        }
+        axes.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(ParamGrid { axes })
+    }
+
+    /// Number of combinations.
+    pub fn len(&self) -> usize {
+        if self.axes.is_empty() {
+            return 0;
+        }
+        self.axes.iter().map(|(_, values)| values.len()).product()
+    }
+
+    /// The index-th combination as (path, value) updates, in path order.
+    pub fn combination(&self, index: usize) -> Vec<(String, Value)> {
+        let mut rest = index;
+        let mut combination: Vec<(String, Value)> = self
+            .axes
+            .iter()
+            .rev()
+            .map(|(path, values)| {
+                let value = values[rest % values.len()].clone();
+                rest /= values.len();
+                (path.clone(), value)
+            })
+            .collect();
+        combination.reverse();
+        combination
+    }
+}
+
+/// One evaluated grid combination.
+#[derive(Debug, Clone)]
+pub struct GridRow<S> {
+    pub index: usize,
+    pub params: Vec<(String, Value)>,
+    pub stats: S,
+}
+
+/// Evaluates every combination of a ParamGrid in index order, a batch at a time, each batch
+/// in parallel. Combinations marked evaluated, e.g. read back from the results of an
+/// interrupted run, are skipped.
+#[derive(Debug, Clone)]
+pub struct GridSearch {
+    grid: ParamGrid,
+    n_threads: usize, // 0 == available parallelism
+    next_index: usize,
+    evaluated: HashSet<String>, // serialized params of combinations to skip
+    n_evaluated: usize,
+    n_skipped: usize,
+}
+
+impl GridSearch {
+    pub fn new(grid: ParamGrid, n_threads: usize) -> Self {
+        GridSearch {
+            grid,
+            n_threads,
+            next_index: 0,
+            evaluated: HashSet::new(),
+            n_evaluated: 0,
+            n_skipped: 0,
+        }
+    }
+
+    pub fn grid(&self) -> &ParamGrid {
+        &self.grid
+    }
+
+    /// Skips the combination with these params, as written in a GridRow's params.
+    pub fn mark_evaluated(&mut self, params: &Map<String, Value>) {
+        self.evaluated
+            .insert(Value::Object(params.clone()).to_string());
+    }
+
+    /// Combinations evaluated by this search, not counting skipped ones.
+    pub fn n_evaluated(&self) -> usize {
+        self.n_evaluated
+    }
+
+    pub fn n_skipped(&self) -> usize {
+        self.n_skipped
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next_index >= self.grid.len()
+    }
+
+    /// Evaluates the next batch_size (0 == one per thread) combinations not marked evaluated;
+    /// rows come back in index order and are empty once the grid is done.
+    pub fn step<S, F>(&mut self, batch_size: usize, evaluate: &F) -> Vec<GridRow<S>>
+    where
+        S: Send,
+        F: Fn(&[(String, Value)]) -> S + Sync,
+    {
+        let batch_size = match batch_size {
+            0 => resolve_n_threads(self.n_threads),
+            n => n,
+        };
+        let mut batch = Vec::with_capacity(batch_size);
+        while batch.len() < batch_size && !self.is_done() {
+            let params = self.grid.combination(self.next_index);
+            if self.evaluated.contains(&params_key(&params)) {
+                self.n_skipped += 1;
+            } else {
+                batch.push((self.next_index, params));
+            }
+            self.next_index += 1;
+        }
+        let stats = evaluate_parallel(&batch, self.n_threads, &|(_, params)| evaluate(params));
+        self.n_evaluated += batch.len();
+        batch
+            .into_iter()
+            .zip(stats)
+            .map(|((index, params), stats)| GridRow {
+                index,
+                params,
+                stats,
+            })
+            .collect()
+    }
+}
+
+/// params as the JSON object written to results, {"long.ema_span_0": 200, ..}.
+pub fn params_to_json(params: &[(String, Value)]) -> Map<String, Value> {
+    params.iter().cloned().collect()
+}
+
+// serde_json maps are sorted, so equal params serialize equally
+fn params_key(params: &[(String, Value)]) -> String {
+    Value::Object(params_to_json(params)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_bounds() -> ParamBounds {
+        ParamBounds::from_config(
+            &json!({
+                "long_ema_span_0": [100.0, 1000.0],
+                "long_entry_grid_spacing_pct": {"low": 0.001, "high": 0.1, "scale": "log"},
+                "short_n_positions": [1, 10],
+            }),
+            None,
+        )
+        .unwrap()
+    }
+
+    // distance from a point inside the box, in unit space so each dimension weighs the same
+    fn sphere(bounds: &ParamBounds) -> impl Fn(&[f64]) -> (f64, usize) + Sync + '_ {
+        |position: &[f64]| {
+            let fitness = bounds
+                .searched()
+                .iter()
+                .zip(position)
+                .map(|(bound, &value)| (bound.to_unit(value) - 0.3).powi(2))
+                .sum();
+            (fitness, position.len())
+        }
+    }
+
+    fn run_swarm(seed: u64, n_threads: usize) -> (Vec<f64>, f64, Vec<Vec<f64>>) {
+        let bounds = test_bounds();
+        let fitness = sphere(&bounds);
+        let params = PsoParams {
+            swarm_size: 12,
+            n_iterations: 15,
+            seed,
+            n_threads,
+            ..Default::default()
+        };
+        let mut swarm = ParticleSwarm::new(bounds.clone(), params).unwrap();
+        while swarm.step(&fitness) {}
+        let best = swarm.best().unwrap().clone();
+        let best_n = swarm.best_n(5).iter().map(|c| c.position.clone()).collect();
+        (best.position, best.fitness, best_n)
+    }
+
+    #[test]
+    fn swarm_is_reproducible_from_its_seed() {
+        let (position, fitness, best_n) = run_swarm(7, 1);
+        assert_eq!(run_swarm(7, 1), (position.clone(), fitness, best_n.clone()));
+        // threads share out evaluations, not random draws
+        assert_eq!(run_swarm(7, 4), (position.clone(), fitness, best_n));
+        assert_ne!(run_swarm(8, 1).0, position);
+
+        let bounds = test_bounds();
+        for (bound, value) in bounds.searched().iter().zip(&position) {
+            assert!((bound.low..=bound.high).contains(value));
+        }
+        // the swarm moves toward the minimum
+        let mut swarm: ParticleSwarm<usize> = ParticleSwarm::new(
+            bounds.clone(),
+            PsoParams {
+                swarm_size: 12,
+                n_iterations: 15,
+                seed: 7,
+                n_threads: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        swarm.step(&sphere(&bounds));
+        assert!(fitness <= swarm.best().unwrap().fitness);
+    }
+
+    #[test]
+    fn sort_and_crowding_on_a_hand_checked_set() {
+        let objectives = vec![
+            vec![1.0, 5.0],
+            vec![2.0, 3.0],
+            vec![4.0, 1.0],
+            vec![3.0, 4.0], // dominated by [2, 3]
+            vec![5.0, 5.0], // dominated by [3, 4]
+            vec![2.0, 6.0], // dominated by [1, 5]
+            vec![f64::NAN, 2.0],
+        ];
+        assert!(dominates(&objectives[1], &objectives[3]));
+        assert!(!dominates(&objectives[0], &objectives[1]));
+        assert!(!dominates(&objectives[1], &objectives[1]));
+        // NaN ranks after every finite value, so [4, 1] dominates [NaN, 2]
+        assert!(dominates(&objectives[2], &objectives[6]));
+        assert!(!dominates(&objectives[6], &objectives[2]));
+
+        let fronts = non_dominated_sort(&objectives);
+        assert_eq!(fronts, vec![vec![0, 1, 2], vec![3, 5, 6], vec![4]]);
+
+        // spreads 3 and 4; [2, 3] is a third of the first and half of the second from each end
+        let distances = crowding_distances(&objectives, &fronts[0]);
+        assert_eq!(distances, vec![f64::INFINITY, 2.0, f64::INFINITY]);
+        // no spread in the second objective, which adds nothing to [2, 2]
+        let flat = vec![vec![1.0, 2.0], vec![2.0, 2.0], vec![4.0, 2.0]];
+        assert_eq!(
+            crowding_distances(&flat, &[0, 1, 2]),
+            vec![f64::INFINITY, 1.0, f64::INFINITY]
+        );
+        assert_eq!(crowding_distances(&flat, &[0, 1]), vec![f64::INFINITY; 2]);
+    }
+
+    #[test]
+    fn nsga2_keeps_its_population_sorted_by_front() {
+        let bounds = test_bounds();
+        // two conflicting objectives over the first parameter, the other two only add cost
+        let fitness = |position: &[f64]| {
+            let units: Vec<f64> = bounds
+                .searched()
+                .iter()
+                .zip(position)
+                .map(|(bound, &value)| bound.to_unit(value))
+                .collect();
+            let cost = units[1..].iter().sum::<f64>();
+            (vec![units[0] + cost, 1.0 - units[0] + cost], ())
+        };
+        let params = Nsga2Params {
+            population_size: 16,
+            n_generations: 10,
+            seed: 3,
+            n_threads: 1,
+            ..Default::default()
+        };
+        let mut nsga2 = Nsga2::new(bounds.clone(), params).unwrap();
+        while nsga2.step(&fitness) {}
+        let front: Vec<Vec<f64>> = nsga2
+            .pareto_front()
+            .iter()
+            .map(|i| i.objectives.clone())
+            .collect();
+        let population = nsga2.population();
+        assert_eq!(population.len(), 16);
+        assert!(!front.is_empty());
+        for pair in population.windows(2) {
+            assert!(pair[0].rank <= pair[1].rank);
+            if pair[0].rank == pair[1].rank {
+                assert!(pair[0].crowding_distance >= pair[1].crowding_distance);
+            }
+        }
+        for individual in population {
+            for (bound, value) in bounds.searched().iter().zip(&individual.position) {
+                assert!((bound.low..=bound.high).contains(value));
+            }
+            assert!(front.iter().all(|f| !dominates(&individual.objectives, f)));
+        }
+        let objectives: Vec<Vec<f64>> = population.iter().map(|i| i.objectives.clone()).collect();
+        assert_eq!(non_dominated_sort(&objectives)[0].len(), front.len());
+    }
+
+    #[test]
+    fn sampled_configs_validate_and_keep_frozen_fields() {
+        let bounds = ParamBounds::from_config(
+            &json!({
+                "long_ema_span_0": [100.0, 1000.0],
+                "long_entry_grid_spacing_pct": {"low": 0.001, "high": 0.1, "scale": "log"},
+                "long_enforce_exposure_limit": [0, 1],
+                "long_close_grid_qty_pct": [0.1, 1.0], // overridden by frozen below
+                "short_n_positions": [1.5, 10.2],
+                "short_ema_span_0": [300.0, 300.0],
+                "short_ema_span_1": {"low": 10.0, "high": 20.0, "frozen": true},
+            }),
+            Some(&json!({"long.close_grid_qty_pct": 0.25})),
+        )
+        .unwrap();
+        let paths: Vec<&str> = bounds.searched().iter().map(|b| b.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "long.ema_span_0",
+                "long.enforce_exposure_limit",
+                "long.entry_grid_spacing_pct",
+                "short.n_positions"
+            ]
+        );
+        assert_eq!(
+            bounds.frozen,
+            [
+                ("long.close_grid_qty_pct".to_string(), json!(0.25)),
+                ("short.ema_span_0".to_string(), json!(300.0)),
+            ]
+        );
+
+        let base = BotParamsPair {
+            short: crate::types::BotParams {
+                ema_span_1: 700.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut rng = Rng::new(11);
+        for _ in 0..200 {
+            let sampled = bounds.sample(&base, &mut rng);
+            bounds.validate(&sampled).unwrap();
+            sampled.validate_ema_spans().unwrap();
+            assert_eq!(sampled.long.close_grid_qty_pct, 0.25);
+            assert_eq!(sampled.short.ema_span_0, 300.0);
+            // frozen: true keeps the base config's value
+            assert_eq!(sampled.short.ema_span_1, 700.0);
+            assert!((2..=10).contains(&sampled.short.n_positions));
+            assert!((0.001..=0.1).contains(&sampled.long.entry_grid_spacing_pct));
+            // positions read back from a config apply to the same config
+            let values = bounds.values(&sampled);
+            assert!(bounds
+                .apply(&base, &values)
+                .unwrap()
+                .diff(&sampled)
+                .is_empty());
+        }
+
+        let mut outside = bounds.sample(&base, &mut rng);
+        outside.long.ema_span_0 = 50.0;
+        assert!(bounds.validate(&outside).is_err());
+        bounds.validate(&bounds.clip(&outside)).unwrap();
+        let mut thawed = bounds.sample(&base, &mut rng);
+        thawed.long.close_grid_qty_pct = 0.5;
+        assert!(bounds.validate(&thawed).is_err());
+        assert_eq!(bounds.clip(&thawed).long.close_grid_qty_pct, 0.25);
+    }
+
+    #[test]
+    fn cache_hits_skip_the_backtest() {
+        use std::sync::atomic::AtomicUsize;
+        let bounds = test_bounds();
+        let base = BotParamsPair::default();
+        let cache = EvaluationCache::<(f64, usize)>::new(2);
+        let backtests = AtomicUsize::new(0);
+        // stands in for a backtest: distinct stats per call, so a rerun would show
+        let evaluate = |values: &[f64], fingerprint: u64| {
+            let candidate = bounds.apply(&base, values).unwrap();
+            let key = EvaluationCache::<(f64, usize)>::calc_key(&candidate, fingerprint);
+            cache.get_or_insert_with(
+                key,
+                || {
+                    let run = backtests.fetch_add(1, Ordering::Relaxed);
+                    (candidate.long.ema_span_0, run)
+                },
+                |_| true,
+            )
+        };
+
+        let first = evaluate(&[500.0, 0.01, 3.0], 1);
+        // n_positions 3.4 rounds to the same config
+        assert_eq!(evaluate(&[500.0, 0.01, 3.4], 1), first);
+        assert_eq!(backtests.load(Ordering::Relaxed), 1);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        assert_eq!(cache.hit_rate(), 0.5);
+
+        // another dataset is another key
+        assert_ne!(evaluate(&[500.0, 0.01, 3.0], 2), first);
+        assert_eq!(backtests.load(Ordering::Relaxed), 2);
+
+        // a third entry evicts the oldest, which then runs again
+        evaluate(&[600.0, 0.01, 3.0], 1);
+        assert_eq!(cache.len(), 2);
+        assert_ne!(evaluate(&[500.0, 0.01, 3.0], 1), first);
+        assert_eq!(backtests.load(Ordering::Relaxed), 4);
+
+        // stats keep rejects, e.g. of pruned runs, are not cached
+        let key = 42;
+        cache.get_or_insert_with(key, || (0.0, 0), |_| false);
+        assert!(cache.get(key).is_none());
+        let disabled = EvaluationCache::<u8>::new(0);
+        disabled.insert(key, 1);
+        assert_eq!(disabled.len(), 0);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::types::{ExchangeParams, Order, OrderKey, OrderType, SymbolIdx};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// How long orders of one type rested, in candles from the candle whose update first laid
+/// them out. Orders keep their identity across candles while their OrderKey is unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderLifetimeStats {
+    pub order_type: OrderType,
+    pub n_created: usize,
+    pub n_filled: usize,
+    pub n_cancelled: usize,   // no longer emitted by the calculators
+    pub n_open_at_end: usize, // still resting when the backtest ended
+    pub time_to_fill_mean: f64,
+    pub time_to_fill_median: f64,
+    pub time_to_fill_p90: f64,
+    pub time_to_fill_max: usize,
+    pub cancelled_lifetime_mean: f64,
+}
+
+#[derive(Default)]
+struct Lifetimes {
+    n_created: usize,
+    times_to_fill: Vec<usize>,
+    cancelled_lifetimes: Vec<usize>,
+}
+
+/// Creation candle of every resting order per (coin, pside), fed by Backtest with each
+/// recomputed order set and each fill.
+#[derive(Default)]
+pub struct OrderLifetimeTracker {
+    resting: HashMap<(SymbolIdx, usize), Vec<(OrderKey, usize)>>,
+    lifetimes: BTreeMap<OrderType, Lifetimes>,
+}
+
+impl OrderLifetimeTracker {
+    /// orders are now open on coin idx's pside, as of candle k; those not open before are
+    /// created, those open before and missing now are cancelled.
+    pub fn update<'o>(
+        &mut self,
+        k: usize,
+        idx: SymbolIdx,
+        pside: usize,
+        orders: impl Iterator<Item = &'o Order>,
+        exchange_params: &ExchangeParams,
+    ) {
+        let keys: Vec<OrderKey> = orders
+            .map(|order| OrderKey::new(order, exchange_params))
+            .collect();
+        let resting = self.resting.entry((idx, pside)).or_default();
+        let mut kept = Vec::with_capacity(keys.len());
+        for (key, created) in resting.drain(..) {
+            if keys.contains(&key) {
+                kept.push((key, created));
+            } else {
+                let lifetimes = self.lifetimes.entry(key.order_type).or_default();
+                lifetimes.cancelled_lifetimes.push(k - created);
+            }
+        }
+        for key in keys {
+            if !kept.iter().any(|(kept_key, _)| *kept_key == key) {
+                self.lifetimes.entry(key.order_type).or_default().n_created += 1;
+                kept.push((key, k));
+            }
+        }
+        *resting = kept;
+    }
+
+    /// order filled on candle k. Matched on type and price alone, as entries may fill
+    /// cropped.
+    pub fn fill(
+        &mut self,
+        k: usize,
+        idx: SymbolIdx,
+        pside: usize,
+        order: &Order,
+        exchange_params: &ExchangeParams,
+    ) {
+        let Some(resting) = self.resting.get_mut(&(idx, pside)) else {
+            return;
+        };
+        let key = OrderKey::new(order, exchange_params);
+        let position = resting.iter().position(|(resting_key, _)| {
+            resting_key.order_type == key.order_type && resting_key.price_ticks == key.price_ticks
+        });
+        if let Some(i) = position {
+            let (_, created) = resting.remove(i);
+            let lifetimes = self.lifetimes.entry(key.order_type).or_default();
+            lifetimes.times_to_fill.push(k - created);
+        }
+    }
+
+    /// Cancels, as of candle k, the orders of every (coin, pside) for which is_open is false.
+    pub fn retain(&mut self, k: usize, is_open: impl Fn(SymbolIdx, usize) -> bool) {
+        let lifetimes = &mut self.lifetimes;
+        self.resting.retain(|&(idx, pside), resting| {
+            if is_open(idx, pside) {
+                return true;
+            }
+            for (key, created) in resting.drain(..) {
+                let entry = lifetimes.entry(key.order_type).or_default();
+                entry.cancelled_lifetimes.push(k - created);
+            }
+            false
+        });
+    }
+
+    /// One entry per order type seen, in OrderType order.
+    pub fn stats(&self) -> Vec<OrderLifetimeStats> {
+        let mut n_open: BTreeMap<OrderType, usize> = BTreeMap::new();
+        for (key, _) in self.resting.values().flatten() {
+            *n_open.entry(key.order_type).or_default() += 1;
+        }
+        self.lifetimes
+            .iter()
+            .map(|(&order_type, lifetimes)| {
+                let mut times = lifetimes.times_to_fill.clone();
+                times.sort_unstable();
+                OrderLifetimeStats {
+                    order_type,
+                    n_created: lifetimes.n_created,
+                    n_filled: times.len(),
+                    n_cancelled: lifetimes.cancelled_lifetimes.len(),
+                    n_open_at_end: n_open.get(&order_type).copied().unwrap_or(0),
+                    time_to_fill_mean: mean(&times),
+                    time_to_fill_median: quantile(&times, 0.5),
+                    time_to_fill_p90: quantile(&times, 0.9),
+                    time_to_fill_max: times.last().copied().unwrap_or(0),
+                    cancelled_lifetime_mean: mean(&lifetimes.cancelled_lifetimes),
+                }
+            })
+            .collect()
+    }
+}
+
+fn mean(values: &[usize]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<usize>() as f64 / values.len() as f64
+    }
+}
+
+// linear interpolation between the closest ranks of sorted
+fn quantile(sorted: &[usize], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = q * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lo] as f64 + (sorted[hi] as f64 - sorted[lo] as f64) * (rank - lo as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::LONG;
+
+    fn order(price: f64, order_type: OrderType) -> Order {
+        Order {
+            qty: 1.0,
+            price,
+            order_type,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        }
+    }
+
+    #[test]
+    fn scripted_lifetimes() {
+        let exchange_params = ExchangeParams {
+            qty_step: 0.001,
+            price_step: 0.01,
+            min_qty: 0.001,
+            min_cost: 1.0,
+            c_mult: 1.0,
+            ..Default::default()
+        };
+        let initial = order(99.0, OrderType::EntryInitialNormalLong);
+        let grid = order(98.0, OrderType::EntryGridNormalLong);
+        let mut tracker = OrderLifetimeTracker::default();
+        tracker.update(1, 0, LONG, [initial, grid].iter(), &exchange_params);
+        // float noise within a tick keeps the order's identity
+        let noisy = order(99.0000001, OrderType::EntryInitialNormalLong);
+        tracker.update(2, 0, LONG, [noisy, grid].iter(), &exchange_params);
+        tracker.fill(5, 0, LONG, &initial, &exchange_params);
+        // grid is replaced at 6, and its replacement dropped with its coin at 9
+        let replacement = order(97.0, OrderType::EntryGridNormalLong);
+        tracker.update(6, 0, LONG, [replacement].iter(), &exchange_params);
+        tracker.retain(9, |_, _| false);
+        tracker.update(10, 1, LONG, [initial].iter(), &exchange_params);
+        tracker.fill(13, 1, LONG, &initial, &exchange_params);
+        tracker.update(13, 1, LONG, [initial].iter(), &exchange_params);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.len(), 2);
+        let initial_stats = &stats[0];
+        assert_eq!(initial_stats.order_type, OrderType::EntryInitialNormalLong);
+        assert_eq!(
+            (
+                initial_stats.n_created,
+                initial_stats.n_filled,
+                initial_stats.n_cancelled,
+                initial_stats.n_open_at_end
+            ),
+            (3, 2, 0, 1)
+        );
+        assert_eq!(
+            (
+                initial_stats.time_to_fill_mean,
+                initial_stats.time_to_fill_max
+            ),
+            (3.5, 4)
+        );
+        assert!((initial_stats.time_to_fill_p90 - 3.9).abs() < 1e-9);
+        let grid_stats = &stats[1];
+        assert_eq!(
+            (
+                grid_stats.n_created,
+                grid_stats.n_filled,
+                grid_stats.n_cancelled,
+                grid_stats.n_open_at_end
+            ),
+            (2, 0, 2, 0)
+        );
+        assert_eq!(grid_stats.cancelled_lifetime_mean, 4.0);
+    }
+}
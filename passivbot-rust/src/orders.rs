@@ -0,0 +1,856 @@
+use crate::closes::{calc_closes_long, calc_closes_short, fresh_target_price};
+use crate::constants::{LONG, SHORT};
+use crate::entries::{calc_entries_long, calc_entries_short};
+use crate::types::{
+    BotParams, ExchangeFilters, ExchangeParams, Order, OrderKey, OrderRejection, Position,
+    StateParams, SymbolIdx, TrailingPriceBundle,
+};
+use crate::utils::{is_on_step, qty_to_cost, round_dn, round_up};
+use std::collections::{HashMap, HashSet};
+
+// notionals are not on any step; compare them with relative float noise
+const NOTIONAL_TOLERANCE: f64 = 1e-9;
+
+/// Checks order against the exchange's filters and returns the first one it breaks, in the
+/// order exchanges apply them: validity, steps, qty limits, notional limits, then the
+/// percent-price band around mark_price. Closes are reduce-only, which exempts them from
+/// min_notional.
+pub fn validate_order(
+    order: &Order,
+    filters: &ExchangeFilters,
+    mark_price: f64,
+) -> Result<(), OrderRejection> {
+    let exchange_params = &filters.exchange_params;
+    if !(order.price.is_finite() && order.price > 0.0) {
+        return Err(OrderRejection::InvalidPrice);
+    }
+    if !order.qty.is_finite() || order.qty == 0.0 {
+        return Err(OrderRejection::InvalidQty);
+    }
+    if !is_on_step(order.price, exchange_params.price_step) {
+        return Err(OrderRejection::PriceOffStep);
+    }
+    if !is_on_step(order.qty, exchange_params.qty_step) {
+        return Err(OrderRejection::QtyOffStep);
+    }
+    let qty = order.qty.abs();
+    let half_qty_step = exchange_params.qty_step * 0.5;
+    if qty < exchange_params.min_qty - half_qty_step {
+        return Err(OrderRejection::BelowMinQty);
+    }
+    if filters.max_qty > 0.0 && qty > filters.max_qty + half_qty_step {
+        return Err(OrderRejection::AboveMaxQty);
+    }
+    let notional = if filters.inverse {
+        qty * exchange_params.c_mult
+    } else {
+        qty_to_cost(qty, order.price, exchange_params.c_mult)
+    };
+    if !order.order_type.is_close()
+        && notional < exchange_params.min_cost * (1.0 - NOTIONAL_TOLERANCE)
+    {
+        return Err(OrderRejection::BelowMinNotional);
+    }
+    if filters.max_notional > 0.0 && notional > filters.max_notional * (1.0 + NOTIONAL_TOLERANCE) {
+        return Err(OrderRejection::AboveMaxNotional);
+    }
+    if exchange_params.price_band_pct > 0.0 && mark_price > 0.0 {
+        // the band's edges as apply_price_band rounds them
+        let lower = round_up(
+            mark_price * (1.0 - exchange_params.price_band_pct),
+            exchange_params.price_step,
+        );
+        let upper = round_dn(
+            mark_price * (1.0 + exchange_params.price_band_pct),
+            exchange_params.price_step,
+        );
+        let half_price_step = exchange_params.price_step * 0.5;
+        if order.price < lower - half_price_step || order.price > upper + half_price_step {
+            return Err(OrderRejection::OutsidePriceBand);
+        }
+    }
+    Ok(())
+}
+
+/// validate_order over orders to be created while n_open orders stay open. Returns the
+/// orders to submit and the dropped ones with their rejection; once max_open_orders would
+/// be reached, later valid orders are dropped as TooManyOpenOrders.
+pub fn validate_orders(
+    orders: &[Order],
+    filters: &ExchangeFilters,
+    mark_price: f64,
+    n_open: usize,
+) -> (Vec<Order>, Vec<(Order, OrderRejection)>) {
+    let mut valid = Vec::<Order>::with_capacity(orders.len());
+    let mut rejected = Vec::<(Order, OrderRejection)>::new();
+    for order in orders {
+        let result = validate_order(order, filters, mark_price).and_then(|_| {
+            if filters.max_open_orders > 0 && n_open + valid.len() >= filters.max_open_orders {
+                Err(OrderRejection::TooManyOpenOrders)
+            } else {
+                Ok(())
+            }
+        });
+        match result {
+            Ok(()) => valid.push(*order),
+            Err(rejection) => rejected.push((*order, rejection)),
+        }
+    }
+    (valid, rejected)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct OrderDiff {
+    pub to_cancel: Vec<Order>,
+    pub to_create: Vec<Order>,
+    pub rejected: Vec<(Order, OrderRejection)>, // ideal orders the exchange would reject
+}
+
+/// Cancellations and creations turning open into ideal, matching orders by OrderKey so
+/// float noise within a step causes no churn. Creations go through validate_orders, with
+/// the open orders not cancelled counting towards max_open_orders.
+pub fn diff_orders(
+    open: &[Order],
+    ideal: &[Order],
+    filters: &ExchangeFilters,
+    mark_price: f64,
+) -> OrderDiff {
+    let exchange_params = &filters.exchange_params;
+    let key_counts = |orders: &[Order]| {
+        let mut counts = HashMap::<OrderKey, usize>::new();
+        for order in orders {
+            *counts
+                .entry(OrderKey::new(order, exchange_params))
+                .or_default() += 1;
+        }
+        counts
+    };
+    // orders in a but not in b, each order in b matching at most one in a
+    let unmatched = |a: &[Order], mut b_counts: HashMap<OrderKey, usize>| {
+        a.iter()
+            .filter(
+                |order| match b_counts.get_mut(&OrderKey::new(order, exchange_params)) {
+                    Some(count) if *count > 0 => {
+                        *count -= 1;
+                        false
+                    }
+                    _ => true,
+                },
+            )
+            .copied()
+            .collect::<Vec<Order>>()
+    };
+    let to_cancel = unmatched(open, key_counts(ideal));
+    let creations = unmatched(ideal, key_counts(open));
+    let (to_create, rejected) = validate_orders(
+        &creations,
+        filters,
+        mark_price,
+        open.len() - to_cancel.len(),
+    );
+    OrderDiff {
+        to_cancel,
+        to_create,
+        rejected,
+    }
+}
+
+/// The orders the bot wants open on a pside position: entries, then closes.
+pub fn calc_ideal_orders(
+    exchange_params: &ExchangeParams,
+    state_params: &StateParams,
+    bot_params: &BotParams,
+    position: &Position,
+    trailing_price_bundle: &TrailingPriceBundle,
+    pside: usize,
+) -> Vec<Order> {
+    let (entries, closes) = match pside {
+        LONG => (
+            calc_entries_long(
+                exchange_params,
+                state_params,
+                bot_params,
+                position,
+                trailing_price_bundle,
+            ),
+            calc_closes_long(
+                exchange_params,
+                state_params,
+                bot_params,
+                position,
+                trailing_price_bundle,
+                &[],
+            ),
+        ),
+        SHORT => (
+            calc_entries_short(
+                exchange_params,
+                state_params,
+                bot_params,
+                position,
+                trailing_price_bundle,
+            ),
+            calc_closes_short(
+                exchange_params,
+                state_params,
+                bot_params,
+                position,
+                trailing_price_bundle,
+                &[],
+            ),
+        ),
+        _ => panic!("unknown pside {}", pside),
+    };
+    entries.into_iter().chain(closes).collect()
+}
+
+/// The inputs of calc_ideal_orders which change between live loop iterations, as integers:
+/// prices in ticks, the balance in buckets, and inputs only some params read left out when
+/// those params don't. The position is kept exactly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateFingerprint(Vec<i64>);
+
+impl StateFingerprint {
+    pub fn new(
+        exchange_params: &ExchangeParams,
+        state_params: &StateParams,
+        bot_params: &BotParams,
+        position: &Position,
+        trailing_price_bundle: &TrailingPriceBundle,
+        balance_resolution_pct: f64,
+    ) -> Self {
+        // saturates for the f64::MAX of unset trailing prices
+        let ticks = |price: f64| (price / exchange_params.price_step).round() as i64;
+        let exact = |value: f64| value.to_bits() as i64;
+        let balance = if balance_resolution_pct > 0.0 && state_params.balance > 0.0 {
+            (state_params.balance.ln() / balance_resolution_pct.ln_1p()).floor() as i64
+        } else {
+            exact(state_params.balance)
+        };
+        let candles_since = |n: usize| {
+            if bot_params.close_trailing_max_candles_since_peak > 0 {
+                n as i64
+            } else {
+                0
+            }
+        };
+        StateFingerprint(vec![
+            exact(position.size),
+            exact(position.price),
+            exact(position.accrued_funding),
+            balance,
+            ticks(state_params.order_book.bid),
+            ticks(state_params.order_book.ask),
+            ticks(state_params.ema_bands.lower),
+            ticks(state_params.ema_bands.upper),
+            ticks(state_params.trailing_ma),
+            bot_params.close_volume_confirmed(state_params.volume) as i64,
+            if bot_params.close_max_qty_pct_of_volume > 0.0 {
+                exact(state_params.avg_volume)
+            } else {
+                0
+            },
+            fresh_target_price(state_params, bot_params).map_or(-1, ticks),
+            exact(bot_params.liquidity_multiplier(state_params.hour)),
+            if bot_params.compound_realized_into_balance {
+                exact(state_params.realized_pnl)
+            } else {
+                0
+            },
+            bot_params.equity_drawdown_triggered(state_params) as i64,
+            exact(state_params.min_markup_floor),
+            if bot_params.close_on_flow_imbalance > 0.0 {
+                state_params.order_flow_imbalance.map_or(-1, exact)
+            } else {
+                0
+            },
+            state_params.close_markup_scale.map_or(-1, exact),
+            ticks(trailing_price_bundle.min_since_open),
+            ticks(trailing_price_bundle.max_since_min),
+            ticks(trailing_price_bundle.max_since_open),
+            ticks(trailing_price_bundle.min_since_max),
+            candles_since(trailing_price_bundle.candles_since_min),
+            candles_since(trailing_price_bundle.candles_since_max),
+            trailing_price_bundle.fib_levels_closed as i64,
+            ticks(trailing_price_bundle.stepped_stop_price),
+        ])
+    }
+}
+
+/// calc_ideal_orders for the live loop, recomputed per (symbol, pside) only when its
+/// StateFingerprint changes, so EMA moves below a tick and balance moves within
+/// balance_resolution_pct reuse the last orders. Bot and exchange params are not
+/// fingerprinted; invalidate after changing them.
+#[derive(Debug, Default)]
+pub struct IdealOrdersCache {
+    balance_resolution_pct: f64, // balances within this fraction share a bucket; 0.0 == exact
+    entries: HashMap<(SymbolIdx, usize), (StateFingerprint, Vec<Order>)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl IdealOrdersCache {
+    pub fn new(balance_resolution_pct: f64) -> Self {
+        IdealOrdersCache {
+            balance_resolution_pct,
+            ..Default::default()
+        }
+    }
+
+    /// calc_ideal_orders for the ladder keyed (idx, pside). Counts a hit or a miss.
+    pub fn calc_ideal_orders(
+        &mut self,
+        (idx, pside): (SymbolIdx, usize),
+        exchange_params: &ExchangeParams,
+        state_params: &StateParams,
+        bot_params: &BotParams,
+        position: &Position,
+        trailing_price_bundle: &TrailingPriceBundle,
+    ) -> Vec<Order> {
+        let fingerprint = StateFingerprint::new(
+            exchange_params,
+            state_params,
+            bot_params,
+            position,
+            trailing_price_bundle,
+            self.balance_resolution_pct,
+        );
+        if let Some((cached_fingerprint, orders)) = self.entries.get(&(idx, pside)) {
+            if *cached_fingerprint == fingerprint {
+                self.hits += 1;
+                return orders.clone();
+            }
+        }
+        self.misses += 1;
+        let orders = calc_ideal_orders(
+            exchange_params,
+            state_params,
+            bot_params,
+            position,
+            trailing_price_bundle,
+            pside,
+        );
+        self.entries
+            .insert((idx, pside), (fingerprint, orders.clone()));
+        orders
+    }
+
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+/// Releases each close ladder, per (symbol, pside), over placement_spread_candles rather than
+/// all at once, so the book doesn't reveal the whole ladder to front-runners. Levels release
+/// in ladder order, nearest first, and stay released while their price stays in the ladder;
+/// an empty ladder resets its schedule.
+#[derive(Debug, Default)]
+pub struct CloseScheduler {
+    placement_spread_candles: usize, // 0 or 1 == release every level at once
+    ladders: HashMap<(SymbolIdx, usize), ScheduledLadder>,
+}
+
+#[derive(Debug)]
+struct ScheduledLadder {
+    first_k: usize,
+    released_price_ticks: HashSet<i64>,
+}
+
+impl CloseScheduler {
+    pub fn new(placement_spread_candles: usize) -> Self {
+        CloseScheduler {
+            placement_spread_candles,
+            ..Default::default()
+        }
+    }
+
+    /// The levels of closes released by candle k: of n levels, ceil(n * candles elapsed /
+    /// placement_spread_candles), counting candle k.
+    pub fn release(
+        &mut self,
+        idx: SymbolIdx,
+        pside: usize,
+        closes: &[Order],
+        exchange_params: &ExchangeParams,
+        k: usize,
+    ) -> Vec<Order> {
+        if closes.is_empty() {
+            self.ladders.remove(&(idx, pside));
+            return Vec::new();
+        }
+        if self.placement_spread_candles <= 1 {
+            return closes.to_vec();
+        }
+        let ladder = self
+            .ladders
+            .entry((idx, pside))
+            .or_insert_with(|| ScheduledLadder {
+                first_k: k,
+                released_price_ticks: HashSet::new(),
+            });
+        let n_candles = (k.saturating_sub(ladder.first_k) + 1).min(self.placement_spread_candles);
+        let n_due = (closes.len() * n_candles).div_ceil(self.placement_spread_candles);
+        let price_ticks: Vec<i64> = closes
+            .iter()
+            .map(|close| (close.price / exchange_params.price_step).round() as i64)
+            .collect();
+        // levels which left the ladder, filled or moved, are forgotten
+        ladder
+            .released_price_ticks
+            .retain(|ticks| price_ticks.contains(ticks));
+        for &ticks in &price_ticks {
+            if ladder.released_price_ticks.len() >= n_due {
+                break;
+            }
+            ladder.released_price_ticks.insert(ticks);
+        }
+        closes
+            .iter()
+            .zip(&price_ticks)
+            .filter(|(_, ticks)| ladder.released_price_ticks.contains(ticks))
+            .map(|(&close, _)| close)
+            .collect()
+    }
+
+    /// Number of distinct price levels released so far of the (idx, pside) ladder.
+    pub fn n_released(&self, idx: SymbolIdx, pside: usize) -> usize {
+        self.ladders
+            .get(&(idx, pside))
+            .map_or(0, |ladder| ladder.released_price_ticks.len())
+    }
+
+    pub fn reset(&mut self) {
+        self.ladders.clear();
+    }
+}
+
+/// diff_orders for close ladders in the live loop which holds each resting close at its
+/// price, per (symbol, pside) and ladder level, until min_amend_interval_candles have passed
+/// since that level was last placed or amended, so a grid price drifting a tick or two
+/// doesn't amend the order every candle. Levels are matched nearest first; a level whose
+/// order type changes is not held.
+#[derive(Debug, Default)]
+pub struct CloseAmendThrottle {
+    min_amend_interval_candles: usize, // 0 == amend whenever the price changes
+    last_amend_candles: HashMap<(SymbolIdx, usize), Vec<Option<usize>>>, // None == not seen
+}
+
+impl CloseAmendThrottle {
+    pub fn new(min_amend_interval_candles: usize) -> Self {
+        CloseAmendThrottle {
+            min_amend_interval_candles,
+            ..Default::default()
+        }
+    }
+
+    /// diff_orders of open closes against ideal closes at candle k, with held levels' ideal
+    /// prices replaced by their resting prices.
+    pub fn diff_closes(
+        &mut self,
+        (idx, pside): (SymbolIdx, usize),
+        open: &[Order],
+        ideal: &[Order],
+        filters: &ExchangeFilters,
+        mark_price: f64,
+        k: usize,
+    ) -> OrderDiff {
+        if ideal.is_empty() {
+            self.last_amend_candles.remove(&(idx, pside));
+            return diff_orders(open, ideal, filters, mark_price);
+        }
+        let exchange_params = &filters.exchange_params;
+        let nearest_first = |orders: &[Order]| {
+            let mut orders = orders.to_vec();
+            if pside == LONG {
+                orders.sort_by(|a, b| a.price.total_cmp(&b.price));
+            } else {
+                orders.sort_by(|a, b| b.price.total_cmp(&a.price));
+            }
+            orders
+        };
+        let open_levels = nearest_first(open);
+        let mut held = nearest_first(ideal);
+        let last_amend_candles = self.last_amend_candles.entry((idx, pside)).or_default();
+        last_amend_candles.resize(held.len(), None);
+        for (level, close) in held.iter_mut().enumerate() {
+            let last_amend_candle = &mut last_amend_candles[level];
+            let resting = match open_levels.get(level) {
+                Some(resting) if resting.order_type == close.order_type => resting,
+                _ => {
+                    *last_amend_candle = Some(k);
+                    continue;
+                }
+            };
+            if OrderKey::new(resting, exchange_params).price_ticks
+                == OrderKey::new(close, exchange_params).price_ticks
+            {
+                continue;
+            }
+            match *last_amend_candle {
+                Some(last) if k < last + self.min_amend_interval_candles => {
+                    close.price = resting.price;
+                }
+                _ => *last_amend_candle = Some(k),
+            }
+        }
+        diff_orders(open, &held, filters, mark_price)
+    }
+
+    /// Candle the (idx, pside) ladder's level was last placed or amended at.
+    pub fn last_amend_candle(&self, idx: SymbolIdx, pside: usize, level: usize) -> Option<usize> {
+        self.last_amend_candles
+            .get(&(idx, pside))
+            .and_then(|levels| levels.get(level).copied().flatten())
+    }
+
+    pub fn reset(&mut self) {
+        self.last_amend_candles.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{EMABands, OrderBook, OrderType};
+
+    fn order(qty: f64, price: f64) -> Order {
+        Order {
+            qty,
+            price,
+            order_type: OrderType::CloseGridLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        }
+    }
+
+    #[test]
+    fn diff_orders_matches_orders_within_a_tick() {
+        let filters = ExchangeFilters {
+            exchange_params: ExchangeParams {
+                qty_step: 0.001,
+                price_step: 0.01,
+                min_qty: 0.001,
+                min_cost: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let open = [order(-0.3, 100.01), order(-0.3, 100.5)];
+        // as recomputed by the bot: the same ladder up to float noise
+        let ideal = [
+            order(-0.1 - 0.2, 100.01 + 1e-12),
+            order(-0.3, 100.5 - 1e-12),
+        ];
+        let diff = diff_orders(&open, &ideal, &filters, 100.0);
+        assert!(diff.to_cancel.is_empty() && diff.to_create.is_empty());
+
+        // one level moved a tick
+        let ideal = [order(-0.3, 100.01), order(-0.3, 100.51)];
+        let diff = diff_orders(&open, &ideal, &filters, 100.0);
+        assert_eq!(diff.to_cancel.len(), 1);
+        assert_eq!(diff.to_cancel[0].price, 100.5);
+        assert_eq!(diff.to_create.len(), 1);
+        assert_eq!(diff.to_create[0].price, 100.51);
+    }
+
+    #[test]
+    fn validate_orders_reports_the_first_filter_broken() {
+        let filters = ExchangeFilters {
+            exchange_params: ExchangeParams {
+                qty_step: 0.001,
+                price_step: 0.01,
+                min_qty: 0.01,
+                min_cost: 5.0,
+                c_mult: 1.0,
+                price_band_pct: 0.05,
+                ..Default::default()
+            },
+            max_qty: 10.0,
+            max_notional: 500.0,
+            max_open_orders: 0,
+            inverse: false,
+        };
+        let entry = |qty: f64, price: f64| Order {
+            order_type: OrderType::EntryGridNormalLong,
+            ..order(qty, price)
+        };
+        for (order, rejection) in [
+            (entry(0.1, 0.0), OrderRejection::InvalidPrice),
+            (entry(0.1, f64::NAN), OrderRejection::InvalidPrice),
+            (entry(0.0, 100.0), OrderRejection::InvalidQty),
+            (entry(0.1, 100.005), OrderRejection::PriceOffStep),
+            (entry(0.1005, 100.0), OrderRejection::QtyOffStep),
+            (entry(0.005, 100.0), OrderRejection::BelowMinQty),
+            (entry(11.0, 100.0), OrderRejection::AboveMaxQty),
+            (entry(0.04, 100.0), OrderRejection::BelowMinNotional),
+            (entry(5.1, 100.0), OrderRejection::AboveMaxNotional),
+            (entry(0.1, 94.0), OrderRejection::OutsidePriceBand),
+            (entry(0.1, 106.0), OrderRejection::OutsidePriceBand),
+        ] {
+            assert_eq!(
+                validate_order(&order, &filters, 100.0),
+                Err(rejection),
+                "{:?}",
+                order
+            );
+        }
+        assert_eq!(validate_order(&entry(0.1, 100.0), &filters, 100.0), Ok(()));
+        // closes are reduce-only and exempt from min_notional
+        assert_eq!(
+            validate_order(&order(-0.04, 100.0), &filters, 100.0),
+            Ok(())
+        );
+        // on inverse contracts the notional is qty * c_mult
+        let inverse = ExchangeFilters {
+            inverse: true,
+            ..filters.clone()
+        };
+        assert_eq!(
+            validate_order(&entry(1.0, 100.0), &inverse, 100.0),
+            Err(OrderRejection::BelowMinNotional)
+        );
+
+        // past max_open_orders, later valid orders are dropped; invalid ones keep their reason
+        let filters = ExchangeFilters {
+            max_open_orders: 3,
+            ..filters
+        };
+        let orders = [
+            entry(0.1, 100.0),
+            entry(0.0, 100.0),
+            entry(0.1, 99.0),
+            entry(0.1, 98.0),
+        ];
+        let (valid, rejected) = validate_orders(&orders, &filters, 100.0, 1);
+        assert_eq!(
+            valid.iter().map(|order| order.price).collect::<Vec<_>>(),
+            [100.0, 99.0]
+        );
+        assert_eq!(
+            rejected
+                .iter()
+                .map(|(order, rejection)| (order.qty, order.price, *rejection))
+                .collect::<Vec<_>>(),
+            [
+                (0.0, 100.0, OrderRejection::InvalidQty),
+                (0.1, 98.0, OrderRejection::TooManyOpenOrders),
+            ]
+        );
+    }
+
+    #[test]
+    fn ideal_orders_are_reused_until_the_fingerprint_changes() {
+        let exchange_params = ExchangeParams {
+            qty_step: 0.001,
+            price_step: 0.01,
+            min_qty: 0.001,
+            min_cost: 1.0,
+            c_mult: 1.0,
+            ..Default::default()
+        };
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.005,
+            close_grid_qty_pct: 0.2,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        };
+        let position = Position {
+            size: 4.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let state_params = |balance: f64, ema: f64| StateParams {
+            balance,
+            order_book: OrderBook::new(100.0, 100.01),
+            ema_bands: EMABands {
+                lower: ema,
+                upper: ema,
+            },
+            ..Default::default()
+        };
+        let mut cache = IdealOrdersCache::new(0.01);
+        let orders = |cache: &mut IdealOrdersCache, idx: SymbolIdx, state: &StateParams| {
+            cache
+                .calc_ideal_orders(
+                    (idx, LONG),
+                    &exchange_params,
+                    state,
+                    &bot_params,
+                    &position,
+                    &TrailingPriceBundle::default(),
+                )
+                .iter()
+                .map(|order| (order.qty, order.price))
+                .collect::<Vec<_>>()
+        };
+        let first = orders(&mut cache, 0, &state_params(1000.0, 99.0));
+        assert!(!first.is_empty());
+        // an EMA move within a tick and a balance move within 1% reuse the ladder
+        assert_eq!(orders(&mut cache, 0, &state_params(1004.0, 99.001)), first);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        // a tick's move recomputes, as does another symbol
+        orders(&mut cache, 0, &state_params(1000.0, 99.02));
+        orders(&mut cache, 1, &state_params(1000.0, 99.02));
+        assert_eq!((cache.hits(), cache.misses(), cache.len()), (1, 3, 2));
+        // a balance moved past its bucket resizes the ladder
+        let richer = orders(&mut cache, 0, &state_params(2000.0, 99.02));
+        assert_ne!(richer, first);
+        assert_eq!(cache.misses(), 4);
+
+        cache.invalidate();
+        assert_eq!(cache.len(), 0);
+        assert_eq!(orders(&mut cache, 0, &state_params(1000.0, 99.0)), first);
+        assert_eq!(cache.misses(), 5);
+    }
+
+    #[test]
+    fn close_ladders_are_released_over_the_spread_candles() {
+        let exchange_params = ExchangeParams {
+            price_step: 0.01,
+            ..Default::default()
+        };
+        let ladder = [
+            order(-1.0, 100.9),
+            order(-1.0, 101.3),
+            order(-1.0, 101.7),
+            order(-1.0, 102.1),
+        ];
+        let prices =
+            |closes: Vec<Order>| closes.iter().map(|close| close.price).collect::<Vec<_>>();
+        let mut scheduler = CloseScheduler::new(4);
+        // nearest levels first, a quarter of the ladder per candle
+        for (k, expected) in [
+            (10, vec![100.9]),
+            (11, vec![100.9, 101.3]),
+            (12, vec![100.9, 101.3, 101.7]),
+            (13, vec![100.9, 101.3, 101.7, 102.1]),
+            (20, vec![100.9, 101.3, 101.7, 102.1]),
+        ] {
+            assert_eq!(
+                prices(scheduler.release(0, LONG, &ladder, &exchange_params, k)),
+                expected
+            );
+        }
+        // other ladders keep their own schedule
+        assert_eq!(
+            prices(scheduler.release(0, SHORT, &ladder, &exchange_params, 20)),
+            vec![100.9]
+        );
+        assert_eq!(scheduler.n_released(1, LONG), 0);
+        // a filled level is forgotten while the rest stay released
+        assert_eq!(
+            prices(scheduler.release(0, LONG, &ladder[1..], &exchange_params, 21)),
+            vec![101.3, 101.7, 102.1]
+        );
+        assert_eq!(scheduler.n_released(0, LONG), 3);
+        // an empty ladder starts over
+        assert!(scheduler
+            .release(0, LONG, &[], &exchange_params, 22)
+            .is_empty());
+        assert_eq!(
+            prices(scheduler.release(0, LONG, &ladder, &exchange_params, 23)),
+            vec![100.9]
+        );
+        // without a spread every level goes out at once
+        assert_eq!(
+            CloseScheduler::new(1)
+                .release(0, LONG, &ladder, &exchange_params, 0)
+                .len(),
+            4
+        );
+    }
+
+    #[test]
+    fn close_amends_are_held_for_the_interval() {
+        let filters = ExchangeFilters {
+            exchange_params: ExchangeParams {
+                qty_step: 0.001,
+                price_step: 0.01,
+                min_qty: 0.001,
+                min_cost: 1.0,
+                c_mult: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let prices = |orders: &[Order]| orders.iter().map(|o| o.price).collect::<Vec<_>>();
+        let mut throttle = CloseAmendThrottle::new(5);
+        let ladder = [order(-0.3, 100.9), order(-0.3, 101.3)];
+        // new levels go out at once
+        let diff = throttle.diff_closes((0, LONG), &[], &ladder, &filters, 100.0, 0);
+        assert_eq!(prices(&diff.to_create), vec![100.9, 101.3]);
+        assert_eq!(throttle.last_amend_candle(0, LONG, 0), Some(0));
+
+        // a level drifting a tick keeps its resting price within the interval
+        let drifted = [order(-0.3, 100.91), order(-0.3, 101.3)];
+        let diff = throttle.diff_closes((0, LONG), &ladder, &drifted, &filters, 100.0, 1);
+        assert!(diff.to_cancel.is_empty() && diff.to_create.is_empty());
+
+        // a qty change goes through at the held price
+        let resized = [order(-0.2, 100.92), order(-0.3, 101.3)];
+        let diff = throttle.diff_closes((0, LONG), &ladder, &resized, &filters, 100.0, 2);
+        assert_eq!(
+            diff.to_cancel
+                .iter()
+                .map(|o| (o.qty, o.price))
+                .collect::<Vec<_>>(),
+            vec![(-0.3, 100.9)]
+        );
+        assert_eq!(
+            diff.to_create
+                .iter()
+                .map(|o| (o.qty, o.price))
+                .collect::<Vec<_>>(),
+            vec![(-0.2, 100.9)]
+        );
+        assert_eq!(throttle.last_amend_candle(0, LONG, 0), Some(0));
+
+        // once the interval has passed the level is amended
+        let open = [order(-0.2, 100.9), order(-0.3, 101.3)];
+        let diff = throttle.diff_closes((0, LONG), &open, &resized, &filters, 100.0, 5);
+        assert_eq!(prices(&diff.to_cancel), vec![100.9]);
+        assert_eq!(prices(&diff.to_create), vec![100.92]);
+        assert_eq!(throttle.last_amend_candle(0, LONG, 0), Some(5));
+        assert_eq!(throttle.last_amend_candle(0, LONG, 1), Some(0));
+
+        // an order type change is never held
+        let open = [order(-0.2, 100.92), order(-0.3, 101.3)];
+        let unstuck = [
+            Order {
+                order_type: OrderType::CloseUnstuckLong,
+                ..order(-0.2, 100.93)
+            },
+            order(-0.3, 101.3),
+        ];
+        let diff = throttle.diff_closes((0, LONG), &open, &unstuck, &filters, 100.0, 6);
+        assert_eq!(prices(&diff.to_create), vec![100.93]);
+        assert_eq!(throttle.last_amend_candle(0, LONG, 0), Some(6));
+
+        // an empty ladder forgets its levels
+        throttle.diff_closes((0, LONG), &open, &[], &filters, 100.0, 7);
+        assert_eq!(throttle.last_amend_candle(0, LONG, 0), None);
+        // without an interval every drift is amended
+        let diff = CloseAmendThrottle::new(0).diff_closes(
+            (0, LONG),
+            &ladder,
+            &drifted,
+            &filters,
+            100.0,
+            1,
+        );
+        assert_eq!(prices(&diff.to_create), vec![100.91]);
+    }
+}
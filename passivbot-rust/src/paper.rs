@@ -0,0 +1,141 @@
+use crate::backtest::Backtest;
+use crate::constants::{CLOSE, HIGH, LOW, VOLUME};
+use crate::types::{
+    BacktestParams, BotParamsPair, ExchangeParams, Fill, Order, SymbolIdx, TradingMask,
+};
+use ndarray::Array2;
+
+/// The backtest's fill engine driven live, one candle at a time: update_market feeds the
+/// forming candle and apply_fills closes it. A backtest lays out a full ladder only when
+/// the candle after would fill it, so candle k is stepped once candle k + 1 is closed,
+/// lagging one candle as run does at its end: replaying candles 0..=k yields exactly the
+/// fills run_backtest yields on them. Coins are backtest_params.coins; collateral is USD,
+/// and coins are never treated as delisted.
+pub struct PaperTrader {
+    bot_params_pair: BotParamsPair,
+    exchange_params_list: Vec<ExchangeParams>,
+    backtest_params: BacktestParams,
+    backtest: Option<Backtest<'static>>, // from the first candle on
+    candles: Array2<f64>,                // forming candle per coin, [coin, HIGH..=VOLUME]
+    updated: Vec<bool>,                  // coins with market data in the forming candle
+}
+
+impl PaperTrader {
+    pub fn new(
+        bot_params_pair: BotParamsPair,
+        exchange_params_list: Vec<ExchangeParams>,
+        backtest_params: BacktestParams,
+    ) -> Result<Self, String> {
+        let n_coins = backtest_params.coins.len();
+        if exchange_params_list.len() != n_coins {
+            return Err(format!(
+                "{} exchange params for {} coins",
+                exchange_params_list.len(),
+                n_coins
+            ));
+        }
+        Ok(PaperTrader {
+            bot_params_pair,
+            exchange_params_list,
+            backtest_params,
+            backtest: None,
+            candles: Array2::zeros((n_coins, VOLUME + 1)),
+            updated: vec![false; n_coins],
+        })
+    }
+
+    pub fn coins(&self) -> &[String] {
+        &self.backtest_params.coins
+    }
+
+    pub fn coin_index(&self, coin: &str) -> Result<SymbolIdx, String> {
+        self.backtest_params
+            .coins
+            .iter()
+            .position(|c| c == coin)
+            .map(|idx| idx as SymbolIdx)
+            .ok_or_else(|| format!("unknown coin {}", coin))
+    }
+
+    /// Feeds market data into coin idx's forming candle. Updates within one candle merge,
+    /// so book updates can be fed as they come: high and low widen, close is the latest
+    /// and volumes add up.
+    pub fn update_market(&mut self, idx: SymbolIdx, high: f64, low: f64, close: f64, volume: f64) {
+        let i = idx as usize;
+        let mut candle = self.candles.row_mut(i);
+        if self.updated[i] {
+            candle[HIGH] = candle[HIGH].max(high);
+            candle[LOW] = candle[LOW].min(low);
+            candle[VOLUME] += volume;
+        } else {
+            candle[HIGH] = high;
+            candle[LOW] = low;
+            candle[VOLUME] = volume;
+        }
+        candle[CLOSE] = close;
+        self.updated[i] = true;
+    }
+
+    /// Sets coin's trading mask, in this run and in any backtest started later.
+    pub fn set_trading_mask(&mut self, coin: &str, mask: TradingMask) -> Result<(), String> {
+        let idx = self.coin_index(coin)?;
+        self.backtest_params
+            .trading_masks
+            .insert(coin.to_string(), mask);
+        if let Some(backtest) = self.backtest.as_mut() {
+            backtest.set_trading_mask(idx, mask);
+        }
+        Ok(())
+    }
+
+    /// Closes the forming candle and steps the backtest over the one before it, filling the
+    /// orders left by the candle before that; returns the new fills. Coins without an update
+    /// this candle repeat their last close with no volume; the first candle needs them all.
+    pub fn apply_fills(&mut self) -> Result<Vec<Fill>, String> {
+        for (i, updated) in self.updated.iter().enumerate() {
+            if *updated {
+                continue;
+            }
+            if self.backtest.is_none() {
+                return Err(format!(
+                    "no market data for {}",
+                    self.backtest_params.coins[i]
+                ));
+            }
+            let mut candle = self.candles.row_mut(i);
+            let last_close = candle[CLOSE];
+            candle[HIGH] = last_close;
+            candle[LOW] = last_close;
+            candle[VOLUME] = 0.0;
+        }
+        self.updated.iter_mut().for_each(|updated| *updated = false);
+        let backtest = match self.backtest.as_mut() {
+            Some(backtest) => backtest,
+            None => {
+                self.backtest = Some(Backtest::new_stepwise(
+                    self.candles.view(),
+                    self.bot_params_pair.clone(),
+                    self.exchange_params_list.clone(),
+                    &self.backtest_params,
+                ));
+                return Ok(Vec::new());
+            }
+        };
+        let k = backtest.push_candles(self.candles.view())?;
+        if k < 2 {
+            // candle 0 only seeds the EMAs
+            return Ok(Vec::new());
+        }
+        let n_fills = backtest.fills().len();
+        backtest.step(k - 1);
+        Ok(backtest.fills()[n_fills..].to_vec())
+    }
+
+    /// The orders the bot wants open on coin idx's pside position as of the last candle
+    /// stepped, i.e. during the last candle closed: entries, then closes.
+    pub fn ideal_orders(&self, idx: SymbolIdx, pside: usize) -> Vec<Order> {
+        self.backtest
+            .as_ref()
+            .map_or_else(Vec::new, |backtest| backtest.open_orders(idx, pside))
+    }
+}
@@ -0,0 +1,179 @@
+use crate::optimizer::dominates;
+
+// Utilities over sets of objective vectors, all minimized as in Nsga2. Solutions with a
+// NaN or inf objective rank after every finite one, matching dominates.
+
+/// Indices of the non-dominated solutions, ascending. Duplicates on the front are all kept.
+pub fn pareto_front(objectives: &[Vec<f64>]) -> Result<Vec<usize>, String> {
+    check_dimensions(objectives)?;
+    Ok((0..objectives.len())
+        .filter(|&i| {
+            !objectives
+                .iter()
+                .any(|other| dominates(other, &objectives[i]))
+        })
+        .collect())
+}
+
+/// Volume of objective space dominated by the solutions and bounded by reference. Only
+/// solutions strictly better than reference in every objective contribute. Exact, by
+/// slicing along the last objective; cost grows as n^(dimensions - 1).
+pub fn hypervolume(objectives: &[Vec<f64>], reference: &[f64]) -> Result<f64, String> {
+    let n_objectives = check_dimensions(objectives)?.unwrap_or(reference.len());
+    if reference.len() != n_objectives {
+        return Err(format!(
+            "reference has {} objectives, solutions have {}",
+            reference.len(),
+            n_objectives
+        ));
+    }
+    if n_objectives == 0 || reference.iter().any(|value| !value.is_finite()) {
+        return Err("reference must hold at least one objective, all finite".to_string());
+    }
+    let points: Vec<Vec<f64>> = objectives
+        .iter()
+        .filter(|point| point.iter().zip(reference).all(|(&x, &r)| x < r))
+        .cloned()
+        .collect();
+    Ok(sliced_volume(points, reference))
+}
+
+/// Up to count front members nearest the knee, best first. Objectives are normalized over
+/// the front to [0, 1], ideal to nadir, and each member scored by how far it lies below the
+/// hyperplane through the normalized extremes; ties go to the lower index. Members with a
+/// non-finite objective are never knees.
+pub fn knee_points(objectives: &[Vec<f64>], count: usize) -> Result<Vec<usize>, String> {
+    let front: Vec<usize> = pareto_front(objectives)?
+        .into_iter()
+        .filter(|&i| objectives[i].iter().all(|value| value.is_finite()))
+        .collect();
+    let n_objectives = front.first().map_or(0, |&i| objectives[i].len());
+    let mut ideal = vec![f64::INFINITY; n_objectives];
+    let mut nadir = vec![f64::NEG_INFINITY; n_objectives];
+    for &i in &front {
+        for (m, &value) in objectives[i].iter().enumerate() {
+            ideal[m] = ideal[m].min(value);
+            nadir[m] = nadir[m].max(value);
+        }
+    }
+    let score = |i: usize| {
+        let normalized_sum: f64 = objectives[i]
+            .iter()
+            .enumerate()
+            .map(|(m, &value)| {
+                let spread = nadir[m] - ideal[m];
+                // objectives with no spread over the front don't tell members apart
+                if spread > 0.0 {
+                    (value - ideal[m]) / spread
+                } else {
+                    0.0
+                }
+            })
+            .sum();
+        (1.0 - normalized_sum) / (n_objectives as f64).sqrt()
+    };
+    let mut scored: Vec<(usize, f64)> = front.iter().map(|&i| (i, score(i))).collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(scored.into_iter().take(count).map(|(i, _)| i).collect())
+}
+
+// number of objectives shared by every solution, None if there are none
+fn check_dimensions(objectives: &[Vec<f64>]) -> Result<Option<usize>, String> {
+    let n_objectives = match objectives.first() {
+        Some(first) => first.len(),
+        None => return Ok(None),
+    };
+    match objectives.iter().position(|o| o.len() != n_objectives) {
+        Some(i) => Err(format!(
+            "solution {} has {} objectives, expected {}",
+            i,
+            objectives[i].len(),
+            n_objectives
+        )),
+        None => Ok(Some(n_objectives)),
+    }
+}
+
+// points are all strictly inside reference
+fn sliced_volume(mut points: Vec<Vec<f64>>, reference: &[f64]) -> f64 {
+    let last = reference.len() - 1;
+    if last == 0 {
+        return points
+            .iter()
+            .map(|point| reference[0] - point[0])
+            .fold(0.0, f64::max);
+    }
+    points.sort_by(|a, b| a[last].total_cmp(&b[last]));
+    let mut volume = 0.0;
+    for i in 0..points.len() {
+        let top = points.get(i + 1).map_or(reference[last], |next| next[last]);
+        let height = top - points[i][last];
+        if height > 0.0 {
+            // the slab between this point and the next is covered by every point so far
+            let slice: Vec<Vec<f64>> = points[..=i]
+                .iter()
+                .map(|point| point[..last].to_vec())
+                .collect();
+            volume += height * sliced_volume(slice, &reference[..last]);
+        }
+    }
+    volume
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn front_and_hypervolume_in_2d() {
+        // (3, 3) is dominated by (2, 2)
+        let objectives = vec![
+            vec![1.0, 4.0],
+            vec![2.0, 2.0],
+            vec![4.0, 1.0],
+            vec![3.0, 3.0],
+        ];
+        assert_eq!(pareto_front(&objectives).unwrap(), [0, 1, 2]);
+        // rectangles to (5, 5) of 4, 9 and 4, overlapping by 3, 3 and 1, with 1 common to all
+        assert_eq!(hypervolume(&objectives, &[5.0, 5.0]).unwrap(), 11.0);
+        assert_eq!(hypervolume(&objectives[..3], &[5.0, 5.0]).unwrap(), 11.0);
+        // only points strictly inside the reference count: (2, 2), whose 2x2 box holds (3, 3)
+        assert_eq!(hypervolume(&objectives, &[4.0, 4.0]).unwrap(), 4.0);
+        assert_eq!(hypervolume(&[], &[5.0, 5.0]).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn front_and_hypervolume_in_3d() {
+        let objectives = vec![
+            vec![0.0, 1.0, 1.0],
+            vec![1.0, 0.0, 1.0],
+            vec![1.0, 1.0, 0.0],
+            vec![1.5, 1.5, 1.5],
+            vec![3.0, 0.0, 0.0],
+        ];
+        assert_eq!(pareto_front(&objectives).unwrap(), [0, 1, 2, 4]);
+        // boxes to (2, 2, 2) of 2 each, pairwise overlapping by 1, with 1 common to all;
+        // (3, 0, 0) lies outside the reference
+        assert_eq!(hypervolume(&objectives, &[2.0, 2.0, 2.0]).unwrap(), 4.0);
+        assert_eq!(
+            hypervolume(&objectives[..1], &[2.0, 2.0, 2.0]).unwrap(),
+            2.0
+        );
+        assert!(hypervolume(&objectives, &[2.0, 2.0]).is_err());
+        assert!(pareto_front(&[vec![0.0, 1.0], vec![1.0]]).is_err());
+    }
+
+    #[test]
+    fn knee_is_the_most_balanced_front_member() {
+        let objectives = vec![
+            vec![0.0, 1.0],
+            vec![1.0, 0.0],
+            vec![0.2, 0.2],
+            vec![0.6, 0.1],
+            vec![0.5, 0.6],
+            vec![f64::NAN, 0.0],
+        ];
+        assert_eq!(knee_points(&objectives, 2).unwrap(), [2, 3]);
+        assert_eq!(knee_points(&objectives, 10).unwrap().len(), 4);
+    }
+}
@@ -1,200 +1,1058 @@
-use crate::backtest::{analyze_backtest_pair, Backtest};
+use crate::backtest::{
+    downsample_equities, evaluate_backtest, reconstruct_trailing_price_bundle, Backtest,
+};
 use crate::closes::{
-    calc_closes_long, calc_closes_short, calc_next_close_long, calc_next_close_short,
+    calc_all_closes_ordered, calc_bracket_close_long, calc_bracket_close_short,
+    calc_close_with_fallback_long, calc_close_with_fallback_short, calc_closes_long,
+    calc_closes_long_by_price, calc_closes_short, calc_closes_short_by_price,
+    calc_daily_pnl_target_close_long, calc_daily_pnl_target_close_short,
+    calc_funding_window_close_long, calc_funding_window_close_short, calc_kelly_close_long,
+    calc_kelly_close_short, calc_ladder_notional, calc_margin_target_close_long,
+    calc_margin_target_close_short, calc_mirrored_closes_long, calc_mirrored_closes_short,
+    calc_neutral_rebalance_close, calc_next_close_long, calc_next_close_short,
+    calc_staggered_closes_long, calc_staggered_closes_short, calc_stepped_trailing_stop_price_long,
+    calc_stepped_trailing_stop_price_short, calc_var_target_closes_long,
+    calc_var_target_closes_short,
 };
+use crate::config::bot_params_pair_from_config;
+use crate::constants::{LONG, SHORT};
 use crate::entries::{
-    calc_entries_long, calc_entries_short, calc_next_entry_long, calc_next_entry_short,
+    calc_entries_long, calc_entries_short, calc_neutral_rebalance_entry, calc_next_entry_long,
+    calc_next_entry_short,
+};
+use crate::invariants::check_ladder_invariants;
+use crate::lots::LotMethod;
+use crate::observers::{BacktestObserver, CandleSnapshot};
+use crate::operators::{GeneticOperators, ParamConstraint};
+use crate::optimizer::{
+    calc_candles_fingerprint, calc_dataset_fingerprint, calc_objective_values,
+    objectives_from_config, params_to_json, step_scored_swarm, EvaluationCache, GridRow,
+    GridSearch, Nsga2, Nsga2Params, ParamBounds, ParamGrid, ParticleSwarm, Pruner, PsoParams,
+};
+use crate::orders::{
+    diff_orders, validate_orders, CloseAmendThrottle, CloseScheduler, IdealOrdersCache, OrderDiff,
+};
+use crate::paper::PaperTrader;
+use crate::pareto::{hypervolume, knee_points, pareto_front};
+use crate::results::{
+    append_jsonl, compare_results, read_jsonl, BacktestResult, RunLogRecord, RunLogger,
 };
+use crate::rng::Rng;
+use crate::scoring::{Score, ScoringConfig};
+use crate::stress::{run_stress_test, StressScenario};
+use crate::synthetic::{generate_synthetic_hlcvs, SyntheticMarketSpec};
 use crate::types::{
-    Analysis, BacktestParams, BotParams, BotParamsPair, EMABands, Equities, ExchangeParams, Order,
-    OrderBook, Position, StateParams, TrailingPriceBundle,
+    Analysis, BacktestParams, BotParams, BotParamsPair, CashFlow, CloseTrailingAnchor, EMABands,
+    EMABandsDetailed, Evaluation, ExchangeFilters, ExchangeParams, ExchangePosition,
+    ExchangeSnapshot, Fill, ModeSwitch, Order, OrderBook, OrderRejection, OrderType, Position,
+    Positions, PruneParams, RoundingConvention, StateParams, SymbolIdx, TradingMask,
+    TrailingPriceBundle,
+};
+use crate::utils::{
+    calc_ema_spans, calc_immediate_full_close_pnl_long, calc_immediate_full_close_pnl_short,
+    calc_min_balance_to_avoid_unstuck, calc_stuck_severity,
+};
+use crate::walk_forward::{run_walk_forward, WalkForwardConfig};
+use memmap::{Mmap, MmapOptions};
+use ndarray::{
+    Array1, Array2, Array3, Array4, ArrayBase, ArrayD, ArrayView, ArrayView1, ArrayView3,
+    ShapeBuilder,
 };
-use memmap::MmapOptions;
-use ndarray::{Array1, Array2, Array3, Array4, ArrayBase, ArrayD, ArrayView, ShapeBuilder};
 use numpy::{
-    IntoPyArray, PyArray1, PyArray2, PyArray3, PyArray4, PyReadonlyArray2, PyReadonlyArray3,
-    PyReadonlyArray4,
+    IntoPyArray, PyArray1, PyArray2, PyArray3, PyArray4, PyReadonlyArray1, PyReadonlyArray2,
+    PyReadonlyArray3, PyReadonlyArray4,
 };
 use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList};
+use pyo3::types::{PyDict, PyList, PyTuple};
 use pyo3::wrap_pyfunction;
 use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{fs::File, slice};
 
-#[pyfunction]
-pub fn run_backtest(
-    shared_memory_file: &str,           // Existing HLCV shared memory file
-    hlcvs_shape: (usize, usize, usize), // Shape of HLCV data
-    hlcvs_dtype: &str,                  // Dtype of HLCV data
-    btc_usd_shared_memory_file: &str,   // New BTC/USD shared memory file
-    btc_usd_dtype: &str,                // Dtype of BTC/USD data
-    bot_params_pair_dict: &PyDict,      // Bot parameters
-    exchange_params_list: &PyAny,       // Exchange parameters
-    backtest_params_dict: &PyDict,      // Backtest parameters
-) -> PyResult<(
+/// An order as (qty, price, order_type).
+type OrderTuple = (f64, f64, String);
+
+/// An order validate_order rejected, as (qty, price, order_type, rejection).
+type RejectedOrderTuple = (f64, f64, String, String);
+
+/// Python layout of a BacktestResult; see backtest_result_to_py.
+type BacktestResultTuple = (
     Py<PyArray2<PyObject>>,
     Py<PyArray1<f64>>,
     Py<PyArray1<f64>>,
     Py<PyDict>,
     Py<PyDict>,
-)> {
-    // Open and map the HLCV shared memory file
-    let file = File::open(shared_memory_file)
-        .map_err(|e| PyValueError::new_err(format!("Unable to open shared memory file: {}", e)))?;
-    let mmap = unsafe {
-        MmapOptions::new()
-            .map(&file)
-            .map_err(|e| PyValueError::new_err(format!("Unable to map HLCV file: {}", e)))?
-    };
-    let hlcvs_rust = unsafe {
-        match hlcvs_dtype {
-            "<f8" => ArrayView::from_shape_ptr(hlcvs_shape, mmap.as_ptr() as *const f64),
-            _ => return Err(PyValueError::new_err("Unsupported dtype for HLCV data")),
-        }
-    };
+);
 
-    // Open and map the BTC/USD shared memory file
-    let btc_usd_file = File::open(btc_usd_shared_memory_file).map_err(|e| {
-        PyValueError::new_err(format!("Unable to open BTC/USD shared memory file: {}", e))
-    })?;
-    let btc_usd_mmap = unsafe {
-        MmapOptions::new()
-            .map(&btc_usd_file)
-            .map_err(|e| PyValueError::new_err(format!("Unable to map BTC/USD file: {}", e)))?
-    };
-    let n_timesteps = hlcvs_shape.0; // Number of timesteps from HLCV shape
-    let btc_usd_rust = unsafe {
-        match btc_usd_dtype {
-            "<f8" => ArrayView::from_shape_ptr((n_timesteps,), btc_usd_mmap.as_ptr() as *const f64),
-            _ => return Err(PyValueError::new_err("Unsupported dtype for BTC/USD data")),
-        }
-    };
+/// Keys of run_backtest's options dict:
+/// - results_path: if set, also save BacktestResult here
+/// - seed: if set, overrides backtest_params_dict's
+/// - observer: see CallbackObserver
+/// - observe_every: call observer.on_candle every n candles; default 1
+/// - balance_curve: per candle; see Backtest::set_balance_curve
+/// - lot_method: "fifo" or "average_cost"; None == off
+/// - track_order_lifetimes: see Backtest::track_order_lifetimes
+const BACKTEST_OPTION_KEYS: &[&str] = &[
+    "results_path",
+    "seed",
+    "observer",
+    "observe_every",
+    "balance_curve",
+    "lot_method",
+    "track_order_lifetimes",
+];
 
-    // Ensure BTC/USD data length matches HLCV timesteps
-    if btc_usd_rust.len() != n_timesteps {
-        return Err(PyValueError::new_err(format!(
-            "BTC/USD data length ({}) does not match HLCV timesteps ({})",
-            btc_usd_rust.len(),
-            n_timesteps
-        )));
+/// options: dict of any of BACKTEST_OPTION_KEYS; unknown keys raise.
+#[pyfunction]
+#[pyo3(signature = (shared_memory_file, hlcvs_shape, hlcvs_dtype, btc_usd_shared_memory_file, btc_usd_dtype, bot_params_pair_dict, exchange_params_list, backtest_params_dict, options=None))]
+pub fn run_backtest(
+    shared_memory_file: &str,                 // Existing HLCV shared memory file
+    hlcvs_shape: (usize, usize, usize),       // Shape of HLCV data
+    hlcvs_dtype: &str,                        // Dtype of HLCV data
+    btc_usd_shared_memory_file: &str,         // New BTC/USD shared memory file
+    btc_usd_dtype: &str,                      // Dtype of BTC/USD data
+    bot_params_pair_dict: &Bound<'_, PyDict>, // Bot parameters
+    exchange_params_list: &Bound<'_, PyAny>,  // Exchange parameters
+    backtest_params_dict: &Bound<'_, PyDict>, // Backtest parameters
+    options: Option<&Bound<'_, PyDict>>,      // see BACKTEST_OPTION_KEYS
+) -> PyResult<BacktestResultTuple> {
+    if let Some(dict) = options {
+        reject_unknown_keys(dict, BACKTEST_OPTION_KEYS)?;
     }
+    let results_path: Option<String> = option_value(options, "results_path", None)?;
+    let seed: Option<u64> = option_value(options, "seed", None)?;
+    let observer: Option<PyObject> = option_value(options, "observer", None)?;
+    let observe_every: usize = option_value(options, "observe_every", 1)?;
+    let balance_curve: Option<PyReadonlyArray1<f64>> =
+        option_value(options, "balance_curve", None)?;
+    let lot_method: Option<String> = option_value(options, "lot_method", None)?;
+    let track_order_lifetimes = option_bool(options, "track_order_lifetimes", false)?;
+
+    let mmap = map_shared_memory(shared_memory_file, "HLCV")?;
+    let hlcvs_rust = hlcvs_view(&mmap, hlcvs_shape, hlcvs_dtype)?;
+    let btc_usd_mmap = map_shared_memory(btc_usd_shared_memory_file, "BTC/USD")?;
+    let btc_usd_rust = btc_usd_view(&btc_usd_mmap, hlcvs_shape.0, btc_usd_dtype)?;
 
     // Prepare bot, exchange, and backtest parameters
     let bot_params_pair = bot_params_pair_from_dict(bot_params_pair_dict)?;
-    let exchange_params = {
-        let mut params_vec = Vec::new();
-        if let Ok(py_list) = exchange_params_list.downcast::<PyList>() {
-            for py_dict in py_list.iter() {
-                if let Ok(dict) = py_dict.downcast::<PyDict>() {
-                    let params = exchange_params_from_dict(dict)?;
-                    params_vec.push(params);
-                } else {
-                    return Err(PyValueError::new_err(
-                        "Unsupported data type in exchange_params_list",
-                    ));
-                }
-            }
-        } else {
-            return Err(PyValueError::new_err(
-                "Unsupported data type for exchange_params_list",
-            ));
-        }
-        params_vec
-    };
+    let exchange_params = exchange_params_list_from_py(exchange_params_list)?;
 
-    let backtest_params = backtest_params_from_dict(backtest_params_dict)?;
+    let mut backtest_params = backtest_params_from_dict(backtest_params_dict)?;
+    if let Some(seed) = seed {
+        backtest_params.seed = seed;
+    }
+    let balance_curve = balance_curve.map_or(Vec::new(), |curve| curve.as_array().to_vec());
+    if !balance_curve.is_empty() && balance_curve.len() != hlcvs_shape.0 {
+        return Err(PyValueError::new_err(format!(
+            "balance_curve has {} values for {} candles",
+            balance_curve.len(),
+            hlcvs_shape.0
+        )));
+    }
+    let balance_curve_fn = |k: usize| balance_curve[k];
+    let lot_method = lot_method
+        .as_deref()
+        .map(str::parse::<LotMethod>)
+        .transpose()
+        .map_err(PyValueError::new_err)?;
     let mut backtest = Backtest::new(
         &hlcvs_rust,
         &btc_usd_rust,
-        bot_params_pair,
-        exchange_params,
+        bot_params_pair.clone(),
+        exchange_params.clone(),
         &backtest_params,
     );
+    let observer_error = Arc::new(Mutex::new(None));
+    if let Some(observer) = observer {
+        if observe_every == 0 {
+            return Err(PyValueError::new_err("observe_every must be at least 1"));
+        }
+        let observer = Python::with_gil(|py| {
+            CallbackObserver::new(py, observer, observe_every, observer_error.clone())
+        })?;
+        backtest.add_observer(Box::new(observer));
+    }
+    if !balance_curve.is_empty() {
+        backtest.set_balance_curve(&balance_curve_fn);
+    }
+    if track_order_lifetimes {
+        backtest.track_order_lifetimes();
+    }
+    if let Some(lot_method) = lot_method {
+        backtest.track_lots(lot_method);
+    }
 
     // Run the backtest and process results
     Python::with_gil(|py| {
         let (fills, equities) = backtest.run();
-        let (analysis_usd, analysis_btc) =
-            analyze_backtest_pair(&fills, &equities, backtest.balance.use_btc_collateral);
-
-        // Create a dictionary to store analysis results using a more concise approach
-        let py_analysis_usd = struct_to_py_dict(py, &analysis_usd)?;
-        let py_analysis_btc = struct_to_py_dict(py, &analysis_btc)?;
-        let mut py_fills = Array2::from_elem((fills.len(), 13), py.None());
-        for (i, fill) in fills.iter().enumerate() {
-            py_fills[(i, 0)] = fill.index.into_py(py);
-            py_fills[(i, 1)] = <String as Clone>::clone(&fill.coin).into_py(py);
-            py_fills[(i, 2)] = fill.pnl.into_py(py);
-            py_fills[(i, 3)] = fill.fee_paid.into_py(py);
-            py_fills[(i, 4)] = fill.balance_usd_total.into_py(py);
-            py_fills[(i, 5)] = fill.balance_btc.into_py(py);
-            py_fills[(i, 6)] = fill.balance_usd.into_py(py);
-            py_fills[(i, 7)] = fill.btc_price.into_py(py);
-            py_fills[(i, 8)] = fill.fill_qty.into_py(py);
-            py_fills[(i, 9)] = fill.fill_price.into_py(py);
-            py_fills[(i, 10)] = fill.position_size.into_py(py);
-            py_fills[(i, 11)] = fill.position_price.into_py(py);
-            py_fills[(i, 12)] = fill.order_type.to_string().into_py(py);
+        if let Some(err) = observer_error.lock().unwrap().take() {
+            return Err(err);
+        }
+        let result = BacktestResult::new(
+            fills,
+            equities,
+            backtest.balance.use_btc_collateral,
+            bot_params_pair,
+            exchange_params,
+            backtest_params,
+            calc_candles_fingerprint(&hlcvs_rust, &btc_usd_rust),
+        )
+        .with_wind_downs(std::mem::take(&mut backtest.wind_downs))
+        .with_markup_floors(&backtest.markup_floors)
+        .with_cost_capped_fills(&backtest.cost_capped_fills)
+        .with_order_lifetimes(backtest.order_lifetime_stats())
+        .with_reduce_only_periods(std::mem::take(&mut backtest.reduce_only_periods))
+        .with_lot_attributions(backtest.lot_attributions());
+        if let Some(results_path) = results_path {
+            result
+                .save(Path::new(&results_path))
+                .map_err(PyValueError::new_err)?;
+        }
+        backtest_result_to_py(py, result)
+    })
+}
+
+/// index, coin, pnl, fee_paid, balance_usd_total, balance_btc, balance_usd, btc_price,
+/// fill_qty, fill_price, position_size, position_price, order_type, impact_pct.
+fn fill_to_py_row(py: Python, fill: &Fill) -> [PyObject; 14] {
+    [
+        fill.index.into_py(py),
+        fill.coin.clone().into_py(py),
+        fill.pnl.into_py(py),
+        fill.fee_paid.into_py(py),
+        fill.balance_usd_total.into_py(py),
+        fill.balance_btc.into_py(py),
+        fill.balance_usd.into_py(py),
+        fill.btc_price.into_py(py),
+        fill.fill_qty.into_py(py),
+        fill.fill_price.into_py(py),
+        fill.position_size.into_py(py),
+        fill.position_price.into_py(py),
+        fill.order_type.to_string().into_py(py),
+        fill.impact_pct.into_py(py),
+    ]
+}
+
+/// One row per fill, as fill_to_py_row.
+fn fills_to_py(py: Python, fills: &[Fill]) -> Py<PyArray2<PyObject>> {
+    let mut py_fills = Array2::from_elem((fills.len(), 14), py.None());
+    for (i, fill) in fills.iter().enumerate() {
+        for (j, value) in fill_to_py_row(py, fill).into_iter().enumerate() {
+            py_fills[(i, j)] = value;
+        }
+    }
+    py_fills.into_pyarray_bound(py).unbind()
+}
+
+/// run_backtest's observer: calls the Python object's on_fill(fill_row), with fill_row as
+/// fill_to_py_row, for every fill, and its on_candle(k, equity_usd, equity_btc) every
+/// every_n_candles candles; either method may be missing. The first exception raised
+/// silences the observer and is re-raised once the backtest is done.
+struct CallbackObserver {
+    on_fill: Option<PyObject>,
+    on_candle: Option<PyObject>,
+    every_n_candles: usize,
+    error: Arc<Mutex<Option<PyErr>>>,
+}
+
+impl CallbackObserver {
+    fn new(
+        py: Python,
+        observer: PyObject,
+        every_n_candles: usize,
+        error: Arc<Mutex<Option<PyErr>>>,
+    ) -> PyResult<Self> {
+        let method = |name: &str| -> PyResult<Option<PyObject>> {
+            let observer = observer.bind(py);
+            Ok(if observer.hasattr(name)? {
+                Some(observer.getattr(name)?.into_py(py))
+            } else {
+                None
+            })
+        };
+        Ok(CallbackObserver {
+            on_fill: method("on_fill")?,
+            on_candle: method("on_candle")?,
+            every_n_candles,
+            error,
+        })
+    }
+
+    fn call(&self, callback: &PyObject, args: impl FnOnce(Python) -> Py<PyTuple>) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_some() {
+            return;
+        }
+        Python::with_gil(|py| {
+            if let Err(err) = callback.call1(py, args(py).bind(py)) {
+                *error = Some(err);
+            }
+        });
+    }
+}
+
+impl BacktestObserver for CallbackObserver {
+    fn on_fill(&mut self, fill: &Fill) {
+        if let Some(callback) = &self.on_fill {
+            self.call(callback, |py| {
+                (PyTuple::new_bound(py, fill_to_py_row(py, fill)),).into_py(py)
+            });
+        }
+    }
+
+    fn on_candle(&mut self, candle: &CandleSnapshot) {
+        if !candle.k.is_multiple_of(self.every_n_candles) {
+            return;
+        }
+        if let Some(callback) = &self.on_candle {
+            self.call(callback, |py| {
+                (candle.k, candle.equity_usd, candle.equity_btc).into_py(py)
+            });
+        }
+    }
+}
+
+/// The backtest's fill engine stepped live for paper trading; see paper::PaperTrader.
+/// Per candle, feed every coin with update_market, then call apply_fills and place
+/// get_ideal_orders. Symbols are backtest_params_dict's coins.
+#[pyclass(name = "PaperTrader")]
+pub struct PaperTraderPy {
+    trader: PaperTrader,
+}
+
+#[pymethods]
+impl PaperTraderPy {
+    #[new]
+    pub fn new(
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        exchange_params_list: &Bound<'_, PyAny>,
+        backtest_params_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<Self> {
+        Ok(PaperTraderPy {
+            trader: PaperTrader::new(
+                bot_params_pair_from_dict(bot_params_pair_dict)?,
+                exchange_params_list_from_py(exchange_params_list)?,
+                backtest_params_from_dict(backtest_params_dict)?,
+            )
+            .map_err(PyValueError::new_err)?,
+        })
+    }
+
+    #[pyo3(signature = (symbol, high, low, close, volume=0.0))]
+    pub fn update_market(
+        &mut self,
+        symbol: &str,
+        high: f64,
+        low: f64,
+        close: f64,
+        volume: f64,
+    ) -> PyResult<()> {
+        let idx = self
+            .trader
+            .coin_index(symbol)
+            .map_err(PyValueError::new_err)?;
+        self.trader.update_market(idx, high, low, close, volume);
+        Ok(())
+    }
+
+    /// Sets which sides symbol may enter on from the next candle; entries open on a newly
+    /// disabled side are dropped and positions there only close.
+    #[pyo3(signature = (symbol, long_enabled=true, short_enabled=true))]
+    pub fn set_trading_mask(
+        &mut self,
+        symbol: &str,
+        long_enabled: bool,
+        short_enabled: bool,
+    ) -> PyResult<()> {
+        let mask = TradingMask {
+            long_enabled,
+            short_enabled,
+        };
+        self.trader
+            .set_trading_mask(symbol, mask)
+            .map_err(PyValueError::new_err)
+    }
+
+    /// Closes the candle; returns its fills as rows like run_backtest's.
+    pub fn apply_fills(&mut self, py: Python) -> PyResult<Py<PyArray2<PyObject>>> {
+        let fills = self.trader.apply_fills().map_err(PyValueError::new_err)?;
+        Ok(fills_to_py(py, &fills))
+    }
+
+    /// {symbol: {"long": [(qty, price, order_type), ..], "short": [..]}} for symbols with
+    /// orders to keep open.
+    pub fn get_ideal_orders(&self, py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new_bound(py);
+        for (idx, coin) in self.trader.coins().iter().enumerate() {
+            let long = self.trader.ideal_orders(idx as SymbolIdx, LONG);
+            let short = self.trader.ideal_orders(idx as SymbolIdx, SHORT);
+            if long.is_empty() && short.is_empty() {
+                continue;
+            }
+            let sides = PyDict::new_bound(py);
+            sides.set_item("long", long.iter().map(order_to_tuple).collect::<Vec<_>>())?;
+            sides.set_item(
+                "short",
+                short.iter().map(order_to_tuple).collect::<Vec<_>>(),
+            )?;
+            dict.set_item(coin, sides)?;
+        }
+        Ok(dict.into())
+    }
+}
+
+/// IdealOrdersCache for the live loop, over one bot config and exchange params per symbol
+/// index. Each call to calc_ideal_orders takes a batch of states; see ideal_orders_state.
+#[pyclass(name = "IdealOrdersCache")]
+pub struct IdealOrdersCachePy {
+    cache: IdealOrdersCache,
+    bot_params_pair: BotParamsPair,
+    exchange_params_list: Vec<ExchangeParams>,
+}
+
+#[pymethods]
+impl IdealOrdersCachePy {
+    #[new]
+    #[pyo3(signature = (bot_params_pair_dict, exchange_params_list, balance_resolution_pct=0.0))]
+    pub fn new(
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        exchange_params_list: &Bound<'_, PyAny>,
+        balance_resolution_pct: f64,
+    ) -> PyResult<Self> {
+        Ok(IdealOrdersCachePy {
+            cache: IdealOrdersCache::new(balance_resolution_pct),
+            bot_params_pair: bot_params_pair_from_dict(bot_params_pair_dict)?,
+            exchange_params_list: exchange_params_list_from_py(exchange_params_list)?,
+        })
+    }
+
+    /// Ideal orders as [(qty, price, order_type), ..] per state, in the order given.
+    pub fn calc_ideal_orders(
+        &mut self,
+        states: Vec<Bound<'_, PyDict>>,
+    ) -> PyResult<Vec<Vec<(f64, f64, String)>>> {
+        states
+            .into_iter()
+            .map(|state| {
+                let (idx, pside, mut state_params, position, trailing_price_bundle) =
+                    ideal_orders_state(&state)?;
+                state_params.balance = self
+                    .bot_params_pair
+                    .allocated_balance(state_params.balance, pside);
+                let exchange_params =
+                    self.exchange_params_list.get(idx as usize).ok_or_else(|| {
+                        PyValueError::new_err(format!("no exchange params for idx {}", idx))
+                    })?;
+                let bot_params = if pside == LONG {
+                    &self.bot_params_pair.long
+                } else {
+                    &self.bot_params_pair.short
+                };
+                let orders = self.cache.calc_ideal_orders(
+                    (idx, pside),
+                    exchange_params,
+                    &state_params,
+                    bot_params,
+                    &position,
+                    &trailing_price_bundle,
+                );
+                Ok(orders.iter().map(order_to_tuple).collect())
+            })
+            .collect()
+    }
+
+    /// Drops every cached order set; call after changing params.
+    pub fn invalidate(&mut self) {
+        self.cache.invalidate();
+    }
+
+    /// {"entries", "hits", "misses"}.
+    pub fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let stats = PyDict::new_bound(py);
+        stats.set_item("entries", self.cache.len())?;
+        stats.set_item("hits", self.cache.hits())?;
+        stats.set_item("misses", self.cache.misses())?;
+        Ok(stats)
+    }
+}
+
+/// CloseScheduler for the live loop, over exchange params per symbol index.
+#[pyclass(name = "CloseScheduler")]
+pub struct CloseSchedulerPy {
+    scheduler: CloseScheduler,
+    exchange_params_list: Vec<ExchangeParams>,
+}
+
+#[pymethods]
+impl CloseSchedulerPy {
+    #[new]
+    pub fn new(
+        placement_spread_candles: usize,
+        exchange_params_list: &Bound<'_, PyAny>,
+    ) -> PyResult<Self> {
+        Ok(CloseSchedulerPy {
+            scheduler: CloseScheduler::new(placement_spread_candles),
+            exchange_params_list: exchange_params_list_from_py(exchange_params_list)?,
+        })
+    }
+
+    /// The released closes, as [(qty, price, order_type), ..], of the full ladder closes at
+    /// candle k.
+    pub fn release(
+        &mut self,
+        idx: SymbolIdx,
+        pside: &str,
+        closes: Vec<(f64, f64, String)>,
+        k: usize,
+    ) -> PyResult<Vec<(f64, f64, String)>> {
+        let pside = pside_from_str(pside)?;
+        let exchange_params = self
+            .exchange_params_list
+            .get(idx as usize)
+            .ok_or_else(|| PyValueError::new_err(format!("no exchange params for idx {}", idx)))?;
+        let released =
+            self.scheduler
+                .release(idx, pside, &orders_from_tuples(closes)?, exchange_params, k);
+        Ok(released.iter().map(order_to_tuple).collect())
+    }
+
+    pub fn n_released(&self, idx: SymbolIdx, pside: &str) -> PyResult<usize> {
+        match pside {
+            "long" => Ok(self.scheduler.n_released(idx, LONG)),
+            "short" => Ok(self.scheduler.n_released(idx, SHORT)),
+            _ => Err(PyValueError::new_err(format!("unknown pside {}", pside))),
+        }
+    }
+
+    /// Forgets every schedule; the next ladders start over.
+    pub fn reset(&mut self) {
+        self.scheduler.reset();
+    }
+}
+
+/// CloseAmendThrottle for the live loop.
+#[pyclass(name = "CloseAmendThrottle")]
+pub struct CloseAmendThrottlePy {
+    throttle: CloseAmendThrottle,
+}
+
+#[pymethods]
+impl CloseAmendThrottlePy {
+    #[new]
+    pub fn new(min_amend_interval_candles: usize) -> Self {
+        CloseAmendThrottlePy {
+            throttle: CloseAmendThrottle::new(min_amend_interval_candles),
+        }
+    }
+
+    /// diff_orders_py of the open closes against the ideal closes at candle k, holding levels
+    /// amended less than min_amend_interval_candles ago at their resting price.
+    pub fn diff_closes(
+        &mut self,
+        py: Python,
+        idx: SymbolIdx,
+        pside: &str,
+        filters: &Bound<'_, PyDict>,
+        open_closes: Vec<(f64, f64, String)>,
+        ideal_closes: Vec<(f64, f64, String)>,
+        mark_price: f64,
+        k: usize,
+    ) -> PyResult<PyObject> {
+        let pside = pside_from_str(pside)?;
+        let diff = self.throttle.diff_closes(
+            (idx, pside),
+            &orders_from_tuples(open_closes)?,
+            &orders_from_tuples(ideal_closes)?,
+            &exchange_filters_from_dict(filters)?,
+            mark_price,
+            k,
+        );
+        order_diff_to_py_dict(py, &diff)
+    }
+
+    pub fn last_amend_candle(
+        &self,
+        idx: SymbolIdx,
+        pside: &str,
+        level: usize,
+    ) -> PyResult<Option<usize>> {
+        match pside {
+            "long" => Ok(self.throttle.last_amend_candle(idx, LONG, level)),
+            "short" => Ok(self.throttle.last_amend_candle(idx, SHORT, level)),
+            _ => Err(PyValueError::new_err(format!("unknown pside {}", pside))),
+        }
+    }
+
+    /// Forgets every level; the next diff amends freely.
+    pub fn reset(&mut self) {
+        self.throttle.reset();
+    }
+}
+
+/// One state of an IdealOrdersCache batch: "idx" and the keys of pside_state.
+fn ideal_orders_state(
+    dict: &Bound<'_, PyDict>,
+) -> PyResult<(SymbolIdx, usize, StateParams, Position, TrailingPriceBundle)> {
+    let (pside, state_params, position, trailing_price_bundle) = pside_state(dict)?;
+    Ok((
+        extract_value(dict, "idx")?,
+        pside,
+        state_params,
+        position,
+        trailing_price_bundle,
+    ))
+}
+
+/// A position's state: "pside" ("long" or "short"), "balance", "order_book" ((bid, ask) or
+/// a dict), "position_size" and "position_price" are required; the other StateParams and
+/// TrailingPriceBundle fields, and "position_accrued_funding", default as in Rust, with the
+/// EMA bands as "ema_bands_lower" and "ema_bands_upper".
+fn pside_state(
+    dict: &Bound<'_, PyDict>,
+) -> PyResult<(usize, StateParams, Position, TrailingPriceBundle)> {
+    let pside = pside_from_str(&extract_value::<String>(dict, "pside")?)?;
+    let state_params = StateParams {
+        balance: extract_value(dict, "balance")?,
+        order_book: order_book_from_py(&extract_value(dict, "order_book")?)?,
+        ema_bands: EMABands {
+            lower: extract_value(dict, "ema_bands_lower").unwrap_or_default(),
+            upper: extract_value(dict, "ema_bands_upper").unwrap_or_default(),
+        },
+        trailing_ma: extract_value(dict, "trailing_ma").unwrap_or_default(),
+        volume: extract_value(dict, "volume").unwrap_or_default(),
+        avg_volume: extract_value(dict, "avg_volume").unwrap_or_default(),
+        timestamp: extract_value(dict, "timestamp").unwrap_or_default(),
+        target_price: extract_value(dict, "target_price").unwrap_or_default(),
+        hour: extract_value(dict, "hour").unwrap_or_default(),
+        realized_pnl: extract_value(dict, "realized_pnl").unwrap_or_default(),
+        equity: extract_value(dict, "equity").unwrap_or_default(),
+        peak_equity: extract_value(dict, "peak_equity").unwrap_or_default(),
+        min_markup_floor: extract_value(dict, "min_markup_floor").unwrap_or_default(),
+        order_flow_imbalance: extract_value(dict, "order_flow_imbalance").unwrap_or_default(),
+        close_markup_scale: extract_value(dict, "close_markup_scale").unwrap_or_default(),
+    };
+    let position = Position {
+        size: extract_value(dict, "position_size")?,
+        price: extract_value(dict, "position_price")?,
+        accrued_funding: extract_value(dict, "position_accrued_funding").unwrap_or_default(),
+    };
+    let default_bundle = TrailingPriceBundle::default();
+    let trailing_price_bundle = TrailingPriceBundle {
+        min_since_open: extract_value(dict, "min_since_open")
+            .unwrap_or(default_bundle.min_since_open),
+        max_since_min: extract_value(dict, "max_since_min").unwrap_or(default_bundle.max_since_min),
+        max_since_open: extract_value(dict, "max_since_open")
+            .unwrap_or(default_bundle.max_since_open),
+        min_since_max: extract_value(dict, "min_since_max").unwrap_or(default_bundle.min_since_max),
+        candles_since_min: extract_value(dict, "candles_since_min").unwrap_or_default(),
+        candles_since_max: extract_value(dict, "candles_since_max").unwrap_or_default(),
+        fib_levels_closed: extract_value(dict, "fib_levels_closed").unwrap_or_default(),
+        stepped_stop_price: extract_value(dict, "stepped_stop_price").unwrap_or_default(),
+    };
+    Ok((pside, state_params, position, trailing_price_bundle))
+}
+
+fn map_shared_memory(path: &str, label: &str) -> PyResult<Mmap> {
+    let file = File::open(path).map_err(|e| {
+        PyValueError::new_err(format!(
+            "Unable to open {} shared memory file: {}",
+            label, e
+        ))
+    })?;
+    unsafe {
+        MmapOptions::new()
+            .map(&file)
+            .map_err(|e| PyValueError::new_err(format!("Unable to map {} file: {}", label, e)))
+    }
+}
+
+fn hlcvs_view<'a>(
+    mmap: &'a Mmap,
+    hlcvs_shape: (usize, usize, usize),
+    hlcvs_dtype: &str,
+) -> PyResult<ArrayView3<'a, f64>> {
+    match hlcvs_dtype {
+        "<f8" => Ok(unsafe { ArrayView::from_shape_ptr(hlcvs_shape, mmap.as_ptr() as *const f64) }),
+        _ => Err(PyValueError::new_err("Unsupported dtype for HLCV data")),
+    }
+}
+
+fn btc_usd_view<'a>(
+    mmap: &'a Mmap,
+    n_timesteps: usize,
+    btc_usd_dtype: &str,
+) -> PyResult<ArrayView1<'a, f64>> {
+    let btc_usd = match btc_usd_dtype {
+        "<f8" => unsafe { ArrayView::from_shape_ptr((n_timesteps,), mmap.as_ptr() as *const f64) },
+        _ => return Err(PyValueError::new_err("Unsupported dtype for BTC/USD data")),
+    };
+    // Ensure BTC/USD data length matches HLCV timesteps
+    if btc_usd.len() != n_timesteps {
+        return Err(PyValueError::new_err(format!(
+            "BTC/USD data length ({}) does not match HLCV timesteps ({})",
+            btc_usd.len(),
+            n_timesteps
+        )));
+    }
+    Ok(btc_usd)
+}
+
+fn exchange_params_list_from_py(
+    exchange_params_list: &Bound<'_, PyAny>,
+) -> PyResult<Vec<ExchangeParams>> {
+    let mut params_vec = Vec::new();
+    if let Ok(py_list) = exchange_params_list.downcast::<PyList>() {
+        for py_dict in py_list.iter() {
+            if let Ok(dict) = py_dict.downcast::<PyDict>() {
+                let params = exchange_params_from_dict(dict)?;
+                params_vec.push(params);
+            } else {
+                return Err(PyValueError::new_err(
+                    "Unsupported data type in exchange_params_list",
+                ));
+            }
         }
+    } else {
+        return Err(PyValueError::new_err(
+            "Unsupported data type for exchange_params_list",
+        ));
+    }
+    Ok(params_vec)
+}
+
+#[pyfunction]
+pub fn load_backtest_result(results_path: &str) -> PyResult<BacktestResultTuple> {
+    let result = BacktestResult::load(Path::new(results_path)).map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| backtest_result_to_py(py, result))
+}
 
-        let py_equities_usd = Array1::from_vec(equities.usd).into_pyarray(py).to_owned();
-        let py_equities_btc = Array1::from_vec(equities.btc).into_pyarray(py).to_owned();
+/// Slot utilization of a saved result, as (long, short) dicts:
+/// {"n_positions", "mean", "full_pct", "daily"}.
+#[pyfunction]
+pub fn load_slot_utilization(results_path: &str) -> PyResult<(Py<PyDict>, Py<PyDict>)> {
+    let result = BacktestResult::load(Path::new(results_path)).map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| {
         Ok((
-            py_fills.into_pyarray(py).to_owned(),
-            py_equities_usd,
-            py_equities_btc,
-            py_analysis_usd.into(),
-            py_analysis_btc.into(),
+            struct_to_py_dict(py, &result.slot_utilization(LONG))?.into(),
+            struct_to_py_dict(py, &result.slot_utilization(SHORT))?.into(),
         ))
     })
 }
 
+/// Wind-downs of a saved result, one dict per scheduled switch out of normal mode:
+/// {"pside", "mode", "k", "n_positions", "duration", "slippage_pct"}.
+#[pyfunction]
+pub fn load_wind_downs(results_path: &str) -> PyResult<Vec<Py<PyDict>>> {
+    let result = BacktestResult::load(Path::new(results_path)).map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| {
+        result
+            .wind_downs
+            .iter()
+            .map(|wind_down| Ok(struct_to_py_dict(py, wind_down)?.into()))
+            .collect()
+    })
+}
+
+/// Order lifetimes of a saved result, one dict per order type: {"order_type", "n_created",
+/// "n_filled", "n_cancelled", "n_open_at_end", "time_to_fill_mean", "time_to_fill_median",
+/// "time_to_fill_p90", "time_to_fill_max", "cancelled_lifetime_mean"}, in candles;
+/// empty unless the backtest ran with track_order_lifetimes.
+#[pyfunction]
+pub fn load_order_lifetimes(results_path: &str) -> PyResult<Vec<Py<PyDict>>> {
+    let result = BacktestResult::load(Path::new(results_path)).map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| {
+        result
+            .order_lifetimes
+            .iter()
+            .map(|stats| Ok(struct_to_py_dict(py, stats)?.into()))
+            .collect()
+    })
+}
+
+/// Lot attributions of a saved result, one dict per close fill: {"fill_id", "lots":
+/// [{"entry_fill_id", "qty", "pnl"}, ..], "unattributed_qty"}, fill ids indexing its fills;
+/// empty unless the backtest ran with a lot_method.
+#[pyfunction]
+pub fn load_lot_attributions(results_path: &str) -> PyResult<Vec<Py<PyDict>>> {
+    let result = BacktestResult::load(Path::new(results_path)).map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| {
+        result
+            .lot_attributions
+            .iter()
+            .map(|attribution| Ok(struct_to_py_dict(py, attribution)?.into()))
+            .collect()
+    })
+}
+
+/// Reduce-only periods of a saved result, one dict per trip of reduce_only_drawdown_pct:
+/// {"start", "end", "drawdown_max"}, end None if still active at the end.
+#[pyfunction]
+pub fn load_reduce_only_periods(results_path: &str) -> PyResult<Vec<Py<PyDict>>> {
+    let result = BacktestResult::load(Path::new(results_path)).map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| {
+        result
+            .reduce_only_periods
+            .iter()
+            .map(|period| Ok(struct_to_py_dict(py, period)?.into()))
+            .collect()
+    })
+}
+
+/// Compares two saved results of the same data as b minus a: {"changed_params",
+/// "analysis_usd", "analysis_btc", "coin_pnls", "first_divergent_index", "equity_indices",
+/// "equities_a", "equities_b"}; see results::compare_results.
+#[pyfunction]
+pub fn compare_backtest_results(
+    results_path_a: &str,
+    results_path_b: &str,
+) -> PyResult<Py<PyDict>> {
+    let a = BacktestResult::load(Path::new(results_path_a)).map_err(PyValueError::new_err)?;
+    let b = BacktestResult::load(Path::new(results_path_b)).map_err(PyValueError::new_err)?;
+    let comparison = compare_results(&a, &b).map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| Ok(struct_to_py_dict(py, &comparison)?.into()))
+}
+
+/// Scores analyses as returned by run_backtest with a scoring config (see ScoringConfig).
+/// Without analysis_btc, btc_ metrics are read from analysis_usd under their btc_ keys.
+/// Returns (fitness, [(term, contribution), ..]); the contributions sum to the fitness.
+#[pyfunction]
+#[pyo3(signature = (scoring, analysis_usd, analysis_btc=None))]
+pub fn calc_fitness_py(
+    py: Python,
+    scoring: &Bound<'_, PyDict>,
+    analysis_usd: &Bound<'_, PyDict>,
+    analysis_btc: Option<&Bound<'_, PyDict>>,
+) -> PyResult<(f64, Vec<(String, f64)>)> {
+    let scoring = ScoringConfig::from_config(&py_to_json_value(py, scoring)?)
+        .map_err(PyValueError::new_err)?;
+    let score = scoring.score_with(|metric| {
+        let (analysis, key) = match (metric.strip_prefix("btc_"), analysis_btc) {
+            (Some(metric_usd), Some(analysis_btc)) => (analysis_btc, metric_usd),
+            _ => (analysis_usd, metric),
+        };
+        // missing and non-numeric metrics score as NaN, like non-finite ones
+        extract_value::<f64>(analysis, key).unwrap_or(f64::NAN)
+    });
+    Ok((score.fitness, score.contributions))
+}
+
+/// Python layout of a BacktestResult: (fills, equities_usd, equities_btc, analysis_usd,
+/// analysis_btc). Fill columns are index, coin, pnl, fee_paid, balance_usd_total, balance_btc,
+/// balance_usd, btc_price, fill_qty, fill_price, position_size, position_price, order_type,
+/// impact_pct.
+fn backtest_result_to_py(py: Python, result: BacktestResult) -> PyResult<BacktestResultTuple> {
+    let py_analysis_usd = struct_to_py_dict(py, &result.analysis_usd)?;
+    let py_analysis_btc = struct_to_py_dict(py, &result.analysis_btc)?;
+    let py_fills = fills_to_py(py, &result.fills);
+    let py_equities_usd = Array1::from_vec(result.equities.usd)
+        .into_pyarray_bound(py)
+        .unbind();
+    let py_equities_btc = Array1::from_vec(result.equities.btc)
+        .into_pyarray_bound(py)
+        .unbind();
+    Ok((
+        py_fills,
+        py_equities_usd,
+        py_equities_btc,
+        py_analysis_usd.into(),
+        py_analysis_btc.into(),
+    ))
+}
+
 fn struct_to_py_dict<'py, T: Serialize + ?Sized>(
     py: Python<'py>,
     obj: &T,
-) -> PyResult<&'py PyDict> {
+) -> PyResult<Bound<'py, PyDict>> {
     // Convert struct to JSON string
     let json_str = serde_json::to_string(obj).map_err(|e| {
         PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("JSON serialization error: {}", e))
     })?;
 
     // Use Python's json module to convert to a Python dict
-    let json = py.import("json")?;
+    let json = py.import_bound("json")?;
     let py_obj = json.call_method1("loads", (json_str,))?;
 
     // Convert to PyDict
-    py_obj.downcast::<PyDict>().map_err(|_| {
+    py_obj.downcast_into::<PyDict>().map_err(|_| {
         PyErr::new::<pyo3::exceptions::PyTypeError, _>("Failed to convert to Python dict")
     })
 }
 
-fn backtest_params_from_dict(dict: &PyDict) -> PyResult<BacktestParams> {
+fn backtest_params_from_dict(dict: &Bound<'_, PyDict>) -> PyResult<BacktestParams> {
     Ok(BacktestParams {
         starting_balance: extract_value(dict, "starting_balance").unwrap_or_default(),
         maker_fee: extract_value(dict, "maker_fee").unwrap_or_default(),
         coins: extract_value(dict, "coins").unwrap_or_default(),
+        correlation_matrix: extract_value(dict, "correlation_matrix").unwrap_or_default(),
+        seed: extract_value(dict, "seed").unwrap_or_default(),
+        slippage_pct: extract_value(dict, "slippage_pct").unwrap_or_default(),
+        funding_rate: extract_value(dict, "funding_rate").unwrap_or_default(),
+        start_hour: extract_value(dict, "start_hour").unwrap_or_default(),
+        max_position_costs: extract_value(dict, "max_position_costs").unwrap_or_default(),
+        mode_schedule: mode_schedule_from_dict(dict)?,
+        markup_floor_median_range: extract_bool_value(dict, "markup_floor_median_range")
+            .unwrap_or(false),
+        markup_floor_taker_fee: extract_value(dict, "markup_floor_taker_fee").unwrap_or_default(),
+        impact_capacities: extract_value(dict, "impact_capacities").unwrap_or_default(),
+        market_impact_pct: extract_value(dict, "market_impact_pct").unwrap_or_default(),
+        market_slippage_buffer_pct: extract_value(dict, "market_slippage_buffer_pct")
+            .unwrap_or_default(),
+        trading_masks: extract_value::<BTreeMap<String, (bool, bool)>>(dict, "trading_masks")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(coin, (long_enabled, short_enabled))| {
+                let mask = TradingMask {
+                    long_enabled,
+                    short_enabled,
+                };
+                (coin, mask)
+            })
+            .collect(),
+        reduce_only_drawdown_pct: extract_value(dict, "reduce_only_drawdown_pct")
+            .unwrap_or_default(),
+        reduce_only_recovery_pct: extract_value(dict, "reduce_only_recovery_pct")
+            .unwrap_or_default(),
+        // [(k, amount), ..]
+        cash_flows: extract_value::<Vec<(usize, f64)>>(dict, "cash_flows")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(k, amount)| CashFlow { k, amount })
+            .collect(),
     })
 }
 
-fn exchange_params_from_dict(dict: &PyDict) -> PyResult<ExchangeParams> {
-    Ok(ExchangeParams {
+/// "mode_schedule" as [(k, mode, pside), ..], pside "long", "short" or None for both;
+/// absent == none.
+fn mode_schedule_from_dict(dict: &Bound<'_, PyDict>) -> PyResult<Vec<ModeSwitch>> {
+    let Some(schedule) = dict.get_item("mode_schedule")? else {
+        return Ok(Vec::new());
+    };
+    schedule
+        .extract::<Vec<(usize, String, Option<String>)>>()?
+        .into_iter()
+        .map(|(k, mode, pside)| {
+            let pside = match pside.as_deref() {
+                None => None,
+                Some("long") => Some(LONG),
+                Some("short") => Some(SHORT),
+                Some(pside) => {
+                    return Err(PyValueError::new_err(format!("unknown pside {}", pside)))
+                }
+            };
+            Ok(ModeSwitch {
+                k,
+                pside,
+                mode: mode.parse().map_err(PyValueError::new_err)?,
+            })
+        })
+        .collect()
+}
+
+/// Zero steps are rejected unless "infer_steps" is set, in which case they are inferred
+/// from the minimums and "price", the coin's typical price; see ExchangeParams::validated.
+/// "rounding_convention" is "round", "truncate" or "toward_passive", the default.
+fn exchange_params_from_dict(dict: &Bound<'_, PyDict>) -> PyResult<ExchangeParams> {
+    ExchangeParams {
         qty_step: extract_value(dict, "qty_step").unwrap_or_default(),
         price_step: extract_value(dict, "price_step").unwrap_or_default(),
         min_qty: extract_value(dict, "min_qty").unwrap_or_default(),
         min_cost: extract_value(dict, "min_cost").unwrap_or_default(),
         c_mult: extract_value(dict, "c_mult").unwrap_or_default(),
-    })
+        price_band_pct: extract_value(dict, "price_band_pct").unwrap_or_default(),
+        rounding_convention: match extract_value::<String>(dict, "rounding_convention") {
+            Ok(convention) => convention.parse().map_err(PyValueError::new_err)?,
+            Err(_) => RoundingConvention::default(),
+        },
+    }
+    .validated(
+        extract_bool_value(dict, "infer_steps").unwrap_or(false),
+        extract_value(dict, "price").unwrap_or_default(),
+    )
+    .map_err(PyValueError::new_err)
 }
 
-fn bot_params_pair_from_dict(dict: &PyDict) -> PyResult<BotParamsPair> {
-    Ok(BotParamsPair {
-        long: bot_params_from_dict(extract_value(dict, "long")?)?,
-        short: bot_params_from_dict(extract_value(dict, "short")?)?,
+fn exchange_filters_from_dict(dict: &Bound<'_, PyDict>) -> PyResult<ExchangeFilters> {
+    Ok(ExchangeFilters {
+        exchange_params: exchange_params_from_dict(dict)?,
+        max_qty: extract_value(dict, "max_qty").unwrap_or_default(),
+        max_notional: extract_value(dict, "max_notional").unwrap_or_default(),
+        max_open_orders: extract_value(dict, "max_open_orders").unwrap_or_default(),
+        inverse: extract_value(dict, "inverse").unwrap_or_default(),
     })
 }
 
-fn extract_bool_value(dict: &PyDict, key: &str) -> PyResult<bool> {
+fn orders_from_tuples(orders: Vec<(f64, f64, String)>) -> PyResult<Vec<Order>> {
+    orders
+        .into_iter()
+        .map(|(qty, price, order_type)| {
+            let order_type = serde_json::from_value::<OrderType>(Value::String(order_type))
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            Ok(Order::new(qty, price, order_type))
+        })
+        .collect()
+}
+
+/// LONG for "long", SHORT for "short"; anything else is a ValueError.
+fn pside_from_str(pside: &str) -> PyResult<usize> {
+    match pside {
+        "long" => Ok(LONG),
+        "short" => Ok(SHORT),
+        _ => Err(PyValueError::new_err(format!("unknown pside {}", pside))),
+    }
+}
+
+fn order_to_tuple(order: &Order) -> (f64, f64, String) {
+    (order.qty, order.price, order.order_type.to_string())
+}
+
+fn rejected_to_tuples(rejected: &[(Order, OrderRejection)]) -> Vec<(f64, f64, String, String)> {
+    rejected
+        .iter()
+        .map(|(order, rejection)| {
+            (
+                order.qty,
+                order.price,
+                order.order_type.to_string(),
+                rejection.to_string(),
+            )
+        })
+        .collect()
+}
+
+fn bot_params_pair_from_dict(dict: &Bound<'_, PyDict>) -> PyResult<BotParamsPair> {
+    let bot_params_pair = BotParamsPair {
+        long: bot_params_from_dict(&extract_value(dict, "long")?)?,
+        short: bot_params_from_dict(&extract_value(dict, "short")?)?,
+        // optional; absent in older configs
+        long_allocation_pct: extract_value(dict, "long_allocation_pct").unwrap_or(0.0),
+        short_allocation_pct: extract_value(dict, "short_allocation_pct").unwrap_or(0.0),
+    };
+    bot_params_pair
+        .validate_allocations()
+        .and_then(|_| bot_params_pair.validate_ema_spans())
+        .map_err(PyValueError::new_err)?;
+    Ok(bot_params_pair)
+}
+
+/// Accepts either (bid, ask) or {"bid": .., "ask": .., "levels": [(price, qty), ..]}.
+fn order_book_from_py(obj: &Bound<'_, PyAny>) -> PyResult<OrderBook> {
+    if let Ok(dict) = obj.downcast::<PyDict>() {
+        return Ok(OrderBook {
+            bid: extract_value(dict, "bid")?,
+            ask: extract_value(dict, "ask")?,
+            levels: extract_value(dict, "levels").unwrap_or_default(),
+        });
+    }
+    let (bid, ask): (f64, f64) = obj.extract()?;
+    Ok(OrderBook::new(bid, ask))
+}
+
+fn extract_bool_value(dict: &Bound<'_, PyDict>, key: &str) -> PyResult<bool> {
     if let Ok(val) = extract_value::<bool>(dict, key) {
         Ok(val)
     } else if let Ok(val) = extract_value::<i64>(dict, key) {
@@ -209,7 +1067,7 @@ fn extract_bool_value(dict: &PyDict, key: &str) -> PyResult<bool> {
     }
 }
 
-fn bot_params_from_dict(dict: &PyDict) -> PyResult<BotParams> {
+fn bot_params_from_dict(dict: &Bound<'_, PyDict>) -> PyResult<BotParams> {
     Ok(BotParams {
         close_grid_markup_range: extract_value(dict, "close_grid_markup_range")?,
         close_grid_min_markup: extract_value(dict, "close_grid_min_markup")?,
@@ -254,50 +1112,260 @@ fn bot_params_from_dict(dict: &PyDict) -> PyResult<BotParams> {
         unstuck_ema_dist: extract_value(dict, "unstuck_ema_dist")?,
         unstuck_loss_allowance_pct: extract_value(dict, "unstuck_loss_allowance_pct")?,
         unstuck_threshold: extract_value(dict, "unstuck_threshold")?,
-    })
-}
-
-fn extract_value<'a, T: pyo3::FromPyObject<'a>>(dict: &'a PyDict, key: &str) -> PyResult<T> {
-    dict.get_item(key)
-        .map_err(|_| {
-            PyErr::new::<pyo3::exceptions::PyKeyError, _>(format!("Key '{}' not found", key))
-        })?
-        .ok_or_else(|| PyErr::new::<pyo3::exceptions::PyValueError, _>("Value is None"))
-        .and_then(pyo3::FromPyObject::extract)
-}
-
-#[pyfunction]
-pub fn calc_next_entry_long_py(
-    qty_step: f64,
-    price_step: f64,
-    min_qty: f64,
-    min_cost: f64,
-    c_mult: f64,
-    entry_grid_double_down_factor: f64,
-    entry_grid_spacing_weight: f64,
-    entry_grid_spacing_pct: f64,
-    entry_initial_ema_dist: f64,
-    entry_initial_qty_pct: f64,
-    entry_trailing_double_down_factor: f64,
-    entry_trailing_grid_ratio: f64,
-    entry_trailing_retracement_pct: f64,
-    entry_trailing_threshold_pct: f64,
-    wallet_exposure_limit: f64,
-    balance: f64,
-    position_size: f64,
-    position_price: f64,
-    min_since_open: f64,
-    max_since_min: f64,
-    ema_bands_lower: f64,
-    order_book_bid: f64,
-) -> (f64, f64, String) {
-    let exchange_params = ExchangeParams {
-        qty_step,
-        price_step,
-        min_qty,
+        // the rest are absent in older configs and fall back to their defaults; a value that is
+        // present must still extract
+        auto_reduce_enabled: extract_optional_bool_value(dict, "auto_reduce_enabled", false)?,
+        auto_reduce_tolerance_pct: extract_optional_value(dict, "auto_reduce_tolerance_pct", 0.01)?,
+        balance_allocation_pct: extract_optional_value(dict, "balance_allocation_pct", 0.0)?,
+        close_before_funding: extract_optional_bool_value(dict, "close_before_funding", false)?,
+        close_before_funding_minutes: extract_optional_value(
+            dict,
+            "close_before_funding_minutes",
+            0.0,
+        )?,
+        close_before_funding_pct: extract_optional_value(dict, "close_before_funding_pct", 0.0)?,
+        close_credit_ladder_pnl: extract_optional_bool_value(
+            dict,
+            "close_credit_ladder_pnl",
+            false,
+        )?,
+        close_grid_qty_ratio: extract_optional_value(dict, "close_grid_qty_ratio", 0.0)?,
+        close_iceberg_visible_qty: extract_optional_value(dict, "close_iceberg_visible_qty", 0.0)?,
+        close_max_qty_pct_of_volume: extract_optional_value(
+            dict,
+            "close_max_qty_pct_of_volume",
+            0.0,
+        )?,
+        close_nearest_taker: extract_optional_bool_value(dict, "close_nearest_taker", false)?,
+        close_on_equity_drawdown_pct: extract_optional_value(
+            dict,
+            "close_on_equity_drawdown_pct",
+            0.0,
+        )?,
+        close_on_flow_imbalance: extract_optional_value(dict, "close_on_flow_imbalance", 0.0)?,
+        close_recover_funding: extract_optional_bool_value(dict, "close_recover_funding", false)?,
+        close_require_volume: extract_optional_bool_value(dict, "close_require_volume", false)?,
+        close_taker_threshold_pct: extract_optional_value(dict, "close_taker_threshold_pct", 0.0)?,
+        close_touch_qty_pct: extract_optional_value(dict, "close_touch_qty_pct", 0.0)?,
+        close_trailing_anchor: match dict.get_item("close_trailing_anchor")? {
+            Some(anchor) => anchor
+                .extract::<String>()?
+                .parse()
+                .map_err(PyValueError::new_err)?,
+            None => CloseTrailingAnchor::default(),
+        },
+        close_trailing_fast_qty_pct: extract_optional_value(
+            dict,
+            "close_trailing_fast_qty_pct",
+            0.0,
+        )?,
+        close_trailing_fast_retracement_pct: extract_optional_value(
+            dict,
+            "close_trailing_fast_retracement_pct",
+            0.0,
+        )?,
+        close_trailing_fast_threshold_pct: extract_optional_value(
+            dict,
+            "close_trailing_fast_threshold_pct",
+            0.0,
+        )?,
+        close_trailing_fib_levels: extract_optional_value(
+            dict,
+            "close_trailing_fib_levels",
+            Vec::new(),
+        )?,
+        close_trailing_fib_qty_pct: extract_optional_value(
+            dict,
+            "close_trailing_fib_qty_pct",
+            0.0,
+        )?,
+        close_trailing_max_candles_since_peak: {
+            let close_trailing_max_candles_since_peak_float: f64 =
+                extract_optional_value(dict, "close_trailing_max_candles_since_peak", 0.0)?;
+            close_trailing_max_candles_since_peak_float.round() as usize
+        },
+        close_trailing_slow_qty_pct: extract_optional_value(
+            dict,
+            "close_trailing_slow_qty_pct",
+            0.0,
+        )?,
+        close_trailing_slow_retracement_pct: extract_optional_value(
+            dict,
+            "close_trailing_slow_retracement_pct",
+            0.0,
+        )?,
+        close_trailing_slow_threshold_pct: extract_optional_value(
+            dict,
+            "close_trailing_slow_threshold_pct",
+            0.0,
+        )?,
+        close_trailing_step_multiple: extract_optional_value(
+            dict,
+            "close_trailing_step_multiple",
+            0.0,
+        )?,
+        compound_realized_into_balance: extract_optional_bool_value(
+            dict,
+            "compound_realized_into_balance",
+            false,
+        )?,
+        liquidity_profile: extract_optional_value(dict, "liquidity_profile", Vec::new())?,
+        max_position_cost: extract_optional_value(dict, "max_position_cost", 0.0)?,
+        min_close_volume: extract_optional_value(dict, "min_close_volume", 0.0)?,
+        mirror_entries: extract_optional_bool_value(dict, "mirror_entries", false)?,
+        neutral_mode: extract_optional_bool_value(dict, "neutral_mode", false)?,
+        rebalance_threshold_pct: extract_optional_value(dict, "rebalance_threshold_pct", 0.0)?,
+        recenter_trailing_on_partial_close: extract_optional_bool_value(
+            dict,
+            "recenter_trailing_on_partial_close",
+            false,
+        )?,
+        simulate_post_only_reject: extract_optional_bool_value(
+            dict,
+            "simulate_post_only_reject",
+            false,
+        )?,
+        target_max_staleness_ms: {
+            let target_max_staleness_ms_float: f64 =
+                extract_optional_value(dict, "target_max_staleness_ms", 0.0)?;
+            target_max_staleness_ms_float.round() as u64
+        },
+        unstuck_max_allowance_fraction: extract_optional_value(
+            dict,
+            "unstuck_max_allowance_fraction",
+            0.0,
+        )?,
+        unstuck_require_profit_buffer_pct: extract_optional_value(
+            dict,
+            "unstuck_require_profit_buffer_pct",
+            0.0,
+        )?,
+        unstuck_rotation_tolerance: extract_optional_value(
+            dict,
+            "unstuck_rotation_tolerance",
+            0.0,
+        )?,
+        // older configs used the entry spans
+        unstuck_ema_span_0: extract_optional_value(
+            dict,
+            "unstuck_ema_span_0",
+            extract_value(dict, "ema_span_0")?,
+        )?,
+        unstuck_ema_span_1: extract_optional_value(
+            dict,
+            "unstuck_ema_span_1",
+            extract_value(dict, "ema_span_1")?,
+        )?,
+    })
+}
+
+fn extract_value<'py, T: pyo3::FromPyObject<'py>>(
+    dict: &Bound<'py, PyDict>,
+    key: &str,
+) -> PyResult<T> {
+    dict.get_item(key)
+        .map_err(|_| {
+            PyErr::new::<pyo3::exceptions::PyKeyError, _>(format!("Key '{}' not found", key))
+        })?
+        .ok_or_else(|| PyErr::new::<pyo3::exceptions::PyValueError, _>("Value is None"))
+        .and_then(|value| value.extract())
+}
+
+/// dict[key], or default if key is absent.
+fn extract_optional_value<'py, T: pyo3::FromPyObject<'py>>(
+    dict: &Bound<'py, PyDict>,
+    key: &str,
+    default: T,
+) -> PyResult<T> {
+    match dict.get_item(key)? {
+        Some(value) => value.extract(),
+        None => Ok(default),
+    }
+}
+
+/// As extract_bool_value, or default if key is absent.
+fn extract_optional_bool_value(
+    dict: &Bound<'_, PyDict>,
+    key: &str,
+    default: bool,
+) -> PyResult<bool> {
+    match dict.get_item(key)? {
+        Some(_) => extract_bool_value(dict, key),
+        None => Ok(default),
+    }
+}
+
+/// As extract_optional_value, for an options dict that may itself be absent.
+fn option_value<'py, T: pyo3::FromPyObject<'py>>(
+    options: Option<&Bound<'py, PyDict>>,
+    key: &str,
+    default: T,
+) -> PyResult<T> {
+    match options {
+        Some(dict) => extract_optional_value(dict, key, default),
+        None => Ok(default),
+    }
+}
+
+/// As extract_optional_bool_value, for an options dict that may itself be absent.
+fn option_bool(options: Option<&Bound<'_, PyDict>>, key: &str, default: bool) -> PyResult<bool> {
+    match options {
+        Some(dict) => extract_optional_bool_value(dict, key, default),
+        None => Ok(default),
+    }
+}
+
+/// Errs on the first key of dict not in known, so a misspelt option fails loudly instead
+/// of leaving its feature off.
+fn reject_unknown_keys(dict: &Bound<'_, PyDict>, known: &[&str]) -> PyResult<()> {
+    for key in dict.keys() {
+        let key: &str = key.extract()?;
+        if !known.contains(&key) {
+            return Err(PyValueError::new_err(format!(
+                "unknown option '{}'; expected one of {}",
+                key,
+                known.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[pyfunction]
+pub fn calc_next_entry_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    entry_grid_double_down_factor: f64,
+    entry_grid_spacing_weight: f64,
+    entry_grid_spacing_pct: f64,
+    entry_initial_ema_dist: f64,
+    entry_initial_qty_pct: f64,
+    entry_trailing_double_down_factor: f64,
+    entry_trailing_grid_ratio: f64,
+    entry_trailing_retracement_pct: f64,
+    entry_trailing_threshold_pct: f64,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    min_since_open: f64,
+    max_since_min: f64,
+    ema_bands_lower: f64,
+    order_book_bid: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
         min_cost,
         c_mult,
-    };
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
     let state_params = StateParams {
         balance,
         order_book: OrderBook {
@@ -326,6 +1394,7 @@ pub fn calc_next_entry_long_py(
     let position = Position {
         size: position_size,
         price: position_price,
+        ..Default::default()
     };
     let trailing_price_bundle = TrailingPriceBundle {
         min_since_open: min_since_open,
@@ -340,14 +1409,13 @@ pub fn calc_next_entry_long_py(
         &trailing_price_bundle,
     );
 
-    (
-        next_entry.qty,
-        next_entry.price,
-        next_entry.order_type.to_string(),
-    )
+    Ok(next_entry
+        .order()
+        .map(|order| (order.qty, order.price, order.order_type.to_string())))
 }
 
 #[pyfunction]
+#[pyo3(signature = (qty_step, price_step, min_qty, min_cost, c_mult, close_grid_markup_range, close_grid_min_markup, close_grid_qty_pct, close_trailing_grid_ratio, close_trailing_qty_pct, close_trailing_retracement_pct, close_trailing_threshold_pct, enforce_exposure_limit, wallet_exposure_limit, balance, position_size, position_price, max_since_open, min_since_max, order_book_ask, close_trailing_anchor="peak", trailing_ma=0.0, override_avg_price=None, auto_reduce_tolerance_pct=0.01, auto_reduce_enabled=false))]
 pub fn calc_next_close_long_py(
     qty_step: f64,
     price_step: f64,
@@ -369,23 +1437,34 @@ pub fn calc_next_close_long_py(
     max_since_open: f64,
     min_since_max: f64,
     order_book_ask: f64,
-) -> (f64, f64, String) {
+    close_trailing_anchor: &str,
+    trailing_ma: f64,
+    override_avg_price: Option<f64>,
+    auto_reduce_tolerance_pct: f64,
+    auto_reduce_enabled: bool,
+) -> PyResult<Option<(f64, f64, String)>> {
     let exchange_params = ExchangeParams {
         qty_step,
         price_step,
         min_qty,
         min_cost,
         c_mult,
-    };
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
     let state_params = StateParams {
         balance,
         order_book: OrderBook {
             ask: order_book_ask,
             ..Default::default()
         },
+        trailing_ma,
         ..Default::default()
     };
     let bot_params = BotParams {
+        auto_reduce_enabled,
+        auto_reduce_tolerance_pct,
         close_grid_markup_range,
         close_grid_min_markup,
         close_grid_qty_pct,
@@ -393,6 +1472,9 @@ pub fn calc_next_close_long_py(
         close_trailing_qty_pct,
         close_trailing_retracement_pct,
         close_trailing_threshold_pct,
+        close_trailing_anchor: close_trailing_anchor
+            .parse()
+            .map_err(PyValueError::new_err)?,
         enforce_exposure_limit,
         wallet_exposure_limit,
         ..Default::default()
@@ -400,7 +1482,10 @@ pub fn calc_next_close_long_py(
     let position = Position {
         size: position_size,
         price: position_price,
-    };
+        ..Default::default()
+    }
+    .with_avg_price(override_avg_price)
+    .map_err(PyValueError::new_err)?;
     let trailing_price_bundle = TrailingPriceBundle {
         max_since_open: max_since_open,
         min_since_max: min_since_max,
@@ -413,11 +1498,9 @@ pub fn calc_next_close_long_py(
         &position,
         &trailing_price_bundle,
     );
-    (
-        next_entry.qty,
-        next_entry.price,
-        next_entry.order_type.to_string(),
-    )
+    Ok(next_entry
+        .order()
+        .map(|order| (order.qty, order.price, order.order_type.to_string())))
 }
 
 #[pyfunction]
@@ -444,14 +1527,17 @@ pub fn calc_next_entry_short_py(
     min_since_max: f64,
     ema_bands_upper: f64,
     order_book_ask: f64,
-) -> (f64, f64, String) {
+) -> PyResult<Option<(f64, f64, String)>> {
     let exchange_params = ExchangeParams {
         qty_step,
         price_step,
         min_qty,
         min_cost,
         c_mult,
-    };
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
     let state_params = StateParams {
         balance,
         order_book: OrderBook {
@@ -480,6 +1566,7 @@ pub fn calc_next_entry_short_py(
     let position = Position {
         size: position_size,
         price: position_price,
+        ..Default::default()
     };
     let trailing_price_bundle = TrailingPriceBundle {
         max_since_open: max_since_open,
@@ -494,14 +1581,13 @@ pub fn calc_next_entry_short_py(
         &trailing_price_bundle,
     );
 
-    (
-        next_entry.qty,
-        next_entry.price,
-        next_entry.order_type.to_string(),
-    )
+    Ok(next_entry
+        .order()
+        .map(|order| (order.qty, order.price, order.order_type.to_string())))
 }
 
 #[pyfunction]
+#[pyo3(signature = (qty_step, price_step, min_qty, min_cost, c_mult, close_grid_markup_range, close_grid_min_markup, close_grid_qty_pct, close_trailing_grid_ratio, close_trailing_qty_pct, close_trailing_retracement_pct, close_trailing_threshold_pct, enforce_exposure_limit, wallet_exposure_limit, balance, position_size, position_price, min_since_open, max_since_min, order_book_bid, close_trailing_anchor="peak", trailing_ma=0.0, override_avg_price=None, auto_reduce_tolerance_pct=0.01, auto_reduce_enabled=false))]
 pub fn calc_next_close_short_py(
     qty_step: f64,
     price_step: f64,
@@ -523,23 +1609,34 @@ pub fn calc_next_close_short_py(
     min_since_open: f64,
     max_since_min: f64,
     order_book_bid: f64,
-) -> (f64, f64, String) {
+    close_trailing_anchor: &str,
+    trailing_ma: f64,
+    override_avg_price: Option<f64>,
+    auto_reduce_tolerance_pct: f64,
+    auto_reduce_enabled: bool,
+) -> PyResult<Option<(f64, f64, String)>> {
     let exchange_params = ExchangeParams {
         qty_step,
         price_step,
         min_qty,
         min_cost,
         c_mult,
-    };
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
     let state_params = StateParams {
         balance,
         order_book: OrderBook {
             bid: order_book_bid,
             ..Default::default()
         },
+        trailing_ma,
         ..Default::default()
     };
     let bot_params = BotParams {
+        auto_reduce_enabled,
+        auto_reduce_tolerance_pct,
         close_grid_markup_range,
         close_grid_min_markup,
         close_grid_qty_pct,
@@ -547,6 +1644,9 @@ pub fn calc_next_close_short_py(
         close_trailing_qty_pct,
         close_trailing_retracement_pct,
         close_trailing_threshold_pct,
+        close_trailing_anchor: close_trailing_anchor
+            .parse()
+            .map_err(PyValueError::new_err)?,
         enforce_exposure_limit,
         wallet_exposure_limit,
         ..Default::default()
@@ -554,7 +1654,10 @@ pub fn calc_next_close_short_py(
     let position = Position {
         size: position_size,
         price: position_price,
-    };
+        ..Default::default()
+    }
+    .with_avg_price(override_avg_price)
+    .map_err(PyValueError::new_err)?;
     let trailing_price_bundle = TrailingPriceBundle {
         min_since_open: min_since_open,
         max_since_min: max_since_min,
@@ -567,11 +1670,9 @@ pub fn calc_next_close_short_py(
         &position,
         &trailing_price_bundle,
     );
-    (
-        next_entry.qty,
-        next_entry.price,
-        next_entry.order_type.to_string(),
-    )
+    Ok(next_entry
+        .order()
+        .map(|order| (order.qty, order.price, order.order_type.to_string())))
 }
 
 #[pyfunction]
@@ -598,14 +1699,17 @@ pub fn calc_entries_long_py(
     max_since_min: f64,
     ema_bands_lower: f64,
     order_book_bid: f64,
-) -> Vec<(f64, f64, String)> {
+) -> PyResult<Vec<(f64, f64, String)>> {
     let exchange_params = ExchangeParams {
         qty_step,
         price_step,
         min_qty,
         min_cost,
         c_mult,
-    };
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
 
     let state_params = StateParams {
         balance,
@@ -637,6 +1741,7 @@ pub fn calc_entries_long_py(
     let position = Position {
         size: position_size,
         price: position_price,
+        ..Default::default()
     };
     let trailing_price_bundle = TrailingPriceBundle {
         min_since_open: min_since_open,
@@ -652,10 +1757,10 @@ pub fn calc_entries_long_py(
     );
 
     // Convert entries to Python-compatible format
-    entries
+    Ok(entries
         .into_iter()
         .map(|order| (order.qty, order.price, order.order_type.to_string()))
-        .collect()
+        .collect())
 }
 
 #[pyfunction]
@@ -682,14 +1787,17 @@ pub fn calc_entries_short_py(
     min_since_max: f64,
     ema_bands_upper: f64,
     order_book_ask: f64,
-) -> Vec<(f64, f64, String)> {
+) -> PyResult<Vec<(f64, f64, String)>> {
     let exchange_params = ExchangeParams {
         qty_step,
         price_step,
         min_qty,
         min_cost,
         c_mult,
-    };
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
 
     let state_params = StateParams {
         balance,
@@ -721,6 +1829,7 @@ pub fn calc_entries_short_py(
     let position = Position {
         size: position_size,
         price: position_price,
+        ..Default::default()
     };
     let trailing_price_bundle = TrailingPriceBundle {
         max_since_open: max_since_open,
@@ -736,13 +1845,187 @@ pub fn calc_entries_short_py(
     );
 
     // Convert entries to Python-compatible format
-    entries
+    Ok(entries
         .into_iter()
         .map(|order| (order.qty, order.price, order.order_type.to_string()))
-        .collect()
+        .collect())
+}
+
+/// Keys of calc_closes_long_py/calc_closes_short_py's options dict, each defaulting to its
+/// feature being off.
+const CLOSE_OPTION_KEYS: &[&str] = &[
+    "price_band_pct",
+    "close_trailing_anchor",
+    "trailing_ma",
+    "blocked_prices",
+    "close_trailing_fast_qty_pct",
+    "close_trailing_fast_retracement_pct",
+    "close_trailing_fast_threshold_pct",
+    "close_trailing_slow_qty_pct",
+    "close_trailing_slow_retracement_pct",
+    "close_trailing_slow_threshold_pct",
+    "balance_allocation_pct",
+    "close_grid_qty_ratio",
+    "volume",
+    "close_require_volume",
+    "min_close_volume",
+    "avg_volume",
+    "close_max_qty_pct_of_volume",
+    "close_nearest_taker",
+    "close_taker_threshold_pct",
+    "close_recover_funding",
+    "position_accrued_funding",
+    "close_trailing_fib_levels",
+    "close_trailing_fib_qty_pct",
+    "fib_levels_closed",
+    "liquidity_profile",
+    "hour",
+    "close_trailing_step_multiple",
+    "stepped_stop_price",
+    "compound_realized_into_balance",
+    "realized_pnl",
+    "simulate_post_only_reject",
+    "close_touch_qty_pct",
+    "equity",
+    "peak_equity",
+    "close_on_equity_drawdown_pct",
+    "min_markup_floor",
+    "override_avg_price",
+    "close_on_flow_imbalance",
+    "order_flow_imbalance",
+    "auto_reduce_tolerance_pct",
+    "auto_reduce_enabled",
+    "close_credit_ladder_pnl",
+];
+
+/// The external target only replaces long grid closes (see fresh_target_price).
+const CLOSE_OPTION_KEYS_LONG: &[&str] = &["timestamp", "target_price", "target_max_staleness_ms"];
+
+/// Everything calc_closes_long_py/calc_closes_short_py take beyond their positional args,
+/// as the params it fills in.
+struct CloseOptions {
+    exchange_params: ExchangeParams,
+    state_params: StateParams,
+    bot_params: BotParams,
+    position: Position,
+    override_avg_price: Option<f64>,
+    trailing_price_bundle: TrailingPriceBundle,
+    blocked_prices: Vec<f64>,
+}
+
+fn close_options_from_dict(
+    options: Option<&Bound<'_, PyDict>>,
+    pside: usize,
+) -> PyResult<CloseOptions> {
+    if let Some(dict) = options {
+        let long_keys = if pside == LONG {
+            CLOSE_OPTION_KEYS_LONG
+        } else {
+            &[]
+        };
+        reject_unknown_keys(dict, &[CLOSE_OPTION_KEYS, long_keys].concat())?;
+    }
+    Ok(CloseOptions {
+        exchange_params: ExchangeParams {
+            price_band_pct: option_value(options, "price_band_pct", 0.0)?,
+            ..Default::default()
+        },
+        state_params: StateParams {
+            trailing_ma: option_value(options, "trailing_ma", 0.0)?,
+            volume: option_value(options, "volume", 0.0)?,
+            avg_volume: option_value(options, "avg_volume", 0.0)?,
+            timestamp: option_value(options, "timestamp", 0)?,
+            target_price: option_value(options, "target_price", None)?,
+            hour: option_value(options, "hour", 0)?,
+            realized_pnl: option_value(options, "realized_pnl", 0.0)?,
+            equity: option_value(options, "equity", 0.0)?,
+            peak_equity: option_value(options, "peak_equity", 0.0)?,
+            min_markup_floor: option_value(options, "min_markup_floor", 0.0)?,
+            order_flow_imbalance: option_value(options, "order_flow_imbalance", None)?,
+            ..Default::default()
+        },
+        bot_params: BotParams {
+            auto_reduce_enabled: option_bool(options, "auto_reduce_enabled", false)?,
+            auto_reduce_tolerance_pct: option_value(options, "auto_reduce_tolerance_pct", 0.01)?,
+            close_credit_ladder_pnl: option_bool(options, "close_credit_ladder_pnl", false)?,
+            close_trailing_fast_qty_pct: option_value(options, "close_trailing_fast_qty_pct", 0.0)?,
+            close_trailing_fast_retracement_pct: option_value(
+                options,
+                "close_trailing_fast_retracement_pct",
+                0.0,
+            )?,
+            close_trailing_fast_threshold_pct: option_value(
+                options,
+                "close_trailing_fast_threshold_pct",
+                0.0,
+            )?,
+            close_trailing_slow_qty_pct: option_value(options, "close_trailing_slow_qty_pct", 0.0)?,
+            close_trailing_slow_retracement_pct: option_value(
+                options,
+                "close_trailing_slow_retracement_pct",
+                0.0,
+            )?,
+            close_trailing_slow_threshold_pct: option_value(
+                options,
+                "close_trailing_slow_threshold_pct",
+                0.0,
+            )?,
+            close_trailing_step_multiple: option_value(
+                options,
+                "close_trailing_step_multiple",
+                0.0,
+            )?,
+            close_trailing_fib_levels: option_value(options, "close_trailing_fib_levels", vec![])?,
+            compound_realized_into_balance: option_bool(
+                options,
+                "compound_realized_into_balance",
+                false,
+            )?,
+            simulate_post_only_reject: option_bool(options, "simulate_post_only_reject", false)?,
+            close_trailing_fib_qty_pct: option_value(options, "close_trailing_fib_qty_pct", 0.0)?,
+            liquidity_profile: option_value(options, "liquidity_profile", vec![])?,
+            balance_allocation_pct: option_value(options, "balance_allocation_pct", 0.0)?,
+            close_grid_qty_ratio: option_value(options, "close_grid_qty_ratio", 0.0)?,
+            close_max_qty_pct_of_volume: option_value(options, "close_max_qty_pct_of_volume", 0.0)?,
+            close_nearest_taker: option_bool(options, "close_nearest_taker", false)?,
+            close_on_equity_drawdown_pct: option_value(
+                options,
+                "close_on_equity_drawdown_pct",
+                0.0,
+            )?,
+            close_on_flow_imbalance: option_value(options, "close_on_flow_imbalance", 0.0)?,
+            close_taker_threshold_pct: option_value(options, "close_taker_threshold_pct", 0.0)?,
+            close_touch_qty_pct: option_value(options, "close_touch_qty_pct", 0.0)?,
+            close_recover_funding: option_bool(options, "close_recover_funding", false)?,
+            close_require_volume: option_bool(options, "close_require_volume", false)?,
+            min_close_volume: option_value(options, "min_close_volume", 0.0)?,
+            close_trailing_anchor: option_value(
+                options,
+                "close_trailing_anchor",
+                "peak".to_string(),
+            )?
+            .parse()
+            .map_err(PyValueError::new_err)?,
+            target_max_staleness_ms: option_value(options, "target_max_staleness_ms", 0)?,
+            ..Default::default()
+        },
+        position: Position {
+            accrued_funding: option_value(options, "position_accrued_funding", 0.0)?,
+            ..Default::default()
+        },
+        override_avg_price: option_value(options, "override_avg_price", None)?,
+        trailing_price_bundle: TrailingPriceBundle {
+            fib_levels_closed: option_value(options, "fib_levels_closed", 0)?,
+            stepped_stop_price: option_value(options, "stepped_stop_price", 0.0)?,
+            ..Default::default()
+        },
+        blocked_prices: option_value(options, "blocked_prices", vec![])?,
+    })
 }
 
+/// options: dict of any of CLOSE_OPTION_KEYS and CLOSE_OPTION_KEYS_LONG; unknown keys raise.
 #[pyfunction]
+#[pyo3(signature = (qty_step, price_step, min_qty, min_cost, c_mult, close_grid_markup_range, close_grid_min_markup, close_grid_qty_pct, close_trailing_grid_ratio, close_trailing_qty_pct, close_trailing_retracement_pct, close_trailing_threshold_pct, enforce_exposure_limit, wallet_exposure_limit, balance, position_size, position_price, max_since_open, min_since_max, order_book_ask, options=None))]
 pub fn calc_closes_long_py(
     qty_step: f64,
     price_step: f64,
@@ -764,14 +2047,19 @@ pub fn calc_closes_long_py(
     max_since_open: f64,
     min_since_max: f64,
     order_book_ask: f64,
-) -> Vec<(f64, f64, String)> {
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let options = close_options_from_dict(options, LONG)?;
     let exchange_params = ExchangeParams {
         qty_step,
         price_step,
         min_qty,
         min_cost,
         c_mult,
-    };
+        ..options.exchange_params
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
 
     let state_params = StateParams {
         balance,
@@ -779,7 +2067,7 @@ pub fn calc_closes_long_py(
             ask: order_book_ask,
             ..Default::default()
         },
-        ..Default::default()
+        ..options.state_params
     };
 
     let bot_params = BotParams {
@@ -792,17 +2080,20 @@ pub fn calc_closes_long_py(
         close_trailing_threshold_pct,
         enforce_exposure_limit,
         wallet_exposure_limit,
-        ..Default::default()
+        ..options.bot_params
     };
 
     let position = Position {
         size: position_size,
         price: position_price,
-    };
+        ..options.position
+    }
+    .with_avg_price(options.override_avg_price)
+    .map_err(PyValueError::new_err)?;
     let trailing_price_bundle = TrailingPriceBundle {
-        max_since_open: max_since_open,
-        min_since_max: min_since_max,
-        ..Default::default()
+        max_since_open,
+        min_since_max,
+        ..options.trailing_price_bundle
     };
     let closes = calc_closes_long(
         &exchange_params,
@@ -810,16 +2101,19 @@ pub fn calc_closes_long_py(
         &bot_params,
         &position,
         &trailing_price_bundle,
+        &options.blocked_prices,
     );
 
     // Convert closes to Python-compatible format
-    closes
+    Ok(closes
         .into_iter()
         .map(|order| (order.qty, order.price, order.order_type.to_string()))
-        .collect()
+        .collect())
 }
 
+/// options: dict of any of CLOSE_OPTION_KEYS; unknown keys raise.
 #[pyfunction]
+#[pyo3(signature = (qty_step, price_step, min_qty, min_cost, c_mult, close_grid_markup_range, close_grid_min_markup, close_grid_qty_pct, close_trailing_grid_ratio, close_trailing_qty_pct, close_trailing_retracement_pct, close_trailing_threshold_pct, enforce_exposure_limit, wallet_exposure_limit, balance, position_size, position_price, min_since_open, max_since_min, order_book_bid, options=None))]
 pub fn calc_closes_short_py(
     qty_step: f64,
     price_step: f64,
@@ -841,14 +2135,19 @@ pub fn calc_closes_short_py(
     min_since_open: f64,
     max_since_min: f64,
     order_book_bid: f64,
-) -> Vec<(f64, f64, String)> {
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let options = close_options_from_dict(options, SHORT)?;
     let exchange_params = ExchangeParams {
         qty_step,
         price_step,
         min_qty,
         min_cost,
         c_mult,
-    };
+        ..options.exchange_params
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
 
     let state_params = StateParams {
         balance,
@@ -856,7 +2155,7 @@ pub fn calc_closes_short_py(
             bid: order_book_bid,
             ..Default::default()
         },
-        ..Default::default()
+        ..options.state_params
     };
 
     let bot_params = BotParams {
@@ -869,16 +2168,19 @@ pub fn calc_closes_short_py(
         close_trailing_threshold_pct,
         enforce_exposure_limit,
         wallet_exposure_limit,
-        ..Default::default()
+        ..options.bot_params
     };
     let position = Position {
         size: position_size,
         price: position_price,
-    };
+        ..options.position
+    }
+    .with_avg_price(options.override_avg_price)
+    .map_err(PyValueError::new_err)?;
     let trailing_price_bundle = TrailingPriceBundle {
-        min_since_open: min_since_open,
-        max_since_min: max_since_min,
-        ..Default::default()
+        min_since_open,
+        max_since_min,
+        ..options.trailing_price_bundle
     };
     let closes = calc_closes_short(
         &exchange_params,
@@ -886,11 +2188,2982 @@ pub fn calc_closes_short_py(
         &bot_params,
         &position,
         &trailing_price_bundle,
+        &options.blocked_prices,
     );
 
     // Convert closes to Python-compatible format
-    closes
+    Ok(closes
         .into_iter()
         .map(|order| (order.qty, order.price, order.order_type.to_string()))
-        .collect()
+        .collect())
+}
+
+/// The trailing stop for pside ("long" or "short") moved from stepped_stop_price, the last
+/// one placed, in whole increments of price_step * close_trailing_step_multiple; keep the
+/// result as the next call's stepped_stop_price.
+#[pyfunction]
+pub fn calc_stepped_trailing_stop_price_py(
+    pside: &str,
+    price_step: f64,
+    close_trailing_step_multiple: f64,
+    stop_price: f64,
+    stepped_stop_price: f64,
+) -> PyResult<f64> {
+    let exchange_params = ExchangeParams {
+        price_step,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let bot_params = BotParams {
+        close_trailing_step_multiple,
+        ..Default::default()
+    };
+    match pside {
+        "long" => Ok(calc_stepped_trailing_stop_price_long(
+            &exchange_params,
+            &bot_params,
+            stop_price,
+            stepped_stop_price,
+        )),
+        "short" => Ok(calc_stepped_trailing_stop_price_short(
+            &exchange_params,
+            &bot_params,
+            stop_price,
+            stepped_stop_price,
+        )),
+        _ => Err(PyValueError::new_err(format!("unknown pside {}", pside))),
+    }
+}
+
+/// The TrailingPriceBundle, as a dict, a backtest would hold for a position whose last fill was
+/// at candle last_fill_index of hlcvs, one coin's candles as rows of [high, low, close, volume],
+/// for restoring trailing state on a live restart; see backtest::reconstruct_trailing_price_bundle.
+/// trailing_mas is only needed to step a moving-average anchored stop.
+#[pyfunction]
+#[pyo3(signature = (pside, hlcvs, last_fill_index, price_step, close_trailing_retracement_pct=0.0, close_trailing_step_multiple=0.0, close_trailing_anchor="peak", trailing_mas=None))]
+pub fn reconstruct_trailing_price_bundle_py(
+    pside: &str,
+    hlcvs: PyReadonlyArray2<f64>,
+    last_fill_index: usize,
+    price_step: f64,
+    close_trailing_retracement_pct: f64,
+    close_trailing_step_multiple: f64,
+    close_trailing_anchor: &str,
+    trailing_mas: Option<PyReadonlyArray1<f64>>,
+) -> PyResult<Py<PyDict>> {
+    let pside = pside_from_str(pside)?;
+    let exchange_params = ExchangeParams {
+        price_step,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let bot_params = BotParams {
+        close_trailing_retracement_pct,
+        close_trailing_step_multiple,
+        close_trailing_anchor: close_trailing_anchor
+            .parse()
+            .map_err(PyValueError::new_err)?,
+        ..Default::default()
+    };
+    let trailing_mas = trailing_mas.map_or(Vec::new(), |mas| mas.as_array().to_vec());
+    let trailing_price_bundle = reconstruct_trailing_price_bundle(
+        &exchange_params,
+        &bot_params,
+        &hlcvs.as_array(),
+        &trailing_mas,
+        last_fill_index,
+        pside,
+    )
+    .map_err(PyValueError::new_err)?;
+    Python::with_gil(|py| Ok(struct_to_py_dict(py, &trailing_price_bundle)?.into()))
+}
+
+#[pyfunction]
+pub fn calc_close_with_fallback_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    close_grid_qty_pct: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    fallback_candles: usize,
+) -> PyResult<Option<(OrderTuple, OrderTuple, usize)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        close_grid_qty_pct,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_close_with_fallback_long(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        fallback_candles,
+    )
+    .map(|close| {
+        (
+            (
+                close.limit.qty,
+                close.limit.price,
+                close.limit.order_type.to_string(),
+            ),
+            (
+                close.fallback.qty,
+                close.fallback.price,
+                close.fallback.order_type.to_string(),
+            ),
+            close.fallback_candles,
+        )
+    }))
+}
+
+#[pyfunction]
+pub fn calc_staggered_closes_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    close_grid_qty_pct: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    min_expiry_candles: usize,
+    max_expiry_candles: usize,
+) -> PyResult<Vec<(OrderTuple, usize)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        close_grid_qty_pct,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_staggered_closes_long(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        &TrailingPriceBundle::default(),
+        min_expiry_candles,
+        max_expiry_candles,
+    )
+    .into_iter()
+    .map(|close| {
+        (
+            (
+                close.order.qty,
+                close.order.price,
+                close.order.order_type.to_string(),
+            ),
+            close.expiry_candles,
+        )
+    })
+    .collect())
+}
+
+#[pyfunction]
+pub fn calc_var_target_closes_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    volatility: f64,
+    close_level_var_target: f64,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_var_target_closes_long(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        volatility,
+        close_level_var_target,
+    )
+    .into_iter()
+    .map(|close| (close.qty, close.price, close.order_type.to_string()))
+    .collect())
+}
+
+/// entries are (qty, price) pairs of the entry grid to mirror.
+#[pyfunction]
+pub fn calc_mirrored_closes_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    entries: Vec<(f64, f64)>,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    let entries: Vec<Order> = entries
+        .into_iter()
+        .map(|(qty, price)| Order {
+            qty,
+            price,
+            order_type: OrderType::EntryGridNormalLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
+        .collect();
+    Ok(
+        calc_mirrored_closes_long(&exchange_params, &state_params, &position, &entries)
+            .into_iter()
+            .map(|close| (close.qty, close.price, close.order_type.to_string()))
+            .collect(),
+    )
+}
+
+#[pyfunction]
+pub fn calc_daily_pnl_target_close_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    close_grid_qty_pct: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    pnl_realized_today: f64,
+    daily_pnl_target: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        close_grid_qty_pct,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_daily_pnl_target_close_long(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        pnl_realized_today,
+        daily_pnl_target,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_daily_pnl_target_close_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    close_grid_qty_pct: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    pnl_realized_today: f64,
+    daily_pnl_target: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        close_grid_qty_pct,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_daily_pnl_target_close_short(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        pnl_realized_today,
+        daily_pnl_target,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_kelly_close_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    win_prob: f64,
+    win_loss_ratio: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_kelly_close_long(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        win_prob,
+        win_loss_ratio,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_kelly_close_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    win_prob: f64,
+    win_loss_ratio: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_kelly_close_short(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        win_prob,
+        win_loss_ratio,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_margin_target_close_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_ask: f64,
+    used_margin: f64,
+    maintenance_margin: f64,
+    target_margin_ratio: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_ask, order_book_ask),
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_margin_target_close_long(
+        &exchange_params,
+        &state_params,
+        &position,
+        used_margin,
+        maintenance_margin,
+        target_margin_ratio,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_margin_target_close_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    used_margin: f64,
+    maintenance_margin: f64,
+    target_margin_ratio: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_bid, order_book_bid),
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_margin_target_close_short(
+        &exchange_params,
+        &state_params,
+        &position,
+        used_margin,
+        maintenance_margin,
+        target_margin_ratio,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_funding_window_close_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_before_funding: bool,
+    close_before_funding_minutes: f64,
+    close_before_funding_pct: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_ask: f64,
+    minutes_to_funding: f64,
+    funding_rate: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_ask, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_before_funding,
+        close_before_funding_minutes,
+        close_before_funding_pct,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_funding_window_close_long(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        minutes_to_funding,
+        funding_rate,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_funding_window_close_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_before_funding: bool,
+    close_before_funding_minutes: f64,
+    close_before_funding_pct: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    minutes_to_funding: f64,
+    funding_rate: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_bid, order_book_bid),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_before_funding,
+        close_before_funding_minutes,
+        close_before_funding_pct,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_funding_window_close_short(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        minutes_to_funding,
+        funding_rate,
+    )
+    .map(|close| (close.qty, close.price, close.order_type.to_string())))
+}
+
+/// Entry bringing the lagging side of a neutral pair up to the leading side.
+/// Returns (qty, price, order_type) or None; short_size is negative.
+#[pyfunction]
+pub fn calc_neutral_rebalance_entry_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    rebalance_threshold_pct: f64,
+    long_size: f64,
+    long_price: f64,
+    short_size: f64,
+    short_price: f64,
+    price: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let bot_params = BotParams {
+        neutral_mode: true,
+        rebalance_threshold_pct,
+        ..Default::default()
+    };
+    let bot_params_pair = BotParamsPair {
+        long: bot_params.clone(),
+        short: bot_params,
+        ..Default::default()
+    };
+    let position_long = Position {
+        size: long_size,
+        price: long_price,
+        ..Default::default()
+    };
+    let position_short = Position {
+        size: short_size,
+        price: short_price,
+        ..Default::default()
+    };
+    Ok(calc_neutral_rebalance_entry(
+        &exchange_params,
+        &bot_params_pair,
+        &position_long,
+        &position_short,
+        price,
+    )
+    .map(|order| (order.qty, order.price, order.order_type.to_string())))
+}
+
+/// Close trimming the leading side of a neutral pair down to the lagging side.
+/// Returns (qty, price, order_type) or None; short_size is negative.
+#[pyfunction]
+pub fn calc_neutral_rebalance_close_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    rebalance_threshold_pct: f64,
+    long_size: f64,
+    long_price: f64,
+    short_size: f64,
+    short_price: f64,
+    price: f64,
+) -> PyResult<Option<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let bot_params = BotParams {
+        neutral_mode: true,
+        rebalance_threshold_pct,
+        ..Default::default()
+    };
+    let bot_params_pair = BotParamsPair {
+        long: bot_params.clone(),
+        short: bot_params,
+        ..Default::default()
+    };
+    let position_long = Position {
+        size: long_size,
+        price: long_price,
+        ..Default::default()
+    };
+    let position_short = Position {
+        size: short_size,
+        price: short_price,
+        ..Default::default()
+    };
+    Ok(calc_neutral_rebalance_close(
+        &exchange_params,
+        &bot_params_pair,
+        &position_long,
+        &position_short,
+        price,
+    )
+    .map(|order| (order.qty, order.price, order.order_type.to_string())))
+}
+
+#[pyfunction]
+pub fn calc_bracket_close_long_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_qty_pct: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_ask: f64,
+    target_price: f64,
+    bracket_pct: f64,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_ask, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_qty_pct,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_bracket_close_long(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        target_price,
+        bracket_pct,
+    )
+    .into_iter()
+    .map(|close| (close.qty, close.price, close.order_type.to_string()))
+    .collect())
+}
+
+#[pyfunction]
+pub fn calc_bracket_close_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_qty_pct: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    target_price: f64,
+    bracket_pct: f64,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_bid, order_book_bid),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_qty_pct,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_bracket_close_short(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        target_price,
+        bracket_pct,
+    )
+    .into_iter()
+    .map(|close| (close.qty, close.price, close.order_type.to_string()))
+    .collect())
+}
+
+/// calc_closes_long_by_price or calc_closes_short_by_price for state (see pside_state), as a
+/// dict of price to (qty, price, order_type) in ascending price.
+#[pyfunction]
+#[pyo3(signature = (exchange_params, bot_params, state, blocked_prices=vec![]))]
+pub fn calc_closes_by_price_py(
+    py: Python,
+    exchange_params: &Bound<'_, PyDict>,
+    bot_params: &Bound<'_, PyDict>,
+    state: &Bound<'_, PyDict>,
+    blocked_prices: Vec<f64>,
+) -> PyResult<Py<PyDict>> {
+    let exchange_params = exchange_params_from_dict(exchange_params)?;
+    let bot_params = bot_params_from_dict(bot_params)?;
+    let (pside, state_params, position, trailing_price_bundle) = pside_state(state)?;
+    let calc_closes_by_price = match pside {
+        LONG => calc_closes_long_by_price,
+        _ => calc_closes_short_by_price,
+    };
+    let by_price = calc_closes_by_price(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        &trailing_price_bundle,
+        &blocked_prices,
+    );
+    let dict = PyDict::new_bound(py);
+    for close in by_price.values() {
+        dict.set_item(close.price, order_to_tuple(close))?;
+    }
+    Ok(dict.unbind())
+}
+
+/// calc_all_closes_ordered for pside ("long" or "short"): the closes of each state (see
+/// ideal_orders_state; each of pside and with its own "idx"), as (idx, closes) sorted by
+/// idx, closes being (qty, price, order_type).
+#[pyfunction]
+pub fn calc_all_closes_ordered_py(
+    exchange_params_list: &Bound<'_, PyAny>,
+    bot_params_pair_dict: &Bound<'_, PyDict>,
+    states: Vec<Bound<'_, PyDict>>,
+    pside: &str,
+) -> PyResult<Vec<(usize, Vec<OrderTuple>)>> {
+    let pside = pside_from_str(pside)?;
+    let exchange_params_list = exchange_params_list_from_py(exchange_params_list)?;
+    let mut state_params_list = vec![StateParams::default(); exchange_params_list.len()];
+    let mut positions = Positions::default();
+    let mut trailing_price_bundles = HashMap::new();
+    for state in states {
+        let (idx, state_pside, state_params, position, trailing_price_bundle) =
+            ideal_orders_state(&state)?;
+        if state_pside != pside {
+            return Err(PyValueError::new_err(format!(
+                "state for idx {} is not of pside {}",
+                idx,
+                if pside == LONG { "long" } else { "short" }
+            )));
+        }
+        if idx as usize >= exchange_params_list.len() {
+            return Err(PyValueError::new_err(format!(
+                "idx {} out of range for {} symbols",
+                idx,
+                exchange_params_list.len()
+            )));
+        }
+        state_params_list[idx as usize] = state_params;
+        match pside {
+            LONG => positions.long.insert(idx, position),
+            _ => positions.short.insert(idx, position),
+        };
+        trailing_price_bundles.insert(idx, trailing_price_bundle);
+    }
+    let closes = calc_all_closes_ordered(
+        &exchange_params_list,
+        &state_params_list,
+        &bot_params_pair_from_dict(bot_params_pair_dict)?,
+        &positions,
+        &trailing_price_bundles,
+        pside,
+    )
+    .map_err(PyValueError::new_err)?;
+    Ok(closes
+        .into_iter()
+        .map(|(idx, closes)| (idx, closes.iter().map(order_to_tuple).collect()))
+        .collect())
+}
+
+/// Total quote notional of orders, as (qty, price, order_type), long and short alike.
+#[pyfunction]
+pub fn calc_ladder_notional_py(orders: Vec<(f64, f64, String)>, c_mult: f64) -> PyResult<f64> {
+    Ok(calc_ladder_notional(&orders_from_tuples(orders)?, c_mult))
+}
+
+/// Checks a ladder of (qty, price, order_type) for pside ("long" or "short") against
+/// check_ladder_invariants; raises ValueError naming the first order and rule broken.
+#[pyfunction]
+pub fn check_ladder_invariants_py(
+    exchange_params: &Bound<'_, PyDict>,
+    bot_params: &Bound<'_, PyDict>,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    orders: Vec<(f64, f64, String)>,
+    pside: &str,
+) -> PyResult<()> {
+    let pside = pside_from_str(pside)?;
+    let orders = orders_from_tuples(orders)?;
+    let state_params = StateParams {
+        balance,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    check_ladder_invariants(
+        &orders,
+        &exchange_params_from_dict(exchange_params)?,
+        &state_params,
+        &bot_params_from_dict(bot_params)?,
+        &position,
+        pside,
+    )
+    .map_err(PyValueError::new_err)
+}
+
+/// Splits orders, as (qty, price, order_type), into those passing validate_order against
+/// filters and those dropped, as (qty, price, order_type, rejection). n_open orders already
+/// open count towards max_open_orders.
+#[pyfunction]
+#[pyo3(signature = (filters, orders, mark_price, n_open=0))]
+pub fn validate_orders_py(
+    filters: &Bound<'_, PyDict>,
+    orders: Vec<(f64, f64, String)>,
+    mark_price: f64,
+    n_open: usize,
+) -> PyResult<(Vec<OrderTuple>, Vec<RejectedOrderTuple>)> {
+    let (valid, rejected) = validate_orders(
+        &orders_from_tuples(orders)?,
+        &exchange_filters_from_dict(filters)?,
+        mark_price,
+        n_open,
+    );
+    Ok((
+        valid.iter().map(order_to_tuple).collect(),
+        rejected_to_tuples(&rejected),
+    ))
+}
+
+/// diff_orders over open and ideal orders given as (qty, price, order_type); returns a dict
+/// with to_cancel, to_create and rejected, the latter's orders carrying their rejection.
+#[pyfunction]
+pub fn diff_orders_py(
+    py: Python,
+    filters: &Bound<'_, PyDict>,
+    open_orders: Vec<(f64, f64, String)>,
+    ideal_orders: Vec<(f64, f64, String)>,
+    mark_price: f64,
+) -> PyResult<PyObject> {
+    let diff = diff_orders(
+        &orders_from_tuples(open_orders)?,
+        &orders_from_tuples(ideal_orders)?,
+        &exchange_filters_from_dict(filters)?,
+        mark_price,
+    );
+    order_diff_to_py_dict(py, &diff)
+}
+
+fn order_diff_to_py_dict(py: Python, diff: &OrderDiff) -> PyResult<PyObject> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item(
+        "to_cancel",
+        diff.to_cancel
+            .iter()
+            .map(order_to_tuple)
+            .collect::<Vec<_>>(),
+    )?;
+    dict.set_item(
+        "to_create",
+        diff.to_create
+            .iter()
+            .map(order_to_tuple)
+            .collect::<Vec<_>>(),
+    )?;
+    dict.set_item("rejected", rejected_to_tuples(&diff.rejected))?;
+    Ok(dict.into())
+}
+
+#[pyfunction]
+pub fn calc_close_with_fallback_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    close_grid_qty_pct: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    fallback_candles: usize,
+) -> PyResult<Option<(OrderTuple, OrderTuple, usize)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        close_grid_qty_pct,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_close_with_fallback_short(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        fallback_candles,
+    )
+    .map(|close| {
+        (
+            (
+                close.limit.qty,
+                close.limit.price,
+                close.limit.order_type.to_string(),
+            ),
+            (
+                close.fallback.qty,
+                close.fallback.price,
+                close.fallback.order_type.to_string(),
+            ),
+            close.fallback_candles,
+        )
+    }))
+}
+
+#[pyfunction]
+pub fn calc_staggered_closes_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    close_grid_qty_pct: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    min_expiry_candles: usize,
+    max_expiry_candles: usize,
+) -> PyResult<Vec<(OrderTuple, usize)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        close_grid_qty_pct,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_staggered_closes_short(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        &TrailingPriceBundle::default(),
+        min_expiry_candles,
+        max_expiry_candles,
+    )
+    .into_iter()
+    .map(|close| {
+        (
+            (
+                close.order.qty,
+                close.order.price,
+                close.order.order_type.to_string(),
+            ),
+            close.expiry_candles,
+        )
+    })
+    .collect())
+}
+
+#[pyfunction]
+pub fn calc_var_target_closes_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    close_grid_markup_range: f64,
+    close_grid_min_markup: f64,
+    enforce_exposure_limit: bool,
+    wallet_exposure_limit: f64,
+    balance: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    volatility: f64,
+    close_level_var_target: f64,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        balance,
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let bot_params = BotParams {
+        close_grid_markup_range,
+        close_grid_min_markup,
+        enforce_exposure_limit,
+        wallet_exposure_limit,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    Ok(calc_var_target_closes_short(
+        &exchange_params,
+        &state_params,
+        &bot_params,
+        &position,
+        volatility,
+        close_level_var_target,
+    )
+    .into_iter()
+    .map(|close| (close.qty, close.price, close.order_type.to_string()))
+    .collect())
+}
+
+/// entries are (qty, price) pairs of the entry grid to mirror.
+#[pyfunction]
+pub fn calc_mirrored_closes_short_py(
+    qty_step: f64,
+    price_step: f64,
+    min_qty: f64,
+    min_cost: f64,
+    c_mult: f64,
+    position_size: f64,
+    position_price: f64,
+    order_book_bid: f64,
+    order_book_ask: f64,
+    entries: Vec<(f64, f64)>,
+) -> PyResult<Vec<(f64, f64, String)>> {
+    let exchange_params = ExchangeParams {
+        qty_step,
+        price_step,
+        min_qty,
+        min_cost,
+        c_mult,
+        ..Default::default()
+    }
+    .validated(false, 0.0)
+    .map_err(PyValueError::new_err)?;
+    let state_params = StateParams {
+        order_book: OrderBook::new(order_book_bid, order_book_ask),
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    let entries: Vec<Order> = entries
+        .into_iter()
+        .map(|(qty, price)| Order {
+            qty,
+            price,
+            order_type: OrderType::EntryGridNormalShort,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        })
+        .collect();
+    Ok(
+        calc_mirrored_closes_short(&exchange_params, &state_params, &position, &entries)
+            .into_iter()
+            .map(|close| (close.qty, close.price, close.order_type.to_string()))
+            .collect(),
+    )
+}
+
+fn py_to_json_value(py: Python, obj: &Bound<'_, PyAny>) -> PyResult<Value> {
+    let json_str: String = py
+        .import_bound("json")?
+        .call_method1("dumps", (obj,))?
+        .extract()?;
+    serde_json::from_str(&json_str)
+        .map_err(|e| PyValueError::new_err(format!("JSON deserialization error: {}", e)))
+}
+
+fn json_value_to_py(py: Python, value: &Value) -> PyResult<PyObject> {
+    let json = py.import_bound("json")?;
+    Ok(json.call_method1("loads", (value.to_string(),))?.into())
+}
+
+#[pyfunction]
+pub fn diff_bot_params_py(
+    py: Python,
+    old_bot_params_pair_dict: &Bound<'_, PyDict>,
+    new_bot_params_pair_dict: &Bound<'_, PyDict>,
+) -> PyResult<Vec<(String, PyObject, PyObject)>> {
+    let old = bot_params_pair_from_dict(old_bot_params_pair_dict)?;
+    let new = bot_params_pair_from_dict(new_bot_params_pair_dict)?;
+    old.diff(&new)
+        .into_iter()
+        .map(|(path, old_value, new_value)| {
+            Ok((
+                path,
+                json_value_to_py(py, &old_value)?,
+                json_value_to_py(py, &new_value)?,
+            ))
+        })
+        .collect()
+}
+
+/// Rewrites the bot parameters of an old (e.g. v6 multi) or current config into the current
+/// {"long": .., "short": ..} layout. Returns the parameters and the migrations applied.
+#[pyfunction]
+pub fn migrate_config<'py>(
+    py: Python<'py>,
+    config: &Bound<'_, PyDict>,
+) -> PyResult<(Bound<'py, PyDict>, Vec<String>)> {
+    let (bot_params_pair, report) = bot_params_pair_from_config(&py_to_json_value(py, config)?)
+        .map_err(PyValueError::new_err)?;
+    Ok((struct_to_py_dict(py, &bot_params_pair)?, report))
+}
+
+#[pyfunction]
+pub fn merge_bot_params_py<'py>(
+    py: Python<'py>,
+    bot_params_pair_dict: &Bound<'_, PyDict>,
+    updates: &Bound<'_, PyDict>, // {"long.close_grid_qty_pct": 0.6, ...}
+) -> PyResult<Bound<'py, PyDict>> {
+    let mut bot_params_pair = bot_params_pair_from_dict(bot_params_pair_dict)?;
+    let updates = updates
+        .iter()
+        .map(|(path, value)| Ok((path.extract::<String>()?, py_to_json_value(py, &value)?)))
+        .collect::<PyResult<Vec<(String, Value)>>>()?;
+    bot_params_pair
+        .merge_partial(&updates)
+        .map_err(PyValueError::new_err)?;
+    struct_to_py_dict(py, &bot_params_pair)
+}
+
+/// Stuck severity of a position, 0.0 to 1.0; see calc_stuck_severity. Shorts have negative
+/// position_size.
+#[pyfunction]
+pub fn calc_stuck_severity_py(
+    position_size: f64,
+    position_price: f64,
+    balance: f64,
+    wallet_exposure_limit: f64,
+    unstuck_threshold: f64,
+    close_price: f64,
+    c_mult: f64,
+) -> f64 {
+    let bot_params = BotParams {
+        wallet_exposure_limit,
+        unstuck_threshold,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    calc_stuck_severity(&position, balance, &bot_params, close_price, c_mult)
+}
+
+/// Positions from an exchange's position payload, [(symbol, pside, size, price), ..] with pside
+/// "long" or "short", as {"long": {idx: (size, price)}, "short": {..}} with idx the symbol's
+/// position in symbols; see Positions::from_exchange_snapshot.
+#[pyfunction]
+pub fn positions_from_exchange_snapshot_py(
+    py: Python,
+    symbols: Vec<String>,
+    positions: Vec<(String, String, f64, f64)>,
+) -> PyResult<Py<PyDict>> {
+    let positions = positions
+        .into_iter()
+        .map(|(symbol, pside, size, price)| {
+            let pside = pside_from_str(&pside)?;
+            Ok(ExchangePosition {
+                symbol,
+                pside,
+                size,
+                price,
+            })
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    let positions = Positions::from_exchange_snapshot(&ExchangeSnapshot { symbols, positions })
+        .map_err(PyValueError::new_err)?;
+    let py_positions = PyDict::new_bound(py);
+    for (pside, side) in [("long", &positions.long), ("short", &positions.short)] {
+        let side: BTreeMap<SymbolIdx, (f64, f64)> = side
+            .iter()
+            .map(|(&idx, position)| (idx, (position.size, position.price)))
+            .collect();
+        py_positions.set_item(pside, side)?;
+    }
+    Ok(py_positions.unbind())
+}
+
+/// Least balance at which a position isn't stuck; see calc_min_balance_to_avoid_unstuck.
+/// Shorts have negative position_size.
+#[pyfunction]
+pub fn calc_min_balance_to_avoid_unstuck_py(
+    position_size: f64,
+    position_price: f64,
+    wallet_exposure_limit: f64,
+    unstuck_threshold: f64,
+    c_mult: f64,
+) -> f64 {
+    let bot_params = BotParams {
+        wallet_exposure_limit,
+        unstuck_threshold,
+        ..Default::default()
+    };
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    calc_min_balance_to_avoid_unstuck(&position, &bot_params, c_mult)
+}
+
+/// "Close now" P/L of a long position: net PnL of market closing it all at book_bid.
+#[pyfunction]
+pub fn calc_immediate_full_close_pnl_long_py(
+    position_size: f64,
+    position_price: f64,
+    book_bid: f64,
+    c_mult: f64,
+    taker_fee: f64,
+) -> f64 {
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    calc_immediate_full_close_pnl_long(&position, book_bid, c_mult, taker_fee)
+}
+
+/// "Close now" P/L of a short position (negative position_size), closing at book_ask.
+#[pyfunction]
+pub fn calc_immediate_full_close_pnl_short_py(
+    position_size: f64,
+    position_price: f64,
+    book_ask: f64,
+    c_mult: f64,
+    taker_fee: f64,
+) -> f64 {
+    let position = Position {
+        size: position_size,
+        price: position_price,
+        ..Default::default()
+    };
+    calc_immediate_full_close_pnl_short(&position, book_ask, c_mult, taker_fee)
+}
+
+/// EMA bands over a series of closes, seeded with the first close as in the backtest.
+/// Returns (upper, lower), or with detailed=True a dict that adds the per-span emas and spans.
+#[pyfunction]
+#[pyo3(signature = (closes, ema_span_0, ema_span_1, detailed=false))]
+pub fn calc_ema_bands_py(
+    py: Python,
+    closes: Vec<f64>,
+    ema_span_0: f64,
+    ema_span_1: f64,
+    detailed: bool,
+) -> PyResult<PyObject> {
+    let first_close = *closes
+        .first()
+        .ok_or_else(|| PyValueError::new_err("closes is empty"))?;
+    let spans = calc_ema_spans(ema_span_0, ema_span_1);
+    let alphas = spans.map(|x| 2.0 / (x + 1.0));
+    let mut emas = [first_close; 3];
+    for close in &closes[1..] {
+        for z in 0..3 {
+            emas[z] = close * alphas[z] + emas[z] * (1.0 - alphas[z]);
+        }
+    }
+    let bands = EMABandsDetailed { spans, emas };
+    if !detailed {
+        return Ok((bands.upper(), bands.lower()).into_py(py));
+    }
+    let dict = PyDict::new_bound(py);
+    dict.set_item("upper", bands.upper())?;
+    dict.set_item("lower", bands.lower())?;
+    dict.set_item("emas", bands.emas.to_vec())?;
+    dict.set_item("spans", bands.spans.to_vec())?;
+    Ok(dict.into())
+}
+
+/// Returns (mid, spread_pct, depth within depth_pct of mid) for an order book given as
+/// (bid, ask) or as a dict with depth levels.
+#[pyfunction]
+#[pyo3(signature = (order_book, depth_pct=0.0))]
+pub fn calc_order_book_stats_py(
+    order_book: &Bound<'_, PyAny>,
+    depth_pct: f64,
+) -> PyResult<(f64, f64, f64)> {
+    let order_book = order_book_from_py(order_book)?;
+    Ok((
+        order_book.mid(),
+        order_book.spread_pct(),
+        order_book.depth_within_pct(depth_pct),
+    ))
+}
+
+fn pruner_from_optimize_dict(
+    py: Python,
+    optimize_dict: &Bound<'_, PyDict>,
+) -> PyResult<Option<Pruner>> {
+    // optional; no pruning when absent
+    match extract_value::<Bound<PyAny>>(optimize_dict, "prune") {
+        Ok(prune) => Pruner::from_config(&py_to_json_value(py, &prune)?)
+            .map(Some)
+            .map_err(PyValueError::new_err),
+        Err(_) => Ok(None),
+    }
+}
+
+const DEFAULT_LOG_FLUSH_INTERVAL_SECS: f64 = 5.0;
+
+fn run_logger_from_optimize_dict(optimize_dict: &Bound<'_, PyDict>) -> PyResult<Option<RunLogger>> {
+    // optional; no run log when absent
+    let path = match extract_value::<String>(optimize_dict, "log_path") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return Ok(None),
+    };
+    let best_path = extract_value::<String>(optimize_dict, "best_log_path")
+        .ok()
+        .map(PathBuf::from);
+    let flush_interval = extract_value(optimize_dict, "log_flush_interval")
+        .unwrap_or(DEFAULT_LOG_FLUSH_INTERVAL_SECS);
+    let flush_interval = Duration::try_from_secs_f64(flush_interval)
+        .map_err(|_| PyValueError::new_err("log_flush_interval must be seconds >= 0"))?;
+    RunLogger::open(&path, best_path.as_deref(), flush_interval)
+        .map(Some)
+        .map_err(PyValueError::new_err)
+}
+
+fn flush_run_log(log: &Option<RunLogger>) -> PyResult<()> {
+    match log {
+        Some(log) => log.flush().map_err(PyValueError::new_err),
+        None => Ok(()),
+    }
+}
+
+fn param_bounds_from_optimize_dict(
+    py: Python,
+    optimize_dict: &Bound<'_, PyDict>,
+) -> PyResult<ParamBounds> {
+    let bounds = py_to_json_value(py, &extract_value::<Bound<PyAny>>(optimize_dict, "bounds")?)?;
+    // optional; fields held fixed by dotted path
+    let frozen = match extract_value::<Bound<PyAny>>(optimize_dict, "frozen") {
+        Ok(frozen) => Some(py_to_json_value(py, &frozen)?),
+        Err(_) => None,
+    };
+    ParamBounds::from_config(&bounds, frozen.as_ref()).map_err(PyValueError::new_err)
+}
+
+fn scoring_from_optimize_dict(
+    py: Python,
+    optimize_dict: &Bound<'_, PyDict>,
+) -> PyResult<ScoringConfig> {
+    let scoring = py_to_json_value(
+        py,
+        &extract_value::<Bound<PyAny>>(optimize_dict, "scoring")?,
+    )?;
+    ScoringConfig::from_config(&scoring).map_err(PyValueError::new_err)
+}
+
+/// seed is the run's master seed, see OptimizerDataset::seed.
+fn pso_params_from_optimize_dict(optimize_dict: &Bound<'_, PyDict>, seed: u64) -> PsoParams {
+    let defaults = PsoParams::default();
+    PsoParams {
+        swarm_size: extract_value(optimize_dict, "swarm_size").unwrap_or(defaults.swarm_size),
+        n_iterations: extract_value(optimize_dict, "n_iterations").unwrap_or(defaults.n_iterations),
+        inertia: extract_value(optimize_dict, "inertia").unwrap_or(defaults.inertia),
+        cognitive: extract_value(optimize_dict, "cognitive").unwrap_or(defaults.cognitive),
+        social: extract_value(optimize_dict, "social").unwrap_or(defaults.social),
+        seed,
+        n_threads: extract_value(optimize_dict, "n_cpus").unwrap_or(defaults.n_threads),
+    }
+}
+
+/// Bounds and frozen fields of an optimization (see ParamBounds::from_config), for sampling,
+/// clipping and checking configs from Python.
+#[pyclass(name = "ParamBounds")]
+pub struct ParamBoundsPy {
+    bounds: ParamBounds,
+    rng: Rng,
+}
+
+#[pymethods]
+impl ParamBoundsPy {
+    #[new]
+    #[pyo3(signature = (bounds, frozen=None, seed=0))]
+    pub fn new(
+        py: Python,
+        bounds: &Bound<'_, PyDict>,
+        frozen: Option<&Bound<'_, PyDict>>,
+        seed: u64,
+    ) -> PyResult<Self> {
+        let frozen = frozen
+            .map(|frozen| py_to_json_value(py, frozen))
+            .transpose()?;
+        Ok(ParamBoundsPy {
+            bounds: ParamBounds::from_config(&py_to_json_value(py, bounds)?, frozen.as_ref())
+                .map_err(PyValueError::new_err)?,
+            rng: Rng::new(seed),
+        })
+    }
+
+    /// Dotted paths of the searched parameters, in position order.
+    pub fn paths(&self) -> Vec<String> {
+        self.bounds
+            .searched()
+            .iter()
+            .map(|bound| bound.path.clone())
+            .collect()
+    }
+
+    /// bot_params_pair_dict with frozen fields set and searched fields sampled uniformly, or
+    /// log-uniformly for log scale bounds.
+    pub fn sample<'py>(
+        &mut self,
+        py: Python<'py>,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let base = bot_params_pair_from_dict(bot_params_pair_dict)?;
+        struct_to_py_dict(py, &self.bounds.sample(&base, &mut self.rng))
+    }
+
+    /// bot_params_pair_dict with searched fields clipped into bounds and frozen fields set.
+    pub fn clip<'py>(
+        &self,
+        py: Python<'py>,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let bot_params_pair = bot_params_pair_from_dict(bot_params_pair_dict)?;
+        struct_to_py_dict(py, &self.bounds.clip(&bot_params_pair))
+    }
+
+    /// Raises ValueError if a searched field is out of bounds or a frozen field differs.
+    pub fn validate(&self, bot_params_pair_dict: &Bound<'_, PyDict>) -> PyResult<()> {
+        let bot_params_pair = bot_params_pair_from_dict(bot_params_pair_dict)?;
+        self.bounds
+            .validate(&bot_params_pair)
+            .map_err(PyValueError::new_err)
+    }
+}
+
+/// Crossover, mutation and repair of configs (see GeneticOperators), for evolutionary loops
+/// scripted in Python. constraints are (greater, lesser) dotted path pairs of searched
+/// bounds, e.g. ("long.close_trailing_threshold_pct", "long.close_trailing_retracement_pct").
+/// Children take their unsearched fields from the first parent.
+#[pyclass(name = "GeneticOperators")]
+pub struct GeneticOperatorsPy {
+    operators: GeneticOperators,
+    rng: Rng,
+}
+
+impl GeneticOperatorsPy {
+    fn position(
+        &self,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<(BotParamsPair, Vec<f64>)> {
+        let bot_params_pair = bot_params_pair_from_dict(bot_params_pair_dict)?;
+        let position = self.operators.bounds().values(&bot_params_pair);
+        Ok((bot_params_pair, position))
+    }
+
+    fn to_py_dict<'py>(
+        &self,
+        py: Python<'py>,
+        base: &BotParamsPair,
+        position: &[f64],
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let bot_params_pair = self
+            .operators
+            .bounds()
+            .apply(base, position)
+            .map_err(PyValueError::new_err)?;
+        struct_to_py_dict(py, &bot_params_pair)
+    }
+
+    fn crossover<'py, C>(
+        &mut self,
+        py: Python<'py>,
+        a_dict: &Bound<'_, PyDict>,
+        b_dict: &Bound<'_, PyDict>,
+        crossover: C,
+    ) -> PyResult<(Bound<'py, PyDict>, Bound<'py, PyDict>)>
+    where
+        C: FnOnce(
+            &GeneticOperators,
+            &mut Rng,
+            &[f64],
+            &[f64],
+        ) -> Result<(Vec<f64>, Vec<f64>), String>,
+    {
+        let (a, a_position) = self.position(a_dict)?;
+        let (_, b_position) = self.position(b_dict)?;
+        let (child_a, child_b) =
+            crossover(&self.operators, &mut self.rng, &a_position, &b_position)
+                .map_err(PyValueError::new_err)?;
+        Ok((
+            self.to_py_dict(py, &a, &child_a)?,
+            self.to_py_dict(py, &a, &child_b)?,
+        ))
+    }
+}
+
+#[pymethods]
+impl GeneticOperatorsPy {
+    #[new]
+    #[pyo3(signature = (bounds, frozen=None, constraints=vec![], seed=0))]
+    pub fn new(
+        py: Python,
+        bounds: &Bound<'_, PyDict>,
+        frozen: Option<&Bound<'_, PyDict>>,
+        constraints: Vec<(String, String)>,
+        seed: u64,
+    ) -> PyResult<Self> {
+        let frozen = frozen
+            .map(|frozen| py_to_json_value(py, frozen))
+            .transpose()?;
+        let bounds = ParamBounds::from_config(&py_to_json_value(py, bounds)?, frozen.as_ref())
+            .map_err(PyValueError::new_err)?;
+        let constraints = constraints
+            .into_iter()
+            .map(|(greater, lesser)| ParamConstraint { greater, lesser })
+            .collect();
+        Ok(GeneticOperatorsPy {
+            operators: GeneticOperators::new(bounds, constraints).map_err(PyValueError::new_err)?,
+            rng: Rng::new(seed),
+        })
+    }
+
+    pub fn uniform_crossover<'py>(
+        &mut self,
+        py: Python<'py>,
+        a_dict: &Bound<'_, PyDict>,
+        b_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<(Bound<'py, PyDict>, Bound<'py, PyDict>)> {
+        self.crossover(py, a_dict, b_dict, |operators, rng, a, b| {
+            operators.uniform_crossover(rng, a, b)
+        })
+    }
+
+    #[pyo3(signature = (a_dict, b_dict, alpha=0.5))]
+    pub fn blend_crossover<'py>(
+        &mut self,
+        py: Python<'py>,
+        a_dict: &Bound<'_, PyDict>,
+        b_dict: &Bound<'_, PyDict>,
+        alpha: f64,
+    ) -> PyResult<(Bound<'py, PyDict>, Bound<'py, PyDict>)> {
+        self.crossover(py, a_dict, b_dict, |operators, rng, a, b| {
+            operators.blend_crossover(rng, a, b, alpha)
+        })
+    }
+
+    /// mutation_prob 0.0 mutates one parameter on average.
+    #[pyo3(signature = (bot_params_pair_dict, sigma=0.1, mutation_prob=0.0))]
+    pub fn gaussian_mutation<'py>(
+        &mut self,
+        py: Python<'py>,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        sigma: f64,
+        mutation_prob: f64,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let (base, position) = self.position(bot_params_pair_dict)?;
+        let mutated = self
+            .operators
+            .gaussian_mutation(&mut self.rng, &position, sigma, mutation_prob)
+            .map_err(PyValueError::new_err)?;
+        self.to_py_dict(py, &base, &mutated)
+    }
+
+    /// mutation_prob 0.0 mutates one parameter on average.
+    #[pyo3(signature = (bot_params_pair_dict, eta=20.0, mutation_prob=0.0))]
+    pub fn polynomial_mutation<'py>(
+        &mut self,
+        py: Python<'py>,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        eta: f64,
+        mutation_prob: f64,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let (base, position) = self.position(bot_params_pair_dict)?;
+        let mutated = self
+            .operators
+            .polynomial_mutation(&mut self.rng, &position, eta, mutation_prob)
+            .map_err(PyValueError::new_err)?;
+        self.to_py_dict(py, &base, &mutated)
+    }
+
+    /// bot_params_pair_dict clipped into bounds, frozen fields set and constraints restored.
+    pub fn repair<'py>(
+        &self,
+        py: Python<'py>,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let (base, position) = self.position(bot_params_pair_dict)?;
+        let repaired = self
+            .operators
+            .repair(&position)
+            .map_err(PyValueError::new_err)?;
+        self.to_py_dict(py, &base, &repaired)
+    }
+
+    /// Raises ValueError if bot_params_pair_dict is out of bounds, off a frozen value or
+    /// breaks a constraint.
+    pub fn validate(&self, bot_params_pair_dict: &Bound<'_, PyDict>) -> PyResult<()> {
+        let bot_params_pair = bot_params_pair_from_dict(bot_params_pair_dict)?;
+        self.operators
+            .validate(&bot_params_pair)
+            .map_err(PyValueError::new_err)
+    }
+}
+
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+fn cache_stats_dict<'py, S: Clone>(
+    py: Python<'py>,
+    cache: &EvaluationCache<S>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let stats = PyDict::new_bound(py);
+    stats.set_item("entries", cache.len())?;
+    stats.set_item("hits", cache.hits())?;
+    stats.set_item("misses", cache.misses())?;
+    stats.set_item("hit_rate", cache.hit_rate())?;
+    Ok(stats)
+}
+
+/// Evaluation cache for optimizers driven from Python: stats of any kind keyed by config.
+/// fingerprint tells datasets apart; see calc_dataset_fingerprint_py.
+#[pyclass(name = "EvaluationCache")]
+pub struct EvaluationCachePy {
+    cache: EvaluationCache<PyObject>,
+    fingerprint: u64,
+}
+
+#[pymethods]
+impl EvaluationCachePy {
+    #[new]
+    #[pyo3(signature = (max_entries=DEFAULT_CACHE_SIZE, fingerprint=0))]
+    pub fn new(max_entries: usize, fingerprint: u64) -> Self {
+        EvaluationCachePy {
+            cache: EvaluationCache::new(max_entries),
+            fingerprint,
+        }
+    }
+
+    pub fn key(&self, bot_params_pair_dict: &Bound<'_, PyDict>) -> PyResult<u64> {
+        let bot_params_pair = bot_params_pair_from_dict(bot_params_pair_dict)?;
+        Ok(EvaluationCache::<PyObject>::calc_key(
+            &bot_params_pair,
+            self.fingerprint,
+        ))
+    }
+
+    /// Stats inserted for the config, or None; counts a hit or a miss.
+    pub fn lookup(&self, bot_params_pair_dict: &Bound<'_, PyDict>) -> PyResult<Option<PyObject>> {
+        Ok(self.cache.get(self.key(bot_params_pair_dict)?))
+    }
+
+    pub fn insert(
+        &self,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        stats: PyObject,
+    ) -> PyResult<()> {
+        self.cache.insert(self.key(bot_params_pair_dict)?, stats);
+        Ok(())
+    }
+
+    /// {"entries", "hits", "misses", "hit_rate"}.
+    pub fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        cache_stats_dict(py, &self.cache)
+    }
+}
+
+/// Fingerprint of a shared-memory dataset and its params, for EvaluationCache.
+#[pyfunction]
+pub fn calc_dataset_fingerprint_py(
+    shared_memory_file: &str,
+    hlcvs_shape: (usize, usize, usize),
+    hlcvs_dtype: &str,
+    btc_usd_shared_memory_file: &str,
+    btc_usd_dtype: &str,
+    exchange_params_list: &Bound<'_, PyAny>,
+    backtest_params_dict: &Bound<'_, PyDict>,
+) -> PyResult<u64> {
+    let hlcvs_mmap = map_shared_memory(shared_memory_file, "HLCV")?;
+    let btc_usd_mmap = map_shared_memory(btc_usd_shared_memory_file, "BTC/USD")?;
+    Ok(calc_dataset_fingerprint(
+        &hlcvs_view(&hlcvs_mmap, hlcvs_shape, hlcvs_dtype)?,
+        &btc_usd_view(&btc_usd_mmap, hlcvs_shape.0, btc_usd_dtype)?,
+        &exchange_params_list_from_py(exchange_params_list)?,
+        &backtest_params_from_dict(backtest_params_dict)?,
+    ))
+}
+
+/// Returns (indices, values, bucket_bounds) of equities thinned to n_buckets buckets' min
+/// and max while keeping the worst drawdown intact; see backtest::downsample_equities.
+#[pyfunction]
+pub fn downsample_equities_py(
+    equities: Vec<f64>,
+    n_buckets: usize,
+) -> PyResult<(Vec<usize>, Vec<f64>, Vec<usize>)> {
+    let downsample = downsample_equities(&equities, n_buckets).map_err(PyValueError::new_err)?;
+    Ok((
+        downsample.indices,
+        downsample.values,
+        downsample.bucket_bounds,
+    ))
+}
+
+/// Indices of the non-dominated rows of objectives (minimized), ascending.
+#[pyfunction]
+pub fn pareto_front_py(objectives: Vec<Vec<f64>>) -> PyResult<Vec<usize>> {
+    pareto_front(&objectives).map_err(PyValueError::new_err)
+}
+
+/// Volume dominated by the rows of objectives (minimized) and bounded by reference.
+#[pyfunction]
+pub fn hypervolume_py(objectives: Vec<Vec<f64>>, reference: Vec<f64>) -> PyResult<f64> {
+    hypervolume(&objectives, &reference).map_err(PyValueError::new_err)
+}
+
+/// Indices of up to count front members nearest the knee, best first.
+#[pyfunction]
+#[pyo3(signature = (objectives, count=1))]
+pub fn knee_points_py(objectives: Vec<Vec<f64>>, count: usize) -> PyResult<Vec<usize>> {
+    knee_points(&objectives, count).map_err(PyValueError::new_err)
+}
+
+/// Seeded synthetic HLCVs of shape (n_candles, n_coins, 4) for tests and config fuzzing.
+/// spec follows SyntheticMarketSpec, e.g. {"seed": 1, "n_coins": 2, "correlation": 0.5,
+/// "regimes": [{"n_candles": 1440, "drift": 0.0001, "volatility": 0.002}], "gaps":
+/// [{"candle": 700, "pct": -0.1}], "listing_candles": [0, 300]}.
+#[pyfunction]
+pub fn generate_synthetic_hlcvs_py(
+    py: Python,
+    spec: &Bound<'_, PyDict>,
+) -> PyResult<Py<PyArray3<f64>>> {
+    let spec: SyntheticMarketSpec = serde_json::from_value(py_to_json_value(py, spec)?)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let hlcvs = generate_synthetic_hlcvs(&spec).map_err(PyValueError::new_err)?;
+    Ok(hlcvs.into_pyarray_bound(py).unbind())
+}
+
+/// Shared-memory HLCV data and base config backtested by the native optimizers.
+struct OptimizerDataset {
+    hlcvs_mmap: Mmap,
+    hlcvs_shape: (usize, usize, usize),
+    btc_usd_mmap: Mmap,
+    bot_params_pair: BotParamsPair, // base config; bounded parameters are overwritten
+    exchange_params: Vec<ExchangeParams>,
+    backtest_params: BacktestParams,
+    fingerprint: u64, // see calc_dataset_fingerprint
+}
+
+impl OptimizerDataset {
+    fn from_py(
+        shared_memory_file: &str,
+        hlcvs_shape: (usize, usize, usize),
+        hlcvs_dtype: &str,
+        btc_usd_shared_memory_file: &str,
+        btc_usd_dtype: &str,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        exchange_params_list: &Bound<'_, PyAny>,
+        backtest_params_dict: &Bound<'_, PyDict>,
+        seed: Option<u64>, // overrides backtest_params_dict's
+    ) -> PyResult<Self> {
+        let hlcvs_mmap = map_shared_memory(shared_memory_file, "HLCV")?;
+        hlcvs_view(&hlcvs_mmap, hlcvs_shape, hlcvs_dtype)?;
+        let btc_usd_mmap = map_shared_memory(btc_usd_shared_memory_file, "BTC/USD")?;
+        btc_usd_view(&btc_usd_mmap, hlcvs_shape.0, btc_usd_dtype)?;
+        let mut dataset = OptimizerDataset {
+            hlcvs_mmap,
+            hlcvs_shape,
+            btc_usd_mmap,
+            bot_params_pair: bot_params_pair_from_dict(bot_params_pair_dict)?,
+            exchange_params: exchange_params_list_from_py(exchange_params_list)?,
+            backtest_params: backtest_params_from_dict(backtest_params_dict)?,
+            fingerprint: 0,
+        };
+        if let Some(seed) = seed {
+            dataset.backtest_params.seed = seed;
+        }
+        let (hlcvs, btc_usd) = dataset.views();
+        let fingerprint = calc_dataset_fingerprint(
+            &hlcvs,
+            &btc_usd,
+            &dataset.exchange_params,
+            &dataset.backtest_params,
+        );
+        dataset.fingerprint = fingerprint;
+        Ok(dataset)
+    }
+
+    /// Master seed of the run: optimize_dict's "seed", else backtest_params_dict's, else 0.
+    /// Seeds the search as well as every backtest, so any candidate can be reproduced by
+    /// run_backtest with the same seed.
+    fn seed(&self) -> u64 {
+        self.backtest_params.seed
+    }
+
+    fn views(&self) -> (ArrayView3<'_, f64>, ArrayView1<'_, f64>) {
+        // dtypes were checked in from_py
+        (
+            hlcvs_view(&self.hlcvs_mmap, self.hlcvs_shape, "<f8").expect("checked in from_py"),
+            btc_usd_view(&self.btc_usd_mmap, self.hlcvs_shape.0, "<f8")
+                .expect("checked in from_py"),
+        )
+    }
+
+    /// Backtests the base config with the bounded parameters set to values, unless the
+    /// resulting config is cached; None if the values cannot be applied.
+    fn evaluate(
+        &self,
+        bounds: &ParamBounds,
+        values: &[f64],
+        prune_params: &PruneParams,
+        cache: &EvaluationCache<Evaluation>,
+    ) -> Option<Evaluation> {
+        let candidate = bounds.apply(&self.bot_params_pair, values).ok()?;
+        let key = EvaluationCache::<Evaluation>::calc_key(&candidate, self.fingerprint);
+        // whether a run is pruned depends on the thresholds of the moment; only complete runs keep
+        Some(cache.get_or_insert_with(
+            key,
+            || self.backtest(candidate, prune_params),
+            |evaluation| !evaluation.pruned,
+        ))
+    }
+
+    fn backtest(&self, candidate: BotParamsPair, prune_params: &PruneParams) -> Evaluation {
+        let (hlcvs, btc_usd) = self.views();
+        evaluate_backtest(
+            &hlcvs,
+            &btc_usd,
+            candidate,
+            self.exchange_params.clone(),
+            &self.backtest_params,
+            prune_params,
+        )
+        .0
+    }
+
+    fn bot_params_pair_dict<'py>(
+        &self,
+        py: Python<'py>,
+        bounds: &ParamBounds,
+        values: &[f64],
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let bot_params_pair = bounds
+            .apply(&self.bot_params_pair, values)
+            .map_err(PyValueError::new_err)?;
+        struct_to_py_dict(py, &bot_params_pair)
+    }
+}
+
+/// Particle swarm optimizer running backtests natively over the shared HLCV dataset.
+/// `start` maps the data once; `step` advances the swarm, releasing the GIL while the
+/// particles are backtested; `inspect` reports progress and the best configs so far.
+#[pyclass]
+pub struct ParticleSwarmOptimizer {
+    dataset: OptimizerDataset,
+    scoring: ScoringConfig,
+    pruner: Option<Pruner>,
+    cache: EvaluationCache<Evaluation>,
+    swarm: ParticleSwarm<(Analysis, Analysis)>,
+    log: Option<RunLogger>,
+}
+
+#[pymethods]
+impl ParticleSwarmOptimizer {
+    /// optimize_dict holds "bounds" ({"long_ema_span_0": [200, 1440], ..}), "scoring"
+    /// (see ScoringConfig; minimized) and optionally "frozen" (see ParamBounds), "prune" (see
+    /// Pruner), cache_size (see EvaluationCache), swarm_size, n_iterations, inertia,
+    /// cognitive, social, seed (see OptimizerDataset::seed), n_cpus and "log_path" (see
+    /// RunLogger; appended to) with "best_log_path" and log_flush_interval (seconds).
+    #[staticmethod]
+    pub fn start(
+        py: Python,
+        shared_memory_file: &str,
+        hlcvs_shape: (usize, usize, usize),
+        hlcvs_dtype: &str,
+        btc_usd_shared_memory_file: &str,
+        btc_usd_dtype: &str,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        exchange_params_list: &Bound<'_, PyAny>,
+        backtest_params_dict: &Bound<'_, PyDict>,
+        optimize_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<Self> {
+        let dataset = OptimizerDataset::from_py(
+            shared_memory_file,
+            hlcvs_shape,
+            hlcvs_dtype,
+            btc_usd_shared_memory_file,
+            btc_usd_dtype,
+            bot_params_pair_dict,
+            exchange_params_list,
+            backtest_params_dict,
+            extract_value(optimize_dict, "seed").ok(),
+        )?;
+        let bounds = param_bounds_from_optimize_dict(py, optimize_dict)?;
+        let pso_params = pso_params_from_optimize_dict(optimize_dict, dataset.seed());
+        Ok(ParticleSwarmOptimizer {
+            dataset,
+            scoring: scoring_from_optimize_dict(py, optimize_dict)?,
+            pruner: pruner_from_optimize_dict(py, optimize_dict)?,
+            cache: EvaluationCache::new(
+                extract_value(optimize_dict, "cache_size").unwrap_or(DEFAULT_CACHE_SIZE),
+            ),
+            swarm: ParticleSwarm::new(bounds, pso_params).map_err(PyValueError::new_err)?,
+            log: run_logger_from_optimize_dict(optimize_dict)?,
+        })
+    }
+
+    /// Runs up to n_iterations more iterations; returns the best fitness so far.
+    #[pyo3(signature = (n_iterations=1))]
+    pub fn step(&mut self, py: Python, n_iterations: usize) -> PyResult<Option<f64>> {
+        let ParticleSwarmOptimizer {
+            dataset,
+            scoring,
+            pruner,
+            cache,
+            swarm,
+            log,
+        } = self;
+        py.allow_threads(|| {
+            let bounds = swarm.bounds().clone();
+            for _ in 0..n_iterations {
+                let iteration = swarm.iteration();
+                let evaluate = |values: &[f64], prune_params: &PruneParams| {
+                    let start = Instant::now();
+                    let evaluation = dataset.evaluate(&bounds, values, prune_params, cache)?;
+                    if let Some(log) = log.as_ref() {
+                        let (analysis_usd, analysis_btc) = &evaluation.analyses;
+                        let score = if evaluation.pruned {
+                            Score::pruned()
+                        } else {
+                            scoring.score(analysis_usd, analysis_btc)
+                        };
+                        // evaluate applied the same values
+                        let bot = bounds.apply(&dataset.bot_params_pair, values).unwrap();
+                        log.log(&RunLogRecord::new(
+                            "pso",
+                            iteration,
+                            bot,
+                            &evaluation,
+                            json!(score.fitness),
+                            start.elapsed(),
+                        ));
+                    }
+                    Some(evaluation)
+                };
+                if !step_scored_swarm(swarm, scoring, pruner, &evaluate) {
+                    break;
+                }
+            }
+            flush_run_log(log)?;
+            Ok(swarm.best().map(|best| best.fitness))
+        })
+    }
+
+    /// {"iteration", "done", "best_fitness", "best": [{"bot", "fitness", "fitness_breakdown",
+    /// "analysis_usd", "analysis_btc"}, ..], "cache": {"entries", "hits", "misses",
+    /// "hit_rate"}} with at most n_best distinct particle bests, best first.
+    #[pyo3(signature = (n_best=1))]
+    pub fn inspect<'py>(&self, py: Python<'py>, n_best: usize) -> PyResult<Bound<'py, PyDict>> {
+        let best = PyList::empty_bound(py);
+        for candidate in self.swarm.best_n(n_best) {
+            let entry = PyDict::new_bound(py);
+            entry.set_item(
+                "bot",
+                self.dataset
+                    .bot_params_pair_dict(py, self.swarm.bounds(), &candidate.position)?,
+            )?;
+            entry.set_item("fitness", candidate.fitness)?;
+            entry.set_item(
+                "fitness_breakdown",
+                self.scoring
+                    .score(&candidate.stats.0, &candidate.stats.1)
+                    .contributions,
+            )?;
+            entry.set_item("analysis_usd", struct_to_py_dict(py, &candidate.stats.0)?)?;
+            entry.set_item("analysis_btc", struct_to_py_dict(py, &candidate.stats.1)?)?;
+            best.append(entry)?;
+        }
+        let report = PyDict::new_bound(py);
+        report.set_item("iteration", self.swarm.iteration())?;
+        report.set_item("done", self.swarm.is_done())?;
+        report.set_item("best_fitness", self.swarm.best().map(|best| best.fitness))?;
+        report.set_item("best", best)?;
+        report.set_item("cache", cache_stats_dict(py, &self.cache)?)?;
+        Ok(report)
+    }
+}
+
+/// NSGA-II over the shared HLCV dataset, trading objectives off against each other instead
+/// of weighting them into one fitness. Used like ParticleSwarmOptimizer; `inspect` returns
+/// the population with objective values and dominance ranks.
+#[pyclass]
+pub struct Nsga2Optimizer {
+    dataset: OptimizerDataset,
+    objectives: Vec<(String, f64)>,
+    pruner: Option<Pruner>,
+    cache: EvaluationCache<Evaluation>,
+    nsga2: Nsga2<(Analysis, Analysis)>,
+    log: Option<RunLogger>,
+}
+
+#[pymethods]
+impl Nsga2Optimizer {
+    /// optimize_dict holds "bounds", "frozen", "prune", cache_size and seed as for
+    /// ParticleSwarmOptimizer, "objectives" ({metric: "minimize" | "maximize"}) and optionally
+    /// population_size, n_generations, crossover_prob, crossover_eta, mutation_prob,
+    /// mutation_eta, n_cpus and run log settings. Pruned backtests get infinite objectives. Logged records
+    /// hold the objective values as fitness, so the best log stays empty.
+    #[staticmethod]
+    pub fn start(
+        py: Python,
+        shared_memory_file: &str,
+        hlcvs_shape: (usize, usize, usize),
+        hlcvs_dtype: &str,
+        btc_usd_shared_memory_file: &str,
+        btc_usd_dtype: &str,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        exchange_params_list: &Bound<'_, PyAny>,
+        backtest_params_dict: &Bound<'_, PyDict>,
+        optimize_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<Self> {
+        let dataset = OptimizerDataset::from_py(
+            shared_memory_file,
+            hlcvs_shape,
+            hlcvs_dtype,
+            btc_usd_shared_memory_file,
+            btc_usd_dtype,
+            bot_params_pair_dict,
+            exchange_params_list,
+            backtest_params_dict,
+            extract_value(optimize_dict, "seed").ok(),
+        )?;
+        let bounds = param_bounds_from_optimize_dict(py, optimize_dict)?;
+        let objectives = objectives_from_config(&py_to_json_value(
+            py,
+            &extract_value::<Bound<PyAny>>(optimize_dict, "objectives")?,
+        )?)
+        .map_err(PyValueError::new_err)?;
+        let defaults = Nsga2Params::default();
+        let nsga2_params = Nsga2Params {
+            population_size: extract_value(optimize_dict, "population_size")
+                .unwrap_or(defaults.population_size),
+            n_generations: extract_value(optimize_dict, "n_generations")
+                .unwrap_or(defaults.n_generations),
+            crossover_prob: extract_value(optimize_dict, "crossover_prob")
+                .unwrap_or(defaults.crossover_prob),
+            crossover_eta: extract_value(optimize_dict, "crossover_eta")
+                .unwrap_or(defaults.crossover_eta),
+            mutation_prob: extract_value(optimize_dict, "mutation_prob")
+                .unwrap_or(defaults.mutation_prob),
+            mutation_eta: extract_value(optimize_dict, "mutation_eta")
+                .unwrap_or(defaults.mutation_eta),
+            seed: dataset.seed(),
+            n_threads: extract_value(optimize_dict, "n_cpus").unwrap_or(defaults.n_threads),
+        };
+        Ok(Nsga2Optimizer {
+            dataset,
+            objectives,
+            pruner: pruner_from_optimize_dict(py, optimize_dict)?,
+            cache: EvaluationCache::new(
+                extract_value(optimize_dict, "cache_size").unwrap_or(DEFAULT_CACHE_SIZE),
+            ),
+            nsga2: Nsga2::new(bounds, nsga2_params).map_err(PyValueError::new_err)?,
+            log: run_logger_from_optimize_dict(optimize_dict)?,
+        })
+    }
+
+    /// Runs up to n_generations more generations; returns the size of the Pareto front.
+    #[pyo3(signature = (n_generations=1))]
+    pub fn step(&mut self, py: Python, n_generations: usize) -> PyResult<usize> {
+        let Nsga2Optimizer {
+            dataset,
+            objectives,
+            pruner,
+            cache,
+            nsga2,
+            log,
+        } = self;
+        py.allow_threads(|| {
+            let bounds = nsga2.bounds().clone();
+            for _ in 0..n_generations {
+                let prune_params = pruner
+                    .as_ref()
+                    .map(Pruner::prune_params)
+                    .unwrap_or_default();
+                let partial_fitnesses = Mutex::new(Vec::new());
+                let generation = nsga2.generation();
+                let fitness = |values: &[f64]| {
+                    let start = Instant::now();
+                    let evaluation = match dataset.evaluate(&bounds, values, &prune_params, cache) {
+                        Some(evaluation) => evaluation,
+                        None => return (vec![f64::INFINITY; objectives.len()], Default::default()),
+                    };
+                    let (analysis_usd, analysis_btc) = &evaluation.analyses;
+                    let objective_values = if evaluation.pruned {
+                        vec![Score::pruned().fitness; objectives.len()]
+                    } else {
+                        calc_objective_values(objectives, analysis_usd, analysis_btc)
+                    };
+                    if let Some(log) = log.as_ref() {
+                        // evaluate applied the same values
+                        let bot = bounds.apply(&dataset.bot_params_pair, values).unwrap();
+                        log.log(&RunLogRecord::new(
+                            "nsga2",
+                            generation,
+                            bot,
+                            &evaluation,
+                            json!(objective_values),
+                            start.elapsed(),
+                        ));
+                    }
+                    partial_fitnesses
+                        .lock()
+                        .unwrap()
+                        .push(evaluation.partial_fitnesses);
+                    (objective_values, evaluation.analyses)
+                };
+                let stepped = nsga2.step(&fitness);
+                if let Some(pruner) = pruner.as_mut() {
+                    pruner.update(&partial_fitnesses.into_inner().unwrap());
+                }
+                if !stepped {
+                    break;
+                }
+            }
+            flush_run_log(log)?;
+            Ok(nsga2.pareto_front().len())
+        })
+    }
+
+    /// {"generation", "done", "objectives": [metric, ..], "population": [{"bot",
+    /// "objectives", "rank", "crowding_distance", "analysis_usd", "analysis_btc"}, ..],
+    /// "cache"} with "cache" as for ParticleSwarmOptimizer.
+    /// Objective values are as minimized, i.e. negated for maximized metrics; the
+    /// population is ordered by rank, then crowding distance, and rank 0 is the Pareto front.
+    #[pyo3(signature = (pareto_front_only=false))]
+    pub fn inspect<'py>(
+        &self,
+        py: Python<'py>,
+        pareto_front_only: bool,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let population = PyList::empty_bound(py);
+        for individual in self.nsga2.population() {
+            if pareto_front_only && individual.rank > 0 {
+                break;
+            }
+            let entry = PyDict::new_bound(py);
+            entry.set_item(
+                "bot",
+                self.dataset
+                    .bot_params_pair_dict(py, self.nsga2.bounds(), &individual.position)?,
+            )?;
+            entry.set_item("objectives", individual.objectives.clone())?;
+            entry.set_item("rank", individual.rank)?;
+            entry.set_item("crowding_distance", individual.crowding_distance)?;
+            entry.set_item("analysis_usd", struct_to_py_dict(py, &individual.stats.0)?)?;
+            entry.set_item("analysis_btc", struct_to_py_dict(py, &individual.stats.1)?)?;
+            population.append(entry)?;
+        }
+        let report = PyDict::new_bound(py);
+        report.set_item("generation", self.nsga2.generation())?;
+        report.set_item("done", self.nsga2.is_done())?;
+        report.set_item(
+            "objectives",
+            self.objectives
+                .iter()
+                .map(|(metric, _)| metric.clone())
+                .collect::<Vec<_>>(),
+        )?;
+        report.set_item("population", population)?;
+        report.set_item("cache", cache_stats_dict(py, &self.cache)?)?;
+        Ok(report)
+    }
+}
+
+/// Results row of a grid combination: {"index", "params", "bot", "analysis_usd",
+/// "analysis_btc"} plus "fitness" and "fitness_breakdown" when scored, or {"index",
+/// "params", "error"} if the combination could not be applied.
+fn grid_row_to_json(
+    row: GridRow<Result<(BotParamsPair, Evaluation), String>>,
+    scoring: Option<&ScoringConfig>,
+) -> Value {
+    let mut json = json!({"index": row.index, "params": params_to_json(&row.params)});
+    match row.stats {
+        Ok((bot_params_pair, evaluation)) => {
+            let (analysis_usd, analysis_btc) = &evaluation.analyses;
+            json["bot"] = json!(bot_params_pair);
+            json["analysis_usd"] = json!(analysis_usd);
+            json["analysis_btc"] = json!(analysis_btc);
+            if let Some(scoring) = scoring {
+                let score = scoring.score(analysis_usd, analysis_btc);
+                json["fitness"] = json!(score.fitness);
+                json["fitness_breakdown"] = json!(score.contributions);
+            }
+        }
+        Err(error) => json["error"] = json!(error),
+    }
+    json
+}
+
+// rows without a finite fitness never become best
+fn update_best_row(best: &mut Option<(f64, Value)>, row: &Value) {
+    let fitness = match row.get("fitness").and_then(Value::as_f64) {
+        Some(fitness) => fitness,
+        None => return,
+    };
+    if best
+        .as_ref()
+        .is_none_or(|(best_fitness, _)| fitness < *best_fitness)
+    {
+        *best = Some((fitness, row.clone()));
+    }
+}
+
+/// Exhaustive grid search over the shared HLCV dataset (see GridSearch), for sweeps small
+/// enough to try every combination. `step` returns the rows it evaluated and appends them to
+/// results_path as it goes, so an interrupted search keeps its rows and can resume from them.
+#[pyclass]
+pub struct GridSearchOptimizer {
+    dataset: OptimizerDataset,
+    scoring: Option<ScoringConfig>,
+    search: GridSearch,
+    batch_size: usize,
+    results_path: Option<PathBuf>,
+    best: Option<(f64, Value)>, // lowest fitness row so far, resumed rows included
+    log: Option<RunLogger>,
+}
+
+#[pymethods]
+impl GridSearchOptimizer {
+    /// optimize_dict holds "grid" (see ParamGrid) and optionally "scoring" (see ScoringConfig;
+    /// rows are scored if set), "results_path" (JSONL, a row per line), "resume" (skip the
+    /// combinations already in results_path instead of overwriting it), batch_size (0 == one
+    /// combination per cpu), n_cpus and run log settings as for ParticleSwarmOptimizer.
+    #[staticmethod]
+    pub fn start(
+        py: Python,
+        shared_memory_file: &str,
+        hlcvs_shape: (usize, usize, usize),
+        hlcvs_dtype: &str,
+        btc_usd_shared_memory_file: &str,
+        btc_usd_dtype: &str,
+        bot_params_pair_dict: &Bound<'_, PyDict>,
+        exchange_params_list: &Bound<'_, PyAny>,
+        backtest_params_dict: &Bound<'_, PyDict>,
+        optimize_dict: &Bound<'_, PyDict>,
+    ) -> PyResult<Self> {
+        let dataset = OptimizerDataset::from_py(
+            shared_memory_file,
+            hlcvs_shape,
+            hlcvs_dtype,
+            btc_usd_shared_memory_file,
+            btc_usd_dtype,
+            bot_params_pair_dict,
+            exchange_params_list,
+            backtest_params_dict,
+            extract_value(optimize_dict, "seed").ok(),
+        )?;
+        let grid = ParamGrid::from_config(&py_to_json_value(
+            py,
+            &extract_value::<Bound<PyAny>>(optimize_dict, "grid")?,
+        )?)
+        .map_err(PyValueError::new_err)?;
+        // optional; rows hold analyses only when absent
+        let scoring = match extract_value::<Bound<PyAny>>(optimize_dict, "scoring") {
+            Ok(_) => Some(scoring_from_optimize_dict(py, optimize_dict)?),
+            Err(_) => None,
+        };
+        let mut optimizer = GridSearchOptimizer {
+            dataset,
+            scoring,
+            search: GridSearch::new(grid, extract_value(optimize_dict, "n_cpus").unwrap_or(0)),
+            batch_size: extract_value(optimize_dict, "batch_size").unwrap_or(0),
+            results_path: extract_value::<String>(optimize_dict, "results_path")
+                .ok()
+                .map(PathBuf::from),
+            best: None,
+            log: run_logger_from_optimize_dict(optimize_dict)?,
+        };
+        let resume = extract_bool_value(optimize_dict, "resume").unwrap_or(false);
+        if let Some(path) = &optimizer.results_path {
+            if resume && path.exists() {
+                for row in read_jsonl(path).map_err(PyValueError::new_err)? {
+                    if let Some(Value::Object(params)) = row.get("params") {
+                        optimizer.search.mark_evaluated(params);
+                    }
+                    update_best_row(&mut optimizer.best, &row);
+                }
+            } else {
+                File::create(path).map_err(|e| {
+                    PyValueError::new_err(format!("unable to write {}: {}", path.display(), e))
+                })?;
+            }
+        }
+        Ok(optimizer)
+    }
+
+    /// Evaluates up to n_batches more batches, releasing the GIL; returns their rows (see
+    /// grid_row_to_json) in index order, none once the grid is done.
+    #[pyo3(signature = (n_batches=1))]
+    pub fn step<'py>(&mut self, py: Python<'py>, n_batches: usize) -> PyResult<Bound<'py, PyList>> {
+        let GridSearchOptimizer {
+            dataset,
+            scoring,
+            search,
+            batch_size,
+            results_path,
+            best,
+            log,
+        } = self;
+        let rows = py
+            .allow_threads(|| {
+                let evaluate = |params: &[(String, Value)]| {
+                    let start = Instant::now();
+                    let mut candidate = dataset.bot_params_pair.clone();
+                    candidate.merge_partial(params)?;
+                    let evaluation = dataset.backtest(candidate.clone(), &PruneParams::default());
+                    Ok((candidate, evaluation, start.elapsed()))
+                };
+                let mut rows = Vec::new();
+                for _ in 0..n_batches {
+                    if search.is_done() {
+                        break;
+                    }
+                    let batch: Vec<Value> = search
+                        .step(*batch_size, &evaluate)
+                        .into_iter()
+                        .map(|row| {
+                            let stats = row.stats.map(|(candidate, evaluation, elapsed)| {
+                                if let Some(log) = log.as_ref() {
+                                    let fitness = scoring.as_ref().map(|scoring| {
+                                        let (analysis_usd, analysis_btc) = &evaluation.analyses;
+                                        scoring.score(analysis_usd, analysis_btc).fitness
+                                    });
+                                    log.log(&RunLogRecord::new(
+                                        "grid",
+                                        row.index,
+                                        candidate.clone(),
+                                        &evaluation,
+                                        json!(fitness),
+                                        elapsed,
+                                    ));
+                                }
+                                (candidate, evaluation)
+                            });
+                            grid_row_to_json(
+                                GridRow {
+                                    index: row.index,
+                                    params: row.params,
+                                    stats,
+                                },
+                                scoring.as_ref(),
+                            )
+                        })
+                        .collect();
+                    if let Some(path) = results_path {
+                        append_jsonl(path, &batch)?;
+                    }
+                    rows.extend(batch);
+                }
+                if let Some(log) = log {
+                    log.flush()?;
+                }
+                Ok::<_, String>(rows)
+            })
+            .map_err(PyValueError::new_err)?;
+        let py_rows = PyList::empty_bound(py);
+        for row in &rows {
+            update_best_row(best, row);
+            py_rows.append(json_value_to_py(py, row)?)?;
+        }
+        Ok(py_rows)
+    }
+
+    /// {"n_combinations", "n_evaluated", "n_skipped", "done", "best"}, best being the
+    /// lowest-fitness row so far, or None without scoring.
+    pub fn inspect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let report = PyDict::new_bound(py);
+        report.set_item("n_combinations", self.search.grid().len())?;
+        report.set_item("n_evaluated", self.search.n_evaluated())?;
+        report.set_item("n_skipped", self.search.n_skipped())?;
+        report.set_item("done", self.search.is_done())?;
+        let best = match &self.best {
+            Some((_, row)) => json_value_to_py(py, row)?,
+            None => py.None(),
+        };
+        report.set_item("best", best)?;
+        Ok(report)
+    }
+}
+
+/// Walk-forward optimization over the shared HLCV dataset (see run_walk_forward): the
+/// particle swarm optimizes each window and its winner is backtested on the next.
+/// optimize_dict is ParticleSwarmOptimizer's plus n_windows. progress_callback, if set, gets a
+/// dict (see WalkForwardProgress) after every iteration; an exception raised by it stops the
+/// run. Returns {"steps": [{"window": {"train": [start, end], "test": [start, end]},
+/// "values", "bot", "in_sample", "out_of_sample"}, ..], "equities_usd", "equities_btc"}, the
+/// scores holding fitness, fitness_breakdown, analysis_usd and analysis_btc, and the equities
+/// the out-of-sample curves stitched end to end.
+#[pyfunction]
+#[pyo3(signature = (shared_memory_file, hlcvs_shape, hlcvs_dtype, btc_usd_shared_memory_file, btc_usd_dtype, bot_params_pair_dict, exchange_params_list, backtest_params_dict, optimize_dict, progress_callback=None))]
+pub fn run_walk_forward_py(
+    py: Python,
+    shared_memory_file: &str,
+    hlcvs_shape: (usize, usize, usize),
+    hlcvs_dtype: &str,
+    btc_usd_shared_memory_file: &str,
+    btc_usd_dtype: &str,
+    bot_params_pair_dict: &Bound<'_, PyDict>,
+    exchange_params_list: &Bound<'_, PyAny>,
+    backtest_params_dict: &Bound<'_, PyDict>,
+    optimize_dict: &Bound<'_, PyDict>,
+    progress_callback: Option<PyObject>,
+) -> PyResult<Py<PyDict>> {
+    let dataset = OptimizerDataset::from_py(
+        shared_memory_file,
+        hlcvs_shape,
+        hlcvs_dtype,
+        btc_usd_shared_memory_file,
+        btc_usd_dtype,
+        bot_params_pair_dict,
+        exchange_params_list,
+        backtest_params_dict,
+        extract_value(optimize_dict, "seed").ok(),
+    )?;
+    let config = WalkForwardConfig {
+        bounds: param_bounds_from_optimize_dict(py, optimize_dict)?,
+        scoring: scoring_from_optimize_dict(py, optimize_dict)?,
+        pruner: pruner_from_optimize_dict(py, optimize_dict)?,
+        pso_params: pso_params_from_optimize_dict(optimize_dict, dataset.seed()),
+        n_windows: extract_value(optimize_dict, "n_windows")?,
+        cache_size: extract_value(optimize_dict, "cache_size").unwrap_or(DEFAULT_CACHE_SIZE),
+    };
+    let mut callback_error = None;
+    let result = py.allow_threads(|| {
+        let (hlcvs, btc_usd) = dataset.views();
+        run_walk_forward(
+            &hlcvs,
+            &btc_usd,
+            &dataset.bot_params_pair,
+            &dataset.exchange_params,
+            &dataset.backtest_params,
+            &config,
+            |progress| {
+                let callback = match &progress_callback {
+                    Some(callback) => callback,
+                    None => return true,
+                };
+                Python::with_gil(|py| {
+                    let called = struct_to_py_dict(py, progress)
+                        .and_then(|progress| callback.call1(py, (progress,)));
+                    match called {
+                        Ok(_) => true,
+                        Err(error) => {
+                            callback_error = Some(error);
+                            false
+                        }
+                    }
+                })
+            },
+        )
+    });
+    if let Some(error) = callback_error {
+        return Err(error);
+    }
+    let report = result.map_err(PyValueError::new_err)?;
+    let steps = PyList::empty_bound(py);
+    for step in &report.steps {
+        steps.append(struct_to_py_dict(py, step)?)?;
+    }
+    let py_report = PyDict::new_bound(py);
+    py_report.set_item("steps", steps)?;
+    py_report.set_item(
+        "equities_usd",
+        Array1::from_vec(report.equities.usd)
+            .into_pyarray_bound(py)
+            .unbind(),
+    )?;
+    py_report.set_item(
+        "equities_btc",
+        Array1::from_vec(report.equities.btc)
+            .into_pyarray_bound(py)
+            .unbind(),
+    )?;
+    Ok(py_report.into())
+}
+
+/// Stress test over the shared HLCV dataset (see run_stress_test). scenarios is a list of
+/// {"name", "shocks": [..]}, each shock a dict with "kind" one of "price_shock" ({"candle",
+/// "pct"}), "volatility_scale" or "spread_widening" ({"start", "end", "factor"}), and an
+/// optional "coin" index, e.g. {"name": "crash", "shocks": [{"kind": "price_shock", "candle":
+/// 5000, "pct": -0.3}]}. Returns [{"name", "analysis_usd", "analysis_btc"}, ..], the unshocked
+/// "base" first.
+#[pyfunction]
+pub fn run_stress_test_py(
+    py: Python,
+    shared_memory_file: &str,
+    hlcvs_shape: (usize, usize, usize),
+    hlcvs_dtype: &str,
+    btc_usd_shared_memory_file: &str,
+    btc_usd_dtype: &str,
+    bot_params_pair_dict: &Bound<'_, PyDict>,
+    exchange_params_list: &Bound<'_, PyAny>,
+    backtest_params_dict: &Bound<'_, PyDict>,
+    scenarios: &Bound<'_, PyList>,
+) -> PyResult<Py<PyList>> {
+    let dataset = OptimizerDataset::from_py(
+        shared_memory_file,
+        hlcvs_shape,
+        hlcvs_dtype,
+        btc_usd_shared_memory_file,
+        btc_usd_dtype,
+        bot_params_pair_dict,
+        exchange_params_list,
+        backtest_params_dict,
+        None,
+    )?;
+    let scenarios = scenarios
+        .iter()
+        .map(|scenario| {
+            serde_json::from_value::<StressScenario>(py_to_json_value(py, &scenario)?)
+                .map_err(|e| PyValueError::new_err(e.to_string()))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    let results = py
+        .allow_threads(|| {
+            let (hlcvs, btc_usd) = dataset.views();
+            run_stress_test(
+                &hlcvs,
+                &btc_usd,
+                &dataset.bot_params_pair,
+                &dataset.exchange_params,
+                &dataset.backtest_params,
+                &scenarios,
+            )
+        })
+        .map_err(PyValueError::new_err)?;
+    let py_results = PyList::empty_bound(py);
+    for result in &results {
+        py_results.append(struct_to_py_dict(py, result)?)?;
+    }
+    Ok(py_results.into())
+}
+
+#[cfg(all(test, not(feature = "extension-module")))]
+mod tests {
+    use super::*;
+
+    fn bot_params_pair() -> BotParamsPair {
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.005,
+            close_grid_qty_pct: 0.2,
+            ema_span_0: 200.0,
+            ema_span_1: 1000.0,
+            n_positions: 2,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        };
+        BotParamsPair {
+            long: bot_params.clone(),
+            short: bot_params,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bound_dicts_round_trip_through_the_bindings() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let original = bot_params_pair();
+            let dict = struct_to_py_dict(py, &original).unwrap();
+            assert!(bot_params_pair_from_dict(&dict)
+                .unwrap()
+                .diff(&original)
+                .is_empty());
+
+            let updates = PyDict::new_bound(py);
+            updates.set_item("long.n_positions", 3).unwrap();
+            updates.set_item("short.close_grid_qty_pct", 0.25).unwrap();
+            let merged = merge_bot_params_py(py, &dict, &updates).unwrap();
+            let diff = diff_bot_params_py(py, &dict, &merged).unwrap();
+            let paths: Vec<&str> = diff.iter().map(|(path, _, _)| path.as_str()).collect();
+            assert_eq!(paths, ["long.n_positions", "short.close_grid_qty_pct"]);
+            let (_, old_value, new_value) = &diff[0];
+            assert_eq!(old_value.extract::<u64>(py).unwrap(), 2);
+            assert_eq!(new_value.extract::<u64>(py).unwrap(), 3);
+
+            let unknown = PyDict::new_bound(py);
+            unknown.set_item("long.no_such_param", 1.0).unwrap();
+            let err = merge_bot_params_py(py, &dict, &unknown).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+
+            let exchange_params = PyDict::new_bound(py);
+            exchange_params.set_item("qty_step", 0.001).unwrap();
+            exchange_params.set_item("price_step", 0.01).unwrap();
+            exchange_params.set_item("min_qty", 0.001).unwrap();
+            exchange_params.set_item("min_cost", 5.0).unwrap();
+            exchange_params.set_item("c_mult", 1.0).unwrap();
+            let exchange_params = exchange_params_from_dict(&exchange_params).unwrap();
+            assert_eq!(
+                (
+                    exchange_params.qty_step,
+                    exchange_params.price_step,
+                    exchange_params.min_cost
+                ),
+                (0.001, 0.01, 5.0)
+            );
+        });
+    }
+
+    #[test]
+    fn unknown_psides_raise_value_errors() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            assert_eq!(pside_from_str("short").unwrap(), SHORT);
+            assert!(pside_from_str("both")
+                .unwrap_err()
+                .is_instance_of::<PyValueError>(py));
+        });
+    }
 }
@@ -0,0 +1,1026 @@
+use crate::backtest::{analyze_backtest_pair, downsample_equities};
+use crate::constants::LONG;
+use crate::lots::CloseAttribution;
+use crate::order_lifetimes::OrderLifetimeStats;
+use crate::types::{
+    Analysis, BacktestParams, BotParamsPair, Equities, Evaluation, ExchangeParams, Fill,
+    ReduceOnlyPeriod, SymbolIdx, WindDown,
+};
+use crate::utils::qty_to_cost;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Layout version of BacktestResult. Bump it whenever a field of BacktestResult, or of any
+/// struct it contains, is added, removed, renamed or changes meaning, and add a migration arm
+/// to `migrate` so that results saved by older versions keep loading. Fields added to the
+/// params structs ConfigEcho holds also need a serde default, as older configs lack them.
+///
+/// history:
+/// 1: fills, equities, use_btc_collateral, coin_stats, analysis_usd, analysis_btc, config
+/// 2: wind_downs
+/// 3: dataset_fingerprint
+/// 4: coin_stats.effective_min_markups
+/// 5: fills.impact_pct
+/// 6: order_lifetimes
+/// 7: reduce_only_periods
+/// 8: lot_attributions
+/// 9: coin_stats.n_cost_capped_fills
+/// 10: config.exchange_params_list.price_band_pct, absent from v1 results saved before it
+pub const BACKTEST_RESULT_SCHEMA_VERSION: u32 = 10;
+
+/// Buckets of the equity curves compare_results overlays; see downsample_equities.
+const COMPARISON_EQUITY_BUCKETS: usize = 1000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CoinStats {
+    pub coin: String,
+    pub n_fills: usize,
+    pub pnl: f64,
+    pub fees_paid: f64,
+    pub volume: f64,                     // quote volume of all fills
+    pub effective_min_markups: [f64; 2], // per pside; see with_markup_floors
+    pub n_cost_capped_fills: usize,      // entry fills bound by max_position_cost
+}
+
+/// How a side's n_positions slots were used over the backtest, per candle.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SlotUtilization {
+    pub n_positions: usize,
+    pub mean: f64,       // mean share of slots holding a position
+    pub full_pct: f64,   // share of candles with every slot taken
+    pub daily: Vec<f64>, // mean share of slots holding a position, per day
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConfigEcho {
+    pub bot_params_pair: BotParamsPair,
+    pub exchange_params_list: Vec<ExchangeParams>,
+    pub backtest_params: BacktestParams,
+}
+
+/// Everything a backtest produces; the one contract between the backtester and consumers.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BacktestResult {
+    pub schema_version: u32,
+    pub fills: Vec<Fill>,
+    pub equities: Equities,
+    pub use_btc_collateral: bool,
+    pub coin_stats: Vec<CoinStats>,
+    pub wind_downs: Vec<WindDown>, // one per scheduled switch out of normal mode
+    pub dataset_fingerprint: u64,  // see calc_candles_fingerprint; 0 == unknown
+    pub order_lifetimes: Vec<OrderLifetimeStats>, // per order type; empty if not tracked
+    pub reduce_only_periods: Vec<ReduceOnlyPeriod>, // see BacktestParams.reduce_only_drawdown_pct
+    pub lot_attributions: Vec<CloseAttribution>, // per close fill; empty if not tracked
+    // serde_json writes non-finite floats as null, so analyses are recomputed on load
+    #[serde(skip_deserializing)]
+    pub analysis_usd: Analysis,
+    #[serde(skip_deserializing)]
+    pub analysis_btc: Analysis,
+    pub config: ConfigEcho,
+}
+
+impl BacktestResult {
+    pub fn new(
+        fills: Vec<Fill>,
+        equities: Equities,
+        use_btc_collateral: bool,
+        bot_params_pair: BotParamsPair,
+        exchange_params_list: Vec<ExchangeParams>,
+        backtest_params: BacktestParams,
+        dataset_fingerprint: u64,
+    ) -> Self {
+        let (analysis_usd, analysis_btc) =
+            analyze_backtest_pair(&fills, &equities, use_btc_collateral);
+        let coin_stats = calc_coin_stats(
+            &fills,
+            &bot_params_pair,
+            &exchange_params_list,
+            &backtest_params,
+        );
+        BacktestResult {
+            schema_version: BACKTEST_RESULT_SCHEMA_VERSION,
+            fills,
+            equities,
+            use_btc_collateral,
+            coin_stats,
+            wind_downs: Vec::new(),
+            dataset_fingerprint,
+            order_lifetimes: Vec::new(),
+            reduce_only_periods: Vec::new(),
+            lot_attributions: Vec::new(),
+            analysis_usd,
+            analysis_btc,
+            config: ConfigEcho {
+                bot_params_pair,
+                exchange_params_list,
+                backtest_params,
+            },
+        }
+    }
+
+    /// Writes `path` as JSON and the equity curves as little-endian f64s to the sidecar
+    /// `path.bin` (usd followed by btc), keeping the JSON small for long backtests.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut json = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        let header = json
+            .as_object_mut()
+            .expect("BacktestResult serializes to an object");
+        header.remove("equities");
+        header.insert(
+            "n_equities".to_string(),
+            Value::from(self.equities.usd.len()),
+        );
+
+        let mut sidecar =
+            Vec::with_capacity((self.equities.usd.len() + self.equities.btc.len()) * 8);
+        for value in self.equities.usd.iter().chain(self.equities.btc.iter()) {
+            sidecar.extend_from_slice(&value.to_le_bytes());
+        }
+        fs::write(path, serde_json::to_vec(&json).map_err(|e| e.to_string())?)
+            .map_err(|e| format!("unable to write {}: {}", path.display(), e))?;
+        let sidecar_path = sidecar_path(path);
+        fs::write(&sidecar_path, sidecar)
+            .map_err(|e| format!("unable to write {}: {}", sidecar_path.display(), e))
+    }
+
+    /// Raises coin_stats' effective_min_markups to the backtest's per coin markup floors.
+    pub fn with_markup_floors(mut self, markup_floors: &[f64]) -> Self {
+        let bot_params_pair = &self.config.bot_params_pair;
+        for (stats, &floor) in self.coin_stats.iter_mut().zip(markup_floors) {
+            stats.effective_min_markups = [
+                bot_params_pair.long.effective_close_grid_min_markup(floor),
+                bot_params_pair.short.effective_close_grid_min_markup(floor),
+            ];
+        }
+        self
+    }
+
+    /// Counts the entry fills max_position_cost bound, per coin and in both analyses.
+    pub fn with_cost_capped_fills(
+        mut self,
+        cost_capped_fills: &[(usize, SymbolIdx, usize)],
+    ) -> Self {
+        for &(_, idx, _) in cost_capped_fills {
+            if let Some(stats) = self.coin_stats.get_mut(idx as usize) {
+                stats.n_cost_capped_fills += 1;
+            }
+        }
+        self.count_cost_capped_fills();
+        self
+    }
+
+    fn count_cost_capped_fills(&mut self) {
+        let n_cost_capped_fills: usize = self
+            .coin_stats
+            .iter()
+            .map(|stats| stats.n_cost_capped_fills)
+            .sum();
+        self.analysis_usd.n_cost_capped_fills = n_cost_capped_fills as f64;
+        self.analysis_btc.n_cost_capped_fills = n_cost_capped_fills as f64;
+    }
+
+    pub fn with_wind_downs(mut self, wind_downs: Vec<WindDown>) -> Self {
+        self.wind_downs = wind_downs;
+        self
+    }
+
+    pub fn with_order_lifetimes(mut self, order_lifetimes: Vec<OrderLifetimeStats>) -> Self {
+        self.order_lifetimes = order_lifetimes;
+        self
+    }
+
+    pub fn with_reduce_only_periods(mut self, reduce_only_periods: Vec<ReduceOnlyPeriod>) -> Self {
+        self.reduce_only_periods = reduce_only_periods;
+        self
+    }
+
+    pub fn with_lot_attributions(mut self, lot_attributions: Vec<CloseAttribution>) -> Self {
+        self.lot_attributions = lot_attributions;
+        self
+    }
+
+    /// Derived from the fills, so results saved before slots were tracked report it too.
+    pub fn slot_utilization(&self, pside: usize) -> SlotUtilization {
+        let bot_params = if pside == LONG {
+            &self.config.bot_params_pair.long
+        } else {
+            &self.config.bot_params_pair.short
+        };
+        calc_slot_utilization(
+            &self.fills,
+            pside,
+            // as the backtest clamps it
+            bot_params
+                .n_positions
+                .min(self.config.backtest_params.coins.len()),
+            self.equities.usd.len(),
+        )
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes =
+            fs::read(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        let mut json: Value = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+        let schema_version = json
+            .get("schema_version")
+            .and_then(Value::as_u64)
+            .ok_or("missing schema_version")? as u32;
+        if schema_version > BACKTEST_RESULT_SCHEMA_VERSION {
+            return Err(format!(
+                "{} has schema_version {}, newer than supported version {}",
+                path.display(),
+                schema_version,
+                BACKTEST_RESULT_SCHEMA_VERSION
+            ));
+        }
+        json = migrate(json, schema_version)?;
+
+        let n_equities = json
+            .as_object_mut()
+            .and_then(|header| header.remove("n_equities"))
+            .and_then(|n| n.as_u64())
+            .ok_or("missing n_equities")? as usize;
+        let sidecar_path = sidecar_path(path);
+        let sidecar = fs::read(&sidecar_path)
+            .map_err(|e| format!("unable to read {}: {}", sidecar_path.display(), e))?;
+        if sidecar.len() != n_equities * 2 * 8 {
+            return Err(format!(
+                "{} holds {} bytes, expected {}",
+                sidecar_path.display(),
+                sidecar.len(),
+                n_equities * 2 * 8
+            ));
+        }
+        let values: Vec<f64> = sidecar
+            .chunks_exact(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        json["equities"] = serde_json::json!({
+            "usd": values[..n_equities],
+            "btc": values[n_equities..],
+        });
+        let mut result: BacktestResult = serde_json::from_value(json).map_err(|e| e.to_string())?;
+        (result.analysis_usd, result.analysis_btc) =
+            analyze_backtest_pair(&result.fills, &result.equities, result.use_btc_collateral);
+        result.count_cost_capped_fills();
+        Ok(result)
+    }
+}
+
+/// How run b differs from run a; deltas are b minus a.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResultComparison {
+    pub changed_params: Vec<String>, // dotted paths into the config echo
+    pub analysis_usd: BTreeMap<String, f64>, // per finite metric
+    pub analysis_btc: BTreeMap<String, f64>,
+    pub coin_pnls: BTreeMap<String, f64>,
+    pub first_divergent_index: Option<usize>, // candle of the first differing fill
+    // both usd curves at the union of their downsampled indices, ready to overlay
+    pub equity_indices: Vec<usize>,
+    pub equities_a: Vec<f64>,
+    pub equities_b: Vec<f64>,
+}
+
+/// Compares two backtests of the same data, e.g. before and after changing one parameter.
+/// Runs on different data, or saved without a dataset fingerprint, are refused.
+pub fn compare_results(a: &BacktestResult, b: &BacktestResult) -> Result<ResultComparison, String> {
+    if a.dataset_fingerprint == 0 || b.dataset_fingerprint == 0 {
+        return Err("results saved without a dataset fingerprint cannot be compared".to_string());
+    }
+    if a.dataset_fingerprint != b.dataset_fingerprint {
+        return Err(format!(
+            "results ran on different data (dataset fingerprints {} and {})",
+            a.dataset_fingerprint, b.dataset_fingerprint
+        ));
+    }
+    let mut changed_params = Vec::new();
+    diff_json_paths(
+        "",
+        &serde_json::to_value(&a.config).map_err(|e| e.to_string())?,
+        &serde_json::to_value(&b.config).map_err(|e| e.to_string())?,
+        &mut changed_params,
+    );
+
+    let mut coin_pnls: BTreeMap<String, f64> = BTreeMap::new();
+    for stats in &a.coin_stats {
+        *coin_pnls.entry(stats.coin.clone()).or_default() -= stats.pnl;
+    }
+    for stats in &b.coin_stats {
+        *coin_pnls.entry(stats.coin.clone()).or_default() += stats.pnl;
+    }
+
+    let same_fill = |fa: &Fill, fb: &Fill| {
+        fa.index == fb.index
+            && fa.coin == fb.coin
+            && fa.order_type == fb.order_type
+            && fa.fill_qty == fb.fill_qty
+            && fa.fill_price == fb.fill_price
+    };
+    let first_divergent_index = match a
+        .fills
+        .iter()
+        .zip(&b.fills)
+        .find(|(fa, fb)| !same_fill(fa, fb))
+    {
+        Some((fa, fb)) => Some(fa.index.min(fb.index)),
+        // one run's fills may continue past the other's
+        None => a
+            .fills
+            .get(b.fills.len())
+            .or_else(|| b.fills.get(a.fills.len()))
+            .map(|fill| fill.index),
+    };
+
+    let mut equity_indices =
+        downsample_equities(&a.equities.usd, COMPARISON_EQUITY_BUCKETS)?.indices;
+    equity_indices.extend(downsample_equities(&b.equities.usd, COMPARISON_EQUITY_BUCKETS)?.indices);
+    equity_indices.sort_unstable();
+    equity_indices.dedup();
+    Ok(ResultComparison {
+        changed_params,
+        analysis_usd: diff_analyses(&a.analysis_usd, &b.analysis_usd)?,
+        analysis_btc: diff_analyses(&a.analysis_btc, &b.analysis_btc)?,
+        coin_pnls,
+        first_divergent_index,
+        equities_a: equity_indices.iter().map(|&i| a.equities.usd[i]).collect(),
+        equities_b: equity_indices.iter().map(|&i| b.equities.usd[i]).collect(),
+        equity_indices,
+    })
+}
+
+fn diff_analyses(a: &Analysis, b: &Analysis) -> Result<BTreeMap<String, f64>, String> {
+    // serde_json writes non-finite floats as null, which as_f64 skips
+    let a = serde_json::to_value(a).map_err(|e| e.to_string())?;
+    let b = serde_json::to_value(b).map_err(|e| e.to_string())?;
+    Ok(a.as_object()
+        .expect("Analysis serializes to an object")
+        .iter()
+        .filter_map(|(metric, value_a)| {
+            let delta = b.get(metric)?.as_f64()? - value_a.as_f64()?;
+            Some((metric.clone(), delta))
+        })
+        .collect())
+}
+
+/// Collects the paths at which a and b differ; arrays are compared whole.
+fn diff_json_paths(path: &str, a: &Value, b: &Value, paths: &mut Vec<String>) {
+    match (a.as_object(), b.as_object()) {
+        (Some(a), Some(b)) => {
+            let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_json_paths(
+                    &child,
+                    a.get(key).unwrap_or(&Value::Null),
+                    b.get(key).unwrap_or(&Value::Null),
+                    paths,
+                );
+            }
+        }
+        _ if a != b => paths.push(path.to_string()),
+        _ => {}
+    }
+}
+
+/// Reads one JSON value per line. A line cut short by an interrupted write is skipped, as it
+/// can only be the last one.
+pub fn read_jsonl(path: &Path) -> Result<Vec<Value>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Appends one line per row and flushes, so rows written survive an interruption. Starts a
+/// new line first if the file ends in a line cut short.
+pub fn append_jsonl(path: &Path, rows: &[Value]) -> Result<(), String> {
+    let mut file = open_jsonl_for_append(path)?;
+    let mut text = String::new();
+    for row in rows {
+        text.push_str(&row.to_string());
+        text.push('\n');
+    }
+    file.write_all(text.as_bytes())
+        .map_err(|e| write_error(path, e))?;
+    file.flush().map_err(|e| write_error(path, e))
+}
+
+fn write_error(path: &Path, e: std::io::Error) -> String {
+    format!("unable to write {}: {}", path.display(), e)
+}
+
+// ends a line cut short by an interrupted write, so appended lines start clean
+fn open_jsonl_for_append(path: &Path) -> Result<File, String> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(path)
+        .map_err(|e| write_error(path, e))?;
+    if file.metadata().map_err(|e| write_error(path, e))?.len() > 0 {
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::End(-1))
+            .map_err(|e| write_error(path, e))?;
+        file.read_exact(&mut last)
+            .map_err(|e| write_error(path, e))?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n").map_err(|e| write_error(path, e))?;
+        }
+    }
+    Ok(file)
+}
+
+/// One line of an optimizer run log: an evaluated config, its stats and how long it took.
+/// Analyses and fitness stay JSON since serde_json writes non-finite numbers as null.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunLogRecord {
+    pub optimizer: String, // "pso", "nsga2" or "grid"
+    pub iteration: usize,  // PSO iteration, NSGA-II generation or grid combination index
+    pub bot: BotParamsPair,
+    pub analysis_usd: Value,
+    pub analysis_btc: Value,
+    pub fitness: Value, // a number, NSGA-II's objective values, or null if unscored
+    pub pruned: bool,
+    pub elapsed_ms: f64,
+    pub timestamp_ms: u64, // since the unix epoch
+}
+
+impl RunLogRecord {
+    pub fn new(
+        optimizer: &str,
+        iteration: usize,
+        bot: BotParamsPair,
+        evaluation: &Evaluation,
+        fitness: Value,
+        elapsed: Duration,
+    ) -> Self {
+        RunLogRecord {
+            optimizer: optimizer.to_string(),
+            iteration,
+            bot,
+            analysis_usd: json!(evaluation.analyses.0),
+            analysis_btc: json!(evaluation.analyses.1),
+            fitness,
+            pruned: evaluation.pruned,
+            elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        }
+    }
+}
+
+struct RunLogFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl RunLogFile {
+    fn open(path: &Path) -> Result<Self, String> {
+        Ok(RunLogFile {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(open_jsonl_for_append(path)?),
+            last_flush: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, record: &RunLogRecord) -> Result<(), String> {
+        let mut line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .map_err(|e| write_error(&self.path, e))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.last_flush = Instant::now();
+        self.writer.flush().map_err(|e| write_error(&self.path, e))
+    }
+}
+
+/// Appends a RunLogRecord per evaluation to a JSONL file, buffered and flushed once
+/// flush_interval has passed, so a crash loses at most that much and a line cut short is
+/// skipped by read_run_log. With a best log, each record whose fitness beats all before it,
+/// those already in the best log included, is written there too and flushed at once.
+/// Shared by evaluations running in parallel; write errors are kept for `flush` to return.
+pub struct RunLogger {
+    log: Mutex<RunLogFile>,
+    best_log: Option<Mutex<(RunLogFile, f64)>>, // with the lowest fitness logged to it
+    flush_interval: Duration,
+    error: Mutex<Option<String>>,
+}
+
+impl RunLogger {
+    pub fn open(
+        path: &Path,
+        best_path: Option<&Path>,
+        flush_interval: Duration,
+    ) -> Result<Self, String> {
+        let best_log = match best_path {
+            Some(best_path) => {
+                let best_fitness = if best_path.exists() {
+                    read_run_log(best_path)?
+                        .iter()
+                        .filter_map(|record| record.fitness.as_f64())
+                        .fold(f64::INFINITY, f64::min)
+                } else {
+                    f64::INFINITY
+                };
+                Some(Mutex::new((RunLogFile::open(best_path)?, best_fitness)))
+            }
+            None => None,
+        };
+        Ok(RunLogger {
+            log: Mutex::new(RunLogFile::open(path)?),
+            best_log,
+            flush_interval,
+            error: Mutex::new(None),
+        })
+    }
+
+    pub fn log(&self, record: &RunLogRecord) {
+        let mut result = {
+            let mut log = self.log.lock().unwrap();
+            log.write(record).and_then(|_| {
+                if log.last_flush.elapsed() >= self.flush_interval {
+                    log.flush()
+                } else {
+                    Ok(())
+                }
+            })
+        };
+        if let (Some(best_log), Some(fitness)) = (&self.best_log, record.fitness.as_f64()) {
+            let mut best_log = best_log.lock().unwrap();
+            if fitness < best_log.1 {
+                best_log.1 = fitness;
+                result = result
+                    .and_then(|_| best_log.0.write(record))
+                    .and_then(|_| best_log.0.flush());
+            }
+        }
+        if let Err(error) = result {
+            self.error.lock().unwrap().get_or_insert(error);
+        }
+    }
+
+    /// Flushes both logs; errs with the first write error since the last flush, if any.
+    pub fn flush(&self) -> Result<(), String> {
+        if let Some(error) = self.error.lock().unwrap().take() {
+            return Err(error);
+        }
+        self.log.lock().unwrap().flush()?;
+        match &self.best_log {
+            Some(best_log) => best_log.lock().unwrap().0.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RunLogger {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Reads a run log back, skipping a last line cut short by a crash.
+pub fn read_run_log(path: &Path) -> Result<Vec<RunLogRecord>, String> {
+    read_jsonl(path)?
+        .into_iter()
+        .map(|record| {
+            serde_json::from_value(record)
+                .map_err(|e| format!("bad run log record in {}: {}", path.display(), e))
+        })
+        .collect()
+}
+
+/// Upgrades a saved result header to the current schema, one version at a time.
+fn migrate(mut json: Value, from_version: u32) -> Result<Value, String> {
+    match from_version {
+        BACKTEST_RESULT_SCHEMA_VERSION => Ok(json),
+        1 => {
+            json["wind_downs"] = json!([]);
+            json["schema_version"] = json!(2);
+            migrate(json, 2)
+        }
+        2 => {
+            json["dataset_fingerprint"] = json!(0);
+            json["schema_version"] = json!(3);
+            migrate(json, 3)
+        }
+        3 => {
+            // markup floors came later, so every coin closed at close_grid_min_markup
+            let bot = &json["config"]["bot_params_pair"];
+            let markups = json!([
+                bot["long"]["close_grid_min_markup"],
+                bot["short"]["close_grid_min_markup"]
+            ]);
+            for stats in json["coin_stats"].as_array_mut().into_iter().flatten() {
+                stats["effective_min_markups"] = markups.clone();
+            }
+            json["schema_version"] = json!(4);
+            migrate(json, 4)
+        }
+        4 => {
+            for fill in json["fills"].as_array_mut().into_iter().flatten() {
+                fill["impact_pct"] = json!(0.0);
+            }
+            json["schema_version"] = json!(5);
+            migrate(json, 5)
+        }
+        5 => {
+            json["order_lifetimes"] = json!([]);
+            json["schema_version"] = json!(6);
+            migrate(json, 6)
+        }
+        6 => {
+            json["reduce_only_periods"] = json!([]);
+            json["schema_version"] = json!(7);
+            migrate(json, 7)
+        }
+        7 => {
+            json["lot_attributions"] = json!([]);
+            json["schema_version"] = json!(8);
+            migrate(json, 8)
+        }
+        8 => {
+            for stats in json["coin_stats"].as_array_mut().into_iter().flatten() {
+                stats["n_cost_capped_fills"] = json!(0);
+            }
+            json["schema_version"] = json!(9);
+            migrate(json, 9)
+        }
+        9 => {
+            let exchange_params_list = json["config"]["exchange_params_list"].as_array_mut();
+            for exchange_params in exchange_params_list.into_iter().flatten() {
+                if let Some(exchange_params) = exchange_params.as_object_mut() {
+                    exchange_params
+                        .entry("price_band_pct")
+                        .or_insert(json!(0.0));
+                }
+            }
+            json["schema_version"] = json!(10);
+            migrate(json, 10)
+        }
+        _ => Err(format!(
+            "no migration from schema_version {} to {}",
+            from_version, BACKTEST_RESULT_SCHEMA_VERSION
+        )),
+    }
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar_path = path.as_os_str().to_owned();
+    sidecar_path.push(".bin");
+    PathBuf::from(sidecar_path)
+}
+
+fn calc_slot_utilization(
+    fills: &[Fill],
+    pside: usize,
+    n_positions: usize,
+    n_candles: usize,
+) -> SlotUtilization {
+    let mut utilization = SlotUtilization {
+        n_positions,
+        ..Default::default()
+    };
+    if n_positions == 0 || n_candles == 0 {
+        return utilization;
+    }
+    let mut open: HashSet<&str> = HashSet::new();
+    let mut fills = fills
+        .iter()
+        .filter(|fill| fill.order_type.pside() == pside)
+        .peekable();
+    let (mut sum, mut n_full) = (0.0, 0);
+    let (mut day_sum, mut day_len) = (0.0, 0);
+    for k in 0..n_candles {
+        while let Some(fill) = fills.next_if(|fill| fill.index <= k) {
+            if fill.position_size == 0.0 {
+                open.remove(fill.coin.as_str());
+            } else {
+                open.insert(fill.coin.as_str());
+            }
+        }
+        let share = open.len().min(n_positions) as f64 / n_positions as f64;
+        sum += share;
+        n_full += (open.len() >= n_positions) as usize;
+        day_sum += share;
+        day_len += 1;
+        if day_len == 1440 || k + 1 == n_candles {
+            utilization.daily.push(day_sum / day_len as f64);
+            (day_sum, day_len) = (0.0, 0);
+        }
+    }
+    utilization.mean = sum / n_candles as f64;
+    utilization.full_pct = n_full as f64 / n_candles as f64;
+    utilization
+}
+
+fn calc_coin_stats(
+    fills: &[Fill],
+    bot_params_pair: &BotParamsPair,
+    exchange_params_list: &[ExchangeParams],
+    backtest_params: &BacktestParams,
+) -> Vec<CoinStats> {
+    let mut coin_stats: Vec<CoinStats> = backtest_params
+        .coins
+        .iter()
+        .map(|coin| CoinStats {
+            coin: coin.clone(),
+            effective_min_markups: [
+                bot_params_pair.long.close_grid_min_markup,
+                bot_params_pair.short.close_grid_min_markup,
+            ],
+            ..Default::default()
+        })
+        .collect();
+    for fill in fills {
+        if let Some(idx) = backtest_params
+            .coins
+            .iter()
+            .position(|coin| coin == &fill.coin)
+        {
+            let c_mult = exchange_params_list
+                .get(idx)
+                .map_or(1.0, |params| params.c_mult);
+            let stats = &mut coin_stats[idx];
+            stats.n_fills += 1;
+            stats.pnl += fill.pnl;
+            stats.fees_paid += fill.fee_paid;
+            stats.volume += qty_to_cost(fill.fill_qty, fill.fill_price, c_mult);
+        }
+    }
+    coin_stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::SHORT;
+    use crate::types::{BotParams, OrderType};
+
+    fn fixture_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("passivbot_{}_{}.json", std::process::id(), name))
+    }
+
+    fn test_result() -> BacktestResult {
+        let fill = |index: usize, pnl: f64, fill_qty: f64, position_size: f64| Fill {
+            index,
+            coin: "BTC".to_string(),
+            pnl,
+            fee_paid: -0.02,
+            balance_usd_total: 1000.0 + pnl,
+            balance_btc: 0.0,
+            balance_usd: 1000.0 + pnl,
+            btc_price: 1.0,
+            fill_qty,
+            fill_price: 100.0,
+            position_size,
+            position_price: 100.0,
+            order_type: if fill_qty > 0.0 {
+                OrderType::EntryInitialNormalLong
+            } else {
+                OrderType::CloseGridLong
+            },
+            impact_pct: 0.0,
+        };
+        let curve: Vec<f64> = (0..2881).map(|k| 1000.0 + (k % 5) as f64).collect();
+        BacktestResult::new(
+            vec![fill(1, 0.0, 1.0, 1.0), fill(5, 2.0, -1.0, 0.0)],
+            Equities {
+                usd: curve.clone(),
+                btc: curve,
+            },
+            false,
+            BotParamsPair {
+                long: BotParams {
+                    close_grid_min_markup: 0.01,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            vec![ExchangeParams::default()],
+            serde_json::from_value(json!({
+                "starting_balance": 1000.0,
+                "maker_fee": 0.0002,
+                "coins": ["BTC"],
+            }))
+            .unwrap(),
+            42,
+        )
+    }
+
+    #[test]
+    fn loads_a_v1_result() {
+        let result = BacktestResult::load(&fixture_path("backtest_result_v1.json")).unwrap();
+        assert_eq!(result.schema_version, BACKTEST_RESULT_SCHEMA_VERSION);
+        assert_eq!(result.fills.len(), 2);
+        assert_eq!(result.fills[1].order_type, OrderType::CloseGridLong);
+        assert_eq!(result.fills[1].impact_pct, 0.0);
+        assert_eq!(result.equities.usd.len(), 2881);
+        assert!(result.wind_downs.is_empty() && result.lot_attributions.is_empty());
+        assert_eq!(result.dataset_fingerprint, 0);
+        assert_eq!(result.coin_stats[0].effective_min_markups, [0.01, 0.01]);
+
+        // config fields added since v1 take their defaults
+        let config = &result.config;
+        assert_eq!(config.bot_params_pair.long.close_grid_qty_pct, 0.25);
+        assert!(!config.bot_params_pair.long.auto_reduce_enabled);
+        assert_eq!(config.bot_params_pair.long_allocation_pct, 0.0);
+        assert_eq!(config.exchange_params_list[0].price_step, 0.01);
+        assert_eq!(config.exchange_params_list[0].price_band_pct, 0.0);
+        assert_eq!(config.backtest_params.seed, 0);
+        assert_eq!(config.backtest_params.coins, ["BTC"]);
+    }
+
+    #[test]
+    fn migration_fills_in_price_band_pct() {
+        let bytes = fs::read(fixture_path("backtest_result_v1.json")).unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+        let exchange_params = &json["config"]["exchange_params_list"][0];
+        assert!(exchange_params.get("price_band_pct").is_none());
+        let migrated = migrate(json, 1).unwrap();
+        assert_eq!(migrated["schema_version"], json!(10));
+        let exchange_params = &migrated["config"]["exchange_params_list"][0];
+        assert_eq!(exchange_params["price_band_pct"], json!(0.0));
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let result = test_result();
+        let path = temp_path("round_trip");
+        result.save(&path).unwrap();
+        let loaded = BacktestResult::load(&path);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(sidecar_path(&path)).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&result).unwrap()
+        );
+        assert_eq!(loaded.analysis_usd.gain, result.analysis_usd.gain);
+    }
+
+    #[test]
+    fn load_gates_on_schema_version() {
+        let path = temp_path("version_gating");
+        let load_with_version = |schema_version: Value| {
+            let mut json = serde_json::to_value(test_result()).unwrap();
+            json["schema_version"] = schema_version;
+            fs::write(&path, serde_json::to_vec(&json).unwrap()).unwrap();
+            let loaded = BacktestResult::load(&path);
+            fs::remove_file(&path).unwrap();
+            loaded.map(|_| ()).unwrap_err()
+        };
+        assert!(load_with_version(json!(BACKTEST_RESULT_SCHEMA_VERSION + 1)).contains("newer"));
+        assert!(load_with_version(json!(0)).contains("no migration"));
+        assert_eq!(load_with_version(Value::Null), "missing schema_version");
+    }
+
+    #[test]
+    fn run_log_read_back_skips_a_line_cut_short() {
+        let path = temp_path("run_log").with_extension("jsonl");
+        let best_path = temp_path("run_log_best").with_extension("jsonl");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&best_path);
+        let record = |iteration: usize, fitness: f64| {
+            RunLogRecord::new(
+                "pso",
+                iteration,
+                BotParamsPair::default(),
+                &Evaluation::default(),
+                json!(fitness),
+                Duration::from_millis(5),
+            )
+        };
+        let logger = RunLogger::open(&path, Some(&best_path), Duration::from_secs(60)).unwrap();
+        for (iteration, fitness) in [(0, 2.0), (1, 1.0), (2, 3.0)] {
+            logger.log(&record(iteration, fitness));
+        }
+        drop(logger);
+
+        // a crash mid-write leaves part of a record and no newline
+        let line = serde_json::to_string(&record(3, 0.5)).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&line.as_bytes()[..line.len() / 2]).unwrap();
+        drop(file);
+        let iterations = |path: &Path| -> Vec<usize> {
+            read_run_log(path)
+                .unwrap()
+                .iter()
+                .map(|record| record.iteration)
+                .collect()
+        };
+        assert_eq!(iterations(&path), [0, 1, 2]);
+        assert_eq!(iterations(&best_path), [0, 1]);
+
+        // reopened, the log continues on a fresh line and the best log from fitness 1.0
+        let logger = RunLogger::open(&path, Some(&best_path), Duration::ZERO).unwrap();
+        logger.log(&record(4, 1.5));
+        logger.log(&record(5, 0.8));
+        logger.flush().unwrap();
+        assert_eq!(iterations(&path), [0, 1, 2, 4, 5]);
+        assert_eq!(iterations(&best_path), [0, 1, 5]);
+        drop(logger);
+
+        fs::write(&path, "{\"optimizer\": \"pso\"}\n").unwrap();
+        assert!(read_run_log(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&best_path).unwrap();
+    }
+
+    #[test]
+    fn slot_utilization_follows_the_open_positions() {
+        let fill = |index: usize, coin: &str, position_size: f64| Fill {
+            index,
+            coin: coin.to_string(),
+            position_size,
+            order_type: if position_size > 0.0 {
+                OrderType::EntryInitialNormalLong
+            } else {
+                OrderType::CloseGridLong
+            },
+            ..test_result().fills[0].clone()
+        };
+        // A open from candle 0 to 3, B from candle 2 on; two slots
+        let fills = [fill(0, "A", 1.0), fill(2, "B", 1.0), fill(3, "A", 0.0)];
+        let utilization = calc_slot_utilization(&fills, LONG, 2, 4);
+        assert_eq!(utilization.mean, (0.5 + 0.5 + 1.0 + 0.5) / 4.0);
+        assert_eq!(utilization.full_pct, 0.25);
+        assert_eq!(utilization.daily, [utilization.mean]);
+        // days of 1440 candles
+        let utilization = calc_slot_utilization(&fills, LONG, 2, 1441);
+        assert_eq!(utilization.daily.len(), 2);
+        assert_eq!(utilization.daily[1], 0.5);
+        // the other side holds nothing
+        assert_eq!(calc_slot_utilization(&fills, SHORT, 2, 4).mean, 0.0);
+    }
+
+    #[test]
+    fn comparison_reports_what_run_b_changed() {
+        let a = test_result();
+        let mut b = a.clone();
+        b.config.bot_params_pair.long.close_grid_min_markup = 0.02;
+        b.fills[1].fill_price = 101.0;
+        b.coin_stats[0].pnl += 1.0;
+        b.analysis_usd.gain += 0.5;
+        b.equities.usd[2000] += 10.0;
+        let comparison = compare_results(&a, &b).unwrap();
+        assert_eq!(
+            comparison.changed_params,
+            ["bot_params_pair.long.close_grid_min_markup"]
+        );
+        assert_eq!(comparison.analysis_usd["gain"], 0.5);
+        assert!(comparison.analysis_btc.values().all(|&delta| delta == 0.0));
+        assert_eq!(comparison.coin_pnls["BTC"], 1.0);
+        assert_eq!(comparison.first_divergent_index, Some(5));
+        // the spike is kept by b's downsample and overlaid on a's curve
+        let i = comparison
+            .equity_indices
+            .iter()
+            .position(|&k| k == 2000)
+            .unwrap();
+        assert_eq!(
+            (comparison.equities_a[i], comparison.equities_b[i]),
+            (a.equities.usd[2000], a.equities.usd[2000] + 10.0)
+        );
+
+        // a run's extra fills diverge where they start
+        b = a.clone();
+        b.fills.truncate(1);
+        assert_eq!(
+            compare_results(&a, &b).unwrap().first_divergent_index,
+            Some(5)
+        );
+        assert_eq!(compare_results(&a, &a).unwrap().first_divergent_index, None);
+
+        b.dataset_fingerprint = 43;
+        assert!(compare_results(&a, &b)
+            .unwrap_err()
+            .contains("different data"));
+        b.dataset_fingerprint = 0;
+        assert!(compare_results(&a, &b)
+            .unwrap_err()
+            .contains("without a dataset fingerprint"));
+    }
+}
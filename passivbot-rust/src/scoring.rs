@@ -0,0 +1,329 @@
+use crate::types::Analysis;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Applied to a metric before weighting: clamped to [low, high], then divided by scale.
+#[derive(Debug, Clone)]
+pub struct Normalization {
+    pub clamp: Option<(f64, f64)>,
+    pub scale: f64,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization {
+            clamp: None,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Normalization {
+    pub fn apply(&self, value: f64) -> f64 {
+        let value = match self.clamp {
+            Some((low, high)) => value.clamp(low, high),
+            None => value,
+        };
+        value / self.scale
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WeightedMetric {
+    pub metric: String,
+    pub weight: f64,
+    pub normalization: Normalization,
+}
+
+/// Keeps a metric within [min, max]; each unit outside adds penalty to the fitness.
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    pub metric: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub penalty: f64,
+}
+
+impl Constraint {
+    pub fn violation(&self, value: f64) -> f64 {
+        let below = self.min.map_or(0.0, |min| (min - value).max(0.0));
+        let above = self.max.map_or(0.0, |max| (value - max).max(0.0));
+        below + above
+    }
+}
+
+pub const DEFAULT_CONSTRAINT_PENALTY: f64 = 1000.0;
+
+/// Fitness, lower being better, and the terms it is the sum of, in config order: one per
+/// weighted metric named after the metric, then one per constraint named "<metric>_penalty".
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Score {
+    pub fitness: f64,
+    pub contributions: Vec<(String, f64)>,
+}
+
+impl Score {
+    /// A backtest aborted by pruning ranks after every complete one.
+    pub fn pruned() -> Self {
+        Score {
+            fitness: f64::INFINITY,
+            contributions: vec![("pruned".to_string(), f64::INFINITY)],
+        }
+    }
+}
+
+/// Turns a backtest's analyses into a single fitness for the optimizers. Metrics are fields of
+/// Analysis; btc_ metrics are read from the BTC-denominated analysis. A non-finite metric
+/// makes the fitness NaN, which the optimizers rank last.
+#[derive(Debug, Clone, Default)]
+pub struct ScoringConfig {
+    pub weights: Vec<WeightedMetric>,
+    pub constraints: Vec<Constraint>,
+}
+
+impl ScoringConfig {
+    /// Reads either a flat {"adg_w": -1.0, ..} of metric weights or
+    /// {"weights": {"adg_w": -1.0, "sharpe_ratio": {"weight": -1.0, "clamp": [0, 3],
+    /// "scale": 1.0}, ..}, "constraints": {"drawdown_worst": {"max": 0.3, "penalty": 100.0},
+    /// "positions_held_per_day": {"min": 0.5}, ..}}. Metrics to maximize take negative
+    /// weights.
+    pub fn from_config(scoring: &Value) -> Result<Self, String> {
+        let scoring = scoring
+            .as_object()
+            .ok_or_else(|| "scoring must be an object".to_string())?;
+        let structured = scoring.contains_key("weights") || scoring.contains_key("constraints");
+        let empty = Map::new();
+        let (weights, constraints) = if structured {
+            let section = |key: &str| match scoring.get(key) {
+                None => Ok(&empty),
+                Some(value) => value
+                    .as_object()
+                    .ok_or_else(|| format!("scoring {} must be an object", key)),
+            };
+            if let Some(key) = scoring
+                .keys()
+                .find(|key| !["weights", "constraints"].contains(&key.as_str()))
+            {
+                return Err(format!("unknown scoring section '{}'", key));
+            }
+            (section("weights")?, section("constraints")?)
+        } else {
+            (scoring, &empty)
+        };
+        if weights.is_empty() && constraints.is_empty() {
+            return Err("scoring is empty".to_string());
+        }
+        Ok(ScoringConfig {
+            weights: weights
+                .iter()
+                .map(|(metric, spec)| weighted_metric_from_config(metric, spec))
+                .collect::<Result<_, _>>()?,
+            constraints: constraints
+                .iter()
+                .map(|(metric, spec)| constraint_from_config(metric, spec))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn score(&self, analysis_usd: &Analysis, analysis_btc: &Analysis) -> Score {
+        let analysis_usd = serde_json::to_value(analysis_usd).expect("Analysis serializes");
+        let analysis_btc = serde_json::to_value(analysis_btc).expect("Analysis serializes");
+        self.score_with(|metric| metric_value(metric, &analysis_usd, &analysis_btc))
+    }
+
+    /// Scores metrics read through metric_value, e.g. from analyses held in Python.
+    pub fn score_with<F: Fn(&str) -> f64>(&self, metric_value: F) -> Score {
+        let mut contributions = Vec::with_capacity(self.weights.len() + self.constraints.len());
+        for weighted in &self.weights {
+            let value = weighted.normalization.apply(metric_value(&weighted.metric));
+            contributions.push((weighted.metric.clone(), weighted.weight * value));
+        }
+        for constraint in &self.constraints {
+            let violation = constraint.violation(metric_value(&constraint.metric));
+            contributions.push((
+                format!("{}_penalty", constraint.metric),
+                constraint.penalty * violation,
+            ));
+        }
+        Score {
+            fitness: contributions.iter().map(|(_, value)| value).sum(),
+            contributions,
+        }
+    }
+}
+
+fn weighted_metric_from_config(metric: &str, spec: &Value) -> Result<WeightedMetric, String> {
+    check_metric(metric)?;
+    if let Some(weight) = spec.as_f64() {
+        return Ok(WeightedMetric {
+            metric: metric.to_string(),
+            weight,
+            normalization: Normalization::default(),
+        });
+    }
+    let spec = spec
+        .as_object()
+        .ok_or_else(|| format!("weight of '{}' must be a number or an object", metric))?;
+    let weight = spec
+        .get("weight")
+        .and_then(Value::as_f64)
+        .ok_or_else(|| format!("weight of '{}' is not a number", metric))?;
+    let clamp = match spec.get("clamp") {
+        None => None,
+        Some(clamp) => match clamp.as_array().map(|c| c.as_slice()) {
+            Some([low, high]) => match (low.as_f64(), high.as_f64()) {
+                (Some(low), Some(high)) if low <= high => Some((low, high)),
+                _ => return Err(format!("clamp of '{}' must be [low, high]", metric)),
+            },
+            _ => return Err(format!("clamp of '{}' must be [low, high]", metric)),
+        },
+    };
+    let scale = match spec.get("scale") {
+        None => 1.0,
+        Some(scale) => match scale.as_f64() {
+            Some(scale) if scale != 0.0 => scale,
+            _ => return Err(format!("scale of '{}' must be a non-zero number", metric)),
+        },
+    };
+    Ok(WeightedMetric {
+        metric: metric.to_string(),
+        weight,
+        normalization: Normalization { clamp, scale },
+    })
+}
+
+fn constraint_from_config(metric: &str, spec: &Value) -> Result<Constraint, String> {
+    check_metric(metric)?;
+    let number = |key: &str| match spec.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_f64()
+            .map(Some)
+            .ok_or_else(|| format!("{} of constraint '{}' is not a number", key, metric)),
+    };
+    let (min, max) = (number("min")?, number("max")?);
+    if min.is_none() && max.is_none() {
+        return Err(format!("constraint '{}' needs a min or a max", metric));
+    }
+    Ok(Constraint {
+        metric: metric.to_string(),
+        min,
+        max,
+        penalty: number("penalty")?.unwrap_or(DEFAULT_CONSTRAINT_PENALTY),
+    })
+}
+
+/// Errs unless metric, less any btc_ prefix, is a numeric field of Analysis.
+pub fn check_metric(metric: &str) -> Result<(), String> {
+    let analysis = serde_json::to_value(Analysis::default()).expect("Analysis serializes");
+    let metric_usd = metric.strip_prefix("btc_").unwrap_or(metric);
+    if analysis.get(metric_usd).is_some_and(Value::is_number) {
+        Ok(())
+    } else {
+        Err(format!("unknown metric '{}'", metric))
+    }
+}
+
+/// Reads metric from serialized analyses; btc_ metrics come from analysis_btc.
+pub fn metric_value(metric: &str, analysis_usd: &Value, analysis_btc: &Value) -> f64 {
+    let value = match metric.strip_prefix("btc_") {
+        Some(metric) => analysis_btc[metric].as_f64(),
+        None => analysis_usd[metric].as_f64(),
+    };
+    // serde_json writes non-finite floats as null
+    value.unwrap_or(f64::NAN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scoring() -> ScoringConfig {
+        ScoringConfig::from_config(&json!({
+            "weights": {
+                "adg": -1.0,
+                "btc_adg": -0.5,
+                "sharpe_ratio": {"weight": -2.0, "clamp": [0.0, 3.0], "scale": 3.0},
+            },
+            "constraints": {
+                "drawdown_worst": {"max": 0.3, "penalty": 100.0},
+                "positions_held_per_day": {"min": 0.5},
+            },
+        }))
+        .unwrap()
+    }
+
+    fn analysis(adg: f64, sharpe_ratio: f64, drawdown_worst: f64, held: f64) -> Analysis {
+        Analysis {
+            adg,
+            sharpe_ratio,
+            drawdown_worst,
+            positions_held_per_day: held,
+            ..Default::default()
+        }
+    }
+
+    fn assert_sums_to_fitness(score: &Score) {
+        let sum: f64 = score.contributions.iter().map(|(_, value)| value).sum();
+        assert!((sum - score.fitness).abs() < 1e-12, "{:?}", score);
+    }
+
+    #[test]
+    fn constraints_add_penalties_per_unit_outside() {
+        let scoring = scoring();
+        let usd = analysis(0.002, 6.0, 0.2, 1.0);
+        let btc = analysis(0.001, 0.0, 0.0, 0.0);
+
+        // within every constraint: weights only, sharpe clamped to 3 and scaled to 1
+        let score = scoring.score(&usd, &btc);
+        let names: Vec<&str> = score
+            .contributions
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "adg",
+                "btc_adg",
+                "sharpe_ratio",
+                "drawdown_worst_penalty",
+                "positions_held_per_day_penalty"
+            ]
+        );
+        assert_eq!(score.contributions[3].1, 0.0);
+        assert_eq!(score.contributions[4].1, 0.0);
+        assert!((score.fitness - (-0.002 - 0.0005 - 2.0)).abs() < 1e-12);
+        assert_sums_to_fitness(&score);
+
+        // 0.1 over the drawdown max at 100 a unit, 0.25 under the default-penalized min
+        let breached = scoring.score(&analysis(0.002, 6.0, 0.4, 0.25), &btc);
+        assert!((breached.contributions[3].1 - 10.0).abs() < 1e-9);
+        assert!((breached.contributions[4].1 - 0.25 * DEFAULT_CONSTRAINT_PENALTY).abs() < 1e-9);
+        assert!((breached.fitness - score.fitness - 260.0).abs() < 1e-9);
+        assert_sums_to_fitness(&breached);
+
+        let constraint = &scoring.constraints[0];
+        assert_eq!(constraint.violation(0.3), 0.0);
+        assert!((constraint.violation(0.35) - 0.05).abs() < 1e-12);
+
+        // a metric missing from the analyses makes the fitness NaN
+        let score = scoring.score_with(|metric| if metric == "adg" { f64::NAN } else { 0.0 });
+        assert!(score.fitness.is_nan());
+    }
+
+    #[test]
+    fn bad_scoring_configs_are_rejected() {
+        assert!(ScoringConfig::from_config(&json!({})).is_err());
+        assert!(ScoringConfig::from_config(&json!({"not_a_metric": 1.0})).is_err());
+        assert!(ScoringConfig::from_config(&json!({"weights": {}, "extra": {}})).is_err());
+        assert!(ScoringConfig::from_config(&json!({"constraints": {"adg": {}}})).is_err());
+        assert!(
+            ScoringConfig::from_config(&json!({"adg": {"weight": 1.0, "clamp": [1, 0]}})).is_err()
+        );
+        assert!(ScoringConfig::from_config(&json!({"adg": {"weight": 1.0, "scale": 0}})).is_err());
+        let flat = ScoringConfig::from_config(&json!({"adg": -1.0, "btc_sharpe_ratio": 1.0}));
+        assert_eq!(flat.unwrap().weights.len(), 2);
+    }
+}
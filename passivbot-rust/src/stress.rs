@@ -0,0 +1,452 @@
+use crate::backtest::evaluate_backtest;
+use crate::constants::{CLOSE, HIGH, LOW};
+use crate::types::{Analysis, BacktestParams, BotParamsPair, ExchangeParams, PruneParams};
+use crate::utils::round_;
+use ndarray::{s, Array3, ArrayView1, ArrayView3};
+use serde::{Deserialize, Serialize};
+
+/// Transformation of the candles of one coin, or of every coin if coin is None.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StressShock {
+    /// Wick of pct off candle's close: a negative pct drops the low to close * (1 + pct), a
+    /// positive one lifts the high to it. The close and later candles are untouched, so price
+    /// recovers within the candle, as in a flash crash.
+    PriceShock {
+        candle: usize,
+        pct: f64,
+        #[serde(default)]
+        coin: Option<usize>,
+    },
+    /// Scales log price deviations from the straight line between the closes bounding
+    /// candles [start, end) by factor, highs and lows moving with their closes; 0.0 leaves a
+    /// flat drift between the bounds. Prices outside the range are untouched.
+    VolatilityScale {
+        start: usize,
+        end: usize,
+        factor: f64,
+        #[serde(default)]
+        coin: Option<usize>,
+    },
+    /// Widens highs and lows away from their closes by factor over candles [start, end). The
+    /// backtest fills on wicks, so wider wicks are what wider spreads look like to it.
+    SpreadWidening {
+        start: usize,
+        end: usize,
+        factor: f64,
+        #[serde(default)]
+        coin: Option<usize>,
+    },
+}
+
+impl StressShock {
+    /// Candles [start, end) the shock changes.
+    fn candles(&self) -> (usize, usize) {
+        match *self {
+            StressShock::PriceShock { candle, .. } => (candle, candle + 1),
+            StressShock::VolatilityScale { start, end, .. }
+            | StressShock::SpreadWidening { start, end, .. } => (start, end),
+        }
+    }
+
+    fn coin(&self) -> Option<usize> {
+        match *self {
+            StressShock::PriceShock { coin, .. }
+            | StressShock::VolatilityScale { coin, .. }
+            | StressShock::SpreadWidening { coin, .. } => coin,
+        }
+    }
+
+    /// Err if the shock reaches outside n_candles or n_coins or its pct or factor is invalid.
+    pub fn validate(&self, n_candles: usize, n_coins: usize) -> Result<(), String> {
+        let (start, end) = self.candles();
+        if start >= end || end > n_candles {
+            return Err(format!(
+                "candles [{}, {}) outside the {} candles",
+                start, end, n_candles
+            ));
+        }
+        if let Some(coin) = self.coin() {
+            if coin >= n_coins {
+                return Err(format!("coin {} outside the {} coins", coin, n_coins));
+            }
+        }
+        match *self {
+            StressShock::PriceShock { pct, .. } if !(pct.is_finite() && pct > -1.0) => {
+                Err(format!("pct must be finite and above -1.0, got {}", pct))
+            }
+            StressShock::VolatilityScale { factor, .. }
+            | StressShock::SpreadWidening { factor, .. }
+                if !(factor.is_finite() && factor >= 0.0) =>
+            {
+                Err(format!(
+                    "factor must be finite and non-negative, got {}",
+                    factor
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the shock to hlcvs in place, reading the candles around its range from it.
+    pub fn apply(&self, hlcvs: &mut Array3<f64>) {
+        let coins = match self.coin() {
+            Some(coin) => coin..coin + 1,
+            None => 0..hlcvs.dim().1,
+        };
+        let (start, end) = self.candles();
+        for coin in coins {
+            match *self {
+                StressShock::PriceShock { candle, pct, .. } => {
+                    let shocked = hlcvs[[candle, coin, CLOSE]] * (1.0 + pct);
+                    if pct < 0.0 {
+                        hlcvs[[candle, coin, LOW]] = hlcvs[[candle, coin, LOW]].min(shocked);
+                    } else {
+                        hlcvs[[candle, coin, HIGH]] = hlcvs[[candle, coin, HIGH]].max(shocked);
+                    }
+                }
+                StressShock::VolatilityScale { factor, .. } => {
+                    // the bounding closes stay put; a range at either end is bounded by its own
+                    let (left, right) = (start.saturating_sub(1), end.min(hlcvs.dim().0 - 1));
+                    let (log_left, log_right) = (
+                        hlcvs[[left, coin, CLOSE]].ln(),
+                        hlcvs[[right, coin, CLOSE]].ln(),
+                    );
+                    for k in start..end {
+                        let line = if right > left {
+                            log_left
+                                + (log_right - log_left) * (k - left) as f64 / (right - left) as f64
+                        } else {
+                            log_left
+                        };
+                        let log_close = hlcvs[[k, coin, CLOSE]].ln();
+                        let scaled_close = line + factor * (log_close - line);
+                        for field in [HIGH, LOW] {
+                            let log_wick = hlcvs[[k, coin, field]].ln() - log_close;
+                            hlcvs[[k, coin, field]] = (scaled_close + factor * log_wick).exp();
+                        }
+                        hlcvs[[k, coin, CLOSE]] = scaled_close.exp();
+                    }
+                }
+                StressShock::SpreadWidening { factor, .. } => {
+                    for k in start..end {
+                        let close = hlcvs[[k, coin, CLOSE]];
+                        hlcvs[[k, coin, HIGH]] = close + (hlcvs[[k, coin, HIGH]] - close) * factor;
+                        // a wick can't reach zero
+                        hlcvs[[k, coin, LOW]] = f64::max(
+                            close - (close - hlcvs[[k, coin, LOW]]) * factor,
+                            close * 1e-3,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Named set of shocks backtested together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StressScenario {
+    pub name: String,
+    pub shocks: Vec<StressShock>,
+}
+
+impl StressScenario {
+    /// Candles [start, end) any of the shocks change; None without shocks.
+    fn candles(&self) -> Option<(usize, usize)> {
+        let start = self.shocks.iter().map(|shock| shock.candles().0).min()?;
+        let end = self.shocks.iter().map(|shock| shock.candles().1).max()?;
+        Some((start, end))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StressResult {
+    pub name: String,
+    pub analysis_usd: Analysis,
+    pub analysis_btc: Analysis,
+}
+
+/// Backtests the config on hlcvs as is, as the first row named "base", then once per scenario
+/// with its shocks applied and the shocked candles rounded to price_step. Scenarios share one
+/// working copy of hlcvs; each patches only the candles its shocks span and restores them
+/// from hlcvs after its backtest.
+pub fn run_stress_test(
+    hlcvs: &ArrayView3<f64>,
+    btc_usd_prices: &ArrayView1<f64>,
+    bot_params_pair: &BotParamsPair,
+    exchange_params_list: &[ExchangeParams],
+    backtest_params: &BacktestParams,
+    scenarios: &[StressScenario],
+) -> Result<Vec<StressResult>, String> {
+    let (n_candles, n_coins, _) = hlcvs.dim();
+    for scenario in scenarios {
+        for shock in &scenario.shocks {
+            shock
+                .validate(n_candles, n_coins)
+                .map_err(|e| format!("scenario '{}': {}", scenario.name, e))?;
+        }
+    }
+    let backtest = |hlcvs: &ArrayView3<f64>, name: &str| {
+        backtest_scenario(
+            name,
+            hlcvs,
+            btc_usd_prices,
+            bot_params_pair,
+            exchange_params_list,
+            backtest_params,
+        )
+    };
+    let mut results = vec![backtest(hlcvs, "base")];
+    let mut shocked = hlcvs.to_owned();
+    for scenario in scenarios {
+        for shock in &scenario.shocks {
+            shock.apply(&mut shocked);
+        }
+        if let Some((start, end)) = scenario.candles() {
+            // orders are priced off the candles, which must stay on price_step as exchange
+            // candles are
+            for (coin, exchange_params) in exchange_params_list.iter().enumerate() {
+                if exchange_params.price_step > 0.0 {
+                    shocked
+                        .slice_mut(s![start..end, coin, HIGH..=CLOSE])
+                        .mapv_inplace(|price| round_(price, exchange_params.price_step));
+                }
+            }
+        }
+        results.push(backtest(&shocked.view(), &scenario.name));
+        if let Some((start, end)) = scenario.candles() {
+            shocked
+                .slice_mut(s![start..end, .., ..])
+                .assign(&hlcvs.slice(s![start..end, .., ..]));
+        }
+    }
+    Ok(results)
+}
+
+fn backtest_scenario(
+    name: &str,
+    hlcvs: &ArrayView3<f64>,
+    btc_usd_prices: &ArrayView1<f64>,
+    bot_params_pair: &BotParamsPair,
+    exchange_params_list: &[ExchangeParams],
+    backtest_params: &BacktestParams,
+) -> StressResult {
+    let (evaluation, _) = evaluate_backtest(
+        &hlcvs.view(),
+        &btc_usd_prices.view(),
+        bot_params_pair.clone(),
+        exchange_params_list.to_vec(),
+        backtest_params,
+        &PruneParams::default(),
+    );
+    let (analysis_usd, analysis_btc) = evaluation.analyses;
+    StressResult {
+        name: name.to_string(),
+        analysis_usd,
+        analysis_btc,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::VOLUME;
+    use crate::synthetic::{generate_synthetic_hlcvs, SyntheticMarketSpec, SyntheticRegime};
+    use crate::types::BotParams;
+    use ndarray::Array1;
+
+    fn test_hlcvs() -> Array3<f64> {
+        let mut hlcvs = generate_synthetic_hlcvs(&SyntheticMarketSpec {
+            seed: 5,
+            n_coins: 2,
+            regimes: vec![SyntheticRegime {
+                n_candles: 1000,
+                volatility: 0.003,
+                mean_reversion: 0.01,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        // on test_exchange_params' price_step, as exchange candles are
+        hlcvs
+            .slice_mut(s![.., .., HIGH..=CLOSE])
+            .mapv_inplace(|price| round_(price, 0.001));
+        hlcvs
+    }
+
+    fn test_exchange_params() -> Vec<ExchangeParams> {
+        vec![
+            ExchangeParams {
+                qty_step: 0.001,
+                price_step: 0.001,
+                min_qty: 0.001,
+                min_cost: 1.0,
+                c_mult: 1.0,
+                ..Default::default()
+            };
+            2
+        ]
+    }
+
+    #[test]
+    fn shocks_change_only_their_candles() {
+        let hlcvs = test_hlcvs();
+        let shocked = |shock: StressShock| {
+            shock.validate(1000, 2).unwrap();
+            let mut shocked = hlcvs.clone();
+            shock.apply(&mut shocked);
+            shocked
+        };
+
+        let crashed = shocked(StressShock::PriceShock {
+            candle: 10,
+            pct: -0.3,
+            coin: Some(1),
+        });
+        assert_eq!(crashed[[10, 1, LOW]], hlcvs[[10, 1, CLOSE]] * 0.7);
+        let mut restored = crashed.clone();
+        restored[[10, 1, LOW]] = hlcvs[[10, 1, LOW]];
+        assert_eq!(restored, hlcvs);
+
+        let widened = shocked(StressShock::SpreadWidening {
+            start: 100,
+            end: 200,
+            factor: 3.0,
+            coin: None,
+        });
+        for k in [99, 150, 200] {
+            let widened_by = (widened[[k, 0, HIGH]] - widened[[k, 0, CLOSE]])
+                / (hlcvs[[k, 0, HIGH]] - hlcvs[[k, 0, CLOSE]]);
+            let expected = if k == 150 { 3.0 } else { 1.0 };
+            assert!((widened_by - expected).abs() < 1e-9);
+            assert_eq!(widened[[k, 0, CLOSE]], hlcvs[[k, 0, CLOSE]]);
+            assert_eq!(widened[[k, 0, VOLUME]], hlcvs[[k, 0, VOLUME]]);
+        }
+
+        // a factor of 0.0 leaves a straight drift between the bounding closes
+        let flattened = shocked(StressShock::VolatilityScale {
+            start: 100,
+            end: 200,
+            factor: 0.0,
+            coin: Some(0),
+        });
+        let (left, right) = (hlcvs[[99, 0, CLOSE]].ln(), hlcvs[[200, 0, CLOSE]].ln());
+        let line = |k: usize| (left + (right - left) * (k - 99) as f64 / 101.0).exp();
+        for k in [100, 150, 199] {
+            assert!((flattened[[k, 0, CLOSE]] / line(k) - 1.0).abs() < 1e-9);
+            assert_eq!(flattened[[k, 0, HIGH]], flattened[[k, 0, CLOSE]]);
+        }
+        assert_eq!(flattened.slice(s![.., 1, ..]), hlcvs.slice(s![.., 1, ..]));
+
+        for invalid in [
+            StressShock::PriceShock {
+                candle: 1000,
+                pct: -0.3,
+                coin: None,
+            },
+            StressShock::PriceShock {
+                candle: 10,
+                pct: -1.0,
+                coin: None,
+            },
+            StressShock::SpreadWidening {
+                start: 100,
+                end: 100,
+                factor: 2.0,
+                coin: None,
+            },
+            StressShock::VolatilityScale {
+                start: 100,
+                end: 200,
+                factor: 2.0,
+                coin: Some(2),
+            },
+        ] {
+            assert!(invalid.validate(1000, 2).is_err());
+        }
+    }
+
+    #[test]
+    fn each_scenario_runs_on_the_unshocked_data() {
+        let hlcvs = test_hlcvs();
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.003,
+            close_grid_qty_pct: 0.2,
+            ema_span_0: 200.0,
+            ema_span_1: 800.0,
+            entry_grid_double_down_factor: 1.0,
+            entry_grid_spacing_pct: 0.02,
+            entry_initial_ema_dist: 0.002,
+            entry_initial_qty_pct: 0.1,
+            n_positions: 2,
+            total_wallet_exposure_limit: 1.0,
+            wallet_exposure_limit: 0.5,
+            ..Default::default()
+        };
+        let bot_params_pair = BotParamsPair {
+            long: bot_params.clone(),
+            short: bot_params,
+            ..Default::default()
+        };
+        let backtest_params: BacktestParams = serde_json::from_value(serde_json::json!({
+            "starting_balance": 1000.0,
+            "maker_fee": 0.0002,
+            "coins": ["COIN0", "COIN1"],
+        }))
+        .unwrap();
+        let scenario = |name: &str, shocks: Vec<StressShock>| StressScenario {
+            name: name.to_string(),
+            shocks,
+        };
+        let results = run_stress_test(
+            &hlcvs.view(),
+            &btc_usd_prices.view(),
+            &bot_params_pair,
+            &test_exchange_params(),
+            &backtest_params,
+            &[
+                scenario(
+                    "crash",
+                    vec![StressShock::VolatilityScale {
+                        start: 300,
+                        end: 600,
+                        factor: 4.0,
+                        coin: None,
+                    }],
+                ),
+                scenario("none", Vec::new()),
+            ],
+        )
+        .unwrap();
+        let names: Vec<&str> = results.iter().map(|result| result.name.as_str()).collect();
+        assert_eq!(names, ["base", "crash", "none"]);
+        let analysis = |i: usize| serde_json::to_value(&results[i].analysis_usd).unwrap();
+        assert_ne!(analysis(1), analysis(0));
+        // the crash's candles were restored before the next scenario
+        assert_eq!(analysis(2), analysis(0));
+
+        let invalid = scenario(
+            "late",
+            vec![StressShock::PriceShock {
+                candle: 1000,
+                pct: -0.1,
+                coin: None,
+            }],
+        );
+        let err = run_stress_test(
+            &hlcvs.view(),
+            &btc_usd_prices.view(),
+            &bot_params_pair,
+            &[],
+            &backtest_params,
+            &[invalid],
+        )
+        .map(|_| ())
+        .unwrap_err();
+        assert!(err.starts_with("scenario 'late'"));
+    }
+}
@@ -0,0 +1,263 @@
+use crate::constants::{CLOSE, HIGH, LOW, VOLUME};
+use crate::rng::Rng;
+use ndarray::{s, Array3};
+use serde::{Deserialize, Serialize};
+
+/// Stretch of candles sharing one return process. Log returns per candle are drift plus
+/// volatility times a normal draw, pulled back toward the stretch's opening log price by
+/// mean_reversion (0 = plain GBM, 1 = full reversion each candle).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyntheticRegime {
+    pub n_candles: usize,
+    pub drift: f64,
+    pub volatility: f64,
+    pub mean_reversion: f64,
+    pub wick_pct: f64, // mean wick beyond the open/close range, as a fraction of price
+    pub volume: f64,   // mean volume per candle
+}
+
+impl Default for SyntheticRegime {
+    fn default() -> Self {
+        SyntheticRegime {
+            n_candles: 0,
+            drift: 0.0,
+            volatility: 0.001,
+            mean_reversion: 0.0,
+            wick_pct: 0.0005,
+            volume: 1000.0,
+        }
+    }
+}
+
+/// Price jump between the previous close and the open of candle; every coin gaps if coin is
+/// None.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyntheticGap {
+    pub candle: usize,
+    pub pct: f64,
+    #[serde(default)]
+    pub coin: Option<usize>,
+}
+
+/// Seeded description of a synthetic market; the same spec always yields the same hlcvs.
+/// Coins' returns share a common factor so any two correlate by correlation. Coins with a
+/// listing candle are flat with zero volume before it, which the backtest reads as not yet
+/// listed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyntheticMarketSpec {
+    pub seed: u64,
+    pub n_coins: usize,
+    pub start_prices: Vec<f64>, // one per coin; empty starts every coin at 100
+    pub regimes: Vec<SyntheticRegime>,
+    pub correlation: f64,
+    pub gaps: Vec<SyntheticGap>,
+    pub listing_candles: Vec<usize>, // one per coin; empty lists every coin at candle 0
+}
+
+impl Default for SyntheticMarketSpec {
+    fn default() -> Self {
+        SyntheticMarketSpec {
+            seed: 0,
+            n_coins: 1,
+            start_prices: Vec::new(),
+            regimes: Vec::new(),
+            correlation: 0.0,
+            gaps: Vec::new(),
+            listing_candles: Vec::new(),
+        }
+    }
+}
+
+impl SyntheticMarketSpec {
+    pub fn n_candles(&self) -> usize {
+        self.regimes.iter().map(|regime| regime.n_candles).sum()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.n_coins == 0 || self.n_candles() == 0 {
+            return Err("synthetic market needs at least one coin and one candle".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.correlation) {
+            return Err(format!("correlation {} outside [0, 1]", self.correlation));
+        }
+        for (name, len) in [
+            ("start_prices", self.start_prices.len()),
+            ("listing_candles", self.listing_candles.len()),
+        ] {
+            if len != 0 && len != self.n_coins {
+                return Err(format!(
+                    "{} has {} entries for {} coins",
+                    name, len, self.n_coins
+                ));
+            }
+        }
+        if !self.start_prices.iter().all(|&price| price > 0.0) {
+            return Err("start_prices must be positive".to_string());
+        }
+        for regime in &self.regimes {
+            let valid = regime.volatility >= 0.0
+                && regime.wick_pct >= 0.0
+                && regime.volume >= 0.0
+                && (0.0..=1.0).contains(&regime.mean_reversion);
+            if !valid {
+                return Err(format!("invalid synthetic regime {:?}", regime));
+            }
+        }
+        for gap in &self.gaps {
+            let valid = gap.pct > -1.0 && gap.coin.is_none_or(|coin| coin < self.n_coins);
+            if !valid {
+                return Err(format!("invalid synthetic gap {:?}", gap));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// HLCVs of shape (n_candles, n_coins, 4) generated from spec. Highs and lows extend the
+/// open/close range by an exponential wick averaging the regime's wick_pct; the open is the
+/// previous close, moved by any gap at that candle.
+pub fn generate_synthetic_hlcvs(spec: &SyntheticMarketSpec) -> Result<Array3<f64>, String> {
+    spec.validate()?;
+    let n_candles = spec.n_candles();
+    let mut hlcvs = Array3::zeros((n_candles, spec.n_coins, 4));
+    let mut rng = Rng::component(spec.seed, "synthetic_market");
+    let mut log_prices: Vec<f64> = (0..spec.n_coins)
+        .map(|idx| spec.start_prices.get(idx).copied().unwrap_or(100.0).ln())
+        .collect();
+    let mut anchors = log_prices.clone();
+    let mut k = 0;
+    for regime in &spec.regimes {
+        for i in 0..regime.n_candles {
+            let common = rng.standard_normal();
+            for idx in 0..spec.n_coins {
+                if i == 0 {
+                    anchors[idx] = log_prices[idx];
+                }
+                let own = rng.standard_normal();
+                let wicks = (rng.next_f64(), rng.next_f64());
+                let volume_draw = rng.next_f64();
+                let listing = spec.listing_candles.get(idx).copied().unwrap_or(0);
+                let mut candle = hlcvs.slice_mut(s![k, idx, ..]);
+                if k < listing {
+                    let price = log_prices[idx].exp();
+                    candle[HIGH] = price;
+                    candle[LOW] = price;
+                    candle[CLOSE] = price;
+                    continue;
+                }
+                let gap: f64 = spec
+                    .gaps
+                    .iter()
+                    .filter(|gap| gap.candle == k && gap.coin.is_none_or(|coin| coin == idx))
+                    .map(|gap| (1.0 + gap.pct).ln())
+                    .sum();
+                let open = log_prices[idx] + gap;
+                let shock =
+                    spec.correlation.sqrt() * common + (1.0 - spec.correlation).sqrt() * own;
+                let close = open
+                    + regime.drift
+                    + regime.mean_reversion * (anchors[idx] - open)
+                    + regime.volatility * shock;
+                // exponential wicks: -mean * ln(u), u in (0, 1]
+                let wick = |u: f64| -regime.wick_pct * (1.0 - u).ln();
+                candle[HIGH] = open.max(close).exp() * (1.0 + wick(wicks.0));
+                // floored so huge wicks keep lows positive
+                candle[LOW] = open.min(close).exp() * (1.0 - wick(wicks.1)).max(0.5);
+                candle[CLOSE] = close.exp();
+                candle[VOLUME] = regime.volume * (0.5 + volume_draw);
+                log_prices[idx] = close;
+            }
+            k += 1;
+        }
+    }
+    Ok(hlcvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_spec() -> SyntheticMarketSpec {
+        SyntheticMarketSpec {
+            seed: 7,
+            n_coins: 2,
+            regimes: vec![
+                SyntheticRegime {
+                    n_candles: 100,
+                    drift: 0.001,
+                    ..Default::default()
+                },
+                SyntheticRegime {
+                    n_candles: 50,
+                    volatility: 0.01,
+                    ..Default::default()
+                },
+            ],
+            gaps: vec![SyntheticGap {
+                candle: 120,
+                pct: -0.2,
+                coin: Some(0),
+            }],
+            listing_candles: vec![0, 30],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_spec_same_candles() {
+        let spec = test_spec();
+        let hlcvs = generate_synthetic_hlcvs(&spec).unwrap();
+        assert_eq!(hlcvs.dim(), (150, 2, 4));
+        assert_eq!(hlcvs, generate_synthetic_hlcvs(&spec).unwrap());
+        let reseeded = SyntheticMarketSpec { seed: 8, ..spec };
+        assert_ne!(hlcvs, generate_synthetic_hlcvs(&reseeded).unwrap());
+
+        for candle in hlcvs.slice(s![.., 0, ..]).rows() {
+            assert!(candle[LOW] > 0.0 && candle[LOW] <= candle[CLOSE]);
+            assert!(candle[CLOSE] <= candle[HIGH]);
+            assert!(candle[VOLUME] > 0.0);
+        }
+        // coin 1 is flat at its start price without volume until listed
+        for k in 0..30 {
+            let candle = hlcvs.slice(s![k, 1, ..]);
+            assert!((candle[CLOSE] - 100.0).abs() < 1e-9);
+            assert_eq!(candle[HIGH], candle[CLOSE]);
+            assert_eq!(candle[LOW], candle[CLOSE]);
+            assert_eq!(candle[VOLUME], 0.0);
+        }
+        assert!(hlcvs[[30, 1, VOLUME]] > 0.0);
+        // the gap opens coin 0 20% below the previous close
+        assert!(hlcvs[[120, 0, HIGH]] < hlcvs[[119, 0, CLOSE]] * 0.9);
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        let invalid = [
+            SyntheticMarketSpec {
+                regimes: Vec::new(),
+                ..test_spec()
+            },
+            SyntheticMarketSpec {
+                correlation: 1.5,
+                ..test_spec()
+            },
+            SyntheticMarketSpec {
+                start_prices: vec![100.0],
+                ..test_spec()
+            },
+            SyntheticMarketSpec {
+                gaps: vec![SyntheticGap {
+                    candle: 0,
+                    pct: 0.1,
+                    coin: Some(2),
+                }],
+                ..test_spec()
+            },
+        ];
+        for spec in invalid {
+            assert!(generate_synthetic_hlcvs(&spec).is_err());
+        }
+    }
+}
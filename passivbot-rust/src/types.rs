@@ -1,14 +1,49 @@
-use serde::Serialize;
-use std::collections::HashMap;
+use crate::constants::{LONG, SHORT};
+use crate::utils::{calc_ema_spans, flatten_json_paths, round_, set_json_path};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExchangeParams {
     pub qty_step: f64,
     pub price_step: f64,
     pub min_qty: f64,
     pub min_cost: f64,
     pub c_mult: f64,
+    #[serde(default)]
+    pub price_band_pct: f64, // max distance of limit orders from mark price; 0 == no limit
+    #[serde(default)]
+    pub rounding_convention: RoundingConvention,
+}
+
+/// How the venue takes prices and qtys onto its steps, applied by round_price_for_side and
+/// round_qty so emitted orders are the ones the venue accepts as sent.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingConvention {
+    /// prices and qtys to the nearest step
+    Round,
+    /// prices and qtys toward zero
+    Truncate,
+    /// prices away from the book, down for buys and up for sells; qtys to the nearest step
+    #[default]
+    TowardPassive,
+}
+
+impl std::str::FromStr for RoundingConvention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round" => Ok(RoundingConvention::Round),
+            "truncate" => Ok(RoundingConvention::Truncate),
+            "toward_passive" => Ok(RoundingConvention::TowardPassive),
+            _ => Err(format!("unknown rounding_convention '{}'", s)),
+        }
+    }
 }
 
 impl Default for ExchangeParams {
@@ -19,40 +54,336 @@ impl Default for ExchangeParams {
             min_qty: 0.00001,
             min_cost: 1.0,
             c_mult: 1.0,
+            price_band_pct: 0.0,
+            rounding_convention: RoundingConvention::default(),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+impl ExchangeParams {
+    /// Fails fast on a zero, negative or non-finite qty_step or price_step, which would
+    /// otherwise surface as NaN qtys and prices out of round_ and friends. With infer_steps,
+    /// zero steps are instead inferred from the minimums and price, the coin's typical price:
+    /// qty_step is min_qty, else the power of ten at or below min_cost / price; price_step is
+    /// the power of ten leaving price five significant digits.
+    pub fn validated(mut self, infer_steps: bool, price: f64) -> Result<Self, String> {
+        let price = if price.is_finite() && price > 0.0 {
+            Some(price)
+        } else {
+            None
+        };
+        let pow10_at_or_below = |x: f64| 10f64.powi(x.log10().floor() as i32);
+        if infer_steps && self.qty_step == 0.0 {
+            self.qty_step = match price {
+                _ if self.min_qty > 0.0 => self.min_qty,
+                Some(price) if self.min_cost > 0.0 => pow10_at_or_below(self.min_cost / price),
+                _ => return Err("cannot infer qty_step without min_qty or min_cost".to_string()),
+            };
+        }
+        if infer_steps && self.price_step == 0.0 {
+            self.price_step = match price {
+                Some(price) => pow10_at_or_below(price) * 1e-4,
+                None => return Err("cannot infer price_step without a price".to_string()),
+            };
+        }
+        for (name, step) in [("qty_step", self.qty_step), ("price_step", self.price_step)] {
+            if !(step.is_finite() && step > 0.0) {
+                return Err(format!("{} must be positive, got {}", name, step));
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BacktestParams {
     pub starting_balance: f64,
     pub maker_fee: f64,
     pub coins: Vec<String>,
+    #[serde(default)]
+    pub correlation_matrix: Vec<Vec<f64>>, // n_coins x n_coins; empty == no scaling
+    #[serde(default)]
+    pub seed: u64, // master seed of every stochastic component; see Rng::component
+    #[serde(default)]
+    pub slippage_pct: f64, // fills land up to this much worse, at random; 0.0 == exact
+    #[serde(default)]
+    pub funding_rate: f64, // per FUNDING_INTERVAL candles; > 0.0 == longs pay shorts
+    #[serde(default)]
+    pub start_hour: usize, // UTC hour of candle 0
+    #[serde(default)]
+    pub max_position_costs: BTreeMap<String, f64>, // per coin max_position_cost overrides
+    #[serde(default)]
+    pub mode_schedule: Vec<ModeSwitch>, // applied in order of k
+    #[serde(default)]
+    pub markup_floor_median_range: bool, // floor close markups at each coin's median candle range
+    #[serde(default)]
+    pub markup_floor_taker_fee: f64, // floor close markups at twice this fee; 0.0 == off
+    #[serde(default)]
+    pub impact_capacities: BTreeMap<String, f64>, // per coin quote volume per candle before impact
+    #[serde(default)]
+    pub market_impact_pct: f64, // price penalty per capacity's worth of excess volume
+    #[serde(default)]
+    pub market_slippage_buffer_pct: f64, // taker unstuck closes priced this much past the close
+    #[serde(default)]
+    pub trading_masks: BTreeMap<String, TradingMask>, // per coin; absent == both sides enabled
+    #[serde(default)]
+    pub reduce_only_drawdown_pct: f64, // equity drawdown from peak suspending entries; 0.0 == off
+    #[serde(default)]
+    pub reduce_only_recovery_pct: f64, // drawdown below which entries resume
+    #[serde(default)]
+    pub cash_flows: Vec<CashFlow>, // applied in order of k
+}
+
+/// Sides a coin may enter on. A disabled side's coin stays in the data and the selection
+/// ranking but never takes a slot, and positions already open on it only close.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TradingMask {
+    pub long_enabled: bool,
+    pub short_enabled: bool,
+}
+
+impl Default for TradingMask {
+    fn default() -> Self {
+        TradingMask {
+            long_enabled: true,
+            short_enabled: true,
+        }
+    }
+}
+
+impl TradingMask {
+    pub fn enabled(&self, pside: usize) -> bool {
+        match pside {
+            LONG => self.long_enabled,
+            _ => self.short_enabled,
+        }
+    }
+}
+
+/// The live bot's modes a backtest can switch a side into: graceful_stop places no entries
+/// but keeps closing, panic closes every position at market.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingMode {
+    #[default]
+    Normal,
+    GracefulStop,
+    Panic,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+impl std::str::FromStr for TradingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(TradingMode::Normal),
+            "graceful_stop" => Ok(TradingMode::GracefulStop),
+            "panic" => Ok(TradingMode::Panic),
+            _ => Err(format!("unknown trading mode '{}'", s)),
+        }
+    }
+}
+
+/// Switches pside, or both sides if None, into mode from candle k on.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModeSwitch {
+    pub k: usize,
+    pub pside: Option<usize>,
+    pub mode: TradingMode,
+}
+
+/// Deposit, or withdrawal if amount is negative, of quote currency at the start of candle k.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CashFlow {
+    pub k: usize,
+    pub amount: f64,
+}
+
+/// How a side's positions wound down after a switch out of normal mode.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WindDown {
+    pub pside: usize,
+    pub mode: TradingMode,
+    pub k: usize,                // candle of the switch
+    pub n_positions: usize,      // open at the switch
+    pub duration: Option<usize>, // candles until the side was flat; None == never
+    // qty weighted, of the closes since the switch against each coin's close at the switch;
+    // positive == worse than mark
+    pub slippage_pct: f64,
+}
+
+/// A stretch of the reduce-only regime tripped by reduce_only_drawdown_pct: entries
+/// suspended and close markups scaled toward breakeven.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReduceOnlyPeriod {
+    pub start: usize,       // candle of the trip
+    pub end: Option<usize>, // candle of the recovery; None == still active at the end
+    pub drawdown_max: f64,  // deepest equity drawdown from peak while active
+}
+
+/// Early abort of backtests which cannot compete: at each checkpoint, a fraction of the
+/// candles, a partial fitness above that checkpoint's threshold stops the run.
+#[derive(Clone, Debug, Default)]
+pub struct PruneParams {
+    pub checkpoints: Vec<f64>, // ascending, in (0, 1)
+    pub thresholds: Vec<f64>,  // per checkpoint; NaN == no limit
+}
+
+/// A candidate's analyses (usd, btc), truncated if it was pruned, and its partial fitnesses.
+#[derive(Clone, Debug, Default)]
+pub struct Evaluation {
+    pub analyses: (Analysis, Analysis),
+    pub partial_fitnesses: Vec<f64>,
+    pub pruned: bool,
+}
+
+/// Index of a symbol in the backtest's coin list; u32 keeps hot per-symbol maps compact.
+pub type SymbolIdx = u32;
+
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Position {
     pub size: f64,
     pub price: f64,
+    pub accrued_funding: f64, // funding paid since open, in quote; negative if received
+}
+
+impl Position {
+    /// A part of the position of the given size, carrying its share of accrued funding.
+    pub fn resized(&self, size: f64) -> Position {
+        let share = if self.size == 0.0 {
+            0.0
+        } else {
+            (size / self.size).abs()
+        };
+        Position {
+            size,
+            price: self.price,
+            accrued_funding: self.accrued_funding * share,
+        }
+    }
+
+    /// The position with its average entry price replaced by override_avg_price, if given,
+    /// so closes are priced off a user's own basis, e.g. for an imported position whose
+    /// exchange-reported price differs from their records.
+    pub fn with_avg_price(&self, override_avg_price: Option<f64>) -> Result<Position, String> {
+        match override_avg_price {
+            None => Ok(*self),
+            Some(price) if price > 0.0 && price.is_finite() => Ok(Position { price, ..*self }),
+            Some(price) => Err(format!("override_avg_price {} must be positive", price)),
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Positions {
-    pub long: HashMap<usize, Position>,
-    pub short: HashMap<usize, Position>,
+    pub long: HashMap<SymbolIdx, Position>,
+    pub short: HashMap<SymbolIdx, Position>,
 }
 
-#[derive(Debug, Default, Clone)]
+impl Positions {
+    /// Maps an exchange's position payload onto symbol indices. Zero sizes and symbols not
+    /// in snapshot.symbols are skipped; long sizes are stored positive, short sizes negative.
+    /// A repeated (symbol, pside) overwrites the earlier entry; a pside other than LONG or
+    /// SHORT is an error.
+    pub fn from_exchange_snapshot(snapshot: &ExchangeSnapshot) -> Result<Positions, String> {
+        let symbol_indices: HashMap<&str, SymbolIdx> = snapshot
+            .symbols
+            .iter()
+            .enumerate()
+            .map(|(idx, symbol)| (symbol.as_str(), idx as SymbolIdx))
+            .collect();
+        let mut positions = Positions::default();
+        for exchange_position in &snapshot.positions {
+            if exchange_position.size == 0.0 {
+                continue;
+            }
+            let idx = match symbol_indices.get(exchange_position.symbol.as_str()) {
+                Some(&idx) => idx,
+                None => continue,
+            };
+            let size = exchange_position.size.abs();
+            match exchange_position.pside {
+                LONG => positions.long.insert(
+                    idx,
+                    Position {
+                        size,
+                        price: exchange_position.price,
+                        ..Default::default()
+                    },
+                ),
+                SHORT => positions.short.insert(
+                    idx,
+                    Position {
+                        size: -size,
+                        price: exchange_position.price,
+                        ..Default::default()
+                    },
+                ),
+                _ => {
+                    return Err(format!(
+                        "unknown pside {} for {}",
+                        exchange_position.pside, exchange_position.symbol
+                    ))
+                }
+            };
+        }
+        Ok(positions)
+    }
+}
+
+/// A position as reported by the exchange. In hedge mode a symbol may appear once per pside.
+#[derive(Debug, Clone)]
+pub struct ExchangePosition {
+    pub symbol: String,
+    pub pside: usize, // LONG or SHORT
+    pub size: f64,    // signed or absolute; pside decides
+    pub price: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExchangeSnapshot {
+    pub symbols: Vec<String>, // position in this list is the SymbolIdx
+    pub positions: Vec<ExchangePosition>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct EMABands {
     pub upper: f64,
     pub lower: f64,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The per-span EMAs behind EMABands, for consumers needing more than the band edges.
+/// upper and lower are the max and min over emas.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EMABandsDetailed {
+    pub spans: [f64; 3], // ascending; emas[i] is the EMA with span spans[i]
+    pub emas: [f64; 3],
+}
+
+impl EMABandsDetailed {
+    pub fn upper(&self) -> f64 {
+        self.emas.iter().copied().fold(f64::MIN, f64::max)
+    }
+
+    pub fn lower(&self) -> f64 {
+        self.emas.iter().copied().fold(f64::MAX, f64::min)
+    }
+
+    pub fn bands(&self) -> EMABands {
+        EMABands {
+            upper: self.upper(),
+            lower: self.lower(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct Order {
     pub qty: f64,
     pub price: f64,
     pub order_type: OrderType,
+    pub iceberg_qty: Option<f64>, // visible slice of qty, same sign; None == fully visible
+    pub min_fill_qty: f64,        // fill-or-kill minimum, same sign as qty; 0.0 == any fill
 }
 
 impl Order {
@@ -61,24 +392,272 @@ impl Order {
             qty,
             price,
             order_type,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
         }
     }
 }
 
-impl Default for Order {
-    fn default() -> Self {
-        Order {
-            qty: 0.0,
-            price: 0.0,
-            order_type: OrderType::Empty,
+/// Hashable identity of an order, with price and qty normalized to integer multiples of
+/// price_step and qty_step so that float noise within one tick does not split keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OrderKey {
+    pub order_type: OrderType,
+    pub price_ticks: i64,
+    pub qty_steps: i64,
+}
+
+impl OrderKey {
+    pub fn new(order: &Order, exchange_params: &ExchangeParams) -> Self {
+        OrderKey {
+            order_type: order.order_type,
+            price_ticks: (order.price / exchange_params.price_step).round() as i64,
+            qty_steps: (order.qty / exchange_params.qty_step).round() as i64,
+        }
+    }
+}
+
+/// Exchange order filters beyond ExchangeParams' steps and minimums. Limits of 0 are no
+/// limit. Inverse contracts are worth c_mult quote per contract whatever the price.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExchangeFilters {
+    pub exchange_params: ExchangeParams, // price_band_pct is the percent-price band
+    pub max_qty: f64,
+    pub max_notional: f64,
+    pub max_open_orders: usize,
+    pub inverse: bool,
+}
+
+/// Why an exchange would reject an order; see validate_order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")] // same names as Display
+pub enum OrderRejection {
+    InvalidPrice,
+    InvalidQty,
+    PriceOffStep,
+    QtyOffStep,
+    BelowMinQty,
+    AboveMaxQty,
+    BelowMinNotional,
+    AboveMaxNotional,
+    OutsidePriceBand,
+    TooManyOpenOrders,
+}
+
+impl fmt::Display for OrderRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderRejection::InvalidPrice => write!(f, "invalid_price"),
+            OrderRejection::InvalidQty => write!(f, "invalid_qty"),
+            OrderRejection::PriceOffStep => write!(f, "price_off_step"),
+            OrderRejection::QtyOffStep => write!(f, "qty_off_step"),
+            OrderRejection::BelowMinQty => write!(f, "below_min_qty"),
+            OrderRejection::AboveMaxQty => write!(f, "above_max_qty"),
+            OrderRejection::BelowMinNotional => write!(f, "below_min_notional"),
+            OrderRejection::AboveMaxNotional => write!(f, "above_max_notional"),
+            OrderRejection::OutsidePriceBand => write!(f, "outside_price_band"),
+            OrderRejection::TooManyOpenOrders => write!(f, "too_many_open_orders"),
+        }
+    }
+}
+
+// Price and Qty share everything but the name of their count
+macro_rules! step_count_type {
+    ($(#[$doc:meta])* $name:ident, $count:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name {
+            $count: i64,
+            step: f64,
+        }
+
+        impl $name {
+            /// None if value is not finite, step is not positive or value / step overflows i64.
+            pub fn from_f64(value: f64, step: f64) -> Option<Self> {
+                let count = (value / step).round();
+                if step.is_nan() || step <= 0.0 || !count.is_finite() || count.abs() >= i64::MAX as f64 {
+                    return None;
+                }
+                Some($name {
+                    $count: count as i64,
+                    step,
+                })
+            }
+
+            pub fn to_f64(self) -> f64 {
+                round_(self.$count as f64 * self.step, self.step)
+            }
+
+            /// Moves by n steps.
+            pub fn offset(self, n: i64) -> Self {
+                $name {
+                    $count: self.$count + n,
+                    ..self
+                }
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                debug_assert_eq!(self.step, other.step);
+                self.$count == other.$count
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl PartialOrd for $name {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for $name {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                debug_assert_eq!(self.step, other.step);
+                self.$count.cmp(&other.$count)
+            }
+        }
+
+        impl std::ops::Add for $name {
+            type Output = Self;
+
+            fn add(self, other: Self) -> Self {
+                debug_assert_eq!(self.step, other.step);
+                self.offset(other.$count)
+            }
+        }
+
+        impl std::ops::Sub for $name {
+            type Output = Self;
+
+            fn sub(self, other: Self) -> Self {
+                debug_assert_eq!(self.step, other.step);
+                self.offset(-other.$count)
+            }
+        }
+
+        impl std::ops::Neg for $name {
+            type Output = Self;
+
+            fn neg(self) -> Self {
+                $name {
+                    $count: -self.$count,
+                    ..self
+                }
+            }
+        }
+    };
+}
+
+step_count_type!(
+    /// A price as a whole number of price_step ticks. Ladder construction compares and
+    /// accumulates prices in ticks so float drift cannot split or duplicate rungs.
+    Price,
+    ticks
+);
+
+impl Price {
+    pub fn ticks(self) -> i64 {
+        self.ticks
+    }
+
+    pub fn step(self) -> f64 {
+        self.step
+    }
+}
+
+step_count_type!(
+    /// A qty as a whole number of qty_step steps; see Price.
+    Qty,
+    steps
+);
+
+impl Qty {
+    pub fn is_zero(self) -> bool {
+        self.steps == 0
+    }
+}
+
+/// Reduce-only limit close paired with a market order which the exchange layer places only if
+/// the limit has not filled after fallback_candles.
+#[derive(Debug, Clone, Copy)]
+pub struct CloseWithFallback {
+    pub limit: Order,
+    pub fallback: Order,
+    pub fallback_candles: usize,
+}
+
+/// A GTC close the exchange layer cancels, and recalculates, after expiry_candles.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StaggeredClose {
+    pub order: Order,
+    pub expiry_candles: usize,
+}
+
+/// Outcome of a single-order calculator.
+#[derive(Debug, Clone, Copy)]
+pub enum NextOrder {
+    Order(Order),
+    /// trailing is in effect but its trigger conditions are not met yet
+    TrailingPending,
+    NoOrder,
+}
+
+impl NextOrder {
+    pub fn order(self) -> Option<Order> {
+        match self {
+            NextOrder::Order(order) => Some(order),
+            NextOrder::TrailingPending | NextOrder::NoOrder => None,
         }
     }
 }
 
+impl From<Option<Order>> for NextOrder {
+    fn from(order: Option<Order>) -> Self {
+        order.map_or(NextOrder::NoOrder, NextOrder::Order)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct OrderBook {
     pub bid: f64,
     pub ask: f64,
+    pub levels: Vec<(f64, f64)>, // optional depth as (price, qty), bids and asks alike
+}
+
+impl OrderBook {
+    pub fn new(bid: f64, ask: f64) -> Self {
+        OrderBook {
+            bid,
+            ask,
+            levels: Vec::new(),
+        }
+    }
+
+    pub fn mid(&self) -> f64 {
+        (self.bid + self.ask) / 2.0
+    }
+
+    /// Spread as a fraction of mid; 0.0 if there is no valid mid.
+    pub fn spread_pct(&self) -> f64 {
+        let mid = self.mid();
+        if mid > 0.0 {
+            (self.ask - self.bid) / mid
+        } else {
+            0.0
+        }
+    }
+
+    /// Total qty resting at levels within mid * (1 ± pct), both sides together.
+    pub fn depth_within_pct(&self, pct: f64) -> f64 {
+        let mid = self.mid();
+        self.levels
+            .iter()
+            .filter(|&&(price, _)| (price - mid).abs() <= mid * pct)
+            .map(|&(_, qty)| qty.abs())
+            .sum()
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -86,23 +665,175 @@ pub struct StateParams {
     pub balance: f64,
     pub order_book: OrderBook,
     pub ema_bands: EMABands,
+    pub trailing_ma: f64, // line followed by trailing closes anchored to a moving average
+    pub volume: f64,      // current candle's
+    pub avg_volume: f64,  // average candle quote volume; 0.0 == unknown
+    pub timestamp: u64,   // ms; now, for judging target_price's staleness
+    pub target_price: Option<(f64, u64)>, // external close target as (price, timestamp ms)
+    pub hour: usize,      // UTC hour of day, 0..24; see liquidity_profile
+    pub realized_pnl: f64, // caller's; see compound_realized_into_balance
+    pub equity: f64,      // balance plus unrealized pnl; 0.0 == unknown
+    pub peak_equity: f64, // highest equity so far; see close_on_equity_drawdown_pct
+    pub min_markup_floor: f64, // symbol's floor under close_grid_min_markup; 0.0 == none
+    // taker buy minus sell volume over their sum, in [-1, 1]; None == unknown
+    pub order_flow_imbalance: Option<f64>,
+    // share of the grid close markups kept, on top of any flow scaling; None == 1.0
+    pub close_markup_scale: Option<f64>,
 }
 
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct BotParamsPair {
     pub long: BotParams,
     pub short: BotParams,
+    #[serde(default)]
+    pub long_allocation_pct: f64, // share of balance long sizes against; 0.0 == all of it
+    #[serde(default)]
+    pub short_allocation_pct: f64,
+}
+
+impl BotParamsPair {
+    /// The balance pside's entries, closes and unstucking are sized against, so one side's
+    /// drawdown can't eat the other's headroom. An allocation of 0.0 means the whole balance.
+    pub fn allocated_balance(&self, balance: f64, pside: usize) -> f64 {
+        let allocation_pct = match pside {
+            LONG => self.long_allocation_pct,
+            SHORT => self.short_allocation_pct,
+            _ => panic!("unknown pside {}", pside),
+        };
+        if allocation_pct > 0.0 {
+            balance * allocation_pct
+        } else {
+            balance
+        }
+    }
+
+    /// Fails on an allocation outside [0, 1] or allocations summing to more than 1.
+    pub fn validate_allocations(&self) -> Result<(), String> {
+        for (name, pct) in [
+            ("long_allocation_pct", self.long_allocation_pct),
+            ("short_allocation_pct", self.short_allocation_pct),
+        ] {
+            if !(0.0..=1.0).contains(&pct) {
+                return Err(format!("{} must be within [0, 1], got {}", name, pct));
+            }
+        }
+        if self.long_allocation_pct + self.short_allocation_pct > 1.0 + f64::EPSILON {
+            return Err(format!(
+                "long_allocation_pct + short_allocation_pct must not exceed 1, got {}",
+                self.long_allocation_pct + self.short_allocation_pct
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails on an entry or unstuck EMA span below 1.0, other than the 0.0 of params whose
+    /// EMAs go unused, e.g. with ema bands given.
+    pub fn validate_ema_spans(&self) -> Result<(), String> {
+        for (pside, bot_params) in [("long", &self.long), ("short", &self.short)] {
+            for (name, span) in [
+                ("ema_span_0", bot_params.ema_span_0),
+                ("ema_span_1", bot_params.ema_span_1),
+                ("unstuck_ema_span_0", bot_params.unstuck_ema_span_0),
+                ("unstuck_ema_span_1", bot_params.unstuck_ema_span_1),
+            ] {
+                if !((span == 0.0 || span >= 1.0) && span.is_finite()) {
+                    return Err(format!(
+                        "{}.{} must be at least 1.0, got {}",
+                        pside, name, span
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parameters which differ from `other`, as (dotted.path, old, new), e.g.
+    /// ("long.close_grid_qty_pct", 0.5, 0.6).
+    pub fn diff(&self, other: &BotParamsPair) -> Vec<(String, Value, Value)> {
+        let old = serde_json::to_value(self).expect("BotParamsPair serializes");
+        let new = serde_json::to_value(other).expect("BotParamsPair serializes");
+        flatten_json_paths(&old)
+            .into_iter()
+            .zip(flatten_json_paths(&new))
+            .filter(|((_, old_value), (_, new_value))| old_value != new_value)
+            .map(|((path, old_value), (_, new_value))| (path, old_value, new_value))
+            .collect()
+    }
+
+    /// Applies (dotted.path, value) updates; all or nothing.
+    pub fn merge_partial(&mut self, updates: &[(String, Value)]) -> Result<(), String> {
+        let mut merged = serde_json::to_value(&*self).expect("BotParamsPair serializes");
+        for (path, value) in updates {
+            set_json_path(&mut merged, path, value.clone())?;
+        }
+        *self = serde_json::from_value(merged).map_err(|e| e.to_string())?;
+        Ok(())
+    }
 }
 
-#[derive(Clone, Default, Debug)]
+/// What the trailing close retraces from: the price peak (trough for shorts) since the
+/// position opened, or StateParams.trailing_ma.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseTrailingAnchor {
+    #[default]
+    Peak,
+    MovingAverage,
+}
+
+impl std::str::FromStr for CloseTrailingAnchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "peak" => Ok(CloseTrailingAnchor::Peak),
+            "moving_average" => Ok(CloseTrailingAnchor::MovingAverage),
+            _ => Err(format!("unknown close_trailing_anchor '{}'", s)),
+        }
+    }
+}
+
+// fields absent from older configs and saved results take their zero value, leaving the
+// features added since then off
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BotParams {
+    pub auto_reduce_enabled: bool,
+    pub auto_reduce_tolerance_pct: f64, // exposure over the limit left alone by auto-reduce
+    pub balance_allocation_pct: f64,    // share of balance closes are sized against; 0.0 == all
+    pub close_before_funding: bool,     // trim the side paying funding ahead of settlement
+    pub close_before_funding_minutes: f64, // window before settlement to trim in
+    pub close_before_funding_pct: f64,  // share of the position trimmed
+    pub close_credit_ladder_pnl: bool,  // levels size against balance + pnl of those before
     pub close_grid_markup_range: f64,
     pub close_grid_min_markup: f64,
     pub close_grid_qty_pct: f64,
+    pub close_grid_qty_ratio: f64, // each close grid level's qty over the previous; 0.0 == flat
+    pub close_iceberg_visible_qty: f64, // shown qty of each larger grid close; 0.0 == disabled
+    pub close_max_qty_pct_of_volume: f64, // of average candle quote volume; 0.0 == no cap
+    pub close_nearest_taker: bool,
+    pub close_on_equity_drawdown_pct: f64, // drawdown from peak_equity closing all; 0.0 == off
+    pub close_on_flow_imbalance: f64,      // flow against positions tightening closes; 0.0 == off
+    pub close_recover_funding: bool,       // widen close markups by Position.accrued_funding
+    pub close_require_volume: bool,
+    pub close_taker_threshold_pct: f64, // nearest close's max distance to be taker; 0.0 == any
+    pub close_touch_qty_pct: f64,       // share of each grid close taken at the touch; 0.0 == off
+    pub close_trailing_anchor: CloseTrailingAnchor,
+    pub close_trailing_fast_qty_pct: f64, // 0.0 == fast leg disabled
+    pub close_trailing_fast_retracement_pct: f64,
+    pub close_trailing_fast_threshold_pct: f64,
+    pub close_trailing_fib_levels: Vec<f64>, // retracements of entry..peak to close at; [] == off
+    pub close_trailing_fib_qty_pct: f64,     // closed per Fibonacci level
     pub close_trailing_retracement_pct: f64,
     pub close_trailing_grid_ratio: f64,
+    pub close_trailing_max_candles_since_peak: usize, // 0 == disabled
     pub close_trailing_qty_pct: f64,
+    pub close_trailing_slow_qty_pct: f64, // 0.0 == slow leg disabled
+    pub close_trailing_slow_retracement_pct: f64,
+    pub close_trailing_slow_threshold_pct: f64,
+    pub close_trailing_step_multiple: f64, // stop moves in price_step multiples; 0.0 == per tick
     pub close_trailing_threshold_pct: f64,
+    pub compound_realized_into_balance: bool, // closes size against balance + realized_pnl
     pub enforce_exposure_limit: bool,
     pub entry_grid_double_down_factor: f64,
     pub entry_grid_spacing_weight: f64,
@@ -118,22 +849,220 @@ pub struct BotParams {
     pub filter_volume_drop_pct: f64,
     pub ema_span_0: f64,
     pub ema_span_1: f64,
+    pub liquidity_profile: Vec<f64>, // grid close qty multiplier per UTC hour; [] == off
+    pub max_position_cost: f64,      // quote cost cap of a full position; 0.0 == none
+    pub min_close_volume: f64,       // candle volume confirming upper grid closes
+    pub mirror_entries: bool,        // close ladder mirrors the entry grid above pprice
     pub n_positions: usize,
+    pub neutral_mode: bool, // with both sides set, hold equal long and short sizes per coin
+    pub rebalance_threshold_pct: f64, // neutral size gap, over the larger side, to rebalance at
+    pub recenter_trailing_on_partial_close: bool, // partial grid closes restart trailing at fill
+    pub simulate_post_only_reject: bool, // drop grid closes crossing the book, as live
+    pub target_max_staleness_ms: u64, // oldest StateParams.target_price to close at; 0 == off
     pub total_wallet_exposure_limit: f64,
     pub wallet_exposure_limit: f64, // is total_wallet_exposure_limit / n_positions
     pub unstuck_close_pct: f64,
     pub unstuck_ema_dist: f64,
+    pub unstuck_ema_span_0: f64, // unstuck bands' spans, as ema_span_0 and ema_span_1 are entries'
+    pub unstuck_ema_span_1: f64,
     pub unstuck_loss_allowance_pct: f64,
+    pub unstuck_max_allowance_fraction: f64, // of the remaining allowance per close; 0.0 == all
+    pub unstuck_require_profit_buffer_pct: f64, // 0.0 == no buffer
+    pub unstuck_rotation_tolerance: f64, // pprice_diff spread of stuck positions to rotate; 0.0 == off
     pub unstuck_threshold: f64,
 }
 
-#[derive(Debug)]
+impl BotParams {
+    /// The slice of balance allotted to this config when several share one account.
+    /// balance_allocation_pct outside (0, 1) means the whole balance.
+    pub fn allocated_balance(&self, balance: f64) -> f64 {
+        if self.balance_allocation_pct > 0.0 && self.balance_allocation_pct < 1.0 {
+            balance * self.balance_allocation_pct
+        } else {
+            balance
+        }
+    }
+
+    /// The balance closes are sized against: the allocated share of state_params.balance,
+    /// with state_params.realized_pnl reinvested first when compound_realized_into_balance
+    /// is set, so wallet exposure ratios shrink as profits accrue.
+    pub fn close_balance(&self, state_params: &StateParams) -> f64 {
+        if self.compound_realized_into_balance {
+            self.allocated_balance(state_params.balance + state_params.realized_pnl)
+        } else {
+            self.allocated_balance(state_params.balance)
+        }
+    }
+
+    /// wallet_exposure_limit, lowered so that a full position at balance costs no more than
+    /// max_position_cost, as positions outgrowing their order book can't be exited.
+    pub fn capped_wallet_exposure_limit(&self, balance: f64) -> f64 {
+        if self.max_position_cost > 0.0 && balance > 0.0 {
+            self.wallet_exposure_limit
+                .min(self.max_position_cost / balance)
+        } else {
+            self.wallet_exposure_limit
+        }
+    }
+
+    /// These params with capped_wallet_exposure_limit at balance, borrowed unless it binds.
+    pub fn with_position_cost_cap(&self, balance: f64) -> Cow<'_, BotParams> {
+        let wallet_exposure_limit = self.capped_wallet_exposure_limit(balance);
+        if wallet_exposure_limit < self.wallet_exposure_limit {
+            Cow::Owned(BotParams {
+                wallet_exposure_limit,
+                ..self.clone()
+            })
+        } else {
+            Cow::Borrowed(self)
+        }
+    }
+
+    /// close_grid_min_markup raised to a symbol's noise floor, e.g. its typical spread and
+    /// fees; see StateParams.min_markup_floor.
+    pub fn effective_close_grid_min_markup(&self, min_markup_floor: f64) -> f64 {
+        self.close_grid_min_markup.max(min_markup_floor)
+    }
+
+    /// Whether equity has fallen close_on_equity_drawdown_pct from peak_equity, so every
+    /// position exits on the drawdown grid; see calc_drawdown_closes_long.
+    pub fn equity_drawdown_triggered(&self, state_params: &StateParams) -> bool {
+        self.close_on_equity_drawdown_pct > 0.0
+            && state_params.equity > 0.0
+            && state_params.equity
+                < state_params.peak_equity * (1.0 - self.close_on_equity_drawdown_pct)
+    }
+
+    /// Share of the grid close markups kept under order flow against pside's position: 1.0
+    /// until the imbalance passes close_on_flow_imbalance against it, aggressive sells for
+    /// longs and buys for shorts, then shrinking linearly to 0.0 at one-sided flow.
+    pub fn flow_imbalance_markup_scale(&self, state_params: &StateParams, pside: usize) -> f64 {
+        let threshold = self.close_on_flow_imbalance;
+        let imbalance = match state_params.order_flow_imbalance {
+            Some(imbalance) if imbalance.is_finite() && threshold > 0.0 && threshold < 1.0 => {
+                imbalance.clamp(-1.0, 1.0)
+            }
+            _ => return 1.0,
+        };
+        let against = if pside == LONG { -imbalance } else { imbalance };
+        if against <= threshold {
+            1.0
+        } else {
+            1.0 - (against - threshold) / (1.0 - threshold)
+        }
+    }
+
+    /// Grid closes in the upper half of the markup range are deferred until a candle's volume
+    /// exceeds min_close_volume, when close_require_volume is set.
+    pub fn close_volume_confirmed(&self, volume: f64) -> bool {
+        !self.close_require_volume || volume > self.min_close_volume
+    }
+
+    /// liquidity_profile's multiplier for UTC hour; 1.0 for hours it doesn't cover.
+    pub fn liquidity_multiplier(&self, hour: usize) -> f64 {
+        self.liquidity_profile
+            .get(hour)
+            .map_or(1.0, |&multiplier| multiplier.max(0.0))
+    }
+
+    /// [ema_span_0, sqrt(ema_span_0 * ema_span_1), ema_span_1] in ascending order.
+    pub fn ema_spans_sorted(&self) -> [f64; 3] {
+        calc_ema_spans(self.ema_span_0, self.ema_span_1)
+    }
+
+    /// ema_spans_sorted of the unstuck spans.
+    pub fn unstuck_ema_spans_sorted(&self) -> [f64; 3] {
+        calc_ema_spans(self.unstuck_ema_span_0, self.unstuck_ema_span_1)
+    }
+
+    /// close_grid_qty_pct outside [0, 1) means closing the whole position in one order, 1.0.
+    pub fn close_grid_qty_pct_clamped(&self) -> f64 {
+        if self.close_grid_qty_pct < 0.0 || self.close_grid_qty_pct >= 1.0 {
+            1.0
+        } else {
+            self.close_grid_qty_pct
+        }
+    }
+
+    pub fn close_trailing_grid_ratio_clamped(&self) -> f64 {
+        self.close_trailing_grid_ratio.clamp(-1.0, 1.0)
+    }
+
+    pub fn entry_trailing_grid_ratio_clamped(&self) -> f64 {
+        self.entry_trailing_grid_ratio.clamp(-1.0, 1.0)
+    }
+
+    pub fn close_trailing_grid_split(&self) -> TrailingGridSplit {
+        TrailingGridSplit::from_ratio(self.close_trailing_grid_ratio_clamped())
+    }
+
+    pub fn entry_trailing_grid_split(&self) -> TrailingGridSplit {
+        TrailingGridSplit::from_ratio(self.entry_trailing_grid_ratio_clamped())
+    }
+
+    /// Whether the fast, slow or Fibonacci trailing close leg is configured.
+    pub fn close_trailing_legs_enabled(&self) -> bool {
+        self.close_trailing_fast_qty_pct > 0.0
+            || self.close_trailing_slow_qty_pct > 0.0
+            || self.close_trailing_fib_enabled()
+    }
+
+    pub fn close_trailing_fib_enabled(&self) -> bool {
+        !self.close_trailing_fib_levels.is_empty() && self.close_trailing_fib_qty_pct > 0.0
+    }
+}
+
+/// How a *_trailing_grid_ratio divides wallet_exposure_limit between trailing and grid orders.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailingGridSplit {
+    GridOnly,
+    TrailingOnly,
+    /// trailing orders up to this share of wallet_exposure_limit, grid orders beyond
+    TrailingFirst(f64),
+    /// grid orders up to this share of wallet_exposure_limit, trailing orders beyond
+    GridFirst(f64),
+}
+
+impl TrailingGridSplit {
+    /// ratio >= 1 or <= -1 is trailing only, 0 is grid only, 0 < ratio < 1 trails first up to
+    /// ratio and -1 < ratio < 0 grids first up to 1 + ratio.
+    pub fn from_ratio(ratio: f64) -> Self {
+        if ratio >= 1.0 || ratio <= -1.0 {
+            TrailingGridSplit::TrailingOnly
+        } else if ratio == 0.0 {
+            TrailingGridSplit::GridOnly
+        } else if ratio > 0.0 {
+            TrailingGridSplit::TrailingFirst(ratio)
+        } else {
+            TrailingGridSplit::GridFirst(1.0 + ratio)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TrailingPriceBundle {
     pub min_since_open: f64,
     pub max_since_min: f64,
     pub max_since_open: f64,
     pub min_since_max: f64,
+    pub candles_since_min: usize,
+    pub candles_since_max: usize,
+    pub fib_levels_closed: usize, // close_trailing_fib_levels filled since the peak (trough)
+    pub stepped_stop_price: f64,  // last stop with close_trailing_step_multiple; 0.0 == none
 }
+impl TrailingPriceBundle {
+    /// A bundle whose extremes all sit at price, as if the position opened there.
+    pub fn centered(price: f64) -> Self {
+        TrailingPriceBundle {
+            min_since_open: price,
+            max_since_min: price,
+            max_since_open: price,
+            min_since_max: price,
+            ..Default::default()
+        }
+    }
+}
+
 impl Default for TrailingPriceBundle {
     fn default() -> Self {
         TrailingPriceBundle {
@@ -141,11 +1070,16 @@ impl Default for TrailingPriceBundle {
             max_since_min: 0.0,
             max_since_open: 0.0,
             min_since_max: f64::MAX,
+            candles_since_min: 0,
+            candles_since_max: 0,
+            fib_levels_closed: 0,
+            stepped_stop_price: 0.0,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")] // same names as Display
 pub enum OrderType {
     EntryInitialNormalLong,
     EntryInitialPartialLong,
@@ -154,11 +1088,22 @@ pub enum OrderType {
     EntryGridNormalLong,
     EntryGridCroppedLong,
     EntryGridInflatedLong,
+    EntryRebalanceLong,
 
     CloseGridLong,
     CloseTrailingLong,
+    CloseTrailingFastLong,
+    CloseTrailingSlowLong,
+    CloseTrailingFibLong,
     CloseUnstuckLong,
     CloseAutoReduceLong,
+    CloseFallbackMarketLong,
+    CloseTakerLong,
+    CloseRebalanceLong,
+    ClosePanicLong,
+    CloseDrawdownLong,
+    CloseMarginTargetLong,
+    CloseFundingWindowLong,
 
     EntryInitialNormalShort,
     EntryInitialPartialShort,
@@ -167,13 +1112,110 @@ pub enum OrderType {
     EntryGridNormalShort,
     EntryGridCroppedShort,
     EntryGridInflatedShort,
+    EntryRebalanceShort,
 
     CloseGridShort,
     CloseTrailingShort,
+    CloseTrailingFastShort,
+    CloseTrailingSlowShort,
+    CloseTrailingFibShort,
     CloseUnstuckShort,
     CloseAutoReduceShort,
+    CloseFallbackMarketShort,
+    CloseTakerShort,
+    CloseRebalanceShort,
+    ClosePanicShort,
+    CloseDrawdownShort,
+    CloseMarginTargetShort,
+    CloseFundingWindowShort,
+}
 
-    Empty,
+impl OrderType {
+    /// LONG or SHORT, the position side the order belongs to.
+    pub fn pside(self) -> usize {
+        use OrderType::*;
+        match self {
+            EntryInitialNormalLong
+            | EntryInitialPartialLong
+            | EntryTrailingNormalLong
+            | EntryTrailingCroppedLong
+            | EntryGridNormalLong
+            | EntryGridCroppedLong
+            | EntryGridInflatedLong
+            | EntryRebalanceLong
+            | CloseGridLong
+            | CloseTrailingLong
+            | CloseTrailingFastLong
+            | CloseTrailingSlowLong
+            | CloseTrailingFibLong
+            | CloseUnstuckLong
+            | CloseAutoReduceLong
+            | CloseFallbackMarketLong
+            | CloseTakerLong
+            | CloseRebalanceLong
+            | ClosePanicLong
+            | CloseDrawdownLong
+            | CloseMarginTargetLong
+            | CloseFundingWindowLong => LONG,
+            EntryInitialNormalShort
+            | EntryInitialPartialShort
+            | EntryTrailingNormalShort
+            | EntryTrailingCroppedShort
+            | EntryGridNormalShort
+            | EntryGridCroppedShort
+            | EntryGridInflatedShort
+            | EntryRebalanceShort
+            | CloseGridShort
+            | CloseTrailingShort
+            | CloseTrailingFastShort
+            | CloseTrailingSlowShort
+            | CloseTrailingFibShort
+            | CloseUnstuckShort
+            | CloseAutoReduceShort
+            | CloseFallbackMarketShort
+            | CloseTakerShort
+            | CloseRebalanceShort
+            | ClosePanicShort
+            | CloseDrawdownShort
+            | CloseMarginTargetShort
+            | CloseFundingWindowShort => SHORT,
+        }
+    }
+
+    pub fn is_close(self) -> bool {
+        use OrderType::*;
+        matches!(
+            self,
+            CloseGridLong
+                | CloseTrailingLong
+                | CloseTrailingFastLong
+                | CloseTrailingSlowLong
+                | CloseTrailingFibLong
+                | CloseUnstuckLong
+                | CloseAutoReduceLong
+                | CloseFallbackMarketLong
+                | CloseTakerLong
+                | CloseRebalanceLong
+                | ClosePanicLong
+                | CloseDrawdownLong
+                | CloseMarginTargetLong
+                | CloseFundingWindowLong
+                | CloseGridShort
+                | CloseTrailingShort
+                | CloseTrailingFastShort
+                | CloseTrailingSlowShort
+                | CloseTrailingFibShort
+                | CloseUnstuckShort
+                | CloseAutoReduceShort
+                | CloseFallbackMarketShort
+                | CloseTakerShort
+                | CloseRebalanceShort
+                | ClosePanicShort
+                | CloseDrawdownShort
+                | CloseMarginTargetShort
+                | CloseFundingWindowShort
+        )
+    }
 }
 
 impl fmt::Display for OrderType {
@@ -186,10 +1228,21 @@ impl fmt::Display for OrderType {
             OrderType::EntryGridNormalLong => write!(f, "entry_grid_normal_long"),
             OrderType::EntryGridCroppedLong => write!(f, "entry_grid_cropped_long"),
             OrderType::EntryGridInflatedLong => write!(f, "entry_grid_inflated_long"),
+            OrderType::EntryRebalanceLong => write!(f, "entry_rebalance_long"),
             OrderType::CloseGridLong => write!(f, "close_grid_long"),
             OrderType::CloseTrailingLong => write!(f, "close_trailing_long"),
+            OrderType::CloseTrailingFastLong => write!(f, "close_trailing_fast_long"),
+            OrderType::CloseTrailingSlowLong => write!(f, "close_trailing_slow_long"),
+            OrderType::CloseTrailingFibLong => write!(f, "close_trailing_fib_long"),
             OrderType::CloseUnstuckLong => write!(f, "close_unstuck_long"),
             OrderType::CloseAutoReduceLong => write!(f, "close_auto_reduce_long"),
+            OrderType::CloseFallbackMarketLong => write!(f, "close_fallback_market_long"),
+            OrderType::CloseTakerLong => write!(f, "close_taker_long"),
+            OrderType::CloseRebalanceLong => write!(f, "close_rebalance_long"),
+            OrderType::ClosePanicLong => write!(f, "close_panic_long"),
+            OrderType::CloseDrawdownLong => write!(f, "close_drawdown_long"),
+            OrderType::CloseMarginTargetLong => write!(f, "close_margin_target_long"),
+            OrderType::CloseFundingWindowLong => write!(f, "close_funding_window_long"),
             OrderType::EntryInitialNormalShort => write!(f, "entry_initial_normal_short"),
             OrderType::EntryInitialPartialShort => write!(f, "entry_initial_partial_short"),
             OrderType::EntryTrailingNormalShort => write!(f, "entry_trailing_normal_short"),
@@ -197,11 +1250,21 @@ impl fmt::Display for OrderType {
             OrderType::EntryGridNormalShort => write!(f, "entry_grid_normal_short"),
             OrderType::EntryGridCroppedShort => write!(f, "entry_grid_cropped_short"),
             OrderType::EntryGridInflatedShort => write!(f, "entry_grid_inflated_short"),
+            OrderType::EntryRebalanceShort => write!(f, "entry_rebalance_short"),
             OrderType::CloseGridShort => write!(f, "close_grid_short"),
             OrderType::CloseTrailingShort => write!(f, "close_trailing_short"),
+            OrderType::CloseTrailingFastShort => write!(f, "close_trailing_fast_short"),
+            OrderType::CloseTrailingSlowShort => write!(f, "close_trailing_slow_short"),
+            OrderType::CloseTrailingFibShort => write!(f, "close_trailing_fib_short"),
             OrderType::CloseUnstuckShort => write!(f, "close_unstuck_short"),
             OrderType::CloseAutoReduceShort => write!(f, "close_auto_reduce_short"),
-            OrderType::Empty => write!(f, "empty"),
+            OrderType::CloseFallbackMarketShort => write!(f, "close_fallback_market_short"),
+            OrderType::CloseTakerShort => write!(f, "close_taker_short"),
+            OrderType::CloseRebalanceShort => write!(f, "close_rebalance_short"),
+            OrderType::ClosePanicShort => write!(f, "close_panic_short"),
+            OrderType::CloseDrawdownShort => write!(f, "close_drawdown_short"),
+            OrderType::CloseMarginTargetShort => write!(f, "close_margin_target_short"),
+            OrderType::CloseFundingWindowShort => write!(f, "close_funding_window_short"),
         }
     }
 }
@@ -216,13 +1279,21 @@ pub struct Balance {
     pub use_btc_collateral: bool, // whether to use btc as collateral
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct Equities {
     pub usd: Vec<f64>,
     pub btc: Vec<f64>,
 }
 
-#[derive(Debug, Clone)]
+/// An equity curve thinned for plotting; see downsample_equities.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct EquityDownsample {
+    pub indices: Vec<usize>, // ascending, into the full curve
+    pub values: Vec<f64>,
+    pub bucket_bounds: Vec<usize>, // bucket i spans bucket_bounds[i]..bucket_bounds[i + 1]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Fill {
     pub index: usize,
     pub coin: String,
@@ -237,6 +1308,7 @@ pub struct Fill {
     pub position_size: f64,
     pub position_price: f64,
     pub order_type: OrderType,
+    pub impact_pct: f64, // fill price moved this much against the fill by market impact
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -281,6 +1353,18 @@ pub struct Analysis {
     pub loss_profit_ratio_w: f64,
     pub volume_pct_per_day_avg: f64,
     pub volume_pct_per_day_avg_w: f64,
+
+    // wallet exposure per candle, time weighted; see calc_exposure_distributions
+    pub exposure_mean_long: f64,
+    pub exposure_p50_long: f64,
+    pub exposure_p90_long: f64,
+    pub exposure_max_long: f64,
+    pub exposure_mean_short: f64,
+    pub exposure_p50_short: f64,
+    pub exposure_p90_short: f64,
+    pub exposure_max_short: f64,
+    pub return_on_deployed_margin: f64, // adg over the mean total exposure; 0.0 if never deployed
+    pub n_cost_capped_fills: f64,       // entry fills bound by max_position_cost
 }
 
 impl Default for Analysis {
@@ -323,6 +1407,311 @@ impl Default for Analysis {
             exponential_fit_error_w: 1.0,
             volume_pct_per_day_avg: 0.0,
             volume_pct_per_day_avg_w: 0.0,
+            exposure_mean_long: 0.0,
+            exposure_p50_long: 0.0,
+            exposure_p90_long: 0.0,
+            exposure_max_long: 0.0,
+            exposure_mean_short: 0.0,
+            exposure_p50_short: 0.0,
+            exposure_p90_short: 0.0,
+            exposure_max_short: 0.0,
+            return_on_deployed_margin: 0.0,
+            n_cost_capped_fills: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn exchange_position(symbol: &str, pside: usize, size: f64, price: f64) -> ExchangePosition {
+        ExchangePosition {
+            symbol: symbol.to_string(),
+            pside,
+            size,
+            price,
+        }
+    }
+
+    #[test]
+    fn zero_steps_are_rejected_unless_inferred() {
+        let exchange_params = |qty_step: f64, price_step: f64, min_qty: f64| ExchangeParams {
+            qty_step,
+            price_step,
+            min_qty,
+            min_cost: 5.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            exchange_params(0.0, 0.01, 0.001)
+                .validated(false, 100.0)
+                .unwrap_err(),
+            "qty_step must be positive, got 0"
+        );
+        assert!(exchange_params(0.001, f64::NAN, 0.001)
+            .validated(false, 100.0)
+            .is_err());
+        assert!(exchange_params(0.001, -0.01, 0.001)
+            .validated(true, 100.0)
+            .is_err());
+        let valid = exchange_params(0.001, 0.01, 0.001)
+            .validated(false, 0.0)
+            .unwrap();
+        assert_eq!((valid.qty_step, valid.price_step), (0.001, 0.01));
+
+        // qty_step from min_qty; price_step leaving 12345.6 five significant digits
+        let inferred = exchange_params(0.0, 0.0, 0.01)
+            .validated(true, 12345.6)
+            .unwrap();
+        assert_eq!((inferred.qty_step, inferred.price_step), (0.01, 1.0));
+        // without min_qty, from min_cost at the price: 5.0 / 2000.0 is 0.0025
+        let inferred = exchange_params(0.0, 0.0, 0.0)
+            .validated(true, 2000.0)
+            .unwrap();
+        assert!((inferred.qty_step - 0.001).abs() < 1e-15);
+        assert!((inferred.price_step - 0.1).abs() < 1e-15);
+        assert!(exchange_params(0.0, 0.01, 0.0)
+            .validated(true, 0.0)
+            .is_err());
+        assert!(exchange_params(0.001, 0.0, 0.001)
+            .validated(true, f64::NAN)
+            .is_err());
+    }
+
+    #[test]
+    fn stated_avg_price_replaces_the_basis() {
+        let position = Position {
+            size: 2.0,
+            price: 100.0,
+            accrued_funding: 0.5,
+        };
+        let restated = position.with_avg_price(Some(95.0)).unwrap();
+        assert_eq!(
+            (restated.size, restated.price, restated.accrued_funding),
+            (2.0, 95.0, 0.5)
+        );
+        assert_eq!(position.with_avg_price(None).unwrap().price, 100.0);
+        for price in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(position.with_avg_price(Some(price)).is_err());
+        }
+    }
+
+    #[test]
+    fn positions_from_hedge_mode_snapshot() {
+        let snapshot = ExchangeSnapshot {
+            symbols: vec![
+                "BTC/USDT:USDT".to_string(),
+                "ETH/USDT:USDT".to_string(),
+                "SOL/USDT:USDT".to_string(),
+            ],
+            positions: vec![
+                exchange_position("BTC/USDT:USDT", LONG, 0.5, 60000.0),
+                // hedge mode: the same symbol on both sides
+                exchange_position("BTC/USDT:USDT", SHORT, -0.2, 61000.0),
+                // absolute short size
+                exchange_position("ETH/USDT:USDT", SHORT, 3.0, 3000.0),
+                exchange_position("SOL/USDT:USDT", LONG, 0.0, 0.0),
+                exchange_position("DOGE/USDT:USDT", LONG, 100.0, 0.1),
+            ],
+        };
+        let positions = Positions::from_exchange_snapshot(&snapshot).unwrap();
+        assert_eq!(positions.long.len(), 1);
+        assert_eq!(positions.short.len(), 2);
+        assert_eq!(
+            (positions.long[&0].size, positions.long[&0].price),
+            (0.5, 60000.0)
+        );
+        assert_eq!(
+            (positions.short[&0].size, positions.short[&0].price),
+            (-0.2, 61000.0)
+        );
+        assert_eq!(
+            (positions.short[&1].size, positions.short[&1].price),
+            (-3.0, 3000.0)
+        );
+        assert!(!positions.long.contains_key(&2));
+
+        let snapshot = ExchangeSnapshot {
+            positions: vec![exchange_position("BTC/USDT:USDT", 2, 0.5, 60000.0)],
+            ..snapshot
+        };
+        assert!(Positions::from_exchange_snapshot(&snapshot).is_err());
+    }
+
+    #[test]
+    fn order_keys_ignore_noise_within_a_tick() {
+        let exchange_params = ExchangeParams {
+            qty_step: 0.001,
+            price_step: 0.01,
+            ..Default::default()
+        };
+        let order = |qty: f64, price: f64| Order {
+            qty,
+            price,
+            order_type: OrderType::CloseGridLong,
+            iceberg_qty: None,
+            min_fill_qty: 0.0,
+        };
+        let key = |order: &Order| OrderKey::new(order, &exchange_params);
+        let base = order(-0.3, 100.01);
+        assert_eq!(key(&base), key(&order(-0.3, 100.01 + 1e-12)));
+        assert_eq!(key(&base), key(&order(-0.3, 100.01 - 1e-12)));
+        assert_eq!(key(&base), key(&order(-0.1 - 0.2, 100.01)));
+        assert_eq!(
+            key(&base),
+            OrderKey {
+                order_type: OrderType::CloseGridLong,
+                price_ticks: 10001,
+                qty_steps: -300,
+            }
+        );
+        assert_ne!(key(&base), key(&order(-0.3, 100.02)));
+        assert_ne!(key(&base), key(&order(-0.301, 100.01)));
+        assert_ne!(
+            key(&base),
+            key(&Order {
+                order_type: OrderType::CloseTrailingLong,
+                ..base
+            })
+        );
+    }
+
+    #[test]
+    fn normalized_accessors() {
+        let bot_params = |close_grid_qty_pct: f64, close_trailing_grid_ratio: f64| BotParams {
+            ema_span_0: 1000.0,
+            ema_span_1: 10.0,
+            close_grid_qty_pct,
+            close_trailing_grid_ratio,
+            ..Default::default()
+        };
+        assert_eq!(
+            bot_params(0.2, 0.0).ema_spans_sorted(),
+            [10.0, 100.0, 1000.0]
+        );
+        for (qty_pct, clamped) in [(0.2, 0.2), (0.0, 0.0), (1.0, 1.0), (1.5, 1.0), (-0.1, 1.0)] {
+            assert_eq!(
+                bot_params(qty_pct, 0.0).close_grid_qty_pct_clamped(),
+                clamped
+            );
+        }
+        for (ratio, split) in [
+            (0.0, TrailingGridSplit::GridOnly),
+            (1.0, TrailingGridSplit::TrailingOnly),
+            (-3.0, TrailingGridSplit::TrailingOnly),
+            (0.25, TrailingGridSplit::TrailingFirst(0.25)),
+            (-0.25, TrailingGridSplit::GridFirst(0.75)),
+        ] {
+            assert_eq!(bot_params(0.2, ratio).close_trailing_grid_split(), split);
+        }
+        assert_eq!(
+            bot_params(0.2, 2.0).close_trailing_grid_ratio_clamped(),
+            1.0
+        );
+    }
+
+    #[test]
+    fn sides_are_sized_against_their_allocation() {
+        let pair = |long_allocation_pct: f64, short_allocation_pct: f64| BotParamsPair {
+            long_allocation_pct,
+            short_allocation_pct,
+            ..Default::default()
+        };
+        assert_eq!(pair(0.6, 0.4).allocated_balance(1000.0, LONG), 600.0);
+        assert_eq!(pair(0.6, 0.4).allocated_balance(1000.0, SHORT), 400.0);
+        // 0.0 is the whole balance
+        assert_eq!(pair(0.6, 0.0).allocated_balance(1000.0, SHORT), 1000.0);
+
+        assert!(pair(0.6, 0.4).validate_allocations().is_ok());
+        assert!(pair(0.0, 0.0).validate_allocations().is_ok());
+        assert_eq!(
+            pair(1.2, 0.0).validate_allocations().unwrap_err(),
+            "long_allocation_pct must be within [0, 1], got 1.2"
+        );
+        assert!(pair(0.5, -0.1).validate_allocations().is_err());
+        assert!(pair(0.7, 0.4).validate_allocations().is_err());
+    }
+
+    #[test]
+    fn ema_spans_are_unused_or_at_least_one() {
+        let pair = |unstuck_ema_span_1: f64| {
+            let bot_params = BotParams {
+                ema_span_0: 200.0,
+                ema_span_1: 800.0,
+                unstuck_ema_span_0: 200.0,
+                unstuck_ema_span_1,
+                ..Default::default()
+            };
+            BotParamsPair {
+                long: bot_params.clone(),
+                short: bot_params,
+                ..Default::default()
+            }
+        };
+        assert!(pair(3200.0).validate_ema_spans().is_ok());
+        assert!(pair(1.0).validate_ema_spans().is_ok());
+        // 0.0 spans are unused EMAs
+        assert!(BotParamsPair::default().validate_ema_spans().is_ok());
+        assert_eq!(
+            pair(0.5).validate_ema_spans().unwrap_err(),
+            "long.unstuck_ema_span_1 must be at least 1.0, got 0.5"
+        );
+        assert!(pair(f64::INFINITY).validate_ema_spans().is_err());
+        assert!(pair(f64::NAN).validate_ema_spans().is_err());
+    }
+
+    #[test]
+    fn bot_params_diff_merges_back() {
+        let old = BotParamsPair::default();
+        let mut new = old.clone();
+        new.long.n_positions = 3;
+        new.short.close_grid_qty_pct = 0.25;
+        new.short.enforce_exposure_limit = true;
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff,
+            [
+                ("long.n_positions".to_string(), json!(0), json!(3)),
+                (
+                    "short.close_grid_qty_pct".to_string(),
+                    json!(0.0),
+                    json!(0.25)
+                ),
+                (
+                    "short.enforce_exposure_limit".to_string(),
+                    json!(false),
+                    json!(true)
+                ),
+            ]
+        );
+
+        let mut merged = old.clone();
+        let updates: Vec<(String, Value)> = diff
+            .into_iter()
+            .map(|(path, _, new_value)| (path, new_value))
+            .collect();
+        merged.merge_partial(&updates).unwrap();
+        assert!(merged.diff(&new).is_empty());
+
+        // integers take whole floats; anything else leaves the params as they were
+        merged
+            .merge_partial(&[("long.n_positions".to_string(), json!(4.0))])
+            .unwrap();
+        assert_eq!(merged.long.n_positions, 4);
+        for bad_update in [
+            ("long.n_positions", json!(2.5)),
+            ("long.no_such_param", json!(1.0)),
+            ("long", json!(1.0)),
+            ("short.close_grid_qty_pct", json!("0.1")),
+        ] {
+            let updates = [
+                ("short.close_grid_qty_pct".to_string(), json!(0.5)),
+                (bad_update.0.to_string(), bad_update.1),
+            ];
+            assert!(merged.merge_partial(&updates).is_err());
+            assert_eq!(merged.short.close_grid_qty_pct, 0.25);
         }
     }
 }
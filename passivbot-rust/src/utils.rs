@@ -1,6 +1,8 @@
-use crate::constants::{CLOSE, LONG, NO_POS, SHORT};
-use crate::types::ExchangeParams;
+use crate::constants::{LONG, SHORT};
+use crate::types::{BotParams, ExchangeParams, Position, RoundingConvention, SymbolIdx};
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
+use serde_json::Value;
 
 /// Rounds a number to the specified number of decimal places.
 fn round_to_decimal_places(value: f64, decimal_places: usize) -> f64 {
@@ -9,27 +11,65 @@ fn round_to_decimal_places(value: f64, decimal_places: usize) -> f64 {
 }
 
 /// Rounds up a number to the nearest multiple of the given step.
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn round_up(n: f64, step: f64) -> f64 {
     let result = (n / step).ceil() * step;
     round_to_decimal_places(result, 10)
 }
 
 /// Rounds a number to the nearest multiple of the given step.
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn round_(n: f64, step: f64) -> f64 {
     let result = (n / step).round() * step;
     round_to_decimal_places(result, 10)
 }
 
 /// Rounds down a number to the nearest multiple of the given step.
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn round_dn(n: f64, step: f64) -> f64 {
     let result = (n / step).floor() * step;
     round_to_decimal_places(result, 10)
 }
 
-#[pyfunction]
+// floor that keeps a value a hair under a step, e.g. 0.3 / 0.1, on it, as truncating the
+// value's decimal digits would
+fn truncate_(n: f64, step: f64) -> f64 {
+    round_to_decimal_places((n / step + 1e-9).floor() * step, 10)
+}
+
+/// Rounds an order price to price_step per the venue's rounding_convention; is_buy picks the
+/// passive side for TowardPassive.
+pub fn round_price_for_side(price: f64, is_buy: bool, exchange_params: &ExchangeParams) -> f64 {
+    let step = exchange_params.price_step;
+    match exchange_params.rounding_convention {
+        RoundingConvention::Round => round_(price, step),
+        RoundingConvention::Truncate => truncate_(price, step),
+        RoundingConvention::TowardPassive if is_buy => round_dn(price, step),
+        RoundingConvention::TowardPassive => round_up(price, step),
+    }
+}
+
+/// Rounds an order qty to qty_step per the venue's rounding_convention, toward zero under
+/// Truncate and to the nearest step otherwise.
+pub fn round_qty(qty: f64, exchange_params: &ExchangeParams) -> f64 {
+    let step = exchange_params.qty_step;
+    match exchange_params.rounding_convention {
+        RoundingConvention::Truncate => truncate_(qty.abs(), step).copysign(qty),
+        RoundingConvention::Round | RoundingConvention::TowardPassive => round_(qty, step),
+    }
+}
+
+/// Whether value is a whole number of steps, within float noise; any value is on a step
+/// that is not positive.
+pub fn is_on_step(value: f64, step: f64) -> bool {
+    if step <= 0.0 {
+        return true;
+    }
+    let steps = value / step;
+    (steps - steps.round()).abs() <= f64::max(1e-6, steps.abs() * 1e-9)
+}
+
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn round_dynamic(n: f64, d: i32) -> f64 {
     if n == 0.0 {
         return n;
@@ -40,7 +80,7 @@ pub fn round_dynamic(n: f64, d: i32) -> f64 {
     round_to_decimal_places(result, 10)
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn round_dynamic_up(n: f64, d: i32) -> f64 {
     if n == 0.0 {
         return n;
@@ -51,7 +91,7 @@ pub fn round_dynamic_up(n: f64, d: i32) -> f64 {
     round_to_decimal_places(result, 10)
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn round_dynamic_dn(n: f64, d: i32) -> f64 {
     if n == 0.0 {
         return n;
@@ -62,7 +102,7 @@ pub fn round_dynamic_dn(n: f64, d: i32) -> f64 {
     round_to_decimal_places(result, 10)
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn hysteresis_rounding(
     balance: f64,
     last_rounded_balance: f64,
@@ -81,7 +121,7 @@ pub fn hysteresis_rounding(
     round_dynamic(rounded_balance, 6)
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn calc_diff(x: f64, y: f64) -> f64 {
     if y == 0.0 {
         if x == 0.0 {
@@ -94,7 +134,7 @@ pub fn calc_diff(x: f64, y: f64) -> f64 {
     }
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn cost_to_qty(cost: f64, price: f64, c_mult: f64) -> f64 {
     if price > 0.0 {
         (cost.abs() / price) / c_mult
@@ -103,12 +143,12 @@ pub fn cost_to_qty(cost: f64, price: f64, c_mult: f64) -> f64 {
     }
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn qty_to_cost(qty: f64, price: f64, c_mult: f64) -> f64 {
     (qty.abs() * price) * c_mult
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn calc_wallet_exposure(
     c_mult: f64,
     balance: f64,
@@ -136,7 +176,7 @@ pub fn calc_wallet_exposure_if_filled(
     calc_wallet_exposure(exchange_params.c_mult, balance, new_psize, new_pprice)
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn calc_new_psize_pprice(
     psize: f64,
     pprice: f64,
@@ -187,16 +227,56 @@ pub fn interpolate(x: f64, xs: &[f64], ys: &[f64]) -> f64 {
     result
 }
 
-#[pyfunction]
+pub fn calc_ema_spans(ema_span_0: f64, ema_span_1: f64) -> [f64; 3] {
+    let mut ema_spans = [ema_span_0, ema_span_1, (ema_span_0 * ema_span_1).sqrt()];
+    ema_spans.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    ema_spans
+}
+
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn calc_pnl_long(entry_price: f64, close_price: f64, qty: f64, c_mult: f64) -> f64 {
     qty.abs() * c_mult * (close_price - entry_price)
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
 pub fn calc_pnl_short(entry_price: f64, close_price: f64, qty: f64, c_mult: f64) -> f64 {
     qty.abs() * c_mult * (entry_price - close_price)
 }
 
+/// Net PnL of closing the whole long position at book_bid right now, as a taker: the
+/// "close now" P/L.
+pub fn calc_immediate_full_close_pnl_long(
+    position: &Position,
+    book_bid: f64,
+    c_mult: f64,
+    taker_fee: f64,
+) -> f64 {
+    calc_pnl_long(position.price, book_bid, position.size, c_mult)
+        - qty_to_cost(position.size, book_bid, c_mult) * taker_fee
+}
+
+/// Net PnL of closing the whole short position at book_ask right now, as a taker.
+pub fn calc_immediate_full_close_pnl_short(
+    position: &Position,
+    book_ask: f64,
+    c_mult: f64,
+    taker_fee: f64,
+) -> f64 {
+    calc_pnl_short(position.price, book_ask, position.size, c_mult)
+        - qty_to_cost(position.size, book_ask, c_mult) * taker_fee
+}
+
+/// Gap between a neutral pair's long and short sizes over the larger one, in [-1, 1];
+/// positive when the long leads, 0.0 without positions.
+pub fn calc_neutral_imbalance(long_size: f64, short_size: f64) -> f64 {
+    let (long_size, short_size) = (long_size.abs(), short_size.abs());
+    let larger = long_size.max(short_size);
+    if larger == 0.0 {
+        return 0.0;
+    }
+    (long_size - short_size) / larger
+}
+
 pub fn calc_pprice_diff_int(pside: usize, pprice: f64, price: f64) -> f64 {
     match pside {
         LONG => {
@@ -219,13 +299,20 @@ pub fn calc_pprice_diff_int(pside: usize, pprice: f64, price: f64) -> f64 {
     }
 }
 
-#[pyfunction]
+#[cfg_attr(feature = "python", pyfunction)]
+#[cfg_attr(feature = "python", pyo3(signature = (balance, loss_allowance_pct, pnl_cumsum_max, pnl_cumsum_last, profit_buffer_pct=0.0)))]
 pub fn calc_auto_unstuck_allowance(
     balance: f64,
     loss_allowance_pct: f64,
     pnl_cumsum_max: f64,
     pnl_cumsum_last: f64,
+    profit_buffer_pct: f64,
 ) -> f64 {
+    // with a buffer set, no allowance until realized profit has reached profit_buffer_pct of
+    // balance
+    if profit_buffer_pct > 0.0 && pnl_cumsum_max < balance * profit_buffer_pct {
+        return 0.0;
+    }
     // allow up to x% drop from balance peak for auto unstuck
 
     let balance_peak = balance + (pnl_cumsum_max - pnl_cumsum_last);
@@ -233,26 +320,435 @@ pub fn calc_auto_unstuck_allowance(
     (balance_peak * (loss_allowance_pct + drop_since_peak_pct)).max(0.0)
 }
 
+/// The most a single unstuck close may lose of allowance, the remaining auto unstuck
+/// allowance: max_allowance_fraction of it, or all of it if max_allowance_fraction is 0.0.
+#[cfg_attr(feature = "python", pyfunction)]
+pub fn calc_unstuck_max_loss(allowance: f64, max_allowance_fraction: f64) -> f64 {
+    if max_allowance_fraction > 0.0 {
+        allowance * max_allowance_fraction.min(1.0)
+    } else {
+        allowance
+    }
+}
+
+/// Order in which stuck positions, as (idx, pside, pprice_diff), are tried for unstucking: by
+/// pprice_diff, then idx. With rotation_tolerance above 0.0 and last_unstucked, the (idx,
+/// pside) the last unstuck close filled on, the positions within rotation_tolerance of the
+/// first take turns: they go in (idx, pside) order starting after last_unstucked, wrapping
+/// around, so equally stuck positions are relieved in rotation.
+#[cfg_attr(feature = "python", pyfunction)]
+#[cfg_attr(feature = "python", pyo3(signature = (stuck_positions, last_unstucked=None, rotation_tolerance=0.0)))]
+pub fn rank_positions_for_unstucking(
+    mut stuck_positions: Vec<(SymbolIdx, usize, f64)>,
+    last_unstucked: Option<(SymbolIdx, usize)>,
+    rotation_tolerance: f64,
+) -> Vec<(SymbolIdx, usize, f64)> {
+    stuck_positions.sort_by(|(i1, _, d1), (i2, _, d2)| {
+        d1.partial_cmp(d2)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(i1.cmp(i2))
+    });
+    let Some(last_unstucked) = last_unstucked else {
+        return stuck_positions;
+    };
+    if rotation_tolerance <= 0.0 || stuck_positions.is_empty() {
+        return stuck_positions;
+    }
+    let first_diff = stuck_positions[0].2;
+    let n_rotating = stuck_positions
+        .iter()
+        .take_while(|(_, _, diff)| *diff <= first_diff + rotation_tolerance)
+        .count();
+    let rotating = &mut stuck_positions[..n_rotating];
+    rotating.sort_by_key(|&(idx, pside, _)| (idx, pside));
+    let next = rotating
+        .iter()
+        .position(|&(idx, pside, _)| (idx, pside) > last_unstucked)
+        .unwrap_or(0);
+    rotating.rotate_left(next);
+    stuck_positions
+}
+
+/// How stuck a position is, from 0.0 to 1.0, for alerting before unstucking kicks in.
+/// Combines how far wallet_exposure / wallet_exposure_limit is above unstuck_threshold
+/// (0.0 at the threshold, 1.0 at the limit) with how far close_price is on the losing side of
+/// the position price (calc_pprice_diff_int, 1.0 at 100%), taking their geometric mean, so
+/// positions that are not stuck or not underwater score 0.0. Shorts have negative size.
+pub fn calc_stuck_severity(
+    position: &Position,
+    balance: f64,
+    bot_params: &BotParams,
+    close_price: f64,
+    c_mult: f64,
+) -> f64 {
+    if position.size == 0.0 || bot_params.wallet_exposure_limit <= 0.0 {
+        return 0.0;
+    }
+    let pside = if position.size > 0.0 { LONG } else { SHORT };
+    let wallet_exposure =
+        calc_wallet_exposure(c_mult, balance, position.size.abs(), position.price);
+    let exposure_ratio = wallet_exposure / bot_params.wallet_exposure_limit;
+    // same test as the backtest's stuck status
+    if exposure_ratio.partial_cmp(&bot_params.unstuck_threshold)
+        != Some(std::cmp::Ordering::Greater)
+    {
+        return 0.0;
+    }
+    let exposure_excess = if bot_params.unstuck_threshold < 1.0 {
+        ((exposure_ratio - bot_params.unstuck_threshold) / (1.0 - bot_params.unstuck_threshold))
+            .min(1.0)
+    } else {
+        1.0
+    };
+    let underwater = calc_pprice_diff_int(pside, position.price, close_price).clamp(0.0, 1.0);
+    (exposure_excess * underwater).sqrt()
+}
+
+/// Balance at which position's wallet_exposure / wallet_exposure_limit is exactly
+/// unstuck_threshold: the least balance that keeps it from counting as stuck, e.g. to size a
+/// deposit. 0.0 for an empty position; infinite if the threshold exposure is not positive, as
+/// then no balance helps. Shorts have negative size.
+pub fn calc_min_balance_to_avoid_unstuck(
+    position: &Position,
+    bot_params: &BotParams,
+    c_mult: f64,
+) -> f64 {
+    if position.size == 0.0 {
+        return 0.0;
+    }
+    let threshold_exposure = bot_params.wallet_exposure_limit * bot_params.unstuck_threshold;
+    if threshold_exposure <= 0.0 {
+        return f64::INFINITY;
+    }
+    qty_to_cost(position.size.abs(), position.price, c_mult) / threshold_exposure
+}
+
 pub fn calc_ema_price_bid(
-    price_step: f64,
+    exchange_params: &ExchangeParams,
     order_book_bid: f64,
     ema_bands_lower: f64,
     ema_dist: f64,
 ) -> f64 {
     f64::min(
         order_book_bid,
-        round_dn(ema_bands_lower * (1.0 - ema_dist), price_step),
+        round_price_for_side(ema_bands_lower * (1.0 - ema_dist), true, exchange_params),
     )
 }
 
 pub fn calc_ema_price_ask(
-    price_step: f64,
+    exchange_params: &ExchangeParams,
     order_book_ask: f64,
     ema_bands_upper: f64,
     ema_dist: f64,
 ) -> f64 {
     f64::max(
         order_book_ask,
-        round_up(ema_bands_upper * (1.0 + ema_dist), price_step),
+        round_price_for_side(ema_bands_upper * (1.0 + ema_dist), false, exchange_params),
     )
 }
+
+/// Flattens nested JSON objects into (dotted.path, leaf) pairs,
+/// e.g. {"long": {"n_positions": 3}} -> [("long.n_positions", 3)].
+pub fn flatten_json_paths(value: &Value) -> Vec<(String, Value)> {
+    fn walk(value: &Value, prefix: &str, out: &mut Vec<(String, Value)>) {
+        match value {
+            Value::Object(map) => {
+                for (key, child) in map {
+                    let path = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    walk(child, &path, out);
+                }
+            }
+            _ => out.push((prefix.to_string(), value.clone())),
+        }
+    }
+    let mut out = Vec::new();
+    walk(value, "", &mut out);
+    out
+}
+
+/// Replaces the existing leaf at a dotted path. Numbers are coerced to the type of the leaf
+/// they replace, so 3.0 may be written to an integer field.
+pub fn set_json_path(root: &mut Value, path: &str, new_value: Value) -> Result<(), String> {
+    let mut node = root;
+    for key in path.split('.') {
+        node = node
+            .get_mut(key)
+            .ok_or_else(|| format!("unknown parameter '{}'", path))?;
+    }
+    if node.is_object() {
+        return Err(format!("'{}' is not a leaf parameter", path));
+    }
+    *node = match (&*node, &new_value) {
+        (Value::Number(old), Value::Number(new)) if old.is_u64() => {
+            let new_f64 = new.as_f64().unwrap_or(f64::NAN);
+            if new_f64 < 0.0 || new_f64.fract() != 0.0 {
+                return Err(format!("'{}' expects a non-negative integer", path));
+            }
+            Value::from(new_f64 as u64)
+        }
+        (Value::Number(_), Value::Number(new)) => Value::from(new.as_f64().unwrap_or(f64::NAN)),
+        (Value::Bool(_), Value::Number(new)) => Value::Bool(new.as_f64() != Some(0.0)),
+        (old, new) if std::mem::discriminant(old) == std::mem::discriminant(new) => new_value,
+        _ => return Err(format!("type mismatch for '{}'", path)),
+    };
+    Ok(())
+}
+
+/// Scales wallet_exposure_limit per symbol for correlated portfolios. n positions with mean
+/// pairwise correlation rho carry sqrt(1 + (n - 1) * rho) times the risk of n independent ones,
+/// so each symbol's limit is divided by that factor, using its mean positive correlation to the
+/// other symbols. Uncorrelated symbols keep the full limit.
+pub fn calc_correlation_scaled_wallet_exposure_limits(
+    wallet_exposure_limit: f64,
+    correlation_matrix: &[Vec<f64>],
+) -> Vec<f64> {
+    let n = correlation_matrix.len();
+    if n < 2 {
+        return vec![wallet_exposure_limit; n];
+    }
+    correlation_matrix
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mean_corr = row
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, &corr)| nan_to_0(corr).clamp(0.0, 1.0))
+                .sum::<f64>()
+                / (n - 1) as f64;
+            wallet_exposure_limit / (1.0 + (n - 1) as f64 * mean_corr).sqrt()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_unstuck_allowance_waits_for_the_profit_buffer() {
+        // never in profit: with the default buffer the allowance is what it always was
+        let never_in_profit = calc_auto_unstuck_allowance(1000.0, 0.01, -5.0, -8.0, 0.0);
+        let balance_peak = 1000.0 + 3.0;
+        let expected = balance_peak * (0.01 + 1000.0 / balance_peak - 1.0);
+        assert!((never_in_profit - expected).abs() < 1e-9);
+        assert!(never_in_profit > 0.0);
+
+        // a 2% buffer is 20.0 of a 1000.0 balance
+        assert_eq!(
+            calc_auto_unstuck_allowance(1000.0, 0.01, 19.99, 10.0, 0.02),
+            0.0
+        );
+        assert_eq!(
+            calc_auto_unstuck_allowance(1000.0, 0.01, 25.0, 10.0, 0.02),
+            calc_auto_unstuck_allowance(1000.0, 0.01, 25.0, 10.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn stuck_severity_combines_excess_exposure_and_loss() {
+        let bot_params = BotParams {
+            wallet_exposure_limit: 1.0,
+            unstuck_threshold: 0.5,
+            ..Default::default()
+        };
+        let severity = |size: f64, close_price: f64| {
+            let position = Position {
+                size,
+                price: 100.0,
+                ..Default::default()
+            };
+            calc_stuck_severity(&position, 1000.0, &bot_params, close_price, 1.0)
+        };
+        // exposure ratio 0.8 is 0.6 of the way from threshold to limit; 10% underwater
+        assert!((severity(8.0, 90.0) - 0.06_f64.sqrt()).abs() < 1e-9);
+        assert!((severity(-8.0, 110.0) - 0.06_f64.sqrt()).abs() < 1e-9);
+        // at the limit and wiped out
+        assert_eq!(severity(10.0, 0.0), 1.0);
+        // not stuck, in profit, or flat
+        assert_eq!(severity(5.0, 50.0), 0.0);
+        assert_eq!(severity(8.0, 110.0), 0.0);
+        assert_eq!(severity(-8.0, 90.0), 0.0);
+        assert_eq!(severity(0.0, 90.0), 0.0);
+    }
+
+    #[test]
+    fn correlated_symbols_share_the_exposure_limit() {
+        let limits = calc_correlation_scaled_wallet_exposure_limits(
+            1.2,
+            &[
+                vec![1.0, 0.5, 0.5],
+                vec![0.5, 1.0, -0.3],
+                vec![0.5, f64::NAN, 1.0],
+            ],
+        );
+        // mean positive correlations 0.5, 0.25 and 0.25; negative and NaN count as 0.0
+        let expected = [
+            1.2 / 2.0_f64.sqrt(),
+            1.2 / 1.5_f64.sqrt(),
+            1.2 / 1.5_f64.sqrt(),
+        ];
+        for (limit, expected) in limits.iter().zip(expected) {
+            assert!((limit - expected).abs() < 1e-12);
+        }
+        let uncorrelated =
+            calc_correlation_scaled_wallet_exposure_limits(1.2, &[vec![1.0, 0.0], vec![0.0, 1.0]]);
+        assert_eq!(uncorrelated, [1.2, 1.2]);
+        assert_eq!(
+            calc_correlation_scaled_wallet_exposure_limits(1.2, &[vec![1.0]]),
+            [1.2]
+        );
+    }
+
+    #[test]
+    fn immediate_close_pnl_is_net_of_taker_fees() {
+        let long = Position {
+            size: 2.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -2.0, ..long };
+        // 10.0 gross less 0.1% of the closed notional
+        assert!((calc_immediate_full_close_pnl_long(&long, 105.0, 1.0, 0.001) - 9.79).abs() < 1e-9);
+        assert!(
+            (calc_immediate_full_close_pnl_short(&short, 95.0, 1.0, 0.001) - 9.81).abs() < 1e-9
+        );
+        // underwater, fees deepen the loss; c_mult scales both
+        assert!(
+            (calc_immediate_full_close_pnl_long(&long, 95.0, 10.0, 0.001) - (-100.0 - 0.19 * 10.0))
+                .abs()
+                < 1e-9
+        );
+        assert!((calc_immediate_full_close_pnl_short(&short, 105.0, 1.0, 0.0) + 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn equally_stuck_positions_take_turns_unstucking() {
+        let stuck = vec![
+            (3, LONG, -0.10),
+            (1, SHORT, -0.095),
+            (2, LONG, -0.05),
+            (0, LONG, -0.098),
+        ];
+        let order = |last_unstucked: Option<(SymbolIdx, usize)>, rotation_tolerance: f64| {
+            rank_positions_for_unstucking(stuck.clone(), last_unstucked, rotation_tolerance)
+                .iter()
+                .map(|&(idx, pside, _)| (idx, pside))
+                .collect::<Vec<_>>()
+        };
+        let by_diff = [(3, LONG), (0, LONG), (1, SHORT), (2, LONG)];
+        assert_eq!(order(None, 0.01), by_diff);
+        assert_eq!(order(Some((3, LONG)), 0.0), by_diff);
+        // the three within 0.01 of the most stuck rotate in (idx, pside) order after the last
+        assert_eq!(
+            order(Some((0, LONG)), 0.01),
+            [(1, SHORT), (3, LONG), (0, LONG), (2, LONG)]
+        );
+        assert_eq!(
+            order(Some((1, SHORT)), 0.01),
+            [(3, LONG), (0, LONG), (1, SHORT), (2, LONG)]
+        );
+        // wrapping around past the last one
+        assert_eq!(
+            order(Some((3, LONG)), 0.01),
+            [(0, LONG), (1, SHORT), (3, LONG), (2, LONG)]
+        );
+    }
+
+    #[test]
+    fn min_balance_to_avoid_unstuck_puts_exposure_at_the_threshold() {
+        let bot_params = BotParams {
+            wallet_exposure_limit: 0.5,
+            unstuck_threshold: 0.8,
+            ..Default::default()
+        };
+        let long = Position {
+            size: 2.0,
+            price: 100.0,
+            ..Default::default()
+        };
+        let short = Position { size: -2.0, ..long };
+        for position in [long, short] {
+            // 200.0 of cost is 0.4 exposure, 0.8 of the limit, at 500.0
+            let balance = calc_min_balance_to_avoid_unstuck(&position, &bot_params, 1.0);
+            assert!((balance - 500.0).abs() < 1e-9);
+            let exposure = calc_wallet_exposure(1.0, balance, position.size, position.price);
+            assert!((exposure / bot_params.wallet_exposure_limit - 0.8).abs() < 1e-12);
+        }
+        assert_eq!(
+            calc_min_balance_to_avoid_unstuck(&long, &bot_params, 0.1),
+            50.0
+        );
+        let empty = Position::default();
+        assert_eq!(
+            calc_min_balance_to_avoid_unstuck(&empty, &bot_params, 1.0),
+            0.0
+        );
+        let always_stuck = BotParams {
+            unstuck_threshold: 0.0,
+            ..bot_params
+        };
+        assert_eq!(
+            calc_min_balance_to_avoid_unstuck(&long, &always_stuck, 1.0),
+            f64::INFINITY
+        );
+    }
+
+    #[test]
+    fn rounding_follows_the_venue_convention() {
+        let exchange_params = |rounding_convention: RoundingConvention| ExchangeParams {
+            qty_step: 0.1,
+            price_step: 0.5,
+            rounding_convention,
+            ..Default::default()
+        };
+        // (convention, buy at 100.3, sell at 100.3, buy at 100.1, qty 0.26, qty -0.26)
+        for (rounding_convention, expected) in [
+            (RoundingConvention::Round, (100.5, 100.5, 100.0, 0.3, -0.3)),
+            (
+                RoundingConvention::Truncate,
+                (100.0, 100.0, 100.0, 0.2, -0.2),
+            ),
+            (
+                RoundingConvention::TowardPassive,
+                (100.0, 100.5, 100.0, 0.3, -0.3),
+            ),
+        ] {
+            let exchange_params = exchange_params(rounding_convention);
+            assert_eq!(
+                (
+                    round_price_for_side(100.3, true, &exchange_params),
+                    round_price_for_side(100.3, false, &exchange_params),
+                    round_price_for_side(100.1, true, &exchange_params),
+                    round_qty(0.26, &exchange_params),
+                    round_qty(-0.26, &exchange_params),
+                ),
+                expected,
+                "{:?}",
+                rounding_convention
+            );
+        }
+        // truncation keeps values a hair under a step on it
+        let truncate = exchange_params(RoundingConvention::Truncate);
+        assert_eq!(round_qty(0.7 - 0.4, &truncate), 0.3);
+        assert_eq!(round_qty(0.29999999999, &truncate), 0.3);
+        assert_eq!(
+            "truncate".parse::<RoundingConvention>(),
+            Ok(RoundingConvention::Truncate)
+        );
+        assert!("floor".parse::<RoundingConvention>().is_err());
+    }
+
+    #[test]
+    fn unstuck_max_loss_is_a_share_of_the_allowance() {
+        assert_eq!(calc_unstuck_max_loss(20.0, 0.0), 20.0);
+        assert_eq!(calc_unstuck_max_loss(20.0, 0.25), 5.0);
+        // more than the whole allowance is never allowed
+        assert_eq!(calc_unstuck_max_loss(20.0, 1.5), 20.0);
+    }
+}
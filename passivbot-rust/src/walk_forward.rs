@@ -0,0 +1,385 @@
+use crate::backtest::evaluate_backtest;
+use crate::optimizer::{
+    calc_dataset_fingerprint, step_scored_swarm, EvaluationCache, ParamBounds, ParticleSwarm,
+    Pruner, PsoParams,
+};
+use crate::scoring::ScoringConfig;
+use crate::types::{
+    Analysis, BacktestParams, BotParamsPair, Equities, Evaluation, ExchangeParams, PruneParams,
+};
+use ndarray::{s, ArrayView1, ArrayView3};
+use serde::Serialize;
+
+const MIN_WINDOW_TIMESTEPS: usize = 2 * 1440; // analyses need two days of 1m candles
+
+/// Timestep ranges [start, end) of one walk-forward step: the config optimized on train is
+/// evaluated out of sample on test, the window right after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WalkForwardWindow {
+    pub train: (usize, usize),
+    pub test: (usize, usize),
+}
+
+/// Splits n_timesteps into n_windows consecutive windows of equal length, the last taking
+/// the remainder, and pairs each window with the next: n_windows - 1 steps.
+pub fn calc_walk_forward_windows(
+    n_timesteps: usize,
+    n_windows: usize,
+) -> Result<Vec<WalkForwardWindow>, String> {
+    if n_windows < 2 {
+        return Err("walk-forward needs at least 2 windows".to_string());
+    }
+    let window_len = n_timesteps / n_windows;
+    if window_len < MIN_WINDOW_TIMESTEPS {
+        return Err(format!(
+            "{} timesteps cannot be split into {} windows of at least {} timesteps",
+            n_timesteps, n_windows, MIN_WINDOW_TIMESTEPS
+        ));
+    }
+    let window = |i: usize| {
+        let end = if i + 1 == n_windows {
+            n_timesteps
+        } else {
+            (i + 1) * window_len
+        };
+        (i * window_len, end)
+    };
+    Ok((0..n_windows - 1)
+        .map(|i| WalkForwardWindow {
+            train: window(i),
+            test: window(i + 1),
+        })
+        .collect())
+}
+
+/// Search of a walk-forward: ParticleSwarmOptimizer's scoring, pruning and cache, with a
+/// fresh swarm per train window.
+#[derive(Debug, Clone)]
+pub struct WalkForwardConfig {
+    pub bounds: ParamBounds,
+    pub scoring: ScoringConfig,
+    pub pruner: Option<Pruner>, // thresholds are learned afresh per window
+    pub pso_params: PsoParams,
+    pub n_windows: usize,
+    pub cache_size: usize,
+}
+
+/// Reported after every iteration of a step's swarm and, with out_of_sample_fitness set,
+/// after the step's out-of-sample backtest.
+#[derive(Debug, Clone, Serialize)]
+pub struct WalkForwardProgress {
+    pub step: usize,
+    pub n_steps: usize,
+    pub iteration: usize,
+    pub n_iterations: usize,
+    pub best_fitness: Option<f64>,
+    pub out_of_sample_fitness: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WalkForwardScore {
+    pub fitness: f64,
+    pub fitness_breakdown: Vec<(String, f64)>,
+    pub analysis_usd: Analysis,
+    pub analysis_btc: Analysis,
+}
+
+impl WalkForwardScore {
+    fn new(scoring: &ScoringConfig, (analysis_usd, analysis_btc): (Analysis, Analysis)) -> Self {
+        let score = scoring.score(&analysis_usd, &analysis_btc);
+        WalkForwardScore {
+            fitness: score.fitness,
+            fitness_breakdown: score.contributions,
+            analysis_usd,
+            analysis_btc,
+        }
+    }
+}
+
+/// A step's winner, as bounded parameter values and as config, scored in and out of sample.
+#[derive(Debug, Clone, Serialize)]
+pub struct WalkForwardStep {
+    pub window: WalkForwardWindow,
+    pub values: Vec<f64>,
+    pub bot: BotParamsPair,
+    pub in_sample: WalkForwardScore,
+    pub out_of_sample: WalkForwardScore,
+}
+
+/// Steps in order, and their out-of-sample equities stitched into one curve, each window's
+/// scaled to start where the previous one ended.
+#[derive(Clone, Serialize)]
+pub struct WalkForwardReport {
+    pub steps: Vec<WalkForwardStep>,
+    pub equities: Equities,
+}
+
+/// Optimizes config.bounds over the base config on each step's train window, then backtests
+/// the winner on the test window. Windows are sliced out of hlcvs and backtested on their own,
+/// so optimizations never see test candles and out-of-sample backtests never see train
+/// candles. progress returning false stops the run with an error.
+pub fn run_walk_forward<P>(
+    hlcvs: &ArrayView3<f64>,
+    btc_usd_prices: &ArrayView1<f64>,
+    bot_params_pair: &BotParamsPair,
+    exchange_params_list: &[ExchangeParams],
+    backtest_params: &BacktestParams,
+    config: &WalkForwardConfig,
+    mut progress: P,
+) -> Result<WalkForwardReport, String>
+where
+    P: FnMut(&WalkForwardProgress) -> bool,
+{
+    let windows = calc_walk_forward_windows(hlcvs.dim().0, config.n_windows)?;
+    // keys hold each window's fingerprint, so one cache serves all windows
+    let cache = EvaluationCache::<Evaluation>::new(config.cache_size);
+    let mut steps = Vec::with_capacity(windows.len());
+    let mut equities = Equities::default();
+    for (step, window) in windows.iter().enumerate() {
+        let (start, end) = window.train;
+        let train_hlcvs = hlcvs.slice(s![start..end, .., ..]);
+        let train_btc_usd_prices = btc_usd_prices.slice(s![start..end]);
+        let fingerprint = calc_dataset_fingerprint(
+            &train_hlcvs,
+            &train_btc_usd_prices,
+            exchange_params_list,
+            backtest_params,
+        );
+        let evaluate = |values: &[f64], prune_params: &PruneParams| {
+            let candidate = config.bounds.apply(bot_params_pair, values).ok()?;
+            let key = EvaluationCache::<Evaluation>::calc_key(&candidate, fingerprint);
+            Some(cache.get_or_insert_with(
+                key,
+                || {
+                    evaluate_backtest(
+                        &train_hlcvs,
+                        &train_btc_usd_prices,
+                        candidate,
+                        exchange_params_list.to_vec(),
+                        backtest_params,
+                        prune_params,
+                    )
+                    .0
+                },
+                |evaluation| !evaluation.pruned,
+            ))
+        };
+        let mut swarm = ParticleSwarm::new(config.bounds.clone(), config.pso_params.clone())?;
+        let mut pruner = config.pruner.clone();
+        let mut report = |swarm: &ParticleSwarm<(Analysis, Analysis)>,
+                          out_of_sample_fitness: Option<f64>| {
+            progress(&WalkForwardProgress {
+                step,
+                n_steps: windows.len(),
+                iteration: swarm.iteration(),
+                n_iterations: config.pso_params.n_iterations,
+                best_fitness: swarm.best().map(|best| best.fitness),
+                out_of_sample_fitness,
+            })
+        };
+        while step_scored_swarm(&mut swarm, &config.scoring, &mut pruner, &evaluate) {
+            if !report(&swarm, None) {
+                return Err("walk-forward stopped by progress callback".to_string());
+            }
+        }
+        let best = swarm
+            .best()
+            .ok_or_else(|| format!("no candidate evaluated in walk-forward step {}", step))?;
+        let bot = config.bounds.apply(bot_params_pair, &best.position)?;
+
+        let (start, end) = window.test;
+        let (evaluation, test_equities) = evaluate_backtest(
+            &hlcvs.slice(s![start..end, .., ..]),
+            &btc_usd_prices.slice(s![start..end]),
+            bot.clone(),
+            exchange_params_list.to_vec(),
+            backtest_params,
+            &PruneParams::default(),
+        );
+        stitch_equities(&mut equities.usd, &test_equities.usd);
+        stitch_equities(&mut equities.btc, &test_equities.btc);
+        let out_of_sample = WalkForwardScore::new(&config.scoring, evaluation.analyses);
+        if !report(&swarm, Some(out_of_sample.fitness)) {
+            return Err("walk-forward stopped by progress callback".to_string());
+        }
+        steps.push(WalkForwardStep {
+            window: *window,
+            values: best.position.clone(),
+            bot,
+            in_sample: WalkForwardScore::new(&config.scoring, best.stats.clone()),
+            out_of_sample,
+        });
+    }
+    Ok(WalkForwardReport { steps, equities })
+}
+
+/// Appends curve to stitched, scaled to start where stitched ends; the shared point is
+/// kept once.
+fn stitch_equities(stitched: &mut Vec<f64>, curve: &[f64]) {
+    let (scale, skip) = match (stitched.last(), curve.first()) {
+        (Some(&last), Some(&first)) if first != 0.0 => (last / first, 1),
+        (Some(_), _) => (1.0, 1),
+        (None, _) => (1.0, 0),
+    };
+    stitched.extend(curve.iter().skip(skip).map(|equity| equity * scale));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{generate_synthetic_hlcvs, SyntheticMarketSpec, SyntheticRegime};
+    use crate::types::BotParams;
+    use ndarray::{Array1, Array3};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn windows_tile_the_timesteps() {
+        let n_timesteps = 3 * MIN_WINDOW_TIMESTEPS + 2;
+        let windows = calc_walk_forward_windows(n_timesteps, 3).unwrap();
+        let w = MIN_WINDOW_TIMESTEPS;
+        assert_eq!(
+            windows,
+            [
+                WalkForwardWindow {
+                    train: (0, w),
+                    test: (w, 2 * w),
+                },
+                WalkForwardWindow {
+                    train: (w, 2 * w),
+                    test: (2 * w, n_timesteps), // the last window takes the remainder
+                },
+            ]
+        );
+        for window in &windows {
+            assert_eq!(window.train.1, window.test.0);
+        }
+        assert!(calc_walk_forward_windows(n_timesteps, 1).is_err());
+        assert!(calc_walk_forward_windows(3 * w - 1, 3).is_err());
+    }
+
+    fn sideways_hlcvs(n_candles: usize, seed: u64) -> Array3<f64> {
+        let mut hlcvs = generate_synthetic_hlcvs(&SyntheticMarketSpec {
+            seed,
+            regimes: vec![SyntheticRegime {
+                n_candles,
+                volatility: 0.003,
+                mean_reversion: 0.01,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        hlcvs
+            .slice_mut(s![.., .., 0..3])
+            .mapv_inplace(|price| (price * 1000.0).round() / 1000.0);
+        hlcvs
+    }
+
+    fn run(hlcvs: &Array3<f64>) -> Vec<Value> {
+        let bot_params = BotParams {
+            close_grid_markup_range: 0.02,
+            close_grid_min_markup: 0.003,
+            close_grid_qty_pct: 0.2,
+            ema_span_0: 200.0,
+            ema_span_1: 800.0,
+            entry_grid_double_down_factor: 1.0,
+            entry_grid_spacing_pct: 0.02,
+            entry_initial_ema_dist: 0.002,
+            entry_initial_qty_pct: 0.1,
+            filter_noisiness_rolling_window: 60,
+            filter_volume_rolling_window: 60,
+            n_positions: 1,
+            total_wallet_exposure_limit: 0.75,
+            wallet_exposure_limit: 0.75,
+            ..Default::default()
+        };
+        let bot_params_pair = BotParamsPair {
+            long: bot_params.clone(),
+            short: BotParams {
+                wallet_exposure_limit: 0.0,
+                total_wallet_exposure_limit: 0.0,
+                ..bot_params
+            },
+            ..Default::default()
+        };
+        let exchange_params = ExchangeParams {
+            qty_step: 0.001,
+            price_step: 0.001,
+            min_qty: 0.001,
+            min_cost: 1.0,
+            c_mult: 1.0,
+            ..Default::default()
+        };
+        let backtest_params: BacktestParams = serde_json::from_value(json!({
+            "starting_balance": 1000.0,
+            "maker_fee": 0.0002,
+            "coins": ["COIN0"],
+            "correlation_matrix": [],
+        }))
+        .unwrap();
+        let config = WalkForwardConfig {
+            bounds: ParamBounds::from_config(
+                &json!({"long_entry_grid_spacing_pct": [0.005, 0.05]}),
+                None,
+            )
+            .unwrap(),
+            scoring: ScoringConfig::from_config(&json!({"adg": -1.0})).unwrap(),
+            pruner: None,
+            pso_params: PsoParams {
+                swarm_size: 3,
+                n_iterations: 2,
+                seed: 1,
+                n_threads: 1,
+                ..Default::default()
+            },
+            n_windows: 3,
+            cache_size: 64,
+        };
+        let btc_usd_prices = Array1::ones(hlcvs.dim().0);
+        let report = run_walk_forward(
+            &hlcvs.view(),
+            &btc_usd_prices.view(),
+            &bot_params_pair,
+            &[exchange_params],
+            &backtest_params,
+            &config,
+            |_| true,
+        )
+        .unwrap();
+        report
+            .steps
+            .iter()
+            .map(|step| serde_json::to_value(step).unwrap())
+            .collect()
+    }
+
+    // hlcvs with window i (of three) taken from another market
+    fn with_window_replaced(hlcvs: &Array3<f64>, i: usize) -> Array3<f64> {
+        let w = MIN_WINDOW_TIMESTEPS;
+        let mut replaced = hlcvs.clone();
+        replaced
+            .slice_mut(s![i * w..(i + 1) * w, .., ..])
+            .assign(&sideways_hlcvs(w, 99));
+        replaced
+    }
+
+    #[test]
+    fn steps_only_see_their_own_windows() {
+        let hlcvs = sideways_hlcvs(3 * MIN_WINDOW_TIMESTEPS, 5);
+        let steps = run(&hlcvs);
+        assert_eq!(steps.len(), 2);
+
+        // the last window is only step 1's test window
+        let last_replaced = run(&with_window_replaced(&hlcvs, 2));
+        assert_eq!(last_replaced[0], steps[0]);
+        assert_eq!(last_replaced[1]["bot"], steps[1]["bot"]);
+        assert_eq!(last_replaced[1]["in_sample"], steps[1]["in_sample"]);
+        assert_ne!(last_replaced[1]["out_of_sample"], steps[1]["out_of_sample"]);
+
+        // the first window is only step 0's train window
+        let first_replaced = run(&with_window_replaced(&hlcvs, 0));
+        assert_eq!(first_replaced[1], steps[1]);
+        assert_eq!(first_replaced[0]["window"], steps[0]["window"]);
+        assert_ne!(first_replaced[0]["in_sample"], steps[0]["in_sample"]);
+    }
+}
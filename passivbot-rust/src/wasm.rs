@@ -0,0 +1,308 @@
+//! JSON in, JSON out bindings of the ladder calculators, for browser tools such as a grid
+//! preview. Built with `--no-default-features --features wasm`.
+//!
+//! Every function takes one request object:
+//! {"pside": "long" | "short", "balance", "bid", "ask", "exchange_params", "bot_params",
+//!  "ema_bands"?, "position"?, "trailing_prices"?, "hour"?, "realized_pnl"?, "equity"?,
+//!  "peak_equity"?, "min_markup_floor"?, "order_flow_imbalance"?, "close_markup_scale"?}
+//! exchange_params, bot_params, ema_bands, position and trailing_prices are partial objects
+//! laid over the defaults; unknown fields are rejected. ema_bands defaults to the bid and ask.
+use crate::closes::{calc_closes_long, calc_closes_short};
+use crate::entries::{calc_entries_long, calc_entries_short};
+use crate::types::{
+    BotParams, EMABands, ExchangeParams, Order, OrderBook, Position, StateParams,
+    TrailingPriceBundle,
+};
+use crate::utils::{calc_new_psize_pprice, calc_wallet_exposure, set_json_path};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+struct LadderRequest {
+    is_long: bool,
+    exchange_params: ExchangeParams,
+    state_params: StateParams,
+    bot_params: BotParams,
+    position: Position,
+    trailing_price_bundle: TrailingPriceBundle,
+}
+
+/// One rung of the entry ladder with the position and close ladder once it and every rung
+/// before it have filled.
+#[derive(Serialize)]
+struct PreviewLevel {
+    entry: Order,
+    position: Position,
+    wallet_exposure: f64,
+    closes: Vec<Order>,
+}
+
+/// Entries of the request's position, as a JSON array of orders.
+#[wasm_bindgen]
+pub fn calc_entries(request: &str) -> Result<String, JsError> {
+    respond(request, entries)
+}
+
+/// Closes of the request's position, as a JSON array of orders.
+#[wasm_bindgen]
+pub fn calc_closes(request: &str) -> Result<String, JsError> {
+    respond(request, |request| {
+        closes(request, &request.state_params, &request.position)
+    })
+}
+
+/// Walks the entry ladder fill by fill, as a JSON array of levels: {"entry", "position",
+/// "wallet_exposure", "closes"}. Each level's closes are priced with the book at its entry.
+#[wasm_bindgen]
+pub fn calc_grid_preview(request: &str) -> Result<String, JsError> {
+    respond(request, grid_preview)
+}
+
+fn grid_preview(request: &LadderRequest) -> Vec<PreviewLevel> {
+    let mut position = request.position;
+    let mut levels = Vec::new();
+    for entry in entries(request) {
+        (position.size, position.price) = calc_new_psize_pprice(
+            position.size,
+            position.price,
+            entry.qty,
+            entry.price,
+            request.exchange_params.qty_step,
+        );
+        let mut state_params = request.state_params.clone();
+        state_params.order_book.bid = entry.price;
+        state_params.order_book.ask = entry.price;
+        levels.push(PreviewLevel {
+            entry,
+            position,
+            wallet_exposure: calc_wallet_exposure(
+                request.exchange_params.c_mult,
+                request.state_params.balance,
+                position.size.abs(),
+                position.price,
+            ),
+            closes: closes(request, &state_params, &position),
+        });
+    }
+    levels
+}
+
+fn entries(request: &LadderRequest) -> Vec<Order> {
+    let calc = if request.is_long {
+        calc_entries_long
+    } else {
+        calc_entries_short
+    };
+    calc(
+        &request.exchange_params,
+        &request.state_params,
+        &request.bot_params,
+        &request.position,
+        &request.trailing_price_bundle,
+    )
+}
+
+fn closes(request: &LadderRequest, state_params: &StateParams, position: &Position) -> Vec<Order> {
+    let calc = if request.is_long {
+        calc_closes_long
+    } else {
+        calc_closes_short
+    };
+    calc(
+        &request.exchange_params,
+        state_params,
+        &request.bot_params,
+        position,
+        &request.trailing_price_bundle,
+        &[],
+    )
+}
+
+fn respond<T: Serialize>(
+    request: &str,
+    calc: impl FnOnce(&LadderRequest) -> T,
+) -> Result<String, JsError> {
+    let request = parse_request(request).map_err(|e| JsError::new(&e))?;
+    serde_json::to_string(&calc(&request)).map_err(|e| JsError::new(&e.to_string()))
+}
+
+fn parse_request(request: &str) -> Result<LadderRequest, String> {
+    let mut request: Value = serde_json::from_str(request).map_err(|e| e.to_string())?;
+    let number = |key: &str| {
+        request[key]
+            .as_f64()
+            .ok_or_else(|| format!("'{}' must be a number", key))
+    };
+    let (balance, bid, ask) = (number("balance")?, number("bid")?, number("ask")?);
+    let is_long = match request["pside"].as_str() {
+        Some("long") => true,
+        Some("short") => false,
+        _ => return Err("'pside' must be \"long\" or \"short\"".to_string()),
+    };
+    let hour = request["hour"].as_u64().unwrap_or(0) as usize;
+    let realized_pnl = request["realized_pnl"].as_f64().unwrap_or(0.0);
+    let equity = request["equity"].as_f64().unwrap_or(0.0);
+    let peak_equity = request["peak_equity"].as_f64().unwrap_or(0.0);
+    let min_markup_floor = request["min_markup_floor"].as_f64().unwrap_or(0.0);
+    let order_flow_imbalance = request["order_flow_imbalance"].as_f64();
+    let close_markup_scale = request["close_markup_scale"].as_f64();
+    let mut exchange_params = request["exchange_params"].take();
+    let infer_steps = match exchange_params.as_object_mut() {
+        Some(fields) => fields.remove("infer_steps").and_then(|v| v.as_bool()),
+        None => None,
+    };
+    let exchange_params: ExchangeParams = overlay(
+        ExchangeParams::default(),
+        "exchange_params",
+        exchange_params,
+    )?;
+    let ema_bands = EMABands {
+        upper: ask,
+        lower: bid,
+    };
+    Ok(LadderRequest {
+        is_long,
+        exchange_params: exchange_params.validated(infer_steps.unwrap_or(false), bid)?,
+        state_params: StateParams {
+            balance,
+            order_book: OrderBook::new(bid, ask),
+            ema_bands: overlay(ema_bands, "ema_bands", request["ema_bands"].take())?,
+            hour,
+            realized_pnl,
+            equity,
+            peak_equity,
+            min_markup_floor,
+            order_flow_imbalance,
+            close_markup_scale,
+            ..Default::default()
+        },
+        bot_params: overlay(
+            BotParams::default(),
+            "bot_params",
+            request["bot_params"].take(),
+        )?,
+        position: overlay(Position::default(), "position", request["position"].take())?,
+        trailing_price_bundle: overlay(
+            TrailingPriceBundle::default(),
+            "trailing_prices",
+            request["trailing_prices"].take(),
+        )?,
+    })
+}
+
+// writes the given fields over defaults; null leaves the defaults as they are
+fn overlay<T: Serialize + DeserializeOwned>(
+    defaults: T,
+    name: &str,
+    fields: Value,
+) -> Result<T, String> {
+    let fields = match fields {
+        Value::Null => return Ok(defaults),
+        Value::Object(fields) => fields,
+        _ => return Err(format!("'{}' must be an object", name)),
+    };
+    let mut value = serde_json::to_value(defaults).map_err(|e| e.to_string())?;
+    for (key, field) in fields {
+        set_json_path(&mut value, &key, field).map_err(|e| format!("{}: {}", name, e))?;
+    }
+    serde_json::from_value(value).map_err(|e| format!("{}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(pside: &str) -> Value {
+        json!({
+            "pside": pside,
+            "balance": 1000.0,
+            "bid": 100.0,
+            "ask": 100.01,
+            "exchange_params": {"qty_step": 0.001, "price_step": 0.01, "min_cost": 5.0},
+            "bot_params": {
+                "close_grid_markup_range": 0.02,
+                "close_grid_min_markup": 0.005,
+                "close_grid_qty_pct": 0.2,
+                "entry_grid_double_down_factor": 1.0,
+                "entry_grid_spacing_pct": 0.02,
+                "entry_initial_qty_pct": 0.1,
+                "wallet_exposure_limit": 0.5,
+            },
+        })
+    }
+
+    #[test]
+    fn requests_lay_their_fields_over_the_defaults() {
+        let mut long = request("long");
+        long["ema_bands"] = json!({"lower": 98.0});
+        long["hour"] = json!(7);
+        let parsed = parse_request(&long.to_string()).unwrap();
+        assert!(parsed.is_long);
+        assert_eq!(parsed.exchange_params.price_step, 0.01);
+        assert_eq!(
+            parsed.exchange_params.c_mult,
+            ExchangeParams::default().c_mult
+        );
+        assert_eq!(parsed.bot_params.wallet_exposure_limit, 0.5);
+        // ema_bands defaults to the book
+        assert_eq!(
+            (
+                parsed.state_params.ema_bands.lower,
+                parsed.state_params.ema_bands.upper
+            ),
+            (98.0, 100.01)
+        );
+        assert_eq!(parsed.state_params.hour, 7);
+        assert_eq!(parsed.position.size, 0.0);
+
+        for (key, value, error) in [
+            (
+                "pside",
+                json!("both"),
+                "'pside' must be \"long\" or \"short\"",
+            ),
+            ("balance", json!("1000"), "'balance' must be a number"),
+            ("position", json!(1.0), "'position' must be an object"),
+        ] {
+            let mut bad = request("long");
+            bad[key] = value;
+            assert_eq!(parse_request(&bad.to_string()).err().unwrap(), error);
+        }
+        let mut unknown = request("long");
+        unknown["bot_params"]["no_such_param"] = json!(1.0);
+        assert!(parse_request(&unknown.to_string()).is_err());
+        // zero steps are rejected unless inferred
+        let mut zero_step = request("long");
+        zero_step["exchange_params"]["price_step"] = json!(0.0);
+        assert!(parse_request(&zero_step.to_string()).is_err());
+        zero_step["exchange_params"]["infer_steps"] = json!(true);
+        let parsed = parse_request(&zero_step.to_string()).unwrap();
+        assert!((parsed.exchange_params.price_step - 0.01).abs() < 1e-15);
+    }
+
+    #[test]
+    fn grid_preview_walks_the_entry_ladder() {
+        for pside in ["long", "short"] {
+            let request = parse_request(&request(pside).to_string()).unwrap();
+            let levels = grid_preview(&request);
+            assert_eq!(levels.len(), entries(&request).len());
+            assert!(levels.len() > 1);
+            let mut size = 0.0;
+            for level in &levels {
+                size += level.entry.qty;
+                assert!((level.position.size - size).abs() < 1e-9);
+                assert!(!level.closes.is_empty());
+                // closes take the position back the other way
+                assert!(level.closes.iter().all(|close| close.qty * size < 0.0));
+            }
+            // exposure grows with each fill
+            assert!(levels
+                .windows(2)
+                .all(|w| w[0].wallet_exposure < w[1].wallet_exposure));
+            // and ends at the limit, up to rounding the last entry to qty_step
+            assert!((levels.last().unwrap().wallet_exposure - 0.5).abs() < 1e-3);
+        }
+    }
+}
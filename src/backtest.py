@@ -80,6 +80,7 @@ def process_forager_fills(fills, coins, hlcvs, equities, equities_btc):
             "psize",
             "pprice",
             "type",
+            "impact_pct",
         ],
     )
     analysis_appendix = {}
@@ -405,6 +405,7 @@ class Passivbot:
             "n_positions",
             "total_wallet_exposure_limit",
             "unstuck_loss_allowance_pct",
+            "unstuck_require_profit_buffer_pct",
             "unstuck_close_pct",
             "filter_noisiness_rolling_window",
             "filter_volume_rolling_window",
@@ -1476,6 +1477,7 @@ class Passivbot:
                     * self.config["bot"][pside]["total_wallet_exposure_limit"],
                     pnls_cumsum_max,
                     pnls_cumsum_last,
+                    self.config["bot"][pside].get("unstuck_require_profit_buffer_pct", 0.0),
                 )
                 if self.config["bot"][pside]["unstuck_loss_allowance_pct"] > 0.0
                 else 0.0